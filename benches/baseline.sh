@@ -0,0 +1,22 @@
+#!/bin/sh
+# Saves or compares Criterion baselines for the engine benches.
+#
+#   benches/baseline.sh save [name]      record a baseline (default: main)
+#   benches/baseline.sh compare [name]   measure against a saved baseline
+#
+# Extra arguments after the name are passed to Criterion, e.g. a filter:
+#   benches/baseline.sh compare main put/
+set -eu
+
+action=${1:-}
+name=${2:-main}
+[ $# -gt 2 ] && shift 2 || set --
+
+case "$action" in
+    save) exec cargo bench --bench engine -- --save-baseline "$name" "$@" ;;
+    compare) exec cargo bench --bench engine -- --baseline "$name" "$@" ;;
+    *)
+        echo "usage: $0 save|compare [name] [criterion args...]" >&2
+        exit 2
+        ;;
+esac
@@ -1,64 +1,254 @@
-use crabkv::CrabKv;
-use criterion::{BatchSize, Criterion, SamplingMode, criterion_group, criterion_main};
+use crabkv::{CrabKv, CrabKvBuilder, ServerConfig, server};
+use criterion::{
+    BatchSize, BenchmarkId, Criterion, SamplingMode, Throughput, criterion_group, criterion_main,
+};
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Operations timed per iteration in the parameterized groups.
+const OPS: usize = 100;
+
+/// Value sizes every configuration is measured with.
+const VALUE_SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+/// Threads of each kind in the concurrent group.
+const THREADS: usize = 4;
+
+type Configure = fn(CrabKvBuilder) -> CrabKvBuilder;
+
+/// Builder setups measured by each group; the name is the benchmark id.
+const CONFIGURATIONS: [(&str, Configure); 5] = [
+    ("default", |builder| builder),
+    ("sync_interval", |builder| {
+        builder.sync_interval(Duration::from_millis(100))
+    }),
+    ("compression", |builder| builder.compression(true)),
+    ("cache", |builder| builder.cache_capacity(cache_capacity())),
+    ("write_back", |builder| {
+        builder
+            .cache_capacity(cache_capacity())
+            .write_back_cache(true)
+    }),
+];
+
+fn cache_capacity() -> NonZeroUsize {
+    NonZeroUsize::new(4_096).unwrap()
+}
 
 fn bench_put(c: &mut Criterion) {
-    let mut group = c.benchmark_group("writes");
-    group.sampling_mode(SamplingMode::Auto);
-    group.warm_up_time(std::time::Duration::from_secs(3));
-    group.measurement_time(std::time::Duration::from_secs(10));
-    group.bench_function("sequential_put_1k", |b| {
-        b.iter_batched(
-            BenchContext::new,
-            |ctx| {
-                for i in 0..1_000 {
-                    let key = format!("k{i}");
-                    ctx.engine.put(key, "v".to_string()).unwrap();
+    let mut group = c.benchmark_group("put");
+    configure_group(&mut group);
+    for (name, configure) in CONFIGURATIONS {
+        for size in VALUE_SIZES {
+            group.throughput(Throughput::Bytes((OPS * size) as u64));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter_batched_ref(
+                    || BenchContext::with(configure),
+                    |ctx| {
+                        let value = "v".repeat(size);
+                        for i in 0..OPS {
+                            ctx.engine.put(format!("k{i}"), value.clone()).unwrap();
+                        }
+                    },
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Times the slowest of `OPS` puts into one long-lived engine, so a put that
+/// pays for an fsync inline shows up rather than averaging away.
+fn bench_put_tail(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put_tail");
+    configure_group(&mut group);
+    let configurations: [(&str, Configure); 2] = [
+        ("default", |builder| builder),
+        ("sync_interval", |builder| {
+            builder.sync_interval(Duration::from_millis(10))
+        }),
+    ];
+    for (name, configure) in configurations {
+        group.bench_function(BenchmarkId::new(name, 1024), |b| {
+            let ctx = BenchContext::with(configure);
+            let value = "v".repeat(1024);
+            let mut next = 0u64;
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let mut slowest = Duration::ZERO;
+                    for _ in 0..OPS {
+                        next += 1;
+                        let started = Instant::now();
+                        ctx.engine.put(format!("k{next}"), value.clone()).unwrap();
+                        slowest = slowest.max(started.elapsed());
+                    }
+                    total += slowest;
                 }
-            },
-            BatchSize::SmallInput,
-        );
-    });
+                total
+            });
+        });
+    }
     group.finish();
 }
 
 fn bench_get(c: &mut Criterion) {
-    let mut group = c.benchmark_group("reads");
-    group.warm_up_time(std::time::Duration::from_secs(2));
-    group.measurement_time(std::time::Duration::from_secs(8));
-    group.bench_function("sequential_get_1k", |b| {
-        b.iter_batched(
-            || {
-                let mut ctx = BenchContext::new();
-                for i in 0..1_000 {
-                    let key = format!("k{i}");
-                    ctx.engine.put(key.clone(), "v".to_string()).unwrap();
-                    ctx.keys.push(key);
-                }
-                ctx
-            },
-            |ctx| {
-                for key in &ctx.keys {
-                    let _ = ctx.engine.get(key).unwrap();
-                }
-            },
-            BatchSize::SmallInput,
-        );
+    let mut group = c.benchmark_group("get");
+    configure_group(&mut group);
+    for (name, configure) in CONFIGURATIONS {
+        for size in VALUE_SIZES {
+            group.throughput(Throughput::Bytes((OPS * size) as u64));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                // Filling outside the timed routine measures a warmed engine.
+                b.iter_batched_ref(
+                    || BenchContext::filled(configure, size),
+                    |ctx| {
+                        for key in &ctx.keys {
+                            let _ = ctx.engine.get(key).unwrap();
+                        }
+                    },
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Reads 500 uncached keys one `get` at a time, with one `get_many`, and with
+/// one `multi_get_into` reusing its arena.
+fn bench_get_many(c: &mut Criterion) {
+    const KEYS: usize = 500;
+    let mut group = c.benchmark_group("get_many");
+    configure_group(&mut group);
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let ctx = BenchContext::with(|builder| builder);
+    let value = "v".repeat(1024);
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("k{i}")).collect();
+    for key in &keys {
+        ctx.engine.put(key.clone(), value.clone()).unwrap();
+    }
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    group.bench_function("get_loop", |b| {
+        b.iter(|| {
+            for key in &keys {
+                let _ = ctx.engine.get(key).unwrap();
+            }
+        })
+    });
+    group.bench_function("get_many", |b| {
+        b.iter(|| ctx.engine.get_many(&keys).unwrap())
+    });
+    let mut arena = Vec::new();
+    group.bench_function("multi_get_into", |b| {
+        b.iter(|| {
+            arena.clear();
+            ctx.engine.multi_get_into(&keys, &mut arena).unwrap()
+        })
     });
     group.finish();
 }
 
+/// Probes for 1M keys against an index holding half of them.
+fn bench_contains_many(c: &mut Criterion) {
+    const KEYS: usize = 1_000_000;
+    let mut group = c.benchmark_group("contains_many");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let ctx = BenchContext::with(|builder| builder);
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("key-{i:07}")).collect();
+    for chunk in keys.chunks(10_000).step_by(2) {
+        let batch = chunk
+            .iter()
+            .map(|key| (key.clone(), "v".to_string(), None))
+            .collect();
+        ctx.engine.put_batch(batch).unwrap();
+    }
+    group.bench_function("1m_probes", |b| {
+        b.iter(|| {
+            ctx.engine
+                .contains_many(keys.iter().map(String::as_str))
+                .unwrap()
+        });
+    });
+    group.finish();
+}
+
+fn bench_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    configure_group(&mut group);
+    for (name, configure) in CONFIGURATIONS {
+        for size in VALUE_SIZES {
+            group.throughput(Throughput::Bytes((OPS * size) as u64));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter_batched_ref(
+                    || {
+                        let value = "v".repeat(size);
+                        let entries: Vec<_> = (0..OPS)
+                            .map(|i| (format!("k{i}"), value.clone(), None))
+                            .collect();
+                        (BenchContext::with(configure), entries)
+                    },
+                    |(ctx, entries)| ctx.engine.put_batch(std::mem::take(entries)).unwrap(),
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_concurrent(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent");
+    configure_group(&mut group);
+    group.throughput(Throughput::Elements((2 * THREADS * OPS) as u64));
+    for (name, configure) in CONFIGURATIONS {
+        group.bench_function(BenchmarkId::new(name, 1024), |b| {
+            b.iter_batched_ref(
+                || BenchContext::filled(configure, 1024),
+                |ctx| {
+                    let ctx = &*ctx;
+                    thread::scope(|scope| {
+                        for writer in 0..THREADS {
+                            let engine = &ctx.engine;
+                            scope.spawn(move || {
+                                for i in 0..OPS {
+                                    engine
+                                        .put(format!("w{writer}-{i}"), "v".repeat(1024))
+                                        .unwrap();
+                                }
+                            });
+                        }
+                        for _ in 0..THREADS {
+                            scope.spawn(move || {
+                                for key in &ctx.keys {
+                                    let _ = ctx.engine.get(key).unwrap();
+                                }
+                            });
+                        }
+                    });
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+    group.finish();
+}
+
 fn bench_compaction(c: &mut Criterion) {
     let mut group = c.benchmark_group("compaction");
-    group.warm_up_time(std::time::Duration::from_secs(2));
-    group.measurement_time(std::time::Duration::from_secs(10));
+    group.warm_up_time(Duration::from_secs(2));
+    group.measurement_time(Duration::from_secs(10));
     group.bench_function("compaction_cycle", |b| {
-        b.iter_batched(
+        b.iter_batched_ref(
             || {
-                let ctx = BenchContext::new();
+                let ctx = BenchContext::with(|builder| builder);
                 for i in 0..2_000 {
                     let key = format!("k{i}");
                     ctx.engine.put(key.clone(), format!("value-{i}")).unwrap();
@@ -77,6 +267,97 @@ fn bench_compaction(c: &mut Criterion) {
     group.finish();
 }
 
+/// Compacts a compressed store on one, two and four workers; the log is the
+/// same in every case, so the ids compare throughput only.
+fn bench_compaction_threads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction_threads");
+    configure_group(&mut group);
+    let value = "compressible value ".repeat(200);
+    for threads in [1, 2, 4] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter_batched_ref(
+                    || {
+                        let dir = BenchDir::new().expect("bench dir");
+                        let engine = CrabKv::builder(dir.path())
+                            .compression(true)
+                            .compaction_threads(threads)
+                            .build()
+                            .expect("engine");
+                        let ctx = BenchContext {
+                            engine,
+                            _dir: dir,
+                            keys: Vec::new(),
+                        };
+                        for i in 0..2_000 {
+                            ctx.engine
+                                .put(format!("k{i}"), format!("{value}{i}"))
+                                .unwrap();
+                        }
+                        ctx
+                    },
+                    |ctx| {
+                        ctx.engine.compact().unwrap();
+                    },
+                    BatchSize::SmallInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_get_tcp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_tcp");
+    configure_group(&mut group);
+    let size = 1 << 20;
+    group.throughput(Throughput::Bytes(size as u64));
+    let ctx = BenchContext::with(|builder| builder);
+    ctx.engine.put("big".into(), "v".repeat(size)).unwrap();
+    let handle =
+        server::spawn("127.0.0.1:0", ctx.engine.clone(), ServerConfig::default()).expect("server");
+    let stream = TcpStream::connect(handle.local_addr()).expect("connect");
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+
+    group.bench_function("GET", |b| {
+        b.iter(|| {
+            writer.write_all(b"GET big\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line.len(), size + "VALUE \n".len());
+        })
+    });
+    let mut value = vec![0; size];
+    group.bench_function("GETRAW", |b| {
+        b.iter(|| {
+            writer.write_all(b"GETRAW big\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            reader.read_exact(&mut value).unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        })
+    });
+    group.finish();
+    drop(writer);
+    drop(reader);
+    handle.shutdown().unwrap();
+}
+
+/// Shared timing settings for the parameterized groups, kept short so the
+/// full matrix runs in a few minutes.
+fn configure_group(group: &mut criterion::BenchmarkGroup<'_, criterion::measurement::WallTime>) {
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(10);
+    group.warm_up_time(Duration::from_secs(1));
+    group.measurement_time(Duration::from_secs(3));
+}
+
 struct BenchContext {
     engine: CrabKv,
     _dir: BenchDir,
@@ -84,15 +365,30 @@ struct BenchContext {
 }
 
 impl BenchContext {
-    fn new() -> Self {
+    fn with(configure: Configure) -> Self {
         let dir = BenchDir::new().expect("bench dir");
-        let engine = CrabKv::open(dir.path()).expect("engine");
+        let engine = configure(CrabKv::builder(dir.path()))
+            .build()
+            .expect("engine");
         Self {
             engine,
             _dir: dir,
             keys: Vec::new(),
         }
     }
+
+    /// Opens an engine holding `OPS` keys with `size`-byte values, flushed to the log.
+    fn filled(configure: Configure, size: usize) -> Self {
+        let mut ctx = Self::with(configure);
+        let value = "v".repeat(size);
+        for i in 0..OPS {
+            let key = format!("k{i}");
+            ctx.engine.put(key.clone(), value.clone()).unwrap();
+            ctx.keys.push(key);
+        }
+        ctx.engine.flush().unwrap();
+        ctx
+    }
 }
 
 struct BenchDir {
@@ -125,5 +421,17 @@ impl Drop for BenchDir {
     }
 }
 
-criterion_group!(benches, bench_put, bench_get, bench_compaction);
+criterion_group!(
+    benches,
+    bench_put,
+    bench_put_tail,
+    bench_get,
+    bench_get_many,
+    bench_contains_many,
+    bench_batch,
+    bench_concurrent,
+    bench_compaction,
+    bench_compaction_threads,
+    bench_get_tcp
+);
 criterion_main!(benches);
@@ -0,0 +1,69 @@
+//! Records the git commit and build date for `crabkv::build_info`.
+//!
+//! Both fall back gracefully: `CRABKV_GIT_HASH` overrides the hash, a build
+//! outside a git checkout (such as from a crates.io tarball) reports
+//! `unknown`, and `SOURCE_DATE_EPOCH` pins the date for reproducible builds.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=CRABKV_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // Only watch files that exist; a missing one would rerun every build.
+    for watched in [".git/HEAD", ".git/index"] {
+        if Path::new(watched).exists() {
+            println!("cargo:rerun-if-changed={watched}");
+        }
+    }
+
+    let git_hash = env::var("CRABKV_GIT_HASH")
+        .ok()
+        .filter(|hash| !hash.is_empty())
+        .or_else(git_hash)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CRABKV_GIT_HASH={git_hash}");
+
+    let epoch_secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+    println!(
+        "cargo:rustc-env=CRABKV_BUILD_DATE={}",
+        civil_date(epoch_secs)
+    );
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!hash.is_empty()).then_some(hash)
+}
+
+/// Formats seconds since the Unix epoch as a UTC `YYYY-MM-DD` date.
+fn civil_date(epoch_secs: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm, for days on or after 1970-01-01.
+    let days = epoch_secs / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
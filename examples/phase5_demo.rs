@@ -3,7 +3,7 @@
 //! Run with:
 //!   cargo run --example phase5_demo
 
-use crabkv::CrabKv;
+use crabkv::{CompactionPolicy, CrabKv};
 use std::num::NonZeroUsize;
 use std::time::{Duration, Instant};
 
@@ -46,7 +46,11 @@ fn demo_baseline() -> std::io::Result<()> {
     }
     let elapsed = start.elapsed();
 
-    println!("   ✓ 1000 writes in {:?} ({:.0} writes/sec)\n", elapsed, 1000.0 / elapsed.as_secs_f64());
+    println!(
+        "   ✓ 1000 writes in {:?} ({:.0} writes/sec)\n",
+        elapsed,
+        1000.0 / elapsed.as_secs_f64()
+    );
     Ok(())
 }
 
@@ -63,7 +67,11 @@ fn demo_buffered_io() -> std::io::Result<()> {
     }
     let elapsed = start.elapsed();
 
-    println!("   ✓ 1000 writes in {:?} ({:.0} writes/sec)", elapsed, 1000.0 / elapsed.as_secs_f64());
+    println!(
+        "   ✓ 1000 writes in {:?} ({:.0} writes/sec)",
+        elapsed,
+        1000.0 / elapsed.as_secs_f64()
+    );
     println!("   ℹ  Up to 100ms of unflushed data may be lost on crash\n");
     Ok(())
 }
@@ -85,7 +93,11 @@ fn demo_batch_writes() -> std::io::Result<()> {
     }
     let elapsed = start.elapsed();
 
-    println!("   ✓ 1000 writes (10 batches) in {:?} ({:.0} writes/sec)\n", elapsed, 1000.0 / elapsed.as_secs_f64());
+    println!(
+        "   ✓ 1000 writes (10 batches) in {:?} ({:.0} writes/sec)\n",
+        elapsed,
+        1000.0 / elapsed.as_secs_f64()
+    );
     Ok(())
 }
 
@@ -94,14 +106,18 @@ fn demo_async_compaction() -> std::io::Result<()> {
     let dir = tempfile::tempdir()?;
     let db = CrabKv::builder(dir.path())
         .async_compaction(true)
+        .compaction_policy(CompactionPolicy::every_n_mutations(1000))
         .build()?;
 
-    // Write enough data to trigger compaction
+    // Every thousandth write hands a compaction to the worker.
     for i in 0..5000 {
         db.put(format!("key{}", i % 100), format!("value{}", i))?;
     }
-
     println!("   ✓ Wrote 5000 updates (overwriting 100 keys)");
+    println!(
+        "   ✓ {} compactions finished in the background so far",
+        db.compaction_count()?
+    );
     println!("   ℹ  Compaction runs in background, writes never blocked\n");
     Ok(())
 }
@@ -109,12 +125,10 @@ fn demo_async_compaction() -> std::io::Result<()> {
 fn demo_compression() -> std::io::Result<()> {
     println!("📦 Demo 5: Snappy compression");
     let dir = tempfile::tempdir()?;
-    let db = CrabKv::builder(dir.path())
-        .compression(true)
-        .build()?;
+    let db = CrabKv::builder(dir.path()).compression(true).build()?;
 
     let large_value = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(10);
-    
+
     let start = Instant::now();
     for i in 0..100 {
         db.put(format!("doc{}", i), large_value.clone())?;
@@ -140,8 +154,11 @@ fn demo_write_back_cache() -> std::io::Result<()> {
     }
     let write_elapsed = start.elapsed();
 
-    println!("   ✓ 1000 buffered writes in {:?} ({:.0} writes/sec)", 
-             write_elapsed, 1000.0 / write_elapsed.as_secs_f64());
+    println!(
+        "   ✓ 1000 buffered writes in {:?} ({:.0} writes/sec)",
+        write_elapsed,
+        1000.0 / write_elapsed.as_secs_f64()
+    );
 
     let flush_start = Instant::now();
     db.flush()?;
@@ -167,16 +184,20 @@ fn demo_all_optimizations() -> std::io::Result<()> {
     for i in 0..10000 {
         db.put(format!("key{}", i), format!("value{}", i))?;
     }
-    
+
     // Periodic flush (every 1000 writes)
     if start.elapsed() > Duration::from_millis(100) {
         db.flush()?;
     }
-    
+
     db.flush()?; // Final flush
     let elapsed = start.elapsed();
 
-    println!("   ✓ 10,000 writes in {:?} ({:.0} writes/sec)", elapsed, 10000.0 / elapsed.as_secs_f64());
+    println!(
+        "   ✓ 10,000 writes in {:?} ({:.0} writes/sec)",
+        elapsed,
+        10000.0 / elapsed.as_secs_f64()
+    );
     println!("   ℹ  Combining all optimizations maximizes throughput\n");
     Ok(())
 }
@@ -0,0 +1,219 @@
+//! Errors reported by the engine's background threads.
+//!
+//! The compaction worker, the WAL flusher, the checkpoint writer, the
+//! scrubber and the TTL sweeper run without a caller to return an error to.
+//! Each reports what went wrong into a bounded ring read with
+//! [`CrabKv::background_errors`](crate::CrabKv::background_errors), and a
+//! worker that panics reports the panic and is started again after a backoff
+//! instead of leaving the store without it.
+
+use crate::clock::Clock;
+use parking_lot::Mutex;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+#[cfg(feature = "fault-injection")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Errors the ring holds; the oldest are dropped first.
+pub const BACKGROUND_ERROR_CAPACITY: usize = 64;
+
+/// How long an error keeps HEALTH degraded unless
+/// [`background_error_window`](crate::CrabKvBuilder::background_error_window)
+/// says otherwise.
+pub const DEFAULT_BACKGROUND_ERROR_WINDOW: Duration = Duration::from_secs(300);
+
+/// First wait before a failed worker is started again; doubles per
+/// consecutive failure up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A thread the engine runs on its own.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BackgroundWorker {
+    /// Runs compactions with `async_compaction` enabled.
+    Compaction,
+    /// Syncs the log once per `sync_interval`.
+    WalFlusher,
+    /// Writes index checkpoints once `checkpoint_mutations` or
+    /// `checkpoint_bytes` is reached.
+    Checkpoint,
+    /// Checks every log record once per `scrub_interval`, reporting each
+    /// damaged one.
+    Scrub,
+    /// Removes expired keys once per `ttl_sweep_interval`.
+    TtlSweeper,
+}
+
+impl BackgroundWorker {
+    #[cfg(feature = "fault-injection")]
+    const ALL: [Self; 5] = [
+        Self::Compaction,
+        Self::WalFlusher,
+        Self::Checkpoint,
+        Self::Scrub,
+        Self::TtlSweeper,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Compaction => "compaction",
+            Self::WalFlusher => "wal-flusher",
+            Self::Checkpoint => "checkpoint",
+            Self::Scrub => "scrubber",
+            Self::TtlSweeper => "ttl-sweeper",
+        }
+    }
+}
+
+impl fmt::Display for BackgroundWorker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// One failure of a background worker.
+#[derive(Clone, Debug)]
+pub struct BackgroundError {
+    /// The thread that failed.
+    pub worker: BackgroundWorker,
+    /// When the failure was reported, by the engine's clock.
+    pub at: SystemTime,
+    /// What failed; a panic shows up as an `Other` error with its message.
+    pub error: Arc<io::Error>,
+}
+
+/// Ring of background errors shared by every handle and worker.
+#[derive(Debug)]
+pub(crate) struct BackgroundErrors {
+    clock: Clock,
+    ring: Mutex<VecDeque<BackgroundError>>,
+    /// Workers a test asked to crash at their next step.
+    #[cfg(feature = "fault-injection")]
+    crash: [AtomicBool; 5],
+}
+
+impl BackgroundErrors {
+    pub(crate) fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            ring: Mutex::new(VecDeque::new()),
+            #[cfg(feature = "fault-injection")]
+            crash: Default::default(),
+        }
+    }
+
+    /// Records `error` against `worker`.
+    pub(crate) fn report(&self, worker: BackgroundWorker, error: io::Error) {
+        let mut ring = self.ring.lock();
+        if ring.len() == BACKGROUND_ERROR_CAPACITY {
+            ring.pop_front();
+        }
+        ring.push_back(BackgroundError {
+            worker,
+            at: self.clock.now(),
+            error: Arc::new(error),
+        });
+    }
+
+    /// Every error held, oldest first.
+    pub(crate) fn snapshot(&self) -> Vec<BackgroundError> {
+        self.ring.lock().iter().cloned().collect()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.ring.lock().clear();
+    }
+
+    /// The latest error reported less than `window` ago.
+    pub(crate) fn latest_within(&self, window: Duration) -> Option<BackgroundError> {
+        let now = self.clock.now();
+        self.ring
+            .lock()
+            .back()
+            .filter(|error| now.duration_since(error.at).unwrap_or_default() < window)
+            .cloned()
+    }
+
+    /// Makes `worker` panic at its next step, as a test hook.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn crash_next(&self, worker: BackgroundWorker) {
+        self.crash[Self::slot(worker)].store(true, Ordering::Relaxed);
+    }
+
+    /// Panics if a crash of `worker` was asked for. Called by each worker at
+    /// a point where it holds no lock.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn crash_point(&self, worker: BackgroundWorker) {
+        if self.crash[Self::slot(worker)].swap(false, Ordering::Relaxed) {
+            // Skips the panic hook, so tests do not print a backtrace.
+            panic::resume_unwind(Box::new(format!("injected crash of the {worker}")));
+        }
+    }
+
+    /// No crash can be asked for without the `fault-injection` feature.
+    #[cfg(not(feature = "fault-injection"))]
+    pub(crate) fn crash_point(&self, _worker: BackgroundWorker) {}
+
+    #[cfg(feature = "fault-injection")]
+    fn slot(worker: BackgroundWorker) -> usize {
+        BackgroundWorker::ALL
+            .iter()
+            .position(|&other| other == worker)
+            .expect("every worker has a slot")
+    }
+
+    /// Runs `body` until it returns, reporting each panic and running it again
+    /// after a backoff.
+    pub(crate) fn supervise(&self, worker: BackgroundWorker, mut body: impl FnMut()) {
+        let mut backoff = Backoff::default();
+        loop {
+            let started = Instant::now();
+            let Err(payload) = panic::catch_unwind(AssertUnwindSafe(&mut body)) else {
+                return;
+            };
+            self.report(worker, panic_error(payload));
+            // A worker that ran for a while before failing starts over.
+            if started.elapsed() > MAX_BACKOFF {
+                backoff.reset();
+            }
+            thread::sleep(backoff.next_delay());
+        }
+    }
+}
+
+/// Delay before retrying after consecutive failures, doubling each time.
+#[derive(Debug, Default)]
+pub(crate) struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    /// Counts a failure and returns how long to wait before the next attempt.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = MIN_BACKOFF
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_BACKOFF);
+        self.failures += 1;
+        delay
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Turns a panic payload into an error carrying its message.
+pub(crate) fn panic_error(payload: Box<dyn Any + Send>) -> io::Error {
+    let message = payload
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| payload.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    io::Error::other(format!("panicked: {message}"))
+}
@@ -0,0 +1,186 @@
+//! Incremental export of the log, for consumers that follow a store's writes
+//! across their own runs through [`CrabKv::export_changes`](crate::CrabKv::export_changes).
+//!
+//! A consumer remembers the [`ChangeBatch::generation`] and
+//! [`ChangeBatch::next_offset`] of its last batch and resumes from that
+//! offset. Offsets stay valid until compaction rewrites the log, which moves
+//! the generation on; from then on the consumer starts again from offset 0,
+//! where the rewritten log holds every live key once.
+//!
+//! [`CrabKv::watch_prefix`](crate::CrabKv::watch_prefix) builds a
+//! [`WatchStream`] on the same reads: it replays the log from a
+//! [`ChangeMarker`](crate::ChangeMarker) and then keeps reading as the log grows.
+
+use crate::engine::CrabKv;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many log bytes a [`WatchStream`] reads per lock hold.
+const WATCH_READ_BYTES: u64 = 256 * 1024;
+
+/// Changes read from the log by [`CrabKv::export_changes`](crate::CrabKv::export_changes).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChangeBatch {
+    /// Generation of the log the offsets belong to. It only changes when
+    /// compaction rewrites the log, restarts included, and never repeats.
+    pub generation: u64,
+    /// Changes in the order they were written.
+    pub changes: Vec<Change>,
+    /// Offset to resume from; the `from_offset` given when nothing was read.
+    pub next_offset: u64,
+    /// Whether the batch reaches the end of the log as it stood when read.
+    pub at_end: bool,
+}
+
+/// One write found in the log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change {
+    /// Offset of the record in the log.
+    pub offset: u64,
+    pub key: String,
+    pub kind: ChangeKind,
+}
+
+/// What a [`Change`] did to its key.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChangeKind {
+    Put {
+        value: String,
+        expires_at: Option<SystemTime>,
+    },
+    /// A value stored with [`CrabKv::put_bytes`](crate::CrabKv::put_bytes)
+    /// that is not valid UTF-8.
+    PutBytes {
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    },
+    Delete,
+}
+
+/// Error payload returned when compaction rewrote the log a watch was
+/// reading, so the changes since its marker can no longer be replayed.
+///
+/// Carried inside an [`io::Error`] of kind [`NotFound`](ErrorKind::NotFound);
+/// match it with [`HistoryTruncated::of`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryTruncated {
+    /// Log generation the watch was reading.
+    pub generation: u64,
+    /// Generation the log is at now.
+    pub current: u64,
+}
+
+impl fmt::Display for HistoryTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "log generation {} was compacted into generation {}; resync with a full scan",
+            self.generation, self.current
+        )
+    }
+}
+
+impl std::error::Error for HistoryTruncated {}
+
+impl HistoryTruncated {
+    /// Returns the payload `err` carries, if any.
+    pub fn of(err: &io::Error) -> Option<&HistoryTruncated> {
+        err.get_ref()?.downcast_ref::<HistoryTruncated>()
+    }
+
+    pub(crate) fn error(generation: u64, current: u64) -> io::Error {
+        io::Error::new(
+            ErrorKind::NotFound,
+            HistoryTruncated {
+                generation,
+                current,
+            },
+        )
+    }
+}
+
+/// Changes to keys under one prefix, returned by
+/// [`CrabKv::watch_prefix`](crate::CrabKv::watch_prefix).
+///
+/// Iterating blocks until the next matching change reaches the log; use
+/// [`WatchStream::next_timeout`] to wait for a bounded time instead. After an
+/// error the iterator ends. The stream holds a handle on the engine.
+pub struct WatchStream {
+    engine: CrabKv,
+    prefix: String,
+    generation: u64,
+    next_offset: u64,
+    /// Matching changes read but not handed out yet.
+    pending: VecDeque<Change>,
+    failed: bool,
+}
+
+impl WatchStream {
+    pub(crate) fn new(engine: CrabKv, prefix: &str, generation: u64, offset: u64) -> Self {
+        WatchStream {
+            engine,
+            prefix: prefix.to_owned(),
+            generation,
+            next_offset: offset,
+            pending: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Log offset the stream reads from next.
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Returns the next matching change, or `None` if none was written
+    /// within `timeout`.
+    pub fn next_timeout(&mut self, timeout: Duration) -> io::Result<Option<Change>> {
+        self.next_before(Some(Instant::now() + timeout))
+    }
+
+    fn next_before(&mut self, deadline: Option<Instant>) -> io::Result<Option<Change>> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Ok(Some(change));
+            }
+            // Read before the log, so an append racing the read still wakes us.
+            let seen = self.engine.appends_seen();
+            let batch =
+                self.engine
+                    .read_changes(self.generation, self.next_offset, WATCH_READ_BYTES)?;
+            self.next_offset = batch.next_offset;
+            let prefix = &self.prefix;
+            self.pending.extend(
+                batch
+                    .changes
+                    .into_iter()
+                    .filter(|change| change.key.starts_with(prefix.as_str())),
+            );
+            if self.pending.is_empty()
+                && batch.at_end
+                && !self.engine.wait_for_appends(seen, deadline)
+            {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl Iterator for WatchStream {
+    type Item = io::Result<Change>;
+
+    fn next(&mut self) -> Option<io::Result<Change>> {
+        if self.failed {
+            return None;
+        }
+        match self.next_before(None) {
+            Ok(change) => change.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
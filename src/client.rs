@@ -0,0 +1,671 @@
+//! Blocking client for the text protocol [`server`](crate::server) speaks, and
+//! a small pool of such connections.
+//!
+//! The server has a single, line-based protocol; there is no binary protocol to
+//! negotiate. Values are still fetched length-framed through `GETRAW`, so they
+//! come back byte for byte. Keys and values travel as whitespace-separated
+//! tokens, so the client refuses ones that are empty or contain whitespace
+//! rather than let the server split them.
+//!
+//! `ERR` replies the engine produced from a typed error come back as that
+//! error: a [`QuotaExceeded`], [`Corrupted`], [`InvalidUtf8`] or
+//! [`EpochMismatch`] payload, as in-process callers see it. Everything else
+//! carries a [`ServerError`].
+//!
+//! A client learns the store's fencing epoch from the welcome line and can
+//! pin it with [`Client::pin_epoch`]. Once a restore, clear or migration moves
+//! the store on, every command on that connection fails with
+//! [`EpochMismatch`] and the client reports [`is_fenced`](Client::is_fenced);
+//! a [`Pool`] closes such connections rather than hand them out again.
+
+use crate::config::format_duration;
+use crate::manifest::EpochMismatch;
+use crate::quarantine::Corrupted;
+use crate::quota::QuotaExceeded;
+use crate::utf8::InvalidUtf8;
+use parking_lot::{Condvar, Mutex};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+/// How long a pool waits for a free connection unless told otherwise.
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Error payload for an `ERR` reply with no typed counterpart.
+///
+/// Carried inside an [`io::Error`]; `ERR BUSY` and a full server arrive with
+/// kind [`WouldBlock`](ErrorKind::WouldBlock), `bad command` with
+/// [`InvalidInput`](ErrorKind::InvalidInput), anything else with
+/// [`Other`](ErrorKind::Other). Match it with [`ServerError::of`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerError {
+    /// The reply after `ERR `, without its request id.
+    pub message: String,
+    /// The request id the server logged the failure under.
+    pub request_id: Option<String>,
+    /// How long the server asked writers to back off, from `ERR BUSY`.
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.request_id {
+            Some(id) => write!(f, "server replied: {} [id={id}]", self.message),
+            None => write!(f, "server replied: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl ServerError {
+    /// Returns the payload `err` carries, if any.
+    pub fn of(err: &io::Error) -> Option<&ServerError> {
+        err.get_ref()?.downcast_ref::<ServerError>()
+    }
+}
+
+/// Turns the text of an `ERR` reply into the error the engine raised.
+fn server_error(reply: &str) -> io::Error {
+    let tagged = reply
+        .strip_suffix(']')
+        .and_then(|reply| reply.rsplit_once(" [id="));
+    let (message, request_id) = match tagged {
+        Some((message, id)) => (message, Some(id.to_owned())),
+        None => (reply, None),
+    };
+    if let Some(err) = typed_error(message) {
+        return err;
+    }
+    let retry_after = message
+        .strip_prefix("BUSY retry_after=")
+        .and_then(|millis| millis.parse().ok())
+        .map(Duration::from_millis);
+    let kind = if retry_after.is_some() || message == "server busy" {
+        ErrorKind::WouldBlock
+    } else if message == "bad command" {
+        ErrorKind::InvalidInput
+    } else {
+        ErrorKind::Other
+    };
+    io::Error::new(
+        kind,
+        ServerError {
+            message: message.to_owned(),
+            request_id,
+            retry_after,
+        },
+    )
+}
+
+/// Parses back the `Display` form of the crate's typed errors.
+fn typed_error(message: &str) -> Option<io::Error> {
+    if let Some(rest) = message.strip_prefix("quota exceeded for prefix `") {
+        let (prefix, usage) = rest.rsplit_once("`: ")?;
+        let (used, limit) = usage.strip_suffix(" bytes used")?.split_once(" of ")?;
+        return Some(
+            QuotaExceeded {
+                prefix: prefix.to_owned(),
+                used: used.parse().ok()?,
+                limit: limit.parse().ok()?,
+            }
+            .into(),
+        );
+    }
+    if let Some(key) = message
+        .strip_prefix('`')
+        .and_then(|rest| rest.strip_suffix("` is quarantined: its log record is corrupted"))
+    {
+        return Some(Corrupted::error(key));
+    }
+    if let Some(rest) = message.strip_prefix("EPOCH expected ") {
+        let (expected, current) = rest
+            .strip_suffix("; reconnect and re-read")?
+            .split_once(", the store is at epoch ")?;
+        return Some(EpochMismatch::error(
+            expected.parse().ok()?,
+            current.parse().ok()?,
+        ));
+    }
+    let (key, offset) = message
+        .strip_prefix("the value of `")?
+        .strip_suffix(" is not valid utf-8")?
+        .rsplit_once("` at log offset ")?;
+    Some(io::Error::new(
+        ErrorKind::InvalidData,
+        InvalidUtf8 {
+            key: key.to_owned(),
+            offset: offset.parse().ok()?,
+        },
+    ))
+}
+
+/// Rejects a key or value the server would split or not see at all.
+fn check_token(what: &str, token: &str) -> io::Result<()> {
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{what} `{token}` cannot be sent: it is empty or contains whitespace"),
+        ));
+    }
+    Ok(())
+}
+
+/// One connection to a CrabKv server.
+///
+/// Methods take `&mut self` because replies arrive in order on the one socket.
+/// Once a call fails on the socket itself the client is broken and every later
+/// call fails; connect again. A `GOAWAY` from a draining server is noted in
+/// [`is_draining`](Client::is_draining) and the connection keeps working until
+/// the server closes it.
+pub struct Client {
+    addr: SocketAddr,
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    /// Bytes of a reply line not complete yet. They are kept across reads,
+    /// so a line cut by a read timeout, even inside a UTF-8 sequence, is only
+    /// decoded once its newline arrives.
+    partial: Vec<u8>,
+    broken: bool,
+    draining: bool,
+    /// Epoch the welcome line named.
+    epoch: Option<u64>,
+    fenced: bool,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("addr", &self.addr)
+            .field("broken", &self.broken)
+            .field("draining", &self.draining)
+            .field("epoch", &self.epoch)
+            .field("fenced", &self.fenced)
+            .finish()
+    }
+}
+
+impl Client {
+    /// Connects to the server at `addr` and reads its welcome line.
+    ///
+    /// A server with no free worker answers `ERR server busy` instead, which
+    /// fails with [`WouldBlock`](ErrorKind::WouldBlock).
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut client = Client {
+            addr: stream.peer_addr()?,
+            reader: BufReader::new(stream.try_clone()?),
+            partial: Vec::new(),
+            stream,
+            broken: false,
+            draining: false,
+            epoch: None,
+            fenced: false,
+        };
+        let welcome = client.read_line()?;
+        if let Some(reply) = welcome.strip_prefix("ERR ") {
+            return Err(server_error(reply));
+        }
+        if !welcome.starts_with("Welcome to CrabKv.") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} is not a CrabKv server: it greeted with `{welcome}`",
+                    client.addr
+                ),
+            ));
+        }
+        client.epoch = welcome
+            .split(". ")
+            .find_map(|part| part.strip_prefix("epoch="))
+            .and_then(|epoch| epoch.parse().ok());
+        Ok(client)
+    }
+
+    /// Address of the server this client talks to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Fails reads and writes that stall for longer than `timeout`; `None`
+    /// waits forever, the default.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// Whether a call failed on the socket, leaving replies out of step.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Whether the server said `GOAWAY`, asking clients to reconnect elsewhere.
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// The store's fencing epoch when this client connected; `None` from a
+    /// server too old to report one.
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+
+    /// Asks the server for the store's fencing epoch now.
+    pub fn current_epoch(&mut self) -> io::Result<u64> {
+        self.send("EPOCH")?;
+        let reply = self.reply()?;
+        reply
+            .strip_prefix("EPOCH ")
+            .and_then(|epoch| epoch.parse().ok())
+            .ok_or_else(|| unexpected(&reply))
+    }
+
+    /// Pins the connection to `epoch`, usually [`epoch`](Client::epoch), so
+    /// every later command fails with [`EpochMismatch`] once the store leaves
+    /// it. Fails the same way if it already has.
+    pub fn pin_epoch(&mut self, epoch: u64) -> io::Result<()> {
+        self.send(&format!("EPOCH {epoch}"))?;
+        let reply = self.reply()?;
+        if reply != format!("EPOCH {epoch}") {
+            return Err(unexpected(&reply));
+        }
+        Ok(())
+    }
+
+    /// Whether the server refused a command because the store left the
+    /// pinned epoch; connect again to learn the new one.
+    pub fn is_fenced(&self) -> bool {
+        self.fenced
+    }
+
+    /// Stores `value` under `key`.
+    pub fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.put_with_ttl(key, value, None)
+    }
+
+    /// Stores `value` under `key`, expiring after `ttl` when given.
+    ///
+    /// The server takes whole milliseconds; anything finer is dropped.
+    pub fn put_with_ttl(
+        &mut self,
+        key: &str,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> io::Result<()> {
+        check_token("key", key)?;
+        check_token("value", value)?;
+        match ttl {
+            Some(ttl) => self.send(&format!("PUT {key} {value} ttl={}", format_duration(ttl)))?,
+            None => self.send(&format!("PUT {key} {value}"))?,
+        }
+        self.expect_ok()
+    }
+
+    /// Fetches the value stored under `key`.
+    pub fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        check_token("key", key)?;
+        self.send(&format!("GETRAW {key}"))?;
+        self.read_value()
+    }
+
+    /// Asks whether `key` holds a live value, without fetching it.
+    pub fn contains_key(&mut self, key: &str) -> io::Result<bool> {
+        check_token("key", key)?;
+        self.send(&format!("EXISTS {key}"))?;
+        match self.reply()?.as_str() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Fetches several keys in one round trip, answering in the order asked.
+    ///
+    /// The requests are pipelined, so one failing key fails the whole call
+    /// only after every reply has been read.
+    pub fn mget(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        let mut request = String::new();
+        for key in keys {
+            check_token("key", key)?;
+            request.push_str("GETRAW ");
+            request.push_str(key);
+            request.push('\n');
+        }
+        self.write(request.as_bytes())?;
+        let replies: Vec<_> = keys.iter().map(|_| self.read_value()).collect();
+        replies.into_iter().collect()
+    }
+
+    /// Removes `key`; removing a missing key is not an error.
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        check_token("key", key)?;
+        self.send(&format!("DELETE {key}"))?;
+        self.expect_ok()
+    }
+
+    /// Compacts the server's log, returning once it is done.
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.send("COMPACT")?;
+        self.expect_ok()
+    }
+
+    /// Reads the server's `STATS`, keyed by field name, e.g. `keys` or
+    /// `wal_bytes`.
+    pub fn stats(&mut self) -> io::Result<HashMap<String, String>> {
+        self.send("STATS")?;
+        let reply = self.reply()?;
+        let fields = reply
+            .strip_prefix("STATS ")
+            .ok_or_else(|| unexpected(&reply))?;
+        Ok(fields
+            .split_whitespace()
+            .filter_map(|field| field.split_once('='))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect())
+    }
+
+    /// Sends one raw command line and returns its first reply line, for
+    /// commands without a method of their own.
+    pub fn command(&mut self, line: &str) -> io::Result<String> {
+        if line.contains('\n') {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a command is a single line",
+            ));
+        }
+        self.send(line)?;
+        self.reply()
+    }
+
+    /// Checks, without blocking, that the server has not hung up or sent
+    /// anything unasked for.
+    fn is_idle(&mut self) -> bool {
+        if self.broken
+            || self.draining
+            || self.fenced
+            || !self.partial.is_empty()
+            || !self.reader.buffer().is_empty()
+        {
+            return false;
+        }
+        let mut byte = [0];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let idle = matches!(
+            self.stream.peek(&mut byte),
+            Err(err) if err.kind() == ErrorKind::WouldBlock
+        );
+        idle && self.stream.set_nonblocking(false).is_ok()
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        self.write(&bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.check_usable()?;
+        let written = self.stream.write_all(bytes);
+        self.track(written)
+    }
+
+    fn check_usable(&self) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "the connection failed earlier; connect again",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Marks the client broken when `result` failed on the socket.
+    fn track<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if result.is_err() {
+            self.broken = true;
+        }
+        result
+    }
+
+    /// Reads the next reply line, skipping notices the server slips in.
+    fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            let read = self.reader.read_until(b'\n', &mut self.partial);
+            match self.track(read)? {
+                0 => {
+                    self.broken = true;
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "the server closed the connection",
+                    ));
+                }
+                _ if self.partial.last() != Some(&b'\n') => continue,
+                _ => {
+                    let bytes = std::mem::take(&mut self.partial);
+                    let Ok(line) = String::from_utf8(bytes) else {
+                        self.broken = true;
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "a reply line is not valid UTF-8",
+                        ));
+                    };
+                    let line = line.trim_end_matches(['\r', '\n']);
+                    if line == "GOAWAY" {
+                        self.draining = true;
+                    } else if !line.starts_with("WARN ") {
+                        return Ok(line.to_owned());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads a reply line, turning `ERR` into an error.
+    fn reply(&mut self) -> io::Result<String> {
+        let line = self.read_line()?;
+        match line.strip_prefix("ERR ") {
+            Some(reply) if reply == "OUTPUT OVERFLOW" => {
+                self.broken = true;
+                Err(server_error(reply))
+            }
+            Some(reply) => {
+                let err = server_error(reply);
+                self.fenced |= EpochMismatch::of(&err).is_some();
+                Err(err)
+            }
+            None => Ok(line),
+        }
+    }
+
+    fn expect_ok(&mut self) -> io::Result<()> {
+        let reply = self.reply()?;
+        if reply == "OK" || reply.starts_with("OK ") {
+            Ok(())
+        } else {
+            Err(unexpected(&reply))
+        }
+    }
+
+    /// Reads a `GETRAW` reply: `VALUE <len>` and the value, or `NOT_FOUND`.
+    fn read_value(&mut self) -> io::Result<Option<String>> {
+        let reply = self.reply()?;
+        if reply == "NOT_FOUND" {
+            return Ok(None);
+        }
+        let Some(len) = reply
+            .strip_prefix("VALUE ")
+            .and_then(|len| len.parse::<usize>().ok())
+        else {
+            self.broken = true;
+            return Err(unexpected(&reply));
+        };
+        let mut value = vec![0; len + 1];
+        let read = self.reader.read_exact(&mut value);
+        self.track(read)?;
+        if value.pop() != Some(b'\n') {
+            self.broken = true;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "a framed value did not end in a newline",
+            ));
+        }
+        String::from_utf8(value)
+            .map(Some)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+fn unexpected(reply: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected reply `{reply}`"),
+    )
+}
+
+/// Connections to one server, opened on demand up to a fixed number.
+///
+/// [`get`](Pool::get) hands out an idle connection after checking that the
+/// server has not closed it, or opens a new one, so a pool recovers from a
+/// server restart on its own. Connections come back when the guard drops,
+/// unless they broke, were fenced off by a new epoch, or the server asked them
+/// to go away.
+#[derive(Debug)]
+pub struct Pool {
+    addr: SocketAddr,
+    max_connections: usize,
+    checkout_timeout: Duration,
+    connections: Mutex<Connections>,
+    returned: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    idle: Vec<Client>,
+    /// Connections idle or checked out.
+    open: usize,
+}
+
+impl Pool {
+    /// Creates a pool for the server at `addr` holding at most
+    /// `max_connections`. Nothing is opened until the first checkout.
+    pub fn new(addr: impl ToSocketAddrs, max_connections: usize) -> io::Result<Pool> {
+        if max_connections == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a pool needs at least one connection",
+            ));
+        }
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "the address resolved to nothing")
+        })?;
+        Ok(Pool {
+            addr,
+            max_connections,
+            checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+            connections: Mutex::new(Connections::default()),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// How long [`get`](Pool::get) waits for a connection while all are in
+    /// use before failing with `TimedOut`; 5 seconds by default.
+    pub fn checkout_timeout(mut self, timeout: Duration) -> Self {
+        self.checkout_timeout = timeout;
+        self
+    }
+
+    /// Connections open right now, idle or checked out.
+    pub fn open_connections(&self) -> usize {
+        self.connections.lock().open
+    }
+
+    /// Checks out a connection, waiting while all of them are in use.
+    pub fn get(&self) -> io::Result<PooledClient<'_>> {
+        let deadline = Instant::now() + self.checkout_timeout;
+        let mut connections = self.connections.lock();
+        loop {
+            while let Some(mut client) = connections.idle.pop() {
+                if client.is_idle() {
+                    return Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    });
+                }
+                connections.open -= 1;
+            }
+            if connections.open < self.max_connections {
+                connections.open += 1;
+                drop(connections);
+                return match Client::connect(self.addr) {
+                    Ok(client) => Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    }),
+                    Err(err) => {
+                        self.release(None);
+                        Err(err)
+                    }
+                };
+            }
+            if self
+                .returned
+                .wait_until(&mut connections, deadline)
+                .timed_out()
+            {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "all {} connections to {} stayed in use for {:?}",
+                        self.max_connections, self.addr, self.checkout_timeout
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Takes back a checked-out connection; `None` closes its slot.
+    fn release(&self, client: Option<Client>) {
+        let mut connections = self.connections.lock();
+        match client {
+            Some(client) if !client.broken && !client.draining && !client.fenced => {
+                connections.idle.push(client)
+            }
+            _ => connections.open -= 1,
+        }
+        self.returned.notify_one();
+    }
+}
+
+/// A connection checked out of a [`Pool`], returned to it on drop.
+#[derive(Debug)]
+pub struct PooledClient<'a> {
+    pool: &'a Pool,
+    client: Option<Client>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("checked out")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("checked out")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.client.take());
+    }
+}
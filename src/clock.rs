@@ -0,0 +1,140 @@
+//! Wall-clock source used for write times, expiry, and retention.
+
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Backward steps up to this size are absorbed silently as ordinary clock jitter.
+pub const BACKWARD_STEP_THRESHOLD: Duration = Duration::from_secs(1);
+
+type TimeQuery = dyn Fn() -> SystemTime + Send + Sync;
+
+/// Query returning the current wall-clock time.
+#[derive(Clone)]
+pub struct Clock(Arc<TimeQuery>);
+
+impl Clock {
+    /// Wraps the provided query.
+    pub fn new(query: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        Self(Arc::new(query))
+    }
+
+    /// Reads the operating system clock.
+    pub fn system() -> Self {
+        Self::new(SystemTime::now)
+    }
+
+    /// Returns the current time.
+    pub fn now(&self) -> SystemTime {
+        (self.0)()
+    }
+
+    /// Returns a clock reading this one that never goes backwards, with a
+    /// record of the backward steps it absorbed.
+    ///
+    /// While the underlying clock is behind, the latest time seen is repeated
+    /// until it catches up, so expiry decisions made earlier are never undone.
+    pub fn monotonic(&self) -> (Clock, Arc<BackwardSteps>) {
+        let source = self.clone();
+        let steps = Arc::new(BackwardSteps::default());
+        let seen = Arc::clone(&steps);
+        // (latest time returned, latest raw reading)
+        let readings: Mutex<Option<(SystemTime, SystemTime)>> = Mutex::new(None);
+        let clock = Clock::new(move || {
+            let raw = source.now();
+            let mut readings = readings.lock();
+            let latest = match *readings {
+                Some((latest, previous_raw)) => {
+                    if let Ok(step) = previous_raw.duration_since(raw)
+                        && step > BACKWARD_STEP_THRESHOLD
+                    {
+                        seen.record(step);
+                    }
+                    latest.max(raw)
+                }
+                None => raw,
+            };
+            *readings = Some((latest, raw));
+            latest
+        });
+        (clock, steps)
+    }
+}
+
+/// Backward steps of the wall clock detected by a [`Clock::monotonic`] clock.
+///
+/// Only steps larger than [`BACKWARD_STEP_THRESHOLD`] are counted.
+#[derive(Debug, Default)]
+pub struct BackwardSteps {
+    count: AtomicU64,
+    largest_nanos: AtomicU64,
+}
+
+impl BackwardSteps {
+    /// Returns how many backward steps were detected.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the largest backward step detected.
+    pub fn largest(&self) -> Duration {
+        Duration::from_nanos(self.largest_nanos.load(Ordering::Relaxed))
+    }
+
+    fn record(&self, step: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.largest_nanos
+            .fetch_max(step.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// Clock that only moves when told to, for tests and simulations.
+///
+/// Clones share the same time, so a handle kept by the caller steers every
+/// engine built from [`ManualClock::clock`].
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<SystemTime>>);
+
+impl ManualClock {
+    /// Creates a clock stopped at `start`.
+    pub fn new(start: SystemTime) -> Self {
+        Self(Arc::new(Mutex::new(start)))
+    }
+
+    /// Returns the time the clock is stopped at.
+    pub fn now(&self) -> SystemTime {
+        *self.0.lock()
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.0.lock();
+        *now += by;
+    }
+
+    /// Moves the clock back by `by`, as an NTP step might.
+    pub fn rewind(&self, by: Duration) {
+        let mut now = self.0.lock();
+        *now -= by;
+    }
+
+    /// Returns a [`Clock`] reading this manual clock.
+    pub fn clock(&self) -> Clock {
+        let shared = self.clone();
+        Clock::new(move || shared.now())
+    }
+}
@@ -1,22 +1,187 @@
 //! Configuration helpers for CrabKv.
 
+use crate::background::DEFAULT_BACKGROUND_ERROR_WINDOW;
+use crate::clock::Clock;
+use crate::events::DEFAULT_EVENT_CAPACITY;
+use crate::idempotency::{DEFAULT_IDEMPOTENCY_CAPACITY, DEFAULT_IDEMPOTENCY_WINDOW};
+use crate::keys::KeyPolicy;
+use crate::pressure::PressureThresholds;
+use crate::stats::DEFAULT_HISTOGRAM_LIMIT;
+use crate::utf8::Utf8Policy;
+use std::fmt;
+use std::io::{self, ErrorKind};
 use std::num::NonZeroUsize;
 use std::time::Duration;
 
+/// Value bytes a compaction window holds unless
+/// [`compaction_buffer_bytes`](crate::CrabKvBuilder::compaction_buffer_bytes)
+/// says otherwise.
+pub const DEFAULT_COMPACTION_BUFFER_BYTES: usize = 8 * 1024 * 1024;
+
+/// Least time between two checkpoints unless
+/// [`checkpoint_min_interval`](crate::CrabKvBuilder::checkpoint_min_interval)
+/// says otherwise.
+pub const DEFAULT_CHECKPOINT_MIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Log bytes a background scrub reads per second unless
+/// [`scrub_bytes_per_sec`](crate::CrabKvBuilder::scrub_bytes_per_sec) says
+/// otherwise.
+pub const DEFAULT_SCRUB_BYTES_PER_SEC: u64 = 8 * 1024 * 1024;
+
 /// Tunable parameters for the storage engine.
+///
+/// With the `config-file` feature it reads from and writes to TOML, with
+/// durations as `100ms` or `2h` and sizes as `64MiB`; missing keys keep their
+/// defaults and unknown ones are refused.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct EngineConfig {
     /// Maximum number of cached entries kept in memory.
     /// When absent, caching is disabled.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(skip_serializing_if = "Option::is_none")
+    )]
     pub cache_capacity: Option<NonZeroUsize>,
     /// Default time-to-live applied to writes when not explicitly provided.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
     pub default_ttl: Option<Duration>,
     /// Interval between WAL syncs; None means sync on every write.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
     pub sync_interval: Option<Duration>,
     /// Whether to compress values with Snappy before writing to WAL.
     pub compression: bool,
     /// Whether to enable write-back caching.
     pub write_back_cache: bool,
+    /// Maximum age of a cached entry before it is re-read from the WAL.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
+    pub cache_entry_ttl: Option<Duration>,
+    /// Minimum time between heuristic-triggered compactions.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
+    pub min_compaction_interval: Option<Duration>,
+    /// When writes start a compaction on their own.
+    pub compaction_policy: CompactionPolicy,
+    /// Puts and deletes after which the index is checkpointed again.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(skip_serializing_if = "Option::is_none")
+    )]
+    pub checkpoint_mutations: Option<u64>,
+    /// Log bytes past the last checkpoint after which the index is checkpointed again.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_size", skip_serializing_if = "Option::is_none")
+    )]
+    pub checkpoint_bytes: Option<u64>,
+    /// Minimum time between checkpoints the two limits above start.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::duration"))]
+    pub checkpoint_min_interval: Duration,
+    /// Checkpoint age past which health checks report the store degraded
+    /// while the log has grown since.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
+    pub checkpoint_max_age: Option<Duration>,
+    /// Whether records with unrecognised opcodes are skipped during replay.
+    pub skip_unknown_ops: bool,
+    /// What reads and compaction do with values that are not valid UTF-8.
+    pub utf8_policy: Utf8Policy,
+    /// How keys are compared; fixed when the store is created.
+    pub key_policy: KeyPolicy,
+    /// Extra attempts made when a write-back flush hits a transient WAL error.
+    pub flush_retries: u32,
+    /// Delay before the first flush retry; doubled after each further attempt.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::duration"))]
+    pub flush_retry_backoff: Duration,
+    /// Live-byte budgets per key prefix, as `(prefix, max_bytes)` pairs.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::quotas"))]
+    pub prefix_quotas: Vec<(String, u64)>,
+    /// Whether repeated values are stored once and shared between keys.
+    pub value_dedup: bool,
+    /// Free space below which puts are refused.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_size", skip_serializing_if = "Option::is_none")
+    )]
+    pub min_free_bytes: Option<u64>,
+    /// Age after which a record counts as expired, whatever its TTL.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
+    pub retention: Option<Duration>,
+    /// Source of the current time for write stamps, expiry, and retention.
+    #[cfg_attr(feature = "config-file", serde(skip))]
+    pub clock: Clock,
+    /// Values longer than this many bytes are stored in blob files.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_size", skip_serializing_if = "Option::is_none")
+    )]
+    pub blob_threshold: Option<usize>,
+    /// Index size above which stats skip the size histograms unless forced.
+    pub histogram_limit: usize,
+    /// Whether keys are kept ordered by write time for `modified_since`.
+    pub track_modifications: bool,
+    /// Whether the index keeps keys sorted, for `CrabKv::range`.
+    pub ordered_index: bool,
+    /// Workers compaction decodes and re-encodes records on.
+    pub compaction_threads: usize,
+    /// Stored value bytes compaction holds in memory at once while copying.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::size"))]
+    pub compaction_buffer_bytes: usize,
+    /// Events the in-memory event ring holds.
+    pub event_capacity: usize,
+    /// How long an idempotency token turns repeats of its write into no-ops.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::duration"))]
+    pub idempotency_window: Duration,
+    /// Idempotency tokens remembered at once; the oldest are forgotten first.
+    pub idempotency_capacity: usize,
+    /// How long an error reported by a background worker keeps health checks
+    /// reporting the store degraded.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::duration"))]
+    pub background_error_window: Duration,
+    /// Time between background scrubs of the log; None disables them.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
+    pub scrub_interval: Option<Duration>,
+    /// Log bytes a background scrub reads per second; 0 means no limit.
+    #[cfg_attr(feature = "config-file", serde(with = "humane::size"))]
+    pub scrub_bytes_per_sec: u64,
+    /// Time between background sweeps removing expired keys; None leaves
+    /// them to reads, writes and compaction.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(with = "humane::opt_duration", skip_serializing_if = "Option::is_none")
+    )]
+    pub ttl_sweep_interval: Option<Duration>,
+    /// Whether a non-empty directory holding no store may be opened.
+    pub allow_existing_dir: bool,
+    /// Store id the directory's manifest must name, if any.
+    #[cfg_attr(
+        feature = "config-file",
+        serde(skip_serializing_if = "Option::is_none")
+    )]
+    pub expected_store_id: Option<String>,
 }
 
 impl EngineConfig {
@@ -34,18 +199,503 @@ impl EngineConfig {
             sync_interval,
             compression,
             write_back_cache,
+            cache_entry_ttl: None,
+            min_compaction_interval: None,
+            compaction_policy: CompactionPolicy::default(),
+            checkpoint_mutations: None,
+            checkpoint_bytes: None,
+            checkpoint_min_interval: DEFAULT_CHECKPOINT_MIN_INTERVAL,
+            checkpoint_max_age: None,
+            skip_unknown_ops: false,
+            utf8_policy: Utf8Policy::Error,
+            key_policy: KeyPolicy::Raw,
+            flush_retries: 0,
+            flush_retry_backoff: Duration::ZERO,
+            prefix_quotas: Vec::new(),
+            value_dedup: false,
+            min_free_bytes: None,
+            retention: None,
+            clock: Clock::system(),
+            blob_threshold: None,
+            histogram_limit: DEFAULT_HISTOGRAM_LIMIT,
+            track_modifications: false,
+            ordered_index: false,
+            compaction_threads: 1,
+            compaction_buffer_bytes: DEFAULT_COMPACTION_BUFFER_BYTES,
+            event_capacity: DEFAULT_EVENT_CAPACITY,
+            idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
+            idempotency_capacity: DEFAULT_IDEMPOTENCY_CAPACITY,
+            background_error_window: DEFAULT_BACKGROUND_ERROR_WINDOW,
+            scrub_interval: None,
+            scrub_bytes_per_sec: DEFAULT_SCRUB_BYTES_PER_SEC,
+            ttl_sweep_interval: None,
+            allow_existing_dir: false,
+            expected_store_id: None,
         }
     }
 }
 
+/// Same as [`EngineConfig::new`] with every option off, which is also what
+/// [`CrabKvBuilder::new`](crate::CrabKvBuilder::new) starts from.
 impl Default for EngineConfig {
     fn default() -> Self {
+        Self::new(None, None, None, false, false)
+    }
+}
+
+impl EngineConfig {
+    /// Every setting but the clock, by name, formatted as a config file
+    /// would hold it; `-` marks an unset option.
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let duration = |value: Option<Duration>| value.map_or_else(|| "-".into(), format_duration);
+        let size = |value: Option<u64>| value.map_or_else(|| "-".into(), format_size);
+        let quotas: Vec<String> = self
+            .prefix_quotas
+            .iter()
+            .map(|(prefix, bytes)| format!("{prefix:?}={}", format_size(*bytes)))
+            .collect();
+        vec![
+            (
+                "cache_capacity",
+                self.cache_capacity
+                    .map_or_else(|| "-".into(), |capacity| capacity.to_string()),
+            ),
+            ("default_ttl", duration(self.default_ttl)),
+            ("sync_interval", duration(self.sync_interval)),
+            ("compression", self.compression.to_string()),
+            ("write_back_cache", self.write_back_cache.to_string()),
+            ("cache_entry_ttl", duration(self.cache_entry_ttl)),
+            (
+                "min_compaction_interval",
+                duration(self.min_compaction_interval),
+            ),
+            ("compaction_policy", self.compaction_policy.to_string()),
+            (
+                "checkpoint_mutations",
+                self.checkpoint_mutations
+                    .map_or_else(|| "-".into(), |mutations| mutations.to_string()),
+            ),
+            ("checkpoint_bytes", size(self.checkpoint_bytes)),
+            (
+                "checkpoint_min_interval",
+                format_duration(self.checkpoint_min_interval),
+            ),
+            ("checkpoint_max_age", duration(self.checkpoint_max_age)),
+            ("skip_unknown_ops", self.skip_unknown_ops.to_string()),
+            ("utf8_policy", self.utf8_policy.to_string()),
+            ("key_policy", self.key_policy.to_string()),
+            ("flush_retries", self.flush_retries.to_string()),
+            (
+                "flush_retry_backoff",
+                format_duration(self.flush_retry_backoff),
+            ),
+            ("prefix_quotas", format!("[{}]", quotas.join(","))),
+            ("value_dedup", self.value_dedup.to_string()),
+            ("min_free_bytes", size(self.min_free_bytes)),
+            ("retention", duration(self.retention)),
+            (
+                "blob_threshold",
+                size(self.blob_threshold.map(|bytes| bytes as u64)),
+            ),
+            ("histogram_limit", self.histogram_limit.to_string()),
+            ("track_modifications", self.track_modifications.to_string()),
+            ("ordered_index", self.ordered_index.to_string()),
+            ("compaction_threads", self.compaction_threads.to_string()),
+            (
+                "compaction_buffer_bytes",
+                format_size(self.compaction_buffer_bytes as u64),
+            ),
+            ("event_capacity", self.event_capacity.to_string()),
+            (
+                "idempotency_window",
+                format_duration(self.idempotency_window),
+            ),
+            (
+                "idempotency_capacity",
+                self.idempotency_capacity.to_string(),
+            ),
+            (
+                "background_error_window",
+                format_duration(self.background_error_window),
+            ),
+            ("scrub_interval", duration(self.scrub_interval)),
+            ("scrub_bytes_per_sec", format_size(self.scrub_bytes_per_sec)),
+            ("ttl_sweep_interval", duration(self.ttl_sweep_interval)),
+            ("allow_existing_dir", self.allow_existing_dir.to_string()),
+            (
+                "expected_store_id",
+                self.expected_store_id.clone().unwrap_or_else(|| "-".into()),
+            ),
+        ]
+    }
+
+    /// Lists the settings that differ between `self` and `other`, in field
+    /// order, with `self` as the old value. The clock is not compared.
+    pub fn diff(&self, other: &EngineConfig) -> Vec<ConfigChange> {
+        self.settings()
+            .into_iter()
+            .zip(other.settings())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, old), (_, new))| ConfigChange { field, old, new })
+            .collect()
+    }
+
+    /// Parses a TOML config file.
+    #[cfg(feature = "config-file")]
+    pub fn from_toml(text: &str) -> io::Result<Self> {
+        toml::from_str(text).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err.to_string()))
+    }
+
+    /// Formats the configuration as a TOML config file.
+    #[cfg(feature = "config-file")]
+    pub fn to_toml(&self) -> io::Result<String> {
+        toml::to_string(self).map_err(io::Error::other)
+    }
+}
+
+/// Formats as `name=value` pairs separated by spaces, e.g.
+/// `cache_capacity=- default_ttl=1h sync_interval=100ms ...`, for startup logs.
+impl fmt::Display for EngineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (field, value)) in self.settings().into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{field}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// One setting [`EngineConfig::diff`] found changed, with both values
+/// formatted as a config file would hold them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+/// Formats as `sync_interval: - -> 100ms`.
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)
+    }
+}
+
+/// When writes start a compaction on their own, chosen with
+/// [`CrabKvBuilder::compaction_policy`](crate::CrabKvBuilder::compaction_policy).
+///
+/// The default compacts once stale records are a third of a log over 1 MiB,
+/// or more than 8 MiB. [`CrabKv::compact`](crate::CrabKv::compact) runs
+/// whatever the policy says.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct CompactionPolicy {
+    #[cfg_attr(
+        feature = "config-file",
+        serde(skip_serializing_if = "Option::is_none")
+    )]
+    every_n_mutations: Option<u64>,
+}
+
+impl CompactionPolicy {
+    /// Compacts after every `n` puts and deletes, counted from the start of
+    /// the last compaction, however little of the log is stale. Meant for
+    /// tests and small embedded stores; `n` of 0 counts as 1.
+    pub fn every_n_mutations(n: u64) -> Self {
         Self {
-            cache_capacity: None,
-            default_ttl: None,
-            sync_interval: None,
-            compression: false,
-            write_back_cache: false,
+            every_n_mutations: Some(n.max(1)),
+        }
+    }
+
+    /// Whether a log of `total_bytes`, `stale_bytes` of them stale, with
+    /// `mutations` since the last compaction started, should be compacted.
+    pub(crate) fn is_due(&self, total_bytes: u64, stale_bytes: u64, mutations: u64) -> bool {
+        match self.every_n_mutations {
+            Some(n) => mutations >= n,
+            None => crate::internals::compaction::should_compact(total_bytes, stale_bytes),
+        }
+    }
+}
+
+/// Formats as `stale_bytes` for the default, or `every_n_mutations=100`.
+impl fmt::Display for CompactionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.every_n_mutations {
+            Some(n) => write!(f, "every_n_mutations={n}"),
+            None => f.write_str("stale_bytes"),
+        }
+    }
+}
+
+/// Tunable parameters for the TCP server.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Number of worker threads serving connections.
+    pub workers: NonZeroUsize,
+    /// Accepted connections allowed to wait for a free worker before new ones are rejected.
+    pub queue_depth: usize,
+    /// Response bytes a connection may have queued before it is dropped.
+    pub max_output_bytes: usize,
+    /// Connections streaming events after `SUBSCRIBE`. Each gets its own
+    /// thread and gives its worker back; past this many, `SUBSCRIBE` is refused.
+    pub max_subscribers: usize,
+    /// Engine pressure at which writes are slowed down or refused.
+    pub pressure: PressureThresholds,
+    /// Delay added to each write while pressure is elevated.
+    pub elevated_write_delay: Duration,
+    /// Retry hint sent with writes refused under critical pressure.
+    pub busy_retry_after: Duration,
+    /// Sets `SO_REUSEPORT` on listeners (Unix only) so several server
+    /// processes can share a port and the kernel spreads connections among
+    /// them. CrabKv takes no lock on its data directory, so each process must
+    /// serve its own.
+    pub reuse_port: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            workers: NonZeroUsize::new(8).unwrap(),
+            queue_depth: 64,
+            max_output_bytes: 4 * 1024 * 1024,
+            max_subscribers: 64,
+            pressure: PressureThresholds::default(),
+            elevated_write_delay: Duration::from_millis(5),
+            busy_retry_after: Duration::from_millis(100),
+            reuse_port: false,
+        }
+    }
+}
+
+/// Parses a duration written as `90`, `90s`, `500ms`, `10m`, `2h`, or `7d`.
+///
+/// A bare number counts seconds. The WAL stores expiry times in whole seconds,
+/// so a sub-second part of a TTL only holds until the engine is reopened.
+pub fn parse_duration(input: &str) -> io::Result<Duration> {
+    let invalid = |reason: String| io::Error::new(ErrorKind::InvalidInput, reason);
+    let unit_start = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (digits, unit) = input.split_at(unit_start);
+    if digits.is_empty() {
+        return Err(invalid(format!(
+            "invalid duration `{input}`: expected a number with an optional unit (ms, s, m, h, d)"
+        )));
+    }
+    let millis_per_unit: u64 = match unit {
+        "ms" => 1,
+        "" | "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        other => {
+            return Err(invalid(format!(
+                "invalid duration `{input}`: unknown unit `{other}`, expected ms, s, m, h, or d"
+            )));
+        }
+    };
+    // Only digits remain, so parsing can fail on overflow alone.
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(millis_per_unit))
+        .map(Duration::from_millis)
+        .ok_or_else(|| invalid(format!("invalid duration `{input}`: too large")))
+}
+
+/// Formats a duration in the largest unit of `d`, `h`, `m`, `s` or `ms` that
+/// holds it exactly, e.g. `90s` or `2h`, so [`parse_duration`] reads it back.
+///
+/// Anything below a millisecond is dropped, as `parse_duration` reads none.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis == 0 {
+        return "0s".to_string();
+    }
+    for (unit, per_unit) in [
+        ("d", 86_400_000),
+        ("h", 3_600_000),
+        ("m", 60_000),
+        ("s", 1_000),
+    ] {
+        if millis.is_multiple_of(per_unit) {
+            return format!("{}{unit}", millis / per_unit);
+        }
+    }
+    format!("{millis}ms")
+}
+
+/// Parses a byte size written as `512`, `512B`, `4KiB`, `64MiB`, `2GiB`, or
+/// `1TiB`. A bare number counts bytes.
+pub fn parse_size(input: &str) -> io::Result<u64> {
+    let invalid = |reason: String| io::Error::new(ErrorKind::InvalidInput, reason);
+    let unit_start = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (digits, unit) = input.split_at(unit_start);
+    if digits.is_empty() {
+        return Err(invalid(format!(
+            "invalid size `{input}`: expected a number with an optional unit (B, KiB, MiB, GiB, TiB)"
+        )));
+    }
+    let shift = match unit {
+        "" | "B" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        "TiB" => 40,
+        other => {
+            return Err(invalid(format!(
+                "invalid size `{input}`: unknown unit `{other}`, expected B, KiB, MiB, GiB, or TiB"
+            )));
+        }
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(1 << shift))
+        .ok_or_else(|| invalid(format!("invalid size `{input}`: too large")))
+}
+
+/// Formats a byte size in the largest binary unit that holds it exactly, e.g.
+/// `64MiB` or `1000B`, so [`parse_size`] reads it back.
+pub fn format_size(bytes: u64) -> String {
+    if bytes == 0 {
+        return "0B".to_string();
+    }
+    for (unit, shift) in [("TiB", 40), ("GiB", 30), ("MiB", 20), ("KiB", 10)] {
+        if bytes.is_multiple_of(1 << shift) {
+            return format!("{}{unit}", bytes >> shift);
+        }
+    }
+    format!("{bytes}B")
+}
+
+/// Serde adapters writing durations and sizes the way people type them.
+#[cfg(feature = "config-file")]
+mod humane {
+    use super::{format_duration, format_size, parse_duration, parse_size};
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    pub mod duration {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            value: &Duration,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&format_duration(*value))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Duration, D::Error> {
+            let text = String::deserialize(deserializer)?;
+            parse_duration(&text).map_err(D::Error::custom)
+        }
+    }
+
+    pub mod opt_duration {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            value: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => serializer.serialize_some(&format_duration(*value)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            super::duration::deserialize(deserializer).map(Some)
+        }
+    }
+
+    /// Sizes held as `u64` or `usize`; the type only changes the range check.
+    pub mod size {
+        use super::*;
+
+        pub fn serialize<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: Copy + TryInto<u64>,
+        {
+            serializer.serialize_str(&format_size((*value).try_into().unwrap_or(u64::MAX)))
+        }
+
+        pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: TryFrom<u64>,
+        {
+            let text = String::deserialize(deserializer)?;
+            let bytes = parse_size(&text).map_err(D::Error::custom)?;
+            T::try_from(bytes).map_err(|_| D::Error::custom(format!("size `{text}` is too large")))
+        }
+    }
+
+    pub mod opt_size {
+        use super::*;
+
+        pub fn serialize<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: Copy + TryInto<u64>,
+        {
+            match value.map(|value| value.try_into().unwrap_or(u64::MAX)) {
+                Some(bytes) => serializer.serialize_some(&format_size(bytes)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: TryFrom<u64>,
+        {
+            super::size::deserialize(deserializer).map(Some)
+        }
+    }
+
+    /// Prefix quotas as a table of prefix to size, e.g. `"user:" = "64MiB"`.
+    pub mod quotas {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            value: &[(String, u64)],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(
+                value
+                    .iter()
+                    .map(|(prefix, bytes)| (prefix, format_size(*bytes))),
+            )
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<(String, u64)>, D::Error> {
+            BTreeMap::<String, String>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(prefix, size)| {
+                    parse_size(&size)
+                        .map(|bytes| (prefix, bytes))
+                        .map_err(D::Error::custom)
+                })
+                .collect()
         }
     }
 }
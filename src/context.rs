@@ -0,0 +1,89 @@
+//! Ids tying engine-side warnings and events to the request behind them.
+//!
+//! An [`OpContext`] is entered on the calling thread for the length of an
+//! operation. Listeners such as
+//! [`ThresholdListener`](crate::threshold::ThresholdListener) can read it with
+//! [`OpContext::current_id`]. The server enters a fresh context for every
+//! command and ends each `ERR` reply with `[id=<id>]`; library callers enter
+//! their own. Work on background threads, such
+//! as the async compaction worker, runs outside any context.
+
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Hex digits in a generated id.
+const GENERATED_ID_LEN: usize = 6;
+
+/// Identifies one operation, such as a server command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpContext {
+    id: String,
+}
+
+/// Keeps an [`OpContext`] entered until dropped, then restores the one that
+/// was entered before it.
+#[must_use = "the context is left as soon as the scope is dropped"]
+#[derive(Debug)]
+pub struct OpScope {
+    previous: Option<String>,
+    /// Scopes restore thread-local state, so they must not change threads.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl OpContext {
+    /// Uses a caller-chosen id, such as one from an upstream request header.
+    pub fn new(id: impl Into<String>) -> Self {
+        Self { id: id.into() }
+    }
+
+    /// Generates a short hex id, unique within the process for a long while
+    /// and unlikely to repeat across restarts.
+    pub fn generate() -> Self {
+        static SEED: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let mut hasher = SEED.build_hasher();
+        hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+        let id = format!("{:016x}", hasher.finish());
+        Self::new(&id[..GENERATED_ID_LEN])
+    }
+
+    /// Returns the id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Enters the context on this thread until the returned scope is dropped.
+    pub fn enter(&self) -> OpScope {
+        let previous = CURRENT.with(|current| current.replace(Some(self.id.clone())));
+        OpScope {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Returns the id of the context entered on this thread, if any.
+    pub fn current_id() -> Option<String> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Runs `f` with the context entered.
+    pub fn scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _scope = self.enter();
+        f()
+    }
+}
+
+impl Drop for OpScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
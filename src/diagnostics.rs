@@ -0,0 +1,386 @@
+//! Read-only checks of a data directory, used by `crabkv doctor` and the
+//! server's HEALTH command.
+//!
+//! No check writes to the directory, so they are safe to run against a store
+//! another process has open.
+
+use crate::internals::wal::{LoadedLog, Wal, WalEntry};
+use crate::manifest::{self, MANIFEST_FILE};
+use crate::quarantine::{self, QUARANTINE_FILE};
+use crate::wal_file::replay;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Free space below which the disk check warns.
+const LOW_DISK_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Stale share of the log above which the index check suggests compacting.
+const HIGH_STALE_RATIO: f64 = 0.5;
+
+/// Outcome of a single check, ordered from best to worst.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Ok => "OK",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+/// Result of one diagnostic check.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    /// What was found.
+    pub detail: String,
+    /// Suggested fix when the status is not [`Status::Ok`].
+    pub remedy: Option<String>,
+}
+
+impl Check {
+    pub fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            remedy: None,
+        }
+    }
+
+    pub fn warn(name: &'static str, detail: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            remedy: Some(remedy.into()),
+        }
+    }
+
+    pub fn fail(name: &'static str, detail: impl Into<String>, remedy: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            remedy: Some(remedy.into()),
+        }
+    }
+}
+
+/// Checks that only look at the directory listing: layout, the store
+/// manifest, leftover compaction files, and free disk space. Cheap enough for
+/// every HEALTH request.
+pub fn quick_checks(dir: &Path) -> Vec<Check> {
+    if !dir.is_dir() {
+        return vec![Check::fail(
+            "layout",
+            format!("{} is not a directory", dir.display()),
+            "point CRABKV_DATA_DIR or --data-dir at the store",
+        )];
+    }
+    vec![layout(dir), store(dir), leftovers(dir), disk(dir)]
+}
+
+/// Every check: the quick ones plus a dry replay of the log, index statistics,
+/// and blob file consistency.
+pub fn full_checks(dir: &Path, now: SystemTime) -> Vec<Check> {
+    let mut checks = quick_checks(dir);
+    if checks.iter().any(|check| check.status == Status::Fail) {
+        return checks;
+    }
+    let wal_path = dir.join("wal.log");
+    if !wal_path.exists() {
+        return checks;
+    }
+    match replay(&wal_path, now) {
+        Ok((wal, loaded, compressed)) => {
+            checks.push(verify_check(&wal, &loaded));
+            checks.push(format_check(&loaded, compressed));
+            checks.push(index_check(&loaded));
+            checks.push(blob_check(dir, &wal, &loaded));
+            checks.push(quarantine_check(dir));
+        }
+        Err(err) => checks.push(Check::fail(
+            "replay",
+            format!("the log cannot be replayed: {err}"),
+            "restore wal.log from a backup; records after the damage cannot be read",
+        )),
+    }
+    checks
+}
+
+fn layout(dir: &Path) -> Check {
+    let wal = match fs::metadata(dir.join("wal.log")) {
+        Ok(meta) => format!("wal.log {} bytes", meta.len()),
+        Err(err) if err.kind() == ErrorKind::NotFound => "no wal.log yet".to_string(),
+        Err(err) => {
+            return Check::fail(
+                "layout",
+                format!("cannot read wal.log: {err}"),
+                "check the permissions of the data directory",
+            );
+        }
+    };
+    match blob_files(dir) {
+        Ok(blobs) if blobs.is_empty() => Check::ok("layout", wal),
+        Ok(blobs) => {
+            let bytes: u64 = blobs.iter().map(|(_, len)| len).sum();
+            Check::ok(
+                "layout",
+                format!("{wal}, {} blob files {bytes} bytes", blobs.len()),
+            )
+        }
+        Err(err) => Check::fail(
+            "layout",
+            format!("cannot list blobs/: {err}"),
+            "check the permissions of the data directory",
+        ),
+    }
+}
+
+fn store(dir: &Path) -> Check {
+    let manifest = match manifest::read(dir) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            return Check::fail(
+                "store",
+                err.to_string(),
+                format!("restore {MANIFEST_FILE} from a backup, or delete it to record a new id"),
+            );
+        }
+    };
+    if let Some(manifest) = manifest {
+        return Check::ok("store", format!("store id {}", manifest.store_id));
+    }
+    let listing = manifest::holds_store(dir)
+        .and_then(|holds| Ok((holds, fs::read_dir(dir)?.next().is_none())));
+    match listing {
+        Ok((true, _)) => Check::ok(
+            "store",
+            format!("no {MANIFEST_FILE} yet; the next open records a store id"),
+        ),
+        Ok((false, true)) => Check::ok("store", "empty directory"),
+        Ok((false, false)) => Check::warn(
+            "store",
+            "the directory holds other files but no store",
+            "check the data dir path; creating a store here needs --force",
+        ),
+        Err(err) => Check::fail(
+            "store",
+            format!("cannot list the data directory: {err}"),
+            "check the permissions of the data directory",
+        ),
+    }
+}
+
+fn leftovers(dir: &Path) -> Check {
+    let wal = dir.join("wal.log").exists();
+    let backup = dir.join("wal.backup").exists();
+    let compact = dir.join("wal.compact").exists();
+    match (wal, backup, compact) {
+        (false, true, _) => Check::fail(
+            "leftovers",
+            "wal.log is missing but wal.backup exists; a compaction stopped between renames",
+            "rename wal.backup to wal.log, then delete wal.compact if present",
+        ),
+        (true, true, _) => Check::warn(
+            "leftovers",
+            "wal.backup left by an interrupted compaction",
+            "delete wal.backup; wal.log is complete",
+        ),
+        (_, false, true) => Check::warn(
+            "leftovers",
+            "wal.compact left by an interrupted compaction, or one is running",
+            "if no engine has the store open, delete wal.compact; wal.log is complete",
+        ),
+        _ => Check::ok("leftovers", "no compaction leftovers"),
+    }
+}
+
+fn disk(dir: &Path) -> Check {
+    match fs2::available_space(dir) {
+        Ok(free) if free < LOW_DISK_BYTES => Check::warn(
+            "disk",
+            format!("{free} bytes free"),
+            "free up space or compact; writes fail once the disk is full",
+        ),
+        Ok(free) => Check::ok("disk", format!("{free} bytes free")),
+        Err(err) => Check::warn(
+            "disk",
+            format!("cannot query free space: {err}"),
+            "check free space manually",
+        ),
+    }
+}
+
+fn verify_check(wal: &Wal, loaded: &LoadedLog) -> Check {
+    let size = match wal.size() {
+        Ok(size) => size,
+        Err(err) => {
+            return Check::fail(
+                "verify",
+                format!("cannot stat wal.log: {err}"),
+                "check the permissions of the data directory",
+            );
+        }
+    };
+    if size > loaded.valid_len {
+        return Check::warn(
+            "verify",
+            format!(
+                "{} torn bytes after the last complete record at offset {}",
+                size - loaded.valid_len,
+                loaded.valid_len
+            ),
+            "open the store once; the torn tail of an interrupted write is truncated on open",
+        );
+    }
+    Check::ok("verify", format!("{size} bytes of complete records"))
+}
+
+fn format_check(loaded: &LoadedLog, compressed: bool) -> Check {
+    let values = if compressed {
+        "Snappy-compressed values (open with compression enabled)"
+    } else {
+        "plain values"
+    };
+    if loaded.unknown_skipped > 0 {
+        return Check::warn(
+            "format",
+            format!(
+                "{values}; {} records with unknown opcodes",
+                loaded.unknown_skipped
+            ),
+            "upgrade CrabKv, or open with skip_unknown_ops to drop those records",
+        );
+    }
+    Check::ok("format", values)
+}
+
+fn index_check(loaded: &LoadedLog) -> Check {
+    let stale_ratio = if loaded.valid_len == 0 {
+        0.0
+    } else {
+        loaded.stale_bytes as f64 / loaded.valid_len as f64
+    };
+    let detail = format!(
+        "{} live keys, {} expired, {:.0}% stale",
+        loaded.index.len(),
+        loaded.expired_skipped,
+        stale_ratio * 100.0
+    );
+    if stale_ratio > HIGH_STALE_RATIO {
+        Check::warn("index", detail, "run `crabkv compact` to reclaim space")
+    } else {
+        Check::ok("index", detail)
+    }
+}
+
+fn blob_check(dir: &Path, wal: &Wal, loaded: &LoadedLog) -> Check {
+    let files = match blob_files(dir) {
+        Ok(files) => files,
+        Err(err) => {
+            return Check::fail(
+                "blobs",
+                format!("cannot list blobs/: {err}"),
+                "check the permissions of the data directory",
+            );
+        }
+    };
+    let on_disk: HashSet<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    let mut referenced = HashSet::new();
+    let mut missing = Vec::new();
+    for (key, entry) in &loaded.index {
+        if !entry.blob {
+            continue;
+        }
+        match wal.read_record(entry.pointer).map(|record| record.entry) {
+            Ok(WalEntry::Blob { blob, .. }) if on_disk.contains(blob.name.as_str()) => {
+                referenced.insert(blob.name);
+            }
+            _ => missing.push(key.as_str()),
+        }
+    }
+    if !missing.is_empty() {
+        missing.sort();
+        return Check::fail(
+            "blobs",
+            format!("{} keys point at missing blob files", missing.len()),
+            format!("delete the affected keys: {}", missing.join(", ")),
+        );
+    }
+    let orphans = on_disk.len() - referenced.len();
+    if orphans > 0 {
+        return Check::warn(
+            "blobs",
+            format!("{orphans} blob files no key references"),
+            "open the store once; orphaned blob files are removed on open",
+        );
+    }
+    Check::ok(
+        "blobs",
+        format!("{} blob files, all referenced", files.len()),
+    )
+}
+
+fn quarantine_check(dir: &Path) -> Check {
+    let keys = match quarantine::read(dir) {
+        Ok(keys) => keys,
+        Err(err) => {
+            return Check::fail(
+                "quarantine",
+                format!("cannot read {QUARANTINE_FILE}: {err}"),
+                format!("delete {QUARANTINE_FILE} to forget the quarantined keys"),
+            );
+        }
+    };
+    if keys.is_empty() {
+        return Check::ok("quarantine", "no quarantined keys");
+    }
+    let listed: Vec<String> = keys
+        .iter()
+        .map(|entry| format!("{} (offset {}: {})", entry.key, entry.offset, entry.error))
+        .collect();
+    Check::warn(
+        "quarantine",
+        format!(
+            "{} keys dropped by compaction for unreadable records: {}",
+            keys.len(),
+            listed.join(", ")
+        ),
+        "restore them from a backup, or call CrabKv::drop_quarantined to give up on them",
+    )
+}
+
+/// Lists blob files with their sizes; a missing `blobs/` directory is empty.
+fn blob_files(dir: &Path) -> io::Result<Vec<(String, u64)>> {
+    let entries = match fs::read_dir(dir.join("blobs")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.path().extension().is_none_or(|ext| ext != "blob") {
+            continue;
+        }
+        files.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            entry.metadata()?.len(),
+        ));
+    }
+    Ok(files)
+}
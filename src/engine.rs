@@ -1,18 +1,55 @@
 //! High-level storage engine orchestrating the in-memory index and WAL.
 
-use crate::cache::{Cache, CacheEntry};
-use crate::compaction;
-use crate::config::EngineConfig;
-use crate::index::ValuePointer;
-use crate::wal::{Wal, WalEntry};
-use std::collections::HashMap;
-use std::io::{self, ErrorKind};
+use crate::background::{self, BackgroundError, BackgroundErrors, BackgroundWorker, Backoff};
+use crate::changes::{Change, ChangeBatch, ChangeKind, HistoryTruncated, WatchStream};
+use crate::clock::{BackwardSteps, Clock};
+use crate::config::{CompactionPolicy, EngineConfig};
+use crate::events::{EngineEvent, EventKind, EventLog};
+use crate::expiry::{self, ExpiryQueue};
+use crate::idempotency::{SeenTokens, WriteOptions, WriteOutcome};
+use crate::internals::blob::{self, BlobStore};
+use crate::internals::cache::{Cache, CacheEntry, EvictionListener};
+use crate::internals::checkpoint::{self, Checkpoint, CheckpointEntry};
+use crate::internals::disk::{DiskGuard, FreeSpaceProbe};
+use crate::internals::index::{IndexCursor, KeyIndex, ValuePointer};
+use crate::internals::pattern;
+use crate::internals::wal::{
+    self, DamagedRecord, Generation, LiveRecord, LoadedEntry, LoadedLog, RetryableCompaction, Wal,
+    WalEntry,
+};
+use crate::keys::KeyPolicy;
+use crate::lock_file;
+use crate::manifest::{self, EpochMismatch, Manifest};
+use crate::mode::{Activity, EngineMode, ModeGate, ModeRefused};
+use crate::modifications::{Modification, ModificationIndex};
+use crate::pressure::{PressureGauges, PressureLevel, PressureThresholds};
+use crate::quarantine::{Corrupted, Quarantine, QuarantinedKey};
+use crate::quota::{PrefixCounters, PrefixQuotas, PrefixUsage, QuotaExceeded};
+use crate::range::{KeyRange, RangeChunk};
+use crate::scrub::{SCRUB_STEP_BYTES, ScrubReport, Scrubber, Stepped};
+use crate::stats::{EngineStats, SizeHistogram, TtlHistogram};
+use crate::threshold::{Gauges, SoftLimitLevels, SoftLimits, ThresholdEvent, ThresholdListener};
+use crate::transform;
+use crate::utf8::{BinaryValue, InvalidUtf8, Utf8Policy};
+use crate::view::{ReadView, ViewInvalidated};
+use crate::wal_file::WalFile;
+use parking_lot::{Condvar, Mutex};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::marker::PhantomData;
 use std::num::NonZeroUsize;
+use std::ops::{Bound, Range, RangeBounds};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{self, Sender};
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::{Duration, SystemTime};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Concurrent key-value store with append-only persistence.
 #[derive(Clone)]
@@ -20,38 +57,772 @@ pub struct CrabKv {
     inner: Arc<RwLock<EngineState>>,
     config: EngineConfig,
     compaction_tx: Option<Sender<CompactionRequest>>,
+    compaction_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    disk_guard: Option<Arc<DiskGuard>>,
+    open_report: OpenReport,
+    changes: Arc<ChangeCounters>,
+    /// Bytes and operations billed to each quota prefix.
+    billing: Arc<PrefixCounters>,
+    compacting: Arc<AtomicBool>,
+    worker: Arc<CompactionWorker>,
+    clock_steps: Arc<BackwardSteps>,
+    /// Shared so a [`CrabKv::migrate_to`] on one handle moves every clone.
+    directory: Arc<Mutex<PathBuf>>,
+    soft_limits: Arc<SoftLimits>,
+    /// Latest pressure readings, reported while a writer holds the state lock.
+    pressure: Arc<Mutex<PressureGauges>>,
+    /// Also held by the state; read from here so it never waits on the lock.
+    events: Arc<EventLog>,
+    /// Also held by the state, for [`CompactionStatus::peak_buffer_bytes`].
+    peak_buffer: Arc<AtomicU64>,
+    checkpoints: Arc<CheckpointProgress>,
+    /// From the directory's manifest; migration carries it along.
+    store_id: Arc<str>,
+    /// Fencing epoch from the manifest; only changed under the state write
+    /// lock, together with the manifest.
+    epoch: Arc<AtomicU64>,
+    /// Errors reported by the compaction worker, WAL flusher, checkpoint
+    /// writer, scrubber and TTL sweeper.
+    background: Arc<BackgroundErrors>,
+    /// Also held by the state; writers wait on it here without the lock.
+    mode: Arc<ModeGate>,
+    scrubber: Arc<Scrubber>,
+    /// Stops the scrub thread; dropping the last handle stops it too.
+    scrub_tx: Option<Sender<()>>,
+    scrub_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Stops the TTL sweeper; dropping the last handle stops it too.
+    sweep_tx: Option<Sender<()>>,
+    sweep_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Shared by every handle; whichever lets go of it last closes the store.
+    /// Only `None` in a handle being closed.
+    last_handle: Option<Arc<()>>,
 }
 
+impl Drop for CrabKv {
+    /// Closes the store when the last handle goes, so buffered writes reach
+    /// the log and background threads stop. Failures can only be recorded in
+    /// `events.log`; [`CrabKv::close`] reports them.
+    fn drop(&mut self) {
+        let Some(token) = self.last_handle.take() else {
+            return;
+        };
+        if Arc::into_inner(token).is_some()
+            && let Err(err) = self.shut_down()
+        {
+            self.events.record(EventKind::CloseFailed {
+                error: err.to_string(),
+            });
+            let _ = self.events.save();
+        }
+    }
+}
+
+thread_local! {
+    /// Engine whose [`CrabKv::update_many`] closure is running on this thread,
+    /// identified by the address of its shared state.
+    static UPDATING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Marks this thread as running an `update_many` closure until dropped,
+/// restoring the previous mark so nested updates on other engines unwind.
+struct UpdatingGuard(usize);
+
+impl UpdatingGuard {
+    fn enter(id: usize) -> Self {
+        Self(UPDATING.with(|updating| updating.replace(id)))
+    }
+}
+
+impl Drop for UpdatingGuard {
+    fn drop(&mut self) {
+        UPDATING.with(|updating| updating.set(self.0));
+    }
+}
+
+thread_local! {
+    /// Engine, identified like `UPDATING`, and epoch that writes from this
+    /// thread are pinned to by [`CrabKv::pin_epoch`].
+    static PINNED_EPOCH: Cell<Option<(usize, u64)>> = const { Cell::new(None) };
+}
+
+/// Keeps the writes of this thread pinned by [`CrabKv::pin_epoch`] until
+/// dropped, then restores the pin that was in place before it.
+#[must_use = "the pin is lifted as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct EpochPin {
+    previous: Option<(usize, u64)>,
+    /// The pin is thread-local state, so the guard must not change threads.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for EpochPin {
+    fn drop(&mut self) {
+        PINNED_EPOCH.with(|pinned| pinned.set(self.previous));
+    }
+}
+
+/// Monotonic counters of user mutations since the engine was opened.
+#[derive(Debug, Default)]
+struct ChangeCounters {
+    mutations: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Bumped after each append to the log and each compaction, waking
+    /// [`WatchStream`]s.
+    appends: Mutex<u64>,
+    appended: Condvar,
+}
+
+impl ChangeCounters {
+    /// Wakes watchers waiting for the log to grow or be replaced.
+    fn wake_watchers(&self) {
+        *self.appends.lock() += 1;
+        self.appended.notify_all();
+    }
+}
+
+/// Index metadata for one sampled key, gathered without reading its value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeySample {
+    pub key: String,
+    /// Length of the stored value payload in bytes.
+    pub value_len: u32,
+    /// Total size of the log record holding the value.
+    pub record_len: u32,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Outcome of [`CrabKv::copy_prefix_to`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CopyReport {
+    /// Keys written to the destination.
+    pub keys_copied: usize,
+    /// Key and value bytes written to the destination.
+    pub bytes_copied: u64,
+    /// Keys left alone because the destination already held them.
+    pub conflicts_skipped: usize,
+    /// Copied keys that replaced a live destination key.
+    pub keys_overwritten: usize,
+}
+
+/// What [`CrabKv::plan_copy_prefix_to`] predicts a copy would do.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CopyPlan {
+    /// The report the copy would return if no key were rejected.
+    pub report: CopyReport,
+    /// Keys the destination would refuse; the real copy fails on the first of them.
+    pub issues: Vec<ValidationIssue>,
+}
+
+/// A write [`CrabKv::validate_batch`] expects the store to refuse.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValidationIssue {
+    pub key: String,
+    pub problem: ValidationProblem,
+}
+
+/// Why a write would be refused.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValidationProblem {
+    /// The write would push a prefix over its quota.
+    QuotaExceeded(QuotaExceeded),
+    /// The key or value is longer than a log record can describe.
+    TooLarge { bytes: u64 },
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            ValidationProblem::QuotaExceeded(err) => write!(f, "{}: {err}", self.key),
+            ValidationProblem::TooLarge { bytes } => {
+                write!(
+                    f,
+                    "{}: {bytes} bytes is too large for a log record",
+                    self.key
+                )
+            }
+        }
+    }
+}
+
+impl From<ValidationIssue> for io::Error {
+    fn from(issue: ValidationIssue) -> Self {
+        match issue.problem {
+            ValidationProblem::QuotaExceeded(err) => err.into(),
+            ValidationProblem::TooLarge { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, issue.to_string())
+            }
+        }
+    }
+}
+
+/// Receives each chunk of a copy, so a dry run walks exactly the real path.
+trait CopySink {
+    /// Checks a chunk against the destination and, unless this is a dry run,
+    /// writes it. Returns the issues a dry run found.
+    fn accept(
+        &mut self,
+        dest: &CrabKv,
+        entries: Vec<(String, String, Option<SystemTime>)>,
+    ) -> io::Result<Vec<ValidationIssue>>;
+}
+
+/// Writes every chunk, failing on the first one with an issue.
+struct WriteSink;
+
+impl CopySink for WriteSink {
+    fn accept(
+        &mut self,
+        dest: &CrabKv,
+        entries: Vec<(String, String, Option<SystemTime>)>,
+    ) -> io::Result<Vec<ValidationIssue>> {
+        let issues = {
+            let state = dest
+                .inner
+                .read()
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+            let mut quotas = state.quotas.clone();
+            state.validate_entries(&mut quotas, &entries, dest.config.clock.now())?
+        };
+        if let Some(issue) = issues.into_iter().next() {
+            return Err(issue.into());
+        }
+        dest.put_batch_expiring(entries)?;
+        Ok(Vec::new())
+    }
+}
+
+/// Validates every chunk without writing, carrying quota usage across chunks
+/// as if the earlier ones had been written.
+struct DryRunSink {
+    quotas: Option<PrefixQuotas>,
+}
+
+impl CopySink for DryRunSink {
+    fn accept(
+        &mut self,
+        dest: &CrabKv,
+        entries: Vec<(String, String, Option<SystemTime>)>,
+    ) -> io::Result<Vec<ValidationIssue>> {
+        let state = dest
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let quotas = self.quotas.get_or_insert_with(|| state.quotas.clone());
+        state.validate_entries(quotas, &entries, dest.config.clock.now())
+    }
+}
+
+/// Puts and deletes applied as one atomic, durable unit by [`CrabKv::apply`].
+///
+/// Operations take effect in the order they were added, so a put followed by
+/// a delete of the same key leaves it absent.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum BatchOp {
+    Put {
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `value` under `key`, expiring after `ttl` when given.
+    pub fn put(mut self, key: String, value: String, ttl: Option<Duration>) -> Self {
+        self.ops.push(BatchOp::Put { key, value, ttl });
+        self
+    }
+
+    /// Removes `key`.
+    pub fn delete(mut self, key: String) -> Self {
+        self.ops.push(BatchOp::Delete { key });
+        self
+    }
+
+    /// Number of operations added so far.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Opaque position in the mutation stream returned by [`CrabKv::change_marker`].
+///
+/// It also records where the log ended, which [`CrabKv::watch_prefix`]
+/// replays from; that part stays valid across restarts until compaction
+/// rewrites the log.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ChangeMarker {
+    mutations: u64,
+    generation: u64,
+    offset: u64,
+}
+
+/// Position every accepted write must reach to survive a crash, returned by
+/// [`CrabKv::durability_marker`].
+///
+/// Formats as `<lsn>.<buffered>`: the WAL sequence number and the count of
+/// write-back puts at the time it was taken. Markers are only meaningful to the
+/// engine instance that issued them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DurabilityMarker {
+    lsn: u64,
+    buffered: u64,
+}
+
+impl fmt::Display for DurabilityMarker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.lsn, self.buffered)
+    }
+}
+
+impl FromStr for DurabilityMarker {
+    type Err = io::Error;
+
+    fn from_str(token: &str) -> io::Result<Self> {
+        let parsed = token
+            .split_once('.')
+            .and_then(|(lsn, buffered)| Some((lsn.parse().ok()?, buffered.parse().ok()?)));
+        match parsed {
+            Some((lsn, buffered)) => Ok(Self { lsn, buffered }),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("malformed durability token `{token}`"),
+            )),
+        }
+    }
+}
+
+/// Summary of the log replay performed when the engine was opened.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpenReport {
+    /// Keys loaded into the index.
+    pub live_keys: usize,
+    /// Stale bytes found in the log at open time.
+    pub stale_bytes: u64,
+    /// Put records left out of the index because they had already expired.
+    pub expired_skipped: u64,
+    /// Records with unrecognised opcodes stepped over in skip mode.
+    pub unknown_skipped: u64,
+    /// Bytes of an incomplete record or batch cut from the end of the log.
+    pub torn_bytes: u64,
+    /// Blob files no record referenced, left by a crash before their WAL append.
+    pub orphaned_blobs: usize,
+    /// Most memory opening the log held at once, the new index included;
+    /// estimated from map capacities, key lengths and batch sizes, not measured.
+    pub peak_memory_bytes: u64,
+    /// Log offset the index checkpoint the replay started from covered, or
+    /// `None` when there was no usable checkpoint and the whole log was read.
+    pub checkpoint_offset: Option<u64>,
+    /// Log bytes replayed: the whole log, or only what followed the checkpoint.
+    pub replayed_bytes: u64,
+}
+
+/// Keys read and written per batch by [`CrabKv::copy_prefix_to`].
+const COPY_CHUNK: usize = 256;
+
+/// Keys [`CrabKv::contains_many`] checks per read lock acquisition.
+const CONTAINS_CHUNK: usize = 4_096;
+
+/// How often an idle compaction worker records a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Heartbeat age after which an idle compaction worker counts as unresponsive.
+const HEARTBEAT_STALE: Duration = Duration::from_secs(10);
+
 enum CompactionRequest {
+    /// Compact if the stale-bytes heuristic still calls for it.
     Trigger,
-    #[allow(dead_code)]
+    /// Compact unconditionally on behalf of [`CrabKv::compact_and_wait`].
+    Forced,
     Shutdown,
 }
 
+/// How the last background compaction ended.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompactionOutcome {
+    Succeeded,
+    /// The rewrite failed with this error message; the old log is still in use.
+    Failed(String),
+}
+
+/// Health of the background compaction worker, from [`CrabKv::compaction_status`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompactionStatus {
+    /// Whether the worker thread is alive; always `false` without async compaction.
+    pub worker_running: bool,
+    /// Whether a compaction, foreground or background, is rewriting the log.
+    pub in_progress: bool,
+    /// When the worker's most recent run started.
+    pub last_started: Option<SystemTime>,
+    /// When the worker's most recent run finished.
+    pub last_finished: Option<SystemTime>,
+    pub last_outcome: Option<CompactionOutcome>,
+    /// Heuristic triggers queued for the worker; repeated triggers coalesce, so
+    /// this is 0 or 1.
+    pub pending_triggers: u64,
+    /// Time since the worker last checked in; `None` without async compaction.
+    pub heartbeat_age: Option<Duration>,
+    /// Most stored value bytes any compaction since open held in memory at
+    /// once; stays near [`CrabKvBuilder::compaction_buffer_bytes`].
+    pub peak_buffer_bytes: u64,
+}
+
+impl CompactionStatus {
+    /// Returns why the worker looks unhealthy, or `None` if it is fine or absent.
+    ///
+    /// A worker busy compacting skips heartbeats, so only an idle one can be
+    /// reported unresponsive.
+    pub fn degraded(&self) -> Option<&'static str> {
+        let age = self.heartbeat_age?;
+        if !self.worker_running {
+            Some("compaction worker stopped")
+        } else if !self.in_progress && age > HEARTBEAT_STALE {
+            Some("compaction worker unresponsive")
+        } else {
+            None
+        }
+    }
+}
+
+/// Progress of the async compaction worker, shared by every handle and the worker.
+#[derive(Default)]
+struct CompactionWorker {
+    progress: Mutex<WorkerProgress>,
+    /// Signalled whenever a run finishes or the worker exits.
+    finished: Condvar,
+    /// Set while a heuristic trigger sits in the channel, so repeats are dropped.
+    trigger_pending: AtomicBool,
+}
+
+/// What starting a compaction takes, for threads that hold the state but no
+/// handle.
+struct CompactionTrigger {
+    min_interval: Option<Duration>,
+    tx: Option<Sender<CompactionRequest>>,
+    worker: Arc<CompactionWorker>,
+}
+
+impl CompactionTrigger {
+    fn start_if_due(&self, state: &mut EngineState) -> io::Result<bool> {
+        start_compaction_if_due(state, self.min_interval, self.tx.as_ref(), &self.worker)
+    }
+}
+
+/// Evaluates the compaction policy and starts a compaction if it is due,
+/// returning whether one was started.
+fn start_compaction_if_due(
+    state: &mut EngineState,
+    min_interval: Option<Duration>,
+    tx: Option<&Sender<CompactionRequest>>,
+    worker: &CompactionWorker,
+) -> io::Result<bool> {
+    if !CrabKv::compaction_due(state, min_interval) {
+        return Ok(false);
+    }
+    // A frozen store remembers it and compacts once unfrozen.
+    if state.mode.admit(Activity::Compaction).is_err() {
+        return Ok(false);
+    }
+    if let Some(tx) = tx {
+        if !worker.trigger_pending.swap(true, Ordering::Relaxed) {
+            let _ = tx.send(CompactionRequest::Trigger);
+        }
+    } else {
+        CrabKv::run_compaction(state)?;
+    }
+    Ok(true)
+}
+
+#[derive(Default)]
+struct WorkerProgress {
+    running: bool,
+    heartbeat: Option<Instant>,
+    last_started: Option<SystemTime>,
+    last_finished: Option<SystemTime>,
+    last_outcome: Option<CompactionOutcome>,
+    /// Forced runs requested so far; a forced run covers every earlier request.
+    requested: u64,
+    /// Forced runs known to have completed.
+    completed: u64,
+}
+
+/// Marks the worker stopped when its thread exits, including by unwinding.
+struct WorkerExit<'a>(&'a CompactionWorker);
+
+impl Drop for WorkerExit<'_> {
+    fn drop(&mut self) {
+        self.0.progress.lock().running = false;
+        self.0.finished.notify_all();
+    }
+}
+
+/// Index checkpoints written since open, shared by every handle and the
+/// thread writing them.
+struct CheckpointProgress {
+    /// User puts and deletes since the last checkpoint was taken.
+    mutations: AtomicU64,
+    /// Set while a background checkpoint is being written, so triggers do not pile up.
+    writing: AtomicBool,
+    last: Mutex<CheckpointMark>,
+    /// Thread writing the latest background checkpoint, joined on close.
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// Failed background checkpoints in a row, which push back the next one.
+    backoff: Mutex<Backoff>,
+}
+
+#[derive(Clone, Copy)]
+struct CheckpointMark {
+    /// When the latest checkpoint was taken, including one loaded on open.
+    taken_at: Option<SystemTime>,
+    /// When the latest checkpoint was started since open, for the minimum interval.
+    started: Option<Instant>,
+    /// When the engine opened, which the age counts from before any checkpoint.
+    opened_at: SystemTime,
+    /// Earliest time a background checkpoint may be tried after one failed.
+    retry_at: Option<Instant>,
+}
+
+impl CheckpointProgress {
+    fn new(taken_at: Option<SystemTime>, opened_at: SystemTime) -> Self {
+        Self {
+            mutations: AtomicU64::new(0),
+            writing: AtomicBool::new(false),
+            last: Mutex::new(CheckpointMark {
+                taken_at,
+                started: None,
+                opened_at,
+                retry_at: None,
+            }),
+            thread: Mutex::new(None),
+            backoff: Mutex::default(),
+        }
+    }
+}
+
+/// Index checkpoints and the replay a crash would cost, from
+/// [`CrabKv::checkpoint_status`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CheckpointStatus {
+    /// When the latest checkpoint was taken, including one loaded on open.
+    pub last_taken: Option<SystemTime>,
+    /// Time since the latest checkpoint, or since open before there was one.
+    pub age: Duration,
+    /// Log bytes past the latest checkpoint or compaction, which reopening
+    /// after a crash would replay.
+    pub uncovered_bytes: u64,
+    /// Whether a checkpoint is being written.
+    pub in_progress: bool,
+    /// The configured [`CrabKvBuilder::checkpoint_max_age`].
+    pub max_age: Option<Duration>,
+}
+
+impl CheckpointStatus {
+    /// Returns why the checkpoints look unhealthy, or `None` if they are fine
+    /// or no maximum age is configured.
+    ///
+    /// A store nobody wrote to since its last checkpoint is fine however old
+    /// the checkpoint is.
+    pub fn degraded(&self) -> Option<&'static str> {
+        let max_age = self.max_age?;
+        (self.uncovered_bytes > 0 && self.age > max_age).then_some("index checkpoint too old")
+    }
+}
+
 /// Builder used to configure the storage engine before opening it.
 #[derive(Clone, Debug)]
 pub struct CrabKvBuilder {
     directory: PathBuf,
-    cache_capacity: Option<NonZeroUsize>,
-    default_ttl: Option<Duration>,
-    sync_interval: Option<Duration>,
+    /// Engine settings the builder methods set; the rest of the fields are
+    /// hooks and runtime choices a config file cannot express.
+    config: EngineConfig,
     async_compaction: bool,
-    compression: bool,
-    write_back_cache: bool,
+    cache_eviction_listener: Option<EvictionListener>,
+    free_space_probe: FreeSpaceProbe,
+    soft_limits: SoftLimitLevels,
+    threshold_listener: Option<ThresholdListener>,
+}
+
+/// A value as read from the store, before [`CrabKv::get`] or
+/// [`CrabKv::get_bytes`] hands it out.
+#[derive(Clone, Debug)]
+enum StoredValue {
+    Text(String),
+    /// Stored with [`CrabKv::put_bytes`] and not valid UTF-8.
+    Binary(Vec<u8>),
+}
+
+impl StoredValue {
+    fn len(&self) -> u64 {
+        match self {
+            StoredValue::Text(value) => value.len() as u64,
+            StoredValue::Binary(value) => value.len() as u64,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct IndexEntry {
     pointer: ValuePointer,
     expires_at: Option<SystemTime>,
+    /// When the key was last written, if the record carries a write time.
+    written_at: Option<SystemTime>,
+    /// Length of the reference record when the key shares another key's record.
+    ref_len: Option<u32>,
+    /// Name of the blob file holding the value, when it is stored outside the log.
+    blob: Option<String>,
+    /// Log sequence number after the write that set this entry; 0 for entries
+    /// loaded at open.
+    lsn: u64,
+}
+
+impl IndexEntry {
+    fn new(
+        pointer: ValuePointer,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+    ) -> Self {
+        Self {
+            pointer,
+            expires_at,
+            written_at,
+            ref_len: None,
+            blob: None,
+            lsn: 0,
+        }
+    }
+
+    /// Bytes that become stale when the key stops pointing here.
+    ///
+    /// A shared record is counted by its owner only, even while references
+    /// still use it; compaction settles the difference.
+    fn owned_len(&self) -> u64 {
+        self.ref_len.unwrap_or(self.pointer.record_len) as u64
+    }
+
+    /// Builds the index from a loaded log, reading blob records for their file names.
+    fn from_loaded(
+        loaded: LoadedLog,
+        wal: &Wal,
+        ordered: bool,
+    ) -> io::Result<KeyIndex<IndexEntry>> {
+        let mut ref_lens = loaded.ref_lens;
+        // Built in place, so the loaded map and the index are the only copies.
+        let mut index = KeyIndex::with_capacity(ordered, loaded.index.len());
+        for (key, entry) in loaded.index {
+            let ref_len = ref_lens.remove(&key);
+            let blob = if entry.blob {
+                match wal.read_record(entry.pointer)?.entry {
+                    WalEntry::Blob { blob, .. } => Some(blob.name),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            let index_entry = IndexEntry {
+                pointer: entry.pointer,
+                expires_at: entry.expires_at,
+                written_at: entry.written_at,
+                ref_len,
+                blob,
+                lsn: 0,
+            };
+            index.insert(key, index_entry);
+        }
+        Ok(index)
+    }
 }
 
 struct EngineState {
-    index: HashMap<String, IndexEntry>,
-    wal: Wal,
+    index: KeyIndex<IndexEntry>,
+    /// Shared so a concurrent compaction can copy records without the state lock.
+    wal: Arc<Wal>,
+    /// Log generation the pointers in `index` refer to.
+    generation: u64,
     cache: Option<Cache>,
     stale_bytes: u64,
     total_bytes: u64,
+    last_write: Mutex<Option<SystemTime>>,
+    last_compaction: Option<Instant>,
+    compactions: u64,
+    compaction_policy: CompactionPolicy,
+    /// User puts and deletes since the last compaction started, buffered
+    /// ones included, for [`CompactionPolicy::every_n_mutations`].
+    mutations_since_compaction: AtomicU64,
+    /// Log length the latest checkpoint or compaction covers; a crash replays
+    /// the rest.
+    checkpointed_len: u64,
+    compacting: Arc<AtomicBool>,
+    quotas: PrefixQuotas,
+    expiry_subscribers: Mutex<Vec<Sender<String>>>,
+    /// Keys reads found expired, removed by the next writes.
+    expiry_queue: ExpiryQueue,
+    /// Value hash to the put record holding it, rebuilt from the log on open
+    /// and after each compaction.
+    dedup: HashMap<u64, ValuePointer>,
+    /// Puts accepted into the write-back buffer since open.
+    buffered_writes: AtomicU64,
+    /// Value of `buffered_writes` covered by the last successful flush.
+    flushed_writes: AtomicU64,
+    retention: Option<Duration>,
+    clock: Clock,
+    blobs: BlobStore,
+    /// Values longer than this many bytes are written to blob files.
+    blob_threshold: Option<usize>,
+    /// Blob files no longer referenced by the index, removed once the log
+    /// records superseding them are durable.
+    doomed_blobs: Mutex<Vec<String>>,
+    /// Log sequence number after the latest delete, for [`ReadView`] checks.
+    last_delete_lsn: u64,
+    /// Writes since open that moved a live key's expiry later or removed it.
+    ttl_extensions: u64,
+    /// Keys written or deleted while a concurrent compaction copies the log,
+    /// with the log sequence number of each change.
+    journal: Option<Vec<(u64, String)>>,
+    /// Idempotency tokens applied within their window.
+    seen_tokens: SeenTokens,
+    /// Tokens applied while a concurrent compaction copies the log.
+    token_journal: Option<Vec<String>>,
+    /// Stored value bytes a compaction window may hold.
+    compaction_buffer: usize,
+    /// Most value bytes any compaction since open held decoded at once; shared
+    /// so the compaction status reads it without the lock.
+    peak_buffer: Arc<AtomicU64>,
+    /// Held for the whole of a compaction; only one generation is written at a time.
+    rewrite_lock: Arc<Mutex<()>>,
+    /// Keys by latest write time, when modification tracking is enabled.
+    modifications: Option<ModificationIndex>,
+    /// Keys compaction dropped because their record could not be decoded.
+    quarantine: Quarantine,
+    events: Arc<EventLog>,
+    /// Consulted by compactions and checkpoints, which only hold the state.
+    mode: Arc<ModeGate>,
+    /// Shared with the handles, so a compaction can wake watchers reading
+    /// the generation it replaced.
+    changes: Arc<ChangeCounters>,
+}
+
+/// Resets a flag when dropped, including on early returns and unwinding.
+struct ClearOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for ClearOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
 impl CrabKv {
@@ -65,54 +836,174 @@ impl CrabKv {
         CrabKvBuilder::new(directory)
     }
 
+    /// Returns what was recovered from the log when this engine was opened.
+    pub fn open_report(&self) -> OpenReport {
+        self.open_report
+    }
+
     /// Flushes write-back cache entries to the WAL if enabled.
+    ///
+    /// Buffered writes are appended in the order they were made as a single
+    /// atomic batch: after a crash either all of them are durable or none are.
+    /// If the append fails, transient errors are retried as configured by
+    /// [`CrabKvBuilder::flush_retries`]; otherwise the writes are put back in the
+    /// buffer so a later flush can persist them.
     pub fn flush(&self) -> io::Result<()> {
         if !self.config.write_back_cache {
             return Ok(());
         }
 
-        let mut state = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
+        let mut backoff = self.config.flush_retry_backoff;
+        let mut retries_left = self.config.flush_retries;
+        loop {
+            let flushed = {
+                let mut state = self
+                    .inner
+                    .write()
+                    .map_err(|_| io::Error::other("engine poisoned"))?;
+                self.flush_locked(&mut state, retries_left)
+            };
+            match flushed {
+                // Backed off without the lock, so reads and other writers go on.
+                Err(err) if retries_left > 0 && is_transient(&err) => {
+                    retries_left -= 1;
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                flushed => return flushed,
+            }
+        }
+    }
 
+    /// Appends the write-back buffer to the log under the write lock the
+    /// caller holds. On failure the writes go back in the buffer; a transient
+    /// one is returned as is while `retries_left` says the caller tries again.
+    fn flush_locked(&self, state: &mut EngineState, retries_left: u32) -> io::Result<()> {
         let cache = match &state.cache {
             Some(cache) => cache,
             None => return Ok(()),
         };
 
+        // An empty buffer flushes in any mode, so closing a frozen store works.
+        if cache.buffered_len() > 0 {
+            state.mode.admit(Activity::Flush)?;
+        }
+
+        // Buffered puts only happen under the read lock, so none can slip in here.
+        let flushing = state.buffered_writes.load(Ordering::Relaxed);
         let buffered = cache.flush_write_buffer();
         if buffered.is_empty() {
+            state.flushed_writes.store(flushing, Ordering::Relaxed);
             return Ok(());
         }
 
-        let entries: Vec<_> = buffered
-            .into_iter()
-            .map(|(key, entry)| (key, entry.value, None))
-            .collect();
-
-        let wal_entries: Vec<WalEntry> = entries
+        // Buffered writes are stamped with the time they become durable.
+        let written_at = Some(self.config.clock.now());
+        let entries: Vec<WalEntry> = buffered
             .iter()
-            .map(|(key, value, ttl)| {
-                let expires_at = ttl.and_then(|duration| SystemTime::now().checked_add(duration));
-                WalEntry::Put {
-                    key: key.clone(),
-                    value: value.clone(),
-                    expires_at,
-                }
+            .map(|(key, entry)| WalEntry::Put {
+                key: key.clone(),
+                value: entry.value.clone(),
+                expires_at: entry.expires_at,
+                written_at,
             })
             .collect();
 
-        let pointers = state.wal.append_batch(&wal_entries)?;
+        // Buffered puts were counted as mutations when they were accepted.
+        if let Err(err) = self.apply_batch(state, entries.clone(), false) {
+            let pending = buffered.len();
+            if let Some(cache) = &state.cache {
+                cache.restore_write_buffer(buffered);
+            }
+            if retries_left > 0 && is_transient(&err) {
+                return Err(err);
+            }
+            self.events.record(EventKind::FlushFailed {
+                pending,
+                error: err.to_string(),
+            });
+            return Err(io::Error::new(
+                err.kind(),
+                format!("flush failed, {pending} writes still pending: {err}"),
+            ));
+        }
+        state.flushed_writes.store(flushing, Ordering::Relaxed);
+        self.events.record(EventKind::Flushed {
+            writes: entries.len(),
+        });
+        self.maybe_compact_async(state);
+        Ok(())
+    }
+
+    /// Captures a position covering every write accepted so far, to pass to
+    /// [`CrabKv::wait_durable`].
+    pub fn durability_marker(&self) -> io::Result<DurabilityMarker> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(DurabilityMarker {
+            lsn: state.wal.lsn(),
+            buffered: state.buffered_writes.load(Ordering::Relaxed),
+        })
+    }
 
-        for (i, (key, _, _)) in entries.into_iter().enumerate() {
-            let pointer = pointers[i];
-            state.total_bytes += pointer.record_len as u64;
-            if let Some(previous) = state.index.get(&key) {
-                state.stale_bytes += previous.pointer.record_len as u64;
+    /// Blocks until every write accepted before `marker` was taken is flushed from
+    /// the write-back buffer and synced to disk, returning `Ok(true)` once it is.
+    ///
+    /// Rather than waiting for the next scheduled sync, this flushes and syncs
+    /// whatever the marker still needs. Transient failures are retried until
+    /// `timeout` elapses, after which `Ok(false)` is returned.
+    pub fn wait_durable(&self, marker: DurabilityMarker, timeout: Duration) -> io::Result<bool> {
+        let started = Instant::now();
+        let mut backoff = self.config.flush_retry_backoff;
+        loop {
+            match self.make_durable(marker) {
+                Ok(()) => return Ok(true),
+                Err(err) if is_transient(&err) => {
+                    let Some(left) = timeout.checked_sub(started.elapsed()) else {
+                        return Ok(false);
+                    };
+                    thread::sleep(backoff.min(left));
+                    backoff = backoff.saturating_mul(2);
+                }
+                Err(err) => return Err(err),
             }
         }
+    }
+
+    fn make_durable(&self, marker: DurabilityMarker) -> io::Result<()> {
+        let unflushed = {
+            let state = self
+                .inner
+                .read()
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+            state.flushed_writes.load(Ordering::Relaxed) < marker.buffered
+        };
+        if unflushed {
+            self.flush()?;
+        }
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        if state.wal.durable_lsn() < marker.lsn {
+            state.wal.sync()?;
+        }
+        Ok(())
+    }
 
+    /// Makes the next `count` WAL batch appends fail with a transient error.
+    ///
+    /// Only built with the `fault-injection` feature, which the crate's own
+    /// tests turn on.
+    #[cfg(feature = "fault-injection")]
+    pub fn fail_next_appends(&self, count: usize) -> io::Result<()> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        state.wal.fail_next_batches(count);
         Ok(())
     }
 
@@ -122,6 +1013,36 @@ impl CrabKv {
         self.put_with_ttl(key, value, ttl)
     }
 
+    /// Stores or updates a value of raw bytes, applying the default TTL when
+    /// configured.
+    ///
+    /// Bytes that are valid UTF-8 are stored as [`CrabKv::put`] stores a
+    /// string, so [`CrabKv::get`] reads them too. Anything else goes into a
+    /// binary record that [`CrabKv::get_bytes`] and [`CrabKv::multi_get_into`]
+    /// read and other reads refuse with a [`BinaryValue`] error. Binary
+    /// records are compressed like any value but skip the cache, the
+    /// write-back buffer, value dedup and blob files.
+    pub fn put_bytes(&self, key: String, value: Vec<u8>) -> io::Result<()> {
+        let key = self.owned_key(key);
+        let value = match String::from_utf8(value) {
+            Ok(value) => return self.put(key, value),
+            Err(err) => err.into_bytes(),
+        };
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let mut state = self.lock_for_write()?;
+        let now = self.config.clock.now();
+        let entry = WalEntry::Bytes {
+            key,
+            value,
+            expires_at: self.config.default_ttl.and_then(|ttl| now.checked_add(ttl)),
+            written_at: Some(now),
+        };
+        self.apply_batch(&mut state, vec![entry], true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
     /// Stores or updates a value using the provided TTL.
     pub fn put_with_ttl(
         &self,
@@ -129,278 +1050,3972 @@ impl CrabKv {
         value: String,
         ttl: Option<Duration>,
     ) -> io::Result<()> {
-        let expires_at = ttl.and_then(|duration| SystemTime::now().checked_add(duration));
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let key = self.owned_key(key);
+        let now = self.config.clock.now();
+        let expires_at = ttl.and_then(|duration| now.checked_add(duration));
 
         // If write-back cache is enabled, buffer in memory
-        if self.config.write_back_cache {
-            if let Ok(state) = self.inner.read() {
-                if let Some(cache) = &state.cache {
-                    cache.put(key, CacheEntry { value, expires_at });
-                    return Ok(());
-                }
+        if self.config.write_back_cache
+            && let Ok(state) = self.read_for_write()
+            && let Some(cache) = &state.cache
+        {
+            if state.quotas.covers(&key) {
+                state.check_quotas(&[WalEntry::Put {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires_at,
+                    written_at: Some(now),
+                }])?;
             }
+            cache.put(key, CacheEntry::new(value, expires_at));
+            state.buffered_writes.fetch_add(1, Ordering::Relaxed);
+            state.touch();
+            self.record_changes(&state, 1, 0);
+            self.check_soft_limits(&state);
+            return Ok(());
         }
 
-        let mut state = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
+        let mut state = self.lock_for_write()?;
         let entry = WalEntry::Put {
             key: key.clone(),
             value: value.clone(),
             expires_at,
+            written_at: Some(now),
         };
-        let pointer = state.wal.append(&entry)?;
-        state.total_bytes += pointer.record_len as u64;
+        state.check_quotas(std::slice::from_ref(&entry))?;
+        let entry = state.tier(entry)?;
+        let blob = match &entry {
+            WalEntry::Blob { blob, .. } => Some(blob.name.clone()),
+            _ => None,
+        };
+        let tiered = blob.is_some();
+        let shared = if self.config.value_dedup && !tiered {
+            state.find_shared(&value)?
+        } else {
+            None
+        };
+        let index_entry = match shared {
+            Some(target) => {
+                let reference = state.wal.append_ref(&key, target, expires_at, Some(now))?;
+                IndexEntry {
+                    pointer: target,
+                    expires_at,
+                    written_at: Some(now),
+                    ref_len: Some(reference.record_len),
+                    blob: None,
+                    lsn: 0,
+                }
+            }
+            None => {
+                let pointer = state.wal.append(&entry)?;
+                if self.config.value_dedup && !tiered {
+                    state.remember_value(&value, pointer);
+                }
+                IndexEntry {
+                    blob,
+                    ..IndexEntry::new(pointer, expires_at, Some(now))
+                }
+            }
+        };
+        let written = index_entry.owned_len();
+        state.total_bytes += written;
+        state.touch();
+        self.record_changes(&state, 1, written);
+        self.billing
+            .record_write(&key, (key.len() + value.len()) as u64);
 
-        if let Some(previous) = state.index.insert(
-            key.clone(),
-            IndexEntry {
-                pointer,
-                expires_at,
-            },
-        ) {
-            state.stale_bytes += previous.pointer.record_len as u64;
+        if let Some(previous) = state.index_insert(key.clone(), index_entry) {
+            state.stale_bytes += previous.owned_len();
         }
 
         if let Some(cache) = &state.cache {
-            cache.put(key, CacheEntry { value, expires_at });
+            // Blob values are read rarely and would crowd out small ones.
+            if tiered {
+                cache.remove(&key);
+            } else {
+                cache.put(key, CacheEntry::new(value, expires_at));
+            }
         }
 
-        self.maybe_compact_async(&mut state)
+        self.maybe_compact_async(&mut state);
+        Ok(())
     }
 
-    /// Stores multiple key-value pairs in a single batch for improved throughput.
+    /// Stores the value only if `key` has no live value, and returns whether
+    /// it did.
+    ///
+    /// The check and the append happen under one hold of the write lock, so of
+    /// several handles racing to insert the same key exactly one wins. An
+    /// expired key counts as absent; its record becomes stale like any
+    /// overwritten one. No TTL applies unless `ttl` is given.
+    pub fn put_if_absent(
+        &self,
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> io::Result<bool> {
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let key = self.owned_key(key);
+        let mut state = self.lock_for_write()?;
+        let now = self.config.clock.now();
+        let buffered = if self.config.write_back_cache
+            && let Some(cache) = &state.cache
+        {
+            cache.get_buffered(&key)
+        } else {
+            None
+        };
+        let live = match buffered {
+            Some(hit) => !Self::is_expired_at(hit.expires_at, now),
+            None => state
+                .index
+                .get(&key)
+                .is_some_and(|entry| !Self::is_expired_at(state.deadline(entry), now)),
+        };
+        if live {
+            return Ok(false);
+        }
+        let entry = WalEntry::Put {
+            key,
+            value,
+            expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+            written_at: Some(now),
+        };
+        self.apply_batch(&mut state, vec![entry], true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(true)
+    }
+
+    /// Adds `delta` to the integer stored under `key` and returns the result,
+    /// reading and writing under one hold of the write lock.
+    ///
+    /// A missing or expired key counts as 0 and takes the default TTL; an
+    /// existing one keeps its expiry. Fails with [`io::ErrorKind::InvalidData`]
+    /// when the value is not an `i64` or the sum would overflow, leaving the
+    /// value as it was.
+    pub fn incr(&self, key: &str, delta: i64) -> io::Result<i64> {
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let key = &*self.key(key);
+        let mut state = self.lock_for_write()?;
+        let now = self.config.clock.now();
+        let (current, expires_at) = match self.read_live(&state, key)? {
+            Some((value, expires_at)) => {
+                let current = value.parse::<i64>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the value of `{key}` is not an integer"),
+                    )
+                })?;
+                (current, expires_at)
+            }
+            None => (
+                0,
+                self.config.default_ttl.and_then(|ttl| now.checked_add(ttl)),
+            ),
+        };
+        let updated = current.checked_add(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("adding {delta} to `{key}` overflows"),
+            )
+        })?;
+        let entry = WalEntry::Put {
+            key: key.to_owned(),
+            value: updated.to_string(),
+            expires_at,
+            written_at: Some(now),
+        };
+        self.apply_batch(&mut state, vec![entry], true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(updated)
+    }
+
+    /// Sets the TTL of `key` to `ttl` from now, or removes its expiry when
+    /// `ttl` is `None`, and returns whether the key was live.
+    ///
+    /// The value is read through its pointer and appended again with the new
+    /// expiry under one hold of the write lock, so the client sends nothing
+    /// but the key; the previous record becomes stale. A missing or expired
+    /// key is left alone and `false` is returned.
+    pub fn expire(&self, key: &str, ttl: Option<Duration>) -> io::Result<bool> {
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let key = &*self.key(key);
+        let mut state = self.lock_for_write()?;
+        let Some((value, _)) = self.read_live(&state, key)? else {
+            return Ok(false);
+        };
+        let now = self.config.clock.now();
+        let entry = WalEntry::Put {
+            key: key.to_owned(),
+            value,
+            expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+            written_at: Some(now),
+        };
+        self.apply_batch(&mut state, vec![entry], true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(true)
+    }
+
+    /// Stores multiple key-value pairs in a single atomic batch for improved throughput.
     pub fn put_batch(&self, entries: Vec<(String, String, Option<Duration>)>) -> io::Result<()> {
-        if entries.is_empty() {
+        let now = self.config.clock.now();
+        let entries = entries
+            .into_iter()
+            .map(|(key, value, ttl)| (key, value, ttl.and_then(|ttl| now.checked_add(ttl))))
+            .collect();
+        self.put_batch_expiring(entries)
+    }
+
+    /// Applies every put and delete in `batch` with a single log append and
+    /// one fsync: after a crash either all of them survive or none do.
+    ///
+    /// The index and cache are updated in batch order. Puts skip the
+    /// write-back buffer, and their TTLs count from when the batch is applied.
+    /// A batch that would exceed a quota is refused as a whole.
+    pub fn apply(&self, batch: WriteBatch) -> io::Result<()> {
+        self.check_not_updating()?;
+        if batch.is_empty() {
             return Ok(());
         }
+        self.check_disk_space()?;
 
-        let mut state = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
+        let mut state = self.lock_for_write()?;
+        let now = self.config.clock.now();
+        let entries = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Put { key, value, ttl } => WalEntry::Put {
+                    key: self.owned_key(key),
+                    value,
+                    expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+                    written_at: Some(now),
+                },
+                BatchOp::Delete { key } => WalEntry::Delete {
+                    key: self.owned_key(key),
+                },
+            })
+            .collect();
+        self.apply_batch(&mut state, entries, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
+    /// Copies every live key starting with `prefix` into `dest`, keeping each
+    /// key's absolute expiry time.
+    ///
+    /// Keys are read and written in chunks, each chunk as one `dest` batch, so
+    /// the copy is not a point-in-time view of the source. Unless `overwrite`
+    /// is set, keys already live in `dest` are skipped and counted as conflicts.
+    /// Each chunk is checked with the same rules as [`CrabKv::validate_batch`]
+    /// before it is written, and the copy stops at the first chunk that fails.
+    pub fn copy_prefix_to(
+        &self,
+        prefix: &str,
+        dest: &CrabKv,
+        overwrite: bool,
+    ) -> io::Result<CopyReport> {
+        self.copy_prefix_into(prefix, dest, overwrite, &mut WriteSink)
+            .map(|plan| plan.report)
+    }
+
+    /// Predicts what [`CrabKv::copy_prefix_to`] would do without writing to `dest`.
+    ///
+    /// Runs the same scan, conflict handling and validation as the real copy,
+    /// so apart from writes made to either store in between, the report matches
+    /// what the copy returns and the issues are the keys it would fail on.
+    pub fn plan_copy_prefix_to(
+        &self,
+        prefix: &str,
+        dest: &CrabKv,
+        overwrite: bool,
+    ) -> io::Result<CopyPlan> {
+        self.copy_prefix_into(prefix, dest, overwrite, &mut DryRunSink { quotas: None })
+    }
+
+    fn copy_prefix_into(
+        &self,
+        prefix: &str,
+        dest: &CrabKv,
+        overwrite: bool,
+        sink: &mut dyn CopySink,
+    ) -> io::Result<CopyPlan> {
+        let prefix = &*self.key(prefix);
+        // Buffered writes are not in the index the scan walks.
+        self.flush()?;
+        let mut keys: Vec<String> = {
+            let state = self
+                .inner
+                .read()
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+            state
+                .index
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect()
+        };
+
+        keys.sort();
+        let mut plan = CopyPlan::default();
+        for chunk in keys.chunks(COPY_CHUNK) {
+            let mut entries = Vec::with_capacity(chunk.len());
+            {
+                let state = self
+                    .inner
+                    .read()
+                    .map_err(|_| io::Error::other("engine poisoned"))?;
+                for key in chunk {
+                    // Keys may expire or be deleted between the scan and the read.
+                    if let Some((value, expires_at)) = self.read_live(&state, key)? {
+                        entries.push((key.clone(), value, expires_at));
+                    }
+                }
+            }
+            {
+                let state = dest
+                    .inner
+                    .read()
+                    .map_err(|_| io::Error::other("engine poisoned"))?;
+                let before = entries.len();
+                if overwrite {
+                    plan.report.keys_overwritten += entries
+                        .iter()
+                        .filter(|(key, _, _)| dest.contains_live(&state, key))
+                        .count();
+                } else {
+                    entries.retain(|(key, _, _)| !dest.contains_live(&state, key));
+                    plan.report.conflicts_skipped += before - entries.len();
+                }
+            }
+            plan.report.keys_copied += entries.len();
+            plan.report.bytes_copied += entries
+                .iter()
+                .map(|(key, value, _)| (key.len() + value.len()) as u64)
+                .sum::<u64>();
+            plan.issues.extend(sink.accept(dest, entries)?);
+        }
+        Ok(plan)
+    }
 
-        let wal_entries: Vec<WalEntry> = entries
+    /// Reports the entries of a [`CrabKv::put_batch`] this store would refuse,
+    /// without writing anything.
+    ///
+    /// Checks prefix quotas, counting earlier entries of the batch as written,
+    /// and that each record fits the log format. An empty result means the
+    /// batch passes those checks now; other writers or a full disk can still
+    /// make the real write fail.
+    pub fn validate_batch(
+        &self,
+        entries: &[(String, String, Option<Duration>)],
+    ) -> io::Result<Vec<ValidationIssue>> {
+        let now = self.config.clock.now();
+        let entries: Vec<_> = entries
             .iter()
             .map(|(key, value, ttl)| {
-                let expires_at = ttl.and_then(|duration| SystemTime::now().checked_add(duration));
-                WalEntry::Put {
-                    key: key.clone(),
-                    value: value.clone(),
-                    expires_at,
-                }
+                let expires_at = ttl.and_then(|ttl| now.checked_add(ttl));
+                (self.owned_key(key.clone()), value.clone(), expires_at)
             })
             .collect();
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let mut quotas = state.quotas.clone();
+        state.validate_entries(&mut quotas, &entries, now)
+    }
 
-        let pointers = state.wal.append_batch(&wal_entries)?;
+    /// Like [`CrabKv::put_batch`], with absolute expiry times instead of TTLs.
+    fn put_batch_expiring(
+        &self,
+        entries: Vec<(String, String, Option<SystemTime>)>,
+    ) -> io::Result<()> {
+        self.check_not_updating()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.check_disk_space()?;
 
-        for (i, (key, value, ttl)) in entries.into_iter().enumerate() {
-            let pointer = pointers[i];
-            let expires_at = ttl.and_then(|duration| SystemTime::now().checked_add(duration));
-            state.total_bytes += pointer.record_len as u64;
+        let mut state = self.lock_for_write()?;
 
-            if let Some(previous) = state.index.insert(
-                key.clone(),
-                IndexEntry {
-                    pointer,
-                    expires_at,
-                },
-            ) {
-                state.stale_bytes += previous.pointer.record_len as u64;
+        let now = self.config.clock.now();
+        let wal_entries = entries
+            .into_iter()
+            .map(|(key, value, expires_at)| WalEntry::Put {
+                key: self.owned_key(key),
+                value,
+                expires_at,
+                written_at: Some(now),
+            })
+            .collect();
+
+        self.apply_batch(&mut state, wal_entries, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
+    /// Returns the value stored for the key if present and not expired.
+    ///
+    /// Fails with a [`BinaryValue`] error when the value was stored with
+    /// [`CrabKv::put_bytes`] and is not valid UTF-8.
+    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+        match self.get_stored(key)? {
+            Some(StoredValue::Text(value)) => Ok(Some(value)),
+            Some(StoredValue::Binary(_)) => Err(BinaryValue::error(key)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value stored for the key as bytes if present and not
+    /// expired, whether it was stored with [`CrabKv::put_bytes`] or as a string.
+    pub fn get_bytes(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.get_stored(key)?.map(|value| match value {
+            StoredValue::Text(value) => value.into_bytes(),
+            StoredValue::Binary(value) => value,
+        }))
+    }
+
+    fn get_stored(&self, key: &str) -> io::Result<Option<StoredValue>> {
+        self.check_not_updating()?;
+        let key = &*self.key(key);
+        let now = self.config.clock.now();
+        let value = self.skipping_invalid_utf8(|| match self.get_once(key, now) {
+            // The index the pointer came from was replaced mid-read; the fresh
+            // one points into the installed generation.
+            Err(err) if RetryableCompaction::is(&err) => self.get_once(key, now),
+            result => result,
+        })?;
+        self.billing
+            .record_read(key, value.as_ref().map_or(0, StoredValue::len));
+        Ok(value)
+    }
+
+    /// `key` as the [`KeyPolicy`] stores it; every call naming a key passes
+    /// it through here first.
+    fn key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        self.config.key_policy.apply(key)
+    }
+
+    fn owned_key(&self, key: String) -> String {
+        self.config.key_policy.apply_owned(key)
+    }
+
+    fn keys_of<'k>(&self, keys: &[&'k str]) -> Vec<Cow<'k, str>> {
+        keys.iter().map(|key| self.key(key)).collect()
+    }
+
+    fn bill_read(&self, key: &str, value: Option<&str>) {
+        self.billing
+            .record_read(key, value.map_or(0, |value| value.len() as u64));
+    }
+
+    /// Runs `read`, and under [`Utf8Policy::Skip`] quarantines each key it
+    /// fails on for an invalid UTF-8 value and runs it again.
+    fn skipping_invalid_utf8<T>(&self, mut read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match read() {
+                Err(err) if self.config.utf8_policy == Utf8Policy::Skip => {
+                    let Some(invalid) = InvalidUtf8::of(&err) else {
+                        return Err(err);
+                    };
+                    let mut state = self
+                        .inner
+                        .write()
+                        .map_err(|_| io::Error::other("engine poisoned"))?;
+                    // Reading again could only fail the same way.
+                    if !state.quarantine_invalid_utf8(&invalid.key)? {
+                        return Err(err);
+                    }
+                }
+                result => return result,
             }
+        }
+    }
 
-            if let Some(cache) = &state.cache {
-                cache.put(key, CacheEntry { value, expires_at });
+    /// Returns the value of each of `keys`, in the same order, under one hold
+    /// of the read lock.
+    ///
+    /// Values not in the cache are read from the log in offset order through a
+    /// single file handle. Expired keys come back as `None` and are queued for
+    /// removal like [`CrabKv::get`] does; a quarantined key fails the whole call.
+    pub fn get_many(&self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        self.check_not_updating()?;
+        let keys = self.keys_of(keys);
+        let keys: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        let keys = keys.as_slice();
+        let now = self.config.clock.now();
+        let values = self.skipping_invalid_utf8(|| match self.get_many_once(keys, now) {
+            Err(err) if RetryableCompaction::is(&err) => self.get_many_once(keys, now),
+            result => result,
+        })?;
+        for (key, value) in keys.iter().zip(&values) {
+            self.bill_read(key, value.as_deref());
+        }
+        Ok(values)
+    }
+
+    fn get_many_once(&self, keys: &[&str], now: SystemTime) -> io::Result<Vec<Option<String>>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let mut values = vec![None; keys.len()];
+        // Position in `keys` and index entry of each value left to read from the log.
+        let mut unread = Vec::new();
+        for (position, &key) in keys.iter().enumerate() {
+            if self.config.write_back_cache
+                && let Some(cache) = &state.cache
+                && let Some(hit) = cache.get_buffered(key)
+            {
+                if !Self::is_expired_at(hit.expires_at, now) {
+                    values[position] = Some(hit.value);
+                }
+                continue;
+            }
+            let Some(entry) = state.index.get(key) else {
+                if state.quarantine.contains(key) && self.config.utf8_policy != Utf8Policy::Skip {
+                    return Err(Corrupted::error(key));
+                }
+                continue;
+            };
+            if Self::is_expired_at(state.deadline(entry), now) {
+                if state.expiry_queue.push(key) {
+                    state.notify_expired(key);
+                }
+                continue;
+            }
+            if let Some(cache) = &state.cache
+                && let Some(hit) = cache.get(key)
+                && !Self::is_expired_at(hit.expires_at, now)
+            {
+                values[position] = Some(hit.value);
+                continue;
             }
+            unread.push((position, entry));
         }
 
-        self.maybe_compact_async(&mut state)
+        unread.sort_by_key(|(_, entry)| entry.pointer.offset);
+        let pointers: Vec<ValuePointer> = unread.iter().map(|(_, entry)| entry.pointer).collect();
+        let records = state
+            .wal
+            .read_records_in(state.generation, &pointers)
+            .map_err(|err| Self::naming_reader(err, keys, &unread, |entry| entry.pointer))?;
+        for ((position, entry), record) in unread.into_iter().zip(records) {
+            values[position] = match record.entry {
+                WalEntry::Put { value, .. } => {
+                    if let Some(cache) = &state.cache {
+                        cache.put(
+                            keys[position].to_owned(),
+                            CacheEntry::new(value.clone(), entry.expires_at),
+                        );
+                    }
+                    Some(value)
+                }
+                WalEntry::Bytes { key, .. } => return Err(BinaryValue::error(&key)),
+                WalEntry::Blob { blob, .. } => Some(state.blobs.read(&blob)?),
+                WalEntry::Delete { .. } | WalEntry::Token { .. } => None,
+            };
+        }
+        Ok(values)
     }
 
-    /// Returns the value stored for the key if present and not expired.
-    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+    /// Appends the value of each of `keys` to `arena` and returns the range of
+    /// `arena` it landed in, in the same order, under one hold of the read lock.
+    ///
+    /// For callers reading many values per request without an allocation per
+    /// value: cache hits are copied out of the cache, and misses are read from
+    /// the log in offset order, as [`CrabKv::get_many`] reads them, straight
+    /// into `arena`. Values read from the log are not added to the cache.
+    /// `arena` is only appended to, so a buffer cleared between calls stops
+    /// growing once it fits the largest request. Absent, expired and
+    /// quarantined keys behave as they do for `get_many`; on error `arena` is
+    /// left as it was.
+    pub fn multi_get_into(
+        &self,
+        keys: &[&str],
+        arena: &mut Vec<u8>,
+    ) -> io::Result<Vec<Option<Range<usize>>>> {
+        self.check_not_updating()?;
+        let keys = self.keys_of(keys);
+        let keys: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+        let keys = keys.as_slice();
+        let now = self.config.clock.now();
+        let start = arena.len();
+        let ranges = self.skipping_invalid_utf8(|| {
+            let mut read = || {
+                arena.truncate(start);
+                self.multi_get_into_once(keys, arena, now)
+            };
+            match read() {
+                Err(err) if RetryableCompaction::is(&err) => read(),
+                result => result,
+            }
+        });
+        let ranges = ranges.inspect_err(|_| arena.truncate(start))?;
+        for (key, range) in keys.iter().zip(&ranges) {
+            self.billing
+                .record_read(key, range.as_ref().map_or(0, |range| range.len() as u64));
+        }
+        Ok(ranges)
+    }
+
+    fn multi_get_into_once(
+        &self,
+        keys: &[&str],
+        arena: &mut Vec<u8>,
+        now: SystemTime,
+    ) -> io::Result<Vec<Option<Range<usize>>>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let mut ranges = vec![None; keys.len()];
+        // Position in `keys` and log pointer of each value left to read.
+        let mut unread = Vec::new();
+        for (position, &key) in keys.iter().enumerate() {
+            if self.config.write_back_cache
+                && let Some(cache) = &state.cache
+                && let Some(range) =
+                    cache.get_buffered_with(key, |hit| Self::append_live(hit, now, arena))
+            {
+                ranges[position] = range;
+                continue;
+            }
+            let Some(entry) = state.index.get(key) else {
+                if state.quarantine.contains(key) && self.config.utf8_policy != Utf8Policy::Skip {
+                    return Err(Corrupted::error(key));
+                }
+                continue;
+            };
+            if Self::is_expired_at(state.deadline(entry), now) {
+                if state.expiry_queue.push(key) {
+                    state.notify_expired(key);
+                }
+                continue;
+            }
+            if let Some(cache) = &state.cache
+                && let Some(Some(range)) =
+                    cache.get_with(key, |hit| Self::append_live(hit, now, arena))
+            {
+                ranges[position] = Some(range);
+                continue;
+            }
+            if entry.blob.is_some() {
+                if let Some(value) = state.read_value(key, entry)? {
+                    ranges[position] = Some(Self::append(arena, value.as_bytes()));
+                }
+                continue;
+            }
+            unread.push((position, entry.pointer));
+        }
+
+        unread.sort_by_key(|(_, pointer)| pointer.offset);
+        let pointers: Vec<ValuePointer> = unread.iter().map(|(_, pointer)| *pointer).collect();
+        let read = state
+            .wal
+            .read_values_into(state.generation, &pointers, arena)
+            .map_err(|err| Self::naming_reader(err, keys, &unread, |pointer| *pointer))?;
+        for ((position, _), range) in unread.into_iter().zip(read) {
+            ranges[position] = Some(range);
+        }
+        Ok(ranges)
+    }
+
+    /// Names in an [`InvalidUtf8`] error from a read of many `unread` values
+    /// the first of `keys` whose value sits at the failed offset.
+    fn naming_reader<T>(
+        err: io::Error,
+        keys: &[&str],
+        unread: &[(usize, T)],
+        pointer: impl Fn(&T) -> ValuePointer,
+    ) -> io::Error {
+        let Some(offset) = InvalidUtf8::of(&err).map(|invalid| invalid.offset) else {
+            return err;
+        };
+        match unread
+            .iter()
+            .find(|(_, item)| pointer(item).offset == offset)
+        {
+            Some((position, _)) => InvalidUtf8::naming(err, keys[*position]),
+            None => err,
+        }
+    }
+
+    /// Appends a cached value to `arena` unless it has expired by `now`.
+    fn append_live(hit: &CacheEntry, now: SystemTime, arena: &mut Vec<u8>) -> Option<Range<usize>> {
+        (!Self::is_expired_at(hit.expires_at, now))
+            .then(|| Self::append(arena, hit.value.as_bytes()))
+    }
+
+    fn append(arena: &mut Vec<u8>, value: &[u8]) -> Range<usize> {
+        let start = arena.len();
+        arena.extend_from_slice(value);
+        start..arena.len()
+    }
+
+    /// Writes the key's value into `sink` and returns its length in bytes, or
+    /// `None` without writing anything when the key is absent or expired.
+    ///
+    /// The value is still read into memory whole, as [`CrabKv::get_bytes`]
+    /// reads it: compressed values are decoded in one piece and checked
+    /// against the UTF-8 policy first, so a failed read writes nothing. What
+    /// this saves is building a response string around it.
+    pub fn get_into(&self, key: &str, sink: &mut impl Write) -> io::Result<Option<u64>> {
+        match self.get_bytes(key)? {
+            Some(value) => {
+                sink.write_all(&value)?;
+                Ok(Some(value.len() as u64))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns whether `key` holds a live value, from the index and write-back
+    /// buffer alone; the log is never read.
+    ///
+    /// An expired entry is treated as [`CrabKv::get`] treats it: reported
+    /// missing, queued for removal and announced to expiry subscribers. A
+    /// quarantined key fails with [`Corrupted`] the same way too, but a value
+    /// that is not valid UTF-8 is only noticed by reading it.
+    pub fn contains_key(&self, key: &str) -> io::Result<bool> {
+        self.check_not_updating()?;
+        let key = &*self.key(key);
+        let now = self.config.clock.now();
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        if self.config.write_back_cache
+            && let Some(cache) = &state.cache
+            && let Some(hit) = cache.get_buffered(key)
+        {
+            return Ok(!Self::is_expired_at(hit.expires_at, now));
+        }
+        match state.index.get(key) {
+            Some(entry) if Self::is_expired_at(state.deadline(entry), now) => {
+                if state.expiry_queue.push(key) {
+                    state.notify_expired(key);
+                }
+                Ok(false)
+            }
+            Some(_) => Ok(true),
+            None if state.quarantine.contains(key)
+                && self.config.utf8_policy != Utf8Policy::Skip =>
+            {
+                Err(Corrupted::error(key))
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn get_once(&self, key: &str, now: SystemTime) -> io::Result<Option<StoredValue>> {
         {
             let state = self
                 .inner
                 .read()
-                .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
-
-            // With write-back cache, check cache first (may contain uncommitted writes)
-            if self.config.write_back_cache {
-                if let Some(cache) = &state.cache {
-                    if let Some(hit) = cache.get(key) {
-                        if !Self::is_expired(hit.expires_at) {
-                            return Ok(Some(hit.value));
-                        } else {
-                            // Expired in cache
-                            return Ok(None);
-                        }
-                    }
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+
+            // With write-back cache, check the write buffer first (uncommitted writes)
+            if self.config.write_back_cache
+                && let Some(cache) = &state.cache
+                && let Some(hit) = cache.get_buffered(key)
+            {
+                if !Self::is_expired_at(hit.expires_at, now) {
+                    return Ok(Some(StoredValue::Text(hit.value)));
+                } else {
+                    // Expired in cache
+                    return Ok(None);
                 }
             }
 
             if let Some(entry) = state.index.get(key) {
-                if Self::is_expired(entry.expires_at) {
-                    drop(state);
-                    return self.expire_key(key);
+                if Self::is_expired_at(state.deadline(entry), now) {
+                    // The next write removes it; subscribers hear about it now.
+                    if state.expiry_queue.push(key) {
+                        state.notify_expired(key);
+                    }
+                    return Ok(None);
                 }
 
-                if let Some(cache) = &state.cache {
-                    if let Some(hit) = cache.get(key) {
-                        if !Self::is_expired(hit.expires_at) {
-                            return Ok(Some(hit.value));
-                        }
-                    }
+                if let Some(cache) = &state.cache
+                    && let Some(hit) = cache.get(key)
+                    && !Self::is_expired_at(hit.expires_at, now)
+                {
+                    return Ok(Some(StoredValue::Text(hit.value)));
                 }
 
-                let record = state.wal.read_record(entry.pointer)?;
-                if let WalEntry::Put { value, .. } = record.entry {
-                    if let Some(cache) = &state.cache {
-                        cache.put(
-                            key.to_owned(),
-                            CacheEntry {
-                                value: value.clone(),
-                                expires_at: entry.expires_at,
-                            },
-                        );
+                let record = state
+                    .wal
+                    .read_record_in(state.generation, entry.pointer)
+                    .map_err(|err| InvalidUtf8::naming(err, key))?;
+                match record.entry {
+                    WalEntry::Put { value, .. } => {
+                        if let Some(cache) = &state.cache {
+                            cache.put(
+                                key.to_owned(),
+                                CacheEntry::new(value.clone(), entry.expires_at),
+                            );
+                        }
+                        return Ok(Some(StoredValue::Text(value)));
                     }
-                    return Ok(Some(value));
+                    WalEntry::Bytes { value, .. } => return Ok(Some(StoredValue::Binary(value))),
+                    WalEntry::Blob { blob, .. } => {
+                        return state
+                            .blobs
+                            .read(&blob)
+                            .map(|value| Some(StoredValue::Text(value)));
+                    }
+                    WalEntry::Delete { .. } | WalEntry::Token { .. } => {}
                 }
+            } else if state.quarantine.contains(key) && self.config.utf8_policy != Utf8Policy::Skip
+            {
+                return Err(Corrupted::error(key));
             }
         }
 
         Ok(None)
     }
 
-    /// Removes the key if present.
-    pub fn delete(&self, key: &str) -> io::Result<()> {
-        let mut state = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
+    /// Returns up to `len` characters of the key's value starting at character `start`.
+    ///
+    /// Fails with [`transform::TransformError::OutOfRange`] when `start` is past the end of the value.
+    pub fn get_range(&self, key: &str, start: usize, len: usize) -> io::Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(transform::char_range(&value, start, len)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies an RFC 6901 JSON pointer to the key's value and returns the matched
+    /// fragment serialized as JSON.
+    #[cfg(feature = "json")]
+    pub fn get_json_path(&self, key: &str, pointer: &str) -> io::Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(transform::json_pointer(&value, pointer)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Serializes `value` to JSON and stores it like [`CrabKv::put`].
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] carrying the serde error when
+    /// `value` does not serialize.
+    #[cfg(feature = "serde")]
+    pub fn put_json<T: serde::Serialize + ?Sized>(&self, key: String, value: &T) -> io::Result<()> {
+        let ttl = self.config.default_ttl;
+        self.put_json_with_ttl(key, value, ttl)
+    }
+
+    /// Serializes `value` to JSON and stores it like [`CrabKv::put_with_ttl`].
+    #[cfg(feature = "serde")]
+    pub fn put_json_with_ttl<T: serde::Serialize + ?Sized>(
+        &self,
+        key: String,
+        value: &T,
+        ttl: Option<Duration>,
+    ) -> io::Result<()> {
+        let value = serde_json::to_string(value)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.put_with_ttl(key, value, ttl)
+    }
+
+    /// Reads the key like [`CrabKv::get`] and deserializes its value from JSON.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] carrying the serde error when
+    /// the value is not JSON of type `T`.
+    #[cfg(feature = "serde")]
+    pub fn get_json<T: serde::de::DeserializeOwned>(&self, key: &str) -> io::Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => serde_json::from_str(&value)
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            None => Ok(None),
+        }
+    }
+
+    /// Exchanges the values of two keys, each keeping the other's expiry.
+    ///
+    /// When only one key exists its value moves to the other key. Both writes
+    /// are appended as a single batch.
+    pub fn swap(&self, key_a: &str, key_b: &str) -> io::Result<()> {
+        self.check_not_updating()?;
+        let (key_a, key_b) = (&*self.key(key_a), &*self.key(key_b));
+        if key_a == key_b {
+            return Ok(());
+        }
+        let mut state = self.lock_for_write()?;
+
+        let value_a = self.read_live(&state, key_a)?;
+        let value_b = self.read_live(&state, key_b)?;
+        let now = self.config.clock.now();
+        let entry_for = |key: &str, value: Option<(String, Option<SystemTime>)>| match value {
+            Some((value, expires_at)) => WalEntry::Put {
+                key: key.to_owned(),
+                value,
+                expires_at,
+                written_at: Some(now),
+            },
+            None => WalEntry::Delete {
+                key: key.to_owned(),
+            },
+        };
+        if value_a.is_none() && value_b.is_none() {
+            return Ok(());
+        }
+
+        let entries = vec![entry_for(key_a, value_b), entry_for(key_b, value_a)];
+        self.apply_batch(&mut state, entries, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
+    /// Reads `keys`, passes their live values to `f`, and applies the writes it
+    /// returns, all under one hold of the write lock.
+    ///
+    /// Missing and expired keys map to `None`. Each returned `(key, Some(value))`
+    /// is a put with the default TTL and each `(key, None)` a delete; they are
+    /// appended as one batch, so no other writer sees or interleaves with a
+    /// partial update. `f` must not call back into this engine: reads and
+    /// writes from inside it fail with [`io::ErrorKind::Deadlock`] instead of
+    /// blocking on the lock it already holds.
+    pub fn update_many<F>(&self, keys: &[&str], f: F) -> io::Result<()>
+    where
+        F: FnOnce(&HashMap<String, Option<String>>) -> Vec<(String, Option<String>)>,
+    {
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let mut state = self.lock_for_write()?;
+
+        // `f` looks values up by the keys it was given, as given.
+        let mut current = HashMap::with_capacity(keys.len());
+        for &key in keys {
+            let value = self
+                .read_live(&state, &self.key(key))?
+                .map(|(value, _)| value);
+            current.insert(key.to_owned(), value);
+        }
+        let writes = {
+            let _updating = UpdatingGuard::enter(self.state_id());
+            f(&current)
+        };
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let now = self.config.clock.now();
+        let expires_at = self.config.default_ttl.and_then(|ttl| now.checked_add(ttl));
+        let entries = writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => WalEntry::Put {
+                    key: self.owned_key(key),
+                    value,
+                    expires_at,
+                    written_at: Some(now),
+                },
+                None => WalEntry::Delete {
+                    key: self.owned_key(key),
+                },
+            })
+            .collect();
+        self.apply_batch(&mut state, entries, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
+    fn state_id(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+
+    /// Fails fast when called from inside this engine's `update_many` closure,
+    /// which already holds the write lock.
+    fn check_not_updating(&self) -> io::Result<()> {
+        if UPDATING.with(Cell::get) == self.state_id() {
+            return Err(io::Error::new(
+                io::ErrorKind::Deadlock,
+                "update_many closure called back into the engine",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Takes the write lock for a user write, waiting while the store is
+    /// frozen and failing while it is read-only or has left the epoch this
+    /// thread is pinned to.
+    fn lock_for_write(&self) -> io::Result<RwLockWriteGuard<'_, EngineState>> {
+        loop {
+            self.mode.wait_for_writes()?;
+            let state = self
+                .inner
+                .write()
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+            // The mode and the epoch only change under the write lock, so
+            // they hold until the guard is dropped.
+            if self.mode.current().allows_writes() {
+                self.check_epoch(self.pinned_epoch())?;
+                return Ok(state);
+            }
+        }
+    }
+
+    /// Like [`CrabKv::lock_for_write`] for puts into the write-back buffer,
+    /// which only need the read lock.
+    fn read_for_write(&self) -> io::Result<RwLockReadGuard<'_, EngineState>> {
+        loop {
+            self.mode.wait_for_writes()?;
+            let state = self
+                .inner
+                .read()
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+            if self.mode.current().allows_writes() {
+                self.check_epoch(self.pinned_epoch())?;
+                return Ok(state);
+            }
+        }
+    }
+
+    /// Epoch [`CrabKv::pin_epoch`] pinned this thread's writes to, if any.
+    fn pinned_epoch(&self) -> Option<u64> {
+        PINNED_EPOCH
+            .with(Cell::get)
+            .filter(|(engine, _)| *engine == self.state_id())
+            .map(|(_, epoch)| epoch)
+    }
+
+    /// Returns up to `n` live keys chosen uniformly at random, using index metadata only.
+    ///
+    /// Each key is ranked by a hash seeded with `seed` and the `n` lowest ranks
+    /// are kept, so the sample is deterministic for a given seed and data set
+    /// regardless of index iteration order. Runs in O(index size) time and
+    /// O(n) memory.
+    pub fn sample_keys(&self, n: usize, seed: u64) -> io::Result<Vec<KeySample>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let now = self.config.clock.now();
+        let mut reservoir: BinaryHeap<(u64, &String)> = BinaryHeap::with_capacity(n + 1);
+        for (key, entry) in &state.index {
+            if Self::is_expired_at(state.deadline(entry), now) {
+                continue;
+            }
+            let rank = sample_rank(seed, key);
+            if reservoir.len() < n {
+                reservoir.push((rank, key));
+            } else if reservoir.peek().is_some_and(|(worst, _)| rank < *worst) {
+                reservoir.pop();
+                reservoir.push((rank, key));
+            }
+        }
+
+        Ok(reservoir
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_, key)| {
+                let entry = &state.index[key];
+                KeySample {
+                    key: key.clone(),
+                    value_len: entry.pointer.value_len,
+                    record_len: entry.pointer.record_len,
+                    expires_at: entry.expires_at,
+                }
+            })
+            .collect())
+    }
+
+    /// Reports whether each of `keys` is live, in input order.
+    ///
+    /// Only the in-memory index is consulted: no value is read from the log or
+    /// cache, and expired keys count as absent. Keys are checked in chunks under
+    /// separate read locks so a long input does not hold off writers, which also
+    /// means the answers are not a point-in-time view. With a write-back cache,
+    /// buffered writes show up once flushed.
+    pub fn contains_many<'a>(&self, keys: impl Iterator<Item = &'a str>) -> io::Result<Vec<bool>> {
+        let mut keys = keys.peekable();
+        let mut found = Vec::with_capacity(keys.size_hint().0);
+        while keys.peek().is_some() {
+            let state = self
+                .inner
+                .read()
+                .map_err(|_| io::Error::other("engine poisoned"))?;
+            let now = self.config.clock.now();
+            found.extend(keys.by_ref().take(CONTAINS_CHUNK).map(|key| {
+                state
+                    .index
+                    .get(&self.key(key))
+                    .is_some_and(|entry| !Self::is_expired_at(state.deadline(entry), now))
+            }));
+        }
+        Ok(found)
+    }
+
+    /// Returns the live key/value pairs with keys in `range`, in key order.
+    ///
+    /// The iterator reads a chunk of keys at a time; see [`KeyRange`]. With
+    /// [`CrabKvBuilder::ordered_index`] each chunk walks only the keys it
+    /// returns; otherwise each chunk scans the whole index.
+    pub fn range(&self, range: impl RangeBounds<String>) -> KeyRange {
+        let bound = |bound: Bound<&String>| bound.map(|key| self.owned_key(key.clone()));
+        KeyRange::new(
+            self.clone(),
+            bound(range.start_bound()),
+            bound(range.end_bound()),
+        )
+    }
+
+    /// Reads up to `limit` index entries between `bounds` for [`KeyRange`],
+    /// plus any write-back keys that sort among them.
+    pub(crate) fn range_chunk(
+        &self,
+        bounds: (Bound<&str>, Bound<&str>),
+        limit: usize,
+    ) -> io::Result<RangeChunk> {
+        self.check_not_updating()?;
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let mut keys: Vec<String> = state
+            .index
+            .range(bounds, limit)
+            .into_iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        let resume_after = (keys.len() == limit)
+            .then(|| keys.last().cloned())
+            .flatten();
+        // Write-back keys not flushed yet are only in the buffer; take those
+        // that sort within this chunk.
+        if let Some(cache) = &state.cache {
+            keys.extend(cache.buffered_keys(|key| {
+                RangeBounds::<str>::contains(&bounds, key)
+                    && resume_after.as_deref().is_none_or(|last| key <= last)
+            }));
+            keys.sort_unstable();
+            keys.dedup();
+        }
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some((value, _)) = self.read_live(&state, &key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(RangeChunk {
+            pairs,
+            resume_after,
+        })
+    }
+
+    /// Returns how many live keys the store holds.
+    ///
+    /// Counted from the in-memory index without reading the log. Expired keys
+    /// are left out, and write-back keys not flushed yet are included.
+    pub fn len(&self) -> io::Result<usize> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let now = self.config.clock.now();
+        let mut len = state
+            .index
+            .iter()
+            .filter(|(_, entry)| !Self::is_expired_at(state.deadline(entry), now))
+            .count();
+        if let Some(cache) = &state.cache {
+            // A buffered write decides whether its key is live, whatever the
+            // index says.
+            cache.for_each_buffered(|key, expires_at| {
+                let indexed = state
+                    .index
+                    .get(key)
+                    .is_some_and(|entry| !Self::is_expired_at(state.deadline(entry), now));
+                let buffered = !Self::is_expired_at(expires_at, now);
+                match (indexed, buffered) {
+                    (false, true) => len += 1,
+                    (true, false) => len -= 1,
+                    _ => {}
+                }
+            });
+        }
+        Ok(len)
+    }
+
+    /// Returns whether the store holds no live keys, as [`CrabKv::len`] counts them.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns every live key, in sorted order.
+    ///
+    /// Like [`CrabKv::len`], this reads only the index and the write-back
+    /// buffer; values are never loaded.
+    pub fn keys(&self) -> io::Result<Vec<String>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let now = self.config.clock.now();
+        let mut keys: Vec<String> = state
+            .index
+            .iter()
+            .filter(|(_, entry)| !Self::is_expired_at(state.deadline(entry), now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if let Some(cache) = &state.cache {
+            let mut expired = HashSet::new();
+            cache.for_each_buffered(|key, expires_at| {
+                if Self::is_expired_at(expires_at, now) {
+                    expired.insert(key.to_owned());
+                } else {
+                    keys.push(key.to_owned());
+                }
+            });
+            keys.retain(|key| !expired.contains(key));
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Returns the live keys matching a glob pattern, in sorted order.
+    pub fn keys_matching(&self, pattern: &str) -> io::Result<Vec<String>> {
+        let pattern = &*self.key(pattern);
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let now = self.config.clock.now();
+        let mut keys: Vec<String> = state
+            .index
+            .iter()
+            .filter(|(key, entry)| {
+                !Self::is_expired_at(state.deadline(entry), now)
+                    && pattern::glob_match(pattern, key)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Returns the live entries last written at or after `since`, sorted by key.
+    ///
+    /// Keys whose records predate write times (logs from older versions) are never
+    /// included. With a write-back cache, buffered writes show up once flushed.
+    pub fn changed_since(&self, since: SystemTime) -> io::Result<Vec<(String, String)>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let now = self.config.clock.now();
+        let mut changed = Vec::new();
+        for (key, entry) in &state.index {
+            let recent = entry
+                .written_at
+                .is_some_and(|written_at| written_at >= since);
+            if !recent || Self::is_expired_at(state.deadline(entry), now) {
+                continue;
+            }
+            if let Some((value, _)) = self.read_live(&state, key)? {
+                changed.push((key.clone(), value));
+            }
+        }
+        changed.sort();
+        Ok(changed)
+    }
+
+    /// Returns up to `limit` keys written or deleted at or after `since`, newest
+    /// first, from the ordering kept by [`CrabKvBuilder::track_modifications`].
+    ///
+    /// Each key appears once, with its latest change. Deletes are only known
+    /// from this open on, and keys still in the write-back buffer appear once
+    /// flushed. Fails with [`io::ErrorKind::Unsupported`] when tracking is off.
+    pub fn modified_since(&self, since: SystemTime, limit: usize) -> io::Result<Vec<Modification>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        match &state.modifications {
+            Some(modifications) => Ok(modifications.since(since, limit)),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "modification tracking is disabled; enable track_modifications",
+            )),
+        }
+    }
+
+    /// Reads the writes recorded in the log from `from_offset` on, for an
+    /// external consumer following the store incrementally; see [`crate::changes`].
+    ///
+    /// Reading stops at the first record boundary past `max_bytes`, but always
+    /// returns at least one record when any is left and never splits a batch.
+    /// Writes still in the write-back buffer appear once flushed, and
+    /// idempotency tokens are left out. `from_offset` must be 0 or a
+    /// `next_offset` returned under the current [`CrabKv::log_generation`];
+    /// others fail or decode garbage.
+    pub fn export_changes(&self, from_offset: u64, max_bytes: u64) -> io::Result<ChangeBatch> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Self::read_changes_in(&state, from_offset, max_bytes)
+    }
+
+    /// Replays the changes to keys starting with `prefix` written after
+    /// `since`, then follows new ones as they reach the log.
+    ///
+    /// Changes come exactly once and in log order, whether replayed or live:
+    /// both are read from the log by offset, so the switch between them can
+    /// neither skip nor repeat one. With `None` the stream starts at the
+    /// current end of the log. Like [`CrabKv::export_changes`], writes still
+    /// in the write-back buffer appear once flushed, and expired keys show up
+    /// as the deletes that reclaim them.
+    ///
+    /// Fails with [`HistoryTruncated`] when compaction rewrote the log after
+    /// `since` was taken; the stream itself fails the same way if compaction
+    /// runs while it is open. Either way, resync with a full scan and watch
+    /// again from a fresh [`CrabKv::change_marker`].
+    pub fn watch_prefix(
+        &self,
+        prefix: &str,
+        since: Option<ChangeMarker>,
+    ) -> io::Result<WatchStream> {
+        let since = since.unwrap_or_else(|| self.change_marker());
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let current = state.wal.generation();
+        if since.generation != current {
+            return Err(HistoryTruncated::error(since.generation, current));
+        }
+        Ok(WatchStream::new(
+            self.clone(),
+            &self.key(prefix),
+            since.generation,
+            since.offset,
+        ))
+    }
+
+    /// Like [`CrabKv::export_changes`], failing with [`HistoryTruncated`]
+    /// unless the log is still at `generation`.
+    pub(crate) fn read_changes(
+        &self,
+        generation: u64,
+        from_offset: u64,
+        max_bytes: u64,
+    ) -> io::Result<ChangeBatch> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        // Compaction installs a new log under the write lock, so the check
+        // holds for the read below.
+        let current = state.wal.generation();
+        if current != generation {
+            return Err(HistoryTruncated::error(generation, current));
+        }
+        Self::read_changes_in(&state, from_offset, max_bytes)
+    }
+
+    fn read_changes_in(
+        state: &EngineState,
+        from_offset: u64,
+        max_bytes: u64,
+    ) -> io::Result<ChangeBatch> {
+        let slice = state.wal.read_from(from_offset, max_bytes)?;
+        let mut changes = Vec::with_capacity(slice.entries.len());
+        for (offset, entry) in slice.entries {
+            let (key, kind) = match entry {
+                WalEntry::Put {
+                    key,
+                    value,
+                    expires_at,
+                    ..
+                } => (key, ChangeKind::Put { value, expires_at }),
+                WalEntry::Bytes {
+                    key,
+                    value,
+                    expires_at,
+                    ..
+                } => (key, ChangeKind::PutBytes { value, expires_at }),
+                WalEntry::Blob {
+                    key,
+                    blob,
+                    expires_at,
+                    ..
+                } => match state.blobs.read(&blob) {
+                    Ok(value) => (key, ChangeKind::Put { value, expires_at }),
+                    // Blobs are only removed once a later record in this
+                    // generation replaced the value.
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                },
+                WalEntry::Delete { key } => (key, ChangeKind::Delete),
+                WalEntry::Token { .. } => continue,
+            };
+            changes.push(Change { offset, key, kind });
+        }
+        Ok(ChangeBatch {
+            generation: slice.generation,
+            changes,
+            next_offset: slice.next_offset,
+            at_end: slice.next_offset >= slice.end,
+        })
+    }
+
+    /// Returns the generation of the log, which moves on each time compaction
+    /// rewrites it; offsets from [`CrabKv::export_changes`] are only valid
+    /// within one generation.
+    pub fn log_generation(&self) -> io::Result<u64> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(state.wal.generation())
+    }
+
+    /// Deletes every key last written before `cutoff` and returns how many were removed.
+    ///
+    /// Keys without a recorded write time and keys with a write still in the
+    /// write-back buffer are kept. The deletes are appended as one batch, so the
+    /// trim survives a reopen.
+    pub fn purge_older_than(&self, cutoff: SystemTime) -> io::Result<usize> {
+        let mut state = self.lock_for_write()?;
+        let deletes: Vec<WalEntry> = state
+            .index
+            .iter()
+            .filter(|(key, entry)| {
+                entry
+                    .written_at
+                    .is_some_and(|written_at| written_at < cutoff)
+                    && state
+                        .cache
+                        .as_ref()
+                        .is_none_or(|cache| cache.get_buffered(key).is_none())
+            })
+            .map(|(key, _)| WalEntry::Delete { key: key.clone() })
+            .collect();
+        if deletes.is_empty() {
+            return Ok(0);
+        }
+        let removed = deletes.len();
+        self.apply_batch(&mut state, deletes, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(removed)
+    }
+
+    /// Removes the key if present.
+    pub fn delete(&self, key: &str) -> io::Result<()> {
+        self.check_not_updating()?;
+        let key = &*self.key(key);
+        let mut state = self.lock_for_write()?;
+
+        let entry = WalEntry::Delete {
+            key: key.to_owned(),
+        };
+        let pointer = state.wal.append(&entry)?;
+        state.total_bytes += pointer.record_len as u64;
+        state.touch();
+        self.record_changes(&state, 1, pointer.record_len as u64);
+        self.billing.record_write(key, key.len() as u64);
+
+        if let Some(previous) = state.index_remove(key) {
+            state.stale_bytes += previous.owned_len();
+            state.note_deleted(key);
+        }
+        state.quarantine.release(key);
+
+        if let Some(cache) = &state.cache {
+            cache.remove(key);
+        }
+
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
+    /// Removes the key and returns the value it held, as one step: of several
+    /// callers popping the same key, exactly one gets the value.
+    ///
+    /// The value comes from the write-back buffer, the cache or the log, and
+    /// the tombstone is appended under the same hold of the write lock that
+    /// read it. An expired key returns `None` but is removed all the same; a
+    /// missing key writes nothing.
+    pub fn pop(&self, key: &str) -> io::Result<Option<String>> {
+        self.check_not_updating()?;
+        let key = &*self.key(key);
+        let value = self.skipping_invalid_utf8(|| self.pop_once(key))?;
+        self.bill_read(key, value.as_deref());
+        Ok(value)
+    }
+
+    fn pop_once(&self, key: &str) -> io::Result<Option<String>> {
+        let mut state = self.lock_for_write()?;
+        let Some(value) = self.read_for_update(&state, key)? else {
+            return Ok(None);
+        };
+        let tombstone = WalEntry::Delete {
+            key: key.to_owned(),
+        };
+        self.apply_batch(&mut state, vec![tombstone], true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(value)
+    }
+
+    /// Stores `value` under `key` and returns the value it replaced, as one
+    /// step: no write from another handle can land between the two.
+    ///
+    /// The old value comes from the write-back buffer, the cache or the log,
+    /// read under the same hold of the write lock that appends the new one.
+    /// The new value takes the default TTL, as [`CrabKv::put`] gives it. A
+    /// missing or expired key returns `None`.
+    pub fn get_set(&self, key: String, value: String) -> io::Result<Option<String>> {
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let key = self.owned_key(key);
+        let old = self.skipping_invalid_utf8(|| self.get_set_once(&key, &value))?;
+        self.bill_read(&key, old.as_deref());
+        Ok(old)
+    }
+
+    fn get_set_once(&self, key: &str, value: &str) -> io::Result<Option<String>> {
+        let mut state = self.lock_for_write()?;
+        let old = self.read_for_update(&state, key)?.flatten();
+        let now = self.config.clock.now();
+        let entry = WalEntry::Put {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            expires_at: self.config.default_ttl.and_then(|ttl| now.checked_add(ttl)),
+            written_at: Some(now),
+        };
+        self.apply_batch(&mut state, vec![entry], true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(old)
+    }
+
+    /// Reads `key` for a write about to replace it, from the write-back
+    /// buffer, the cache or the log.
+    ///
+    /// `None` when the key holds nothing, `Some(None)` when it holds an
+    /// expired value; a quarantined key fails with [`Corrupted`].
+    fn read_for_update(
+        &self,
+        state: &EngineState,
+        key: &str,
+    ) -> io::Result<Option<Option<String>>> {
+        let now = self.config.clock.now();
+        let buffered = state
+            .cache
+            .as_ref()
+            .filter(|_| self.config.write_back_cache)
+            .and_then(|cache| cache.get_buffered(key));
+        if let Some(hit) = buffered {
+            return Ok(Some(
+                (!Self::is_expired_at(hit.expires_at, now)).then_some(hit.value),
+            ));
+        }
+        let Some(entry) = state.index.get(key) else {
+            if state.quarantine.contains(key) && self.config.utf8_policy != Utf8Policy::Skip {
+                return Err(Corrupted::error(key));
+            }
+            return Ok(None);
+        };
+        if Self::is_expired_at(state.deadline(entry), now) {
+            return Ok(Some(None));
+        }
+        let cached = state
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(key))
+            .filter(|hit| !Self::is_expired_at(hit.expires_at, now));
+        match cached {
+            Some(hit) => Ok(Some(Some(hit.value))),
+            None => state.read_value(key, entry).map(Some),
+        }
+    }
+
+    /// Removes every key in `keys` in a single atomic batch.
+    ///
+    /// Keys that hold nothing are skipped rather than given a tombstone, so a
+    /// batch of only missing keys writes nothing at all.
+    pub fn delete_batch(&self, keys: Vec<String>) -> io::Result<()> {
+        self.check_not_updating()?;
+        let mut state = self.lock_for_write()?;
+        let mut seen = HashSet::new();
+        let deletes: Vec<WalEntry> = keys
+            .into_iter()
+            .map(|key| self.owned_key(key))
+            .filter(|key| {
+                let buffered = state
+                    .cache
+                    .as_ref()
+                    .is_some_and(|cache| cache.get_buffered(key).is_some());
+                (state.index.contains_key(key) || buffered || state.quarantine.contains(key))
+                    && seen.insert(key.clone())
+            })
+            .map(|key| WalEntry::Delete { key })
+            .collect();
+        if deletes.is_empty() {
+            return Ok(());
+        }
+        self.apply_batch(&mut state, deletes, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(())
+    }
+
+    /// Stores a value like [`CrabKv::put_with_ttl`], deduplicated by the
+    /// options' idempotency key and fenced by their expected epoch when given.
+    ///
+    /// A repeat of a key applied within the
+    /// [idempotency window](CrabKvBuilder::idempotency_window) writes nothing
+    /// and reports `replayed`. Without a TTL in `options` the default TTL
+    /// applies. A write with either option goes straight to the log, past the
+    /// write-back buffer.
+    pub fn put_with(
+        &self,
+        key: String,
+        value: String,
+        options: &WriteOptions,
+    ) -> io::Result<WriteOutcome> {
+        let ttl = options.ttl.or(self.config.default_ttl);
+        if options.idempotency_key.is_none() && options.expected_epoch.is_none() {
+            self.put_with_ttl(key, value, ttl)?;
+            return Ok(WriteOutcome::applied(String::new()));
+        }
+        self.check_disk_space()?;
+        self.write_once(options, |now| {
+            let entry = WalEntry::Put {
+                key: self.owned_key(key),
+                value,
+                expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+                written_at: Some(now),
+            };
+            Ok((vec![entry], String::new()))
+        })
+    }
+
+    /// Removes the key like [`CrabKv::delete`], deduplicated and fenced like
+    /// [`CrabKv::put_with`]. The TTL in `options` is ignored.
+    pub fn delete_with(&self, key: &str, options: &WriteOptions) -> io::Result<WriteOutcome> {
+        if options.idempotency_key.is_none() && options.expected_epoch.is_none() {
+            self.delete(key)?;
+            return Ok(WriteOutcome::applied(String::new()));
+        }
+        self.write_once(options, |_| {
+            let entry = WalEntry::Delete {
+                key: self.key(key).into_owned(),
+            };
+            Ok((vec![entry], String::new()))
+        })
+    }
+
+    /// Applies the entries `write` builds, together with a record of the
+    /// options' token, unless that token was applied within the window or the
+    /// store left the expected epoch.
+    ///
+    /// `write` returns the entries and the outcome repeats are answered with.
+    fn write_once(
+        &self,
+        options: &WriteOptions,
+        write: impl FnOnce(SystemTime) -> io::Result<(Vec<WalEntry>, String)>,
+    ) -> io::Result<WriteOutcome> {
+        self.check_not_updating()?;
+        let mut state = self.lock_for_write()?;
+        // The epoch only moves under the write lock, so it holds until the
+        // batch is in.
+        self.check_epoch(options.expected_epoch)?;
+        let now = self.config.clock.now();
+        let token = options.idempotency_key.as_deref();
+        if let Some(outcome) = token.and_then(|token| state.seen_tokens.get(token, now)) {
+            return Ok(WriteOutcome {
+                replayed: true,
+                outcome: outcome.to_owned(),
+            });
+        }
+        let (mut entries, outcome) = write(now)?;
+        if let Some(token) = token
+            && let Some(expires_at) = now.checked_add(self.config.idempotency_window)
+        {
+            entries.push(WalEntry::Token {
+                token: token.to_owned(),
+                outcome: outcome.clone(),
+                expires_at,
+            });
+        }
+        self.apply_batch(&mut state, entries, true)?;
+        self.maybe_compact_async(&mut state);
+        Ok(WriteOutcome::applied(outcome))
+    }
+
+    /// Forces a compaction cycle regardless of the current heuristic.
+    ///
+    /// Writers are only held off while the live entries are collected and while
+    /// the new log is swapped in, not while it is written.
+    pub fn compact(&self) -> io::Result<()> {
+        Self::compact_concurrently(&self.inner, false)
+    }
+
+    /// Has the async compaction worker compact now and blocks until that run is done.
+    ///
+    /// Without async compaction this compacts in the calling thread like
+    /// [`CrabKv::compact`]. Fails if the worker has stopped or the run fails.
+    pub fn compact_and_wait(&self) -> io::Result<()> {
+        let Some(tx) = &self.compaction_tx else {
+            return self.compact();
+        };
+        let stopped = || io::Error::other("compaction worker is not running");
+        let mut progress = self.worker.progress.lock();
+        if !progress.running {
+            return Err(stopped());
+        }
+        progress.requested += 1;
+        let ticket = progress.requested;
+        tx.send(CompactionRequest::Forced).map_err(|_| stopped())?;
+        while progress.completed < ticket {
+            if !progress.running {
+                return Err(stopped());
+            }
+            self.worker.finished.wait(&mut progress);
+        }
+        match &progress.last_outcome {
+            Some(CompactionOutcome::Failed(reason)) => Err(io::Error::other(reason.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports whether the background compaction worker is alive and how its
+    /// last run went.
+    pub fn compaction_status(&self) -> CompactionStatus {
+        let progress = self.worker.progress.lock();
+        CompactionStatus {
+            worker_running: progress.running,
+            in_progress: self.is_compacting(),
+            last_started: progress.last_started,
+            last_finished: progress.last_finished,
+            last_outcome: progress.last_outcome.clone(),
+            pending_triggers: self.worker.trigger_pending.load(Ordering::Relaxed) as u64,
+            heartbeat_age: progress.heartbeat.map(|beat| beat.elapsed()),
+            peak_buffer_bytes: self.peak_buffer.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Holds the engine write lock on another thread for `duration`, returning
+    /// once it is held, so tests can observe readers of a stalled engine.
+    ///
+    /// Only built with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn stall_writes_for(&self, duration: Duration) -> JoinHandle<()> {
+        let inner = Arc::clone(&self.inner);
+        let (held_tx, held_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let _state = inner.write();
+            let _ = held_tx.send(());
+            thread::sleep(duration);
+        });
+        let _ = held_rx.recv();
+        handle
+    }
+
+    /// Returns the errors background workers reported since open or the last
+    /// [`CrabKv::clear_background_errors`], oldest first. Only the latest 64
+    /// are kept.
+    ///
+    /// Workers keep running after an error: a failed compaction or checkpoint
+    /// is tried again on a later trigger, a failed sync on a later tick, and a
+    /// worker that panics is started again after a backoff that doubles per
+    /// consecutive failure.
+    pub fn background_errors(&self) -> Vec<BackgroundError> {
+        self.background.snapshot()
+    }
+
+    /// Forgets every background error reported so far.
+    pub fn clear_background_errors(&self) {
+        self.background.clear();
+    }
+
+    /// Returns the latest background error if it was reported within
+    /// [`CrabKvBuilder::background_error_window`], in which case health checks
+    /// report the store degraded.
+    pub fn recent_background_error(&self) -> Option<BackgroundError> {
+        self.background
+            .latest_within(self.config.background_error_window)
+    }
+
+    /// Makes `worker` panic at its next step, so tests can watch it restart.
+    ///
+    /// Only built with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn crash_background_worker(&self, worker: BackgroundWorker) {
+        self.background.crash_next(worker);
+    }
+
+    /// Stops the compaction worker as if its thread had died.
+    ///
+    /// Only built with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    pub fn stop_compaction_worker(&self) {
+        if let Some(tx) = &self.compaction_tx {
+            let _ = tx.send(CompactionRequest::Shutdown);
+        }
+        if let Some(worker) = self.compaction_thread.lock().take() {
+            let _ = worker.join();
+        }
+    }
+
+    /// Removes up to a batch of keys reads queued as expired, appending their
+    /// tombstones as one batch. Keys written again since are left alone.
+    fn reclaim_expired(&self, state: &mut EngineState) -> io::Result<()> {
+        let queued = state.expiry_queue.take(expiry::RECLAIM_BATCH);
+        if queued.is_empty() {
+            return Ok(());
+        }
+        let removed = state.remove_expired(queued)?;
+        if !removed.is_empty() {
+            self.note_appended();
+        }
+        state.expiry_queue.note_reclaimed(removed.len());
+        Ok(())
+    }
+
+    /// Returns whether `key` currently holds a value, without reading it.
+    fn contains_live(&self, state: &EngineState, key: &str) -> bool {
+        let now = self.config.clock.now();
+        if let Some(cache) = &state.cache
+            && let Some(hit) = cache.get_buffered(key)
+        {
+            return !Self::is_expired_at(hit.expires_at, now);
+        }
+        state
+            .index
+            .get(key)
+            .is_some_and(|entry| !Self::is_expired_at(state.deadline(entry), now))
+    }
+
+    /// Reads a key's current value and expiry, including unflushed write-back entries.
+    fn read_live(
+        &self,
+        state: &EngineState,
+        key: &str,
+    ) -> io::Result<Option<(String, Option<SystemTime>)>> {
+        let now = self.config.clock.now();
+        if self.config.write_back_cache
+            && let Some(cache) = &state.cache
+            && let Some(hit) = cache.get_buffered(key)
+        {
+            return Ok(
+                (!Self::is_expired_at(hit.expires_at, now)).then_some((hit.value, hit.expires_at))
+            );
+        }
+        match state.index.get(key) {
+            Some(entry) if !Self::is_expired_at(state.deadline(entry), now) => Ok(state
+                .read_value(key, entry)?
+                .map(|value| (value, entry.expires_at))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads `key` as of log sequence number `lsn`, for [`ReadView`].
+    ///
+    /// Ignores the write-back buffer, whose writes are not yet in the log.
+    pub(crate) fn get_at(&self, key: &str, lsn: u64) -> io::Result<Option<String>> {
+        let key = &*self.key(key);
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let invalidated = || {
+            io::Error::other(ViewInvalidated {
+                key: key.to_owned(),
+            })
+        };
+        let value = match state.index.get(key) {
+            Some(entry) if entry.lsn > lsn => return Err(invalidated()),
+            Some(entry) if Self::is_expired_at(state.deadline(entry), self.config.clock.now()) => {
+                None
+            }
+            Some(entry) => state.read_value(key, entry)?,
+            // The key may have been deleted after the view was taken.
+            None if state.last_delete_lsn > lsn => return Err(invalidated()),
+            None => None,
+        };
+        self.bill_read(key, value.as_deref());
+        Ok(value)
+    }
+
+    /// Returns a consistent view of the store as of now; see [`ReadView`].
+    ///
+    /// Flushes the write-back buffer first so the view covers every write
+    /// accepted before it was taken.
+    pub fn read_view(&self) -> io::Result<ReadView> {
+        self.flush()?;
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(ReadView::new(self.clone(), state.wal.lsn()))
+    }
+
+    fn check_disk_space(&self) -> io::Result<()> {
+        match &self.disk_guard {
+            Some(guard) => guard.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends a mixed batch of puts and deletes and applies it to the index and cache.
+    ///
+    /// `user_mutations` is false when replaying writes that were already counted,
+    /// such as a write-back flush.
+    fn apply_batch(
+        &self,
+        state: &mut EngineState,
+        entries: Vec<WalEntry>,
+        user_mutations: bool,
+    ) -> io::Result<()> {
+        if user_mutations {
+            state.check_quotas(&entries)?;
+        }
+        // Billed by their uncompressed size, before values move to blob files.
+        let billed: Vec<u64> = entries
+            .iter()
+            .map(|entry| match entry {
+                WalEntry::Put { key, value, .. } => (key.len() + value.len()) as u64,
+                WalEntry::Bytes { key, value, .. } => (key.len() + value.len()) as u64,
+                WalEntry::Delete { key } => key.len() as u64,
+                WalEntry::Blob { .. } | WalEntry::Token { .. } => 0,
+            })
+            .collect();
+        // A failed append leaves the new blob files behind; they are removed
+        // as orphans on the next open rather than risk deleting a durable one.
+        let entries = entries
+            .into_iter()
+            .map(|entry| state.tier(entry))
+            .collect::<io::Result<Vec<_>>>()?;
+        let appended = state.wal.append_batch(&entries)?;
+        state.touch();
+        let mutations = if user_mutations {
+            let tokens = entries
+                .iter()
+                .filter(|entry| matches!(entry, WalEntry::Token { .. }))
+                .count();
+            (appended.pointers.len() - tokens) as u64
+        } else {
+            0
+        };
+        self.record_changes(state, mutations, appended.bytes);
+        state.total_bytes += appended.overhead();
+        state.stale_bytes += appended.overhead();
+
+        for ((entry, pointer), billed) in entries.into_iter().zip(appended.pointers).zip(billed) {
+            state.total_bytes += pointer.record_len as u64;
+            if !matches!(entry, WalEntry::Token { .. }) {
+                self.billing.record_write(entry.key(), billed);
+            }
+            match entry {
+                WalEntry::Put {
+                    key,
+                    value,
+                    expires_at,
+                    written_at,
+                } => {
+                    let index_entry = IndexEntry::new(pointer, expires_at, written_at);
+                    if let Some(previous) = state.index_insert(key.clone(), index_entry) {
+                        state.stale_bytes += previous.owned_len();
+                    }
+                    if let Some(cache) = &state.cache {
+                        // Drop any buffered write-back value before caching the durable one.
+                        cache.remove(&key);
+                        cache.put_clean(key, CacheEntry::new(value, expires_at));
+                    }
+                }
+                WalEntry::Bytes {
+                    key,
+                    expires_at,
+                    written_at,
+                    ..
+                } => {
+                    let index_entry = IndexEntry::new(pointer, expires_at, written_at);
+                    if let Some(previous) = state.index_insert(key.clone(), index_entry) {
+                        state.stale_bytes += previous.owned_len();
+                    }
+                    // The cache holds strings, so a binary value is read from the log.
+                    if let Some(cache) = &state.cache {
+                        cache.remove(&key);
+                    }
+                }
+                WalEntry::Blob {
+                    key,
+                    blob,
+                    expires_at,
+                    written_at,
+                } => {
+                    let index_entry = IndexEntry {
+                        blob: Some(blob.name),
+                        ..IndexEntry::new(pointer, expires_at, written_at)
+                    };
+                    if let Some(previous) = state.index_insert(key.clone(), index_entry) {
+                        state.stale_bytes += previous.owned_len();
+                    }
+                    if let Some(cache) = &state.cache {
+                        cache.remove(&key);
+                    }
+                }
+                WalEntry::Delete { key } => {
+                    if let Some(previous) = state.index_remove(&key) {
+                        state.stale_bytes += previous.owned_len();
+                        state.note_deleted(&key);
+                    }
+                    state.quarantine.release(&key);
+                    if let Some(cache) = &state.cache {
+                        cache.remove(&key);
+                    }
+                }
+                WalEntry::Token {
+                    token,
+                    outcome,
+                    expires_at,
+                } => {
+                    if let Some(journal) = &mut state.token_journal {
+                        journal.push(token.clone());
+                    }
+                    let now = state.clock.now();
+                    state.seen_tokens.insert(token, outcome, expires_at, now);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    fn maybe_compact(state: &mut EngineState) -> io::Result<()> {
+        if Self::compaction_due(state, None) {
+            Self::run_compaction(state)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn maybe_compact_async(&self, state: &mut EngineState) {
+        // Every mutation ends here, so it also reclaims keys reads found expired
+        // and records keys released from quarantine. The write is durable by
+        // now; what fails from here on is reported, not returned to the writer.
+        if let Err(err) = self.reclaim_expired(state) {
+            self.background.report(BackgroundWorker::TtlSweeper, err);
+        }
+        // Keys are quarantined by reads and the scrubber alike; the scrubber
+        // is where damaged records are looked for.
+        if let Err(err) = state.quarantine.save() {
+            self.background.report(BackgroundWorker::Scrub, err);
+        }
+        self.check_soft_limits(state);
+        match self.start_compaction_if_due(state) {
+            Ok(true) => {}
+            Ok(false) => self.maybe_checkpoint(state),
+            Err(err) => self.background.report(BackgroundWorker::Compaction, err),
+        }
+    }
+
+    /// Evaluates the compaction policy now and starts a compaction if it is
+    /// due, returning whether one was started.
+    ///
+    /// Writes check the policy themselves, except puts held in the write-back
+    /// buffer, which are checked when it is flushed. This lets tests and idle
+    /// stores act on it without another write. With async compaction a
+    /// started run is queued for the worker and may still be in progress when
+    /// this returns; otherwise it has finished.
+    pub fn force_compaction_check(&self) -> io::Result<bool> {
+        self.check_not_updating()?;
+        let mut state = self
+            .inner
+            .write()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        self.start_compaction_if_due(&mut state)
+    }
+
+    fn start_compaction_if_due(&self, state: &mut EngineState) -> io::Result<bool> {
+        start_compaction_if_due(
+            state,
+            self.config.min_compaction_interval,
+            self.compaction_tx.as_ref(),
+            &self.worker,
+        )
+    }
+
+    /// Writes an index checkpoint covering the log as it is now, so reopening
+    /// after a crash replays only what is written after it.
+    ///
+    /// Waits for a compaction or migration in progress to finish first.
+    /// Writes still in the write-back buffer are not in the log yet and are
+    /// not covered. Checkpoints are also written in the background as
+    /// [`CrabKvBuilder::checkpoint_mutations`] and
+    /// [`CrabKvBuilder::checkpoint_bytes`] say.
+    pub fn checkpoint(&self) -> io::Result<()> {
+        Self::write_checkpoint(&self.inner, &self.checkpoints, true).map(drop)
+    }
+
+    /// Reports the latest index checkpoint and how far the log has grown past it.
+    pub fn checkpoint_status(&self) -> io::Result<CheckpointStatus> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let last = *self.checkpoints.last.lock();
+        let since = last.taken_at.unwrap_or(last.opened_at);
+        Ok(CheckpointStatus {
+            last_taken: last.taken_at,
+            age: state.clock.now().duration_since(since).unwrap_or_default(),
+            uncovered_bytes: state.total_bytes.saturating_sub(state.checkpointed_len),
+            in_progress: self.checkpoints.writing.load(Ordering::Relaxed),
+            max_age: self.config.checkpoint_max_age,
+        })
+    }
+
+    /// Returns the mode set with [`CrabKv::set_mode`], `Normal` after open.
+    pub fn mode(&self) -> EngineMode {
+        self.mode.current()
+    }
+
+    /// Switches every handle to `mode`, returning the previous one.
+    ///
+    /// Waits for a compaction, checkpoint or migration in progress to finish
+    /// first. Entering `Frozen` or `ReadOnly` flushes the write-back buffer
+    /// and syncs the log, so once this returns nothing in the directory
+    /// changes until the mode is left. Leaving `Frozen` wakes the writers it
+    /// held, then runs the compaction and checkpoint asked for meanwhile: on
+    /// the worker with async compaction, otherwise on this thread, reporting
+    /// failures to [`CrabKv::background_errors`].
+    pub fn set_mode(&self, mode: EngineMode) -> io::Result<EngineMode> {
+        self.check_not_updating()?;
+        let poisoned = || io::Error::other("engine poisoned");
+        // Flushed before waiting on the rewrite lock too, so a compaction the
+        // flush calls for is not skipped for want of it.
+        if !mode.allows_writes() {
+            self.flush()?;
+        }
+        let rewrite_lock = Arc::clone(&self.inner.read().map_err(|_| poisoned())?.rewrite_lock);
+        let previous = {
+            let _rewriting = rewrite_lock.lock();
+            let mut state = self.inner.write().map_err(|_| poisoned())?;
+            // Flushed under the same hold that sets the mode, so no buffered
+            // put lands between the flush and the switch.
+            if !mode.allows_writes() {
+                if self.config.write_back_cache {
+                    // Retried by the flush above; this one only picks up the rest.
+                    self.flush_locked(&mut state, 0)?;
+                }
+                state.wal.sync()?;
+            }
+            self.mode.set(mode)
+        };
+        if previous != mode {
+            self.events.record(EventKind::ModeChanged {
+                from: previous,
+                to: mode,
+            });
+        }
+        if mode.allows_writes() {
+            self.run_deferred();
+        }
+        Ok(previous)
+    }
+
+    /// Runs the compaction and checkpoint a freeze held back.
+    fn run_deferred(&self) {
+        let deferred = self.mode.take_deferred();
+        let report = |worker, result: io::Result<()>| match result {
+            // Frozen again meanwhile, which deferred it once more.
+            Err(err) if ModeRefused::of(&err).is_some() => {}
+            Err(err) => self.background.report(worker, err),
+            Ok(()) => {}
+        };
+        if deferred.compaction {
+            let compacted = match &self.compaction_tx {
+                Some(tx) => {
+                    self.worker.progress.lock().requested += 1;
+                    let _ = tx.send(CompactionRequest::Forced);
+                    Ok(())
+                }
+                None => Self::compact_concurrently(&self.inner, true),
+            };
+            report(BackgroundWorker::Compaction, compacted);
+        }
+        if deferred.checkpoint {
+            let written = Self::write_checkpoint(&self.inner, &self.checkpoints, true);
+            report(BackgroundWorker::Checkpoint, written.map(drop));
+        }
+    }
+
+    /// Starts a background checkpoint if one is due, none is being written,
+    /// and the engine is not under pressure.
+    fn maybe_checkpoint(&self, state: &EngineState) {
+        if !self.checkpoint_due(state) || self.pressure_of(state) > PressureLevel::Ok {
+            return;
+        }
+        if self.checkpoints.writing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let inner = Arc::clone(&self.inner);
+        let checkpoints = Arc::clone(&self.checkpoints);
+        let background = Arc::clone(&self.background);
+        let writer = thread::spawn(move || {
+            let _clear = ClearOnDrop(&checkpoints.writing);
+            let written = panic::catch_unwind(AssertUnwindSafe(|| {
+                background.crash_point(BackgroundWorker::Checkpoint);
+                Self::write_checkpoint(&inner, &checkpoints, false)
+            }));
+            let err = match written {
+                Ok(Ok(_)) => {
+                    checkpoints.backoff.lock().reset();
+                    return;
+                }
+                // Frozen since it was started; it runs again once unfrozen.
+                Ok(Err(err)) if ModeRefused::of(&err).is_some() => return,
+                Ok(Err(err)) => err,
+                Err(payload) => background::panic_error(payload),
+            };
+            // The next trigger tries again once the backoff has passed.
+            let delay = checkpoints.backoff.lock().next_delay();
+            checkpoints.last.lock().retry_at = Some(Instant::now() + delay);
+            background.report(BackgroundWorker::Checkpoint, err);
+        });
+        // `writing` was clear, so any earlier writer has finished.
+        if let Some(previous) = self.checkpoints.thread.lock().replace(writer) {
+            let _ = previous.join();
+        }
+    }
+
+    /// Whether the log has grown past the checkpoint limits since the last
+    /// checkpoint, and the minimum interval has passed.
+    fn checkpoint_due(&self, state: &EngineState) -> bool {
+        let config = &self.config;
+        if config.checkpoint_mutations.is_none() && config.checkpoint_bytes.is_none() {
+            return false;
+        }
+        if state.compacting.load(Ordering::Relaxed) {
+            return false;
+        }
+        let last = *self.checkpoints.last.lock();
+        if let Some(started) = last.started
+            && started.elapsed() < config.checkpoint_min_interval
+        {
+            return false;
+        }
+        if last
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return false;
+        }
+        let mutations = self.checkpoints.mutations.load(Ordering::Relaxed);
+        let bytes = state.total_bytes.saturating_sub(state.checkpointed_len);
+        config.checkpoint_mutations.is_some_and(|n| mutations >= n)
+            || config.checkpoint_bytes.is_some_and(|n| bytes >= n)
+    }
+
+    /// Judges the pressure gauges as of `state` against the default thresholds.
+    fn pressure_of(&self, state: &EngineState) -> PressureLevel {
+        let gauges = PressureGauges {
+            write_buffer: state.cache.as_ref().map_or(0, Cache::buffered_len),
+            stale_bytes: state.stale_bytes,
+            free_bytes: self
+                .disk_guard
+                .as_ref()
+                .and_then(|guard| guard.free_bytes().ok()),
+        };
+        PressureThresholds::default().level(&gauges)
+    }
+
+    /// Takes a checkpoint of the index under the read lock, then writes it
+    /// while writes continue. Returns `false` without writing when `wait` is
+    /// unset and a compaction or migration holds the log.
+    fn write_checkpoint(
+        inner: &RwLock<EngineState>,
+        checkpoints: &CheckpointProgress,
+        wait: bool,
+    ) -> io::Result<bool> {
+        let poisoned = || io::Error::other("engine poisoned");
+        let (rewrite_lock, mode) = {
+            let state = inner.read().map_err(|_| poisoned())?;
+            (Arc::clone(&state.rewrite_lock), Arc::clone(&state.mode))
+        };
+        // Held until the file is in place, so the log it describes is not
+        // replaced meanwhile and compaction cannot miss it when cleaning up.
+        let _rewriting = if wait {
+            rewrite_lock.lock()
+        } else {
+            match rewrite_lock.try_lock() {
+                Some(guard) => guard,
+                None => return Ok(false),
+            }
+        };
+        mode.admit(Activity::Checkpoint)?;
+        let (wal, mut taken) = {
+            let state = inner.read().map_err(|_| poisoned())?;
+            checkpoints.mutations.store(0, Ordering::Relaxed);
+            (Arc::clone(&state.wal), state.checkpoint())
+        };
+        checkpoints.last.lock().started = Some(Instant::now());
+        // A checkpoint must not cover records a crash could still lose.
+        wal.sync()?;
+        taken.fingerprint = checkpoint::fingerprint(&wal, taken.offset)?;
+        taken.write(&checkpoint::path(wal.path()))?;
+        inner.write().map_err(|_| poisoned())?.checkpointed_len = taken.offset;
+        checkpoints.last.lock().taken_at = Some(taken.taken_at);
+        Ok(true)
+    }
+
+    /// Notifies the threshold listener of soft limits the last write crossed.
+    fn check_soft_limits(&self, state: &EngineState) {
+        let gauges = Gauges {
+            wal_bytes: state.total_bytes,
+            stale_bytes: state.stale_bytes,
+            keys: state.index.len() as u64,
+            write_buffer: state.cache.as_ref().map_or(0, Cache::buffered_len),
+        };
+        self.soft_limits.check(gauges, |event| {
+            self.events.record(EventKind::ThresholdCrossed(event));
+        });
+    }
+
+    /// Applies the compaction policy, holding back while the last compaction
+    /// is more recent than `min_interval`.
+    fn compaction_due(state: &EngineState, min_interval: Option<Duration>) -> bool {
+        if let (Some(interval), Some(last)) = (min_interval, state.last_compaction)
+            && last.elapsed() < interval
+        {
+            return false;
+        }
+        state.compaction_policy.is_due(
+            state.total_bytes,
+            state.stale_bytes,
+            state.mutations_since_compaction.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Compacts with the state lock held throughout, for callers that already hold it.
+    ///
+    /// Skipped while a concurrent compaction is writing a generation; that run
+    /// reclaims the same stale bytes.
+    fn run_compaction(state: &mut EngineState) -> io::Result<()> {
+        let rewrite_lock = Arc::clone(&state.rewrite_lock);
+        let Some(_rewriting) = rewrite_lock.try_lock() else {
+            return Ok(());
+        };
+        let compacting = Arc::clone(&state.compacting);
+        compacting.store(true, Ordering::Relaxed);
+        let _clear = ClearOnDrop(&compacting);
+
+        state
+            .events
+            .record(EventKind::CompactionStarted { background: false });
+        let result = state.begin_compaction().and_then(|(live, tokens)| {
+            let generation = state
+                .wal
+                .copy_generation(&live, &tokens, state.compaction_buffer);
+            state.finish_compaction(generation)
+        });
+        state.note_compaction(&result, false);
+        result
+    }
+
+    /// Compacts while writers keep going.
+    ///
+    /// The live entries are collected under the state lock, written to a new
+    /// generation without it, and the writes made meanwhile are replayed onto
+    /// that generation in log sequence order before it replaces the log.
+    fn compact_concurrently(inner: &RwLock<EngineState>, background: bool) -> io::Result<()> {
+        let poisoned = || io::Error::other("engine poisoned");
+        let (rewrite_lock, compacting, mode) = {
+            let state = inner.read().map_err(|_| poisoned())?;
+            (
+                Arc::clone(&state.rewrite_lock),
+                Arc::clone(&state.compacting),
+                Arc::clone(&state.mode),
+            )
+        };
+        let _rewriting = rewrite_lock.lock();
+        // Checked under the rewrite lock, which a mode change waits for.
+        mode.admit(Activity::Compaction)?;
+        compacting.store(true, Ordering::Relaxed);
+        let _clear = ClearOnDrop(&compacting);
+
+        let (wal, buffer, (live, tokens)) = {
+            let mut state = inner.write().map_err(|_| poisoned())?;
+            state
+                .events
+                .record(EventKind::CompactionStarted { background });
+            match state.begin_compaction() {
+                Ok(plan) => (Arc::clone(&state.wal), state.compaction_buffer, plan),
+                Err(err) => {
+                    let result = Err(err);
+                    state.note_compaction(&result, background);
+                    return result;
+                }
+            }
+        };
+        let generation = wal.copy_generation(&live, &tokens, buffer);
+        let mut state = inner.write().map_err(|_| poisoned())?;
+        let result = state.finish_compaction(generation);
+        state.note_compaction(&result, background);
+        result
+    }
+
+    /// Returns a channel receiving each key as the engine expires it.
+    ///
+    /// Keys are reported when an expired entry is found by a read or dropped by
+    /// compaction. The subscription ends when the receiver is dropped.
+    pub fn subscribe_expired(&self) -> io::Result<Receiver<String>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let (tx, rx) = mpsc::channel();
+        state.expiry_subscribers.lock().push(tx);
+        Ok(rx)
+    }
+
+    /// Reports live-byte usage for every prefix registered with a quota, with
+    /// the bytes and operations billed to it since open or the last
+    /// [`CrabKv::reset_prefix_counters`].
+    ///
+    /// A prefix meant only for billing can be registered with a quota of
+    /// `u64::MAX`. The counters start from zero each time the store is opened.
+    pub fn stats_by_prefix(&self) -> io::Result<Vec<PrefixUsage>> {
+        self.prefix_usage(false)
+    }
+
+    /// Returns the same as [`CrabKv::stats_by_prefix`] and zeroes the billing
+    /// counters in the same step, so no operation is billed twice or missed.
+    pub fn reset_prefix_counters(&self) -> io::Result<Vec<PrefixUsage>> {
+        self.prefix_usage(true)
+    }
+
+    fn prefix_usage(&self, reset: bool) -> io::Result<Vec<PrefixUsage>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        let mut usage = state.quotas.usage();
+        self.billing.fill(&mut usage, reset);
+        Ok(usage)
+    }
+
+    /// Returns the time of the most recent put or delete, if any.
+    ///
+    /// Before the first mutation of this session the WAL modification time is
+    /// reported, so the value survives restarts.
+    pub fn last_write_time(&self) -> io::Result<Option<SystemTime>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(*state.last_write.lock())
+    }
+
+    /// Returns `true` while a compaction, foreground or background, is rewriting the log.
+    ///
+    /// Reads an atomic flag, so it never waits on the engine lock held by the compaction.
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many compactions have completed since the engine was opened.
+    pub fn compaction_count(&self) -> io::Result<u64> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(state.compactions)
+    }
+
+    /// Returns how many times the wall clock was seen stepping backwards by more
+    /// than [`crate::clock::BACKWARD_STEP_THRESHOLD`] since open.
+    ///
+    /// The engine holds its time still through such steps, so keys never come
+    /// back to life after expiring.
+    pub fn clock_steps_back(&self) -> u64 {
+        self.clock_steps.count()
+    }
+
+    /// Returns the engine events still in the ring, oldest first.
+    ///
+    /// Opening and closing, compactions, flushes, soft limit crossings and
+    /// quarantined keys are recorded; the ring keeps the latest
+    /// [`CrabKvBuilder::event_capacity`] of them. Never waits on the engine lock.
+    pub fn recent_events(&self) -> Vec<EngineEvent> {
+        self.events.recent(usize::MAX)
+    }
+
+    /// Returns the keys compaction set aside because their log record could not
+    /// be decoded, in key order.
+    ///
+    /// Reading one fails with [`Corrupted`] until the key is written or
+    /// deleted again or [`CrabKv::drop_quarantined`] gives up on it.
+    pub fn quarantined(&self) -> io::Result<Vec<QuarantinedKey>> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(state.quarantine.list())
+    }
+
+    /// Gives up on every quarantined key, so reads of them find nothing, and
+    /// returns their names.
+    pub fn drop_quarantined(&self) -> io::Result<Vec<String>> {
+        let mut state = self.lock_for_write()?;
+        let dropped = state.quarantine.clear();
+        state.quarantine.save()?;
+        Ok(dropped)
+    }
+
+    /// Checks that every record of the log still decodes, without the rate
+    /// limit of the background scrub, and returns what the pass found.
+    ///
+    /// A pass the background scrub has under way, or one a restart cut short,
+    /// is finished rather than started over. Live keys whose record is damaged
+    /// are quarantined as compaction would, and each damaged record is also
+    /// reported as a [`BackgroundWorker::Scrub`] error. Runs in every mode;
+    /// only the progress file and quarantine are left unsaved while the
+    /// store is frozen or read-only.
+    pub fn scrub_now(&self) -> io::Result<ScrubReport> {
+        self.check_not_updating()?;
+        let mut start = true;
+        loop {
+            match scrub_step(&self.inner, &self.scrubber, &self.background, start)? {
+                Some(Stepped {
+                    finished: Some(report),
+                    ..
+                }) => return Ok(report),
+                Some(_) => start = false,
+                // The background scrub finished the pass between two steps.
+                None => return Ok(self.scrubber.last().expect("a pass only ends by finishing")),
+            }
+        }
+    }
+
+    /// Returns the configuration the engine was built with.
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Returns the data directory the engine writes to: the one it was opened
+    /// on, or the last one it was moved to by [`CrabKv::migrate_to`].
+    pub fn directory(&self) -> PathBuf {
+        self.directory.lock().clone()
+    }
+
+    /// The directory every clone shares, for a lock file that follows it.
+    pub(crate) fn shared_directory(&self) -> Arc<Mutex<PathBuf>> {
+        Arc::clone(&self.directory)
+    }
+
+    /// Returns the id recorded in the store's manifest when it was created.
+    pub fn store_id(&self) -> &str {
+        &self.store_id
+    }
+
+    /// Returns the store's fencing epoch, kept in its manifest.
+    ///
+    /// It starts at 0 and goes up by one on every [`CrabKv::restore_from`],
+    /// [`CrabKv::clear`] and [`CrabKv::migrate_to`]. A client that read it
+    /// before one of them can pin it with [`WriteOptions::expected_epoch`], so
+    /// its writes fail instead of landing in data it has not seen.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Pins every write this thread makes on this engine to `epoch` until the
+    /// returned guard is dropped, like [`WriteOptions::expected_epoch`] on
+    /// each of them: puts, deletes, pops, increments and batches alike fail
+    /// with an [`EpochMismatch`] once the store has left `epoch`, checked
+    /// under the lock they write under. The server pins the commands of a
+    /// connection that sent `EPOCH <n>`.
+    pub fn pin_epoch(&self, epoch: u64) -> EpochPin {
+        let previous = PINNED_EPOCH.with(|pinned| pinned.replace(Some((self.state_id(), epoch))));
+        EpochPin {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Fails with an [`EpochMismatch`] unless the store is at `expected`, or
+    /// nothing is expected.
+    fn check_epoch(&self, expected: Option<u64>) -> io::Result<()> {
+        match expected {
+            Some(expected) if expected != self.epoch() => {
+                Err(EpochMismatch::error(expected, self.epoch()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records `epoch` in the manifest in `dir`; called under the state write
+    /// lock before the data changes, so a write fenced to the old epoch
+    /// cannot land afterwards.
+    fn advance_epoch(&self, dir: &Path, epoch: u64) -> io::Result<()> {
+        manifest::write(
+            dir,
+            &Manifest {
+                store_id: self.store_id.to_string(),
+                epoch,
+                keys: self.config.key_policy,
+            },
+        )?;
+        self.epoch.store(epoch, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Replaces every key with the live keys of the store in `backup_dir`,
+    /// and moves to the next epoch, which it returns.
+    ///
+    /// `backup_dir` is a data directory copied while its engine was closed,
+    /// or one left behind by [`CrabKv::migrate_to`]; it is only read. Its keys
+    /// keep their expiry, and ones already expired are left out. The backup's
+    /// values are read into memory first, then written in one batch with
+    /// tombstones for every other key, so readers see either the old data or
+    /// the restored data. Buffered write-back values are dropped with the data
+    /// they were written over, and prefix quotas are not checked.
+    pub fn restore_from(&self, backup_dir: impl AsRef<Path>) -> io::Result<u64> {
+        let backup_dir = backup_dir.as_ref();
+        let backup = WalFile::open_at(backup_dir, self.config.clock.now())?;
+        let mut entries = backup.live_entries()?;
+        for entry in &mut entries {
+            let key = entry.key_mut();
+            *key = self.owned_key(std::mem::take(key));
+        }
+        let epoch = self.replace_all(entries)?;
+        self.events.record(EventKind::Restored {
+            from: backup_dir.to_path_buf(),
+            epoch,
+        });
+        Ok(epoch)
+    }
+
+    /// Removes every key in one batch, buffered ones included, and moves to
+    /// the next epoch, which it returns.
+    pub fn clear(&self) -> io::Result<u64> {
+        let epoch = self.replace_all(Vec::new())?;
+        self.events.record(EventKind::Cleared { epoch });
+        Ok(epoch)
+    }
+
+    /// Bumps the epoch, then writes `entries` with a tombstone for every other
+    /// key, under one hold of the write lock.
+    fn replace_all(&self, entries: Vec<WalEntry>) -> io::Result<u64> {
+        self.check_not_updating()?;
+        self.check_disk_space()?;
+        let mut state = self.lock_for_write()?;
+        let kept: HashSet<&str> = entries.iter().map(WalEntry::key).collect();
+        // A tombstone also drops a buffered value, and a put replaces one.
+        let mut removed: Vec<String> = state
+            .index
+            .keys()
+            .filter(|key| !kept.contains(key.as_str()))
+            .cloned()
+            .chain(
+                state
+                    .quarantine
+                    .list()
+                    .into_iter()
+                    .map(|quarantined| quarantined.key)
+                    .filter(|key| !kept.contains(key.as_str())),
+            )
+            .collect();
+        if let Some(cache) = &state.cache {
+            removed.extend(cache.buffered_keys(|key| !kept.contains(key)));
+        }
+        removed.sort_unstable();
+        removed.dedup();
+        let mut batch: Vec<WalEntry> = removed
+            .into_iter()
+            .map(|key| WalEntry::Delete { key })
+            .collect();
+        batch.extend(entries);
+        if !batch.is_empty() {
+            // Quotas would count the old data against the new; the writes
+            // still count toward compaction and checkpoints.
+            let writes = batch.len() as u64;
+            self.apply_batch(&mut state, batch, false)?;
+            self.record_changes(&state, writes, 0);
+            self.maybe_compact_async(&mut state);
+        }
+        // Only once the data changed, so a failed batch fences nobody out.
+        let epoch = self.epoch() + 1;
+        self.advance_epoch(&self.directory(), epoch)?;
+        Ok(epoch)
+    }
+
+    /// Moves the store to `new_dir` while reads and writes continue.
+    ///
+    /// The log and blob files are copied without holding the state lock.
+    /// Writers are then paused only to copy what was appended meanwhile and
+    /// switch the engine to the copy, so every later write lands in `new_dir`
+    /// and the old directory is left as it was at the switch, for the caller
+    /// to remove. `new_dir` is created if needed and must not already hold a
+    /// `wal.log`. Compaction waits until the move is done. On error the engine
+    /// keeps using the old directory and the partial `wal.log` is removed.
+    ///
+    /// The copy's manifest records the next [epoch](CrabKv::epoch), so a
+    /// client pinned to the old one reconnects before writing again.
+    pub fn migrate_to(&self, new_dir: impl AsRef<Path>) -> io::Result<()> {
+        let new_dir = new_dir.as_ref();
+        let poisoned = || io::Error::other("engine poisoned");
+        let rewrite_lock = {
+            let state = self.inner.read().map_err(|_| poisoned())?;
+            Arc::clone(&state.rewrite_lock)
+        };
+        // Compaction would replace the log file being copied.
+        let _rewriting = rewrite_lock.lock();
+        self.mode.admit(Activity::Migration)?;
+
+        std::fs::create_dir_all(new_dir)?;
+        let wal_path = new_dir.join("wal.log");
+        let mut copy = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&wal_path)?;
+        let migrated = self.copy_and_switch(new_dir, &wal_path, &mut copy);
+        if migrated.is_err() {
+            let _ = std::fs::remove_file(&wal_path);
+            let _ = std::fs::remove_file(new_dir.join(lock_file::LOCK_FILE));
+        }
+        migrated
+    }
+
+    /// Copies the log and blobs into `new_dir`, then finishes the copy under
+    /// the state lock and points the engine at it.
+    fn copy_and_switch(
+        &self,
+        new_dir: &Path,
+        wal_path: &Path,
+        copy: &mut std::fs::File,
+    ) -> io::Result<()> {
+        let poisoned = || io::Error::other("engine poisoned");
+        let (wal, old_blobs) = {
+            let state = self.inner.read().map_err(|_| poisoned())?;
+            (Arc::clone(&state.wal), BlobStore::new(state.blobs.dir()))
+        };
+        let new_blobs = BlobStore::new(new_dir.join("blobs"));
+        let copied = wal.copy_to(copy, 0)?;
+        old_blobs.copy_to(new_blobs.dir())?;
+
+        let mut state = self.inner.write().map_err(|_| poisoned())?;
+        wal.copy_to(copy, copied)?;
+        old_blobs.copy_to(new_blobs.dir())?;
+        let epoch = self.epoch() + 1;
+        manifest::write(
+            new_dir,
+            &Manifest {
+                store_id: self.store_id.to_string(),
+                epoch,
+                keys: self.config.key_policy,
+            },
+        )?;
+        // The copy keeps every offset, so the checkpoint holds for it too.
+        match std::fs::copy(checkpoint::path(wal.path()), checkpoint::path(wal_path)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        // A server's lock moves along, so the CLI forwards to it from `new_dir`
+        // and no longer from the directory it left.
+        let old_dir = self.directory();
+        let served = lock_file::copy_held(&old_dir, new_dir)?;
+        copy.sync_all()?;
+        blob::sync_dir(new_dir)?;
+        let new_wal = Arc::new(wal.reopen_at(wal_path)?);
+        new_wal.start_flusher()?;
+        state.generation = new_wal.generation();
+        state.wal = new_wal;
+        state.blobs = new_blobs;
+        state.quarantine.relocate(new_dir)?;
+        self.epoch.store(epoch, Ordering::SeqCst);
+        self.events.record(EventKind::Migrated {
+            to: new_dir.to_path_buf(),
+        });
+        self.events.relocate(new_dir)?;
+        self.scrubber.relocate(new_dir);
+        *self.directory.lock() = new_dir.to_path_buf();
+        if let Some(guard) = &self.disk_guard {
+            guard.relocate(new_dir);
+        }
+        if served {
+            let _ = std::fs::remove_file(old_dir.join(lock_file::LOCK_FILE));
+        }
+        Ok(())
+    }
+
+    /// Returns the live key count and, while the index holds no more keys than
+    /// [`CrabKvBuilder::histogram_limit`], key and value size histograms.
+    pub fn stats(&self) -> io::Result<EngineStats> {
+        self.collect_stats(false)
+    }
+
+    /// Like [`CrabKv::stats`] but always computes the histograms.
+    pub fn stats_with_histograms(&self) -> io::Result<EngineStats> {
+        self.collect_stats(true)
+    }
+
+    /// Reads the gauges behind [`CrabKv::pressure`] without waiting: while a
+    /// writer or compaction holds the engine lock, the last readings are
+    /// returned instead.
+    pub fn pressure_gauges(&self) -> PressureGauges {
+        let mut last = self.pressure.lock();
+        if let Ok(state) = self.inner.try_read() {
+            last.write_buffer = state.cache.as_ref().map_or(0, Cache::buffered_len);
+            last.stale_bytes = state.stale_bytes;
+        }
+        last.free_bytes = self
+            .disk_guard
+            .as_ref()
+            .and_then(|guard| guard.free_bytes().ok());
+        *last
+    }
+
+    /// Judges [`CrabKv::pressure_gauges`] against the default
+    /// [`PressureThresholds`].
+    pub fn pressure(&self) -> PressureLevel {
+        PressureThresholds::default().level(&self.pressure_gauges())
+    }
+
+    /// Like [`CrabKv::stats`] but returns `None` instead of waiting while a
+    /// writer or compaction holds the engine lock.
+    pub fn try_stats(&self) -> io::Result<Option<EngineStats>> {
+        match self.inner.try_read() {
+            Ok(state) => Ok(Some(self.stats_of(&state, false))),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Poisoned(_)) => Err(io::Error::other("engine poisoned")),
+        }
+    }
+
+    fn collect_stats(&self, force: bool) -> io::Result<EngineStats> {
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        Ok(self.stats_of(&state, force))
+    }
+
+    /// Walks the index once; the caller holds the read lock.
+    fn stats_of(&self, state: &EngineState, force: bool) -> EngineStats {
+        let (cache_hits, cache_misses) =
+            state.cache.as_ref().map_or((0, 0), Cache::hits_and_misses);
+        let now = state.clock.now();
+        let mut stats = EngineStats {
+            cache_hits,
+            cache_misses,
+            expiry: state.expiry_queue.counters(),
+            quarantined: state.quarantine.len() as u64,
+            lossy_utf8_reads: state.wal.lossy_read_count(),
+            ttl_extensions: state.ttl_extensions,
+            log_syncs: state.wal.sync_count(),
+            log_opens: state.wal.open_count(),
+            mode: self.mode.current(),
+            last_scrub: self.scrubber.last(),
+            wal_bytes: state.total_bytes,
+            live_bytes: state.total_bytes.saturating_sub(state.stale_bytes),
+            stale_bytes: state.stale_bytes,
+            cache_capacity: self.config.cache_capacity.map(NonZeroUsize::get),
+            compaction_pending: self.worker.trigger_pending.load(Ordering::Relaxed)
+                || Self::compaction_due(state, None),
+            checkpoint_age: self
+                .checkpoints
+                .last
+                .lock()
+                .taken_at
+                .map(|taken_at| now.duration_since(taken_at).unwrap_or_default()),
+            ..EngineStats::default()
+        };
+        let histograms = force || state.index.len() <= self.config.histogram_limit;
+        let mut key_sizes = SizeHistogram::default();
+        let mut value_sizes = SizeHistogram::default();
+        let mut ttl_remaining = TtlHistogram::default();
+        for (key, entry) in state.index.iter() {
+            let deadline = state.deadline(entry);
+            if Self::is_expired_at(deadline, now) {
+                stats.expired_unreclaimed += 1;
+                continue;
+            }
+            stats.keys += 1;
+            if let Some(ref_len) = entry.ref_len {
+                stats.dedup_saved_bytes += entry.pointer.record_len.saturating_sub(ref_len) as u64;
+            }
+            match deadline {
+                Some(deadline) => {
+                    stats.keys_with_ttl += 1;
+                    if histograms {
+                        let remaining = deadline.duration_since(now).unwrap_or_default();
+                        ttl_remaining.record(remaining);
+                    }
+                }
+                None => stats.keys_without_ttl += 1,
+            }
+            if histograms {
+                key_sizes.record(key.len() as u64);
+                value_sizes.record(entry.pointer.value_len as u64);
+            }
+        }
+        if histograms {
+            stats.key_sizes = Some(key_sizes);
+            stats.value_sizes = Some(value_sizes);
+            stats.ttl_remaining = Some(ttl_remaining);
+        }
+        stats
+    }
+
+    /// Returns how many `CrabKv` handles share this engine, including this one.
+    pub fn handle_count(&self) -> usize {
+        // Every open handle holds the token and nothing else does; the
+        // state and the workers hold the other shared parts too.
+        self.last_handle.as_ref().map_or(1, Arc::strong_count)
+    }
+
+    /// Closes this handle, reporting what a drop could only log.
+    ///
+    /// The write-back buffer is flushed and the WAL synced. If this is the
+    /// last handle, the store is closed as well: the background compaction,
+    /// scrub and TTL sweeper threads are stopped and joined, and how far a
+    /// scrub got is saved. The result reports a failure of any of those
+    /// steps. Dropping the last handle does the same on a best-effort basis.
+    pub fn close(mut self) -> io::Result<()> {
+        let last = self
+            .last_handle
+            .take()
+            .is_some_and(|token| Arc::into_inner(token).is_some());
+        if last {
+            return self.shut_down();
+        }
+        self.flush()?;
+        self.inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?
+            .wal
+            .sync()
+    }
+
+    /// Closes the engine if this is its last handle, otherwise hands it back.
+    ///
+    /// Closing does what [`CrabKv::close`] does for the last handle; the
+    /// inner result reports a failure of any of its steps.
+    // Handing the handle back by value mirrors `Arc::try_unwrap`.
+    #[allow(clippy::result_large_err)]
+    pub fn try_close(mut self) -> Result<io::Result<()>, CrabKv> {
+        let token = self.last_handle.take().expect("only taken while closing");
+        match Arc::try_unwrap(token) {
+            Ok(()) => Ok(self.shut_down()),
+            Err(token) => {
+                self.last_handle = Some(token);
+                Err(self)
+            }
+        }
+    }
+
+    /// Closes the store for its last handle.
+    fn shut_down(&self) -> io::Result<()> {
+        let flushed = self.flush();
+        if let Some(tx) = &self.compaction_tx {
+            let _ = tx.send(CompactionRequest::Shutdown);
+        }
+        // Every thread is joined before the first failure is returned, so
+        // none outlives the close.
+        let compaction = join_thread(self.compaction_thread.lock().take(), "compaction");
+        let checkpoint = join_thread(self.checkpoints.thread.lock().take(), "checkpoint");
+        if let Some(tx) = &self.scrub_tx {
+            let _ = tx.send(());
+        }
+        let scrub = join_thread(self.scrub_thread.lock().take(), "scrub");
+        if let Some(tx) = &self.sweep_tx {
+            let _ = tx.send(());
+        }
+        let sweep = join_thread(self.sweep_thread.lock().take(), "TTL sweeper");
+        compaction
+            .and(checkpoint)
+            .and(scrub)
+            .and(sweep)
+            .and(flushed)?;
+        let state = self
+            .inner
+            .read()
+            .map_err(|_| io::Error::other("engine poisoned"))?;
+        state.wal.sync()?;
+        self.events.record(EventKind::Closed);
+        if !self.mode.current().allows_writes() {
+            return Ok(());
+        }
+        state.remove_doomed_blobs();
+        self.scrubber.save()?;
+        self.events.save()
+    }
+
+    /// Returns the number of puts and deletes accepted since the engine was opened.
+    ///
+    /// Expiry and compaction are not user mutations and are not counted.
+    pub fn mutations_since_open(&self) -> u64 {
+        self.changes.mutations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the WAL bytes appended by user mutations since the engine was opened.
+    pub fn bytes_written_since_open(&self) -> u64 {
+        self.changes.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Captures the current position in the mutation stream.
+    pub fn change_marker(&self) -> ChangeMarker {
+        // Only positions are read, which a panicked writer cannot leave half-made.
+        let state = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        ChangeMarker {
+            mutations: self.mutations_since_open(),
+            generation: state.wal.generation(),
+            offset: state.total_bytes,
+        }
+    }
+
+    /// Returns how many mutations happened after `marker` was taken.
+    pub fn mutations_since(&self, marker: ChangeMarker) -> u64 {
+        self.mutations_since_open().saturating_sub(marker.mutations)
+    }
+
+    fn record_changes(&self, state: &EngineState, mutations: u64, bytes: u64) {
+        state
+            .mutations_since_compaction
+            .fetch_add(mutations, Ordering::Relaxed);
+        self.checkpoints
+            .mutations
+            .fetch_add(mutations, Ordering::Relaxed);
+        self.changes
+            .mutations
+            .fetch_add(mutations, Ordering::Relaxed);
+        self.changes
+            .bytes_written
+            .fetch_add(bytes, Ordering::Relaxed);
+        self.note_appended();
+    }
+
+    /// Wakes watchers waiting for the log to grow.
+    fn note_appended(&self) {
+        self.changes.wake_watchers();
+    }
+
+    /// Returns the count [`CrabKv::wait_for_appends`] waits to see move on.
+    pub(crate) fn appends_seen(&self) -> u64 {
+        *self.changes.appends.lock()
+    }
+
+    /// Blocks until something was appended since `seen` was read, or until
+    /// `deadline`; returns whether anything was.
+    pub(crate) fn wait_for_appends(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let mut appends = self.changes.appends.lock();
+        while *appends == seen {
+            match deadline {
+                Some(deadline) => {
+                    if self
+                        .changes
+                        .appended
+                        .wait_until(&mut appends, deadline)
+                        .timed_out()
+                    {
+                        return *appends != seen;
+                    }
+                }
+                None => self.changes.appended.wait(&mut appends),
+            }
+        }
+        true
+    }
+
+    fn is_expired_at(expires_at: Option<SystemTime>, now: SystemTime) -> bool {
+        matches!(expires_at, Some(deadline) if now >= deadline)
+    }
+}
+
+/// Seeded FNV-1a hash with a SplitMix64 finaliser, used to rank keys for sampling.
+fn sample_rank(seed: u64, key: &str) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Waits for a background thread, if it was started.
+fn join_thread(thread: Option<JoinHandle<()>>, name: &str) -> io::Result<()> {
+    match thread {
+        Some(thread) => thread
+            .join()
+            .map_err(|_| io::Error::other(format!("{name} thread panicked"))),
+        None => Ok(()),
+    }
+}
+
+/// Whether a failed operation may succeed if simply tried again.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Runs one step of the scrub pass under way, starting one if `start` is
+/// set. Each damaged record is reported, and the live keys whose value it
+/// held are quarantined unless a compaction swapped the log meanwhile.
+fn scrub_step(
+    inner: &RwLock<EngineState>,
+    scrubber: &Scrubber,
+    background: &BackgroundErrors,
+    start: bool,
+) -> io::Result<Option<Stepped>> {
+    let poisoned = || io::Error::other("engine poisoned");
+    let (wal, now, writable, events) = {
+        let state = inner.read().map_err(|_| poisoned())?;
+        (
+            Arc::clone(&state.wal),
+            state.clock.now(),
+            state.mode.admit(Activity::Scrub).is_ok(),
+            Arc::clone(&state.events),
+        )
+    };
+    let mut holders: Option<HashMap<u64, Vec<String>>> = None;
+    let stepped = scrubber.step(
+        &wal,
+        start,
+        SCRUB_STEP_BYTES,
+        now,
+        writable,
+        // Which keys each record holds is mapped on the first finding, once
+        // per step rather than a sweep of the index per damaged record.
+        |generation, finding| {
+            background.report(
+                BackgroundWorker::Scrub,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "log record at offset {} is damaged: {}",
+                        finding.offset, finding.error
+                    ),
+                ),
+            );
+            let Ok(mut state) = inner.write() else {
+                return;
+            };
+            if state.generation != generation {
+                return;
+            }
+            let holders = holders.get_or_insert_with(|| state.keys_by_offset());
+            let keys = holders.remove(&finding.offset).unwrap_or_default();
+            finding.keys = state.quarantine_scrubbed(finding.offset, keys, &finding.error);
+            if !finding.keys.is_empty()
+                && state.mode.admit(Activity::Scrub).is_ok()
+                && let Err(err) = state.quarantine.save()
+            {
+                background.report(BackgroundWorker::Scrub, err);
+            }
+        },
+    )?;
+    if let Some(report) = stepped
+        .as_ref()
+        .and_then(|stepped| stepped.finished.as_ref())
+    {
+        events.record(EventKind::ScrubFinished {
+            records: report.records_checked,
+            damaged: report.damaged_records,
+        });
+    }
+    Ok(stepped)
+}
+
+/// Body of the scrub thread: a pass once per `interval`, reading at most
+/// `bytes_per_sec`, until a stop is sent or the last handle is dropped.
+fn run_scrubs(
+    inner: &RwLock<EngineState>,
+    scrubber: &Scrubber,
+    background: &BackgroundErrors,
+    clock: &Clock,
+    interval: Duration,
+    bytes_per_sec: u64,
+    stop: &Receiver<()>,
+) {
+    loop {
+        background.crash_point(BackgroundWorker::Scrub);
+        let mut wait = scrubber.due_in(interval, clock.now());
+        if wait.is_zero() {
+            wait = match scrub_step(inner, scrubber, background, true) {
+                Ok(stepped) => {
+                    let bytes = stepped.map_or(0, |stepped| stepped.bytes);
+                    match bytes_per_sec {
+                        0 => Duration::ZERO,
+                        rate => Duration::from_secs_f64(bytes as f64 / rate as f64),
+                    }
+                }
+                // Tried again once the interval is up.
+                Err(err) => {
+                    background.report(BackgroundWorker::Scrub, err);
+                    interval
+                }
+            };
+        }
+        match stop.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return,
+        }
+    }
+}
+
+/// Removes every key expired as of now and returns how many it removed.
+///
+/// The index is scanned [`expiry::SWEEP_BATCH`] keys at a time under the
+/// read lock, and the expired keys of each chunk are removed under the write
+/// lock, so neither lock is held for long. The tombstones count toward the
+/// compaction policy like any other write. A store whose mode refuses writes
+/// is left alone, and a mode change partway stops the sweep until the next
+/// one.
+fn sweep_expired(inner: &RwLock<EngineState>, compaction: &CompactionTrigger) -> io::Result<usize> {
+    let poisoned = || io::Error::other("engine poisoned");
+    let mut cursor = IndexCursor::default();
+    let mut swept = 0;
+    loop {
+        let expired: Vec<String> = {
+            let state = inner.read().map_err(|_| poisoned())?;
+            if state.mode.admit(Activity::Sweep).is_err() {
+                break;
+            }
+            let now = state.clock.now();
+            let chunk = state.index.chunk(&mut cursor, expiry::SWEEP_BATCH);
+            if chunk.is_empty() {
+                break;
+            }
+            chunk
+                .into_iter()
+                .filter(|(_, entry)| CrabKv::is_expired_at(state.deadline(entry), now))
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        if expired.is_empty() {
+            continue;
+        }
+        let mut state = inner.write().map_err(|_| poisoned())?;
+        if state.mode.admit(Activity::Sweep).is_err() {
+            break;
+        }
+        let removed = state.remove_expired(expired)?;
+        cursor.note_removed(removed.len());
+        // Keys reads already queued were reported when they were found.
+        let reported = state.expiry_queue.forget(&removed);
+        for key in removed.iter().filter(|key| !reported.contains(*key)) {
+            state.notify_expired(key);
+        }
+        state.expiry_queue.note_swept(removed.len());
+        if !removed.is_empty() {
+            state.changes.wake_watchers();
+        }
+        swept += removed.len();
+    }
+    if swept > 0 {
+        let mut state = inner.write().map_err(|_| poisoned())?;
+        compaction.start_if_due(&mut state)?;
+    }
+    Ok(swept)
+}
+
+/// Body of the TTL sweeper thread: a sweep once per `interval`, until a stop
+/// is sent or the last handle is dropped.
+fn run_sweeps(
+    inner: &RwLock<EngineState>,
+    compaction: &CompactionTrigger,
+    background: &BackgroundErrors,
+    interval: Duration,
+    stop: &Receiver<()>,
+) {
+    loop {
+        match stop.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return,
+        }
+        background.crash_point(BackgroundWorker::TtlSweeper);
+        // Keys a failed sweep left behind are found again by the next one.
+        if let Err(err) = sweep_expired(inner, compaction) {
+            background.report(BackgroundWorker::TtlSweeper, err);
+        }
+    }
+}
+
+impl CompactionWorker {
+    /// Serves compaction requests until shutdown, beating the heartbeat while idle.
+    fn run(
+        &self,
+        inner: &RwLock<EngineState>,
+        requests: &Receiver<CompactionRequest>,
+        min_interval: Option<Duration>,
+        clock: &Clock,
+        errors: &BackgroundErrors,
+    ) {
+        let _exit = WorkerExit(self);
+        self.progress.lock().running = true;
+        loop {
+            self.progress.lock().heartbeat = Some(Instant::now());
+            errors.crash_point(BackgroundWorker::Compaction);
+            let request = match requests.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(request) => request,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match request {
+                CompactionRequest::Trigger => {
+                    self.trigger_pending.store(false, Ordering::Relaxed);
+                    let due = inner
+                        .read()
+                        .is_ok_and(|state| CrabKv::compaction_due(&state, min_interval));
+                    if due {
+                        self.compact(inner, clock, None, errors);
+                    }
+                }
+                CompactionRequest::Forced => {
+                    let ticket = {
+                        let progress = self.progress.lock();
+                        // An earlier forced run already covered this request.
+                        if progress.completed >= progress.requested {
+                            continue;
+                        }
+                        progress.requested
+                    };
+                    self.compact(inner, clock, Some(ticket), errors);
+                }
+                CompactionRequest::Shutdown => break,
+            }
+        }
+    }
+
+    fn compact(
+        &self,
+        inner: &RwLock<EngineState>,
+        clock: &Clock,
+        ticket: Option<u64>,
+        errors: &BackgroundErrors,
+    ) {
+        self.progress.lock().last_started = Some(clock.now());
+        let result = CrabKv::compact_concurrently(inner, true);
+        let outcome = match result {
+            Ok(()) => CompactionOutcome::Succeeded,
+            // Not a failure of the worker; a frozen store runs it once unfrozen.
+            Err(err) if ModeRefused::of(&err).is_some() => match ticket {
+                Some(_) => CompactionOutcome::Failed(err.to_string()),
+                None => return,
+            },
+            Err(err) => {
+                let outcome = CompactionOutcome::Failed(err.to_string());
+                errors.report(BackgroundWorker::Compaction, err);
+                outcome
+            }
+        };
+        self.record(clock, outcome, ticket);
+    }
+
+    /// Stores the outcome of a run and wakes callers waiting for `ticket`.
+    fn record(&self, clock: &Clock, outcome: CompactionOutcome, ticket: Option<u64>) {
+        let mut progress = self.progress.lock();
+        progress.last_finished = Some(clock.now());
+        progress.last_outcome = Some(outcome);
+        if let Some(ticket) = ticket {
+            progress.completed = progress.completed.max(ticket);
+        }
+        drop(progress);
+        self.finished.notify_all();
+    }
+}
+
+impl EngineState {
+    /// Returns when the entry stops being readable: its TTL or the end of the
+    /// retention window, whichever comes first.
+    fn deadline(&self, entry: &IndexEntry) -> Option<SystemTime> {
+        let retained_until = self
+            .retention
+            .zip(entry.written_at)
+            .and_then(|(retention, written_at)| written_at.checked_add(retention));
+        match (entry.expires_at, retained_until) {
+            (Some(expires_at), Some(retained_until)) => Some(expires_at.min(retained_until)),
+            (expires_at, retained_until) => expires_at.or(retained_until),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_write.lock() = Some(self.clock.now());
+    }
+
+    /// Reads the value `key`'s index entry points at, from the log or its
+    /// blob file.
+    fn read_value(&self, key: &str, entry: &IndexEntry) -> io::Result<Option<String>> {
+        let record = self
+            .wal
+            .read_record(entry.pointer)
+            .map_err(|err| InvalidUtf8::naming(err, key))?;
+        match record.entry {
+            WalEntry::Put { value, .. } => Ok(Some(value)),
+            WalEntry::Bytes { .. } => Err(BinaryValue::error(key)),
+            WalEntry::Blob { blob, .. } => self.blobs.read(&blob).map(Some),
+            WalEntry::Delete { .. } | WalEntry::Token { .. } => Ok(None),
+        }
+    }
+
+    /// Moves a put's value into a blob file when it exceeds the blob threshold.
+    ///
+    /// The file is synced before the returned blob entry can reach the log.
+    fn tier(&self, entry: WalEntry) -> io::Result<WalEntry> {
+        match entry {
+            WalEntry::Put {
+                key,
+                value,
+                expires_at,
+                written_at,
+            } if self
+                .blob_threshold
+                .is_some_and(|threshold| value.len() > threshold) =>
+            {
+                let blob = self.blobs.write(&key, &value)?;
+                Ok(WalEntry::Blob {
+                    key,
+                    blob,
+                    expires_at,
+                    written_at,
+                })
+            }
+            entry => Ok(entry),
+        }
+    }
+
+    /// Deletes blob files dropped from the index; failures leave orphans for the next open.
+    fn remove_doomed_blobs(&self) {
+        for name in self.doomed_blobs.lock().drain(..) {
+            let _ = self.blobs.remove(&name);
+        }
+    }
+
+    /// Sends `key` to every live expiry subscriber, forgetting disconnected ones.
+    fn notify_expired(&self, key: &str) {
+        self.expiry_subscribers
+            .lock()
+            .retain(|tx| tx.send(key.to_owned()).is_ok());
+    }
+
+    /// Returns a put record already holding `value`, if one is known.
+    fn find_shared(&self, value: &str) -> io::Result<Option<ValuePointer>> {
+        if value.len() <= wal::REF_PAYLOAD_SIZE {
+            return Ok(None);
+        }
+        let Some(&target) = self.dedup.get(&wal::value_hash(value)) else {
+            return Ok(None);
+        };
+        // Hashes can collide; only share a record holding the very same value.
+        match self.wal.read_record(target)?.entry {
+            WalEntry::Put { value: stored, .. } if stored == value => Ok(Some(target)),
+            _ => Ok(None),
+        }
+    }
+
+    fn remember_value(&mut self, value: &str, pointer: ValuePointer) {
+        if value.len() > wal::REF_PAYLOAD_SIZE {
+            self.dedup.insert(wal::value_hash(value), pointer);
+        }
+    }
+
+    /// Points `key` at a new record, keeping prefix usage in step.
+    ///
+    /// Called once the record is appended, so the entry takes the current LSN;
+    /// every entry of a batch shares the LSN of the batch's end.
+    fn index_insert(&mut self, key: String, mut entry: IndexEntry) -> Option<IndexEntry> {
+        entry.lsn = self.wal.lsn();
+        if let Some(journal) = &mut self.journal {
+            journal.push((entry.lsn, key.clone()));
+        }
+        if let Some(modifications) = &mut self.modifications {
+            let at = entry.written_at.unwrap_or_else(|| self.clock.now());
+            modifications.record(&key, at, false);
+        }
+        if let Some(previous) = self.index.get(&key)
+            && let Some(expires_at) = previous.expires_at
+            && !CrabKv::is_expired_at(self.deadline(previous), self.clock.now())
+            && entry
+                .expires_at
+                .is_none_or(|extended| extended > expires_at)
+        {
+            self.ttl_extensions += 1;
+        }
+        if !self.quotas.is_empty() {
+            if let Some(previous) = self.index.get(&key) {
+                self.quotas.refund(&key, previous.pointer.record_len as u64);
+            }
+            self.quotas.charge(&key, entry.pointer.record_len as u64);
+        }
+        self.quarantine.release(&key);
+        let previous = self.index.insert(key, entry)?;
+        self.doom_blob(&previous);
+        Some(previous)
+    }
+
+    /// Removes those of `keys` still expired, appending their tombstones as
+    /// one batch, and returns them. Keys written again since are left alone.
+    fn remove_expired(&mut self, keys: Vec<String>) -> io::Result<Vec<String>> {
+        let now = self.clock.now();
+        let tombstones: Vec<WalEntry> = keys
+            .into_iter()
+            .filter(|key| {
+                self.index
+                    .get(key)
+                    .is_some_and(|entry| CrabKv::is_expired_at(self.deadline(entry), now))
+            })
+            .map(|key| WalEntry::Delete { key })
+            .collect();
+        if tombstones.is_empty() {
+            return Ok(Vec::new());
+        }
+        let appended = self.wal.append_batch(&tombstones)?;
+        self.total_bytes += appended.bytes;
+        let mut removed = Vec::with_capacity(tombstones.len());
+        for tombstone in tombstones {
+            let WalEntry::Delete { key } = tombstone else {
+                unreachable!("only tombstones are appended");
+            };
+            if let Some(entry) = self.index_remove(&key) {
+                self.stale_bytes += entry.owned_len();
+                if let Some(cache) = &self.cache {
+                    cache.remove_clean(&key);
+                }
+            }
+            removed.push(key);
+        }
+        Ok(removed)
+    }
+
+    /// Records a user delete of `key` for [`CrabKv::modified_since`]; expiry
+    /// is not a modification.
+    fn note_deleted(&mut self, key: &str) {
+        if let Some(modifications) = &mut self.modifications {
+            modifications.record(key, self.clock.now(), true);
+        }
+    }
+
+    /// Drops `key` from the index, releasing its prefix usage.
+    fn index_remove(&mut self, key: &str) -> Option<IndexEntry> {
+        let previous = self.index.remove(key)?;
+        self.last_delete_lsn = self.wal.lsn();
+        if let Some(journal) = &mut self.journal {
+            journal.push((self.last_delete_lsn, key.to_string()));
+        }
+        self.quotas.refund(key, previous.pointer.record_len as u64);
+        self.doom_blob(&previous);
+        Some(previous)
+    }
+
+    /// Schedules removal of the blob file an entry dropped from the index pointed at.
+    fn doom_blob(&self, previous: &IndexEntry) {
+        if let Some(name) = &previous.blob {
+            self.doomed_blobs.lock().push(name.clone());
+        }
+    }
+
+    /// Copies the index as of the end of the log; the fingerprint is filled
+    /// in once the log is synced.
+    fn checkpoint(&self) -> Checkpoint {
+        let entries = self
+            .index
+            .iter()
+            .map(|(key, entry)| CheckpointEntry {
+                key: key.clone(),
+                entry: LoadedEntry {
+                    pointer: entry.pointer,
+                    expires_at: entry.expires_at,
+                    written_at: entry.written_at,
+                    blob: entry.blob.is_some(),
+                },
+                ref_len: entry.ref_len,
+            })
+            .collect();
+        let now = self.clock.now();
+        let tokens = self
+            .seen_tokens
+            .entries(now)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                WalEntry::Token {
+                    token,
+                    outcome,
+                    expires_at,
+                } => Some((token, outcome, expires_at)),
+                _ => None,
+            })
+            .collect();
+        Checkpoint {
+            generation: self.generation,
+            offset: self.total_bytes,
+            fingerprint: 0,
+            taken_at: now,
+            stale_bytes: self.stale_bytes,
+            entries,
+            tokens,
+            shared_values: self.dedup.iter().map(|(hash, at)| (*hash, *at)).collect(),
+        }
+    }
+
+    /// Drops expired keys and returns the live keys a new generation of the log
+    /// is copied from, in key order, with the idempotency tokens written after
+    /// them. Then starts journaling writes until the generation is installed.
+    ///
+    /// Only pointers are collected; values are read while the generation is
+    /// written, a bounded window at a time.
+    fn begin_compaction(&mut self) -> io::Result<(Vec<LiveRecord>, Vec<WalEntry>)> {
+        self.mutations_since_compaction.store(0, Ordering::Relaxed);
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        let mut live = Vec::with_capacity(self.index.len());
+        for (key, entry) in self.index.iter() {
+            if CrabKv::is_expired_at(self.deadline(entry), now) {
+                expired.push(key.clone());
+            } else {
+                live.push(LiveRecord {
+                    key: key.clone(),
+                    pointer: entry.pointer,
+                    expires_at: entry.expires_at,
+                    written_at: entry.written_at,
+                });
+            }
+        }
+
+        // Keys reads already queued were reported when they were found.
+        self.expiry_queue.note_expired(expired.len());
+        let reported = self.expiry_queue.forget(&expired);
+        for key in expired {
+            if let Some(entry) = self.index.remove(&key)
+                && let Some(name) = entry.blob
+            {
+                self.doomed_blobs.lock().push(name);
+            }
+            if let Some(cache) = &self.cache {
+                cache.remove_clean(&key);
+            }
+            if !reported.contains(&key) {
+                self.notify_expired(&key);
+            }
+        }
+
+        live.sort_by(|a, b| a.key.cmp(&b.key));
+        // Open idempotency windows must outlive the records that opened them.
+        let tokens = self.seen_tokens.entries(now);
+        self.journal = Some(Vec::new());
+        self.token_journal = Some(Vec::new());
+        Ok((live, tokens))
+    }
+
+    /// Sets aside keys whose record the new generation could not copy, unless
+    /// a write since replaced the record.
+    ///
+    /// Must run before the index is replaced; `rewritten` holds the keys the
+    /// journal is replaying.
+    fn quarantine_damaged(
+        &mut self,
+        damaged: Vec<DamagedRecord>,
+        rewritten: &HashSet<&str>,
+    ) -> io::Result<()> {
+        if damaged.is_empty() {
+            return Ok(());
+        }
+        for damaged in damaged {
+            if rewritten.contains(damaged.key.as_str()) {
+                continue;
+            }
+            self.set_aside(QuarantinedKey {
+                key: damaged.key,
+                offset: damaged.offset,
+                error: damaged.error,
+            });
+        }
+        self.quarantine.save()
+    }
 
-        let entry = WalEntry::Delete {
-            key: key.to_owned(),
+    /// Moves `key` from the index to the quarantine if its record still holds
+    /// a value that is not valid UTF-8, and returns whether it did; a write
+    /// since may have replaced it.
+    fn quarantine_invalid_utf8(&mut self, key: &str) -> io::Result<bool> {
+        let Some(entry) = self.index.get(key) else {
+            return Ok(false);
         };
-        let pointer = state.wal.append(&entry)?;
-        state.total_bytes += pointer.record_len as u64;
-
-        if let Some(previous) = state.index.remove(key) {
-            state.stale_bytes += previous.pointer.record_len as u64;
+        let err = match self.wal.read_record(entry.pointer) {
+            Err(err) if InvalidUtf8::of(&err).is_some() => InvalidUtf8::naming(err, key),
+            _ => return Ok(false),
+        };
+        if let Some(previous) = self.index_remove(key) {
+            self.stale_bytes += previous.owned_len();
         }
-
-        if let Some(cache) = &state.cache {
-            cache.remove(key);
+        let offset = InvalidUtf8::of(&err).map_or(0, |invalid| invalid.offset);
+        self.set_aside(QuarantinedKey {
+            key: key.to_owned(),
+            offset,
+            error: err.to_string(),
+        });
+        // Reads get here in any mode; otherwise the next write saves it.
+        if self.mode.current().allows_writes() {
+            self.quarantine.save()?;
         }
-
-        self.maybe_compact_async(&mut state)
+        Ok(true)
     }
 
-    /// Forces a compaction cycle regardless of the current heuristic.
-    pub fn compact(&self) -> io::Result<()> {
-        let mut state = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
-        Self::run_compaction(&mut state)
+    /// The live keys grouped by the offset of the record holding their value.
+    fn keys_by_offset(&self) -> HashMap<u64, Vec<String>> {
+        let mut holders: HashMap<u64, Vec<String>> = HashMap::new();
+        for (key, entry) in self.index.iter() {
+            holders
+                .entry(entry.pointer.offset)
+                .or_default()
+                .push(key.clone());
+        }
+        holders
     }
 
-    fn expire_key(&self, key: &str) -> io::Result<Option<String>> {
-        let mut state = self
-            .inner
-            .write()
-            .map_err(|_| io::Error::new(ErrorKind::Other, "engine poisoned"))?;
-
-        if let Some(entry) = state.index.remove(key) {
-            state.stale_bytes += entry.pointer.record_len as u64;
-            if let Some(cache) = &state.cache {
-                cache.remove(key);
+    /// Quarantines those of `keys` whose value is still in the damaged record
+    /// at `offset` and returns them; the caller saves the quarantine.
+    fn quarantine_scrubbed(&mut self, offset: u64, keys: Vec<String>, error: &str) -> Vec<String> {
+        // The lock was let go between findings, so a key may have moved on.
+        let keys: Vec<String> = keys
+            .into_iter()
+            .filter(|key| {
+                self.index
+                    .get(key)
+                    .is_some_and(|entry| entry.pointer.offset == offset)
+            })
+            .collect();
+        for key in &keys {
+            if let Some(previous) = self.index_remove(key) {
+                self.stale_bytes += previous.owned_len();
             }
-            let delete = WalEntry::Delete {
-                key: key.to_owned(),
-            };
-            let pointer = state.wal.append(&delete)?;
-            state.total_bytes += pointer.record_len as u64;
+            self.set_aside(QuarantinedKey {
+                key: key.clone(),
+                offset,
+                error: error.to_owned(),
+            });
         }
+        keys
+    }
 
-        Ok(None)
+    /// Quarantines a key already out of the index; the caller saves the quarantine.
+    fn set_aside(&mut self, key: QuarantinedKey) {
+        if let Some(cache) = &self.cache {
+            cache.remove_clean(&key.key);
+        }
+        self.events.record(EventKind::Quarantined {
+            key: key.key.clone(),
+            offset: key.offset,
+        });
+        self.quarantine.insert(key);
     }
 
-    #[allow(dead_code)]
-    fn maybe_compact(state: &mut EngineState) -> io::Result<()> {
-        if compaction::should_compact(state.total_bytes, state.stale_bytes) {
-            Self::run_compaction(state)
-        } else {
-            Ok(())
+    /// Records how a compaction ended and saves the events file.
+    fn note_compaction(&self, result: &io::Result<()>, background: bool) {
+        self.events.record(match result {
+            Ok(()) => EventKind::CompactionFinished {
+                background,
+                log_bytes: self.total_bytes,
+            },
+            Err(err) => EventKind::CompactionFailed {
+                background,
+                error: err.to_string(),
+            },
+        });
+        // The file only helps diagnose; failing to write it must not fail the run.
+        let _ = self.events.save();
+    }
+
+    /// Brings a written generation up to date with the journal and swaps it in.
+    fn finish_compaction(&mut self, generation: io::Result<Generation>) -> io::Result<()> {
+        let journal = self.journal.take().unwrap_or_default();
+        let tokens = self.token_journal.take().unwrap_or_default();
+        let mut generation = generation?;
+        let rewritten: HashSet<&str> = journal.iter().map(|(_, key)| key.as_str()).collect();
+        self.quarantine_damaged(generation.take_damaged(), &rewritten)?;
+        drop(rewritten);
+        self.peak_buffer
+            .fetch_max(generation.peak_buffer_bytes(), Ordering::Relaxed);
+        let mut tail = self.replay_journal(journal)?;
+        let now = self.clock.now();
+        tail.extend(
+            tokens
+                .iter()
+                .filter_map(|token| self.seen_tokens.entry(token, now)),
+        );
+        // Its pointers are into the log about to be replaced.
+        checkpoint::remove(&checkpoint::path(self.wal.path()))?;
+        let mut rebuilt = self.wal.install(generation, &tail)?;
+        self.generation = self.wal.generation();
+        // Watchers blocked on the old generation find it gone once they can read.
+        self.changes.wake_watchers();
+        let stale_bytes = rebuilt.stale_bytes;
+        let shared_values = std::mem::take(&mut rebuilt.shared_values);
+        let mut index = IndexEntry::from_loaded(rebuilt, &self.wal, self.index.is_ordered())?;
+        // Open read views still compare against the LSN of each key's last write.
+        for (key, entry) in &mut index {
+            if let Some(previous) = self.index.get(key) {
+                entry.lsn = previous.lsn;
+            }
         }
+        self.index = index;
+        self.remove_doomed_blobs();
+        // The old records are gone; values written since the snapshot refill it.
+        self.dedup = shared_values;
+        self.rebuild_quotas();
+        self.total_bytes = self.wal.size()?;
+        self.checkpointed_len = self.total_bytes;
+        self.stale_bytes = stale_bytes;
+        self.last_compaction = Some(Instant::now());
+        self.compactions += 1;
+        Ok(())
     }
 
-    fn maybe_compact_async(&self, state: &mut EngineState) -> io::Result<()> {
-        if compaction::should_compact(state.total_bytes, state.stale_bytes) {
-            if let Some(tx) = &self.compaction_tx {
-                let _ = tx.send(CompactionRequest::Trigger);
-                Ok(())
-            } else {
-                Self::run_compaction(state)
+    /// Turns journaled writes into the log entries that replay them onto a new
+    /// generation, in log sequence order.
+    ///
+    /// Each key's current state is written once, at the position of its last
+    /// change: its live value, or a delete when it is gone.
+    fn replay_journal(&self, mut journal: Vec<(u64, String)>) -> io::Result<Vec<WalEntry>> {
+        // The sort is stable, so keys of one batch keep their order.
+        journal.sort_by_key(|(lsn, _)| *lsn);
+        let last_change: HashMap<&str, usize> = journal
+            .iter()
+            .enumerate()
+            .map(|(position, (_, key))| (key.as_str(), position))
+            .collect();
+        let mut tail = Vec::new();
+        for (position, (_, key)) in journal.iter().enumerate() {
+            if last_change[key.as_str()] != position {
+                continue;
+            }
+            let Some(entry) = self.index.get(key) else {
+                tail.push(WalEntry::Delete { key: key.clone() });
+                continue;
+            };
+            match self.wal.read_record(entry.pointer)?.entry {
+                WalEntry::Put { value, .. } => tail.push(WalEntry::Put {
+                    key: key.clone(),
+                    value,
+                    expires_at: entry.expires_at,
+                    written_at: entry.written_at,
+                }),
+                WalEntry::Bytes { value, .. } => tail.push(WalEntry::Bytes {
+                    key: key.clone(),
+                    value,
+                    expires_at: entry.expires_at,
+                    written_at: entry.written_at,
+                }),
+                WalEntry::Blob { blob, .. } => tail.push(WalEntry::Blob {
+                    key: key.clone(),
+                    blob,
+                    expires_at: entry.expires_at,
+                    written_at: entry.written_at,
+                }),
+                WalEntry::Delete { .. } | WalEntry::Token { .. } => {}
             }
-        } else {
-            Ok(())
         }
+        Ok(tail)
     }
 
-    fn run_compaction(state: &mut EngineState) -> io::Result<()> {
-        let mut entries = Vec::with_capacity(state.index.len());
-        let now = SystemTime::now();
-        let mut expired = Vec::new();
+    fn rebuild_quotas(&mut self) {
+        self.quotas.rebuild(
+            self.index
+                .iter()
+                .map(|(key, entry)| (key.as_str(), entry.pointer.record_len as u64)),
+        );
+    }
 
-        for (key, entry) in state.index.iter() {
-            if Self::is_expired_at(entry.expires_at, now) {
-                expired.push(key.clone());
+    /// Checks puts against `quotas`, charging it for each entry that passes.
+    fn validate_entries(
+        &self,
+        quotas: &mut PrefixQuotas,
+        entries: &[(String, String, Option<SystemTime>)],
+        now: SystemTime,
+    ) -> io::Result<Vec<ValidationIssue>> {
+        let mut issues = Vec::new();
+        // Record lengths of keys written earlier in the batch.
+        let mut written: HashMap<&str, u64> = HashMap::new();
+        for (key, value, expires_at) in entries {
+            let tiered = self
+                .blob_threshold
+                .is_some_and(|threshold| value.len() > threshold);
+            let payload = if tiered { 0 } else { value.len() };
+            let bytes = key.len().max(payload) as u64;
+            if bytes > u32::MAX as u64 {
+                issues.push(ValidationIssue {
+                    key: key.clone(),
+                    problem: ValidationProblem::TooLarge { bytes },
+                });
                 continue;
             }
-            let record = state.wal.read_record(entry.pointer)?;
-            if let WalEntry::Put {
-                value, expires_at, ..
-            } = record.entry
-            {
-                entries.push((key.clone(), value, expires_at));
+            if !quotas.covers(key) {
+                continue;
             }
+            let new_len = self.wal.encoded_len(&WalEntry::Put {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: *expires_at,
+                written_at: Some(now),
+            })?;
+            let old_len = match written.get(key.as_str()) {
+                Some(len) => *len,
+                None => self
+                    .index
+                    .get(key)
+                    .map_or(0, |entry| entry.pointer.record_len as u64),
+            };
+            if let Err(err) = quotas.check(&[(key, old_len, new_len)]) {
+                issues.push(ValidationIssue {
+                    key: key.clone(),
+                    problem: ValidationProblem::QuotaExceeded(err),
+                });
+                continue;
+            }
+            quotas.refund(key, old_len);
+            quotas.charge(key, new_len);
+            written.insert(key, new_len);
         }
+        Ok(issues)
+    }
 
-        for key in expired {
-            state.index.remove(&key);
-            if let Some(cache) = &state.cache {
-                cache.remove(&key);
+    /// Rejects `entries` if applying them would push a prefix past its quota.
+    fn check_quotas(&self, entries: &[WalEntry]) -> io::Result<()> {
+        if self.quotas.is_empty() {
+            return Ok(());
+        }
+        // Only the last write to a key in the batch survives.
+        let mut changes: Vec<(&str, u64, u64)> = Vec::new();
+        for entry in entries {
+            let key = entry.key();
+            if matches!(entry, WalEntry::Token { .. }) || !self.quotas.covers(key) {
+                continue;
+            }
+            let new_len = match entry {
+                WalEntry::Put { .. } | WalEntry::Bytes { .. } | WalEntry::Blob { .. } => {
+                    self.wal.encoded_len(entry)?
+                }
+                WalEntry::Delete { .. } | WalEntry::Token { .. } => 0,
+            };
+            match changes.iter_mut().find(|(changed, _, _)| *changed == key) {
+                Some(change) => change.2 = new_len,
+                None => {
+                    let old_len = self
+                        .index
+                        .get(key)
+                        .map_or(0, |entry| entry.pointer.record_len as u64);
+                    changes.push((key, old_len, new_len));
+                }
             }
         }
-
-        entries.sort_by(|a, b| a.0.cmp(&b.0));
-        let rebuilt = state.wal.rewrite(&entries)?;
-        state.index = rebuilt
-            .into_iter()
-            .map(|(key, (pointer, expires_at))| {
-                (
-                    key,
-                    IndexEntry {
-                        pointer,
-                        expires_at,
-                    },
-                )
-            })
-            .collect();
-        state.total_bytes = state.wal.size()?;
-        state.stale_bytes = 0;
+        self.quotas.check(&changes)?;
         Ok(())
     }
-
-    fn is_expired(expires_at: Option<SystemTime>) -> bool {
-        Self::is_expired_at(expires_at, SystemTime::now())
-    }
-
-    fn is_expired_at(expires_at: Option<SystemTime>, now: SystemTime) -> bool {
-        matches!(expires_at, Some(deadline) if now >= deadline)
-    }
 }
 
 impl CrabKvBuilder {
@@ -408,30 +5023,40 @@ impl CrabKvBuilder {
     pub fn new(directory: impl AsRef<Path>) -> Self {
         Self {
             directory: directory.as_ref().to_path_buf(),
-            cache_capacity: None,
-            default_ttl: None,
-            sync_interval: None,
+            config: EngineConfig::default(),
             async_compaction: false,
-            compression: false,
-            write_back_cache: false,
+            cache_eviction_listener: None,
+            free_space_probe: FreeSpaceProbe::system(),
+            soft_limits: SoftLimitLevels::default(),
+            threshold_listener: None,
         }
     }
 
+    /// Replaces every engine setting with `config`, such as one read from a
+    /// config file; later builder calls adjust it further.
+    ///
+    /// Hooks and runtime choices set on the builder, like listeners and async
+    /// compaction, are kept.
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
     /// Enables an LRU cache sized by the provided entry count.
     pub fn cache_capacity(mut self, capacity: NonZeroUsize) -> Self {
-        self.cache_capacity = Some(capacity);
+        self.config.cache_capacity = Some(capacity);
         self
     }
 
     /// Applies a default TTL to future writes.
     pub fn default_ttl(mut self, ttl: Duration) -> Self {
-        self.default_ttl = Some(ttl);
+        self.config.default_ttl = Some(ttl);
         self
     }
 
     /// Sets a sync interval for periodic WAL flushes instead of fsyncing every write.
     pub fn sync_interval(mut self, interval: Duration) -> Self {
-        self.sync_interval = Some(interval);
+        self.config.sync_interval = Some(interval);
         self
     }
 
@@ -443,80 +5068,682 @@ impl CrabKvBuilder {
 
     /// Enables Snappy compression for values written to the WAL.
     pub fn compression(mut self, enabled: bool) -> Self {
-        self.compression = enabled;
+        self.config.compression = enabled;
         self
     }
 
     /// Enables write-back caching mode that buffers writes in memory before flushing.
     pub fn write_back_cache(mut self, enabled: bool) -> Self {
-        self.write_back_cache = enabled;
+        self.config.write_back_cache = enabled;
+        self
+    }
+
+    /// Serves cached values for at most `ttl` before re-reading them from the WAL.
+    pub fn cache_entry_ttl(mut self, ttl: Duration) -> Self {
+        self.config.cache_entry_ttl = Some(ttl);
+        self
+    }
+
+    /// Prevents heuristic-triggered compactions from running more than once per interval.
+    ///
+    /// Explicit calls to [`CrabKv::compact`] are not affected.
+    pub fn min_compaction_interval(mut self, interval: Duration) -> Self {
+        self.config.min_compaction_interval = Some(interval);
+        self
+    }
+
+    /// Chooses when writes start a compaction; see [`CompactionPolicy`].
+    pub fn compaction_policy(mut self, policy: CompactionPolicy) -> Self {
+        self.config.compaction_policy = policy;
+        self
+    }
+
+    /// Checkpoints the index in the background after every `mutations` puts
+    /// and deletes, so reopening after a crash replays only the log written
+    /// since; see [`CrabKv::checkpoint`].
+    ///
+    /// Checkpoints are skipped while a compaction runs or the engine is under
+    /// pressure, and started at most once per
+    /// [`CrabKvBuilder::checkpoint_min_interval`].
+    pub fn checkpoint_mutations(mut self, mutations: u64) -> Self {
+        self.config.checkpoint_mutations = Some(mutations.max(1));
+        self
+    }
+
+    /// Checkpoints the index in the background once the log has grown
+    /// `bytes` past the last checkpoint or compaction, like
+    /// [`CrabKvBuilder::checkpoint_mutations`].
+    pub fn checkpoint_bytes(mut self, bytes: u64) -> Self {
+        self.config.checkpoint_bytes = Some(bytes.max(1));
+        self
+    }
+
+    /// Starts background checkpoints at most once per `interval`; 30 seconds
+    /// unless set.
+    pub fn checkpoint_min_interval(mut self, interval: Duration) -> Self {
+        self.config.checkpoint_min_interval = interval;
+        self
+    }
+
+    /// Reports the store degraded through [`CheckpointStatus::degraded`]
+    /// and the server's `HEALTH` once the latest checkpoint is older than
+    /// `age` while the log has grown past it.
+    pub fn checkpoint_max_age(mut self, age: Duration) -> Self {
+        self.config.checkpoint_max_age = Some(age);
+        self
+    }
+
+    /// Invokes `listener` with each key the LRU cache evicts to make room.
+    ///
+    /// A panicking listener disables the cache rather than failing the write.
+    pub fn cache_eviction_listener(
+        mut self,
+        listener: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Self {
+        self.cache_eviction_listener = Some(EvictionListener::new(listener));
+        self
+    }
+
+    /// Steps over WAL records with unrecognised opcodes instead of failing to open.
+    ///
+    /// Lets older builds read logs extended by newer ones; the skipped records are
+    /// discarded by the next compaction.
+    pub fn skip_unknown_ops(mut self, enabled: bool) -> Self {
+        self.config.skip_unknown_ops = enabled;
+        self
+    }
+
+    /// Chooses what happens to stored values that are not valid UTF-8; by
+    /// default reading one fails. See [`Utf8Policy`].
+    pub fn utf8_policy(mut self, policy: Utf8Policy) -> Self {
+        self.config.utf8_policy = policy;
+        self
+    }
+
+    /// Chooses how keys are compared; by default byte for byte. See
+    /// [`KeyPolicy`].
+    ///
+    /// A new store records the policy in its manifest, and reopening it with
+    /// another fails with a [`KeyPolicyMismatch`](crate::KeyPolicyMismatch) error.
+    pub fn key_policy(mut self, policy: KeyPolicy) -> Self {
+        self.config.key_policy = policy;
+        self
+    }
+
+    /// Retries a failed write-back flush up to `retries` times on transient WAL errors.
+    ///
+    /// The first retry waits `backoff`, each later one twice as long as the last.
+    pub fn flush_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.config.flush_retries = retries;
+        self.config.flush_retry_backoff = backoff;
+        self
+    }
+
+    /// Limits the live bytes stored under keys starting with `prefix`.
+    ///
+    /// Writes that would push the prefix past `max_bytes` fail with an error of
+    /// kind [`io::ErrorKind::QuotaExceeded`] carrying a
+    /// [`QuotaExceeded`](crate::quota::QuotaExceeded). Buffered write-back puts
+    /// are checked against flushed usage only, so the limit is a soft one.
+    pub fn prefix_quota(mut self, prefix: &str, max_bytes: u64) -> Self {
+        self.config
+            .prefix_quotas
+            .push((prefix.to_owned(), max_bytes));
+        self
+    }
+
+    /// Stores a value written again under another key only once.
+    ///
+    /// A `put` of a value already written since the engine was opened appends a
+    /// small reference record instead of a second copy, and compaction keeps a
+    /// single copy of every distinct value. Batches and write-back flushes are
+    /// not deduplicated, and short values are always stored inline.
+    pub fn value_dedup(mut self, enabled: bool) -> Self {
+        self.config.value_dedup = enabled;
+        self
+    }
+
+    /// Refuses puts with [`io::ErrorKind::StorageFull`] while the data directory's
+    /// filesystem has fewer than `bytes` available.
+    ///
+    /// Free space is re-read at most once a second. Deletes are always allowed.
+    pub fn min_free_bytes(mut self, bytes: u64) -> Self {
+        self.config.min_free_bytes = Some(bytes);
+        self
+    }
+
+    /// Replaces the free-space query used by [`CrabKvBuilder::min_free_bytes`].
+    pub fn free_space_probe(
+        mut self,
+        probe: impl Fn(&Path) -> io::Result<u64> + Send + Sync + 'static,
+    ) -> Self {
+        self.free_space_probe = FreeSpaceProbe::new(probe);
+        self
+    }
+
+    /// Treats records written more than `retention` ago as expired.
+    ///
+    /// Applies on top of per-key TTLs, whichever deadline comes first. Reads stop
+    /// returning such keys and compaction drops them. Records from logs that
+    /// predate write times have no known age and are kept.
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.config.retention = Some(retention);
+        self
+    }
+
+    /// Replaces the clock used for write times, expiry, and retention.
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    /// Stores values longer than `bytes` in their own files under `blobs/`.
+    ///
+    /// The log keeps a short reference instead, so compaction copies the
+    /// reference rather than the value. Blob values bypass the read cache.
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.config.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// Sets the index size above which [`CrabKv::stats`] leaves out the key and
+    /// value size histograms; [`CrabKv::stats_with_histograms`] ignores it.
+    pub fn histogram_limit(mut self, keys: usize) -> Self {
+        self.config.histogram_limit = keys;
+        self
+    }
+
+    /// Keeps keys ordered by their latest write so [`CrabKv::modified_since`]
+    /// answers without scanning the index.
+    ///
+    /// Costs one extra map entry per key, plus one per key deleted since open.
+    pub fn track_modifications(mut self, enabled: bool) -> Self {
+        self.config.track_modifications = enabled;
+        self
+    }
+
+    /// Keeps the index sorted by key so [`CrabKv::range`] walks only the keys
+    /// it returns; without it every range scan goes through the whole index.
+    ///
+    /// Lookups and writes cost O(log n) instead of O(1).
+    pub fn ordered_index(mut self, enabled: bool) -> Self {
+        self.config.ordered_index = enabled;
+        self
+    }
+
+    /// Spreads the decompression and recompression of values during
+    /// compaction over `threads` workers; defaults to 1.
+    ///
+    /// Records are still written in key order, so the rewritten log is the same
+    /// byte for byte whatever the setting. Workers take a bounded window of
+    /// records at a time, which caps the extra memory held in flight. Mostly
+    /// worth raising with [`compression`](Self::compression) on.
+    pub fn compaction_threads(mut self, threads: usize) -> Self {
+        self.config.compaction_threads = threads.max(1);
+        self
+    }
+
+    /// Lets compaction hold about `bytes` of stored values in memory at once
+    /// while it copies live records; defaults to 8 MiB. A single larger value
+    /// is still copied, on its own.
+    pub fn compaction_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.config.compaction_buffer_bytes = bytes;
+        self
+    }
+
+    /// Keeps the latest `events` engine events for [`CrabKv::recent_events`];
+    /// defaults to 256. Zero records none.
+    pub fn event_capacity(mut self, events: usize) -> Self {
+        self.config.event_capacity = events;
+        self
+    }
+
+    /// Remembers the token of each write made with
+    /// [`WriteOptions::idempotency_key`] for `window`, turning repeats within
+    /// it into no-ops; defaults to 10 minutes. The window survives restarts.
+    pub fn idempotency_window(mut self, window: Duration) -> Self {
+        self.config.idempotency_window = window;
+        self
+    }
+
+    /// Remembers at most `tokens` idempotency tokens, forgetting those whose
+    /// window ends soonest first; defaults to 100 000. Zero remembers none.
+    pub fn idempotency_capacity(mut self, tokens: usize) -> Self {
+        self.config.idempotency_capacity = tokens;
+        self
+    }
+
+    /// Reports the store degraded through [`CrabKv::recent_background_error`]
+    /// for `window` after a background worker fails; defaults to 5 minutes.
+    pub fn background_error_window(mut self, window: Duration) -> Self {
+        self.config.background_error_window = window;
+        self
+    }
+
+    /// Checks every record of the log once per `interval` on a background
+    /// thread, at no more than
+    /// [`scrub_bytes_per_sec`](Self::scrub_bytes_per_sec); off by default.
+    /// See [`CrabKv::scrub_now`] for what a pass does.
+    pub fn scrub_interval(mut self, interval: Duration) -> Self {
+        self.config.scrub_interval = Some(interval);
+        self
+    }
+
+    /// Caps how fast the background scrub reads the log; 0 lifts the cap.
+    /// Defaults to 8 MiB a second.
+    pub fn scrub_bytes_per_sec(mut self, bytes_per_sec: u64) -> Self {
+        self.config.scrub_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Removes expired keys on a background thread once per `interval`, so
+    /// keys nobody reads again do not linger until a compaction; off by
+    /// default. Tombstones are appended and `stale_bytes` grows as if the keys
+    /// were deleted, and the write lock is taken once per
+    /// [`SWEEP_BATCH`](crate::expiry::SWEEP_BATCH) keys. Sweeps skip a store
+    /// whose [`EngineMode`] refuses writes.
+    pub fn ttl_sweep_interval(mut self, interval: Duration) -> Self {
+        self.config.ttl_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Lets [`build`](Self::build) create a store in a directory that already
+    /// holds other files; off by default, so pointing the engine at the wrong
+    /// path fails with `DirectoryNotEmpty` instead of mixing its files in.
+    pub fn allow_existing_dir(mut self, allow: bool) -> Self {
+        self.config.allow_existing_dir = allow;
+        self
+    }
+
+    /// Fails [`build`](Self::build) with a [`StoreIdMismatch`](crate::StoreIdMismatch) when the
+    /// directory's manifest names a store other than `id`. A directory without
+    /// a manifest records `id` as its store id.
+    pub fn expected_store_id(mut self, id: impl Into<String>) -> Self {
+        self.config.expected_store_id = Some(id.into());
+        self
+    }
+
+    /// Invokes `listener` when a soft limit set with the `soft_*` methods is
+    /// crossed, as a warning before any hard failure.
+    ///
+    /// Limits are checked at the end of every write. Each fires once when its
+    /// gauge rises above it and again only after the gauge has dropped 10%
+    /// below it. The listener runs on the writing thread with the engine lock
+    /// held, so it must be quick and must not call back into the engine.
+    pub fn on_threshold(
+        mut self,
+        listener: impl Fn(ThresholdEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.threshold_listener = Some(ThresholdListener::new(listener));
+        self
+    }
+
+    /// Fires [`ThresholdEvent::WalBytesAbove`] once the log grows past `bytes`.
+    pub fn soft_wal_bytes(mut self, bytes: u64) -> Self {
+        self.soft_limits.wal_bytes = Some(bytes);
+        self
+    }
+
+    /// Fires [`ThresholdEvent::StaleRatioAbove`] once more than `ratio` of the
+    /// log is stale.
+    pub fn soft_stale_ratio(mut self, ratio: f64) -> Self {
+        self.soft_limits.stale_ratio = Some(ratio);
+        self
+    }
+
+    /// Fires [`ThresholdEvent::KeyCountAbove`] once the index holds more than `keys`.
+    pub fn soft_key_count(mut self, keys: u64) -> Self {
+        self.soft_limits.keys = Some(keys);
+        self
+    }
+
+    /// Fires [`ThresholdEvent::WriteBufferAbove`] once more than `writes` wait
+    /// in the write-back buffer.
+    pub fn soft_write_buffer(mut self, writes: usize) -> Self {
+        self.soft_limits.write_buffer = Some(writes);
         self
     }
 
     /// Builds the engine, loading the WAL contents into memory.
-    pub fn build(self) -> io::Result<CrabKv> {
+    pub fn build(mut self) -> io::Result<CrabKv> {
+        self.config.key_policy.check_supported()?;
         std::fs::create_dir_all(&self.directory)?;
+        let (manifest, fresh) = manifest::prepare(
+            &self.directory,
+            self.config.allow_existing_dir,
+            self.config.expected_store_id.as_deref(),
+            self.config.key_policy,
+        )?;
+        // Expiry is decided against a clock that never runs backwards.
+        let (clock, clock_steps) = self.config.clock.monotonic();
+        self.config.clock = clock;
         let wal_path = self.directory.join("wal.log");
-        let wal = Wal::open(&wal_path, self.sync_interval, self.compression)?;
-        let (raw_index, stale_bytes) = wal.load_index()?;
-        let index = raw_index
-            .into_iter()
-            .map(|(key, (pointer, expires_at))| {
-                (
-                    key,
-                    IndexEntry {
-                        pointer,
-                        expires_at,
-                    },
-                )
-            })
+        let background = Arc::new(BackgroundErrors::new(self.config.clock.clone()));
+        let wal = Wal::open(
+            &wal_path,
+            self.config.sync_interval,
+            self.config.compression,
+        )?
+        .skip_unknown_ops(self.config.skip_unknown_ops)
+        .utf8_policy(self.config.utf8_policy)
+        .value_dedup(self.config.value_dedup)
+        .compaction_threads(self.config.compaction_threads)
+        .report_to(&background);
+        let (checkpoint, ignored_checkpoint) = Self::usable_checkpoint(&wal)?;
+        let checkpoint_offset = checkpoint.as_ref().map(|checkpoint| checkpoint.offset);
+        let checkpoint_taken = checkpoint.as_ref().map(|checkpoint| checkpoint.taken_at);
+        let mut loaded = match checkpoint {
+            Some(checkpoint) => wal.resume_index(checkpoint, self.config.clock.now())?,
+            None => wal.load_index(self.config.clock.now())?,
+        };
+        let replayed_bytes = loaded.valid_len - checkpoint_offset.unwrap_or(0);
+        let invalid_utf8 = std::mem::take(&mut loaded.invalid_utf8);
+        let file_len = wal.size()?;
+        let torn_bytes = file_len.saturating_sub(loaded.valid_len);
+        if torn_bytes > 0 {
+            // Appending after a torn record would leave it in the middle of the log.
+            wal.truncate(loaded.valid_len)?;
+        }
+        let mut stale_bytes = loaded.stale_bytes;
+        let mut expired_skipped = loaded.expired_skipped;
+        let unknown_skipped = loaded.unknown_skipped;
+        // Turning the loaded map into the index holds both at once.
+        let index_bytes =
+            loaded.index.len() as u64 * (size_of::<(String, IndexEntry)>() + 1) as u64;
+        let peak_memory_bytes = loaded
+            .peak_memory_bytes
+            .max(loaded.held_bytes() + index_bytes);
+        let dedup = std::mem::take(&mut loaded.shared_values);
+        let now = self.config.clock.now();
+        let mut seen_tokens = SeenTokens::new(self.config.idempotency_capacity);
+        for (token, (outcome, expires_at)) in std::mem::take(&mut loaded.tokens) {
+            seen_tokens.insert(token, outcome, expires_at, now);
+        }
+        let mut index = IndexEntry::from_loaded(loaded, &wal, self.config.ordered_index)?;
+        // Entries dropped by the retention window below still have records in
+        // the log, so their files are kept until compaction rewrites it.
+        let referenced: HashSet<String> = index
+            .values()
+            .filter_map(|entry| entry.blob.clone())
             .collect();
+        let blobs = BlobStore::new(self.directory.join("blobs"));
+        let orphaned_blobs = blobs.remove_orphans(&referenced)?;
+        if let Some(retention) = self.config.retention
+            && let Some(cutoff) = self.config.clock.now().checked_sub(retention)
+        {
+            index.retain(|_, entry| {
+                let retained = entry
+                    .written_at
+                    .is_none_or(|written_at| written_at > cutoff);
+                if !retained {
+                    stale_bytes += entry.owned_len();
+                    expired_skipped += 1;
+                }
+                retained
+            });
+        }
+        let open_report = OpenReport {
+            live_keys: index.len(),
+            stale_bytes,
+            expired_skipped,
+            unknown_skipped,
+            torn_bytes,
+            orphaned_blobs,
+            peak_memory_bytes,
+            checkpoint_offset,
+            replayed_bytes,
+        };
+        // Deletes leave no write time in the log, so only live keys are known.
+        let modifications = self.config.track_modifications.then(|| {
+            let mut modifications = ModificationIndex::default();
+            for (key, entry) in &index {
+                if let Some(written_at) = entry.written_at {
+                    modifications.record(key, written_at, false);
+                }
+            }
+            modifications
+        });
         let total_bytes = wal.size()?;
-        let cache = if let Some(capacity) = self.cache_capacity {
-            Some(Cache::with_write_back(capacity, self.write_back_cache))
+        let last_write = if total_bytes > 0 {
+            wal.modified()?
+        } else {
+            None
+        };
+        let cache = if let Some(capacity) = self.config.cache_capacity {
+            Some(
+                Cache::with_write_back(capacity, self.config.write_back_cache)
+                    .with_entry_ttl(self.config.cache_entry_ttl)
+                    .with_eviction_listener(self.cache_eviction_listener),
+            )
         } else {
             None
         };
-        let config = EngineConfig {
-            cache_capacity: self.cache_capacity,
-            default_ttl: self.default_ttl,
-            sync_interval: self.sync_interval,
-            compression: self.compression,
-            write_back_cache: self.write_back_cache,
+        let config = self.config.clone();
+
+        let mut quarantine = Quarantine::open(&self.directory)?;
+        // A write that reached the log after the key was quarantined supersedes it.
+        for key in index.keys() {
+            quarantine.release(key);
+        }
+        quarantine.save()?;
+        // A new store is only named once it opened, so a failed first open
+        // leaves no manifest behind.
+        let manifest = if fresh {
+            manifest::create(
+                &self.directory,
+                manifest,
+                self.config.expected_store_id.as_deref(),
+            )?
+        } else {
+            manifest
         };
 
-        let inner = Arc::new(RwLock::new(EngineState {
+        let peak_buffer = Arc::new(AtomicU64::new(0));
+        let events = Arc::new(EventLog::new(
+            &self.directory,
+            self.config.event_capacity,
+            self.config.clock.clone(),
+        ));
+        if let Some(err) = ignored_checkpoint {
+            events.record(EventKind::CheckpointIgnored {
+                error: err.to_string(),
+            });
+        }
+        events.record(EventKind::Opened {
+            keys: open_report.live_keys,
+            torn_bytes: open_report.torn_bytes,
+        });
+
+        let opened_at = self.config.clock.now();
+        let wal = Arc::new(wal);
+        wal.start_flusher()?;
+        let compacting = Arc::new(AtomicBool::new(false));
+        let mode = Arc::new(ModeGate::default());
+        let changes = Arc::new(ChangeCounters::default());
+        let mut state = EngineState {
             index,
+            generation: wal.generation(),
             wal,
             cache,
             stale_bytes,
             total_bytes,
-        }));
+            last_write: Mutex::new(last_write),
+            last_compaction: None,
+            compactions: 0,
+            compaction_policy: self.config.compaction_policy,
+            mutations_since_compaction: AtomicU64::new(0),
+            checkpointed_len: checkpoint_offset.unwrap_or(0),
+            compacting: Arc::clone(&compacting),
+            quotas: PrefixQuotas::new(&self.config.prefix_quotas),
+            expiry_subscribers: Mutex::new(Vec::new()),
+            expiry_queue: ExpiryQueue::default(),
+            dedup,
+            buffered_writes: AtomicU64::new(0),
+            flushed_writes: AtomicU64::new(0),
+            retention: self.config.retention,
+            clock: self.config.clock.clone(),
+            blobs,
+            blob_threshold: self.config.blob_threshold,
+            doomed_blobs: Mutex::new(Vec::new()),
+            last_delete_lsn: 0,
+            ttl_extensions: 0,
+            journal: None,
+            seen_tokens,
+            token_journal: None,
+            compaction_buffer: self.config.compaction_buffer_bytes,
+            peak_buffer: Arc::clone(&peak_buffer),
+            rewrite_lock: Arc::new(Mutex::new(())),
+            modifications,
+            quarantine,
+            events: Arc::clone(&events),
+            mode: Arc::clone(&mode),
+            changes: Arc::clone(&changes),
+        };
+        state.rebuild_quotas();
+        // Under `Utf8Policy::Skip` the replay left these keys out of the index.
+        if !invalid_utf8.is_empty() {
+            for (key, offset) in invalid_utf8 {
+                let error = InvalidUtf8 {
+                    key: key.clone(),
+                    offset,
+                };
+                state.set_aside(QuarantinedKey {
+                    key,
+                    offset,
+                    error: error.to_string(),
+                });
+            }
+            state.quarantine.save()?;
+        }
+        let inner = Arc::new(RwLock::new(state));
 
-        let compaction_tx = if self.async_compaction {
+        let worker = Arc::new(CompactionWorker::default());
+        let (compaction_tx, compaction_thread) = if self.async_compaction {
             let (tx, rx) = mpsc::channel::<CompactionRequest>();
             let inner_clone = Arc::clone(&inner);
-            thread::spawn(move || {
-                for req in rx {
-                    match req {
-                        CompactionRequest::Trigger => {
-                            if let Ok(mut state) = inner_clone.write() {
-                                let _ = CrabKv::run_compaction(&mut state);
-                            }
-                        }
-                        CompactionRequest::Shutdown => break,
-                    }
-                }
+            let worker_clone = Arc::clone(&worker);
+            let min_interval = config.min_compaction_interval;
+            let clock = self.config.clock.clone();
+            let errors = Arc::clone(&background);
+            {
+                let mut progress = worker.progress.lock();
+                progress.running = true;
+                progress.heartbeat = Some(Instant::now());
+            }
+            let handle = thread::spawn(move || {
+                errors.supervise(BackgroundWorker::Compaction, || {
+                    worker_clone.run(&inner_clone, &rx, min_interval, &clock, &errors);
+                });
             });
-            Some(tx)
+            (Some(tx), Some(handle))
         } else {
-            None
+            (None, None)
+        };
+
+        let scrubber = Arc::new(Scrubber::open(&self.directory)?);
+        let (scrub_tx, scrub_thread) = match config.scrub_interval {
+            Some(interval) => {
+                let (tx, rx) = mpsc::channel::<()>();
+                let inner = Arc::clone(&inner);
+                let scrubber = Arc::clone(&scrubber);
+                let errors = Arc::clone(&background);
+                let clock = self.config.clock.clone();
+                let bytes_per_sec = config.scrub_bytes_per_sec;
+                let handle = thread::spawn(move || {
+                    errors.supervise(BackgroundWorker::Scrub, || {
+                        run_scrubs(
+                            &inner,
+                            &scrubber,
+                            &errors,
+                            &clock,
+                            interval,
+                            bytes_per_sec,
+                            &rx,
+                        );
+                    });
+                });
+                (Some(tx), Some(handle))
+            }
+            None => (None, None),
+        };
+
+        let (sweep_tx, sweep_thread) = match config.ttl_sweep_interval {
+            Some(interval) => {
+                let (tx, rx) = mpsc::channel::<()>();
+                let inner = Arc::clone(&inner);
+                let compaction = CompactionTrigger {
+                    min_interval: config.min_compaction_interval,
+                    tx: compaction_tx.clone(),
+                    worker: Arc::clone(&worker),
+                };
+                let errors = Arc::clone(&background);
+                let handle = thread::spawn(move || {
+                    errors.supervise(BackgroundWorker::TtlSweeper, || {
+                        run_sweeps(&inner, &compaction, &errors, interval, &rx);
+                    });
+                });
+                (Some(tx), Some(handle))
+            }
+            None => (None, None),
         };
 
+        let disk_guard = self.config.min_free_bytes.map(|min_free| {
+            Arc::new(DiskGuard::new(
+                &self.directory,
+                min_free,
+                self.free_space_probe.clone(),
+            ))
+        });
+
+        let billing = Arc::new(PrefixCounters::new(&config.prefix_quotas));
         Ok(CrabKv {
             inner,
             config,
             compaction_tx,
+            compaction_thread: Arc::new(Mutex::new(compaction_thread)),
+            disk_guard,
+            open_report,
+            changes,
+            billing,
+            compacting,
+            worker,
+            clock_steps,
+            directory: Arc::new(Mutex::new(self.directory.clone())),
+            soft_limits: Arc::new(SoftLimits::new(self.soft_limits, self.threshold_listener)),
+            pressure: Arc::default(),
+            events,
+            peak_buffer,
+            checkpoints: Arc::new(CheckpointProgress::new(checkpoint_taken, opened_at)),
+            store_id: manifest.store_id.into(),
+            epoch: Arc::new(AtomicU64::new(manifest.epoch)),
+            background,
+            mode,
+            scrubber,
+            scrub_tx,
+            scrub_thread: Arc::new(Mutex::new(scrub_thread)),
+            sweep_tx,
+            sweep_thread: Arc::new(Mutex::new(sweep_thread)),
+            last_handle: Some(Arc::new(())),
         })
     }
+
+    /// Reads the index checkpoint next to `wal`, if there is one that still
+    /// describes the log. Any other checkpoint is removed, and the whole log
+    /// is replayed instead; a checkpoint that could not be read comes back as
+    /// the error, for the event log.
+    fn usable_checkpoint(wal: &Wal) -> io::Result<(Option<Checkpoint>, Option<io::Error>)> {
+        let path = checkpoint::path(wal.path());
+        let (found, ignored) = match Checkpoint::read(&path) {
+            Ok(found) => (found, None),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => (None, Some(err)),
+            Err(err) => return Err(err),
+        };
+        match found {
+            Some(checkpoint) if checkpoint.matches(wal)? => Ok((Some(checkpoint), None)),
+            _ => {
+                checkpoint::remove(&path)?;
+                Ok((None, ignored))
+            }
+        }
+    }
 }
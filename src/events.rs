@@ -0,0 +1,287 @@
+//! Ring of recent engine events, kept so an operator can tell what the engine
+//! did and when without full logging enabled.
+//!
+//! The ring lives in memory and is read with
+//! [`CrabKv::recent_events`](crate::CrabKv::recent_events). A copy is written
+//! to `events.log` next to `wal.log` after each compaction and on close, so
+//! `crabkv doctor` can show it without opening the store.
+
+use crate::clock::Clock;
+use crate::mode::EngineMode;
+use crate::threshold::ThresholdEvent;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the events file inside the data directory.
+pub const EVENTS_FILE: &str = "events.log";
+
+/// Events the ring holds unless
+/// [`event_capacity`](crate::CrabKvBuilder::event_capacity) says otherwise.
+pub const DEFAULT_EVENT_CAPACITY: usize = 256;
+
+/// Events shown by `crabkv doctor`, `STATS` and the status page.
+pub const EVENTS_SHOWN: usize = 20;
+
+/// One significant thing the engine did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EngineEvent {
+    /// Position in the stream of events recorded since open, from 0.
+    pub seq: u64,
+    pub at: SystemTime,
+    pub kind: EventKind,
+}
+
+/// What happened. Variants carry only small figures, except the rare error
+/// and corruption events, which keep their message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    /// The engine finished replaying its log.
+    Opened {
+        keys: usize,
+        torn_bytes: u64,
+    },
+    /// The index checkpoint could not be read, so the open replayed the
+    /// whole log instead.
+    CheckpointIgnored {
+        error: String,
+    },
+    /// The last handle was closed or dropped.
+    Closed,
+    /// Closing as the last handle was dropped failed; [`CrabKv::close`]
+    /// returns the error instead.
+    ///
+    /// [`CrabKv::close`]: crate::CrabKv::close
+    CloseFailed {
+        error: String,
+    },
+    CompactionStarted {
+        background: bool,
+    },
+    /// A compaction swapped in a log of `log_bytes`.
+    CompactionFinished {
+        background: bool,
+        log_bytes: u64,
+    },
+    /// A compaction failed; the old log is still in use.
+    CompactionFailed {
+        background: bool,
+        error: String,
+    },
+    /// Write-back writes appended to the log by a flush.
+    Flushed {
+        writes: usize,
+    },
+    /// A flush failed and put its writes back in the buffer.
+    FlushFailed {
+        pending: usize,
+        error: String,
+    },
+    /// A soft limit set on the builder was crossed.
+    ThresholdCrossed(ThresholdEvent),
+    /// A compaction, read or scrub found a key's record unreadable and
+    /// quarantined it.
+    Quarantined {
+        key: String,
+        offset: u64,
+    },
+    /// [`CrabKv::migrate_to`](crate::CrabKv::migrate_to) moved the store.
+    Migrated {
+        to: PathBuf,
+    },
+    /// [`CrabKv::restore_from`](crate::CrabKv::restore_from) replaced the
+    /// data with a backup's, moving the store to `epoch`.
+    Restored {
+        from: PathBuf,
+        epoch: u64,
+    },
+    /// [`CrabKv::clear`](crate::CrabKv::clear) removed every key, moving the
+    /// store to `epoch`.
+    Cleared {
+        epoch: u64,
+    },
+    /// [`CrabKv::set_mode`](crate::CrabKv::set_mode) switched the mode.
+    ModeChanged {
+        from: EngineMode,
+        to: EngineMode,
+    },
+    /// A scrub checked every record of the log.
+    ScrubFinished {
+        records: u64,
+        damaged: u64,
+    },
+}
+
+impl EventKind {
+    /// Snake-case name used in `STATS` and `events.log`, e.g. `compaction_started`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Opened { .. } => "opened",
+            EventKind::CheckpointIgnored { .. } => "checkpoint_ignored",
+            EventKind::Closed => "closed",
+            EventKind::CloseFailed { .. } => "close_failed",
+            EventKind::CompactionStarted { .. } => "compaction_started",
+            EventKind::CompactionFinished { .. } => "compaction_finished",
+            EventKind::CompactionFailed { .. } => "compaction_failed",
+            EventKind::Flushed { .. } => "flushed",
+            EventKind::FlushFailed { .. } => "flush_failed",
+            EventKind::ThresholdCrossed(_) => "threshold_crossed",
+            EventKind::Quarantined { .. } => "quarantined",
+            EventKind::Migrated { .. } => "migrated",
+            EventKind::Restored { .. } => "restored",
+            EventKind::Cleared { .. } => "cleared",
+            EventKind::ModeChanged { .. } => "mode_changed",
+            EventKind::ScrubFinished { .. } => "scrub_finished",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())?;
+        match self {
+            EventKind::Opened { keys, torn_bytes } => {
+                write!(f, " keys={keys} torn_bytes={torn_bytes}")
+            }
+            EventKind::CheckpointIgnored { error } => write!(f, " error={}", one_line(error)),
+            EventKind::Closed => Ok(()),
+            EventKind::CloseFailed { error } => write!(f, " error={}", one_line(error)),
+            EventKind::CompactionStarted { background } => write!(f, " background={background}"),
+            EventKind::CompactionFinished {
+                background,
+                log_bytes,
+            } => write!(f, " background={background} log_bytes={log_bytes}"),
+            EventKind::CompactionFailed { background, error } => {
+                write!(f, " background={background} error={}", one_line(error))
+            }
+            EventKind::Flushed { writes } => write!(f, " writes={writes}"),
+            EventKind::FlushFailed { pending, error } => {
+                write!(f, " pending={pending} error={}", one_line(error))
+            }
+            EventKind::ThresholdCrossed(event) => match event {
+                ThresholdEvent::WalBytesAbove(bytes) => write!(f, " wal_bytes={bytes}"),
+                ThresholdEvent::StaleRatioAbove(ratio) => write!(f, " stale_ratio={ratio:.3}"),
+                ThresholdEvent::KeyCountAbove(keys) => write!(f, " keys={keys}"),
+                ThresholdEvent::WriteBufferAbove(writes) => write!(f, " write_buffer={writes}"),
+            },
+            EventKind::Quarantined { key, offset } => {
+                write!(f, " key={} offset={offset}", one_line(key))
+            }
+            EventKind::Migrated { to } => write!(f, " to={}", one_line(&to.display().to_string())),
+            EventKind::Restored { from, epoch } => write!(
+                f,
+                " from={} epoch={epoch}",
+                one_line(&from.display().to_string())
+            ),
+            EventKind::Cleared { epoch } => write!(f, " epoch={epoch}"),
+            EventKind::ModeChanged { from, to } => write!(f, " from={from} to={to}"),
+            EventKind::ScrubFinished { records, damaged } => {
+                write!(f, " records={records} damaged={damaged}")
+            }
+        }
+    }
+}
+
+/// Formats as `<unix millis> <name> <details>`, the line `events.log` holds.
+impl fmt::Display for EngineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        write!(f, "{millis} {}", self.kind)
+    }
+}
+
+fn one_line(text: &str) -> String {
+    text.replace(['\n', '\r'], " ")
+}
+
+/// Fixed-size ring of events shared by every handle of one engine.
+#[derive(Debug)]
+pub(crate) struct EventLog {
+    ring: Mutex<Ring>,
+    clock: Clock,
+    /// Where [`EventLog::save`] writes; moved by a migration.
+    path: Mutex<PathBuf>,
+}
+
+#[derive(Debug)]
+struct Ring {
+    events: VecDeque<EngineEvent>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl EventLog {
+    pub(crate) fn new(dir: &Path, capacity: usize, clock: Clock) -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                // Allocated up front so recording only ever reuses a slot.
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                next_seq: 0,
+            }),
+            clock,
+            path: Mutex::new(dir.join(EVENTS_FILE)),
+        }
+    }
+
+    /// Records `kind` as happening now, dropping the oldest event once full.
+    pub(crate) fn record(&self, kind: EventKind) {
+        let at = self.clock.now();
+        let mut ring = self.ring.lock();
+        let seq = ring.next_seq;
+        ring.next_seq += 1;
+        if ring.capacity == 0 {
+            return;
+        }
+        if ring.events.len() == ring.capacity {
+            ring.events.pop_front();
+        }
+        ring.events.push_back(EngineEvent { seq, at, kind });
+    }
+
+    /// Returns up to `limit` of the latest events, oldest first.
+    pub(crate) fn recent(&self, limit: usize) -> Vec<EngineEvent> {
+        let ring = self.ring.lock();
+        let skip = ring.events.len().saturating_sub(limit);
+        ring.events.iter().skip(skip).cloned().collect()
+    }
+
+    /// Writes the ring to `events.log`, replacing what was there.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let mut text = String::new();
+        for event in self.recent(usize::MAX) {
+            text.push_str(&event.to_string());
+            text.push('\n');
+        }
+        let path = self.path.lock().clone();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &path)
+    }
+
+    /// Points the file at `dir` and writes it there.
+    pub(crate) fn relocate(&self, dir: &Path) -> io::Result<()> {
+        *self.path.lock() = dir.join(EVENTS_FILE);
+        self.save()
+    }
+}
+
+/// Reads up to `limit` of the latest lines of the events file of the store in
+/// `dir` without opening it; a missing file has none.
+pub fn read_recent(dir: &Path, limit: usize) -> io::Result<Vec<String>> {
+    let text = match fs::read_to_string(dir.join(EVENTS_FILE)) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    let skip = lines.len().saturating_sub(limit);
+    Ok(lines[skip..].iter().map(|line| line.to_string()).collect())
+}
@@ -0,0 +1,135 @@
+//! Keys found expired by reads, queued for later writers to reclaim.
+//!
+//! Reads only hold the state read lock, so instead of removing an expired key
+//! themselves they queue it here. Each write then drains up to
+//! [`RECLAIM_BATCH`] keys, re-checks that they are still expired and removes
+//! them with one batch of tombstones. The queue holds each key once and drops
+//! keys beyond [`QUEUE_CAPACITY`]; compaction removes whatever it misses.
+//!
+//! Keys nobody reads again never reach the queue. With
+//! [`ttl_sweep_interval`](crate::CrabKvBuilder::ttl_sweep_interval) set, a
+//! background sweeper finds them in the index instead. It scans and removes
+//! [`SWEEP_BATCH`] keys at a time, so writers wait on one batch at most.
+
+use parking_lot::Mutex;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Keys reclaimed per write at most.
+pub const RECLAIM_BATCH: usize = 64;
+
+/// Keys waiting at most; further expired keys are left to compaction.
+pub const QUEUE_CAPACITY: usize = 4_096;
+
+/// Keys the TTL sweeper scans per hold of the read lock, and removes per
+/// hold of the write lock, at most.
+pub const SWEEP_BATCH: usize = 1_000;
+
+/// Counters reported through [`EngineStats`](crate::stats::EngineStats).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ExpiryCounters {
+    /// Keys queued since open.
+    pub queued: u64,
+    /// Queued keys a write removed from the index since open.
+    pub reclaimed: u64,
+    /// Keys not queued since open because the queue was full.
+    pub dropped: u64,
+    /// Expired keys the TTL sweeper removed from the index since open.
+    pub swept: u64,
+    /// Expired keys removed from the index since open, by writes reclaiming
+    /// queued keys, by the TTL sweeper or by compaction.
+    pub expired: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ExpiryQueue {
+    pending: Mutex<Pending>,
+    queued: AtomicU64,
+    reclaimed: AtomicU64,
+    dropped: AtomicU64,
+    swept: AtomicU64,
+    expired: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// The keys in `keys`, oldest first.
+    order: VecDeque<String>,
+    keys: HashSet<String>,
+}
+
+impl ExpiryQueue {
+    /// Queues `key`, returning whether it was newly queued rather than already
+    /// waiting or dropped because the queue is full.
+    pub(crate) fn push(&self, key: &str) -> bool {
+        let mut pending = self.pending.lock();
+        if pending.keys.contains(key) {
+            return false;
+        }
+        if pending.keys.len() >= QUEUE_CAPACITY {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        pending.keys.insert(key.to_owned());
+        pending.order.push_back(key.to_owned());
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Removes and returns up to `max` keys, oldest first.
+    pub(crate) fn take(&self, max: usize) -> Vec<String> {
+        let mut pending = self.pending.lock();
+        let mut taken = Vec::new();
+        while taken.len() < max {
+            let Some(key) = pending.order.pop_front() else {
+                break;
+            };
+            if pending.keys.remove(&key) {
+                taken.push(key);
+            }
+        }
+        taken
+    }
+
+    /// Unqueues keys compaction removed, returning those that were waiting.
+    pub(crate) fn forget<'a>(&self, keys: impl IntoIterator<Item = &'a String>) -> HashSet<String> {
+        let mut pending = self.pending.lock();
+        if pending.keys.is_empty() {
+            return HashSet::new();
+        }
+        let forgotten: HashSet<String> = keys
+            .into_iter()
+            .filter_map(|key| pending.keys.take(key))
+            .collect();
+        if !forgotten.is_empty() {
+            let Pending { order, keys } = &mut *pending;
+            order.retain(|key| keys.contains(key));
+        }
+        forgotten
+    }
+
+    pub(crate) fn note_reclaimed(&self, count: usize) {
+        self.reclaimed.fetch_add(count as u64, Ordering::Relaxed);
+        self.note_expired(count);
+    }
+
+    pub(crate) fn note_swept(&self, count: usize) {
+        self.swept.fetch_add(count as u64, Ordering::Relaxed);
+        self.note_expired(count);
+    }
+
+    /// Counts expired keys removed from the index, queued or not.
+    pub(crate) fn note_expired(&self, count: usize) {
+        self.expired.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn counters(&self) -> ExpiryCounters {
+        ExpiryCounters {
+            queued: self.queued.load(Ordering::Relaxed),
+            reclaimed: self.reclaimed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            swept: self.swept.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Warm restarts: a running server hands its listening sockets to a newly
+//! started one, so clients are never refused while the binary is replaced.
+//!
+//! The old process starts the new one with each listener as an inherited
+//! descriptor, named by `--inherit-listener`, and the path of a state file in
+//! [`HANDOFF_FILE_ENV`]. Then, in order, it drains its connections, makes every
+//! accepted write durable, closes the store and removes its lock file, and
+//! writes `released` to the state file. The new process waits for that before
+//! it opens the store and starts accepting, then writes `ready pid=<pid>`.
+//! Clients that connect in between wait in the kernel's backlog.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Environment variable naming the state file of a warm restart.
+pub const HANDOFF_FILE_ENV: &str = "CRABKV_HANDOFF_FILE";
+
+/// Name of the state file inside the data directory.
+pub const HANDOFF_FILE: &str = "server.handoff";
+
+/// How often [`wait_released`] checks the state file.
+const POLL: Duration = Duration::from_millis(10);
+
+/// How far a warm restart has got, as recorded in the state file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HandoffState {
+    /// The old server has closed the store; the new one may open it.
+    Released,
+    /// The new server, with this process id, is accepting connections.
+    Ready { pid: u32 },
+}
+
+/// Reads the state file at `path`; `None` while nothing has been recorded.
+pub fn state(path: &Path) -> io::Result<Option<HandoffState>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if text == "released" {
+        return Ok(Some(HandoffState::Released));
+    }
+    text.strip_prefix("ready pid=")
+        .and_then(|pid| pid.parse().ok())
+        .map(|pid| Some(HandoffState::Ready { pid }))
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a handoff state file", path.display()),
+            )
+        })
+}
+
+/// Records that this server has released the store.
+pub fn mark_released(path: &Path) -> io::Result<()> {
+    write_state(path, "released\n")
+}
+
+/// Records that this process is now serving.
+pub fn mark_ready(path: &Path) -> io::Result<()> {
+    write_state(path, &format!("ready pid={}\n", process::id()))
+}
+
+/// Clears the state file before a new handoff starts.
+pub fn reset(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn write_state(path: &Path, text: &str) -> io::Result<()> {
+    let temp = path.with_extension("handoff.tmp");
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path)
+}
+
+/// Blocks until the state file at `path` says the store was released.
+///
+/// Fails with `TimedOut` after `timeout`, leaving the store to the old server.
+pub fn wait_released(path: &Path, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if state(path)? == Some(HandoffState::Released) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "the previous server did not release the store within {timeout:?}; \
+                     it keeps serving"
+                ),
+            ));
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Returns a duplicate of `listener`'s descriptor that a child process
+/// inherits. The caller closes it with [`close_inherited`] once the child has
+/// started.
+#[cfg(unix)]
+pub fn inheritable(listener: &TcpListener) -> io::Result<i32> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: `dup` only reads the descriptor, which `listener` keeps open;
+    // the duplicate is created without close-on-exec.
+    let fd = unsafe { libc::dup(listener.as_raw_fd()) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Closes a descriptor [`inheritable`] returned.
+#[cfg(unix)]
+pub fn close_inherited(fd: i32) {
+    // SAFETY: `fd` came from `inheritable` and nothing else owns it.
+    unsafe {
+        libc::close(fd);
+    }
+}
+
+/// Takes ownership of an inherited listening socket.
+///
+/// Fails with `InvalidInput` unless `fd` is an open socket that listens, so a
+/// wrong number on the command line is caught before anything is served.
+#[cfg(unix)]
+pub fn inherited_listener(fd: i32) -> io::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+    let mut listening: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the out-pointers are valid for the sizes passed.
+    let status = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            (&mut listening as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if status != 0 || listening == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("descriptor {fd} is not a listening socket"),
+        ));
+    }
+    // SAFETY: `fd` is an open listening socket handed to this process, which
+    // owns it from here on.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // Not passed on to anything this process starts, short of another handoff.
+    // SAFETY: plain flag update on a descriptor we own.
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    Ok(listener)
+}
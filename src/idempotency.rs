@@ -0,0 +1,150 @@
+//! Idempotency tokens, so clients can retry a write without applying it twice.
+//!
+//! A write given a token through [`WriteOptions::idempotency_key`] appends a
+//! token record in the same batch as the write itself. Replaying the log
+//! rebuilds the set of recent tokens, so a retry after a restart is still
+//! recognised as long as it falls inside the window.
+
+use crate::internals::wal::WalEntry;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime};
+
+/// How long a token is remembered unless
+/// [`idempotency_window`](crate::CrabKvBuilder::idempotency_window) says otherwise.
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Tokens remembered at once unless
+/// [`idempotency_capacity`](crate::CrabKvBuilder::idempotency_capacity) says otherwise.
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 100_000;
+
+/// Options for one write made through [`CrabKv::put_with`](crate::CrabKv::put_with)
+/// or [`CrabKv::delete_with`](crate::CrabKv::delete_with).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteOptions {
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) idempotency_key: Option<String>,
+    pub(crate) expected_epoch: Option<u64>,
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expires the written key after `ttl`; ignored by deletes.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Applies the write only if no write carrying `key` was applied within
+    /// the idempotency window; a repeat returns the first outcome instead.
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    /// Applies the write only while the store is at `epoch`, as read from
+    /// [`CrabKv::epoch`](crate::CrabKv::epoch); otherwise it fails with an
+    /// [`EpochMismatch`](crate::EpochMismatch) and nothing is written.
+    pub fn expected_epoch(mut self, epoch: u64) -> Self {
+        self.expected_epoch = Some(epoch);
+        self
+    }
+}
+
+/// What a write made with [`WriteOptions`] did.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WriteOutcome {
+    /// Whether the write repeated a token seen inside the window, and so was
+    /// not applied again.
+    pub replayed: bool,
+    /// Result recorded when the write was first applied; empty for puts and
+    /// deletes.
+    pub outcome: String,
+}
+
+impl WriteOutcome {
+    pub(crate) fn applied(outcome: String) -> Self {
+        Self {
+            replayed: false,
+            outcome,
+        }
+    }
+}
+
+/// Tokens applied within the window, each with its outcome and the end of its
+/// window. The oldest are forgotten first once `capacity` is reached.
+#[derive(Debug)]
+pub(crate) struct SeenTokens {
+    tokens: HashMap<String, (String, SystemTime)>,
+    by_expiry: BTreeSet<(SystemTime, String)>,
+    capacity: usize,
+}
+
+impl SeenTokens {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            tokens: HashMap::new(),
+            by_expiry: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    /// Returns the outcome recorded for `token` if its window has not ended.
+    pub(crate) fn get(&self, token: &str, now: SystemTime) -> Option<&str> {
+        match self.tokens.get(token) {
+            Some((outcome, expires_at)) if now < *expires_at => Some(outcome),
+            _ => None,
+        }
+    }
+
+    /// Remembers `token` until `expires_at`, forgetting ended windows and,
+    /// past capacity, the tokens closest to their end.
+    pub(crate) fn insert(
+        &mut self,
+        token: String,
+        outcome: String,
+        expires_at: SystemTime,
+        now: SystemTime,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, previous)) = self.tokens.remove(&token) {
+            self.by_expiry.remove(&(previous, token.clone()));
+        }
+        while let Some((first, _)) = self.by_expiry.first()
+            && (*first <= now || self.tokens.len() >= self.capacity)
+        {
+            let (_, oldest) = self.by_expiry.pop_first().expect("checked above");
+            self.tokens.remove(&oldest);
+        }
+        self.by_expiry.insert((expires_at, token.clone()));
+        self.tokens.insert(token, (outcome, expires_at));
+    }
+
+    /// Token entries for every window still open at `now`, for compaction to
+    /// carry into the new log.
+    pub(crate) fn entries(&self, now: SystemTime) -> Vec<WalEntry> {
+        self.tokens
+            .iter()
+            .filter(|(_, (_, expires_at))| now < *expires_at)
+            .map(|(token, (outcome, expires_at))| WalEntry::Token {
+                token: token.clone(),
+                outcome: outcome.clone(),
+                expires_at: *expires_at,
+            })
+            .collect()
+    }
+
+    /// Returns the entry for `token` if its window is still open.
+    pub(crate) fn entry(&self, token: &str, now: SystemTime) -> Option<WalEntry> {
+        let (outcome, expires_at) = self.tokens.get(token)?;
+        (now < *expires_at).then(|| WalEntry::Token {
+            token: token.to_owned(),
+            outcome: outcome.clone(),
+            expires_at: *expires_at,
+        })
+    }
+}
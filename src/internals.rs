@@ -0,0 +1,14 @@
+//! Building blocks of the engine, exposed for tests and tooling only.
+//!
+//! Nothing here is covered by semver: signatures change whenever the engine
+//! needs them to. Use [`CrabKv`](crate::CrabKv) for data and
+//! [`WalFile`](crate::WalFile) to inspect a log offline.
+
+pub mod blob;
+pub mod cache;
+pub mod checkpoint;
+pub mod compaction;
+pub mod disk;
+pub mod index;
+pub mod pattern;
+pub mod wal;
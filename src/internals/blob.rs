@@ -0,0 +1,178 @@
+//! Out-of-log storage for large values, one file per write under `blobs/`.
+
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extension of the files holding blob values.
+const BLOB_EXTENSION: &str = "blob";
+
+/// Name and size of a value stored in its own file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlobRef {
+    /// File name inside the blob directory.
+    pub name: String,
+    /// Length of the value in bytes.
+    pub len: u64,
+}
+
+/// Directory of blob files referenced from the WAL.
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+    next: AtomicU64,
+}
+
+impl BlobStore {
+    /// Uses `dir` for blob files; it is created on the first write.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        // Seeding from the clock keeps names unique across restarts.
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        Self {
+            dir: dir.into(),
+            next: AtomicU64::new(seed),
+        }
+    }
+
+    /// Returns the blob directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes `value` to a new file named after a hash of `key` and syncs it.
+    ///
+    /// The file is durable when this returns, so a WAL record referencing it
+    /// can be appended next. A crash in between leaves an orphan for
+    /// [`BlobStore::remove_orphans`].
+    pub fn write(&self, key: &str, value: &str) -> io::Result<BlobRef> {
+        fs::create_dir_all(&self.dir).map_err(|err| with_path(err, &self.dir))?;
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let name = format!(
+            "{:016x}-{:016x}.{BLOB_EXTENSION}",
+            hasher.finish(),
+            self.next.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.dir.join(&name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| with_path(err, &path))?;
+        file.write_all(value.as_bytes())?;
+        file.sync_all()?;
+        sync_dir(&self.dir)?;
+        Ok(BlobRef {
+            name,
+            len: value.len() as u64,
+        })
+    }
+
+    /// Reads the value stored in `blob`.
+    pub fn read(&self, blob: &BlobRef) -> io::Result<String> {
+        let path = self.dir.join(&blob.name);
+        let bytes = fs::read(&path).map_err(|err| with_path(err, &path))?;
+        if bytes.len() as u64 != blob.len {
+            return Err(with_path(
+                io::Error::new(ErrorKind::InvalidData, "blob file has the wrong length"),
+                &path,
+            ));
+        }
+        String::from_utf8(bytes).map_err(|_| {
+            with_path(
+                io::Error::new(ErrorKind::InvalidData, "invalid utf-8 blob"),
+                &path,
+            )
+        })
+    }
+
+    /// Deletes the named blob file; a file that is already gone is not an error.
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        let path = self.dir.join(name);
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(with_path(err, &path)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies every blob file `dir` does not hold yet into it and syncs them.
+    ///
+    /// Blob files never change once written, so a file of the same length is
+    /// taken as already copied. Files deleted during the copy are skipped.
+    pub fn copy_to(&self, dir: &Path) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(with_path(err, &self.dir)),
+        };
+        fs::create_dir_all(dir).map_err(|err| with_path(err, dir))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != BLOB_EXTENSION) {
+                continue;
+            }
+            let target = dir.join(entry.file_name());
+            let len = match entry.metadata() {
+                Ok(meta) => meta.len(),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(with_path(err, &path)),
+            };
+            if fs::metadata(&target).is_ok_and(|meta| meta.len() == len) {
+                continue;
+            }
+            match fs::copy(&path, &target) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(with_path(err, &path)),
+            }
+            fs::File::open(&target)?.sync_all()?;
+        }
+        sync_dir(dir)
+    }
+
+    /// Deletes every blob file not named in `live` and returns how many went.
+    pub fn remove_orphans(&self, live: &HashSet<String>) -> io::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(with_path(err, &self.dir)),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if path.extension().is_some_and(|ext| ext == BLOB_EXTENSION) && !live.contains(name) {
+                self.remove(name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Makes a newly created file's directory entry durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directory handles cannot be synced on Windows; NTFS journals the entry.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Prefixes an I/O error with the path it concerns, keeping its kind.
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
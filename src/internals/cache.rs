@@ -0,0 +1,302 @@
+//! Optional in-memory cache for CrabKv lookups.
+
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Shared cache handle wrapping an `LruCache` guarded by a mutex.
+///
+/// A panic raised while touching the LRU (including from an eviction listener)
+/// disables it for the rest of the cache's lifetime instead of unwinding into
+/// the engine; reads then fall through to the WAL. The write-back buffer is
+/// left intact so unflushed writes are never lost.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    inner: Arc<Mutex<LruCache<String, CacheEntry>>>,
+    write_buffer: Arc<Mutex<WriteBuffer>>,
+    write_back: bool,
+    entry_ttl: Option<Duration>,
+    on_evict: Option<EvictionListener>,
+    disabled: Arc<AtomicBool>,
+    /// Lookups answered from the cache.
+    hits: Arc<AtomicU64>,
+    /// Lookups that fell through to the WAL.
+    misses: Arc<AtomicU64>,
+}
+
+/// Unflushed write-back entries, each tagged with the sequence of its latest write.
+#[derive(Debug, Default)]
+struct WriteBuffer {
+    entries: HashMap<String, (u64, CacheEntry)>,
+    next_seq: u64,
+}
+
+/// Callback invoked with the key of every entry evicted to make room.
+#[derive(Clone)]
+pub struct EvictionListener(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl EvictionListener {
+    /// Wraps the provided callback.
+    pub fn new(callback: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for EvictionListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EvictionListener")
+    }
+}
+
+impl Cache {
+    /// Constructs a cache with the provided capacity. When capacity is zero, caching is disabled.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self::with_write_back(capacity, false)
+    }
+
+    /// Constructs a cache with optional write-back mode.
+    pub fn with_write_back(capacity: NonZeroUsize, write_back: bool) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(LruCache::new(capacity))),
+            write_buffer: Arc::new(Mutex::new(WriteBuffer::default())),
+            write_back,
+            entry_ttl: None,
+            on_evict: None,
+            disabled: Arc::new(AtomicBool::new(false)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Registers a callback notified whenever capacity pressure evicts an entry.
+    pub fn with_eviction_listener(mut self, listener: Option<EvictionListener>) -> Self {
+        self.on_evict = listener;
+        self
+    }
+
+    /// Returns `true` once a panic has disabled the LRU layer.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Bounds how long an entry may be served before it must be re-read from the WAL.
+    pub fn with_entry_ttl(mut self, entry_ttl: Option<Duration>) -> Self {
+        self.entry_ttl = entry_ttl;
+        self
+    }
+
+    /// Returns the cached entry if present, checking write buffer first.
+    pub fn get(&self, key: &str) -> Option<CacheEntry> {
+        self.get_with(key, CacheEntry::clone)
+    }
+
+    /// Like [`Cache::get`], but hands the entry to `read` in place instead of
+    /// cloning it.
+    pub fn get_with<T>(&self, key: &str, read: impl FnOnce(&CacheEntry) -> T) -> Option<T> {
+        let found = self.lookup(key, read);
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Returns how many lookups were answered from the cache and how many missed.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn lookup<T>(&self, key: &str, read: impl FnOnce(&CacheEntry) -> T) -> Option<T> {
+        if self.write_back {
+            let buffer = self.write_buffer.lock();
+            if let Some((_, entry)) = buffer.entries.get(key) {
+                return Some(read(entry));
+            }
+        }
+        self.guarded(|| {
+            let mut guard = self.inner.lock();
+            let entry = guard.get(key)?;
+            if let Some(ttl) = self.entry_ttl
+                && entry.inserted_at.elapsed() >= ttl
+            {
+                guard.pop(key);
+                return None;
+            }
+            Some(read(entry))
+        })
+        .flatten()
+    }
+
+    /// Returns the entry waiting in the write-back buffer, ignoring clean entries.
+    pub fn get_buffered(&self, key: &str) -> Option<CacheEntry> {
+        self.get_buffered_with(key, CacheEntry::clone)
+    }
+
+    /// Like [`Cache::get_buffered`], but hands the entry to `read` in place
+    /// instead of cloning it.
+    pub fn get_buffered_with<T>(
+        &self,
+        key: &str,
+        read: impl FnOnce(&CacheEntry) -> T,
+    ) -> Option<T> {
+        if !self.write_back {
+            return None;
+        }
+        let buffer = self.write_buffer.lock();
+        buffer.entries.get(key).map(|(_, entry)| read(entry))
+    }
+
+    /// Inserts or updates the cached entry, buffering if write-back is enabled.
+    pub fn put(&self, key: String, entry: CacheEntry) {
+        if self.write_back {
+            let mut buffer = self.write_buffer.lock();
+            let seq = buffer.next_seq;
+            buffer.next_seq += 1;
+            buffer.entries.insert(key.clone(), (seq, entry.clone()));
+        }
+        self.put_clean(key, entry);
+    }
+
+    /// Caches an entry that is already durable, bypassing the write-back buffer.
+    pub fn put_clean(&self, key: String, entry: CacheEntry) {
+        self.guarded(|| {
+            let evicted = self.inner.lock().push(key.clone(), entry);
+            if let (Some((evicted, _)), Some(listener)) = (evicted, &self.on_evict)
+                && evicted != key
+            {
+                (listener.0)(&evicted);
+            }
+        });
+    }
+
+    /// Evicts the provided key from the cache and write buffer.
+    pub fn remove(&self, key: &str) {
+        if self.write_back {
+            let mut buffer = self.write_buffer.lock();
+            buffer.entries.remove(key);
+        }
+        self.guarded(|| {
+            self.inner.lock().pop(key);
+        });
+    }
+
+    /// Evicts the provided key from the cache but keeps a buffered write for it.
+    ///
+    /// Used when the durable version of a key expires: a newer write still
+    /// waiting in the write-back buffer must survive until it is flushed.
+    pub fn remove_clean(&self, key: &str) {
+        self.guarded(|| {
+            self.inner.lock().pop(key);
+        });
+    }
+
+    /// Runs an LRU operation, disabling the LRU instead of propagating a panic.
+    fn guarded<T>(&self, op: impl FnOnce() -> T) -> Option<T> {
+        if self.is_disabled() {
+            return None;
+        }
+        match panic::catch_unwind(AssertUnwindSafe(op)) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.disabled.store(true, Ordering::Relaxed);
+                self.inner.lock().clear();
+                None
+            }
+        }
+    }
+
+    /// Returns the keys waiting in the write-back buffer that `keep` accepts.
+    pub fn buffered_keys(&self, mut keep: impl FnMut(&str) -> bool) -> Vec<String> {
+        if !self.write_back {
+            return Vec::new();
+        }
+        let buffer = self.write_buffer.lock();
+        buffer
+            .entries
+            .keys()
+            .filter(|key| keep(key))
+            .cloned()
+            .collect()
+    }
+
+    /// Calls `visit` with every key waiting in the write-back buffer and its
+    /// expiry, without cloning values.
+    pub fn for_each_buffered(&self, mut visit: impl FnMut(&str, Option<SystemTime>)) {
+        if !self.write_back {
+            return;
+        }
+        let buffer = self.write_buffer.lock();
+        for (key, (_, entry)) in &buffer.entries {
+            visit(key, entry.expires_at);
+        }
+    }
+
+    /// Returns the number of keys waiting in the write-back buffer.
+    pub fn buffered_len(&self) -> usize {
+        self.write_buffer.lock().entries.len()
+    }
+
+    /// Flushes and clears the write buffer, returning buffered entries for WAL persistence.
+    ///
+    /// Entries come back in the order of each key's most recent write.
+    pub fn flush_write_buffer(&self) -> Vec<(String, CacheEntry)> {
+        if !self.write_back {
+            return Vec::new();
+        }
+        let mut buffer = self.write_buffer.lock();
+        let mut entries: Vec<_> = buffer.entries.drain().collect();
+        entries.sort_by_key(|(_, (seq, _))| *seq);
+        entries
+            .into_iter()
+            .map(|(key, (_, entry))| (key, entry))
+            .collect()
+    }
+
+    /// Puts entries taken by [`Cache::flush_write_buffer`] back into the write buffer.
+    ///
+    /// Keys written again since the flush keep their newer value.
+    pub fn restore_write_buffer(&self, entries: Vec<(String, CacheEntry)>) {
+        if !self.write_back {
+            return;
+        }
+        let mut buffer = self.write_buffer.lock();
+        for (key, entry) in entries {
+            let seq = buffer.next_seq;
+            if let std::collections::hash_map::Entry::Vacant(slot) = buffer.entries.entry(key) {
+                slot.insert((seq, entry));
+                buffer.next_seq += 1;
+            }
+        }
+    }
+}
+
+/// Value stored in the cache, keeping the decoded payload and optional expiration.
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub value: String,
+    pub expires_at: Option<SystemTime>,
+    /// When the entry entered the cache, used to enforce the cache entry TTL.
+    pub inserted_at: Instant,
+}
+
+impl CacheEntry {
+    /// Creates an entry stamped with the current instant.
+    pub fn new(value: String, expires_at: Option<SystemTime>) -> Self {
+        Self {
+            value,
+            expires_at,
+            inserted_at: Instant::now(),
+        }
+    }
+}
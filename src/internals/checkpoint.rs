@@ -0,0 +1,369 @@
+//! Index checkpoints, so opening a store replays only the log written since.
+//!
+//! A checkpoint records every live key's pointer as of one offset of one log
+//! generation, with the stale byte count, the open idempotency windows and
+//! the dedup map. It is written to a temporary file and renamed into place,
+//! and ends with a hash of its contents. On open it is only trusted while the
+//! log still holds the same generation, reaches the offset, and the bytes
+//! just before it hash to the fingerprint taken when it was written.
+//!
+//! Entries are stored in key order, with the keys front coded by
+//! [`keycoding`](crate::keycoding) ahead of the rest of each entry, since
+//! hierarchical keys share most of their bytes with the key before them.
+
+use super::blob;
+use super::index::ValuePointer;
+use super::wal::{LoadedEntry, Wal};
+use crate::keycoding;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the checkpoint file next to the log.
+pub const CHECKPOINT_FILE: &str = "index.checkpoint";
+
+/// Leading bytes of a checkpoint file, with the format version.
+const MAGIC: &[u8; 8] = b"CKVCKPT2";
+
+/// Log bytes before the offset that the fingerprint covers.
+const FINGERPRINT_LEN: u64 = 64;
+
+/// A key as a checkpoint records it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CheckpointEntry {
+    pub key: String,
+    pub entry: LoadedEntry,
+    /// Length of the reference record when the key shares another's record.
+    pub ref_len: Option<u32>,
+}
+
+/// The index as of one log offset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoint {
+    /// Log generation the pointers refer to.
+    pub generation: u64,
+    /// Length of the log the checkpoint covers; replay resumes here.
+    pub offset: u64,
+    /// Hash of the log bytes just before `offset`, from [`fingerprint`].
+    pub fingerprint: u64,
+    /// When the checkpoint was taken.
+    pub taken_at: SystemTime,
+    pub stale_bytes: u64,
+    pub entries: Vec<CheckpointEntry>,
+    /// Idempotency tokens with their outcome and the end of their window.
+    pub tokens: Vec<(String, String, SystemTime)>,
+    /// Value hash to the put record holding it, for value dedup.
+    pub shared_values: Vec<(u64, ValuePointer)>,
+}
+
+/// Path of the checkpoint for the log at `log`.
+pub fn path(log: &Path) -> PathBuf {
+    log.with_file_name(CHECKPOINT_FILE)
+}
+
+/// Hashes the log bytes just before `offset`.
+///
+/// Written records are never rewritten in place, so a log whose bytes there
+/// changed was truncated and written again, or replaced, since the checkpoint.
+pub fn fingerprint(wal: &Wal, offset: u64) -> io::Result<u64> {
+    let start = offset.saturating_sub(FINGERPRINT_LEN);
+    let bytes = wal.read_bytes(start, (offset - start) as usize)?;
+    let mut hasher = Fnv::default();
+    hasher.update(&offset.to_le_bytes());
+    hasher.update(&bytes);
+    Ok(hasher.0)
+}
+
+/// Removes the checkpoint at `path`, if there is one.
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(with_path(err, path)),
+        _ => Ok(()),
+    }
+}
+
+impl Checkpoint {
+    /// Whether the log `wal` still holds what this checkpoint was taken from.
+    pub fn matches(&self, wal: &Wal) -> io::Result<bool> {
+        Ok(self.generation == wal.generation()
+            && self.offset <= wal.size()?
+            && self.fingerprint == fingerprint(wal, self.offset)?)
+    }
+
+    /// Writes the checkpoint to `path` through a temporary file, so a crash
+    /// leaves either the previous checkpoint or this one.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let temp = path.with_extension("checkpoint.tmp");
+        let file = File::create(&temp).map_err(|err| with_path(err, &temp))?;
+        let mut out = Hashing::new(BufWriter::new(file));
+        self.encode(&mut out)?;
+        let hash = out.hash.0;
+        let mut writer = out.inner;
+        writer.write_all(&hash.to_le_bytes())?;
+        let file = writer.into_inner().map_err(io::Error::from)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, path).map_err(|err| with_path(err, path))?;
+        if let Some(dir) = path.parent() {
+            blob::sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the checkpoint at `path`; `None` when there is none.
+    ///
+    /// Fails with `InvalidData` when the file is not a whole checkpoint.
+    pub fn read(path: &Path) -> io::Result<Option<Checkpoint>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(with_path(err, path)),
+        };
+        let mut input = Hashing::new(BufReader::new(file));
+        let invalid = |what: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {what}", path.display()),
+            )
+        };
+        let decoded = Self::decode(&mut input).map_err(|err| match err.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::InvalidData => invalid("not a whole checkpoint"),
+            _ => err,
+        })?;
+        let hash = input.hash.0;
+        let mut stored = [0u8; 8];
+        input
+            .inner
+            .read_exact(&mut stored)
+            .map_err(|_| invalid("checkpoint ends early"))?;
+        if u64::from_le_bytes(stored) != hash || input.inner.read(&mut [0u8])? != 0 {
+            return Err(invalid("checkpoint does not match its hash"));
+        }
+        Ok(Some(decoded))
+    }
+
+    fn encode(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        for value in [
+            self.generation,
+            self.offset,
+            self.fingerprint,
+            time_to_u64(Some(self.taken_at)),
+            self.stale_bytes,
+            self.entries.len() as u64,
+        ] {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        let mut entries: Vec<&CheckpointEntry> = self.entries.iter().collect();
+        entries.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        let keys = keycoding::encode(entries.iter().map(|entry| entry.key.as_str()));
+        out.write_all(&(keys.len() as u64).to_le_bytes())?;
+        out.write_all(&keys)?;
+        for CheckpointEntry { entry, ref_len, .. } in entries {
+            write_pointer(out, entry.pointer)?;
+            out.write_all(&time_to_u64(entry.expires_at).to_le_bytes())?;
+            out.write_all(&time_to_u64(entry.written_at).to_le_bytes())?;
+            let flags = u8::from(entry.blob) | (u8::from(ref_len.is_some()) << 1);
+            out.write_all(&[flags])?;
+            out.write_all(&ref_len.unwrap_or(0).to_le_bytes())?;
+        }
+        out.write_all(&(self.tokens.len() as u64).to_le_bytes())?;
+        for (token, outcome, expires_at) in &self.tokens {
+            write_str(out, token)?;
+            write_str(out, outcome)?;
+            out.write_all(&time_to_u64(Some(*expires_at)).to_le_bytes())?;
+        }
+        out.write_all(&(self.shared_values.len() as u64).to_le_bytes())?;
+        for (hash, pointer) in &self.shared_values {
+            out.write_all(&hash.to_le_bytes())?;
+            write_pointer(out, *pointer)?;
+        }
+        Ok(())
+    }
+
+    fn decode(input: &mut impl Read) -> io::Result<Checkpoint> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "bad magic"));
+        }
+        let generation = read_u64(input)?;
+        let offset = read_u64(input)?;
+        let fingerprint = read_u64(input)?;
+        let taken_at = time_from_u64(read_u64(input)?).unwrap_or(UNIX_EPOCH);
+        let stale_bytes = read_u64(input)?;
+        let count = read_u64(input)?;
+        let keys_len = read_u64(input)?;
+        let keys = keycoding::decode(&read_bytes(input, keys_len)?)?;
+        if keys.len() as u64 != count {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "key count does not match",
+            ));
+        }
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let pointer = read_pointer(input)?;
+            let expires_at = time_from_u64(read_u64(input)?);
+            let written_at = time_from_u64(read_u64(input)?);
+            let mut flags = [0u8; 1];
+            input.read_exact(&mut flags)?;
+            let ref_len = read_u32(input)?;
+            entries.push(CheckpointEntry {
+                key,
+                entry: LoadedEntry {
+                    pointer,
+                    expires_at,
+                    written_at,
+                    blob: flags[0] & 1 != 0,
+                },
+                ref_len: (flags[0] & 2 != 0).then_some(ref_len),
+            });
+        }
+        let count = read_u64(input)?;
+        let mut tokens = Vec::with_capacity(count.min(1 << 16) as usize);
+        for _ in 0..count {
+            let token = read_str(input)?;
+            let outcome = read_str(input)?;
+            let expires_at = time_from_u64(read_u64(input)?).unwrap_or(UNIX_EPOCH);
+            tokens.push((token, outcome, expires_at));
+        }
+        let count = read_u64(input)?;
+        let mut shared_values = Vec::with_capacity(count.min(1 << 16) as usize);
+        for _ in 0..count {
+            let hash = read_u64(input)?;
+            shared_values.push((hash, read_pointer(input)?));
+        }
+        Ok(Checkpoint {
+            generation,
+            offset,
+            fingerprint,
+            taken_at,
+            stale_bytes,
+            entries,
+            tokens,
+            shared_values,
+        })
+    }
+}
+
+/// Nanoseconds since the Unix epoch plus one, with 0 for no time.
+fn time_to_u64(time: Option<SystemTime>) -> u64 {
+    time.map_or(0, |time| {
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        u64::try_from(nanos).unwrap_or(u64::MAX - 1) + 1
+    })
+}
+
+fn time_from_u64(value: u64) -> Option<SystemTime> {
+    (value != 0).then(|| UNIX_EPOCH + Duration::from_nanos(value - 1))
+}
+
+fn write_str(out: &mut impl Write, text: &str) -> io::Result<()> {
+    let len = u32::try_from(text.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "string too long"))?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(text.as_bytes())
+}
+
+fn write_pointer(out: &mut impl Write, pointer: ValuePointer) -> io::Result<()> {
+    out.write_all(&pointer.offset.to_le_bytes())?;
+    out.write_all(&pointer.value_len.to_le_bytes())?;
+    out.write_all(&pointer.record_len.to_le_bytes())
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_str(input: &mut impl Read) -> io::Result<String> {
+    let len = read_u32(input)?;
+    String::from_utf8(read_bytes(input, len.into())?)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Reads `len` bytes; the length comes from the file, so nothing is reserved
+/// up front.
+fn read_bytes(input: &mut impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn read_pointer(input: &mut impl Read) -> io::Result<ValuePointer> {
+    let offset = read_u64(input)?;
+    let value_len = read_u32(input)?;
+    let record_len = read_u32(input)?;
+    Ok(ValuePointer::new(offset, value_len, record_len))
+}
+
+/// 64-bit FNV-1a, chosen because its output never changes between builds.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Hashes the bytes passing through a reader or writer.
+struct Hashing<T> {
+    inner: T,
+    hash: Fnv,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hash: Fnv::default(),
+        }
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hash.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hash.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
@@ -0,0 +1,49 @@
+//! Compaction heuristics used to decide when to rebuild the log, and the
+//! worker pool records are decoded and re-encoded on while it is rebuilt.
+
+use std::panic;
+use std::thread;
+
+/// Records each compaction worker handles per window; a window of
+/// `threads * WINDOW_PER_THREAD` records is the most held in flight at once.
+pub const WINDOW_PER_THREAD: usize = 256;
+
+/// Returns `true` when the ratio of stale records justifies rewriting the log.
+pub fn should_compact(total_bytes: u64, stale_bytes: u64) -> bool {
+    if total_bytes == 0 {
+        return false;
+    }
+    let stale_ratio = stale_bytes as f64 / total_bytes as f64;
+    stale_ratio >= 0.33 && total_bytes > 1_048_576 || stale_bytes > 8 * 1_048_576
+}
+
+/// Applies `f` to every item on up to `threads` scoped workers and returns
+/// the results in the order of `items`.
+///
+/// Each worker takes one contiguous run of items, so putting the results back
+/// in order is a concatenation. With one thread, or a single item, `f` runs on
+/// the calling thread.
+pub fn map_ordered<T, R>(items: &[T], threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    if threads <= 1 || items.len() < 2 {
+        return items.iter().map(f).collect();
+    }
+    let run = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(run)
+            .map(|run| scope.spawn(|| run.iter().map(&f).collect::<Vec<_>>()))
+            .collect();
+        let mut results = Vec::with_capacity(items.len());
+        for worker in workers {
+            match worker.join() {
+                Ok(part) => results.extend(part),
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+        results
+    })
+}
@@ -0,0 +1,95 @@
+//! Free disk space guard refusing writes when the WAL's filesystem runs low.
+
+use parking_lot::Mutex;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a free-space reading is trusted before the filesystem is queried again.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+type FreeSpaceQuery = dyn Fn(&Path) -> io::Result<u64> + Send + Sync;
+
+/// Query returning the bytes available to unprivileged writers at a path.
+#[derive(Clone)]
+pub struct FreeSpaceProbe(Arc<FreeSpaceQuery>);
+
+impl FreeSpaceProbe {
+    /// Wraps the provided query.
+    pub fn new(query: impl Fn(&Path) -> io::Result<u64> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(query))
+    }
+
+    /// Asks the operating system (`statvfs` on Unix, `GetDiskFreeSpaceExW` on Windows).
+    pub fn system() -> Self {
+        Self::new(|path| fs2::available_space(path))
+    }
+}
+
+impl fmt::Debug for FreeSpaceProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FreeSpaceProbe")
+    }
+}
+
+/// Rejects writes while free space at `path` is below a minimum.
+///
+/// The probe runs at most once per [`CHECK_INTERVAL`]; writes in between reuse
+/// the last reading.
+#[derive(Debug)]
+pub struct DiskGuard {
+    path: Mutex<PathBuf>,
+    min_free: u64,
+    probe: FreeSpaceProbe,
+    last: Mutex<Option<(Instant, u64)>>,
+}
+
+impl DiskGuard {
+    /// Creates a guard for the filesystem holding `path`.
+    pub fn new(path: impl Into<PathBuf>, min_free: u64, probe: FreeSpaceProbe) -> Self {
+        Self {
+            path: Mutex::new(path.into()),
+            min_free,
+            probe,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Returns the free bytes at `path`, reusing a reading younger than
+    /// [`CHECK_INTERVAL`].
+    pub fn free_bytes(&self) -> io::Result<u64> {
+        let mut last = self.last.lock();
+        match *last {
+            Some((at, free)) if at.elapsed() < CHECK_INTERVAL => Ok(free),
+            _ => {
+                let free = (self.probe.0)(&self.path.lock())?;
+                *last = Some((Instant::now(), free));
+                Ok(free)
+            }
+        }
+    }
+
+    /// Watches the filesystem holding `path` instead, dropping the last reading.
+    pub fn relocate(&self, path: impl Into<PathBuf>) {
+        *self.path.lock() = path.into();
+        *self.last.lock() = None;
+    }
+
+    /// Returns an error of kind [`io::ErrorKind::StorageFull`] when space is too low.
+    pub fn check(&self) -> io::Result<()> {
+        let free = self.free_bytes()?;
+        if free < self.min_free {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "{}: {free} bytes free, below the {} byte minimum",
+                    self.path.lock().display(),
+                    self.min_free
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! In-memory index pointing to values stored in the write-ahead log.
+
+use std::collections::{BTreeMap, HashMap, btree_map, hash_map};
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+
+/// Location of a value within the log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ValuePointer {
+    /// Byte offset inside the log file where the record begins.
+    pub offset: u64,
+    /// Length of the stored value payload in bytes.
+    pub value_len: u32,
+    /// Total size of the log record, including header and key bytes.
+    pub record_len: u32,
+}
+
+impl ValuePointer {
+    /// Creates a pointer describing a record written to the log.
+    pub fn new(offset: u64, value_len: u32, record_len: u32) -> Self {
+        Self {
+            offset,
+            value_len,
+            record_len,
+        }
+    }
+}
+
+impl fmt::Display for ValuePointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "offset={}, value_len={}, record_len={}",
+            self.offset, self.value_len, self.record_len
+        )
+    }
+}
+
+/// Where a walk over a [`KeyIndex`] with [`KeyIndex::chunk`] picks up.
+///
+/// An ordered index resumes after the last key visited. A hashed one resumes
+/// after as many entries as were visited, in iteration order, so keys inserted
+/// between chunks may be visited twice or missed, and a walk that must see
+/// every key takes the whole index in one chunk.
+#[derive(Clone, Debug, Default)]
+pub struct IndexCursor {
+    visited: usize,
+    last: Option<String>,
+}
+
+impl IndexCursor {
+    /// Accounts for `count` visited entries removed since, so a hashed walk
+    /// does not skip as many it has not visited.
+    pub fn note_removed(&mut self, count: usize) {
+        self.visited = self.visited.saturating_sub(count);
+    }
+}
+
+/// Live keys mapped to their entries, hashed by default or kept in key order
+/// when range scans are wanted.
+#[derive(Debug)]
+pub enum KeyIndex<V> {
+    Hashed(HashMap<String, V>),
+    Ordered(BTreeMap<String, V>),
+}
+
+impl<V> KeyIndex<V> {
+    /// Creates an empty index, ordered or not.
+    pub fn new(ordered: bool) -> Self {
+        if ordered {
+            KeyIndex::Ordered(BTreeMap::new())
+        } else {
+            KeyIndex::Hashed(HashMap::new())
+        }
+    }
+
+    /// Creates an empty index with room for `capacity` keys; an ordered index
+    /// grows node by node, so it ignores the hint.
+    pub fn with_capacity(ordered: bool, capacity: usize) -> Self {
+        if ordered {
+            KeyIndex::Ordered(BTreeMap::new())
+        } else {
+            KeyIndex::Hashed(HashMap::with_capacity(capacity))
+        }
+    }
+
+    /// Whether keys are kept in order.
+    pub fn is_ordered(&self) -> bool {
+        matches!(self, KeyIndex::Ordered(_))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            KeyIndex::Hashed(map) => map.len(),
+            KeyIndex::Ordered(map) => map.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        match self {
+            KeyIndex::Hashed(map) => map.get(key),
+            KeyIndex::Ordered(map) => map.get(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        match self {
+            KeyIndex::Hashed(map) => map.insert(key, value),
+            KeyIndex::Ordered(map) => map.insert(key, value),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        match self {
+            KeyIndex::Hashed(map) => map.remove(key),
+            KeyIndex::Ordered(map) => map.remove(key),
+        }
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&String, &mut V) -> bool) {
+        match self {
+            KeyIndex::Hashed(map) => map.retain(|key, value| keep(key, value)),
+            KeyIndex::Ordered(map) => map.retain(|key, value| keep(key, value)),
+        }
+    }
+
+    /// Iterates over every entry, in key order when the index is ordered.
+    pub fn iter(&self) -> Iter<'_, V> {
+        match self {
+            KeyIndex::Hashed(map) => Iter::Hashed(map.iter()),
+            KeyIndex::Ordered(map) => Iter::Ordered(map.iter()),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, V> {
+        match self {
+            KeyIndex::Hashed(map) => IterMut::Hashed(map.iter_mut()),
+            KeyIndex::Ordered(map) => IterMut::Ordered(map.iter_mut()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Returns up to `limit` entries with keys between the bounds, in key order.
+    ///
+    /// An ordered index walks just that part of the tree; a hashed one scans
+    /// every key and sorts the matches.
+    pub fn range(&self, bounds: (Bound<&str>, Bound<&str>), limit: usize) -> Vec<(&String, &V)> {
+        match self {
+            KeyIndex::Ordered(map) => map.range::<str, _>(bounds).take(limit).collect(),
+            KeyIndex::Hashed(map) => {
+                let mut matches: Vec<(&String, &V)> = map
+                    .iter()
+                    .filter(|(key, _)| RangeBounds::<str>::contains(&bounds, key.as_str()))
+                    .collect();
+                if matches.len() > limit {
+                    matches.select_nth_unstable_by(limit, |a, b| a.0.cmp(b.0));
+                    matches.truncate(limit);
+                }
+                matches.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                matches
+            }
+        }
+    }
+
+    /// Returns up to `limit` entries following `cursor` and moves it past
+    /// them; nothing once the walk has reached the end.
+    pub fn chunk(&self, cursor: &mut IndexCursor, limit: usize) -> Vec<(&String, &V)> {
+        let chunk: Vec<(&String, &V)> = match self {
+            KeyIndex::Ordered(map) => {
+                let start = match &cursor.last {
+                    Some(last) => Bound::Excluded(last.as_str()),
+                    None => Bound::Unbounded,
+                };
+                map.range::<str, _>((start, Bound::Unbounded))
+                    .take(limit)
+                    .collect()
+            }
+            KeyIndex::Hashed(map) => map.iter().skip(cursor.visited).take(limit).collect(),
+        };
+        cursor.visited += chunk.len();
+        if let Some((key, _)) = chunk.last() {
+            cursor.last = Some((*key).clone());
+        }
+        chunk
+    }
+}
+
+impl<V> FromIterator<(String, V)> for KeyIndex<V> {
+    /// Collects into a hashed index; use [`KeyIndex::new`] and [`Extend`] for
+    /// an ordered one.
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(entries: I) -> Self {
+        KeyIndex::Hashed(entries.into_iter().collect())
+    }
+}
+
+impl<V> Extend<(String, V)> for KeyIndex<V> {
+    fn extend<I: IntoIterator<Item = (String, V)>>(&mut self, entries: I) {
+        match self {
+            KeyIndex::Hashed(map) => map.extend(entries),
+            KeyIndex::Ordered(map) => map.extend(entries),
+        }
+    }
+}
+
+impl<V> std::ops::Index<&str> for KeyIndex<V> {
+    type Output = V;
+
+    fn index(&self, key: &str) -> &V {
+        self.get(key).expect("key not in index")
+    }
+}
+
+impl<'a, V> IntoIterator for &'a KeyIndex<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<'a, V> IntoIterator for &'a mut KeyIndex<V> {
+    type Item = (&'a String, &'a mut V);
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter(self) -> IterMut<'a, V> {
+        self.iter_mut()
+    }
+}
+
+/// Iterator over the entries of a [`KeyIndex`].
+pub enum Iter<'a, V> {
+    Hashed(hash_map::Iter<'a, String, V>),
+    Ordered(btree_map::Iter<'a, String, V>),
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (&'a String, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Hashed(iter) => iter.next(),
+            Iter::Ordered(iter) => iter.next(),
+        }
+    }
+}
+
+/// Mutable iterator over the entries of a [`KeyIndex`].
+pub enum IterMut<'a, V> {
+    Hashed(hash_map::IterMut<'a, String, V>),
+    Ordered(btree_map::IterMut<'a, String, V>),
+}
+
+impl<'a, V> Iterator for IterMut<'a, V> {
+    type Item = (&'a String, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            IterMut::Hashed(iter) => iter.next(),
+            IterMut::Ordered(iter) => iter.next(),
+        }
+    }
+}
@@ -0,0 +1,2795 @@
+//! Write-ahead log providing durable storage for CrabKv operations.
+
+use super::blob::BlobRef;
+use super::checkpoint::{Checkpoint, CheckpointEntry};
+use super::compaction;
+use super::index::ValuePointer;
+use crate::background::{BackgroundError, BackgroundErrors, BackgroundWorker, Backoff};
+use crate::clock::Clock;
+use crate::utf8::{InvalidUtf8, Utf8Policy};
+use parking_lot::Condvar;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "fault-injection")]
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const HEADER_SIZE: usize = 1 + 4 + 4 + 1 + 8;
+/// Size of the write time stored ahead of the value in timestamped records.
+const STAMP_SIZE: usize = 8;
+/// Payload size of a reference record; values no longer than this are never shared.
+pub const REF_PAYLOAD_SIZE: usize = 8 + 4 + 4 + STAMP_SIZE;
+
+/// Bytes read ahead when a [`SequentialReader`] walks the log in offset order.
+const SEQUENTIAL_READ_BUFFER: usize = 64 * 1024;
+
+/// Record length assumed when sizing the index from the length of the log.
+const ESTIMATED_RECORD_LEN: u64 = 64;
+
+/// Most keys the index is sized for up front; larger logs grow it as they load.
+const MAX_RESERVED_KEYS: u64 = 1 << 20;
+
+/// Index rebuilt from the log, mapping keys to where their value lives.
+pub type LoadedIndex = HashMap<String, LoadedEntry>;
+
+/// Location and metadata of a live key recovered from the log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LoadedEntry {
+    pub pointer: ValuePointer,
+    pub expires_at: Option<SystemTime>,
+    /// When the key was last written; `None` for records without a write time.
+    pub written_at: Option<SystemTime>,
+    /// Whether the value lives in a blob file rather than in the log.
+    pub blob: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum WalOp {
+    Put = 1,
+    Delete = 2,
+    /// Header announcing that the next `n` records commit together; `n` is
+    /// stored in the TTL slot.
+    Batch = 3,
+    /// Binds a key to the value of an earlier put record; the payload holds the
+    /// target's offset, value length, record length and the write time.
+    Ref = 4,
+    /// Put whose value payload starts with the write time in nanoseconds since
+    /// the Unix epoch, so readers that skip unknown opcodes can still step over it.
+    PutAt = 5,
+    /// Put whose value lives in a blob file; the payload holds the write time,
+    /// the value length, and the blob file name.
+    Blob = 6,
+    /// Idempotency token of a write, written in the same batch; the key slot
+    /// holds the token, the payload the write's outcome, and the TTL slot the
+    /// end of the window it is remembered for.
+    Token = 7,
+    /// Put of a value that is raw bytes rather than UTF-8; the payload starts
+    /// with the write time like [`WalOp::PutAt`], zero when none was recorded.
+    Bytes = 8,
+}
+
+impl WalOp {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(WalOp::Put),
+            2 => Some(WalOp::Delete),
+            3 => Some(WalOp::Batch),
+            4 => Some(WalOp::Ref),
+            5 => Some(WalOp::PutAt),
+            6 => Some(WalOp::Blob),
+            7 => Some(WalOp::Token),
+            8 => Some(WalOp::Bytes),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of decoding one record from a log stream.
+enum Decoded {
+    Record(WalRecord),
+    /// A record with an unrecognised opcode was skipped; carries its length.
+    Skipped(u32),
+    /// A batch header covering the given number of following records.
+    Batch(u64),
+    /// A record whose value is not valid UTF-8, decoded with the invalid
+    /// sequences replaced; what becomes of it is up to the [`Utf8Policy`].
+    Lossy(WalRecord),
+    /// A reference binding `key` to the put record described by `target`.
+    Ref {
+        key: String,
+        target: ValuePointer,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+        record_len: u32,
+    },
+}
+
+/// Records read by [`Wal::read_from`].
+#[derive(Clone, Debug)]
+pub struct LogSlice {
+    /// Generation the offsets belong to.
+    pub generation: u64,
+    /// Each record with the offset it starts at.
+    pub entries: Vec<(u64, WalEntry)>,
+    /// Offset just past the last record read.
+    pub next_offset: u64,
+    /// Length of the log when it was read.
+    pub end: u64,
+}
+
+/// What [`Wal::scrub_from`] found checking a stretch of the log.
+#[derive(Clone, Debug)]
+pub struct ScrubSlice {
+    /// Generation the offsets belong to.
+    pub generation: u64,
+    /// Offset the check started at: the one asked for, or 0 when another
+    /// generation was installed since.
+    pub start: u64,
+    /// Offset just past the last record checked.
+    pub next_offset: u64,
+    /// Length of the log when it was read.
+    pub end: u64,
+    /// Records checked, batch headers included.
+    pub records: u64,
+    /// Offset of each record that did not decode, with why.
+    pub damaged: Vec<(u64, String)>,
+}
+
+/// Pointers and total size of a batch appended to the log.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AppendedBatch {
+    /// One pointer per entry, in the order they were supplied.
+    pub pointers: Vec<ValuePointer>,
+    /// Bytes appended, including the batch header.
+    pub bytes: u64,
+}
+
+impl AppendedBatch {
+    /// Bytes spent on framing rather than on any entry.
+    pub fn overhead(&self) -> u64 {
+        self.bytes
+            - self
+                .pointers
+                .iter()
+                .map(|pointer| pointer.record_len as u64)
+                .sum::<u64>()
+    }
+}
+
+/// Persistent log entry describing either a put or delete operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WalEntry {
+    /// Stores the provided UTF-8 value for the key.
+    Put {
+        key: String,
+        value: String,
+        expires_at: Option<SystemTime>,
+        /// When the write happened; records from older logs carry none.
+        written_at: Option<SystemTime>,
+    },
+    /// Stores the provided bytes for the key, with no UTF-8 requirement.
+    Bytes {
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+    },
+    /// Stores a value kept in a blob file, referenced by name.
+    Blob {
+        key: String,
+        blob: BlobRef,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+    },
+    /// Removes the key from the store.
+    Delete { key: String },
+    /// Records that the write carrying `token` was applied with `outcome`;
+    /// not a key, and never in the index.
+    Token {
+        token: String,
+        outcome: String,
+        expires_at: SystemTime,
+    },
+}
+
+impl WalEntry {
+    /// Returns the key the entry applies to, or the token of a token entry.
+    pub fn key(&self) -> &str {
+        match self {
+            WalEntry::Put { key, .. }
+            | WalEntry::Bytes { key, .. }
+            | WalEntry::Blob { key, .. }
+            | WalEntry::Delete { key } => key,
+            WalEntry::Token { token, .. } => token,
+        }
+    }
+
+    pub(crate) fn key_mut(&mut self) -> &mut String {
+        match self {
+            WalEntry::Put { key, .. }
+            | WalEntry::Bytes { key, .. }
+            | WalEntry::Blob { key, .. }
+            | WalEntry::Delete { key } => key,
+            WalEntry::Token { token, .. } => token,
+        }
+    }
+
+    fn key_bytes(&self) -> &[u8] {
+        self.key().as_bytes()
+    }
+
+    fn value_bytes(&self) -> &[u8] {
+        match self {
+            WalEntry::Put { value, .. } => value.as_bytes(),
+            WalEntry::Bytes { value, .. } => value,
+            WalEntry::Token { outcome, .. } => outcome.as_bytes(),
+            WalEntry::Blob { .. } | WalEntry::Delete { .. } => &[],
+        }
+    }
+
+    /// Length of the stored value, wherever it lives.
+    fn value_len(&self) -> u32 {
+        match self {
+            WalEntry::Blob { blob, .. } => u32::try_from(blob.len).unwrap_or(u32::MAX),
+            _ => self.value_bytes().len() as u32,
+        }
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        match self {
+            WalEntry::Put { expires_at, .. }
+            | WalEntry::Bytes { expires_at, .. }
+            | WalEntry::Blob { expires_at, .. } => *expires_at,
+            WalEntry::Token { expires_at, .. } => Some(*expires_at),
+            WalEntry::Delete { .. } => None,
+        }
+    }
+
+    fn written_at(&self) -> Option<SystemTime> {
+        match self {
+            WalEntry::Put { written_at, .. }
+            | WalEntry::Bytes { written_at, .. }
+            | WalEntry::Blob { written_at, .. } => *written_at,
+            WalEntry::Delete { .. } | WalEntry::Token { .. } => None,
+        }
+    }
+}
+
+/// Result of replaying the log on open.
+#[derive(Debug, Default)]
+pub struct LoadedLog {
+    /// Live keys and their pointers.
+    pub index: LoadedIndex,
+    /// Bytes held by superseded, deleted, or already expired records.
+    pub stale_bytes: u64,
+    /// Number of put records skipped because they had already expired.
+    pub expired_skipped: u64,
+    /// Number of records skipped because their opcode was not recognised.
+    pub unknown_skipped: u64,
+    /// Length of the log up to the last complete record or batch.
+    pub valid_len: u64,
+    /// Live keys bound to a shared record by a reference, with the length of
+    /// their reference record.
+    pub ref_lens: HashMap<String, u32>,
+    /// Hash of each live shareable value to the put record holding it, filled
+    /// when value dedup is enabled.
+    pub shared_values: HashMap<u64, ValuePointer>,
+    /// Idempotency tokens whose window had not ended, with their outcome and
+    /// the end of the window.
+    pub tokens: HashMap<String, (String, SystemTime)>,
+    /// Keys whose latest put holds a value that is not valid UTF-8, with the
+    /// offset of that put; filled only under [`Utf8Policy::Skip`].
+    pub invalid_utf8: HashMap<String, u64>,
+    /// Most memory the replay held at once, estimated from map capacities,
+    /// key lengths and the largest batch read.
+    pub peak_memory_bytes: u64,
+    /// Bytes of the keys and tokens the maps own.
+    key_bytes: u64,
+}
+
+impl LoadedLog {
+    /// Estimates the memory the maps hold: every allocated slot, plus the
+    /// keys and tokens they own.
+    pub fn held_bytes(&self) -> u64 {
+        fn slots<T>(capacity: usize) -> u64 {
+            // One control byte per slot besides the slot itself.
+            (capacity * (size_of::<T>() + 1)) as u64
+        }
+        slots::<(String, LoadedEntry)>(self.index.capacity())
+            + slots::<(String, u32)>(self.ref_lens.capacity())
+            + slots::<(u64, ValuePointer)>(self.shared_values.capacity())
+            + slots::<(String, (String, SystemTime))>(self.tokens.capacity())
+            + self.key_bytes
+    }
+
+    /// Raises the peak to what is held now plus `buffered` bytes outside the maps.
+    fn note_peak(&mut self, buffered: u64) {
+        self.peak_memory_bytes = self.peak_memory_bytes.max(self.held_bytes() + buffered);
+    }
+
+    /// Remembers the record holding `value` so later puts can share it.
+    fn remember_value(&mut self, value: &str, pointer: ValuePointer) {
+        if value.len() > REF_PAYLOAD_SIZE {
+            self.shared_values.insert(value_hash(value), pointer);
+        }
+    }
+
+    /// Forgets remembered values whose record no live key points at any more.
+    fn retain_live_values(&mut self) {
+        if self.shared_values.is_empty() {
+            return;
+        }
+        self.note_peak((self.index.len() * (size_of::<u64>() + 1)) as u64);
+        let live: HashSet<u64> = self
+            .index
+            .values()
+            .filter(|entry| !entry.blob)
+            .map(|entry| entry.pointer.offset)
+            .collect();
+        self.shared_values
+            .retain(|_, pointer| live.contains(&pointer.offset));
+    }
+
+    fn apply(&mut self, record: WalRecord, offset: u64, now: SystemTime) {
+        let pointer = ValuePointer::new(offset, record.value_len, record.record_len);
+        match record.entry {
+            WalEntry::Put {
+                key,
+                expires_at,
+                written_at,
+                ..
+            }
+            | WalEntry::Bytes {
+                key,
+                expires_at,
+                written_at,
+                ..
+            } => {
+                let entry = LoadedEntry {
+                    pointer,
+                    expires_at,
+                    written_at,
+                    blob: false,
+                };
+                self.bind(key, entry, None, now)
+            }
+            WalEntry::Blob {
+                key,
+                expires_at,
+                written_at,
+                ..
+            } => {
+                let entry = LoadedEntry {
+                    pointer,
+                    expires_at,
+                    written_at,
+                    blob: true,
+                };
+                self.bind(key, entry, None, now)
+            }
+            WalEntry::Delete { key } => self.release(&key),
+            WalEntry::Token {
+                token,
+                outcome,
+                expires_at,
+            } => {
+                if now >= expires_at {
+                    self.stale_bytes += record.record_len as u64;
+                } else {
+                    let held = (token.len() + outcome.len()) as u64;
+                    if self.tokens.insert(token, (outcome, expires_at)).is_none() {
+                        self.key_bytes += held;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Points `key` at `entry`, where `ref_len` is set when the key goes
+    /// through a reference record rather than owning the record itself.
+    fn bind(&mut self, key: String, entry: LoadedEntry, ref_len: Option<u32>, now: SystemTime) {
+        self.release(&key);
+        let own_len = ref_len.unwrap_or(entry.pointer.record_len);
+        if matches!(entry.expires_at, Some(deadline) if now >= deadline) {
+            self.stale_bytes += own_len as u64;
+            self.expired_skipped += 1;
+            return;
+        }
+        if let Some(ref_len) = ref_len {
+            self.key_bytes += key.len() as u64;
+            self.ref_lens.insert(key.clone(), ref_len);
+        }
+        self.key_bytes += key.len() as u64;
+        self.index.insert(key, entry);
+    }
+
+    /// Drops `key` from the index, counting the bytes it owned as stale.
+    ///
+    /// A shared record is counted by its owner only, so it may be reported
+    /// stale while references still use it; compaction settles the difference.
+    fn release(&mut self, key: &str) {
+        self.invalid_utf8.remove(key);
+        if let Some(previous) = self.index.remove(key) {
+            // Maps filled outside `bind` were never counted.
+            self.key_bytes = self.key_bytes.saturating_sub(key.len() as u64);
+            let own_len = match self.ref_lens.remove(key) {
+                Some(ref_len) => {
+                    self.key_bytes = self.key_bytes.saturating_sub(key.len() as u64);
+                    ref_len
+                }
+                None => previous.pointer.record_len,
+            };
+            self.stale_bytes += own_len as u64;
+        }
+    }
+}
+
+/// Decoded record retrieved from the log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WalRecord {
+    /// Entry reconstructed from the log.
+    pub entry: WalEntry,
+    /// Starting byte offset of the record within the log.
+    pub offset: u64,
+    /// Total size of the record in bytes.
+    pub record_len: u32,
+    /// Size of the value payload.
+    pub value_len: u32,
+}
+
+/// Write-ahead log abstraction responsible for durable persistence.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    writer: Mutex<BufWriter<File>>,
+    sync_interval: Option<Duration>,
+    compression: bool,
+    skip_unknown_ops: bool,
+    utf8_policy: Utf8Policy,
+    value_dedup: bool,
+    /// Workers [`Wal::write_generation`] encodes records on.
+    compaction_threads: usize,
+    #[cfg(feature = "fault-injection")]
+    injected_failures: AtomicUsize,
+    /// Bytes appended since open; the running total is the log sequence number.
+    appended: AtomicU64,
+    /// fsyncs issued on log files since open.
+    syncs: AtomicU64,
+    /// Log files opened since open, the log itself included.
+    opens: AtomicU64,
+    /// Values returned with invalid UTF-8 replaced under [`Utf8Policy::Lossy`].
+    lossy_reads: AtomicU64,
+    /// Log sequence number up to which appends are known to be on disk.
+    synced: parking_lot::Mutex<u64>,
+    /// Signalled whenever `synced` advances.
+    synced_advanced: Condvar,
+    /// Dropping the sender stops the flusher thread started by [`Wal::start_flusher`].
+    flusher: Mutex<Option<Sender<()>>>,
+    /// Where the flusher reports failed syncs and panics.
+    background: Arc<BackgroundErrors>,
+    /// Handle reads go through, tagged with the number of generations
+    /// installed before it, counted since the log was created. Readers clone
+    /// it, so a read that started before a swap finishes on the file it
+    /// started on.
+    reader: parking_lot::RwLock<(u64, Arc<File>)>,
+}
+
+/// Error payload returned when a read raced a compaction swap and the pointer
+/// it was given belongs to a generation that is no longer installed.
+///
+/// Carried inside an [`io::Error`] of kind [`io::ErrorKind::Other`]; look the
+/// key up again and retry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryableCompaction {
+    /// Generation the pointer was taken from.
+    pub expected: u64,
+    /// Generation installed when the read ran.
+    pub installed: u64,
+}
+
+impl RetryableCompaction {
+    /// Whether `err` carries a [`RetryableCompaction`].
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref()
+            .is_some_and(|inner| inner.is::<RetryableCompaction>())
+    }
+}
+
+impl fmt::Display for RetryableCompaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read of log generation {} raced compaction, which installed generation {}; retry",
+            self.expected, self.installed
+        )
+    }
+}
+
+impl std::error::Error for RetryableCompaction {}
+
+/// Error payload carrying a record whose value was not valid UTF-8 until
+/// [`Wal::settle_utf8`] applies the policy to it.
+#[derive(Debug)]
+struct LossyValue(WalRecord);
+
+impl LossyValue {
+    /// The read of `record`, found at `offset`, before the policy is applied.
+    fn at(mut record: WalRecord, offset: u64) -> io::Result<WalRecord> {
+        record.offset = offset;
+        Err(io::Error::new(ErrorKind::InvalidData, Self(record)))
+    }
+}
+
+impl fmt::Display for LossyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid utf-8 value")
+    }
+}
+
+impl std::error::Error for LossyValue {}
+
+/// Compacted log written by [`Wal::write_generation`] and not yet installed.
+#[derive(Debug)]
+pub struct Generation {
+    writer: BufWriter<File>,
+    loaded: LoadedLog,
+    damaged: Vec<DamagedRecord>,
+    peak_buffer_bytes: u64,
+}
+
+impl Generation {
+    /// Takes the records [`Wal::copy_generation`] left out because they no
+    /// longer decode.
+    pub fn take_damaged(&mut self) -> Vec<DamagedRecord> {
+        std::mem::take(&mut self.damaged)
+    }
+
+    /// Most value bytes held decoded at once while the generation was written.
+    pub fn peak_buffer_bytes(&self) -> u64 {
+        self.peak_buffer_bytes
+    }
+}
+
+/// A live key [`Wal::copy_generation`] copies, with where its value is stored
+/// and the expiry and write time it keeps.
+///
+/// A key sharing another's record points at that record; the copy takes this
+/// key, expiry and write time rather than the owner's.
+#[derive(Clone, Debug)]
+pub struct LiveRecord {
+    pub key: String,
+    pub pointer: ValuePointer,
+    pub expires_at: Option<SystemTime>,
+    pub written_at: Option<SystemTime>,
+}
+
+impl LiveRecord {
+    /// Turns the entry stored at the pointer into this key's entry; `None`
+    /// for records that carry no value.
+    fn rekey(&self, stored: WalEntry) -> Option<WalEntry> {
+        match stored {
+            WalEntry::Put { value, .. } => Some(WalEntry::Put {
+                key: self.key.clone(),
+                value,
+                expires_at: self.expires_at,
+                written_at: self.written_at,
+            }),
+            WalEntry::Bytes { value, .. } => Some(WalEntry::Bytes {
+                key: self.key.clone(),
+                value,
+                expires_at: self.expires_at,
+                written_at: self.written_at,
+            }),
+            // Only the reference is copied; the blob file stays where it is.
+            WalEntry::Blob { blob, .. } => Some(WalEntry::Blob {
+                key: self.key.clone(),
+                blob,
+                expires_at: self.expires_at,
+                written_at: self.written_at,
+            }),
+            WalEntry::Delete { .. } | WalEntry::Token { .. } => None,
+        }
+    }
+}
+
+/// A live key whose record could not be decoded during compaction.
+#[derive(Clone, Debug)]
+pub struct DamagedRecord {
+    pub key: String,
+    pub offset: u64,
+    pub error: String,
+}
+
+/// A generation being written, with the index of what it holds so far.
+struct GenerationWriter {
+    writer: BufWriter<File>,
+    loaded: LoadedLog,
+    /// Hash of each shareable value written to the put record holding it.
+    shared: HashMap<u64, ValuePointer>,
+    offset: u64,
+    damaged: Vec<DamagedRecord>,
+    peak_buffer_bytes: u64,
+}
+
+impl GenerationWriter {
+    /// Creates or empties the file the next generation is written to.
+    fn start(wal: &Wal) -> io::Result<Self> {
+        let temp_path = wal.path.with_extension("compact");
+        let file = Wal::shared(OpenOptions::new().read(true).append(true).create(true))
+            .open(&temp_path)
+            .map_err(|err| with_path(err, &temp_path))?;
+        wal.opens.fetch_add(1, Ordering::Relaxed);
+        file.set_len(0).map_err(|err| with_path(err, &temp_path))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            loaded: LoadedLog::default(),
+            shared: HashMap::new(),
+            offset: 0,
+            damaged: Vec::new(),
+            peak_buffer_bytes: 0,
+        })
+    }
+
+    /// Syncs the written records and hands them over for [`Wal::install`].
+    fn finish(mut self, wal: &Wal) -> io::Result<Generation> {
+        self.loaded.valid_len = self.offset;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        wal.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(Generation {
+            writer: self.writer,
+            loaded: self.loaded,
+            damaged: self.damaged,
+            peak_buffer_bytes: self.peak_buffer_bytes,
+        })
+    }
+
+    /// Writes one entry, using `encoded` when the workers already encoded it.
+    fn write(
+        &mut self,
+        wal: &Wal,
+        entry: &WalEntry,
+        encoded: Option<io::Result<Vec<u8>>>,
+    ) -> io::Result<()> {
+        let encoded = || encoded.unwrap_or_else(|| wal.encode_entry(entry));
+        let offset = self.offset;
+        match entry {
+            WalEntry::Blob {
+                key,
+                expires_at,
+                written_at,
+                ..
+            } => {
+                let encoded = encoded()?;
+                self.writer.write_all(&encoded)?;
+                let pointer = ValuePointer::new(offset, entry.value_len(), encoded.len() as u32);
+                let loaded_entry = LoadedEntry {
+                    pointer,
+                    expires_at: *expires_at,
+                    written_at: *written_at,
+                    blob: true,
+                };
+                self.loaded.index.insert(key.clone(), loaded_entry);
+                self.offset += encoded.len() as u64;
+            }
+            WalEntry::Token {
+                token,
+                outcome,
+                expires_at,
+            } => {
+                let encoded = encoded()?;
+                self.writer.write_all(&encoded)?;
+                self.loaded
+                    .tokens
+                    .insert(token.clone(), (outcome.clone(), *expires_at));
+                self.offset += encoded.len() as u64;
+            }
+            WalEntry::Put {
+                key,
+                value,
+                expires_at,
+                written_at,
+            } => {
+                if let Some(target) = self.shared_record(wal, value)? {
+                    // A copy the workers encoded ahead is dropped for the reference.
+                    let encoded = Wal::encode_ref(key, target, *expires_at, *written_at);
+                    self.writer.write_all(&encoded)?;
+                    let loaded_entry = LoadedEntry {
+                        pointer: target,
+                        expires_at: *expires_at,
+                        written_at: *written_at,
+                        blob: false,
+                    };
+                    self.loaded.index.insert(key.clone(), loaded_entry);
+                    self.loaded
+                        .ref_lens
+                        .insert(key.clone(), encoded.len() as u32);
+                    self.offset += encoded.len() as u64;
+                    return Ok(());
+                }
+                let encoded = encoded()?;
+                self.writer.write_all(&encoded)?;
+                let pointer = ValuePointer::new(offset, value.len() as u32, encoded.len() as u32);
+                let loaded_entry = LoadedEntry {
+                    pointer,
+                    expires_at: *expires_at,
+                    written_at: *written_at,
+                    blob: false,
+                };
+                self.loaded.index.insert(key.clone(), loaded_entry);
+                if wal.value_dedup && value.len() > REF_PAYLOAD_SIZE {
+                    self.shared.insert(value_hash(value), pointer);
+                    self.loaded.remember_value(value, pointer);
+                }
+                self.offset += encoded.len() as u64;
+            }
+            // Byte values are never shared, so they are always copied.
+            WalEntry::Bytes {
+                key,
+                value,
+                expires_at,
+                written_at,
+            } => {
+                let encoded = encoded()?;
+                self.writer.write_all(&encoded)?;
+                let pointer = ValuePointer::new(offset, value.len() as u32, encoded.len() as u32);
+                let loaded_entry = LoadedEntry {
+                    pointer,
+                    expires_at: *expires_at,
+                    written_at: *written_at,
+                    blob: false,
+                };
+                self.loaded.index.insert(key.clone(), loaded_entry);
+                self.offset += encoded.len() as u64;
+            }
+            WalEntry::Delete { .. } => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "rewrite only accepts put, blob and token entries",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the record of this generation already holding `value`, if any.
+    ///
+    /// Values are remembered by hash only, so a match is read back from the
+    /// file and compared before it is shared.
+    fn shared_record(&mut self, wal: &Wal, value: &str) -> io::Result<Option<ValuePointer>> {
+        if !wal.value_dedup || value.len() <= REF_PAYLOAD_SIZE {
+            return Ok(None);
+        }
+        let Some(&target) = self.shared.get(&value_hash(value)) else {
+            return Ok(None);
+        };
+        self.writer.flush()?;
+        let record = Wal::read_record_from(
+            self.writer.get_ref(),
+            target.offset,
+            wal.compression,
+            wal.skip_unknown_ops,
+        )?;
+        Ok(match record.entry {
+            WalEntry::Put { value: held, .. } if held == value => Some(target),
+            _ => None,
+        })
+    }
+}
+
+/// Whether `err` means a record could not be decoded, as opposed to the log
+/// being unreadable.
+pub(crate) fn is_corruption(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof
+    )
+}
+
+impl Wal {
+    /// Opens or creates the log at the given path with optional sync interval.
+    pub fn open(
+        path: impl AsRef<Path>,
+        sync_interval: Option<Duration>,
+        compression: bool,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = Self::open_append(&path)?;
+        let generation = Self::stored_generation(&path)?;
+        let reader = parking_lot::RwLock::new((generation, Arc::new(file.try_clone()?)));
+        let writer = Mutex::new(BufWriter::new(file));
+        Ok(Self {
+            path,
+            writer,
+            sync_interval,
+            compression,
+            skip_unknown_ops: false,
+            utf8_policy: Utf8Policy::Error,
+            value_dedup: false,
+            compaction_threads: 1,
+            #[cfg(feature = "fault-injection")]
+            injected_failures: AtomicUsize::new(0),
+            appended: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+            opens: AtomicU64::new(1),
+            lossy_reads: AtomicU64::new(0),
+            synced: parking_lot::Mutex::new(0),
+            synced_advanced: Condvar::new(),
+            flusher: Mutex::new(None),
+            background: Arc::new(BackgroundErrors::new(Clock::system())),
+            reader,
+        })
+    }
+
+    /// Starts the thread that syncs the log once per sync interval.
+    ///
+    /// Under an interval, appends only write to the buffer and never pay for an
+    /// fsync themselves; this thread flushes and syncs on schedule and advances
+    /// [`Wal::durable_lsn`]. Without an interval every append syncs inline and
+    /// no thread is started. The thread stops once the log is dropped, which
+    /// syncs whatever is still pending.
+    ///
+    /// Failed syncs and panics of the thread are kept for
+    /// [`Wal::background_errors`].
+    pub fn start_flusher(self: &Arc<Self>) -> io::Result<()> {
+        let Some(interval) = self.sync_interval else {
+            return Ok(());
+        };
+        let wal = Arc::downgrade(self);
+        let errors = Arc::clone(&self.background);
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        thread::Builder::new()
+            .name("crabkv-wal-flusher".into())
+            .spawn(move || {
+                errors.supervise(BackgroundWorker::WalFlusher, || {
+                    let mut backoff = Backoff::default();
+                    let mut wait = interval;
+                    while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(wait) {
+                        let Some(wal) = wal.upgrade() else {
+                            return;
+                        };
+                        errors.crash_point(BackgroundWorker::WalFlusher);
+                        // A failed sync is retried after a backoff; waiters keep waiting.
+                        wait = match wal.sync_pending() {
+                            Ok(()) => {
+                                backoff.reset();
+                                interval
+                            }
+                            Err(err) => {
+                                errors.report(BackgroundWorker::WalFlusher, err);
+                                interval.max(backoff.next_delay())
+                            }
+                        };
+                    }
+                })
+            })?;
+        *self
+            .flusher
+            .lock()
+            .map_err(|_| io::Error::other("flusher poisoned"))? = Some(stop_tx);
+        Ok(())
+    }
+
+    /// Syncs appends made since the last sync without holding the writer lock
+    /// during the fsync, so appends keep going meanwhile.
+    fn sync_pending(&self) -> io::Result<()> {
+        let (file, lsn) = {
+            let mut writer = self
+                .writer
+                .lock()
+                .map_err(|_| io::Error::other("writer poisoned"))?;
+            let lsn = self.appended.load(Ordering::Relaxed);
+            if lsn <= self.durable_lsn() {
+                return Ok(());
+            }
+            writer.flush()?;
+            (writer.get_ref().try_clone()?, lsn)
+        };
+        file.sync_data()?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.mark_synced(lsn);
+        Ok(())
+    }
+
+    fn mark_synced(&self, lsn: u64) {
+        let mut synced = self.synced.lock();
+        // A sync of a file replaced by compaction may finish after the newer one.
+        if lsn > *synced {
+            *synced = lsn;
+            self.synced_advanced.notify_all();
+        }
+    }
+
+    /// Blocks until appends up to `lsn` are synced or `timeout` elapses,
+    /// returning whether they were.
+    pub fn wait_synced(&self, lsn: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut synced = self.synced.lock();
+        while *synced < lsn {
+            if self
+                .synced_advanced
+                .wait_until(&mut synced, deadline)
+                .timed_out()
+            {
+                return *synced >= lsn;
+            }
+        }
+        true
+    }
+
+    /// Skips records with unrecognised opcodes while replaying instead of failing.
+    ///
+    /// Every record header carries its key and value lengths, so records written
+    /// by newer builds can be stepped over. Skipped bytes are treated as stale and
+    /// are dropped by the next compaction.
+    pub fn skip_unknown_ops(mut self, enabled: bool) -> Self {
+        self.skip_unknown_ops = enabled;
+        self
+    }
+
+    /// Sets what becomes of values that are not valid UTF-8; see [`Utf8Policy`].
+    pub fn utf8_policy(mut self, policy: Utf8Policy) -> Self {
+        self.utf8_policy = policy;
+        self
+    }
+
+    /// Reports failures of the flusher to `errors` instead of the log's own
+    /// ring, so they show up wherever the owner looks for them.
+    pub(crate) fn report_to(mut self, errors: &Arc<BackgroundErrors>) -> Self {
+        self.background = Arc::clone(errors);
+        self
+    }
+
+    /// Returns the failures the flusher reported, oldest first.
+    pub fn background_errors(&self) -> Vec<BackgroundError> {
+        self.background.snapshot()
+    }
+
+    /// Returns how many values were read with invalid UTF-8 replaced.
+    pub fn lossy_read_count(&self) -> u64 {
+        self.lossy_reads.load(Ordering::Relaxed)
+    }
+
+    /// Makes [`Wal::rewrite`] store each distinct value once, binding further
+    /// keys holding the same value through reference records.
+    pub fn value_dedup(mut self, enabled: bool) -> Self {
+        self.value_dedup = enabled;
+        self
+    }
+
+    /// Encodes records for [`Wal::write_generation`] on `threads` workers.
+    ///
+    /// The generation is byte-for-byte the one a single thread writes; values
+    /// are just compressed in parallel, a bounded window at a time. Zero is
+    /// treated as one.
+    pub fn compaction_threads(mut self, threads: usize) -> Self {
+        self.compaction_threads = threads.max(1);
+        self
+    }
+
+    /// Returns the number of workers compaction encodes and decodes records on.
+    pub fn compaction_thread_count(&self) -> usize {
+        self.compaction_threads
+    }
+
+    /// Returns the underlying log path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the current size of the log in bytes.
+    pub fn size(&self) -> io::Result<u64> {
+        match fs::metadata(&self.path) {
+            Ok(meta) => Ok(meta.len()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the last modification time of the log file, if it exists.
+    pub fn modified(&self) -> io::Result<Option<SystemTime>> {
+        match fs::metadata(&self.path) {
+            Ok(meta) => meta.modified().map(Some),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Appends an entry to the log and returns a pointer describing it.
+    pub fn append(&self, entry: &WalEntry) -> io::Result<ValuePointer> {
+        let encoded = self.encode_entry(entry)?;
+        let offset = self.write_record(&encoded)?;
+        Ok(ValuePointer::new(
+            offset,
+            entry.value_len(),
+            encoded.len() as u32,
+        ))
+    }
+
+    /// Appends a record binding `key` to the put record at `target`.
+    ///
+    /// Returns the pointer of the reference record itself; reads of the key go
+    /// through `target`.
+    pub fn append_ref(
+        &self,
+        key: &str,
+        target: ValuePointer,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+    ) -> io::Result<ValuePointer> {
+        let encoded = Self::encode_ref(key, target, expires_at, written_at);
+        let offset = self.write_record(&encoded)?;
+        Ok(ValuePointer::new(
+            offset,
+            REF_PAYLOAD_SIZE as u32,
+            encoded.len() as u32,
+        ))
+    }
+
+    /// Writes one encoded record at the end of the log and returns its offset.
+    fn write_record(&self, encoded: &[u8]) -> io::Result<u64> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("writer poisoned"))?;
+        let offset = writer.seek(SeekFrom::End(0))?;
+        writer.write_all(encoded)?;
+        let lsn = self
+            .appended
+            .fetch_add(encoded.len() as u64, Ordering::Relaxed)
+            + encoded.len() as u64;
+
+        // Under a sync interval the flusher thread syncs on schedule instead.
+        if self.sync_interval.is_none() {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            self.mark_synced(lsn);
+        }
+
+        Ok(offset)
+    }
+
+    /// Appends multiple entries as one atomic batch with a single fsync.
+    ///
+    /// Multi-entry batches are preceded by a header carrying the entry count;
+    /// on replay a batch cut short by a crash is discarded as a whole, so either
+    /// every entry becomes durable or none does. If the append fails, bytes
+    /// already written for the batch are cut back off the log.
+    pub fn append_batch(&self, entries: &[WalEntry]) -> io::Result<AppendedBatch> {
+        if entries.is_empty() {
+            return Ok(AppendedBatch::default());
+        }
+
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("writer poisoned"))?;
+        writer.flush()?;
+        let start = writer.seek(SeekFrom::End(0))?;
+
+        let appended = match self.write_batch(&mut writer, entries, start) {
+            Ok(appended) => appended,
+            Err(err) => {
+                // Discard whatever the buffer still holds and cut the partial batch.
+                let file = writer.get_ref().try_clone()?;
+                let (file, _) = std::mem::replace(&mut *writer, BufWriter::new(file)).into_parts();
+                file.set_len(start)?;
+                return Err(err);
+            }
+        };
+
+        let lsn = self.appended.fetch_add(appended.bytes, Ordering::Relaxed) + appended.bytes;
+        // Earlier records reached the file ahead of the batch and are synced with it.
+        self.mark_synced(lsn);
+
+        Ok(appended)
+    }
+
+    /// Makes the next `count` batch appends fail with [`ErrorKind::Interrupted`].
+    #[cfg(feature = "fault-injection")]
+    pub fn fail_next_batches(&self, count: usize) {
+        self.injected_failures.store(count, Ordering::Relaxed);
+    }
+
+    fn write_batch(
+        &self,
+        writer: &mut BufWriter<File>,
+        entries: &[WalEntry],
+        start: u64,
+    ) -> io::Result<AppendedBatch> {
+        #[cfg(feature = "fault-injection")]
+        if self
+            .injected_failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(io::Error::new(
+                ErrorKind::Interrupted,
+                "injected append failure",
+            ));
+        }
+
+        let mut pointers = Vec::with_capacity(entries.len());
+        let mut offset = start;
+
+        if entries.len() > 1 {
+            let header = Self::encode_batch_header(entries.len() as u64);
+            writer.write_all(&header)?;
+            offset += header.len() as u64;
+        }
+
+        for entry in entries {
+            let encoded = self.encode_entry(entry)?;
+            writer.write_all(&encoded)?;
+            pointers.push(ValuePointer::new(
+                offset,
+                entry.value_len(),
+                encoded.len() as u32,
+            ));
+            offset += encoded.len() as u64;
+        }
+
+        // Always flush and sync after batch
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+
+        Ok(AppendedBatch {
+            pointers,
+            bytes: offset - start,
+        })
+    }
+
+    /// Returns how many bytes `entry` would occupy once appended.
+    pub fn encoded_len(&self, entry: &WalEntry) -> io::Result<u64> {
+        Ok(self.encode_entry(entry)?.len() as u64)
+    }
+
+    /// Reads the record stored at the provided pointer in the installed
+    /// generation.
+    pub fn read_record(&self, pointer: ValuePointer) -> io::Result<WalRecord> {
+        let file = self.read_handle()?.1;
+        self.settle_utf8(Self::read_record_from(
+            &file,
+            pointer.offset,
+            self.compression,
+            self.skip_unknown_ops,
+        ))
+    }
+
+    /// Reads the record at `pointer`, which was taken from generation
+    /// `generation`.
+    ///
+    /// Fails with [`RetryableCompaction`] when another generation has been
+    /// installed since, instead of reading the new file at an old offset.
+    pub fn read_record_in(&self, generation: u64, pointer: ValuePointer) -> io::Result<WalRecord> {
+        let (installed, file) = self.read_handle()?;
+        if installed != generation {
+            return Err(io::Error::other(RetryableCompaction {
+                expected: generation,
+                installed,
+            }));
+        }
+        self.settle_utf8(Self::read_record_from(
+            &file,
+            pointer.offset,
+            self.compression,
+            self.skip_unknown_ops,
+        ))
+    }
+
+    /// Applies the [`Utf8Policy`] to a read whose value was not valid UTF-8:
+    /// the repaired record under `Lossy`, an [`InvalidUtf8`] error otherwise.
+    fn settle_utf8(&self, read: io::Result<WalRecord>) -> io::Result<WalRecord> {
+        let err = match read {
+            Err(err) if err.get_ref().is_some_and(|inner| inner.is::<LossyValue>()) => err,
+            read => return read,
+        };
+        let inner = err.into_inner().expect("checked above");
+        let LossyValue(record) = *inner.downcast::<LossyValue>().expect("checked above");
+        if self.utf8_policy == Utf8Policy::Lossy {
+            self.lossy_reads.fetch_add(1, Ordering::Relaxed);
+            return Ok(record);
+        }
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            InvalidUtf8 {
+                key: record.entry.key().to_owned(),
+                offset: record.offset,
+            },
+        ))
+    }
+
+    /// Reads the records at `pointers`, all taken from generation
+    /// `generation`, through one handle after a single flush of buffered
+    /// appends. Records come back in the order of `pointers`; passing them in
+    /// offset order keeps the reads sequential.
+    ///
+    /// Fails with [`RetryableCompaction`] like [`Wal::read_record_in`].
+    pub fn read_records_in(
+        &self,
+        generation: u64,
+        pointers: &[ValuePointer],
+    ) -> io::Result<Vec<WalRecord>> {
+        if pointers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (installed, file) = self.read_handle()?;
+        if installed != generation {
+            return Err(io::Error::other(RetryableCompaction {
+                expected: generation,
+                installed,
+            }));
+        }
+        let mut reader = SequentialReader::new(&file, pointers[0].offset);
+        let mut records = Vec::with_capacity(pointers.len());
+        for pointer in pointers {
+            reader.seek_to(pointer.offset)?;
+            let record = self.settle_utf8(Self::read_data_record(
+                &mut reader,
+                pointer.offset,
+                self.compression,
+                self.skip_unknown_ops,
+            ))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Appends the values of the put records at `pointers`, all taken from
+    /// generation `generation`, to the end of `arena` and returns the range
+    /// each one landed in, in the order of `pointers`.
+    ///
+    /// Reads like [`Wal::read_records_in`] but decodes only the value, straight
+    /// into `arena`, so no key or value string is built per record. Byte
+    /// values are copied as they are; a string value that is not valid UTF-8
+    /// is settled under the [`Utf8Policy`] as a single read would settle it.
+    pub fn read_values_into(
+        &self,
+        generation: u64,
+        pointers: &[ValuePointer],
+        arena: &mut Vec<u8>,
+    ) -> io::Result<Vec<Range<usize>>> {
+        let mut ranges = Vec::with_capacity(pointers.len());
+        if pointers.is_empty() {
+            return Ok(ranges);
+        }
+        let (installed, file) = self.read_handle()?;
+        if installed != generation {
+            return Err(io::Error::other(RetryableCompaction {
+                expected: generation,
+                installed,
+            }));
+        }
+        let mut reader = SequentialReader::new(&file, pointers[0].offset);
+        // Compressed values are read here before being decompressed into `arena`.
+        let mut compressed = Vec::new();
+        for pointer in pointers {
+            reader.seek_to(pointer.offset)?;
+            let start = arena.len();
+            let text =
+                Self::read_value_into(&mut reader, self.compression, &mut compressed, arena)?;
+            if text && std::str::from_utf8(&arena[start..]).is_err() {
+                // Rare enough to decode the whole record again for the policy.
+                arena.truncate(start);
+                if let WalEntry::Put { value, .. } =
+                    self.read_record_in(generation, *pointer)?.entry
+                {
+                    arena.extend_from_slice(value.as_bytes());
+                }
+            }
+            ranges.push(start..arena.len());
+        }
+        Ok(ranges)
+    }
+
+    /// Decodes the value of the put record `reader` is positioned at onto the
+    /// end of `arena`, stepping over the key, and returns whether the value is
+    /// meant to be UTF-8.
+    fn read_value_into(
+        reader: &mut SequentialReader<'_>,
+        compression: bool,
+        compressed: &mut Vec<u8>,
+        arena: &mut Vec<u8>,
+    ) -> io::Result<bool> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let key_len = u32::from_le_bytes(header[1..5].try_into().expect("four bytes")) as usize;
+        let value_len = u32::from_le_bytes(header[5..9].try_into().expect("four bytes")) as usize;
+        let op = WalOp::from_byte(header[0]);
+        let stored_len = match op {
+            Some(WalOp::Put) => {
+                reader.skip(key_len as u64)?;
+                value_len
+            }
+            Some(WalOp::PutAt | WalOp::Bytes) if value_len >= STAMP_SIZE => {
+                reader.skip((key_len + STAMP_SIZE) as u64)?;
+                value_len - STAMP_SIZE
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "pointer does not refer to a put record",
+                ));
+            }
+        };
+        let start = arena.len();
+        if compression && stored_len > 0 {
+            compressed.resize(stored_len, 0);
+            reader.read_exact(compressed)?;
+            let len = snap::raw::decompress_len(compressed).map_err(io::Error::other)?;
+            arena.resize(start + len, 0);
+            snap::raw::Decoder::new()
+                .decompress(compressed, &mut arena[start..])
+                .map_err(io::Error::other)?;
+        } else {
+            arena.resize(start + stored_len, 0);
+            reader.read_exact(&mut arena[start..])?;
+        }
+        Ok(op != Some(WalOp::Bytes))
+    }
+
+    /// Number of generations installed since the log was created.
+    ///
+    /// The count is kept next to the log, in a file with the `generation`
+    /// extension, and saved before each new generation is swapped in, so after
+    /// a crash it may have skipped ahead but never repeats.
+    pub fn generation(&self) -> u64 {
+        self.reader.read().0
+    }
+
+    fn generation_path(log: &Path) -> PathBuf {
+        log.with_extension("generation")
+    }
+
+    /// Reads the generation saved next to `log`; 0 when none was saved.
+    fn stored_generation(log: &Path) -> io::Result<u64> {
+        let path = Self::generation_path(log);
+        match fs::read_to_string(&path) {
+            Ok(text) => text.trim().parse().map_err(|_| {
+                with_path(
+                    io::Error::new(ErrorKind::InvalidData, "not a generation number"),
+                    &path,
+                )
+            }),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(with_path(err, &path)),
+        }
+    }
+
+    /// Saves `generation` next to the log, replacing the file in one rename.
+    fn store_generation(&self, generation: u64) -> io::Result<()> {
+        let path = Self::generation_path(&self.path);
+        let temp = path.with_extension("generation.tmp");
+        let mut file = File::create(&temp).map_err(|err| with_path(err, &temp))?;
+        writeln!(file, "{generation}")?;
+        file.sync_all()?;
+        fs::rename(&temp, &path).map_err(|err| with_path(err, &path))
+    }
+
+    /// Records from `offset` to the end of the log, stopping at the first
+    /// record boundary once `max_bytes` have been read; a batch is never split
+    /// and at least one record is returned when any is left.
+    ///
+    /// References come back as puts of the value they share, and records with
+    /// unknown opcodes skipped under `skip_unknown_ops` are stepped over.
+    /// `offset` must start a record of the installed generation, such as the
+    /// `next_offset` of an earlier call.
+    pub fn read_from(&self, offset: u64, max_bytes: u64) -> io::Result<LogSlice> {
+        let (generation, file) = self.read_handle()?;
+        let end = file.metadata()?.len();
+        if offset > end {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("offset {offset} is past the end of the log at {end}"),
+            ));
+        }
+        let mut reader = SequentialReader::new(&file, offset);
+        let mut position = offset;
+        let mut entries = Vec::new();
+        while position < end && (position == offset || position - offset < max_bytes) {
+            let decoded = self.read_from_reader(&mut reader)?;
+            match decoded {
+                Decoded::Record(record) => {
+                    let len = record.record_len as u64;
+                    entries.push((position, record.entry));
+                    position += len;
+                }
+                Decoded::Lossy(record) => {
+                    let len = record.record_len as u64;
+                    if let Some(record) = self.unless_skipped(LossyValue::at(record, position))? {
+                        entries.push((position, record.entry));
+                    }
+                    position += len;
+                }
+                Decoded::Skipped(record_len) => position += record_len as u64,
+                Decoded::Ref {
+                    key,
+                    target,
+                    expires_at,
+                    written_at,
+                    record_len,
+                } => {
+                    let shared = self.unless_skipped(Self::read_record_from(
+                        &file,
+                        target.offset,
+                        self.compression,
+                        self.skip_unknown_ops,
+                    ))?;
+                    let Some(shared) = shared else {
+                        position += record_len as u64;
+                        continue;
+                    };
+                    let WalEntry::Put { value, .. } = shared.entry else {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("reference at offset {position} does not point at a put"),
+                        ));
+                    };
+                    entries.push((
+                        position,
+                        WalEntry::Put {
+                            key,
+                            value,
+                            expires_at,
+                            written_at,
+                        },
+                    ));
+                    position += record_len as u64;
+                }
+                Decoded::Batch(count) => {
+                    position += HEADER_SIZE as u64;
+                    for _ in 0..count {
+                        let record = match self.read_from_reader(&mut reader)? {
+                            Decoded::Record(record) => Some(record),
+                            Decoded::Lossy(record) => {
+                                let len = record.record_len as u64;
+                                let record =
+                                    self.unless_skipped(LossyValue::at(record, position))?;
+                                if record.is_none() {
+                                    position += len;
+                                }
+                                record
+                            }
+                            _ => {
+                                return Err(io::Error::new(
+                                    ErrorKind::InvalidData,
+                                    "unexpected record inside batch",
+                                ));
+                            }
+                        };
+                        if let Some(record) = record {
+                            let len = record.record_len as u64;
+                            entries.push((position, record.entry));
+                            position += len;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(LogSlice {
+            generation,
+            entries,
+            next_offset: position,
+            end,
+        })
+    }
+
+    /// Decodes every record from `offset` of `generation` on, stopping at the
+    /// first record boundary once `max_bytes` have been read, and lists the
+    /// records that do not decode instead of failing on them.
+    ///
+    /// A value that is not valid UTF-8 counts as damage unless the
+    /// [`Utf8Policy`] is `Lossy`, and an unknown opcode unless
+    /// `skip_unknown_ops` is set. A record whose lengths run past the end of
+    /// the log leaves nothing after it to frame, so the check ends there. If
+    /// another generation was installed since, or the log is shorter than
+    /// `offset`, it starts over from the beginning. Only read errors fail the
+    /// call.
+    pub fn scrub_from(
+        &self,
+        generation: u64,
+        offset: u64,
+        max_bytes: u64,
+    ) -> io::Result<ScrubSlice> {
+        // Appends only reach the file whole while the writer is held, so the
+        // end read under it is a record boundary.
+        let (installed, file, end) = {
+            let mut writer = self
+                .writer
+                .lock()
+                .map_err(|_| io::Error::other("writer poisoned"))?;
+            writer.flush()?;
+            let (installed, file) = self.reader.read().clone();
+            let end = file.metadata()?.len();
+            (installed, file, end)
+        };
+        let start = if installed == generation && offset <= end {
+            offset
+        } else {
+            0
+        };
+        let mut reader = SequentialReader::new(&file, start);
+        let mut records = 0;
+        let mut damaged = Vec::new();
+        // Set when damage leaves nothing after it to frame.
+        let mut unframed = false;
+        while reader.position() < end
+            && (reader.position() == start || reader.position() - start < max_bytes)
+        {
+            let position = reader.position();
+            let left = end - position;
+            if left < HEADER_SIZE as u64 {
+                damaged.push((position, "log ends inside a record header".to_string()));
+                unframed = true;
+                break;
+            }
+            let mut record = vec![0u8; HEADER_SIZE];
+            reader.read_exact(&mut record)?;
+            let body = match WalOp::from_byte(record[0]) {
+                Some(WalOp::Batch) => 0,
+                _ => {
+                    let key_len = u32::from_le_bytes(record[1..5].try_into().unwrap());
+                    let value_len = u32::from_le_bytes(record[5..9].try_into().unwrap());
+                    key_len as u64 + value_len as u64
+                }
+            };
+            let record_len = HEADER_SIZE as u64 + body;
+            if record_len > left {
+                damaged.push((
+                    position,
+                    format!("record of {record_len} bytes runs past the end of the log"),
+                ));
+                unframed = true;
+                break;
+            }
+            record.resize(record_len as usize, 0);
+            reader.read_exact(&mut record[HEADER_SIZE..])?;
+            let problem = match Self::read_record_internal(&mut &record[..], self.compression, true)
+            {
+                Ok(Some(Decoded::Lossy(_))) if self.utf8_policy != Utf8Policy::Lossy => {
+                    Some("value is not valid utf-8".to_string())
+                }
+                Ok(Some(Decoded::Skipped(_))) if !self.skip_unknown_ops => {
+                    Some(format!("unknown WAL opcode {}", record[0]))
+                }
+                Ok(_) => None,
+                // The whole record is in memory, so any failure is in its bytes.
+                Err(err) => Some(err.to_string()),
+            };
+            if let Some(problem) = problem {
+                damaged.push((position, problem));
+            }
+            records += 1;
+        }
+        let next_offset = if unframed { end } else { reader.position() };
+        Ok(ScrubSlice {
+            generation: installed,
+            start,
+            next_offset,
+            end,
+            records,
+            damaged,
+        })
+    }
+
+    /// Applies the [`Utf8Policy`] to `read`, returning `None` for a value that
+    /// `Skip` leaves out.
+    fn unless_skipped(&self, read: io::Result<WalRecord>) -> io::Result<Option<WalRecord>> {
+        match self.settle_utf8(read) {
+            Ok(record) => Ok(Some(record)),
+            Err(err) if self.utf8_policy == Utf8Policy::Skip && InvalidUtf8::of(&err).is_some() => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Decodes the next record of a log known to hold more.
+    fn read_from_reader<R: Read>(&self, reader: &mut R) -> io::Result<Decoded> {
+        Self::read_record_internal(reader, self.compression, self.skip_unknown_ops)?
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "log ended inside a record"))
+    }
+
+    /// Reads `len` bytes of the log starting at `offset`.
+    pub fn read_bytes(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (_, file) = self.read_handle()?;
+        let mut bytes = vec![0; len];
+        ReadAt {
+            file: &file,
+            offset,
+        }
+        .read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Flushes buffered appends so they can be read back, then returns the
+    /// installed generation and its handle.
+    fn read_handle(&self) -> io::Result<(u64, Arc<File>)> {
+        // Records buffered under a sync interval must reach the file before we read it back.
+        self.writer
+            .lock()
+            .map_err(|_| io::Error::other("writer poisoned"))?
+            .flush()?;
+        Ok(self.reader.read().clone())
+    }
+
+    /// Loads the index by replaying the log from scratch.
+    ///
+    /// Entries already expired at `now` are left out of the index and their
+    /// bytes are counted as stale; nothing is written back to the log. A record
+    /// or batch cut short at the end of the log is ignored and reported through
+    /// [`LoadedLog::valid_len`].
+    pub fn load_index(&self, now: SystemTime) -> io::Result<LoadedLog> {
+        let Some(file) = self.open_for_replay()? else {
+            return Ok(LoadedLog::default());
+        };
+        // Sized from the log length so the index is not regrown step by step;
+        // superseded records make this an overestimate, given back at the end.
+        let estimated_records = file.metadata()?.len() / ESTIMATED_RECORD_LEN;
+        let mut loaded = LoadedLog::default();
+        loaded
+            .index
+            .reserve(estimated_records.min(MAX_RESERVED_KEYS) as usize);
+        self.replay(BufReader::new(file), loaded, 0, now)
+    }
+
+    /// Loads the index from `checkpoint`, replaying only the log after its
+    /// offset. The caller checks it with [`Checkpoint::matches`] first.
+    ///
+    /// Checkpointed entries expired at `now` are left out as in a full
+    /// replay. Expired tokens are dropped without counting their records as
+    /// stale, which compaction settles.
+    pub fn resume_index(&self, checkpoint: Checkpoint, now: SystemTime) -> io::Result<LoadedLog> {
+        let mut loaded = LoadedLog {
+            stale_bytes: checkpoint.stale_bytes,
+            ..LoadedLog::default()
+        };
+        loaded.index.reserve(checkpoint.entries.len());
+        for CheckpointEntry {
+            key,
+            entry,
+            ref_len,
+        } in checkpoint.entries
+        {
+            loaded.bind(key, entry, ref_len, now);
+        }
+        for (token, outcome, expires_at) in checkpoint.tokens {
+            if now < expires_at {
+                loaded.key_bytes += (token.len() + outcome.len()) as u64;
+                loaded.tokens.insert(token, (outcome, expires_at));
+            }
+        }
+        loaded.shared_values.extend(checkpoint.shared_values);
+        loaded.note_peak(0);
+        let Some(file) = self.open_for_replay()? else {
+            return Ok(loaded);
+        };
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(checkpoint.offset))?;
+        self.replay(reader, loaded, checkpoint.offset, now)
+    }
+
+    /// Opens the log for a replay; `None` when there is no log yet.
+    fn open_for_replay(&self) -> io::Result<Option<File>> {
+        match Self::shared(OpenOptions::new().read(true)).open(&self.path) {
+            Ok(file) => {
+                self.opens.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(file))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(with_path(err, &self.path)),
+        }
+    }
+
+    /// Applies the records from `offset`, where `reader` is positioned, to
+    /// the end of the log onto `loaded`.
+    fn replay(
+        &self,
+        mut reader: BufReader<File>,
+        mut loaded: LoadedLog,
+        mut offset: u64,
+        now: SystemTime,
+    ) -> io::Result<LoadedLog> {
+        let file_len = reader.get_ref().metadata()?.len();
+        while let Some(decoded) = self.read_tolerating_torn_tail(&mut reader, offset, file_len)? {
+            match decoded {
+                Decoded::Record(record) => {
+                    let len = record.record_len as u64;
+                    self.remember_loaded_value(&mut loaded, &record, offset);
+                    loaded.apply(record, offset, now);
+                    offset += len;
+                }
+                Decoded::Lossy(record) => {
+                    let len = record.record_len as u64;
+                    self.load_lossy(&mut loaded, record, offset, now)?;
+                    offset += len;
+                }
+                Decoded::Skipped(record_len) => {
+                    loaded.stale_bytes += record_len as u64;
+                    loaded.unknown_skipped += 1;
+                    offset += record_len as u64;
+                }
+                Decoded::Ref {
+                    key,
+                    target,
+                    expires_at,
+                    written_at,
+                    record_len,
+                } => {
+                    let entry = LoadedEntry {
+                        pointer: target,
+                        expires_at,
+                        written_at,
+                        blob: false,
+                    };
+                    loaded.bind(key, entry, Some(record_len), now);
+                    offset += record_len as u64;
+                }
+                Decoded::Batch(count) => {
+                    let mut records = Vec::new();
+                    let mut body_offset = offset + HEADER_SIZE as u64;
+                    for _ in 0..count {
+                        match self.read_tolerating_torn_tail(&mut reader, body_offset, file_len)? {
+                            Some(Decoded::Record(record)) => {
+                                let len = record.record_len as u64;
+                                records.push((record, body_offset, false));
+                                body_offset += len;
+                            }
+                            Some(Decoded::Lossy(record)) => {
+                                let len = record.record_len as u64;
+                                records.push((record, body_offset, true));
+                                body_offset += len;
+                            }
+                            Some(_) => {
+                                return Err(io::Error::new(
+                                    ErrorKind::InvalidData,
+                                    "unexpected record inside batch",
+                                ));
+                            }
+                            None => break,
+                        }
+                    }
+                    let buffered: u64 = records
+                        .iter()
+                        .map(|(record, _, _)| record.record_len as u64)
+                        .sum();
+                    loaded.note_peak(buffered);
+                    if records.len() as u64 != count {
+                        break;
+                    }
+                    loaded.stale_bytes += HEADER_SIZE as u64;
+                    for (record, record_offset, lossy) in records {
+                        if lossy {
+                            self.load_lossy(&mut loaded, record, record_offset, now)?;
+                            continue;
+                        }
+                        self.remember_loaded_value(&mut loaded, &record, record_offset);
+                        loaded.apply(record, record_offset, now);
+                    }
+                    offset = body_offset;
+                }
+            }
+            loaded.note_peak(0);
+        }
+
+        loaded.valid_len = offset;
+        loaded.retain_live_values();
+        loaded.index.shrink_to_fit();
+        loaded.ref_lens.shrink_to_fit();
+        loaded.shared_values.shrink_to_fit();
+        loaded.tokens.shrink_to_fit();
+        Ok(loaded)
+    }
+
+    /// Replays a put whose value is not valid UTF-8 as the [`Utf8Policy`] says:
+    /// as any other put under `Lossy`, as a missing key noted in
+    /// [`LoadedLog::invalid_utf8`] under `Skip`, and as an error otherwise.
+    fn load_lossy(
+        &self,
+        loaded: &mut LoadedLog,
+        record: WalRecord,
+        offset: u64,
+        now: SystemTime,
+    ) -> io::Result<()> {
+        match self.utf8_policy {
+            Utf8Policy::Lossy => {
+                self.remember_loaded_value(loaded, &record, offset);
+                loaded.apply(record, offset, now);
+            }
+            Utf8Policy::Skip => {
+                let key = record.entry.key().to_owned();
+                loaded.release(&key);
+                loaded.stale_bytes += record.record_len as u64;
+                loaded.invalid_utf8.insert(key, offset);
+            }
+            Utf8Policy::Error => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    InvalidUtf8 {
+                        key: record.entry.key().to_owned(),
+                        offset,
+                    },
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn remember_loaded_value(&self, loaded: &mut LoadedLog, record: &WalRecord, offset: u64) {
+        if self.value_dedup
+            && let WalEntry::Put { value, .. } = &record.entry
+        {
+            let pointer = ValuePointer::new(offset, record.value_len, record.record_len);
+            loaded.remember_value(value, pointer);
+        }
+    }
+
+    /// Writes out buffered records and syncs the log to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("writer poisoned"))?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.mark_synced(self.appended.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    /// Returns the log sequence number of the latest append.
+    ///
+    /// Sequence numbers count the bytes appended since the log was opened, so they
+    /// keep growing across compactions but restart when the log is reopened.
+    pub fn lsn(&self) -> u64 {
+        self.appended.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many fsyncs the log has issued since open, compactions
+    /// included.
+    pub fn sync_count(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many files the log has opened since open. Reads share one
+    /// handle, so this grows with replays and compactions, not with reads.
+    pub fn open_count(&self) -> u64 {
+        self.opens.load(Ordering::Relaxed)
+    }
+
+    /// Returns the log sequence number up to which appends have been synced to disk.
+    pub fn durable_lsn(&self) -> u64 {
+        *self.synced.lock()
+    }
+
+    /// Cuts the log back to `len` bytes, discarding a torn tail left by a crash.
+    pub fn truncate(&self, len: u64) -> io::Result<()> {
+        let writer = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("writer poisoned"))?;
+        writer.get_ref().set_len(len)?;
+        writer.get_ref().sync_all()?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Appends the log's bytes from `from` onwards to `dest` and returns the
+    /// log length they reach.
+    ///
+    /// Appends racing the copy may or may not be included; call again from the
+    /// returned length to pick them up. If the log is now shorter than `from`,
+    /// because a failed append was rolled back, `dest` is emptied and refilled
+    /// from the start.
+    pub fn copy_to(&self, dest: &mut File, from: u64) -> io::Result<u64> {
+        let (_, file) = self.read_handle()?;
+        let end = file.metadata()?.len();
+        let from = if end < from {
+            dest.set_len(0)?;
+            0
+        } else {
+            from
+        };
+        dest.seek(SeekFrom::Start(from))?;
+        let mut reader = ReadAt {
+            file: &file,
+            offset: from,
+        }
+        .take(end - from);
+        io::copy(&mut reader, dest)?;
+        Ok(end)
+    }
+
+    /// Opens `path`, which must hold a byte-for-byte copy of this log, with
+    /// the same options, to take over from it.
+    ///
+    /// Log sequence numbers carry on from this log, so durability markers and
+    /// read views taken before stay valid. The copy must be synced by the
+    /// caller: everything appended so far counts as durable in it.
+    pub fn reopen_at(&self, path: impl AsRef<Path>) -> io::Result<Wal> {
+        let wal = Wal::open(path, self.sync_interval, self.compression)?
+            .skip_unknown_ops(self.skip_unknown_ops)
+            .utf8_policy(self.utf8_policy)
+            .value_dedup(self.value_dedup)
+            .compaction_threads(self.compaction_threads)
+            .report_to(&self.background);
+        // The copy holds the same records at the same offsets.
+        let generation = self.generation();
+        wal.store_generation(generation)?;
+        wal.reader.write().0 = generation;
+        let appended = self.appended.load(Ordering::Relaxed);
+        wal.appended.store(appended, Ordering::Relaxed);
+        *wal.synced.lock() = appended;
+        wal.syncs
+            .fetch_add(self.syncs.load(Ordering::Relaxed), Ordering::Relaxed);
+        wal.opens
+            .fetch_add(self.opens.load(Ordering::Relaxed), Ordering::Relaxed);
+        wal.lossy_reads
+            .fetch_add(self.lossy_read_count(), Ordering::Relaxed);
+        Ok(wal)
+    }
+
+    /// Reads the record at `offset`, or `None` at the end of the log or when
+    /// the record is a torn tail.
+    ///
+    /// A crash only cuts short the last append, so a record that fails to
+    /// read is torn when its header is cut off, or when the lengths in it
+    /// reach past `file_len` and no complete records follow it. Complete
+    /// records after it mean its length was damaged in place, and the open
+    /// fails rather than cutting them away with it.
+    fn read_tolerating_torn_tail(
+        &self,
+        reader: &mut BufReader<File>,
+        offset: u64,
+        file_len: u64,
+    ) -> io::Result<Option<Decoded>> {
+        match Self::read_record_internal(reader, self.compression, self.skip_unknown_ops) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {}
+            other => return other,
+        }
+        let file = reader.get_mut();
+        let Some((_, body_len)) = Self::read_header_at(file, offset, file_len)? else {
+            return Ok(None);
+        };
+        if offset + HEADER_SIZE as u64 + body_len > file_len
+            && !self.records_resume_after(file, offset, file_len)?
+        {
+            return Ok(None);
+        }
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{}: the record at offset {offset} is damaged and complete records follow it",
+                self.path.display()
+            ),
+        ))
+    }
+
+    /// The opcode and body length of the header at `offset`; `None` when the
+    /// file ends inside it.
+    fn read_header_at(
+        file: &mut File,
+        offset: u64,
+        file_len: u64,
+    ) -> io::Result<Option<(u8, u64)>> {
+        if offset + HEADER_SIZE as u64 > file_len {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let key_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+        let value_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]);
+        Ok(Some((header[0], key_len as u64 + value_len as u64)))
+    }
+
+    /// Whether some position after the record at `offset` starts a run of
+    /// complete records that ends exactly at `file_len`.
+    ///
+    /// Bytes of a torn value can look like a header by chance, but a run of
+    /// records that decodes cleanly and ends where the file does is what a
+    /// damaged length leaves behind. The bytes are scanned once for headers
+    /// that fit in the file, and only those are decoded further.
+    fn records_resume_after(
+        &self,
+        file: &mut File,
+        offset: u64,
+        file_len: u64,
+    ) -> io::Result<bool> {
+        let mut candidates = Vec::new();
+        file.seek(SeekFrom::Start(offset + 1))?;
+        let mut reader = BufReader::new(&*file);
+        // `window` starts at file position `base`.
+        let (mut window, mut base) = (Vec::new(), offset + 1);
+        loop {
+            let chunk = reader.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            window.extend_from_slice(chunk);
+            let read = chunk.len();
+            reader.consume(read);
+            let headers = window.len().saturating_sub(HEADER_SIZE - 1);
+            for (i, header) in window.windows(HEADER_SIZE).enumerate() {
+                let key_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+                let value_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]);
+                let end =
+                    base + i as u64 + (HEADER_SIZE as u64 + key_len as u64 + value_len as u64);
+                if WalOp::from_byte(header[0]).is_some() && header[9] <= 1 && end <= file_len {
+                    candidates.push(base + i as u64);
+                }
+            }
+            window.drain(..headers);
+            base += headers as u64;
+        }
+        for start in candidates {
+            if self.runs_to_end(file, start, file_len)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether the records from `start` decode one after another up to
+    /// exactly `file_len`.
+    fn runs_to_end(&self, file: &mut File, start: u64, file_len: u64) -> io::Result<bool> {
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(&*file);
+        let mut position = start;
+        loop {
+            let len = match Self::read_record_internal(&mut reader, self.compression, false) {
+                Ok(None) => return Ok(position == file_len && position > start),
+                Ok(Some(Decoded::Record(record) | Decoded::Lossy(record))) => record.record_len,
+                Ok(Some(Decoded::Ref { record_len, .. } | Decoded::Skipped(record_len))) => {
+                    record_len
+                }
+                Ok(Some(Decoded::Batch(_))) => HEADER_SIZE as u32,
+                Err(_) => return Ok(false),
+            };
+            position += len as u64;
+        }
+    }
+
+    /// Rewrites the log so it holds exactly the provided puts and blob
+    /// references and returns the rebuilt index. Delete entries are not accepted.
+    /// Blob files are left in place.
+    pub fn rewrite(&self, entries: &[WalEntry]) -> io::Result<LoadedLog> {
+        let generation = self.write_generation(entries)?;
+        self.install(generation, &[])
+    }
+
+    /// Writes the provided puts and blob references to a new generation of the
+    /// log next to the active one, which keeps accepting appends meanwhile.
+    ///
+    /// Only one generation may be in progress at a time; nothing takes its
+    /// place until [`Wal::install`] is called.
+    pub fn write_generation(&self, entries: &[WalEntry]) -> io::Result<Generation> {
+        let mut generation = GenerationWriter::start(self)?;
+        // Records are encoded ahead on the workers one window at a time and
+        // written in order, so offsets and dedup choices match a single thread.
+        let threads = self.compaction_threads;
+        let window = if threads > 1 {
+            threads * compaction::WINDOW_PER_THREAD
+        } else {
+            entries.len().max(1)
+        };
+        for window in entries.chunks(window) {
+            let mut ahead = if threads > 1 {
+                compaction::map_ordered(window, threads, |entry| Some(self.encode_entry(entry)))
+            } else {
+                Vec::new()
+            }
+            .into_iter();
+            for entry in window {
+                generation.write(self, entry, ahead.next().flatten())?;
+            }
+        }
+        generation.finish(self)
+    }
+
+    /// Copies the records of `live` keys from the active log into a new
+    /// generation, followed by `extra` entries, like [`Wal::write_generation`].
+    ///
+    /// Values are read and re-encoded a window at a time, each window holding
+    /// at most `buffer_bytes` of stored values (or a single larger one), so
+    /// memory does not grow with the size of the store. A record that no longer
+    /// decodes is left out and reported through [`Generation::take_damaged`]
+    /// instead of failing the run.
+    pub fn copy_generation(
+        &self,
+        live: &[LiveRecord],
+        extra: &[WalEntry],
+        buffer_bytes: usize,
+    ) -> io::Result<Generation> {
+        let (installed, source) = self.read_handle()?;
+        let mut generation = GenerationWriter::start(self)?;
+        let threads = self.compaction_threads;
+        let max_records = threads * compaction::WINDOW_PER_THREAD;
+        let mut rest = live;
+        while !rest.is_empty() {
+            let mut len = 0;
+            let mut bytes = 0usize;
+            for record in rest.iter().take(max_records) {
+                let value_len = record.pointer.value_len as usize;
+                if len > 0 && bytes + value_len > buffer_bytes {
+                    break;
+                }
+                bytes += value_len;
+                len += 1;
+            }
+            let (window, next) = rest.split_at(len);
+            rest = next;
+
+            // Decompression dominates here, so records are read on the workers.
+            let decoded = compaction::map_ordered(window, threads, |record| {
+                let stored = self.settle_utf8(Self::read_record_from(
+                    &source,
+                    record.pointer.offset,
+                    self.compression,
+                    self.skip_unknown_ops,
+                ))?;
+                let Some(entry) = record.rekey(stored.entry) else {
+                    return Ok(None);
+                };
+                let encoded = (threads > 1).then(|| self.encode_entry(&entry));
+                Ok(Some((entry, encoded)))
+            });
+            let held: u64 = decoded
+                .iter()
+                .filter_map(|result: &io::Result<_>| result.as_ref().ok()?.as_ref())
+                .map(|(entry, _): &(WalEntry, _)| entry.value_len() as u64)
+                .sum();
+            generation.peak_buffer_bytes = generation.peak_buffer_bytes.max(held);
+            for (record, result) in window.iter().zip(decoded) {
+                match result {
+                    Ok(Some((entry, encoded))) => generation.write(self, &entry, encoded)?,
+                    Ok(None) => {}
+                    // One undecodable record must not keep the rest of the log
+                    // from being rewritten; its key is set aside instead.
+                    Err(err) if is_corruption(&err) => generation.damaged.push(DamagedRecord {
+                        key: record.key.clone(),
+                        offset: record.pointer.offset,
+                        error: err.to_string(),
+                    }),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        for entry in extra {
+            generation.write(self, entry, None)?;
+        }
+        // Records were read from the generation the pointers were taken from.
+        if self.generation() != installed {
+            return Err(io::Error::other(RetryableCompaction {
+                expected: installed,
+                installed: self.generation(),
+            }));
+        }
+        generation.finish(self)
+    }
+
+    /// Appends `tail` to a written generation and swaps it in for the active
+    /// log, returning the index of the combined file.
+    ///
+    /// `tail` carries the writes the active log accepted while the generation
+    /// was being written, in the order they happened; deletes are allowed here.
+    /// Appends are held off from the moment the tail is written until the swap.
+    pub fn install(&self, generation: Generation, tail: &[WalEntry]) -> io::Result<LoadedLog> {
+        let Generation {
+            mut writer,
+            mut loaded,
+            ..
+        } = generation;
+        let temp_path = self.path.with_extension("compact");
+        let backup_path = self.path.with_extension("backup");
+        let mut active = self
+            .writer
+            .lock()
+            .map_err(|_| io::Error::other("writer poisoned"))?;
+        active.flush()?;
+
+        let mut offset = loaded.valid_len;
+        for entry in tail {
+            let encoded = self.encode_entry(entry)?;
+            writer.write_all(&encoded)?;
+            let record = WalRecord {
+                entry: entry.clone(),
+                offset,
+                record_len: encoded.len() as u32,
+                value_len: entry.value_len(),
+            };
+            if matches!(entry, WalEntry::Delete { .. }) {
+                loaded.stale_bytes += encoded.len() as u64;
+            }
+            // Tail entries were live when written out; expiry is left to reads.
+            loaded.apply(record, offset, UNIX_EPOCH);
+            offset += encoded.len() as u64;
+        }
+        loaded.valid_len = offset;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+
+        // New reads go to the compacted file from here on, without ever looking
+        // the path up, so none of them can land between the two renames.
+        let previous = self.swap_reader(writer.get_ref())?;
+        // Hand the writer over to the compacted file before renaming: Windows
+        // refuses to replace or delete a file while our own handle holds it open.
+        // The handle follows the file through the rename below.
+        *active = writer;
+
+        if let Err(err) = self.put_in_place(&temp_path, &backup_path) {
+            // Appends and reads must keep going to the log that is still in
+            // place; the compacted file is left to be removed.
+            *active = BufWriter::new(Self::open_existing(&self.path)?);
+            self.opens.fetch_add(1, Ordering::Relaxed);
+            self.restore_reader(previous)?;
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+
+        // Every live record now sits in the synced compacted file.
+        self.mark_synced(self.appended.load(Ordering::Relaxed));
+        Ok(loaded)
+    }
+
+    /// Renames the compacted file at `temp_path` over the log, by way of
+    /// `backup_path`.
+    ///
+    /// On error the old log is back at its path, unless putting it back failed
+    /// too. Once the compacted file is in place a leftover backup is only
+    /// untidy, so failing to remove it does not fail the install.
+    fn put_in_place(&self, temp_path: &Path, backup_path: &Path) -> io::Result<()> {
+        if !self.path.exists() {
+            return fs::rename(temp_path, &self.path).map_err(|err| with_path(err, temp_path));
+        }
+        if backup_path.exists() {
+            fs::remove_file(backup_path).map_err(|err| with_path(err, backup_path))?;
+        }
+        fs::rename(&self.path, backup_path).map_err(|err| with_path(err, &self.path))?;
+        if let Err(err) = fs::rename(temp_path, &self.path) {
+            fs::rename(backup_path, &self.path).map_err(|err| with_path(err, backup_path))?;
+            return Err(with_path(err, temp_path));
+        }
+        let _ = fs::remove_file(backup_path);
+        Ok(())
+    }
+
+    /// Points new reads at `file` as the next generation, and returns the
+    /// generation it replaced.
+    fn swap_reader(&self, file: &File) -> io::Result<(u64, Arc<File>)> {
+        let file = Arc::new(file.try_clone()?);
+        let mut reader = self.reader.write();
+        let next = reader.0 + 1;
+        // Saved first, so a crash can only make the count skip ahead.
+        self.store_generation(next)?;
+        Ok(std::mem::replace(&mut *reader, (next, file)))
+    }
+
+    /// Undoes [`Wal::swap_reader`] after a failed install: the old log is
+    /// still in place, so the index built for its generation holds again.
+    fn restore_reader(&self, previous: (u64, Arc<File>)) -> io::Result<()> {
+        let generation = previous.0;
+        *self.reader.write() = previous;
+        // Saved after, so a crash in between leaves the count ahead.
+        self.store_generation(generation)
+    }
+
+    /// Like [`Wal::open_append`], failing rather than creating an empty log
+    /// when `path` is gone.
+    fn open_existing(path: &Path) -> io::Result<File> {
+        Self::shared(OpenOptions::new().read(true).append(true))
+            .open(path)
+            .map_err(|err| with_path(err, path))
+    }
+
+    fn open_append(path: &Path) -> io::Result<File> {
+        Self::shared(OpenOptions::new().create(true).read(true).append(true))
+            .open(path)
+            .map_err(|err| with_path(err, path))
+    }
+
+    /// Lets other handles read, write, rename and delete the file while it is open.
+    ///
+    /// This is the platform default everywhere except Windows, where compaction
+    /// could otherwise not rename the log underneath a concurrent reader.
+    fn shared(options: &mut OpenOptions) -> &mut OpenOptions {
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_SHARE_READ_WRITE_DELETE: u32 = 0x1 | 0x2 | 0x4;
+            options.share_mode(FILE_SHARE_READ_WRITE_DELETE);
+        }
+        options
+    }
+
+    fn read_record_from(
+        file: &File,
+        offset: u64,
+        compression: bool,
+        skip_unknown_ops: bool,
+    ) -> io::Result<WalRecord> {
+        let mut reader = ReadAt { file, offset };
+        Self::read_data_record(&mut reader, offset, compression, skip_unknown_ops)
+    }
+
+    /// Decodes the record `reader` is positioned at, which must hold data
+    /// rather than a batch header or a reference.
+    fn read_data_record<R: Read>(
+        reader: &mut R,
+        offset: u64,
+        compression: bool,
+        skip_unknown_ops: bool,
+    ) -> io::Result<WalRecord> {
+        match Self::read_record_internal(reader, compression, skip_unknown_ops)? {
+            Some(Decoded::Record(mut record)) => {
+                record.offset = offset;
+                Ok(record)
+            }
+            Some(Decoded::Lossy(record)) => LossyValue::at(record, offset),
+            Some(Decoded::Skipped(_) | Decoded::Batch(_) | Decoded::Ref { .. }) => {
+                Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "pointer does not refer to a data record",
+                ))
+            }
+            None => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "missing record at offset",
+            )),
+        }
+    }
+
+    fn read_record_internal<R: Read>(
+        reader: &mut R,
+        compression: bool,
+        skip_unknown_ops: bool,
+    ) -> io::Result<Option<Decoded>> {
+        let mut op_buf = [0u8; 1];
+        let read = reader.read(&mut op_buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf)?;
+        let key_len = u32::from_le_bytes(len_buf) as usize;
+        reader.read_exact(&mut len_buf)?;
+        let value_len = u32::from_le_bytes(len_buf) as usize;
+
+        let mut ttl_flag = [0u8; 1];
+        reader.read_exact(&mut ttl_flag)?;
+        let mut ttl_buf = [0u8; 8];
+        reader.read_exact(&mut ttl_buf)?;
+        let ttl_secs = u64::from_le_bytes(ttl_buf);
+
+        let op = match WalOp::from_byte(op_buf[0]) {
+            Some(WalOp::Batch) => return Ok(Some(Decoded::Batch(ttl_secs))),
+            Some(op) => op,
+            None if skip_unknown_ops => {
+                let body = (key_len + value_len) as u64;
+                if io::copy(&mut reader.take(body), &mut io::sink())? != body {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "truncated record with unknown opcode",
+                    ));
+                }
+                return Ok(Some(Decoded::Skipped((HEADER_SIZE as u64 + body) as u32)));
+            }
+            None => {
+                return Err(io::Error::new(ErrorKind::InvalidData, "unknown WAL opcode"));
+            }
+        };
+
+        let mut key_buf = vec![0u8; key_len];
+        reader.read_exact(&mut key_buf)?;
+        let key = String::from_utf8(key_buf)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid utf-8 key"))?;
+        let mut value = String::new();
+        let mut lossy = false;
+        let record_len = (HEADER_SIZE + key_len + value_len) as u32;
+        let expires_at = if ttl_flag[0] == 1 {
+            Some(
+                UNIX_EPOCH
+                    .checked_add(Duration::from_secs(ttl_secs))
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "ttl overflow"))?,
+            )
+        } else {
+            None
+        };
+
+        if matches!(op, WalOp::Ref) {
+            if value_len != REF_PAYLOAD_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "reference record has malformed payload",
+                ));
+            }
+            let mut offset_buf = [0u8; 8];
+            reader.read_exact(&mut offset_buf)?;
+            reader.read_exact(&mut len_buf)?;
+            let target_value_len = u32::from_le_bytes(len_buf);
+            reader.read_exact(&mut len_buf)?;
+            let target = ValuePointer::new(
+                u64::from_le_bytes(offset_buf),
+                target_value_len,
+                u32::from_le_bytes(len_buf),
+            );
+            let mut stamp = [0u8; STAMP_SIZE];
+            reader.read_exact(&mut stamp)?;
+            return Ok(Some(Decoded::Ref {
+                key,
+                target,
+                expires_at,
+                written_at: Self::decode_stamp(stamp),
+                record_len,
+            }));
+        }
+
+        if matches!(op, WalOp::Blob) {
+            if value_len < STAMP_SIZE + 8 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "blob record has malformed payload",
+                ));
+            }
+            let mut stamp = [0u8; STAMP_SIZE];
+            reader.read_exact(&mut stamp)?;
+            let mut blob_len = [0u8; 8];
+            reader.read_exact(&mut blob_len)?;
+            let mut name = vec![0u8; value_len - STAMP_SIZE - 8];
+            reader.read_exact(&mut name)?;
+            let blob = BlobRef {
+                name: String::from_utf8(name).map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, "invalid utf-8 blob name")
+                })?,
+                len: u64::from_le_bytes(blob_len),
+            };
+            let entry = WalEntry::Blob {
+                key,
+                blob,
+                expires_at,
+                written_at: Self::decode_stamp(stamp),
+            };
+            return Ok(Some(Decoded::Record(WalRecord {
+                value_len: entry.value_len(),
+                entry,
+                offset: 0,
+                record_len,
+            })));
+        }
+
+        let mut written_at = None;
+        let mut stored_len = value_len;
+        if matches!(op, WalOp::PutAt | WalOp::Bytes) {
+            if value_len < STAMP_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "timestamped record is missing its write time",
+                ));
+            }
+            let mut stamp = [0u8; STAMP_SIZE];
+            reader.read_exact(&mut stamp)?;
+            written_at = Self::decode_stamp(stamp);
+            stored_len -= STAMP_SIZE;
+        }
+
+        let mut bytes = Vec::new();
+        if matches!(op, WalOp::Put | WalOp::PutAt | WalOp::Token | WalOp::Bytes) {
+            let mut value_buf = vec![0u8; stored_len];
+            reader.read_exact(&mut value_buf)?;
+
+            let decompressed = if compression && !value_buf.is_empty() {
+                snap::raw::Decoder::new()
+                    .decompress_vec(&value_buf)
+                    .map_err(io::Error::other)?
+            } else {
+                value_buf
+            };
+
+            if matches!(op, WalOp::Bytes) {
+                bytes = decompressed;
+            } else {
+                value = match String::from_utf8(decompressed) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        lossy = true;
+                        String::from_utf8_lossy(err.as_bytes()).into_owned()
+                    }
+                };
+            }
+        } else if value_len != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "delete record has unexpected payload",
+            ));
+        }
+
+        let entry = match op {
+            WalOp::Put | WalOp::PutAt => WalEntry::Put {
+                key,
+                value,
+                expires_at,
+                written_at,
+            },
+            WalOp::Bytes => WalEntry::Bytes {
+                key,
+                value: bytes,
+                expires_at,
+                written_at,
+            },
+            WalOp::Delete => WalEntry::Delete { key },
+            WalOp::Token => WalEntry::Token {
+                token: key,
+                outcome: value,
+                expires_at: expires_at.ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "token record has no window")
+                })?,
+            },
+            WalOp::Batch | WalOp::Ref | WalOp::Blob => {
+                unreachable!("batch headers, references and blobs return before decoding a value")
+            }
+        };
+
+        let record = WalRecord {
+            entry,
+            offset: 0,
+            record_len,
+            value_len: stored_len as u32,
+        };
+        Ok(Some(if lossy {
+            Decoded::Lossy(record)
+        } else {
+            Decoded::Record(record)
+        }))
+    }
+
+    fn encode_stamp(written_at: SystemTime) -> [u8; STAMP_SIZE] {
+        let nanos = written_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        nanos.to_le_bytes()
+    }
+
+    /// Zero marks a write time that was not recorded.
+    fn decode_stamp(stamp: [u8; STAMP_SIZE]) -> Option<SystemTime> {
+        match u64::from_le_bytes(stamp) {
+            0 => None,
+            nanos => UNIX_EPOCH.checked_add(Duration::from_nanos(nanos)),
+        }
+    }
+
+    fn encode_batch_header(count: u64) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.push(WalOp::Batch as u8);
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&count.to_le_bytes());
+        buf
+    }
+
+    fn encode_ref(
+        key: &str,
+        target: ValuePointer,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+    ) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + key.len() + REF_PAYLOAD_SIZE);
+        buf.push(WalOp::Ref as u8);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(REF_PAYLOAD_SIZE as u32).to_le_bytes());
+        Self::encode_ttl(&mut buf, expires_at);
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(&target.offset.to_le_bytes());
+        buf.extend_from_slice(&target.value_len.to_le_bytes());
+        buf.extend_from_slice(&target.record_len.to_le_bytes());
+        buf.extend_from_slice(&written_at.map_or([0; STAMP_SIZE], Self::encode_stamp));
+        buf
+    }
+
+    fn encode_blob(
+        key: &str,
+        blob: &BlobRef,
+        expires_at: Option<SystemTime>,
+        written_at: Option<SystemTime>,
+    ) -> Vec<u8> {
+        let payload_len = STAMP_SIZE + 8 + blob.name.len();
+        let mut buf = Vec::with_capacity(HEADER_SIZE + key.len() + payload_len);
+        buf.push(WalOp::Blob as u8);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(payload_len as u32).to_le_bytes());
+        Self::encode_ttl(&mut buf, expires_at);
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(&written_at.map_or([0; STAMP_SIZE], Self::encode_stamp));
+        buf.extend_from_slice(&blob.len.to_le_bytes());
+        buf.extend_from_slice(blob.name.as_bytes());
+        buf
+    }
+
+    fn encode_ttl(buf: &mut Vec<u8>, expires_at: Option<SystemTime>) {
+        let mut flag = 0u8;
+        let mut ttl = 0u64;
+        if let Some(expires_at) = expires_at {
+            flag = 1;
+            // An expiry before the epoch is stored as the epoch: still expired,
+            // rather than dropping the flag and making the key permanent.
+            ttl = expires_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+        }
+        buf.push(flag);
+        buf.extend_from_slice(&ttl.to_le_bytes());
+    }
+
+    fn encode_entry(&self, entry: &WalEntry) -> io::Result<Vec<u8>> {
+        if let WalEntry::Blob {
+            key,
+            blob,
+            expires_at,
+            written_at,
+        } = entry
+        {
+            return Ok(Self::encode_blob(key, blob, *expires_at, *written_at));
+        }
+        let key = entry.key_bytes();
+        let value = entry.value_bytes();
+
+        let compressed;
+        let final_value = if self.compression && !value.is_empty() {
+            compressed = snap::raw::Encoder::new()
+                .compress_vec(value)
+                .map_err(io::Error::other)?;
+            &compressed[..]
+        } else {
+            value
+        };
+
+        let stamp = match entry {
+            WalEntry::Bytes { written_at, .. } => {
+                Some(written_at.map_or([0; STAMP_SIZE], Self::encode_stamp))
+            }
+            _ => entry.written_at().map(Self::encode_stamp),
+        };
+        let stamp = stamp.as_ref().map_or(&[][..], |stamp| &stamp[..]);
+        let payload_len = stamp.len() + final_value.len();
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + key.len() + payload_len);
+        buf.push(match entry {
+            WalEntry::Put { .. } if stamp.is_empty() => WalOp::Put as u8,
+            WalEntry::Put { .. } => WalOp::PutAt as u8,
+            WalEntry::Bytes { .. } => WalOp::Bytes as u8,
+            WalEntry::Delete { .. } => WalOp::Delete as u8,
+            WalEntry::Token { .. } => WalOp::Token as u8,
+            WalEntry::Blob { .. } => unreachable!("blob records are encoded separately"),
+        });
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(payload_len as u32).to_le_bytes());
+        Self::encode_ttl(&mut buf, entry.expires_at());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(stamp);
+        buf.extend_from_slice(final_value);
+        Ok(buf)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        // Records still buffered under a sync interval are synced on a clean shutdown.
+        if self.appended.load(Ordering::Relaxed) > self.durable_lsn() {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// Hash identifying a value for dedup; equal hashes still need a value comparison.
+pub(crate) fn value_hash(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Reads a shared handle at explicit offsets, so concurrent readers never
+/// depend on where another left the cursor.
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.file, buf, self.offset)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ReadAt<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "cannot seek from the end of a shared handle",
+                ));
+            }
+        };
+        self.offset = offset.ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "seek before the start of the log")
+        })?;
+        Ok(self.offset)
+    }
+}
+
+/// Walks one log file in offset order through a read-ahead buffer; shared by
+/// compaction's batched reads, the change feed and the scrubber.
+///
+/// Moving forward stays inside the buffer where it can, and only moving back
+/// starts a new one.
+struct SequentialReader<'a> {
+    file: &'a File,
+    reader: BufReader<ReadAt<'a>>,
+    /// Offset of the next byte [`Read::read`] returns.
+    position: u64,
+}
+
+impl<'a> SequentialReader<'a> {
+    fn new(file: &'a File, offset: u64) -> Self {
+        Self {
+            file,
+            reader: BufReader::with_capacity(SEQUENTIAL_READ_BUFFER, ReadAt { file, offset }),
+            position: offset,
+        }
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves to `offset`, keeping the buffer when it lies ahead.
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        match offset.checked_sub(self.position) {
+            Some(gap) => self.reader.seek_relative(gap as i64)?,
+            None => *self = Self::new(self.file, offset),
+        }
+        self.position = offset;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.seek_to(self.position + len)
+    }
+}
+
+impl Read for SequentialReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// Prefixes an I/O error with the path it concerns, keeping its kind.
+fn with_path(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+}
@@ -0,0 +1,115 @@
+//! Front coding for key lists that share long prefixes.
+//!
+//! Each key is stored as the number of leading bytes it shares with the key
+//! before it plus the remaining suffix, so sorted hierarchical keys such as
+//! `tenant/0000123/user/...` cost little more than their distinct tails.
+//!
+//! Layout: a [`FORMAT_VERSION`] byte and a little-endian `u64` key count,
+//! then per key the shared length and the suffix length as LEB128 varints
+//! followed by the suffix bytes.
+
+use std::io;
+
+/// Version byte written at the start of every encoded key list.
+pub const FORMAT_VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = 1 + 8;
+
+/// Encodes `keys` in the order given; sorting them first maximises sharing.
+pub fn encode<'a>(keys: impl IntoIterator<Item = &'a str>) -> Vec<u8> {
+    let mut buf = vec![FORMAT_VERSION];
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    let mut count = 0u64;
+    let mut previous = "";
+    for key in keys {
+        let shared = shared_prefix_len(previous, key);
+        let suffix = &key.as_bytes()[shared..];
+        write_varint(&mut buf, shared);
+        write_varint(&mut buf, suffix.len());
+        buf.extend_from_slice(suffix);
+        previous = key;
+        count += 1;
+    }
+    buf[1..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+    buf
+}
+
+/// Decodes a key list written by [`encode`], in its original order.
+pub fn decode(bytes: &[u8]) -> io::Result<Vec<String>> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let (header, mut rest) = bytes
+        .split_at_checked(HEADER_SIZE)
+        .ok_or_else(|| invalid("key list header truncated"))?;
+    if header[0] != FORMAT_VERSION {
+        return Err(invalid(&format!(
+            "unsupported key list format version {}",
+            header[0]
+        )));
+    }
+    let count = u64::from_le_bytes(header[1..].try_into().expect("8-byte count"));
+
+    let mut keys: Vec<String> = Vec::new();
+    for _ in 0..count {
+        let shared = read_varint(&mut rest).ok_or_else(|| invalid("key list entry truncated"))?;
+        let suffix_len =
+            read_varint(&mut rest).ok_or_else(|| invalid("key list entry truncated"))?;
+        let (suffix, tail) = rest
+            .split_at_checked(suffix_len)
+            .ok_or_else(|| invalid("key list entry truncated"))?;
+        let previous = keys.last().map_or("", String::as_str);
+        if !previous.is_char_boundary(shared) {
+            return Err(invalid("shared prefix longer than the previous key"));
+        }
+        let suffix = std::str::from_utf8(suffix).map_err(|_| invalid("key is not UTF-8"))?;
+        let mut key = String::with_capacity(shared + suffix.len());
+        key.push_str(&previous[..shared]);
+        key.push_str(suffix);
+        keys.push(key);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(invalid("trailing bytes after key list"));
+    }
+    Ok(keys)
+}
+
+/// Bytes `key` shares with `previous`, cut back to a character boundary so
+/// both the prefix and the suffix stay valid UTF-8.
+fn shared_prefix_len(previous: &str, key: &str) -> usize {
+    let mut shared = previous
+        .bytes()
+        .zip(key.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !key.is_char_boundary(shared) {
+        shared -= 1;
+    }
+    shared
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads a varint from the front of `bytes`, or `None` if it is cut short or
+/// does not fit a `usize`.
+fn read_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let shift = 7 * i as u32;
+        let bits = ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if bits >> shift != (byte & 0x7f) as usize {
+            return None;
+        }
+        value |= bits;
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,123 @@
+//! How keys are compared: byte for byte, or after Unicode normalization.
+//!
+//! The policy is fixed for the life of a store. The manifest records it when
+//! the store is created, and an open asking for another policy is refused,
+//! since keys written under one would not be found under the other.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// Chosen with [`CrabKvBuilder::key_policy`](crate::CrabKvBuilder::key_policy).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum KeyPolicy {
+    /// Keys are compared byte for byte: `café` in NFC and in NFD are two keys.
+    #[default]
+    Raw,
+    /// Every key a call names is normalized to Unicode NFC first, so both
+    /// spellings of `café` reach the same entry, and keys are listed in NFC.
+    /// Needs the `unicode-keys` feature; without it the engine refuses to
+    /// open with this policy.
+    Nfc,
+}
+
+impl KeyPolicy {
+    /// [`KeyPolicy::Nfc`] when `enabled`, otherwise [`KeyPolicy::Raw`].
+    #[cfg(feature = "unicode-keys")]
+    pub fn normalize_nfc(enabled: bool) -> Self {
+        if enabled {
+            KeyPolicy::Nfc
+        } else {
+            KeyPolicy::Raw
+        }
+    }
+
+    /// The key as the engine stores and looks it up.
+    pub(crate) fn apply<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        match self {
+            KeyPolicy::Raw => Cow::Borrowed(key),
+            #[cfg(feature = "unicode-keys")]
+            KeyPolicy::Nfc => {
+                use unicode_normalization::{IsNormalized, UnicodeNormalization, is_nfc_quick};
+                match is_nfc_quick(key.chars()) {
+                    IsNormalized::Yes => Cow::Borrowed(key),
+                    _ => Cow::Owned(key.nfc().collect()),
+                }
+            }
+            // The builder refuses the policy without the feature.
+            #[cfg(not(feature = "unicode-keys"))]
+            KeyPolicy::Nfc => Cow::Borrowed(key),
+        }
+    }
+
+    /// Like [`KeyPolicy::apply`], keeping `key` when it is already in form.
+    pub(crate) fn apply_owned(&self, key: String) -> String {
+        match self.apply(&key) {
+            Cow::Borrowed(_) => key,
+            Cow::Owned(normalized) => normalized,
+        }
+    }
+
+    /// Fails with `Unsupported` when this build cannot apply the policy.
+    pub(crate) fn check_supported(&self) -> io::Result<()> {
+        if *self == KeyPolicy::Nfc && !cfg!(feature = "unicode-keys") {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the nfc key policy needs CrabKv built with the `unicode-keys` feature",
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "raw" => Some(KeyPolicy::Raw),
+            "nfc" => Some(KeyPolicy::Nfc),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyPolicy::Raw => "raw",
+            KeyPolicy::Nfc => "nfc",
+        })
+    }
+}
+
+/// Error payload for an open asking for another [`KeyPolicy`] than the one
+/// the store was created with. Carried inside an `InvalidInput` error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyPolicyMismatch {
+    /// The policy the store's manifest records.
+    pub store: KeyPolicy,
+    /// The policy the open asked for.
+    pub requested: KeyPolicy,
+}
+
+impl KeyPolicyMismatch {
+    /// Returns the mismatch carried by `err`, if any.
+    pub fn of(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for KeyPolicyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the store was created with the {} key policy, not {}",
+            self.store, self.requested
+        )
+    }
+}
+
+impl Error for KeyPolicyMismatch {}
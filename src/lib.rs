@@ -1,12 +1,151 @@
 //! CrabKv storage engine library.
+//!
+//! The stable surface is [`CrabKv`], [`CrabKvBuilder`], the types their
+//! methods take and return (re-exported here), the [`server`] and [`status`]
+//! entry points, `client` when built with the `client` feature, and
+//! [`WalFile`] for inspecting a log offline. [`internals`] carries no
+//! compatibility promise.
 
-pub mod cache;
-pub mod compaction;
+pub mod background;
+pub mod changes;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod clock;
 pub mod config;
+pub mod context;
+pub mod diagnostics;
 pub mod engine;
-pub mod index;
+pub mod events;
+pub mod expiry;
+pub mod handoff;
+pub mod idempotency;
+#[doc(hidden)]
+pub mod internals;
+pub mod keycoding;
+pub mod keys;
+pub mod lock_file;
+pub mod manifest;
+pub mod mode;
+pub mod modifications;
+pub mod pressure;
+pub mod quarantine;
+pub mod quota;
+pub mod range;
+pub mod scrub;
 pub mod server;
-pub mod wal;
+pub mod stats;
+pub mod status;
+pub mod threshold;
+pub mod transform;
+pub mod utf8;
+pub mod version;
+pub mod view;
+pub mod wal_file;
 
-pub use engine::CrabKv;
-pub use engine::CrabKvBuilder;
+pub use background::{BackgroundError, BackgroundWorker};
+pub use changes::{Change, ChangeBatch, ChangeKind, HistoryTruncated, WatchStream};
+pub use clock::{Clock, ManualClock};
+pub use config::{CompactionPolicy, ConfigChange, EngineConfig, ServerConfig};
+pub use context::{OpContext, OpScope};
+pub use engine::{
+    ChangeMarker, CheckpointStatus, CompactionOutcome, CompactionStatus, CopyPlan, CopyReport,
+    CrabKv, CrabKvBuilder, DurabilityMarker, EpochPin, KeySample, OpenReport, ValidationIssue,
+    ValidationProblem, WriteBatch,
+};
+pub use events::{EngineEvent, EventKind};
+pub use expiry::ExpiryCounters;
+pub use idempotency::{WriteOptions, WriteOutcome};
+pub use internals::wal::RetryableCompaction;
+pub use keys::{KeyPolicy, KeyPolicyMismatch};
+pub use manifest::{EpochMismatch, StoreIdMismatch};
+pub use mode::{Activity, EngineMode, ModeRefused};
+pub use modifications::Modification;
+pub use pressure::{PressureGauges, PressureLevel, PressureLimits, PressureThresholds};
+pub use quarantine::{Corrupted, QuarantinedKey};
+pub use quota::{PrefixUsage, QuotaExceeded};
+pub use range::KeyRange;
+pub use scrub::{ScrubFinding, ScrubReport};
+pub use stats::{EngineStats, SizeHistogram, TtlHistogram};
+pub use threshold::{ThresholdEvent, ThresholdListener};
+pub use transform::TransformError;
+pub use utf8::{BinaryValue, InvalidUtf8, Utf8Policy};
+pub use version::{BuildInfo, build_info};
+pub use view::{ReadView, ViewInvalidated};
+pub use wal_file::{WalFile, WalVerification};
+
+#[deprecated(note = "internal to the engine; use `crabkv::internals::blob` meanwhile")]
+#[doc(hidden)]
+pub mod blob {
+    #[deprecated(note = "internal to the engine")]
+    pub type BlobRef = crate::internals::blob::BlobRef;
+    #[deprecated(note = "internal to the engine")]
+    pub type BlobStore = crate::internals::blob::BlobStore;
+}
+
+#[deprecated(note = "internal to the engine; configure it through `CrabKvBuilder`")]
+#[doc(hidden)]
+pub mod cache {
+    #[deprecated(note = "internal to the engine")]
+    pub type Cache = crate::internals::cache::Cache;
+    #[deprecated(note = "internal to the engine")]
+    pub type CacheEntry = crate::internals::cache::CacheEntry;
+    #[deprecated(note = "use `CrabKvBuilder::cache_eviction_listener`")]
+    pub type EvictionListener = crate::internals::cache::EvictionListener;
+}
+
+#[deprecated(note = "internal to the engine; see `CrabKv::compaction_status`")]
+#[doc(hidden)]
+pub mod compaction {
+    #[deprecated(note = "internal to the engine")]
+    pub fn should_compact(total_bytes: u64, stale_bytes: u64) -> bool {
+        crate::internals::compaction::should_compact(total_bytes, stale_bytes)
+    }
+}
+
+#[deprecated(note = "internal to the engine; use `CrabKvBuilder::min_free_bytes`")]
+#[doc(hidden)]
+pub mod disk {
+    #[deprecated(note = "use `CrabKvBuilder::free_space_probe`")]
+    pub type FreeSpaceProbe = crate::internals::disk::FreeSpaceProbe;
+    #[deprecated(note = "internal to the engine")]
+    pub type DiskGuard = crate::internals::disk::DiskGuard;
+}
+
+#[deprecated(note = "internal to the engine")]
+#[doc(hidden)]
+pub mod index {
+    #[deprecated(note = "internal to the engine")]
+    pub type ValuePointer = crate::internals::index::ValuePointer;
+}
+
+#[deprecated(note = "internal to the engine; see `CrabKv::keys_matching`")]
+#[doc(hidden)]
+pub mod pattern {
+    #[deprecated(note = "internal to the engine")]
+    pub fn glob_match(pattern: &str, text: &str) -> bool {
+        crate::internals::pattern::glob_match(pattern, text)
+    }
+}
+
+#[deprecated(note = "internal to the engine; use `crabkv::WalFile` to read or verify a log")]
+#[doc(hidden)]
+pub mod wal {
+    #[deprecated(note = "internal to the engine")]
+    pub const REF_PAYLOAD_SIZE: usize = crate::internals::wal::REF_PAYLOAD_SIZE;
+    #[deprecated(note = "internal to the engine")]
+    pub type LoadedIndex = crate::internals::wal::LoadedIndex;
+    #[deprecated(note = "internal to the engine")]
+    pub type LoadedEntry = crate::internals::wal::LoadedEntry;
+    #[deprecated(note = "internal to the engine")]
+    pub type AppendedBatch = crate::internals::wal::AppendedBatch;
+    #[deprecated(note = "internal to the engine")]
+    pub type WalEntry = crate::internals::wal::WalEntry;
+    #[deprecated(note = "use `crabkv::WalVerification`")]
+    pub type LoadedLog = crate::internals::wal::LoadedLog;
+    #[deprecated(note = "internal to the engine")]
+    pub type WalRecord = crate::internals::wal::WalRecord;
+    #[deprecated(note = "use `crabkv::WalFile`")]
+    pub type Wal = crate::internals::wal::Wal;
+    #[deprecated(note = "internal to the engine")]
+    pub type Generation = crate::internals::wal::Generation;
+}
@@ -0,0 +1,203 @@
+//! Lock file a running server leaves in its data directory, naming its process
+//! and address, so the CLI can send commands to it instead of opening the
+//! store a second time.
+//!
+//! The file is advisory: the engine itself never checks it, and only moves it
+//! along when [`CrabKv::migrate_to`] switches a served store to another
+//! directory. A holder counts as live only while its address accepts
+//! connections, so a file left behind by a crash is ignored and overwritten by
+//! the next server.
+
+use crate::{CrabKv, manifest};
+use parking_lot::Mutex;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Name of the lock file inside the data directory.
+pub const LOCK_FILE: &str = "server.lock";
+
+/// How long [`LockHolder::is_live`] waits for the holder to accept a connection.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The server process a lock file names.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LockHolder {
+    pub pid: u32,
+    /// First address the server listens on.
+    pub addr: SocketAddr,
+    /// Id of the store the server had open, from its manifest; absent in lock
+    /// files written before stores had one.
+    pub store_id: Option<String>,
+}
+
+impl LockHolder {
+    /// Address a local client reaches the holder on: its listening address,
+    /// with a wildcard IP replaced by loopback.
+    pub fn local_addr(&self) -> SocketAddr {
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        addr
+    }
+
+    /// Whether the holder still accepts connections.
+    pub fn is_live(&self) -> bool {
+        TcpStream::connect_timeout(&self.local_addr(), PROBE_TIMEOUT).is_ok()
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let (mut pid, mut addr, mut store_id) = (None, None, None);
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some(("pid", value)) => pid = value.parse().ok(),
+                Some(("addr", value)) => addr = value.parse().ok(),
+                Some(("store", value)) => store_id = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            pid: pid?,
+            addr: addr?,
+            store_id,
+        })
+    }
+}
+
+/// Reads the lock file in `dir`; `None` when there is none.
+///
+/// Fails with `InvalidData` when the file exists but does not name a process
+/// and address.
+pub fn holder(dir: &Path) -> io::Result<Option<LockHolder>> {
+    let text = match fs::read_to_string(dir.join(LOCK_FILE)) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    LockHolder::parse(&text).map(Some).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} is not a server lock file",
+                dir.join(LOCK_FILE).display()
+            ),
+        )
+    })
+}
+
+/// Returns the server holding `dir` if it is still running.
+pub fn live_holder(dir: &Path) -> io::Result<Option<LockHolder>> {
+    Ok(holder(dir)?.filter(LockHolder::is_live))
+}
+
+/// Copies the lock file this process holds in `from` into `to`, for a store
+/// about to switch there; returns whether there was one.
+pub(crate) fn copy_held(from: &Path, to: &Path) -> io::Result<bool> {
+    let text = match fs::read_to_string(from.join(LOCK_FILE)) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if LockHolder::parse(&text).is_none_or(|holder| holder.pid != process::id()) {
+        return Ok(false);
+    }
+    write(to, &text)?;
+    Ok(true)
+}
+
+fn write(dir: &Path, text: &str) -> io::Result<()> {
+    let temp = dir.join(format!("{LOCK_FILE}.tmp"));
+    fs::write(&temp, text)?;
+    fs::rename(&temp, dir.join(LOCK_FILE))
+}
+
+/// The lock file of this process, removed when dropped.
+pub struct ServerLock {
+    place: Place,
+}
+
+/// Where a [`ServerLock`] looks for its file when dropped.
+enum Place {
+    Dir(PathBuf),
+    /// The engine's directory, wherever it has been migrated to since.
+    Engine(Arc<Mutex<PathBuf>>),
+}
+
+impl ServerLock {
+    /// Records this process as the server of `dir`, listening on `addr`,
+    /// along with the store id from the directory's manifest.
+    ///
+    /// Fails with `AddrInUse` while another live server holds the directory; a
+    /// stale or unreadable lock file is replaced.
+    pub fn acquire(dir: &Path, addr: SocketAddr) -> io::Result<Self> {
+        if let Ok(Some(other)) = live_holder(dir)
+            && other.pid != process::id()
+        {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!(
+                    "{} is already served by process {} at {}",
+                    dir.display(),
+                    other.pid,
+                    other.addr
+                ),
+            ));
+        }
+        let mut text = format!("pid={}\naddr={addr}\n", process::id());
+        if let Some(manifest) = manifest::read(dir)? {
+            text.push_str(&format!("store={}\n", manifest.store_id));
+        }
+        write(dir, &text)?;
+        Ok(Self {
+            place: Place::Dir(dir.to_path_buf()),
+        })
+    }
+
+    /// Like [`ServerLock::acquire`] on the engine's directory, with the lock
+    /// following the engine when [`CrabKv::migrate_to`] moves it, so it is
+    /// removed from wherever the store lives when dropped.
+    pub fn acquire_for(engine: &CrabKv, addr: SocketAddr) -> io::Result<Self> {
+        let mut lock = Self::acquire(&engine.directory(), addr)?;
+        lock.place = Place::Engine(engine.shared_directory());
+        Ok(lock)
+    }
+
+    /// The lock file's current path.
+    pub fn path(&self) -> PathBuf {
+        match &self.place {
+            Place::Dir(dir) => dir.join(LOCK_FILE),
+            Place::Engine(dir) => dir.lock().join(LOCK_FILE),
+        }
+    }
+}
+
+impl fmt::Debug for ServerLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerLock")
+            .field("path", &self.path())
+            .finish()
+    }
+}
+
+impl Drop for ServerLock {
+    fn drop(&mut self) {
+        let path = self.path();
+        // Another server may have taken over a directory this one stopped answering for.
+        let ours = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| LockHolder::parse(&text))
+            .is_some_and(|holder| holder.pid == process::id());
+        if ours {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
@@ -1,13 +1,26 @@
-use crabkv::{CrabKv, server};
+use crabkv::config::{ServerConfig, parse_duration, parse_size};
+use crabkv::diagnostics::{self, Check, Status};
+use crabkv::events::{self, EVENTS_SHOWN};
+use crabkv::handoff::{self, HANDOFF_FILE, HANDOFF_FILE_ENV};
+use crabkv::lock_file::{self, LockHolder, ServerLock};
+use crabkv::stats::{SIZE_BUCKETS, SizeHistogram, TtlHistogram};
+use crabkv::{ChangeBatch, ChangeKind, CrabKv, CrabKvBuilder, server, status};
 use std::env;
-use std::io::{self, ErrorKind};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Log bytes `crabkv changes` reads unless `--max-bytes` says otherwise.
+const DEFAULT_CHANGES_BYTES: u64 = 1024 * 1024;
 
 fn main() {
     if let Err(error) = run() {
         eprintln!("Error: {error}");
+        if error.kind() == ErrorKind::DirectoryNotEmpty {
+            eprintln!("pass --force to `put` or `serve` to create a store there anyway");
+        }
         std::process::exit(1);
     }
 }
@@ -27,7 +40,19 @@ fn run() -> io::Result<()> {
         "get" => cmd_get(&data_dir, args),
         "delete" => cmd_delete(&data_dir, args),
         "compact" => cmd_compact(&data_dir, args),
+        "scrub" => cmd_scrub(&data_dir, args),
+        "sample" => cmd_sample(&data_dir, args),
+        "recent" => cmd_recent(&data_dir, args),
+        "changes" => cmd_changes(&data_dir, args),
+        "copy" => cmd_copy(&data_dir, args),
+        "flush" => cmd_flush(&data_dir, args),
+        "stats" => cmd_stats(&data_dir, args),
+        "doctor" => cmd_doctor(&data_dir, args),
         "serve" => cmd_serve(&data_dir, args),
+        "version" | "--version" | "-V" => {
+            println!("{}", crabkv::build_info());
+            Ok(())
+        }
         "help" | "--help" | "-h" => {
             print_usage();
             Ok(())
@@ -42,11 +67,26 @@ fn run() -> io::Result<()> {
 fn print_usage() {
     println!("CrabKv CLI");
     println!("Usage:");
-    println!("  crabkv put <key> <value> [--ttl <seconds>]");
+    println!("  crabkv put <key> <value> [--ttl <duration>] [--force]");
     println!("  crabkv get <key>");
     println!("  crabkv delete <key>");
     println!("  crabkv compact");
-    println!("  crabkv serve [--addr <host:port>] [--cache <entries>] [--default-ttl <seconds>]");
+    println!("  crabkv flush");
+    println!("  crabkv scrub");
+    println!("  crabkv sample [--count <n>] [--seed <n>] [--json]");
+    println!("  crabkv recent --since <duration> [--limit <n>]");
+    println!("  crabkv changes [--from <offset>] [--max-bytes <size>] [--generation <n>] [--json]");
+    println!("  crabkv copy --prefix <prefix> --to <dir> [--overwrite] [--dry-run]");
+    println!("  crabkv stats [--json] [--force]");
+    println!("  crabkv doctor [--data-dir <dir>]");
+    println!("  crabkv --version");
+    println!(
+        "  crabkv serve [--addr <host:port>[,<host:port>...]] [--reuse-port] [--force] [--cache <entries>] [--default-ttl <duration>] [--sync-interval <duration>] [--workers <n>] [--status-addr <host:port>] [--drain-timeout <duration>] [--max-output-bytes <n>] [--inherit-listener <fd>[,<fd>...]]"
+    );
+    println!("Durations: 90 (seconds), 90s, 500ms, 10m, 2h, 7d");
+    println!(
+        "put, get, delete, compact, flush, scrub and stats are forwarded to a server already running on the data directory; sample, recent, changes and copy refuse to run beside it"
+    );
     println!(
         "Environment overrides: CRABKV_DATA_DIR, CRABKV_CACHE_CAPACITY, CRABKV_DEFAULT_TTL_SECS"
     );
@@ -61,8 +101,22 @@ fn cmd_put(data_dir: &Path, mut args: Vec<String>) -> io::Result<()> {
     }
     let key = args.remove(0);
     let value = args.remove(0);
-    let ttl = parse_ttl_flag(&args)?;
-    let engine = open_engine_with_env(data_dir)?;
+    let (ttl, force) = parse_put_flags(&args)?;
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        let mut command = format!(
+            "PUT {} {}",
+            forwarded_token(&server, &key)?,
+            forwarded_token(&server, &value)?
+        );
+        if let Some(ttl) = ttl {
+            command.push_str(&format!(" ttl={}ms", ttl.as_millis()));
+        }
+        forward(&server, &command)?;
+        println!("stored");
+        return Ok(());
+    }
+    let builder = CrabKv::builder(data_dir).allow_existing_dir(force);
+    let engine = configure(builder, env_cache_capacity()?, env_default_ttl()?).build()?;
     match ttl {
         Some(ttl) => engine.put_with_ttl(key, value, Some(ttl))?,
         None => engine.put(key, value)?,
@@ -77,8 +131,13 @@ fn cmd_get(data_dir: &Path, mut args: Vec<String>) -> io::Result<()> {
     }
     let key = args.remove(0);
     ensure_no_flags(&args)?;
-    let engine = open_engine_with_env(data_dir)?;
-    match engine.get(&key)? {
+    let value = if let Some(server) = lock_file::live_holder(data_dir)? {
+        let reply = forward(&server, &format!("GET {}", forwarded_token(&server, &key)?))?;
+        reply.strip_prefix("VALUE ").map(str::to_string)
+    } else {
+        open_engine_with_env(data_dir)?.get(&key)?
+    };
+    match value {
         Some(value) => println!("{value}"),
         None => println!("key not found"),
     }
@@ -91,24 +150,436 @@ fn cmd_delete(data_dir: &Path, mut args: Vec<String>) -> io::Result<()> {
     }
     let key = args.remove(0);
     ensure_no_flags(&args)?;
-    let engine = open_engine_with_env(data_dir)?;
-    engine.delete(&key)?;
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        forward(
+            &server,
+            &format!("DELETE {}", forwarded_token(&server, &key)?),
+        )?;
+    } else {
+        open_engine_with_env(data_dir)?.delete(&key)?;
+    }
     println!("deleted");
     Ok(())
 }
 
 fn cmd_compact(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
     ensure_no_flags(&args)?;
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        forward(&server, "COMPACT")?;
+        println!("compacted");
+        return Ok(());
+    }
     let engine = open_engine_with_env(data_dir)?;
     engine.compact()?;
     println!("compacted");
     Ok(())
 }
 
+/// Writes the write-back cache to the log.
+fn cmd_flush(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    ensure_no_flags(&args)?;
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        forward(&server, "FLUSH")?;
+    } else {
+        open_engine_with_env(data_dir)?.flush()?;
+    }
+    println!("flushed");
+    Ok(())
+}
+
+/// Checks every log record, printing `SCRUB records=.. damaged=..` and each
+/// damaged record found; fails when any was.
+fn cmd_scrub(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    ensure_no_flags(&args)?;
+    let damaged = if let Some(server) = lock_file::live_holder(data_dir)? {
+        let reply = forward(&server, "SCRUB")?;
+        println!("{reply}");
+        reply
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("damaged="))
+            .and_then(|damaged| damaged.parse().ok())
+            .unwrap_or(0)
+    } else {
+        let engine = open_engine_with_env(data_dir)?;
+        let report = engine.scrub_now()?;
+        println!(
+            "SCRUB records={} bytes={} damaged={} quarantined={}",
+            report.records_checked,
+            report.bytes_checked,
+            report.damaged_records,
+            report.quarantined_keys
+        );
+        for finding in &report.findings {
+            println!(
+                "damaged offset={} keys=[{}] error={}",
+                finding.offset,
+                finding.keys.join(","),
+                finding.error
+            );
+        }
+        report.damaged_records
+    };
+    if damaged > 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("found {damaged} damaged log records"),
+        ));
+    }
+    Ok(())
+}
+
+fn cmd_sample(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    let mut count = 100usize;
+    let mut seed = 0u64;
+    let mut json = false;
+
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--count" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--count requires a value")
+                })?;
+                count = value
+                    .parse()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid count"))?;
+            }
+            "--seed" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--seed requires a value")
+                })?;
+                seed = value
+                    .parse()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid seed"))?;
+            }
+            "--json" => json = true,
+            flag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option `{flag}`"),
+                ));
+            }
+        }
+        index += 1;
+    }
+
+    refuse_while_served(data_dir, "sample")?;
+    let engine = open_engine_with_env(data_dir)?;
+    let samples = engine.sample_keys(count, seed)?;
+    if json {
+        let rows: Vec<String> = samples
+            .iter()
+            .map(|sample| {
+                let expires_at = sample
+                    .expires_at
+                    .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                    .map(|since| since.as_secs().to_string())
+                    .unwrap_or_else(|| "null".to_string());
+                format!(
+                    "{{\"key\":\"{}\",\"value_len\":{},\"record_len\":{},\"expires_at\":{}}}",
+                    json_escape(&sample.key),
+                    sample.value_len,
+                    sample.record_len,
+                    expires_at
+                )
+            })
+            .collect();
+        println!("[{}]", rows.join(","));
+    } else {
+        for sample in samples {
+            println!(
+                "{} value_len={} record_len={}",
+                sample.key, sample.value_len, sample.record_len
+            );
+        }
+    }
+    Ok(())
+}
+
+fn cmd_recent(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    let mut since = None;
+    let mut limit = 100usize;
+
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--since" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--since requires a value")
+                })?;
+                since = Some(parse_duration(value)?);
+            }
+            "--limit" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--limit requires a value")
+                })?;
+                limit = value
+                    .parse()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid limit"))?;
+            }
+            flag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option `{flag}`"),
+                ));
+            }
+        }
+        index += 1;
+    }
+    let since = since.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "missing --since"))?;
+    refuse_while_served(data_dir, "recent")?;
+
+    let builder = configure(
+        CrabKv::builder(data_dir),
+        env_cache_capacity()?,
+        env_default_ttl()?,
+    );
+    let engine = builder.track_modifications(true).build()?;
+    let cutoff = SystemTime::now().checked_sub(since).unwrap_or(UNIX_EPOCH);
+    for modification in engine.modified_since(cutoff, limit)? {
+        let at = modification
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        if modification.deleted {
+            println!("{at} {} (deleted)", modification.key);
+        } else {
+            println!("{at} {}", modification.key);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_changes(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    let mut from = 0u64;
+    let mut max_bytes = DEFAULT_CHANGES_BYTES;
+    let mut generation = None;
+    let mut json = false;
+
+    let mut index = 0;
+    while index < args.len() {
+        let flag = args[index].as_str();
+        let value = args.get(index + 1).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, format!("{flag} requires a value"))
+        });
+        match flag {
+            "--from" => {
+                from = value?
+                    .parse()
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid offset"))?;
+                index += 1;
+            }
+            "--max-bytes" => {
+                max_bytes = parse_size(value?)?;
+                index += 1;
+            }
+            "--generation" => {
+                generation =
+                    Some(value?.parse::<u64>().map_err(|_| {
+                        io::Error::new(ErrorKind::InvalidInput, "invalid generation")
+                    })?);
+                index += 1;
+            }
+            "--json" => json = true,
+            flag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option `{flag}`"),
+                ));
+            }
+        }
+        index += 1;
+    }
+
+    refuse_while_served(data_dir, "changes")?;
+    let engine = open_engine_with_env(data_dir)?;
+    let current = engine.log_generation()?;
+    // Offsets from another generation point into a log that no longer exists.
+    let reset = generation.is_some_and(|generation| generation != current);
+    let batch = if reset {
+        ChangeBatch {
+            generation: current,
+            changes: Vec::new(),
+            next_offset: 0,
+            at_end: false,
+        }
+    } else {
+        engine.export_changes(from, max_bytes)?
+    };
+    if json {
+        let rows: Vec<String> = batch
+            .changes
+            .iter()
+            .map(|change| match &change.kind {
+                ChangeKind::Put { value, expires_at } => {
+                    let expires_at = expires_at
+                        .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                        .map(|since| since.as_secs().to_string())
+                        .unwrap_or_else(|| "null".to_string());
+                    format!(
+                        "{{\"offset\":{},\"op\":\"put\",\"key\":\"{}\",\"value\":\"{}\",\"expires_at\":{}}}",
+                        change.offset,
+                        json_escape(&change.key),
+                        json_escape(value),
+                        expires_at
+                    )
+                }
+                ChangeKind::PutBytes { value, expires_at } => {
+                    let expires_at = expires_at
+                        .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                        .map(|since| since.as_secs().to_string())
+                        .unwrap_or_else(|| "null".to_string());
+                    format!(
+                        "{{\"offset\":{},\"op\":\"put_bytes\",\"key\":\"{}\",\"value_hex\":\"{}\",\"expires_at\":{}}}",
+                        change.offset,
+                        json_escape(&change.key),
+                        hex(value),
+                        expires_at
+                    )
+                }
+                ChangeKind::Delete => format!(
+                    "{{\"offset\":{},\"op\":\"delete\",\"key\":\"{}\"}}",
+                    change.offset,
+                    json_escape(&change.key)
+                ),
+            })
+            .collect();
+        println!(
+            "{{\"generation\":{},\"reset\":{reset},\"next_offset\":{},\"at_end\":{},\"changes\":[{}]}}",
+            batch.generation,
+            batch.next_offset,
+            batch.at_end,
+            rows.join(",")
+        );
+    } else {
+        println!(
+            "generation={} reset={reset} next_offset={} at_end={}",
+            batch.generation, batch.next_offset, batch.at_end
+        );
+        for change in &batch.changes {
+            match &change.kind {
+                ChangeKind::Put { value, .. } => {
+                    println!("{} put {} {value}", change.offset, change.key)
+                }
+                ChangeKind::PutBytes { value, .. } => {
+                    println!("{} put_bytes {} {}", change.offset, change.key, hex(value))
+                }
+                ChangeKind::Delete => println!("{} delete {}", change.offset, change.key),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_copy(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    let mut prefix = None;
+    let mut dest_dir = None;
+    let mut overwrite = false;
+    let mut dry_run = false;
+
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--prefix" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--prefix requires a value")
+                })?;
+                prefix = Some(value.clone());
+            }
+            "--to" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--to requires a value")
+                })?;
+                dest_dir = Some(PathBuf::from(value));
+            }
+            "--overwrite" => overwrite = true,
+            "--dry-run" => dry_run = true,
+            flag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option `{flag}`"),
+                ));
+            }
+        }
+        index += 1;
+    }
+    let prefix =
+        prefix.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "missing --prefix"))?;
+    let dest_dir =
+        dest_dir.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "missing --to"))?;
+
+    refuse_while_served(data_dir, "copy")?;
+    refuse_while_served(&dest_dir, "copy")?;
+    let source = open_engine_with_env(data_dir)?;
+    // Copied keys keep their own expiry, so no default TTL applies.
+    let dest = open_engine(&dest_dir, env_cache_capacity()?, None)?;
+    if dry_run {
+        let plan = source.plan_copy_prefix_to(&prefix, &dest, overwrite)?;
+        let report = plan.report;
+        println!(
+            "would copy {} keys ({} bytes), overwriting {}, skipping {} conflicts",
+            report.keys_copied,
+            report.bytes_copied,
+            report.keys_overwritten,
+            report.conflicts_skipped
+        );
+        for issue in &plan.issues {
+            println!("would fail on {issue}");
+        }
+        if !plan.issues.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} keys would be rejected", plan.issues.len()),
+            ));
+        }
+        return Ok(());
+    }
+    let report = source.copy_prefix_to(&prefix, &dest, overwrite)?;
+    println!(
+        "copied {} keys ({} bytes), overwrote {}, skipped {} conflicts",
+        report.keys_copied, report.bytes_copied, report.keys_overwritten, report.conflicts_skipped
+    );
+    Ok(())
+}
+
+/// Lowercase hex of a binary value, so it prints on one line.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn json_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
 fn cmd_serve(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
     let mut addr = String::from("127.0.0.1:4000");
+    let mut inherited = Vec::new();
     let mut cache = env_cache_capacity()?;
     let mut default_ttl = env_default_ttl()?;
+    let mut sync_interval = None;
+    let mut status_addr = None;
+    let mut drain_timeout = DEFAULT_DRAIN_TIMEOUT;
+    let mut server_config = ServerConfig::default();
+    let mut force = false;
 
     let mut index = 0;
     while index < args.len() {
@@ -132,7 +603,69 @@ fn cmd_serve(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
                 let value = args.get(index).ok_or_else(|| {
                     io::Error::new(ErrorKind::InvalidInput, "--default-ttl requires a value")
                 })?;
-                default_ttl = Some(parse_duration_secs(value)?);
+                default_ttl = Some(parse_duration(value)?);
+            }
+            "--sync-interval" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--sync-interval requires a value")
+                })?;
+                sync_interval = Some(parse_duration(value)?);
+            }
+            "--status-addr" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--status-addr requires a value")
+                })?;
+                status_addr = Some(value.clone());
+            }
+            "--drain-timeout" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--drain-timeout requires a value")
+                })?;
+                drain_timeout = parse_duration(value)?;
+            }
+            "--workers" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "--workers requires a value")
+                })?;
+                server_config.workers = value
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(NonZeroUsize::new)
+                    .ok_or_else(|| {
+                        io::Error::new(ErrorKind::InvalidInput, "invalid worker count")
+                    })?;
+            }
+            "--reuse-port" => server_config.reuse_port = true,
+            "--force" => force = true,
+            "--inherit-listener" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "--inherit-listener requires a value",
+                    )
+                })?;
+                for fd in value.split(',') {
+                    inherited.push(fd.parse::<i32>().map_err(|_| {
+                        io::Error::new(ErrorKind::InvalidInput, "invalid listener descriptor")
+                    })?);
+                }
+            }
+            "--max-output-bytes" => {
+                index += 1;
+                let value = args.get(index).ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "--max-output-bytes requires a value",
+                    )
+                })?;
+                server_config.max_output_bytes = value.parse().map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidInput, "invalid output byte limit")
+                })?;
             }
             flag => {
                 return Err(io::Error::new(
@@ -144,8 +677,446 @@ fn cmd_serve(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
         index += 1;
     }
 
-    let engine = open_engine(data_dir, cache, default_ttl)?;
-    server::run(&addr, engine)
+    println!("{}", crabkv::build_info());
+    // Started by a server handing off: it still holds the store until it has drained.
+    if !inherited.is_empty()
+        && let Some(state_file) = env::var_os(HANDOFF_FILE_ENV)
+    {
+        println!("waiting for the previous server to release the store");
+        handoff::wait_released(Path::new(&state_file), drain_timeout + HANDOFF_GRACE)?;
+    }
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!(
+                "{} is already served by process {} at {}",
+                data_dir.display(),
+                server.pid,
+                server.addr
+            ),
+        ));
+    }
+    let mut engine = CrabKv::builder(data_dir).allow_existing_dir(force);
+    if let Some(interval) = sync_interval {
+        engine = engine.sync_interval(interval);
+    }
+    let engine = configure(engine, cache, default_ttl).build()?;
+    // Kept alive for as long as the server runs.
+    let status = match status_addr {
+        Some(status_addr) => Some(status::spawn(&status_addr, engine.clone())?),
+        None => None,
+    };
+    let listeners = if inherited.is_empty() {
+        server::bind(&addr, &server_config)?
+    } else {
+        inherited_listeners(&inherited)?
+    };
+    let serving = Serving {
+        engine,
+        status,
+        config: server_config,
+        drain_timeout,
+        args: &args,
+    };
+    serve_until_signalled(serving, listeners)
+}
+
+/// Extra time a successor waits for the store beyond the drain timeout.
+const HANDOFF_GRACE: Duration = Duration::from_secs(30);
+
+/// What `serve` runs until it is told to stop.
+struct Serving<'a> {
+    engine: CrabKv,
+    status: Option<status::StatusHandle>,
+    config: ServerConfig,
+    drain_timeout: Duration,
+    /// The `serve` arguments, passed on to a successor.
+    args: &'a [String],
+}
+
+#[cfg(unix)]
+fn inherited_listeners(fds: &[i32]) -> io::Result<Vec<TcpListener>> {
+    fds.iter()
+        .map(|&fd| handoff::inherited_listener(fd))
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn inherited_listeners(_fds: &[i32]) -> io::Result<Vec<TcpListener>> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "--inherit-listener needs Unix; drain the old server and start a new one instead",
+    ))
+}
+
+/// How long the CLI waits on a running server it forwards a command to.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Sends `command` to the running server and returns its reply line, saying
+/// so on stdout; an `ERR` reply becomes the error.
+fn forward(server: &LockHolder, command: &str) -> io::Result<String> {
+    let addr = server.local_addr();
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(FORWARD_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome)?;
+    (&stream).write_all(format!("{command}\n").as_bytes())?;
+    let mut reply = String::new();
+    if reader.read_line(&mut reply)? == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("server at {addr} closed the connection"),
+        ));
+    }
+    let reply = reply.trim_end().to_string();
+    if let Some(error) = reply.strip_prefix("ERR ") {
+        return Err(io::Error::other(format!("server at {addr}: {error}")));
+    }
+    println!("forwarded to running server at {addr}");
+    Ok(reply)
+}
+
+/// `token` as one field of a command line sent to `server`; the protocol splits
+/// lines on whitespace, so a token with any cannot be forwarded.
+fn forwarded_token<'t>(server: &LockHolder, token: &'t str) -> io::Result<&'t str> {
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "`{token}` cannot be sent to the server (process {}) at {} over its line protocol; stop the server to use it",
+                server.pid, server.addr
+            ),
+        ));
+    }
+    Ok(token)
+}
+
+/// Fails while a live server holds `data_dir`, naming it, for a command that
+/// has no server counterpart and would otherwise open the store beside it.
+fn refuse_while_served(data_dir: &Path, command: &str) -> io::Result<()> {
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{command} needs the store to itself; stop the server (process {}) at {}",
+                server.pid, server.addr
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// How long `serve` keeps draining connections after SIGTERM by default.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serves until SIGTERM, which drains open connections, or SIGINT, which closes them at once.
+#[cfg(unix)]
+fn serve_until_signalled(serving: Serving<'_>, listeners: Vec<TcpListener>) -> io::Result<()> {
+    signals::install();
+    // Kept for a successor, and closed before a plain drain so it refuses new clients.
+    let handed = listeners
+        .iter()
+        .map(TcpListener::try_clone)
+        .collect::<io::Result<Vec<_>>>()?;
+    let server = server::spawn_on(listeners, serving.engine.clone(), serving.config.clone())?;
+    for addr in server.local_addrs() {
+        println!("CrabKv TCP server listening on {addr}");
+    }
+    // Lets `crabkv compact` and `stats` reach this server instead of the files.
+    let lock = ServerLock::acquire_for(&serving.engine, server.local_addrs()[0])?;
+    if let Some(state_file) = env::var_os(HANDOFF_FILE_ENV) {
+        handoff::mark_ready(Path::new(&state_file))?;
+    }
+    loop {
+        match signals::wait() {
+            signals::Stop::Drain => {
+                drop(handed);
+                println!("draining connections for up to {:?}", serving.drain_timeout);
+                return server.drain(serving.drain_timeout);
+            }
+            signals::Stop::Now => return server.shutdown(),
+            signals::Stop::Upgrade => {
+                let state_file = serving.engine.directory().join(HANDOFF_FILE);
+                let successor = match start_successor(&serving, &handed, &state_file) {
+                    Ok(successor) => successor,
+                    Err(err) => {
+                        eprintln!("cannot start a successor, still serving: {err}");
+                        continue;
+                    }
+                };
+                println!(
+                    "handing off to process {}, draining for up to {:?}",
+                    successor.id(),
+                    serving.drain_timeout
+                );
+                // The successor opens the store only once it is released, in this order.
+                server.hand_off(serving.drain_timeout)?;
+                drop(serving.status);
+                // Released only once closed. Otherwise the successor gives
+                // up waiting rather than open a store this process may still
+                // be writing to.
+                match serving.engine.try_close() {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => {
+                        return Err(io::Error::new(
+                            err.kind(),
+                            format!("closing the store failed, not releasing it: {err}"),
+                        ));
+                    }
+                    Err(_) => {
+                        return Err(io::Error::other(
+                            "the store still has other handles, not releasing it",
+                        ));
+                    }
+                }
+                drop(lock);
+                return handoff::mark_released(&state_file);
+            }
+        }
+    }
+}
+
+/// Starts this binary again as `serve` on inherited copies of `listeners`,
+/// told through the environment to wait for `state_file`.
+#[cfg(unix)]
+fn start_successor(
+    serving: &Serving<'_>,
+    listeners: &[TcpListener],
+    state_file: &Path,
+) -> io::Result<std::process::Child> {
+    handoff::reset(state_file)?;
+    let mut args = Vec::new();
+    let mut rest = serving.args.iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--inherit-listener" {
+            rest.next();
+        } else {
+            args.push(arg.clone());
+        }
+    }
+    let mut fds = Vec::new();
+    let spawned = listeners
+        .iter()
+        .try_for_each(|listener| {
+            fds.push(handoff::inheritable(listener)?);
+            Ok(())
+        })
+        .and_then(|()| {
+            let fds: Vec<String> = fds.iter().map(i32::to_string).collect();
+            std::process::Command::new(env::current_exe()?)
+                .arg("serve")
+                .args(&args)
+                .arg("--inherit-listener")
+                .arg(fds.join(","))
+                .env("CRABKV_DATA_DIR", serving.engine.directory())
+                .env(HANDOFF_FILE_ENV, state_file)
+                .spawn()
+        });
+    // The successor holds its own copies now.
+    for fd in fds {
+        handoff::close_inherited(fd);
+    }
+    spawned
+}
+
+#[cfg(not(unix))]
+fn serve_until_signalled(serving: Serving<'_>, listeners: Vec<TcpListener>) -> io::Result<()> {
+    let server = server::spawn_on(listeners, serving.engine.clone(), serving.config)?;
+    for addr in server.local_addrs() {
+        println!("CrabKv TCP server listening on {addr}");
+    }
+    let _lock = ServerLock::acquire_for(&serving.engine, server.local_addrs()[0])?;
+    let _status = serving.status;
+    loop {
+        std::thread::park();
+    }
+}
+
+fn cmd_stats(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    let mut json = false;
+    let mut force = false;
+    for arg in &args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--force" => force = true,
+            flag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option `{flag}`"),
+                ));
+            }
+        }
+    }
+
+    if let Some(server) = lock_file::live_holder(data_dir)? {
+        if json {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "--json needs the store to itself; stop the server (process {}) at {} or send it STATS",
+                    server.pid, server.addr
+                ),
+            ));
+        }
+        let reply = forward(&server, if force { "STATS force=true" } else { "STATS" })?;
+        for field in reply.split_whitespace().skip(1) {
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            println!("{name} {value}");
+        }
+        return Ok(());
+    }
+    let engine = open_engine_with_env(data_dir)?;
+    let stats = if force {
+        engine.stats_with_histograms()?
+    } else {
+        engine.stats()?
+    };
+    if json {
+        let counts = |counts: Option<&[u64]>| match counts {
+            Some(counts) => {
+                let counts: Vec<String> = counts.iter().map(u64::to_string).collect();
+                format!("[{}]", counts.join(","))
+            }
+            None => "null".to_string(),
+        };
+        let bounds: Vec<String> = (0..SIZE_BUCKETS)
+            .map(|bucket| SizeHistogram::lower_bound(bucket).to_string())
+            .collect();
+        println!(
+            "{{\"keys\":{},\"wal_bytes\":{},\"live_bytes\":{},\"stale_bytes\":{},\"dedup_saved_bytes\":{},\"quarantined\":{},\"bucket_lower_bounds\":[{}],\"key_sizes\":{},\"value_sizes\":{},\
+             \"keys_with_ttl\":{},\"keys_without_ttl\":{},\"ttl_bucket_labels\":[{}],\"ttl_remaining\":{},\
+             \"expired_unreclaimed\":{},\"expired_since_open\":{},\"ttl_extensions\":{}}}",
+            stats.keys,
+            stats.wal_bytes,
+            stats.live_bytes,
+            stats.stale_bytes,
+            stats.dedup_saved_bytes,
+            stats.quarantined,
+            bounds.join(","),
+            counts(stats.key_sizes.as_ref().map(|sizes| &sizes.counts[..])),
+            counts(stats.value_sizes.as_ref().map(|sizes| &sizes.counts[..])),
+            stats.keys_with_ttl,
+            stats.keys_without_ttl,
+            TtlHistogram::LABELS
+                .map(|label| format!("\"{label}\""))
+                .join(","),
+            counts(stats.ttl_remaining.as_ref().map(|ttl| &ttl.counts[..])),
+            stats.expired_unreclaimed,
+            stats.expiry.expired,
+            stats.ttl_extensions,
+        );
+        return Ok(());
+    }
+
+    println!("keys {}", stats.keys);
+    println!(
+        "log {} bytes: {} live, {} stale",
+        stats.wal_bytes, stats.live_bytes, stats.stale_bytes
+    );
+    if stats.dedup_saved_bytes > 0 {
+        println!("dedup saved {} bytes", stats.dedup_saved_bytes);
+    }
+    if stats.quarantined > 0 {
+        println!(
+            "quarantined {} keys with unreadable records (see `crabkv doctor`)",
+            stats.quarantined
+        );
+    }
+    match (&stats.key_sizes, &stats.value_sizes) {
+        (Some(keys), Some(values)) => {
+            println!("{:>12} {:>10} {:>10}", "size >=", "keys", "values");
+            for bucket in 0..SIZE_BUCKETS {
+                if keys.counts[bucket] > 0 || values.counts[bucket] > 0 {
+                    println!(
+                        "{:>12} {:>10} {:>10}",
+                        SizeHistogram::lower_bound(bucket),
+                        keys.counts[bucket],
+                        values.counts[bucket]
+                    );
+                }
+            }
+        }
+        _ => println!("size histograms skipped for a large index; pass --force to compute them"),
+    }
+    println!(
+        "ttl: {} keys expire, {} never; {} expired awaiting removal",
+        stats.keys_with_ttl, stats.keys_without_ttl, stats.expired_unreclaimed
+    );
+    if let Some(ttl) = &stats.ttl_remaining {
+        let buckets: Vec<String> = TtlHistogram::LABELS
+            .iter()
+            .zip(ttl.counts)
+            .map(|(label, count)| format!("{label} {count}"))
+            .collect();
+        println!("ttl remaining: {}", buckets.join(", "));
+    }
+    Ok(())
+}
+
+/// Reports on the data directory without opening an engine or writing to it.
+fn cmd_doctor(data_dir: &Path, args: Vec<String>) -> io::Result<()> {
+    let data_dir = match args.as_slice() {
+        [] => data_dir.to_path_buf(),
+        [flag, dir] if flag == "--data-dir" => PathBuf::from(dir),
+        [flag] if flag == "--data-dir" => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--data-dir requires a value",
+            ));
+        }
+        _ => return ensure_no_flags(&args),
+    };
+    let mut checks = vec![config_check(&data_dir)];
+    checks.extend(diagnostics::full_checks(&data_dir, SystemTime::now()));
+    for check in &checks {
+        println!("[{}] {}: {}", check.status, check.name, check.detail);
+        if let Some(remedy) = &check.remedy {
+            println!("       fix: {remedy}");
+        }
+    }
+    // Saved by the engine after each compaction and on close, so it may lag
+    // behind a store that is open right now.
+    match events::read_recent(&data_dir, EVENTS_SHOWN) {
+        Ok(lines) if lines.is_empty() => {}
+        Ok(lines) => {
+            println!("recent events (unix millis, oldest first):");
+            for line in lines {
+                println!("  {line}");
+            }
+        }
+        Err(err) => println!("recent events unreadable: {err}"),
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(io::Error::other(format!("{failed} checks failed")));
+    }
+    Ok(())
+}
+
+/// Reports the configuration the other commands would open the store with.
+fn config_check(data_dir: &Path) -> Check {
+    let cache = match env_cache_capacity() {
+        Ok(Some(capacity)) => format!("cache {capacity} entries"),
+        Ok(None) => "cache disabled".to_string(),
+        Err(err) => {
+            return Check::fail("config", err.to_string(), "fix or unset the variable");
+        }
+    };
+    let ttl = match env_default_ttl() {
+        Ok(Some(ttl)) => format!("default ttl {}s", ttl.as_secs()),
+        Ok(None) => "no default ttl".to_string(),
+        Err(err) => {
+            return Check::fail("config", err.to_string(), "fix or unset the variable");
+        }
+    };
+    Check::ok(
+        "config",
+        format!("data dir {}, {cache}, {ttl}", data_dir.display()),
+    )
 }
 
 fn ensure_no_flags(args: &[String]) -> io::Result<()> {
@@ -158,11 +1129,9 @@ fn ensure_no_flags(args: &[String]) -> io::Result<()> {
     ))
 }
 
-fn parse_ttl_flag(args: &[String]) -> io::Result<Option<Duration>> {
-    if args.is_empty() {
-        return Ok(None);
-    }
-    let mut ttl = None;
+/// Parses the `--ttl` and `--force` options of `put`.
+fn parse_put_flags(args: &[String]) -> io::Result<(Option<Duration>, bool)> {
+    let (mut ttl, mut force) = (None, false);
     let mut index = 0;
     while index < args.len() {
         match args[index].as_str() {
@@ -171,8 +1140,9 @@ fn parse_ttl_flag(args: &[String]) -> io::Result<Option<Duration>> {
                 let value = args.get(index).ok_or_else(|| {
                     io::Error::new(ErrorKind::InvalidInput, "--ttl requires a value")
                 })?;
-                ttl = Some(parse_duration_secs(value)?);
+                ttl = Some(parse_duration(value)?);
             }
+            "--force" => force = true,
             flag => {
                 return Err(io::Error::new(
                     ErrorKind::InvalidInput,
@@ -182,7 +1152,7 @@ fn parse_ttl_flag(args: &[String]) -> io::Result<Option<Duration>> {
         }
         index += 1;
     }
-    Ok(ttl)
+    Ok((ttl, force))
 }
 
 fn parse_cache_capacity(value: &str) -> io::Result<Option<NonZeroUsize>> {
@@ -192,13 +1162,6 @@ fn parse_cache_capacity(value: &str) -> io::Result<Option<NonZeroUsize>> {
     Ok(NonZeroUsize::new(entries))
 }
 
-fn parse_duration_secs(input: &str) -> io::Result<Duration> {
-    let seconds = input
-        .parse::<u64>()
-        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid seconds"))?;
-    Ok(Duration::from_secs(seconds))
-}
-
 fn data_directory() -> PathBuf {
     env::var("CRABKV_DATA_DIR")
         .map(PathBuf::from)
@@ -218,7 +1181,9 @@ fn env_cache_capacity() -> io::Result<Option<NonZeroUsize>> {
 
 fn env_default_ttl() -> io::Result<Option<Duration>> {
     match env::var("CRABKV_DEFAULT_TTL_SECS") {
-        Ok(value) => parse_duration_secs(&value).map(Some),
+        Ok(value) => parse_duration(&value)
+            .map(Some)
+            .map_err(|err| io::Error::new(err.kind(), format!("CRABKV_DEFAULT_TTL_SECS: {err}"))),
         Err(env::VarError::NotPresent) => Ok(None),
         Err(_) => Err(io::Error::new(
             ErrorKind::InvalidInput,
@@ -238,12 +1203,63 @@ fn open_engine(
     cache_capacity: Option<NonZeroUsize>,
     default_ttl: Option<Duration>,
 ) -> io::Result<CrabKv> {
-    let mut builder = CrabKv::builder(data_dir);
+    configure(CrabKv::builder(data_dir), cache_capacity, default_ttl).build()
+}
+
+fn configure(
+    mut builder: CrabKvBuilder,
+    cache_capacity: Option<NonZeroUsize>,
+    default_ttl: Option<Duration>,
+) -> CrabKvBuilder {
     if let Some(capacity) = cache_capacity {
         builder = builder.cache_capacity(capacity);
     }
     if let Some(ttl) = default_ttl {
         builder = builder.default_ttl(ttl);
     }
-    builder.build()
+    builder
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Last signal received, or 0 once it has been handled.
+    static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+    pub enum Stop {
+        Drain,
+        Now,
+        /// Hand the listeners to a freshly started copy of the binary.
+        Upgrade,
+    }
+
+    extern "C" fn record(signal: libc::c_int) {
+        RECEIVED.store(signal, Ordering::SeqCst);
+    }
+
+    /// Routes SIGTERM, SIGINT and SIGUSR2 to [`wait`] instead of killing the process.
+    pub fn install() {
+        let handler = record as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: `record` only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            libc::signal(libc::SIGTERM, handler);
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGUSR2, handler);
+        }
+    }
+
+    /// Blocks until SIGTERM, SIGINT or SIGUSR2 arrives.
+    pub fn wait() -> Stop {
+        loop {
+            match RECEIVED.swap(0, Ordering::SeqCst) {
+                libc::SIGTERM => return Stop::Drain,
+                libc::SIGINT => return Stop::Now,
+                libc::SIGUSR2 => return Stop::Upgrade,
+                _ => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
 }
@@ -0,0 +1,320 @@
+//! Manifest file naming the store a data directory holds.
+//!
+//! The engine writes it the first time a store opens in a directory, with a
+//! random store id that stays the same for the life of the store and moves
+//! with it on [`CrabKv::migrate_to`](crate::CrabKv::migrate_to). A first open
+//! that fails leaves no manifest behind. The server lock file
+//! and `crabkv doctor` report the id, so two stores can be told apart even
+//! when they have lived at the same path.
+//!
+//! The manifest also keeps the store's fencing epoch. It starts at 0 and goes
+//! up by one whenever the data is replaced or moved, by
+//! [`CrabKv::restore_from`](crate::CrabKv::restore_from),
+//! [`CrabKv::clear`](crate::CrabKv::clear) or `migrate_to`, so clients that
+//! pinned the epoch they connected at find out before writing into data they
+//! never saw. A manifest written before epochs existed reads as epoch 0.
+//!
+//! A store created with a [`KeyPolicy`] other than the default records it
+//! too, as `keys=nfc`; a manifest without the line reads as raw keys.
+
+use crate::keys::{KeyPolicy, KeyPolicyMismatch};
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the manifest file inside the data directory.
+pub const MANIFEST_FILE: &str = "crabkv.manifest";
+
+/// Contents of a data directory's manifest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    /// Random id given to the store when it was created, as a UUID.
+    pub store_id: String,
+    /// Fencing epoch, bumped each time the store's data is replaced or moved.
+    pub epoch: u64,
+    /// How the store compares keys, fixed when it was created.
+    pub keys: KeyPolicy,
+}
+
+impl Manifest {
+    /// A manifest for a new store with a fresh random id.
+    pub fn generate() -> Self {
+        Self {
+            store_id: new_store_id(),
+            epoch: 0,
+            keys: KeyPolicy::Raw,
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let (mut store_id, mut epoch, mut keys) = (None, Some(0), Some(KeyPolicy::Raw));
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some(("store_id", value)) if valid_store_id(value) => {
+                    store_id = Some(value.to_string());
+                }
+                Some(("epoch", value)) => epoch = value.parse().ok(),
+                Some(("keys", value)) => keys = KeyPolicy::parse(value),
+                _ => {}
+            }
+        }
+        Some(Self {
+            store_id: store_id?,
+            epoch: epoch?,
+            keys: keys?,
+        })
+    }
+
+    /// The file's contents. Raw keys are left out, so stores that never
+    /// chose a policy keep the manifest older builds wrote.
+    fn text(&self) -> String {
+        let mut text = format!("store_id={}\nepoch={}\n", self.store_id, self.epoch);
+        if self.keys != KeyPolicy::Raw {
+            text.push_str(&format!("keys={}\n", self.keys));
+        }
+        text
+    }
+}
+
+/// Error payload for a directory holding a different store than the one
+/// [`CrabKvBuilder::expected_store_id`](crate::CrabKvBuilder::expected_store_id)
+/// names. Carried inside an `InvalidData` error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoreIdMismatch {
+    /// The id the caller asked for.
+    pub expected: String,
+    /// The id the directory's manifest holds.
+    pub found: String,
+}
+
+impl StoreIdMismatch {
+    /// Returns the mismatch carried by `err`, if any.
+    pub fn of(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for StoreIdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "directory holds store {}, expected store {}",
+            self.found, self.expected
+        )
+    }
+}
+
+impl Error for StoreIdMismatch {}
+
+/// Error payload for a write pinned with
+/// [`WriteOptions::expected_epoch`](crate::WriteOptions::expected_epoch) to an
+/// epoch the store has since left. Carried inside a
+/// [`StaleNetworkFileHandle`](ErrorKind::StaleNetworkFileHandle) error, and
+/// sent by the server as `ERR EPOCH ...`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EpochMismatch {
+    pub expected: u64,
+    pub current: u64,
+}
+
+impl EpochMismatch {
+    /// Returns the mismatch carried by `err`, if any.
+    pub fn of(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    pub(crate) fn error(expected: u64, current: u64) -> io::Error {
+        io::Error::new(
+            ErrorKind::StaleNetworkFileHandle,
+            EpochMismatch { expected, current },
+        )
+    }
+}
+
+impl fmt::Display for EpochMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EPOCH expected {}, the store is at epoch {}; reconnect and re-read",
+            self.expected, self.current
+        )
+    }
+}
+
+impl Error for EpochMismatch {}
+
+/// Reads the manifest in `dir`; `None` when there is none.
+///
+/// Fails with `InvalidData` when the file exists but names no store, has an
+/// epoch that is not a number or a key policy this build does not know.
+pub fn read(dir: &Path) -> io::Result<Option<Manifest>> {
+    let path = dir.join(MANIFEST_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Manifest::parse(&text).map(Some).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} does not name a store", path.display()),
+        )
+    })
+}
+
+/// Writes `manifest` into `dir`, replacing any manifest already there.
+pub fn write(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    let temp = dir.join(format!("{MANIFEST_FILE}.tmp"));
+    fs::write(&temp, manifest.text())?;
+    fs::File::open(&temp)?.sync_all()?;
+    fs::rename(&temp, dir.join(MANIFEST_FILE))
+}
+
+/// Whether `dir` holds anything the engine would recognise as a store: a
+/// manifest, a log, or a file left by an interrupted compaction.
+pub fn holds_store(dir: &Path) -> io::Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name == MANIFEST_FILE || name == "wal.log" || name.starts_with("wal.") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Checks that `dir` may hold the store and returns its manifest, or a new
+/// one for [`create`] to write once the store has opened, with `true`.
+///
+/// A non-empty directory that does not already hold a store is refused with
+/// `DirectoryNotEmpty` unless `allow_existing` is set. When `expected` is
+/// given, a manifest naming another store is refused with a
+/// [`StoreIdMismatch`], and a new manifest records `expected` as the id. A
+/// store created with another key policy than `keys` is refused with a
+/// [`KeyPolicyMismatch`]; one without a manifest has raw keys.
+pub(crate) fn prepare(
+    dir: &Path,
+    allow_existing: bool,
+    expected: Option<&str>,
+    keys: KeyPolicy,
+) -> io::Result<(Manifest, bool)> {
+    if let Some(expected) = expected
+        && !valid_store_id(expected)
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("`{expected}` is not a valid store id"),
+        ));
+    }
+    if let Some(manifest) = read(dir)? {
+        return check_expected(manifest, expected, keys).map(|manifest| (manifest, false));
+    }
+    if keys != KeyPolicy::Raw && holds_store(dir)? {
+        return Err(policy_mismatch(KeyPolicy::Raw, keys));
+    }
+    if !allow_existing && !holds_store(dir)? && fs::read_dir(dir)?.next().is_some() {
+        return Err(io::Error::new(
+            ErrorKind::DirectoryNotEmpty,
+            format!(
+                "{} is not empty and holds no CrabKv store; allow_existing_dir(true) opens it anyway",
+                dir.display()
+            ),
+        ));
+    }
+    let manifest = Manifest {
+        store_id: expected.map_or_else(new_store_id, str::to_string),
+        epoch: 0,
+        keys,
+    };
+    Ok((manifest, true))
+}
+
+/// Writes the new `manifest` [`prepare`] returned into `dir` and returns the
+/// manifest in force.
+///
+/// The file appears whole or not at all, and is never replaced: when another
+/// open wrote one first, that one is checked against `expected` and the new
+/// manifest's key policy like in `prepare` and returned instead.
+pub(crate) fn create(
+    dir: &Path,
+    manifest: Manifest,
+    expected: Option<&str>,
+) -> io::Result<Manifest> {
+    let temp = dir.join(format!("{MANIFEST_FILE}.{}.tmp", process::id()));
+    fs::write(&temp, manifest.text())?;
+    fs::File::open(&temp)?.sync_all()?;
+    let linked = fs::hard_link(&temp, dir.join(MANIFEST_FILE));
+    fs::remove_file(&temp)?;
+    match linked {
+        Ok(()) => Ok(manifest),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            let found = read(dir)?.ok_or(err)?;
+            check_expected(found, expected, manifest.keys)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn check_expected(
+    manifest: Manifest,
+    expected: Option<&str>,
+    keys: KeyPolicy,
+) -> io::Result<Manifest> {
+    match expected {
+        Some(expected) if expected != manifest.store_id => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            StoreIdMismatch {
+                expected: expected.to_string(),
+                found: manifest.store_id,
+            },
+        )),
+        _ if manifest.keys != keys => Err(policy_mismatch(manifest.keys, keys)),
+        _ => Ok(manifest),
+    }
+}
+
+fn policy_mismatch(store: KeyPolicy, requested: KeyPolicy) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        KeyPolicyMismatch { store, requested },
+    )
+}
+
+/// Ids are kept to one printable token so the manifest and lock file stay
+/// line-oriented.
+fn valid_store_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// A version 4 UUID drawn from the standard library's randomly seeded hasher,
+/// mixed with the time and process id.
+fn new_store_id() -> String {
+    let mut bytes = [0u8; 16];
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    for (half, chunk) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u32(process::id());
+        hasher.write_usize(half);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -0,0 +1,220 @@
+//! Operating modes deciding what may change the data directory.
+//!
+//! The mode is set with [`CrabKv::set_mode`](crate::CrabKv::set_mode). User
+//! writes and every maintenance task ask the same gate before touching disk,
+//! so a mode means one thing to all of them:
+//!
+//! | mode        | user writes       | compactions, checkpoints |
+//! |-------------|-------------------|--------------------------|
+//! | `normal`    | run               | run                      |
+//! | `shadow`    | run               | run                      |
+//! | `frozen`    | wait for unfreeze | deferred until unfreeze  |
+//! | `read-only` | refused           | refused                  |
+//!
+//! Migrations are refused in both `frozen` and `read-only`; the TTL sweeper
+//! skips both, and the scrubber keeps reading but saves nothing. Entering either flushes the write-back buffer, so no
+//! flush has anything left to write. Reads are never gated.
+
+use parking_lot::{Condvar, Mutex};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// What the engine may do to its data directory.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum EngineMode {
+    /// Everything runs.
+    #[default]
+    Normal,
+    /// Writers block until the store is unfrozen; compactions and checkpoints
+    /// asked for meanwhile run then. For taking a consistent copy of the
+    /// directory without stopping readers.
+    Frozen,
+    /// Writes, compactions, checkpoints and migrations fail with a
+    /// [`ModeRefused`] error, and nothing in the directory changes.
+    ReadOnly,
+    /// Behaves like `Normal`, for a store receiving mirrored traffic; only
+    /// reported, so operators can tell it from the primary.
+    Shadow,
+}
+
+impl EngineMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EngineMode::Normal => "normal",
+            EngineMode::Frozen => "frozen",
+            EngineMode::ReadOnly => "read-only",
+            EngineMode::Shadow => "shadow",
+        }
+    }
+
+    /// Whether the mode lets the directory change at all.
+    pub fn allows_writes(self) -> bool {
+        matches!(self, EngineMode::Normal | EngineMode::Shadow)
+    }
+}
+
+impl fmt::Display for EngineMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Something the mode gate decides on.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Activity {
+    /// A user put, delete or other mutation.
+    Write,
+    /// Appending the write-back buffer to the log.
+    Flush,
+    Compaction,
+    Checkpoint,
+    /// [`CrabKv::migrate_to`](crate::CrabKv::migrate_to).
+    Migration,
+    /// The TTL sweeper dropping expired keys.
+    Sweep,
+    /// The scrubber saving its progress and quarantining damaged records.
+    Scrub,
+}
+
+impl Activity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Activity::Write => "write",
+            Activity::Flush => "flush",
+            Activity::Compaction => "compaction",
+            Activity::Checkpoint => "checkpoint",
+            Activity::Migration => "migration",
+            Activity::Sweep => "sweep",
+            Activity::Scrub => "scrub",
+        }
+    }
+
+    /// Whether a frozen store runs it once unfrozen instead of dropping it.
+    fn deferrable(self) -> bool {
+        matches!(self, Activity::Compaction | Activity::Checkpoint)
+    }
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error payload for an activity the current [`EngineMode`] does not allow.
+///
+/// Carried inside an [`io::Error`] of kind
+/// [`ReadOnlyFilesystem`](ErrorKind::ReadOnlyFilesystem) in `read-only`
+/// mode and [`WouldBlock`](ErrorKind::WouldBlock) in `frozen` mode.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModeRefused {
+    pub mode: EngineMode,
+    pub activity: Activity,
+    /// Whether the activity runs by itself once the store is unfrozen.
+    pub deferred: bool,
+}
+
+impl ModeRefused {
+    /// Returns the refusal carried by `err`, if any.
+    pub fn of(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for ModeRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.deferred {
+            write!(f, "{} deferred until the store is unfrozen", self.activity)
+        } else {
+            write!(f, "{} refused, the store is {}", self.activity, self.mode)
+        }
+    }
+}
+
+impl Error for ModeRefused {}
+
+/// Maintenance a frozen store was asked for.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Deferred {
+    pub(crate) compaction: bool,
+    pub(crate) checkpoint: bool,
+}
+
+/// The mode shared by every handle, the state and the background workers.
+///
+/// The engine changes it only while holding both the state write lock and
+/// the rewrite lock, so a caller holding either sees it stay put.
+#[derive(Debug, Default)]
+pub(crate) struct ModeGate {
+    mode: Mutex<EngineMode>,
+    unfrozen: Condvar,
+    deferred: Mutex<Deferred>,
+}
+
+impl ModeGate {
+    pub(crate) fn current(&self) -> EngineMode {
+        *self.mode.lock()
+    }
+
+    /// Switches to `mode`, waking writers waiting on a freeze, and returns
+    /// the previous mode.
+    pub(crate) fn set(&self, mode: EngineMode) -> EngineMode {
+        let previous = std::mem::replace(&mut *self.mode.lock(), mode);
+        self.unfrozen.notify_all();
+        previous
+    }
+
+    /// Blocks while the store is frozen; fails if it is read-only.
+    ///
+    /// Callers check again under the state lock, since the mode may change
+    /// before they get it.
+    pub(crate) fn wait_for_writes(&self) -> io::Result<()> {
+        let mut mode = self.mode.lock();
+        while *mode == EngineMode::Frozen {
+            self.unfrozen.wait(&mut mode);
+        }
+        match *mode {
+            EngineMode::ReadOnly => Err(refusal(EngineMode::ReadOnly, Activity::Write, false)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails unless the mode allows `activity` now. A frozen store remembers
+    /// refused compactions and checkpoints, for [`ModeGate::take_deferred`].
+    pub(crate) fn admit(&self, activity: Activity) -> io::Result<()> {
+        let mode = self.current();
+        if mode.allows_writes() {
+            return Ok(());
+        }
+        let deferred = mode == EngineMode::Frozen && activity.deferrable();
+        if deferred {
+            let mut pending = self.deferred.lock();
+            match activity {
+                Activity::Compaction => pending.compaction = true,
+                _ => pending.checkpoint = true,
+            }
+        }
+        Err(refusal(mode, activity, deferred))
+    }
+
+    /// Returns and forgets the maintenance deferred by a freeze.
+    pub(crate) fn take_deferred(&self) -> Deferred {
+        std::mem::take(&mut *self.deferred.lock())
+    }
+}
+
+fn refusal(mode: EngineMode, activity: Activity, deferred: bool) -> io::Error {
+    let kind = match mode {
+        EngineMode::Frozen => ErrorKind::WouldBlock,
+        _ => ErrorKind::ReadOnlyFilesystem,
+    };
+    io::Error::new(
+        kind,
+        ModeRefused {
+            mode,
+            activity,
+            deferred,
+        },
+    )
+}
@@ -0,0 +1,49 @@
+//! Secondary ordering of keys by their latest write time, kept when
+//! modification tracking is enabled.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
+
+/// One entry returned by [`CrabKv::modified_since`].
+///
+/// [`CrabKv::modified_since`]: crate::CrabKv::modified_since
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Modification {
+    pub key: String,
+    /// When the key was last written or deleted.
+    pub at: SystemTime,
+    /// Whether that last change removed the key.
+    pub deleted: bool,
+}
+
+/// Each key's latest change, ordered by time.
+#[derive(Debug, Default)]
+pub(crate) struct ModificationIndex {
+    /// Latest change per key, with whether it was a delete.
+    by_time: BTreeMap<(SystemTime, String), bool>,
+    latest: HashMap<String, SystemTime>,
+}
+
+impl ModificationIndex {
+    /// Records that `key` changed at `at`, replacing its earlier change.
+    pub(crate) fn record(&mut self, key: &str, at: SystemTime, deleted: bool) {
+        if let Some(previous) = self.latest.insert(key.to_owned(), at) {
+            self.by_time.remove(&(previous, key.to_owned()));
+        }
+        self.by_time.insert((at, key.to_owned()), deleted);
+    }
+
+    /// Returns up to `limit` keys changed at or after `since`, newest first.
+    pub(crate) fn since(&self, since: SystemTime, limit: usize) -> Vec<Modification> {
+        self.by_time
+            .range((since, String::new())..)
+            .rev()
+            .take(limit)
+            .map(|((at, key), deleted)| Modification {
+                key: key.clone(),
+                at: *at,
+                deleted: *deleted,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,95 @@
+//! Coarse load signal the server uses to shed writes before the engine stalls.
+
+/// How close the engine is to stalling writers, from best to worst.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PressureLevel {
+    Ok,
+    /// Writes still succeed but should slow down.
+    Elevated,
+    /// Writes should be refused until the backlog clears.
+    Critical,
+}
+
+impl PressureLevel {
+    /// Lower-case name used on the wire, e.g. `elevated`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PressureLevel::Ok => "ok",
+            PressureLevel::Elevated => "elevated",
+            PressureLevel::Critical => "critical",
+        }
+    }
+}
+
+/// Readings [`PressureLevel`]s are judged from, as returned by
+/// [`CrabKv::pressure_gauges`](crate::CrabKv::pressure_gauges).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PressureGauges {
+    /// Unflushed writes in the write-back buffer.
+    pub write_buffer: usize,
+    /// Log bytes held by superseded, deleted or expired records that
+    /// compaction has not reclaimed yet.
+    pub stale_bytes: u64,
+    /// Free bytes on the log's filesystem; only read when
+    /// [`min_free_bytes`](crate::CrabKvBuilder::min_free_bytes) is set.
+    pub free_bytes: Option<u64>,
+}
+
+/// Gauge levels at which one [`PressureLevel`] starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PressureLimits {
+    /// Unflushed writes above which the level applies.
+    pub write_buffer: usize,
+    /// Stale log bytes above which the level applies.
+    pub stale_bytes: u64,
+    /// Free disk bytes below which the level applies.
+    pub min_free_bytes: u64,
+}
+
+/// Limits for [`PressureLevel::Elevated`] and [`PressureLevel::Critical`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PressureThresholds {
+    pub elevated: PressureLimits,
+    pub critical: PressureLimits,
+}
+
+impl Default for PressureThresholds {
+    fn default() -> Self {
+        Self {
+            elevated: PressureLimits {
+                write_buffer: 10_000,
+                stale_bytes: 64 * 1024 * 1024,
+                min_free_bytes: 1024 * 1024 * 1024,
+            },
+            critical: PressureLimits {
+                write_buffer: 100_000,
+                stale_bytes: 256 * 1024 * 1024,
+                min_free_bytes: 256 * 1024 * 1024,
+            },
+        }
+    }
+}
+
+impl PressureLimits {
+    /// Returns whether any gauge is past its limit.
+    pub fn reached(&self, gauges: &PressureGauges) -> bool {
+        gauges.write_buffer > self.write_buffer
+            || gauges.stale_bytes > self.stale_bytes
+            || gauges
+                .free_bytes
+                .is_some_and(|free| free < self.min_free_bytes)
+    }
+}
+
+impl PressureThresholds {
+    /// Returns the worst level whose limits `gauges` reach.
+    pub fn level(&self, gauges: &PressureGauges) -> PressureLevel {
+        if self.critical.reached(gauges) {
+            PressureLevel::Critical
+        } else if self.elevated.reached(gauges) {
+            PressureLevel::Elevated
+        } else {
+            PressureLevel::Ok
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Keys compaction gave up on because their log record could not be decoded.
+//!
+//! The list is kept in `quarantine.log` next to `wal.log`, so it survives the
+//! rewrite that drops the damaged records and `crabkv doctor` can report it
+//! without opening the store.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Name of the quarantine file inside the data directory.
+pub const QUARANTINE_FILE: &str = "quarantine.log";
+
+/// A key whose record was unreadable, as returned by
+/// [`CrabKv::quarantined`](crate::CrabKv::quarantined).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuarantinedKey {
+    pub key: String,
+    /// Offset of the damaged record in the log it was found in.
+    pub offset: u64,
+    /// Why the record could not be read.
+    pub error: String,
+}
+
+/// Error payload returned when reading a quarantined key.
+///
+/// Carried inside an [`io::Error`] of kind [`io::ErrorKind::InvalidData`];
+/// match it with `err.get_ref().and_then(|e| e.downcast_ref::<Corrupted>())`.
+/// The key stays unreadable until it is written or deleted again, or
+/// [`CrabKv::drop_quarantined`](crate::CrabKv::drop_quarantined) gives up on it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Corrupted {
+    pub key: String,
+}
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is quarantined: its log record is corrupted",
+            self.key
+        )
+    }
+}
+
+impl std::error::Error for Corrupted {}
+
+impl Corrupted {
+    pub(crate) fn error(key: &str) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            Corrupted {
+                key: key.to_owned(),
+            },
+        )
+    }
+}
+
+/// Quarantined keys of one store, written back to its file when they change.
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    path: PathBuf,
+    keys: BTreeMap<String, QuarantinedKey>,
+    /// Whether `keys` changed since the file was last written.
+    changed: bool,
+}
+
+impl Quarantine {
+    /// Loads the quarantine of the store in `dir`; a missing file is empty.
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        let keys = read(dir)?
+            .into_iter()
+            .map(|entry| (entry.key.clone(), entry))
+            .collect();
+        Ok(Self {
+            path: dir.join(QUARANTINE_FILE),
+            keys,
+            changed: false,
+        })
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub(crate) fn list(&self) -> Vec<QuarantinedKey> {
+        self.keys.values().cloned().collect()
+    }
+
+    pub(crate) fn insert(&mut self, entry: QuarantinedKey) {
+        self.keys.insert(entry.key.clone(), entry);
+        self.changed = true;
+    }
+
+    /// Releases `key` because it was written or deleted again.
+    pub(crate) fn release(&mut self, key: &str) {
+        if self.keys.remove(key).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Empties the quarantine and returns the keys it held.
+    pub(crate) fn clear(&mut self) -> Vec<String> {
+        self.changed |= !self.keys.is_empty();
+        std::mem::take(&mut self.keys).into_keys().collect()
+    }
+
+    /// Moves the file to `dir` and writes it there.
+    pub(crate) fn relocate(&mut self, dir: &Path) -> io::Result<()> {
+        self.path = dir.join(QUARANTINE_FILE);
+        self.changed = true;
+        self.save()
+    }
+
+    /// Writes the file if the list changed, removing it once the list is empty.
+    pub(crate) fn save(&mut self) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        if self.keys.is_empty() {
+            match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        } else {
+            let mut text = String::new();
+            for entry in self.keys.values() {
+                // The key is length-prefixed so it may hold tabs and newlines.
+                text.push_str(&format!(
+                    "{}\t{}\t{}\t{}\n",
+                    entry.offset,
+                    entry.key.len(),
+                    entry.key,
+                    entry.error.replace(['\n', '\r'], " ")
+                ));
+            }
+            let tmp = self.path.with_extension("tmp");
+            fs::write(&tmp, text)?;
+            fs::File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+        }
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Reads the quarantine file of the store in `dir` without opening it.
+pub fn read(dir: &Path) -> io::Result<Vec<QuarantinedKey>> {
+    let path = dir.join(QUARANTINE_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let malformed = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: malformed quarantine entry", path.display()),
+        )
+    };
+    let mut entries = Vec::new();
+    let mut rest = text.as_str();
+    while !rest.is_empty() {
+        let (offset, after) = rest.split_once('\t').ok_or_else(malformed)?;
+        let (key_len, after) = after.split_once('\t').ok_or_else(malformed)?;
+        let key_len: usize = key_len.parse().map_err(|_| malformed())?;
+        let key = after.get(..key_len).ok_or_else(malformed)?;
+        let after = after[key_len..].strip_prefix('\t').ok_or_else(malformed)?;
+        let (error, after) = after.split_once('\n').ok_or_else(malformed)?;
+        entries.push(QuarantinedKey {
+            key: key.to_owned(),
+            offset: offset.parse().map_err(|_| malformed())?,
+            error: error.to_owned(),
+        });
+        rest = after;
+    }
+    Ok(entries)
+}
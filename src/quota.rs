@@ -0,0 +1,226 @@
+//! Per-prefix storage budgets enforced on writes, and the byte and operation
+//! counters billed to the same prefixes.
+
+use parking_lot::Mutex;
+use std::fmt;
+use std::io;
+
+/// Live-byte usage of one registered key prefix, and what was written and
+/// read under it since the engine was opened or its counters were last reset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrefixUsage {
+    pub prefix: String,
+    /// Sum of the record lengths of live keys under the prefix.
+    pub used: u64,
+    /// Configured maximum for `used`.
+    pub limit: u64,
+    /// Key and value bytes of puts, and key bytes of deletes, appended to the
+    /// log, counted before compression.
+    pub bytes_written: u64,
+    /// Value bytes returned by reads, whether from the cache or the log.
+    pub bytes_read: u64,
+    /// Puts and deletes appended to the log; each write of a batch counts.
+    pub writes: u64,
+    /// Keys read, including ones that turned out to be missing.
+    pub reads: u64,
+}
+
+/// Error payload returned when a write would push a prefix over its quota.
+///
+/// Carried inside an [`io::Error`] of kind [`io::ErrorKind::QuotaExceeded`];
+/// recover it with `err.get_ref().and_then(|e| e.downcast_ref::<QuotaExceeded>())`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuotaExceeded {
+    pub prefix: String,
+    /// Usage before the rejected write.
+    pub used: u64,
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "quota exceeded for prefix `{}`: {} of {} bytes used",
+            self.prefix, self.used, self.limit
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl From<QuotaExceeded> for io::Error {
+    fn from(err: QuotaExceeded) -> Self {
+        io::Error::new(io::ErrorKind::QuotaExceeded, err)
+    }
+}
+
+/// Usage counters for every prefix registered with a quota.
+///
+/// A key counts towards each registered prefix it starts with.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PrefixQuotas {
+    usage: Vec<PrefixUsage>,
+}
+
+impl PrefixQuotas {
+    /// Creates counters for the given `(prefix, limit)` pairs with nothing used.
+    pub fn new(limits: &[(String, u64)]) -> Self {
+        Self {
+            usage: limits
+                .iter()
+                .map(|(prefix, limit)| PrefixUsage {
+                    prefix: prefix.clone(),
+                    used: 0,
+                    limit: *limit,
+                    bytes_written: 0,
+                    bytes_read: 0,
+                    writes: 0,
+                    reads: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns `true` when no quota is registered.
+    pub fn is_empty(&self) -> bool {
+        self.usage.is_empty()
+    }
+
+    /// Returns `true` when `key` falls under at least one registered prefix.
+    pub fn covers(&self, key: &str) -> bool {
+        self.usage
+            .iter()
+            .any(|usage| key.starts_with(&usage.prefix))
+    }
+
+    /// Records that `key` now occupies `bytes` more live bytes.
+    pub fn charge(&mut self, key: &str, bytes: u64) {
+        for usage in self.matching(key) {
+            usage.used += bytes;
+        }
+    }
+
+    /// Records that `key` released `bytes` live bytes.
+    pub fn refund(&mut self, key: &str, bytes: u64) {
+        for usage in self.matching(key) {
+            usage.used = usage.used.saturating_sub(bytes);
+        }
+    }
+
+    /// Recomputes usage from scratch given every live key and its record length.
+    pub fn rebuild<'a>(&mut self, live: impl IntoIterator<Item = (&'a str, u64)>) {
+        for usage in &mut self.usage {
+            usage.used = 0;
+        }
+        for (key, bytes) in live {
+            self.charge(key, bytes);
+        }
+    }
+
+    /// Checks that replacing records as described keeps every prefix within budget.
+    ///
+    /// Each change is `(key, old_len, new_len)`, with zero standing for an absent
+    /// record. A prefix already over its quota still accepts writes that shrink it.
+    pub fn check(&self, changes: &[(&str, u64, u64)]) -> Result<(), QuotaExceeded> {
+        for usage in &self.usage {
+            let (mut added, mut released) = (0u64, 0u64);
+            for (key, old_len, new_len) in changes {
+                if key.starts_with(&usage.prefix) {
+                    added += new_len;
+                    released += old_len;
+                }
+            }
+            if added > released && usage.used + added - released > usage.limit {
+                return Err(QuotaExceeded {
+                    prefix: usage.prefix.clone(),
+                    used: usage.used,
+                    limit: usage.limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a snapshot of every registered prefix's usage.
+    pub fn usage(&self) -> Vec<PrefixUsage> {
+        self.usage.clone()
+    }
+
+    fn matching<'a>(&'a mut self, key: &'a str) -> impl Iterator<Item = &'a mut PrefixUsage> {
+        self.usage
+            .iter_mut()
+            .filter(move |usage| key.starts_with(&usage.prefix))
+    }
+}
+
+/// Bytes and operations billed to each prefix registered with a quota.
+///
+/// One lock guards every counter, so a reset reads and clears them all at
+/// one point in the stream of operations. Nothing is persisted.
+#[derive(Debug, Default)]
+pub(crate) struct PrefixCounters {
+    prefixes: Vec<String>,
+    counts: Mutex<Vec<Counts>>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counts {
+    bytes_written: u64,
+    bytes_read: u64,
+    writes: u64,
+    reads: u64,
+}
+
+impl PrefixCounters {
+    /// Creates zeroed counters for the prefixes of the given `(prefix, limit)` pairs.
+    pub fn new(limits: &[(String, u64)]) -> Self {
+        Self {
+            prefixes: limits.iter().map(|(prefix, _)| prefix.clone()).collect(),
+            counts: Mutex::new(vec![Counts::default(); limits.len()]),
+        }
+    }
+
+    /// Bills a put or delete of `key` that appended `bytes` to the log.
+    pub fn record_write(&self, key: &str, bytes: u64) {
+        self.record(key, |counts| {
+            counts.bytes_written += bytes;
+            counts.writes += 1;
+        });
+    }
+
+    /// Bills a read of `key` that returned `bytes` of value.
+    pub fn record_read(&self, key: &str, bytes: u64) {
+        self.record(key, |counts| {
+            counts.bytes_read += bytes;
+            counts.reads += 1;
+        });
+    }
+
+    /// Copies the counters into `usage`, which lists the same prefixes in
+    /// the same order, and zeroes them when `reset` is set.
+    pub fn fill(&self, usage: &mut [PrefixUsage], reset: bool) {
+        let mut counts = self.counts.lock();
+        for (usage, counts) in usage.iter_mut().zip(counts.iter_mut()) {
+            usage.bytes_written = counts.bytes_written;
+            usage.bytes_read = counts.bytes_read;
+            usage.writes = counts.writes;
+            usage.reads = counts.reads;
+            if reset {
+                *counts = Counts::default();
+            }
+        }
+    }
+
+    fn record(&self, key: &str, bill: impl Fn(&mut Counts)) {
+        if !self.prefixes.iter().any(|prefix| key.starts_with(prefix)) {
+            return;
+        }
+        let mut counts = self.counts.lock();
+        for (prefix, counts) in self.prefixes.iter().zip(counts.iter_mut()) {
+            if key.starts_with(prefix) {
+                bill(counts);
+            }
+        }
+    }
+}
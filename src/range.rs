@@ -0,0 +1,71 @@
+//! Sorted iteration over a range of keys.
+
+use crate::engine::CrabKv;
+use std::collections::VecDeque;
+use std::io;
+use std::ops::Bound;
+
+/// Keys read under one hold of the engine lock.
+pub(crate) const RANGE_CHUNK: usize = 256;
+
+/// One chunk read by [`CrabKv::range_chunk`].
+pub(crate) struct RangeChunk {
+    pub(crate) pairs: Vec<(String, String)>,
+    /// Last key scanned when the chunk was full, to resume after.
+    pub(crate) resume_after: Option<String>,
+}
+
+/// Iterator over the live key/value pairs between two bounds, in key order,
+/// returned by [`CrabKv::range`].
+///
+/// Pairs are read a chunk at a time, taking the engine's read lock once per
+/// chunk, so writers are never held off for a whole scan. Each chunk reflects
+/// the store when it was read: a key written behind the scan's position is not
+/// revisited, and one written ahead of it is picked up. Use a
+/// [`ReadView`](crate::ReadView) to check keys against one point in time.
+pub struct KeyRange {
+    engine: CrabKv,
+    lower: Bound<String>,
+    upper: Bound<String>,
+    pending: VecDeque<(String, String)>,
+    done: bool,
+}
+
+impl KeyRange {
+    pub(crate) fn new(engine: CrabKv, lower: Bound<String>, upper: Bound<String>) -> Self {
+        Self {
+            engine,
+            lower,
+            upper,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl Iterator for KeyRange {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.done {
+            let bounds = (
+                self.lower.as_ref().map(String::as_str),
+                self.upper.as_ref().map(String::as_str),
+            );
+            match self.engine.range_chunk(bounds, RANGE_CHUNK) {
+                Ok(chunk) => {
+                    match chunk.resume_after {
+                        Some(key) => self.lower = Bound::Excluded(key),
+                        None => self.done = true,
+                    }
+                    self.pending.extend(chunk.pairs);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,342 @@
+//! Background verification of every record in the log.
+//!
+//! A scrub reads the installed generation from start to end and decodes each
+//! record, so damage is found before a read or a compaction trips over it.
+//! The log carries no checksums: what a scrub catches is a record that no
+//! longer decodes, such as a value that is not valid UTF-8, a compressed value
+//! Snappy rejects, or lengths running past the end of the file. A flipped bit
+//! that still decodes goes unnoticed.
+//!
+//! Progress is kept in `scrub.progress` next to `wal.log`, so a pass cut short
+//! by a restart resumes where it stopped, and the next one is due an interval
+//! after the last one finished, whichever run finished it.
+
+use crate::internals::wal::Wal;
+use parking_lot::Mutex;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the scrub progress file inside the data directory.
+pub const SCRUB_PROGRESS_FILE: &str = "scrub.progress";
+
+/// Log bytes checked per step; a background scrub pauses between steps to
+/// keep to its rate.
+pub(crate) const SCRUB_STEP_BYTES: u64 = 256 * 1024;
+
+/// Findings a [`ScrubReport`] lists; further damage is only counted.
+pub const SCRUB_FINDINGS_KEPT: usize = 64;
+
+/// Log bytes checked between two saves of the progress file.
+const SAVE_EVERY_BYTES: u64 = 4 * 1024 * 1024;
+
+/// A record a scrub could not decode.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScrubFinding {
+    /// Offset of the record in the generation scrubbed.
+    pub offset: u64,
+    /// Why the record did not decode.
+    pub error: String,
+    /// Live keys whose value the record held, now quarantined.
+    pub keys: Vec<String>,
+}
+
+/// One pass of a scrub over the whole log, as returned by
+/// [`CrabKv::scrub_now`](crate::CrabKv::scrub_now).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScrubReport {
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    /// Log generation the pass read; a compaction meanwhile starts it over.
+    pub generation: u64,
+    pub bytes_checked: u64,
+    /// Records decoded, batch headers included.
+    pub records_checked: u64,
+    pub damaged_records: u64,
+    /// Keys moved to the quarantine because their record was damaged.
+    pub quarantined_keys: u64,
+    /// The first damaged records found, at most [`SCRUB_FINDINGS_KEPT`]. A
+    /// pass resumed after a restart lists only those found since.
+    pub findings: Vec<ScrubFinding>,
+}
+
+impl ScrubReport {
+    /// Whether every record decoded.
+    pub fn is_clean(&self) -> bool {
+        self.damaged_records == 0
+    }
+}
+
+/// A pass under way.
+#[derive(Clone, Debug)]
+struct Pass {
+    generation: u64,
+    /// Where the next step starts.
+    offset: u64,
+    started_at: SystemTime,
+    bytes: u64,
+    records: u64,
+    damaged: u64,
+    quarantined: u64,
+    findings: Vec<ScrubFinding>,
+}
+
+impl Pass {
+    fn new(generation: u64, started_at: SystemTime) -> Self {
+        Self {
+            generation,
+            offset: 0,
+            started_at,
+            bytes: 0,
+            records: 0,
+            damaged: 0,
+            quarantined: 0,
+            findings: Vec::new(),
+        }
+    }
+
+    fn finish(self, finished_at: SystemTime) -> ScrubReport {
+        ScrubReport {
+            started_at: self.started_at,
+            finished_at,
+            generation: self.generation,
+            bytes_checked: self.bytes,
+            records_checked: self.records,
+            damaged_records: self.damaged,
+            quarantined_keys: self.quarantined,
+            findings: self.findings,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    pass: Option<Pass>,
+    /// When the latest pass finished, in this run or an earlier one.
+    last_finished: Option<SystemTime>,
+    /// Bytes checked since the file was last saved.
+    unsaved: u64,
+}
+
+/// What one [`Scrubber::step`] did.
+#[derive(Debug)]
+pub(crate) struct Stepped {
+    /// Log bytes read.
+    pub(crate) bytes: u64,
+    /// The report, when the step reached the end of the log.
+    pub(crate) finished: Option<ScrubReport>,
+}
+
+/// Scrub progress shared by every handle and the scrub thread.
+#[derive(Debug)]
+pub(crate) struct Scrubber {
+    /// Held for the whole of a step, so two scrubs never check the same
+    /// stretch twice.
+    progress: Mutex<Progress>,
+    /// Kept apart from `progress` so stats never wait on a step.
+    last: Mutex<Option<ScrubReport>>,
+    /// Where the progress file is saved; moved by a migration.
+    path: Mutex<PathBuf>,
+}
+
+impl Scrubber {
+    /// Loads the progress saved in `dir`. A file that does not parse is
+    /// ignored, and the next pass starts from the beginning.
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(SCRUB_PROGRESS_FILE);
+        let progress = match read(&path) {
+            Ok(progress) => progress,
+            Err(err) if err.kind() == ErrorKind::InvalidData => Progress::default(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            progress: Mutex::new(progress),
+            last: Mutex::new(None),
+            path: Mutex::new(path),
+        })
+    }
+
+    /// The report of the latest pass finished since open.
+    pub(crate) fn last(&self) -> Option<ScrubReport> {
+        self.last.lock().clone()
+    }
+
+    /// Time until the next pass is due, zero while one is under way.
+    pub(crate) fn due_in(&self, interval: Duration, now: SystemTime) -> Duration {
+        let progress = self.progress.lock();
+        match (&progress.pass, progress.last_finished) {
+            (None, Some(finished)) => {
+                let since = now.duration_since(finished).unwrap_or_default();
+                interval.saturating_sub(since)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Checks up to `max_bytes` of `wal` for the pass under way, starting one
+    /// if `start` is set, and returns `None` when there was none to continue.
+    ///
+    /// `settle` quarantines what each damaged record held; it gets the
+    /// generation scrubbed and fills in the finding's keys. The progress file
+    /// is saved now and then when `save` is set.
+    pub(crate) fn step(
+        &self,
+        wal: &Wal,
+        start: bool,
+        max_bytes: u64,
+        now: SystemTime,
+        save: bool,
+        mut settle: impl FnMut(u64, &mut ScrubFinding),
+    ) -> io::Result<Option<Stepped>> {
+        let mut progress = self.progress.lock();
+        if progress.pass.is_none() {
+            if !start {
+                return Ok(None);
+            }
+            progress.pass = Some(Pass::new(wal.generation(), now));
+        }
+        let pass = progress.pass.as_mut().expect("a pass is under way");
+        let slice = wal.scrub_from(pass.generation, pass.offset, max_bytes)?;
+        if slice.generation != pass.generation || slice.start != pass.offset {
+            *pass = Pass::new(slice.generation, now);
+        }
+        let bytes = slice.next_offset - slice.start;
+        pass.offset = slice.next_offset;
+        pass.bytes += bytes;
+        pass.records += slice.records;
+        for (offset, error) in slice.damaged {
+            let mut finding = ScrubFinding {
+                offset,
+                error,
+                keys: Vec::new(),
+            };
+            settle(slice.generation, &mut finding);
+            pass.damaged += 1;
+            pass.quarantined += finding.keys.len() as u64;
+            if pass.findings.len() < SCRUB_FINDINGS_KEPT {
+                pass.findings.push(finding);
+            }
+        }
+        progress.unsaved += bytes;
+
+        let finished = if slice.next_offset >= slice.end {
+            let report = progress
+                .pass
+                .take()
+                .expect("a pass is under way")
+                .finish(now);
+            progress.last_finished = Some(now);
+            *self.last.lock() = Some(report.clone());
+            Some(report)
+        } else {
+            None
+        };
+        if save && (finished.is_some() || progress.unsaved >= SAVE_EVERY_BYTES) {
+            write(&self.path.lock(), &progress)?;
+            progress.unsaved = 0;
+        }
+        Ok(Some(Stepped { bytes, finished }))
+    }
+
+    /// Saves progress not yet in the file, so a pass resumes after a close.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let mut progress = self.progress.lock();
+        if progress.unsaved == 0 {
+            return Ok(());
+        }
+        write(&self.path.lock(), &progress)?;
+        progress.unsaved = 0;
+        Ok(())
+    }
+
+    /// Points the progress file at `dir`, where the next save writes it.
+    ///
+    /// Called under the state lock, which a step may be waiting for while it
+    /// holds the progress, so the progress is left alone.
+    pub(crate) fn relocate(&self, dir: &Path) {
+        *self.path.lock() = dir.join(SCRUB_PROGRESS_FILE);
+    }
+}
+
+fn millis(at: SystemTime) -> u128 {
+    at.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis())
+}
+
+fn from_millis(millis: &str) -> Option<SystemTime> {
+    UNIX_EPOCH.checked_add(Duration::from_millis(millis.parse().ok()?))
+}
+
+/// Writes `pass <generation> <offset> <started> <bytes> <records> <damaged>
+/// <quarantined>` for a pass under way and `finished <millis>` for the last
+/// one done, either line left out when there is none.
+fn write(path: &Path, progress: &Progress) -> io::Result<()> {
+    let mut text = String::new();
+    if let Some(pass) = &progress.pass {
+        text.push_str(&format!(
+            "pass {} {} {} {} {} {} {}\n",
+            pass.generation,
+            pass.offset,
+            millis(pass.started_at),
+            pass.bytes,
+            pass.records,
+            pass.damaged,
+            pass.quarantined
+        ));
+    }
+    if let Some(finished) = progress.last_finished {
+        text.push_str(&format!("finished {}\n", millis(finished)));
+    }
+    // Losing the latest save only repeats a stretch, so it is not synced.
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+fn read(path: &Path) -> io::Result<Progress> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Progress::default()),
+        Err(err) => return Err(err),
+    };
+    let malformed = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: malformed scrub progress", path.display()),
+        )
+    };
+    let mut progress = Progress::default();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            [
+                "pass",
+                generation,
+                offset,
+                started,
+                bytes,
+                records,
+                damaged,
+                quarantined,
+            ] => {
+                let number = |field: &str| field.parse::<u64>().map_err(|_| malformed());
+                progress.pass = Some(Pass {
+                    generation: number(generation)?,
+                    offset: number(offset)?,
+                    started_at: from_millis(started).ok_or_else(malformed)?,
+                    bytes: number(bytes)?,
+                    records: number(records)?,
+                    damaged: number(damaged)?,
+                    quarantined: number(quarantined)?,
+                    findings: Vec::new(),
+                });
+            }
+            ["finished", finished] => {
+                progress.last_finished = Some(from_millis(finished).ok_or_else(malformed)?);
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(progress)
+}
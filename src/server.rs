@@ -1,161 +1,1725 @@
 //! Minimal TCP front-end exposing the CrabKv API.
 
-use crate::engine::CrabKv;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use crate::config::{ServerConfig, parse_duration};
+use crate::context::OpContext;
+use crate::diagnostics::{self, Status};
+use crate::engine::{CompactionOutcome, CrabKv, DurabilityMarker};
+use crate::events::EVENTS_SHOWN;
+use crate::idempotency::WriteOptions;
+use crate::manifest::EpochMismatch;
+use crate::mode::EngineMode;
+use crate::pressure::PressureLevel;
+use crate::scrub::ScrubReport;
+use crate::version::build_info;
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, IoSlice, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::str::FromStr;
-use std::thread;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const HELP: &str =
-    "Commands: PUT <key> <value> [ttl=<seconds>], GET <key>, DELETE <key>, COMPACT, HELP";
+/// Buffered response bytes that force a flush even while commands are pending.
+const FLUSH_THRESHOLD: usize = 32 * 1024;
+
+/// Values at least this long skip the output buffer and go straight to the
+/// socket after their GETRAW header.
+const DIRECT_VALUE_BYTES: usize = 64 * 1024;
+
+/// Pending connections the kernel queues per listener, as `TcpListener::bind` uses.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// How often a subscribed connection checks whether the server is stopping.
+const SUBSCRIBE_POLL: Duration = Duration::from_millis(100);
+
+/// Least time between tries at writing to a socket that is full.
+const FULL_SOCKET_PAUSE: Duration = Duration::from_millis(5);
+
+/// How long a subscribed connection waits on each look for its client hanging up.
+const HANGUP_POLL: Duration = Duration::from_millis(1);
+
+/// How often an idle connection wakes up to check whether the server is draining.
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// How often [`ServerHandle::drain`] checks whether every connection has closed.
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// Maximum number of pairs returned by a single SCANKV.
+const SCANKV_LIMIT: usize = 1_000;
+
+/// How long WAITDURABLE keeps retrying when no timeout is given.
+const WAITDURABLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Description of a server command; parsing, dispatch, HELP and COMMANDS are all driven from [`COMMANDS`].
+struct CommandSpec {
+    name: &'static str,
+    args: &'static str,
+    min_args: usize,
+    max_args: usize,
+    summary: &'static str,
+    parse: fn(&[&str]) -> Option<Command>,
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "PUT",
+        args: "<key> <value> [ttl=<duration>] [idem=<token>]",
+        min_args: 2,
+        max_args: 4,
+        summary: "Store a value, optionally expiring after a duration such as 90, 10m, or 2h; a repeated idem token is acknowledged without writing again",
+        parse: |args| {
+            let (ttl, idem) = split_write_options(&args[2..])?;
+            Some(Command::Put {
+                key: args[0].to_owned(),
+                value: args[1].to_owned(),
+                ttl,
+                idem,
+            })
+        },
+    },
+    CommandSpec {
+        name: "GET",
+        args: "<key>",
+        min_args: 1,
+        max_args: 1,
+        summary: "Fetch the value stored under a key",
+        parse: |args| {
+            Some(Command::Get {
+                key: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "EXISTS",
+        args: "<key>",
+        min_args: 1,
+        max_args: 1,
+        summary: "Answer 1 if a key holds a live value and 0 otherwise, without reading the value",
+        parse: |args| {
+            Some(Command::Exists {
+                key: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "GETRAW",
+        args: "<key>",
+        min_args: 1,
+        max_args: 1,
+        summary: "Fetch a value as VALUE <bytes> followed by exactly that many bytes and a newline",
+        parse: |args| {
+            Some(Command::GetRaw {
+                key: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "GETRANGE",
+        args: "<key> <start> <len>",
+        min_args: 3,
+        max_args: 3,
+        summary: "Fetch up to <len> characters of a value starting at character <start>",
+        parse: |args| {
+            Some(Command::GetRange {
+                key: args[0].to_owned(),
+                start: args[1].to_owned(),
+                len: args[2].to_owned(),
+            })
+        },
+    },
+    #[cfg(feature = "json")]
+    CommandSpec {
+        name: "GETJSON",
+        args: "<key> <pointer>",
+        min_args: 2,
+        max_args: 2,
+        summary: "Fetch the part of a JSON value selected by an RFC 6901 pointer",
+        parse: |args| {
+            Some(Command::GetJson {
+                key: args[0].to_owned(),
+                pointer: args[1].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "DELETE",
+        args: "<key> [idem=<token>]",
+        min_args: 1,
+        max_args: 2,
+        summary: "Remove a key; a repeated idem token is acknowledged without deleting again",
+        parse: |args| match split_write_options(&args[1..])? {
+            (None, idem) => Some(Command::Delete {
+                key: args[0].to_owned(),
+                idem,
+            }),
+            (Some(_), _) => None,
+        },
+    },
+    CommandSpec {
+        name: "POP",
+        args: "<key>",
+        min_args: 1,
+        max_args: 1,
+        summary: "Remove a key and return the value it held; of clients popping the same key, only one gets it",
+        parse: |args| {
+            Some(Command::Pop {
+                key: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "INCR",
+        args: "<key> [delta]",
+        min_args: 1,
+        max_args: 2,
+        summary: "Add delta (1 by default, may be negative) to an integer value and return the result; a missing key counts as 0",
+        parse: |args| {
+            Some(Command::Incr {
+                key: args[0].to_owned(),
+                delta: args.get(1).map(|delta| delta.to_string()),
+            })
+        },
+    },
+    CommandSpec {
+        name: "SCANKV",
+        args: "<glob>",
+        min_args: 1,
+        max_args: 1,
+        summary: "List key/value pairs whose key matches a glob pattern",
+        parse: |args| {
+            Some(Command::ScanKv {
+                pattern: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "STATS",
+        args: "[force=true]",
+        min_args: 0,
+        max_args: 1,
+        summary: "Report engine counters and size histograms; force=true computes histograms for large indexes",
+        parse: |args| {
+            Some(Command::Stats {
+                force: args.first().map(|token| token.to_string()),
+            })
+        },
+    },
+    CommandSpec {
+        name: "HEALTH",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "Report OK, or DEGRADED with a reason when the compaction worker or data directory is unhealthy, with the mode unless normal",
+        parse: |_| Some(Command::Health),
+    },
+    CommandSpec {
+        name: "COMPACT",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "Rewrite the log without stale records",
+        parse: |_| Some(Command::Compact),
+    },
+    CommandSpec {
+        name: "FLUSH",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "Write buffered writes of the write-back cache to the log",
+        parse: |_| Some(Command::Flush),
+    },
+    CommandSpec {
+        name: "SCRUB",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "Check every log record now, quarantining keys whose record is damaged",
+        parse: |_| Some(Command::Scrub),
+    },
+    CommandSpec {
+        name: "MIGRATE",
+        args: "<path>",
+        min_args: 1,
+        max_args: 1,
+        summary: "Move the data directory to <path> on the server's host while serving",
+        parse: |args| {
+            Some(Command::Migrate {
+                path: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "EPOCH",
+        args: "[expected]",
+        min_args: 0,
+        max_args: 1,
+        summary: "Report the store's fencing epoch; given the expected epoch, pin this connection to it so every later command fails with ERR EPOCH once a restore, clear or migration moves the store on",
+        parse: |args| {
+            Some(Command::Epoch {
+                expected: args.first().map(|epoch| epoch.to_string()),
+            })
+        },
+    },
+    CommandSpec {
+        name: "SUBSCRIBE",
+        args: "expired",
+        min_args: 1,
+        max_args: 1,
+        summary: "Stream an EXPIRED <key> line for every key that expires",
+        parse: |args| {
+            Some(Command::Subscribe {
+                channel: args[0].to_owned(),
+            })
+        },
+    },
+    CommandSpec {
+        name: "LASTWRITE",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "Show the durability token of this connection's latest PUT or DELETE",
+        parse: |_| Some(Command::LastWrite),
+    },
+    CommandSpec {
+        name: "WAITDURABLE",
+        args: "<token> [timeout_ms]",
+        min_args: 1,
+        max_args: 2,
+        summary: "Block until the write behind a token is flushed and synced to disk",
+        parse: |args| {
+            Some(Command::WaitDurable {
+                token: args[0].to_owned(),
+                timeout_ms: args.get(1).map(|ms| ms.to_string()),
+            })
+        },
+    },
+    CommandSpec {
+        name: "VERSION",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "Report the server's version, git commit, build date, and cargo features",
+        parse: |_| Some(Command::Version),
+    },
+    CommandSpec {
+        name: "COMMANDS",
+        args: "",
+        min_args: 0,
+        max_args: 0,
+        summary: "List every command with its arity and description",
+        parse: |_| Some(Command::Commands),
+    },
+    CommandSpec {
+        name: "HELP",
+        args: "[command]",
+        min_args: 0,
+        max_args: 1,
+        summary: "Show the command summary or detailed usage for one command",
+        parse: |args| {
+            Some(Command::Help {
+                command: args.first().map(|name| name.to_string()),
+            })
+        },
+    },
+];
 
 /// Starts a blocking TCP server handling text commands.
 pub fn run(addr: &str, engine: CrabKv) -> io::Result<()> {
-    let listener = TcpListener::bind(addr)?;
-    println!("CrabKv TCP server listening on {addr}");
-    for stream in listener.incoming() {
-        let stream = stream?;
+    run_with_config(addr, engine, ServerConfig::default())
+}
+
+/// Starts a blocking TCP server using the provided worker pool settings.
+///
+/// `addr` may list several comma-separated addresses, as for [`bind`].
+pub fn run_with_config(addr: &str, engine: CrabKv, config: ServerConfig) -> io::Result<()> {
+    let listeners = bind(addr, &config)?;
+    for listener in &listeners {
+        println!("CrabKv TCP server listening on {}", listener.local_addr()?);
+    }
+    serve_all(listeners, engine, config)
+}
+
+/// Serves connections accepted on `listener` with a fixed pool of worker threads.
+///
+/// Accepted streams wait in a bounded queue; when it is full the connection is
+/// told the server is busy and closed.
+pub fn serve(listener: TcpListener, engine: CrabKv, config: ServerConfig) -> io::Result<()> {
+    serve_all(vec![listener], engine, config)
+}
+
+/// Like [`serve`], but accepts on every listener into the same worker pool.
+pub fn serve_all(
+    listeners: Vec<TcpListener>,
+    engine: CrabKv,
+    config: ServerConfig,
+) -> io::Result<()> {
+    let shared = Arc::new(Shared::new(&config, listeners.len()));
+    accept_loop(listeners, engine, config, &shared)
+}
+
+/// Binds a listener for each comma-separated address in `addrs`, such as
+/// `0.0.0.0:4000,[::]:4000` for both IPv4 and IPv6.
+///
+/// Listeners set `SO_REUSEADDR` on Unix, so a restarted server can bind again
+/// while connections from the previous one linger in `TIME_WAIT`, and
+/// `SO_REUSEPORT` when [`ServerConfig::reuse_port`] is set. IPv6 listeners
+/// only accept IPv6, so the two families can share a port. A name resolving to
+/// several addresses binds the first that works. Errors name the address and
+/// suggest a fix.
+pub fn bind(addrs: &str, config: &ServerConfig) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for addr in addrs.split(',').map(str::trim) {
+        if addr.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("empty address in `{addrs}`"),
+            ));
+        }
+        let resolved = addr
+            .to_socket_addrs()
+            .map_err(|err| io::Error::new(err.kind(), format!("cannot resolve {addr}: {err}")))?;
+        let mut last_err = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{addr} resolved to no addresses"),
+        );
+        let mut bound = None;
+        for socket_addr in resolved {
+            match bind_one(socket_addr, config) {
+                Ok(listener) => {
+                    bound = Some(listener);
+                    break;
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        listeners.push(bound.ok_or(last_err)?);
+    }
+    Ok(listeners)
+}
+
+fn bind_one(addr: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    let bind = || {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // On Windows SO_REUSEADDR lets another process take over a bound port,
+        // and a port in TIME_WAIT can be rebound anyway, so it stays off there.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if config.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        Ok(TcpListener::from(socket))
+    };
+    bind().map_err(|err: io::Error| {
+        let hint = match err.kind() {
+            io::ErrorKind::AddrInUse => {
+                "; another process is listening there, stop it or choose another --addr"
+            }
+            io::ErrorKind::AddrNotAvailable => "; the address is not assigned to this host",
+            io::ErrorKind::PermissionDenied => "; ports below 1024 need elevated privileges",
+            _ => "",
+        };
+        io::Error::new(err.kind(), format!("cannot bind {addr}: {err}{hint}"))
+    })
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+/// Starts the server on background threads and returns a handle to stop it.
+///
+/// `addr` may list several comma-separated addresses, as for [`bind`].
+/// Binding to port 0 picks a free port, reported by [`ServerHandle::local_addr`].
+pub fn spawn(addr: &str, engine: CrabKv, config: ServerConfig) -> io::Result<ServerHandle> {
+    let listeners = bind(addr, &config)?;
+    spawn_on(listeners, engine, config)
+}
+
+/// Like [`spawn`], but serves listeners the caller already holds, such as
+/// ones inherited from a previous server process during a warm restart.
+pub fn spawn_on(
+    listeners: Vec<TcpListener>,
+    engine: CrabKv,
+    config: ServerConfig,
+) -> io::Result<ServerHandle> {
+    let local_addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<io::Result<Vec<_>>>()?;
+    let shared = Arc::new(Shared::new(&config, listeners.len()));
+    let acceptor = {
+        let shared = Arc::clone(&shared);
         let engine = engine.clone();
-        thread::spawn(move || {
-            if let Err(err) = handle_client(stream, engine) {
-                eprintln!("client error: {err}");
+        thread::spawn(move || accept_loop(listeners, engine, config, &shared))
+    };
+    Ok(ServerHandle {
+        local_addrs,
+        engine,
+        shared,
+        acceptor: Some(acceptor),
+    })
+}
+
+/// Running server started by [`spawn`].
+///
+/// Dropping the handle shuts the server down.
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    engine: CrabKv,
+    shared: Arc<Shared>,
+    acceptor: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl ServerHandle {
+    /// Returns the address the server is listening on, the first one when it
+    /// was given several.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Returns every address the server is listening on, in the order given.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Stops accepting connections, closes open ones and joins every server thread.
+    ///
+    /// Returns the error that stopped the accept loop, if any.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    /// Closes the listener but keeps serving open connections for up to `timeout`,
+    /// then stops the server and makes every accepted write durable.
+    ///
+    /// Each connection is sent a `GOAWAY` line once it is between commands, telling
+    /// the client to finish up and reconnect elsewhere. Connections still open when
+    /// `timeout` elapses are closed as by [`ServerHandle::shutdown`].
+    pub fn drain(mut self, timeout: Duration) -> io::Result<()> {
+        if self.acceptor.is_none() {
+            return Ok(());
+        }
+        self.shared.draining.store(true, Ordering::SeqCst);
+        self.wake_acceptor();
+        let deadline = Instant::now() + timeout;
+        while !self.shared.is_idle() && Instant::now() < deadline {
+            thread::sleep(DRAIN_POLL);
+        }
+        self.stop()?;
+
+        let marker = self.engine.durability_marker()?;
+        if !self.engine.wait_durable(marker, WAITDURABLE_TIMEOUT)? {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "writes not yet durable after draining",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Drains like [`ServerHandle::drain`] while another server takes over the
+    /// same listening sockets, as in a warm restart.
+    ///
+    /// The listeners stay open in the other server, so clients that connect
+    /// during the drain wait in the kernel's backlog for it instead of being
+    /// refused. Those this server had already queued before it stopped
+    /// accepting are served here and sent `GOAWAY`. The other server should
+    /// only start accepting, and open the store, once this returns.
+    pub fn hand_off(self, timeout: Duration) -> io::Result<()> {
+        self.shared.handing_off.store(true, Ordering::SeqCst);
+        self.drain(timeout)
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let Some(acceptor) = self.acceptor.take() else {
+            return Ok(());
+        };
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        for connection in self.shared.connections.lock().values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        self.wake_acceptor();
+        acceptor
+            .join()
+            .map_err(|_| io::Error::other("server thread panicked"))?
+    }
+
+    /// Connects to each listener so blocked accept loops notice a raised flag.
+    ///
+    /// Each wake-up connection is recorded first, so that while handing off an
+    /// accept loop can tell it from a client.
+    fn wake_acceptor(&self) {
+        if self.shared.listening.load(Ordering::SeqCst) == 0 {
+            // A second wake-up would land in the next server's backlog.
+            return;
+        }
+        for &addr in &self.local_addrs {
+            let mut wake = addr;
+            if wake.ip().is_unspecified() {
+                wake.set_ip(match wake {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
             }
-        });
+            let _ = self.connect_waker(wake);
+        }
+    }
+
+    fn connect_waker(&self, addr: SocketAddr) -> io::Result<()> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.bind(&SocketAddr::new(addr.ip(), 0).into())?;
+        if let Some(local) = socket.local_addr()?.as_socket() {
+            self.shared.wakers.lock().push(local);
+        }
+        socket.connect(&addr.into())
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// State shared between a [`ServerHandle`] and the threads it stops.
+#[derive(Default)]
+struct Shared {
+    config: ServerConfig,
+    stopping: AtomicBool,
+    /// Raised by [`ServerHandle::drain`]: no new connections, open ones are told to leave.
+    draining: AtomicBool,
+    /// Raised by [`ServerHandle::hand_off`]: the listeners outlive this server.
+    handing_off: AtomicBool,
+    /// Accept loops still running.
+    listening: AtomicUsize,
+    /// Accepted connections queued for a worker but not yet registered.
+    pending: AtomicUsize,
+    /// Local addresses of the connections [`ServerHandle::wake_acceptor`] made.
+    wakers: Mutex<Vec<SocketAddr>>,
+    /// Open connections by id, so shutdown can close them under their workers.
+    connections: Mutex<HashMap<u64, Connection>>,
+    next_id: AtomicU64,
+    /// Subscribed connections holding a place under `max_subscribers`.
+    subscribed: AtomicUsize,
+    /// Threads streaming events to subscribed connections, joined on shutdown.
+    subscribers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shared {
+    fn new(config: &ServerConfig, listeners: usize) -> Self {
+        Self {
+            config: config.clone(),
+            listening: AtomicUsize::new(listeners),
+            ..Self::default()
+        }
+    }
+
+    /// Whether no accept loop is running and no connection is queued or open.
+    fn is_idle(&self) -> bool {
+        self.listening.load(Ordering::SeqCst) == 0
+            && self.pending.load(Ordering::SeqCst) == 0
+            && self.connections.lock().is_empty()
+    }
+
+    fn is_waker(&self, stream: &TcpStream) -> bool {
+        stream
+            .peer_addr()
+            .is_ok_and(|peer| self.wakers.lock().contains(&peer))
+    }
+
+    /// Returns the response bytes queued across all connections and the most
+    /// queued for any one of them.
+    fn output_bytes(&self) -> (usize, usize) {
+        self.connections
+            .lock()
+            .values()
+            .map(|connection| connection.output.load(Ordering::Relaxed))
+            .fold((0, 0), |(total, max), bytes| {
+                (total + bytes, max.max(bytes))
+            })
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Response bytes queued but not yet accepted by the socket.
+    output: Arc<AtomicUsize>,
+}
+
+fn accept_loop(
+    listeners: Vec<TcpListener>,
+    engine: CrabKv,
+    config: ServerConfig,
+    shared: &Arc<Shared>,
+) -> io::Result<()> {
+    let (tx, rx) = mpsc::sync_channel::<TcpStream>(config.queue_depth);
+    let rx = Arc::new(Mutex::new(rx));
+    let workers: Vec<_> = (0..config.workers.get())
+        .map(|_| {
+            let rx = Arc::clone(&rx);
+            let engine = engine.clone();
+            let shared = Arc::clone(shared);
+            thread::spawn(move || {
+                loop {
+                    let next = rx.lock().recv();
+                    let Ok(stream) = next else {
+                        break;
+                    };
+                    let limit = config.max_output_bytes;
+                    if let Err(err) = handle_client(stream, &engine, &shared, limit) {
+                        eprintln!("client error: {err}");
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let acceptors: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let tx = tx.clone();
+            let shared = Arc::clone(shared);
+            thread::spawn(move || accept_from(listener, &tx, &shared))
+        })
+        .collect();
+    drop(tx);
+
+    // Workers stop once every acceptor has dropped its sender.
+    let mut result = Ok(());
+    for acceptor in acceptors {
+        let accepted = acceptor
+            .join()
+            .map_err(|_| io::Error::other("server thread panicked"))
+            .and_then(|accepted| accepted);
+        if result.is_ok() {
+            result = accepted;
+        }
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+    // With the workers gone no more subscribers start.
+    for subscriber in std::mem::take(&mut *shared.subscribers.lock()) {
+        let _ = subscriber.join();
+    }
+    result
+}
+
+/// Queues connections accepted on `listener` for the workers until the
+/// server stops or drains, or accepting fails.
+fn accept_from(
+    listener: TcpListener,
+    tx: &mpsc::SyncSender<TcpStream>,
+    shared: &Shared,
+) -> io::Result<()> {
+    let result = accept_until_stopped(&listener, tx, shared);
+    shared.listening.fetch_sub(1, Ordering::SeqCst);
+    // Returning closes the listener before the workers finish open
+    // connections, so a draining server refuses new clients instead of
+    // leaving them in the backlog. A server handing off closes only its own
+    // handle on it.
+    result
+}
+
+fn accept_until_stopped(
+    listener: &TcpListener,
+    tx: &mpsc::SyncSender<TcpStream>,
+    shared: &Shared,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        if shared.stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        if shared.draining.load(Ordering::SeqCst) {
+            if !shared.handing_off.load(Ordering::SeqCst) {
+                break;
+            }
+            // Clients queued ahead of the wake-up connection are served here;
+            // everything behind it is left for the next server.
+            let stream = stream?;
+            if shared.is_waker(&stream) {
+                break;
+            }
+            queue_stream(stream, tx, shared);
+            continue;
+        }
+        queue_stream(stream?, tx, shared);
     }
     Ok(())
 }
 
-fn handle_client(stream: TcpStream, engine: CrabKv) -> io::Result<()> {
+fn queue_stream(stream: TcpStream, tx: &mpsc::SyncSender<TcpStream>, shared: &Shared) {
+    shared.pending.fetch_add(1, Ordering::SeqCst);
+    match tx.try_send(stream) {
+        Ok(()) => {}
+        Err(TrySendError::Full(mut stream)) => {
+            shared.pending.fetch_sub(1, Ordering::SeqCst);
+            let _ = writeln!(stream, "ERR server busy");
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            shared.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn handle_client(
+    stream: TcpStream,
+    engine: &CrabKv,
+    shared: &Arc<Shared>,
+    output_limit: usize,
+) -> io::Result<()> {
     let peer = stream.peer_addr().ok();
-    let mut writer = stream.try_clone()?;
-    let reader = BufReader::new(stream);
-    writeln!(writer, "Welcome to CrabKv. {HELP}")?;
-
-    for line in reader.lines() {
-        let line = line?;
-        let response = match parse_command(&line) {
-            Command::Put { key, value, ttl } => match ttl {
-                Some(ttl) => engine
-                    .put_with_ttl(key, value, Some(ttl))
-                    .map(|_| "OK".to_string()),
-                None => engine.put(key, value).map(|_| "OK".to_string()),
-            },
-            Command::Get { key } => match engine.get(&key)? {
-                Some(value) => Ok(format!("VALUE {value}")),
-                None => Ok("NOT_FOUND".to_string()),
-            },
-            Command::Delete { key } => engine.delete(&key).map(|_| "OK".to_string()),
-            Command::Compact => engine.compact().map(|_| "OK".to_string()),
-            Command::Help => Ok(HELP.to_string()),
-            Command::Invalid => Err(io::Error::new(io::ErrorKind::InvalidInput, "bad command")),
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let output = Arc::new(AtomicUsize::new(0));
+    {
+        // Checked under the lock so shutdown either sees this connection or we see it stopping.
+        let mut connections = shared.connections.lock();
+        shared.pending.fetch_sub(1, Ordering::SeqCst);
+        if shared.stopping.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let connection = Connection {
+            stream: stream.try_clone()?,
+            output: Arc::clone(&output),
         };
+        connections.insert(id, connection);
+    }
 
-        match response {
-            Ok(output) => {
-                writeln!(writer, "{output}")?;
+    // Wake idle connections periodically so they notice a drain, and give up
+    // on a full socket now and then so a subscriber can count its backlog.
+    stream.set_read_timeout(Some(IDLE_POLL))?;
+    stream.set_write_timeout(Some(SUBSCRIBE_POLL))?;
+    let mut writer = OutputBuffer::new(stream.try_clone()?, output_limit, &output);
+    let ended = serve_session(stream.try_clone()?, &mut writer, engine, shared);
+    drop(writer);
+    let events = match ended {
+        Ok(SessionEnd::Subscribed(events)) => events,
+        ended => {
+            shared.connections.lock().remove(&id);
+            ended?;
+            if let Some(addr) = peer {
+                println!("connection closed: {addr}");
             }
-            Err(err) => {
-                writeln!(writer, "ERR {err}")?;
+            return Ok(());
+        }
+    };
+
+    // The stream goes to a thread of its own, so a subscriber does not keep
+    // a worker from other clients until it disconnects. It stays registered,
+    // for shutdown to close and STATS to count.
+    let subscriber = thread::spawn({
+        let shared = Arc::clone(shared);
+        move || {
+            // Commands are no longer read, so a peek only looks for the hangup.
+            let _ = stream.set_read_timeout(Some(HANGUP_POLL));
+            let hung_up = || matches!(stream.peek(&mut [0]), Ok(0));
+            let mut writer = OutputBuffer::new(&stream, output_limit, &output);
+            let streamed = stream_expired(&events, &mut writer, &shared.stopping, hung_up);
+            shared.connections.lock().remove(&id);
+            shared.subscribed.fetch_sub(1, Ordering::SeqCst);
+            match (streamed, peer) {
+                (Err(err), _) => eprintln!("client error: {err}"),
+                (Ok(()), Some(addr)) => println!("connection closed: {addr}"),
+                (Ok(()), None) => {}
             }
         }
-        writer.flush()?;
+    });
+    let mut subscribers = shared.subscribers.lock();
+    subscribers.retain(|subscriber| !subscriber.is_finished());
+    subscribers.push(subscriber);
+    Ok(())
+}
+
+/// How [`serve_session`] let a connection go.
+enum SessionEnd {
+    /// The client hung up or the server stopped.
+    Closed,
+    /// `SUBSCRIBE expired` was answered `OK`; the caller streams `events`.
+    Subscribed(Receiver<String>),
+}
+
+/// Serves text commands read from `reader`, writing responses to `writer`.
+///
+/// Responses are buffered and only flushed once every complete command already
+/// received has been answered, so pipelined clients cost one flush per read
+/// rather than one per command while interactive clients still see each reply
+/// immediately.
+pub fn serve_connection<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    engine: &CrabKv,
+) -> io::Result<()> {
+    let output = AtomicUsize::new(0);
+    let limit = ServerConfig::default().max_output_bytes;
+    let mut writer = OutputBuffer::new(writer, limit, &output);
+    let shared = Shared::default();
+    match serve_session(reader, &mut writer, engine, &shared)? {
+        SessionEnd::Closed => Ok(()),
+        SessionEnd::Subscribed(events) => {
+            stream_expired(&events, &mut writer, &shared.stopping, || false)
+        }
     }
+}
 
-    if let Some(addr) = peer {
-        println!("connection closed: {addr}");
+/// Runs one connection until the client hangs up or the server stops.
+///
+/// Reads that time out are retried, giving an idle connection the chance to
+/// send its `GOAWAY` notice once the server starts draining.
+fn serve_session<R: Read, W: Write>(
+    reader: R,
+    writer: &mut OutputBuffer<'_, W>,
+    engine: &CrabKv,
+    shared: &Shared,
+) -> io::Result<SessionEnd> {
+    let mut reader = BufReader::new(reader);
+    writer.queue_line(&format!(
+        "Welcome to CrabKv. {}. epoch={}. {}",
+        build_info(),
+        engine.epoch(),
+        help_summary()
+    ))?;
+    writer.flush()?;
+
+    let mut last_write = None;
+    // Epoch the client pinned with EPOCH <expected>.
+    let mut pinned = None;
+    let mut told_to_leave = false;
+    // Raw bytes of the line being read, decoded once it is complete, so a read
+    // timing out inside a UTF-8 sequence loses nothing.
+    let mut partial = Vec::new();
+    loop {
+        if !told_to_leave
+            && partial.is_empty()
+            && reader.buffer().is_empty()
+            && shared.draining.load(Ordering::SeqCst)
+        {
+            writer.queue_line("GOAWAY")?;
+            writer.flush()?;
+            told_to_leave = true;
+        }
+        // A timed out read keeps whatever part of the line already arrived.
+        match reader.read_until(b'\n', &mut partial) {
+            Ok(0) if partial.is_empty() => break,
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+        let command = match std::str::from_utf8(&partial) {
+            Ok(line) => parse_command(line.trim_end_matches(['\r', '\n'])),
+            Err(_) => Command::Invalid,
+        };
+        partial.clear();
+        let context = OpContext::generate();
+        let _scope = context.enter();
+        let _pin = pinned.map(|epoch| engine.pin_epoch(epoch));
+        // Reads always go through; writes slow down or are refused under pressure.
+        let pressure = match command {
+            Command::Put { .. }
+            | Command::Delete { .. }
+            | Command::Pop { .. }
+            | Command::Incr { .. } => shared.config.pressure.level(&engine.pressure_gauges()),
+            _ => PressureLevel::Ok,
+        };
+        if pressure == PressureLevel::Elevated {
+            thread::sleep(shared.config.elevated_write_delay);
+            if let Err(err) = writer.queue_line("WARN pressure=elevated") {
+                writer.overflow();
+                return Err(err);
+            }
+        }
+        // Bytes GETRAW sends after its header line.
+        let mut payload = None;
+        let response = match command {
+            Command::Put { .. }
+            | Command::Delete { .. }
+            | Command::Pop { .. }
+            | Command::Incr { .. }
+                if pressure == PressureLevel::Critical =>
+            {
+                Ok(format!(
+                    "ERR BUSY retry_after={} [id={}]",
+                    shared.config.busy_retry_after.as_millis(),
+                    context.id()
+                ))
+            }
+            Command::Epoch { expected } => epoch(engine, expected.as_deref(), &mut pinned),
+            // Writes check again under the lock they take, through the pin.
+            _ if pinned.is_some_and(|pinned| pinned != engine.epoch()) => Err(
+                EpochMismatch::error(pinned.unwrap_or_default(), engine.epoch()),
+            ),
+            Command::Put {
+                key,
+                value,
+                ttl,
+                idem,
+            } => {
+                let written = match ttl.as_deref().map(parse_ttl_kv).transpose() {
+                    Ok(ttl) => engine.put_with(key, value, &write_options(ttl, idem)),
+                    Err(err) => Err(err),
+                };
+                written.and_then(|_| acknowledge_write(engine, &mut last_write))
+            }
+            Command::Get { key } => engine.get(&key).map(|value| match value {
+                Some(value) => format!("VALUE {value}"),
+                None => "NOT_FOUND".to_string(),
+            }),
+            Command::GetRaw { key } => engine.get_bytes(&key).map(|value| match value {
+                Some(value) => {
+                    let header = format!("VALUE {}", value.len());
+                    payload = Some(value);
+                    header
+                }
+                None => "NOT_FOUND".to_string(),
+            }),
+            Command::Exists { key } => engine
+                .contains_key(&key)
+                .map(|found| if found { "1" } else { "0" }.to_string()),
+            Command::GetRange { key, start, len } => get_range(engine, &key, &start, &len),
+            #[cfg(feature = "json")]
+            Command::GetJson { key, pointer } => {
+                engine
+                    .get_json_path(&key, &pointer)
+                    .map(|fragment| match fragment {
+                        Some(fragment) => format!("VALUE {fragment}"),
+                        None => "NOT_FOUND".to_string(),
+                    })
+            }
+            Command::Delete { key, idem } => engine
+                .delete_with(&key, &write_options(None, idem))
+                .and_then(|_| acknowledge_write(engine, &mut last_write)),
+            Command::Pop { key } => pop(engine, &key, &mut last_write),
+            Command::Incr { key, delta } => incr(engine, &key, delta.as_deref(), &mut last_write),
+            Command::ScanKv { pattern } => scan_kv(engine, &pattern),
+            Command::Stats { force } => stats(engine, force.as_deref(), shared),
+            Command::Health => Ok(health(engine)),
+            Command::Compact => engine.compact().map(|_| "OK".to_string()),
+            Command::Flush => engine.flush().map(|_| "OK".to_string()),
+            Command::Scrub => engine.scrub_now().map(|report| scrub_summary(&report)),
+            Command::Migrate { path } => engine.migrate_to(&path).map(|_| "OK".to_string()),
+            Command::Subscribe { channel } if channel.eq_ignore_ascii_case("expired") => {
+                match subscribe_expired(engine, shared) {
+                    Ok(events) => {
+                        let answered = writer.queue_line("OK").and_then(|()| writer.flush());
+                        if let Err(err) = answered {
+                            shared.subscribed.fetch_sub(1, Ordering::SeqCst);
+                            return Err(err);
+                        }
+                        return Ok(SessionEnd::Subscribed(events));
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Command::Subscribe { channel } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown channel `{channel}`"),
+            )),
+            Command::LastWrite => match last_write {
+                Some(marker) => Ok(format!("TOKEN {marker}")),
+                None => Ok("NOT_FOUND".to_string()),
+            },
+            Command::WaitDurable { token, timeout_ms } => {
+                wait_durable(engine, &token, timeout_ms.as_deref())
+            }
+            Command::Version => Ok(version()),
+            Command::Commands => Ok(list_commands()),
+            Command::Help { command } => help(command.as_deref()),
+            Command::Invalid => Err(io::Error::new(io::ErrorKind::InvalidInput, "bad command")),
+        };
+
+        let queued = match (response, payload) {
+            (Ok(header), Some(value)) => writer.queue_framed(&header, &value),
+            (Ok(output), None) => writer.queue_line(&output),
+            (Err(err), _) => writer.queue_line(&format!("ERR {err} [id={}]", context.id())),
+        };
+        if let Err(err) = queued {
+            writer.overflow();
+            return Err(err);
+        }
+        if reader.buffer().is_empty() || writer.queued() >= FLUSH_THRESHOLD {
+            writer.flush()?;
+        }
     }
-    Ok(())
+
+    writer.flush()?;
+    Ok(SessionEnd::Closed)
 }
 
 enum Command {
     Put {
         key: String,
         value: String,
-        ttl: Option<Duration>,
+        ttl: Option<String>,
+        idem: Option<String>,
     },
     Get {
         key: String,
     },
+    GetRaw {
+        key: String,
+    },
+    Exists {
+        key: String,
+    },
+    GetRange {
+        key: String,
+        start: String,
+        len: String,
+    },
+    #[cfg(feature = "json")]
+    GetJson {
+        key: String,
+        pointer: String,
+    },
     Delete {
         key: String,
+        idem: Option<String>,
+    },
+    Pop {
+        key: String,
     },
+    Incr {
+        key: String,
+        delta: Option<String>,
+    },
+    ScanKv {
+        pattern: String,
+    },
+    Stats {
+        force: Option<String>,
+    },
+    Health,
     Compact,
-    Help,
+    Flush,
+    Scrub,
+    Migrate {
+        path: String,
+    },
+    Epoch {
+        expected: Option<String>,
+    },
+    Subscribe {
+        channel: String,
+    },
+    LastWrite,
+    WaitDurable {
+        token: String,
+        timeout_ms: Option<String>,
+    },
+    Version,
+    Commands,
+    Help {
+        command: Option<String>,
+    },
     Invalid,
 }
 
+impl CommandSpec {
+    fn find(name: &str) -> Option<&'static CommandSpec> {
+        COMMANDS
+            .iter()
+            .find(|spec| spec.name.eq_ignore_ascii_case(name))
+    }
+
+    fn usage(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+
+    fn arity(&self) -> String {
+        if self.min_args == self.max_args {
+            self.min_args.to_string()
+        } else {
+            format!("{}-{}", self.min_args, self.max_args)
+        }
+    }
+}
+
 fn parse_command(line: &str) -> Command {
-    let mut parts = line.trim().split_whitespace();
-    match parts.next() {
-        Some(cmd) if cmd.eq_ignore_ascii_case("put") => {
-            let key = match parts.next() {
-                Some(key) => key.to_owned(),
-                None => return Command::Invalid,
-            };
-            let value = match parts.next() {
-                Some(value) => value.to_owned(),
-                None => return Command::Invalid,
-            };
-            let ttl = parts.next().and_then(parse_ttl_kv);
-            if parts.next().is_some() {
-                return Command::Invalid;
-            }
-            Command::Put { key, value, ttl }
-        }
-        Some(cmd) if cmd.eq_ignore_ascii_case("get") => match parts.next() {
-            Some(key) => {
-                if parts.next().is_some() {
-                    Command::Invalid
-                } else {
-                    Command::Get {
-                        key: key.to_owned(),
+    let mut parts = line.split_whitespace();
+    let Some(spec) = parts.next().and_then(CommandSpec::find) else {
+        return Command::Invalid;
+    };
+    let args: Vec<&str> = parts.collect();
+    if args.len() < spec.min_args || args.len() > spec.max_args {
+        return Command::Invalid;
+    }
+    (spec.parse)(&args).unwrap_or(Command::Invalid)
+}
+
+fn help(command: Option<&str>) -> io::Result<String> {
+    match command {
+        None => Ok(help_summary()),
+        Some(name) => match CommandSpec::find(name) {
+            Some(spec) => Ok(format!("Usage: {} - {}", spec.usage(), spec.summary)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown command `{name}`"),
+            )),
+        },
+    }
+}
+
+fn help_summary() -> String {
+    let usages: Vec<String> = COMMANDS.iter().map(CommandSpec::usage).collect();
+    format!("Commands: {}", usages.join(", "))
+}
+
+fn list_commands() -> String {
+    let mut lines: Vec<String> = COMMANDS
+        .iter()
+        .map(|spec| format!("{} {} {}", spec.name, spec.arity(), spec.summary))
+        .collect();
+    lines.push("END".to_string());
+    lines.join("\n")
+}
+
+/// Replies `OK <token>` and remembers the token for LASTWRITE.
+fn acknowledge_write(
+    engine: &CrabKv,
+    last_write: &mut Option<DurabilityMarker>,
+) -> io::Result<String> {
+    let marker = engine.durability_marker()?;
+    *last_write = Some(marker);
+    Ok(format!("OK {marker}"))
+}
+
+fn wait_durable(engine: &CrabKv, token: &str, timeout_ms: Option<&str>) -> io::Result<String> {
+    let marker = DurabilityMarker::from_str(token)?;
+    let timeout = match timeout_ms {
+        Some(ms) => Duration::from_millis(u64::from_str(ms).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "timeout_ms must be a non-negative integer",
+            )
+        })?),
+        None => WAITDURABLE_TIMEOUT,
+    };
+    if engine.wait_durable(marker, timeout)? {
+        Ok("OK".to_string())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out waiting for the write to become durable",
+        ))
+    }
+}
+
+fn get_range(engine: &CrabKv, key: &str, start: &str, len: &str) -> io::Result<String> {
+    let parse = |token: &str, name: &str| {
+        token.parse::<usize>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} must be a non-negative integer"),
+            )
+        })
+    };
+    match engine.get_range(key, parse(start, "start")?, parse(len, "len")?)? {
+        Some(value) => Ok(format!("VALUE {value}")),
+        None => Ok("NOT_FOUND".to_string()),
+    }
+}
+
+fn pop(
+    engine: &CrabKv,
+    key: &str,
+    last_write: &mut Option<DurabilityMarker>,
+) -> io::Result<String> {
+    match engine.pop(key)? {
+        Some(value) => {
+            acknowledge_write(engine, last_write)?;
+            Ok(format!("VALUE {value}"))
+        }
+        None => Ok("NOT_FOUND".to_string()),
+    }
+}
+
+fn incr(
+    engine: &CrabKv,
+    key: &str,
+    delta: Option<&str>,
+    last_write: &mut Option<DurabilityMarker>,
+) -> io::Result<String> {
+    let delta = match delta {
+        Some(delta) => delta
+            .parse::<i64>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "delta must be an integer"))?,
+        None => 1,
+    };
+    let value = engine.incr(key, delta)?;
+    acknowledge_write(engine, last_write)?;
+    Ok(format!("VALUE {value}"))
+}
+
+fn scan_kv(engine: &CrabKv, pattern: &str) -> io::Result<String> {
+    let mut lines = Vec::new();
+    let mut truncated = false;
+    for key in engine.keys_matching(pattern)? {
+        if lines.len() == SCANKV_LIMIT {
+            truncated = true;
+            break;
+        }
+        // Keys may expire or be deleted between the scan and the read.
+        if let Some(value) = engine.get(&key)? {
+            lines.push(format!("{key} {value}"));
+        }
+    }
+    if truncated {
+        lines.push("TRUNCATED".to_string());
+    }
+    lines.push("END".to_string());
+    Ok(lines.join("\n"))
+}
+
+/// Takes one of the server's `max_subscribers` places and subscribes to
+/// expiry events; [`stream_expired`] gives the place back.
+fn subscribe_expired(engine: &CrabKv, shared: &Shared) -> io::Result<Receiver<String>> {
+    let max = shared.config.max_subscribers;
+    let taken = shared
+        .subscribed
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |subscribed| {
+            (subscribed < max).then_some(subscribed + 1)
+        });
+    if taken.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::QuotaExceeded,
+            format!("too many subscribers, at most {max}"),
+        ));
+    }
+    engine.subscribe_expired().inspect_err(|_| {
+        shared.subscribed.fetch_sub(1, Ordering::SeqCst);
+    })
+}
+
+/// Turns the connection into a stream of `EXPIRED <key>` lines.
+///
+/// The connection stops accepting commands; it is closed once `hung_up` says
+/// the client went away or writing an event fails, or once events the client
+/// has not read outgrow the connection's output limit.
+fn stream_expired<W: Write>(
+    events: &Receiver<String>,
+    writer: &mut OutputBuffer<'_, W>,
+    stopping: &AtomicBool,
+    hung_up: impl Fn() -> bool,
+) -> io::Result<()> {
+    while !stopping.load(Ordering::SeqCst) && !hung_up() {
+        match events.recv_timeout(SUBSCRIBE_POLL) {
+            Ok(key) => {
+                // Queue everything already waiting so a slow reader's backlog
+                // is counted here rather than in the engine's channel.
+                for key in std::iter::once(key).chain(events.try_iter()) {
+                    if let Err(err) = writer.queue_line(&format!("EXPIRED {key}")) {
+                        writer.overflow();
+                        return Err(err);
                     }
                 }
             }
-            None => Command::Invalid,
-        },
-        Some(cmd) if cmd.eq_ignore_ascii_case("delete") => match parts.next() {
-            Some(key) => {
-                if parts.next().is_some() {
-                    Command::Invalid
-                } else {
-                    Command::Delete {
-                        key: key.to_owned(),
-                    }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        writer.send(false)?;
+    }
+    Ok(())
+}
+
+/// Response bytes waiting to be written to one connection.
+///
+/// Lines are only queued here; [`OutputBuffer::flush`] writes them out. Queuing
+/// more than `limit` bytes fails, so a client that stops reading cannot make
+/// the server buffer without bound. `gauge` mirrors the queued byte count for
+/// STATS.
+struct OutputBuffer<'a, W: Write> {
+    inner: W,
+    pending: Vec<u8>,
+    limit: usize,
+    gauge: &'a AtomicUsize,
+}
+
+impl<'a, W: Write> OutputBuffer<'a, W> {
+    fn new(inner: W, limit: usize, gauge: &'a AtomicUsize) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            limit,
+            gauge,
+        }
+    }
+
+    /// Queues `line` and a newline, or fails with [`io::ErrorKind::OutOfMemory`]
+    /// if that would exceed the limit.
+    fn queue_line(&mut self, line: &str) -> io::Result<()> {
+        self.check_room(line.len() + 1)?;
+        self.push(line);
+        Ok(())
+    }
+
+    /// A reply larger than the limit still goes out when nothing is queued
+    /// ahead of it; the limit is there for replies piling up unread.
+    fn check_room(&self, bytes: usize) -> io::Result<()> {
+        if !self.pending.is_empty() && self.pending.len() + bytes > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("output overflow: more than {} bytes queued", self.limit),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Queues `header`, then `payload` and a newline. A payload of
+    /// [`DIRECT_VALUE_BYTES`] or more is written to the socket right away,
+    /// together with the queue ahead of it, instead of being copied into it.
+    fn queue_framed(&mut self, header: &str, payload: &[u8]) -> io::Result<()> {
+        if payload.len() < DIRECT_VALUE_BYTES {
+            self.check_room(header.len() + payload.len() + 2)?;
+            self.push(header);
+            self.pending.extend_from_slice(payload);
+            self.pending.push(b'\n');
+            self.gauge.fetch_add(payload.len() + 1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.queue_line(header)?;
+        // Queued replies, payload and newline go out in one vectored write, so
+        // no small segment trails the payload waiting on an ACK.
+        let queued = std::mem::take(&mut self.pending);
+        self.gauge.fetch_sub(queued.len(), Ordering::Relaxed);
+        let mut slices = [
+            IoSlice::new(&queued),
+            IoSlice::new(payload),
+            IoSlice::new(b"\n"),
+        ];
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            let attempt = Instant::now();
+            match self.inner.write_vectored(slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    wait_for_room(attempt);
                 }
+                Err(err) => return Err(err),
             }
-            None => Command::Invalid,
-        },
-        Some(cmd) if cmd.eq_ignore_ascii_case("compact") => {
-            if parts.next().is_some() {
-                Command::Invalid
-            } else {
-                Command::Compact
+        }
+        Ok(())
+    }
+
+    fn queued(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Writes every queued byte, waiting out a full socket.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send(true)?;
+        self.inner.flush()
+    }
+
+    /// Writes queued bytes until done, or with `wait` unset, until the socket
+    /// stops accepting them.
+    fn send(&mut self, wait: bool) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.pending.len() {
+                break Ok(());
+            }
+            let attempt = Instant::now();
+            match self.inner.write(&self.pending[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if !wait {
+                        break Ok(());
+                    }
+                    wait_for_room(attempt);
+                }
+                Err(err) => break Err(err),
             }
+        };
+        self.pending.drain(..written);
+        self.gauge.fetch_sub(written, Ordering::Relaxed);
+        result
+    }
+
+    /// Queues a final `ERR OUTPUT OVERFLOW` past the limit and sends what the
+    /// socket takes without waiting; the caller then closes the connection.
+    fn overflow(&mut self) {
+        self.push("ERR OUTPUT OVERFLOW");
+        let _ = self.send(false);
+    }
+
+    fn push(&mut self, line: &str) {
+        self.pending.extend_from_slice(line.as_bytes());
+        self.pending.push(b'\n');
+        self.gauge.fetch_add(line.len() + 1, Ordering::Relaxed);
+    }
+}
+
+/// Waits after a write made at `attempt` found the socket full. A socket
+/// with a write timeout has already waited that long; one that refused at
+/// once, like a non-blocking socket, is given a moment rather than retried
+/// in a busy loop.
+fn wait_for_room(attempt: Instant) {
+    if let Some(left) = FULL_SOCKET_PAUSE.checked_sub(attempt.elapsed()) {
+        thread::sleep(left);
+    }
+}
+
+impl<W: Write> Drop for OutputBuffer<'_, W> {
+    fn drop(&mut self) {
+        self.gauge.fetch_sub(self.pending.len(), Ordering::Relaxed);
+    }
+}
+
+/// Reports the first problem found, followed by the engine mode when it is
+/// not `normal`, as in `HEALTH OK mode=frozen`.
+fn health(engine: &CrabKv) -> String {
+    let mut reply = first_problem(engine);
+    let mode = engine.mode();
+    if mode != EngineMode::Normal {
+        reply.push_str(&format!(" mode={mode}"));
+    }
+    reply
+}
+
+/// Checks for a stalled compaction worker, an index checkpoint past its
+/// maximum age, a recent background worker error, then any failing quick
+/// check of the data directory.
+fn first_problem(engine: &CrabKv) -> String {
+    if let Some(reason) = engine.compaction_status().degraded() {
+        return format!("HEALTH DEGRADED {reason}");
+    }
+    if let Ok(status) = engine.checkpoint_status()
+        && let Some(reason) = status.degraded()
+    {
+        return format!("HEALTH DEGRADED {reason}");
+    }
+    if let Some(failure) = engine.recent_background_error() {
+        return format!(
+            "HEALTH DEGRADED {} worker failed: {}",
+            failure.worker, failure.error
+        );
+    }
+    match diagnostics::quick_checks(&engine.directory())
+        .into_iter()
+        .find(|check| check.status == Status::Fail)
+    {
+        Some(check) => format!("HEALTH DEGRADED {}: {}", check.name, check.detail),
+        None => "HEALTH OK".to_string(),
+    }
+}
+
+fn stats(engine: &CrabKv, force: Option<&str>, shared: &Shared) -> io::Result<String> {
+    let contents = match force {
+        None => engine.stats()?,
+        Some(token) if token.eq_ignore_ascii_case("force=true") => {
+            engine.stats_with_histograms()?
         }
-        Some(cmd) if cmd.eq_ignore_ascii_case("help") => {
-            if parts.next().is_some() {
-                Command::Invalid
-            } else {
-                Command::Help
+        Some(token) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected force=true, got `{token}`"),
+            ));
+        }
+    };
+    let compaction = engine.compaction_status();
+    let last_outcome = match compaction.last_outcome {
+        Some(CompactionOutcome::Succeeded) => "ok",
+        Some(CompactionOutcome::Failed(_)) => "failed",
+        None => "none",
+    };
+    let (output_bytes, output_bytes_max) = shared.output_bytes();
+    Ok(format!(
+        "STATS mode={} mutations_since_open={} bytes_written_since_open={} compactions={} compacting={} handles={} clock_steps_back={} \
+         output_bytes={output_bytes} output_bytes_max={output_bytes_max} compaction_worker={} pending_compactions={} last_compaction={last_outcome} \
+         last_compaction_started={} last_compaction_finished={} keys_with_ttl={} keys_without_ttl={} \
+         ttl_remaining={} expired_unreclaimed={} expired_since_open={} expired_swept={} ttl_extensions={} keys={} key_sizes={} value_sizes={} \
+         wal_bytes={} live_bytes={} stale_bytes={} cache_capacity={} compaction_pending={} checkpoint_age_ms={} dedup_saved_bytes={} quarantined={} lossy_utf8_reads={} \
+         last_scrub={} scrub_damaged={} recent_events={}",
+        contents.mode,
+        engine.mutations_since_open(),
+        engine.bytes_written_since_open(),
+        engine.compaction_count()?,
+        compaction.in_progress,
+        engine.handle_count(),
+        engine.clock_steps_back(),
+        compaction.worker_running,
+        compaction.pending_triggers,
+        unix_millis(compaction.last_started),
+        unix_millis(compaction.last_finished),
+        contents.keys_with_ttl,
+        contents.keys_without_ttl,
+        buckets(contents.ttl_remaining.as_ref().map(|ttl| &ttl.counts[..])),
+        contents.expired_unreclaimed,
+        contents.expiry.expired,
+        contents.expiry.swept,
+        contents.ttl_extensions,
+        contents.keys,
+        buckets(contents.key_sizes.as_ref().map(|sizes| &sizes.counts[..])),
+        buckets(contents.value_sizes.as_ref().map(|sizes| &sizes.counts[..])),
+        contents.wal_bytes,
+        contents.live_bytes,
+        contents.stale_bytes,
+        contents
+            .cache_capacity
+            .map_or_else(|| "-".into(), |capacity| capacity.to_string()),
+        contents.compaction_pending,
+        contents
+            .checkpoint_age
+            .map_or_else(|| "-".into(), |age| age.as_millis().to_string()),
+        contents.dedup_saved_bytes,
+        contents.quarantined,
+        contents.lossy_utf8_reads,
+        unix_millis(contents.last_scrub.as_ref().map(|scrub| scrub.finished_at)),
+        contents
+            .last_scrub
+            .as_ref()
+            .map_or_else(|| "-".into(), |scrub| scrub.damaged_records.to_string()),
+        recent_events(engine),
+    ))
+}
+
+/// Answers `EPOCH <epoch>`, first pinning the connection to `expected` when
+/// given; a stale `expected` leaves any earlier pin in place.
+fn epoch(engine: &CrabKv, expected: Option<&str>, pinned: &mut Option<u64>) -> io::Result<String> {
+    let current = engine.epoch();
+    if let Some(expected) = expected {
+        let expected = expected.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{expected}` is not an epoch"),
+            )
+        })?;
+        if expected != current {
+            return Err(EpochMismatch::error(expected, current));
+        }
+        *pinned = Some(expected);
+    }
+    Ok(format!("EPOCH {current}"))
+}
+
+/// Formats a finished pass as `SCRUB records=.. bytes=.. damaged=.. quarantined=..`.
+fn scrub_summary(report: &ScrubReport) -> String {
+    format!(
+        "SCRUB records={} bytes={} damaged={} quarantined={}",
+        report.records_checked,
+        report.bytes_checked,
+        report.damaged_records,
+        report.quarantined_keys
+    )
+}
+
+/// Formats the latest events as `[<unix millis>:<name>,...]`, oldest first.
+fn recent_events(engine: &CrabKv) -> String {
+    let events = engine.recent_events();
+    let skip = events.len().saturating_sub(EVENTS_SHOWN);
+    let events: Vec<String> = events[skip..]
+        .iter()
+        .map(|event| format!("{}:{}", unix_millis(Some(event.at)), event.kind.as_str()))
+        .collect();
+    format!("[{}]", events.join(","))
+}
+
+fn version() -> String {
+    let info = build_info();
+    format!(
+        "VERSION version={} git={} build_date={} features={}",
+        info.version,
+        info.git_hash,
+        info.build_date,
+        info.feature_list()
+    )
+}
+
+/// Formats histogram counts as `[c0,c1,...]`, or `skipped` when not computed.
+fn buckets(counts: Option<&[u64]>) -> String {
+    counts.map_or_else(
+        || "skipped".to_string(),
+        |counts| {
+            let counts: Vec<String> = counts.iter().map(u64::to_string).collect();
+            format!("[{}]", counts.join(","))
+        },
+    )
+}
+
+/// Formats a time as milliseconds since the Unix epoch, or `-` when absent.
+fn unix_millis(time: Option<SystemTime>) -> String {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(|| "-".to_string(), |since| since.as_millis().to_string())
+}
+
+/// Sorts the options after a write's arguments into its `ttl=` and `idem=`
+/// tokens; `None` if either is given twice. Anything but `idem=` is taken as
+/// the TTL and checked when the command runs.
+fn split_write_options(options: &[&str]) -> Option<(Option<String>, Option<String>)> {
+    let (mut ttl, mut idem) = (None, None);
+    for option in options {
+        let repeated = match option.split_once('=') {
+            Some((name, token)) if name.eq_ignore_ascii_case("idem") && !token.is_empty() => {
+                idem.replace(token.to_owned()).is_some()
             }
+            _ => ttl.replace(option.to_string()).is_some(),
+        };
+        if repeated {
+            return None;
         }
-        _ => Command::Invalid,
     }
+    Some((ttl, idem))
 }
 
-fn parse_ttl_kv(token: &str) -> Option<Duration> {
-    let (key, value) = token.split_once('=')?;
-    if key.eq_ignore_ascii_case("ttl") {
-        parse_duration_secs(value).ok()
-    } else {
-        None
+fn write_options(ttl: Option<Duration>, idem: Option<String>) -> WriteOptions {
+    let mut options = WriteOptions::new();
+    if let Some(ttl) = ttl {
+        options = options.ttl(ttl);
     }
+    if let Some(idem) = idem {
+        options = options.idempotency_key(idem);
+    }
+    options
 }
 
-fn parse_duration_secs(input: &str) -> io::Result<Duration> {
-    let seconds = u64::from_str(input)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid TTL"))?;
-    Ok(Duration::from_secs(seconds))
+fn parse_ttl_kv(token: &str) -> io::Result<Duration> {
+    match token.split_once('=') {
+        Some((key, value)) if key.eq_ignore_ascii_case("ttl") => parse_duration(value),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected ttl=<duration>, got `{token}`"),
+        )),
+    }
 }
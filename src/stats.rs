@@ -0,0 +1,167 @@
+//! Point-in-time engine statistics computed from the index on demand.
+
+use crate::expiry::ExpiryCounters;
+use crate::mode::EngineMode;
+use crate::scrub::ScrubReport;
+use std::time::Duration;
+
+/// Index size above which [`CrabKv::stats`] skips the size histograms unless forced.
+///
+/// [`CrabKv::stats`]: crate::CrabKv::stats
+pub const DEFAULT_HISTOGRAM_LIMIT: usize = 1_000_000;
+
+/// Number of buckets in a [`SizeHistogram`].
+pub const SIZE_BUCKETS: usize = 25;
+
+/// Counts of lengths in fixed power-of-two buckets.
+///
+/// Bucket 0 holds zero-length entries and bucket `i` holds lengths in
+/// `2^(i-1) .. 2^i`; the last bucket also takes everything longer, so it
+/// starts at 8 MiB.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SizeHistogram {
+    pub counts: [u64; SIZE_BUCKETS],
+}
+
+impl SizeHistogram {
+    /// Counts one entry of `len` bytes.
+    pub fn record(&mut self, len: u64) {
+        self.counts[Self::bucket_of(len)] += 1;
+    }
+
+    /// Returns the bucket a length of `len` bytes falls into.
+    pub fn bucket_of(len: u64) -> usize {
+        ((u64::BITS - len.leading_zeros()) as usize).min(SIZE_BUCKETS - 1)
+    }
+
+    /// Returns the smallest length counted by `bucket`.
+    pub fn lower_bound(bucket: usize) -> u64 {
+        match bucket {
+            0 => 0,
+            bucket => 1 << (bucket - 1),
+        }
+    }
+
+    /// Returns the number of entries counted.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// Number of buckets in a [`TtlHistogram`].
+pub const TTL_BUCKETS: usize = 5;
+
+/// Exclusive upper bounds of every [`TtlHistogram`] bucket but the last.
+pub const TTL_BUCKET_BOUNDS: [Duration; TTL_BUCKETS - 1] = [
+    Duration::from_secs(60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(24 * 60 * 60),
+    Duration::from_secs(7 * 24 * 60 * 60),
+];
+
+/// Counts of live keys by time left until they expire: under a minute, an
+/// hour, a day, a week, and everything longer.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TtlHistogram {
+    pub counts: [u64; TTL_BUCKETS],
+}
+
+impl TtlHistogram {
+    /// Short labels for the buckets, in order.
+    pub const LABELS: [&'static str; TTL_BUCKETS] = ["<1m", "<1h", "<1d", "<7d", "more"];
+
+    /// Counts one key with `remaining` time to live.
+    pub fn record(&mut self, remaining: Duration) {
+        self.counts[Self::bucket_of(remaining)] += 1;
+    }
+
+    /// Returns the bucket a remaining time to live falls into.
+    pub fn bucket_of(remaining: Duration) -> usize {
+        TTL_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| remaining < *bound)
+            .unwrap_or(TTL_BUCKETS - 1)
+    }
+
+    /// Returns the number of keys counted.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// Snapshot of the engine's contents returned by [`CrabKv::stats`].
+///
+/// Only entries in the index are counted; writes still waiting in the
+/// write-back buffer are not.
+///
+/// [`CrabKv::stats`]: crate::CrabKv::stats
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EngineStats {
+    /// Live keys, not counting expired ones awaiting removal.
+    pub keys: u64,
+    /// Bytes the log takes on disk, records and batch framing included.
+    pub wal_bytes: u64,
+    /// Log bytes still holding live data: `wal_bytes` less `stale_bytes`.
+    pub live_bytes: u64,
+    /// Log bytes held by overwritten, deleted or otherwise dead records,
+    /// which the next compaction reclaims.
+    pub stale_bytes: u64,
+    /// Entries the cache holds at most, or `None` without a cache.
+    pub cache_capacity: Option<usize>,
+    /// Whether the compaction policy calls for a run, including one held back
+    /// by the minimum compaction interval, or one is queued for the
+    /// background worker.
+    pub compaction_pending: bool,
+    /// Time since the latest index checkpoint was taken, or `None` before
+    /// the first one.
+    pub checkpoint_age: Option<Duration>,
+    /// Key lengths in bytes, or `None` when the index was over the histogram
+    /// limit and the histograms were not forced.
+    pub key_sizes: Option<SizeHistogram>,
+    /// Value lengths in bytes, skipped together with `key_sizes`.
+    pub value_sizes: Option<SizeHistogram>,
+    /// Live keys that will expire.
+    pub keys_with_ttl: u64,
+    /// Live keys without an expiry.
+    pub keys_without_ttl: u64,
+    /// Time left on the live keys that will expire, skipped together with
+    /// `key_sizes`.
+    pub ttl_remaining: Option<TtlHistogram>,
+    /// Keys past their expiry that are still in the index, waiting for a
+    /// write or compaction to remove them.
+    pub expired_unreclaimed: u64,
+    /// Writes since open that gave a live key a later expiry, or none.
+    pub ttl_extensions: u64,
+    /// Reads answered from the cache since open.
+    pub cache_hits: u64,
+    /// Reads that missed the cache and went to the log since open.
+    pub cache_misses: u64,
+    /// Log bytes saved by keys that reference another key's identical value
+    /// instead of storing their own copy, with value dedup enabled.
+    pub dedup_saved_bytes: u64,
+    /// Keys reads found expired and queued for writes to reclaim.
+    pub expiry: ExpiryCounters,
+    /// Keys compaction set aside because their record could not be decoded.
+    pub quarantined: u64,
+    /// Values read with invalid UTF-8 replaced under
+    /// [`Utf8Policy::Lossy`](crate::Utf8Policy::Lossy), compaction copies included.
+    pub lossy_utf8_reads: u64,
+    /// fsyncs issued on the log since open, compactions included.
+    pub log_syncs: u64,
+    /// Log files opened since open. Reads share one handle, so this only
+    /// grows with replays and compactions.
+    pub log_opens: u64,
+    /// Mode set with [`CrabKv::set_mode`](crate::CrabKv::set_mode).
+    pub mode: EngineMode,
+    /// The latest scrub pass finished since open, background or manual.
+    pub last_scrub: Option<ScrubReport>,
+}
+
+impl EngineStats {
+    /// Returns the share of cached reads, or `None` before the first lookup
+    /// or without a cache.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        (lookups > 0).then(|| self.cache_hits as f64 / lookups as f64)
+    }
+}
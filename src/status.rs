@@ -0,0 +1,411 @@
+//! Read-only HTML status page for people looking at a running server.
+//!
+//! The page is served by its own listener thread and never writes to the
+//! engine. Figures that need the engine lock are read with a try-lock, so the
+//! page still renders while the write path is stalled, showing the last
+//! figures it managed to read and how old they are.
+
+use crate::engine::{CompactionOutcome, CrabKv};
+use crate::events::EVENTS_SHOWN;
+use crate::stats::{EngineStats, TtlHistogram};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Seconds between automatic page reloads.
+const REFRESH_SECS: u64 = 5;
+
+/// How long a client may take to send its request before it is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Starts the status listener on a background thread and returns a handle to stop it.
+///
+/// `GET /status` answers with the page; every other path is a 404. Binding to
+/// port 0 picks a free port, reported by [`StatusHandle::local_addr`].
+pub fn spawn(addr: &str, engine: CrabKv) -> io::Result<StatusHandle> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stopping = Arc::clone(&stopping);
+        thread::spawn(move || {
+            let mut page = StatusPage::new(engine);
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                // A client that misbehaves only loses its own request.
+                if let Ok(stream) = stream {
+                    let _ = page.respond(stream);
+                }
+            }
+        })
+    };
+    Ok(StatusHandle {
+        local_addr,
+        stopping,
+        thread: Some(thread),
+    })
+}
+
+/// Running status listener started by [`spawn`].
+///
+/// Dropping the handle stops the listener.
+pub struct StatusHandle {
+    local_addr: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StatusHandle {
+    /// Returns the address the status page is served on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting requests and joins the listener thread.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it notices the flag.
+        let mut wake = self.local_addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake);
+        thread
+            .join()
+            .map_err(|_| io::Error::other("status thread panicked"))
+    }
+}
+
+impl Drop for StatusHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Renders the page, remembering the last stats read for when the lock is busy.
+struct StatusPage {
+    engine: CrabKv,
+    started: Instant,
+    last_stats: Option<(EngineStats, SystemTime)>,
+}
+
+impl StatusPage {
+    fn new(engine: CrabKv) -> Self {
+        Self {
+            engine,
+            started: Instant::now(),
+            last_stats: None,
+        }
+    }
+
+    fn respond(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // Headers are not needed, but are read so the client sees a clean close.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/status")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "only GET is served\n".to_string()),
+        };
+        let content_type = if status.starts_with("200") {
+            "text/html; charset=utf-8"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        let mut writer = &stream;
+        write!(
+            writer,
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        writer.flush()
+    }
+
+    fn render(&mut self) -> String {
+        let stats_note = match self.engine.try_stats() {
+            Ok(Some(stats)) => {
+                self.last_stats = Some((stats, SystemTime::now()));
+                None
+            }
+            Ok(None) => Some(match &self.last_stats {
+                Some((_, read_at)) => format!(
+                    "engine busy; stale as of {}s ago",
+                    read_at.elapsed().unwrap_or_default().as_secs()
+                ),
+                None => "engine busy; no figures read yet".to_string(),
+            }),
+            Err(err) => Some(format!("unavailable: {err}")),
+        };
+        let config = self.engine.config();
+        let compaction = self.engine.compaction_status();
+
+        let mut html = String::new();
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <meta http-equiv=\"refresh\" content=\"{REFRESH_SECS}\">\
+             <title>CrabKv status</title></head><body>\n<h1>CrabKv status</h1>\n"
+        );
+
+        section(&mut html, "Server");
+        row(
+            &mut html,
+            "Uptime",
+            &format!("{}s", self.started.elapsed().as_secs()),
+        );
+        row(
+            &mut html,
+            "Data directory",
+            &self.engine.directory().display().to_string(),
+        );
+        row(
+            &mut html,
+            "Handles",
+            &self.engine.handle_count().to_string(),
+        );
+        end_section(&mut html);
+
+        section(&mut html, "Configuration");
+        row(
+            &mut html,
+            "Cache capacity",
+            &optional(config.cache_capacity),
+        );
+        row(
+            &mut html,
+            "Write-back cache",
+            &config.write_back_cache.to_string(),
+        );
+        row(
+            &mut html,
+            "Default TTL",
+            &optional_duration(config.default_ttl),
+        );
+        row(
+            &mut html,
+            "Sync interval",
+            &optional_duration(config.sync_interval),
+        );
+        row(&mut html, "Compression", &config.compression.to_string());
+        row(&mut html, "Value dedup", &config.value_dedup.to_string());
+        row(
+            &mut html,
+            "Ordered index",
+            &config.ordered_index.to_string(),
+        );
+        row(
+            &mut html,
+            "Compaction threads",
+            &config.compaction_threads.to_string(),
+        );
+        row(
+            &mut html,
+            "Blob threshold",
+            &optional(config.blob_threshold),
+        );
+        row(&mut html, "Retention", &optional_duration(config.retention));
+        end_section(&mut html);
+
+        section(&mut html, "Engine");
+        if let Some(note) = &stats_note {
+            row(&mut html, "Note", note);
+        }
+        row(&mut html, "Mode", self.engine.mode().as_str());
+        if let Some((stats, _)) = &self.last_stats {
+            row(&mut html, "Live keys", &stats.keys.to_string());
+            row(
+                &mut html,
+                "Cache hit rate",
+                &stats
+                    .cache_hit_rate()
+                    .map_or_else(|| "-".to_string(), |rate| format!("{:.1}%", rate * 100.0)),
+            );
+            row(
+                &mut html,
+                "Keys with / without TTL",
+                &format!("{} / {}", stats.keys_with_ttl, stats.keys_without_ttl),
+            );
+            if let Some(ttl) = &stats.ttl_remaining {
+                let buckets: Vec<String> = TtlHistogram::LABELS
+                    .iter()
+                    .zip(ttl.counts)
+                    .map(|(label, count)| format!("{label}: {count}"))
+                    .collect();
+                row(&mut html, "TTL remaining", &buckets.join(", "));
+            }
+            row(
+                &mut html,
+                "Expired, not yet removed",
+                &stats.expired_unreclaimed.to_string(),
+            );
+            row(
+                &mut html,
+                "Expired / TTL extensions since open",
+                &format!("{} / {}", stats.expiry.expired, stats.ttl_extensions),
+            );
+            let scrub = stats.last_scrub.as_ref().map_or_else(
+                || "-".to_string(),
+                |scrub| {
+                    format!(
+                        "{}, {} damaged of {} records",
+                        unix_secs(Some(scrub.finished_at)),
+                        scrub.damaged_records,
+                        scrub.records_checked
+                    )
+                },
+            );
+            row(&mut html, "Last scrub", &scrub);
+        }
+        row(
+            &mut html,
+            "Mutations since open",
+            &self.engine.mutations_since_open().to_string(),
+        );
+        row(
+            &mut html,
+            "Bytes written since open",
+            &self.engine.bytes_written_since_open().to_string(),
+        );
+        row(
+            &mut html,
+            "Clock steps back",
+            &self.engine.clock_steps_back().to_string(),
+        );
+        end_section(&mut html);
+
+        section(&mut html, "Compaction");
+        row(
+            &mut html,
+            "In progress",
+            &compaction.in_progress.to_string(),
+        );
+        row(
+            &mut html,
+            "Last started",
+            &unix_secs(compaction.last_started),
+        );
+        row(
+            &mut html,
+            "Last finished",
+            &unix_secs(compaction.last_finished),
+        );
+        let outcome = match &compaction.last_outcome {
+            Some(CompactionOutcome::Succeeded) => "succeeded",
+            Some(CompactionOutcome::Failed(_)) => "failed",
+            None => "none",
+        };
+        row(&mut html, "Last outcome", outcome);
+        end_section(&mut html);
+
+        section(&mut html, "Background worker");
+        let health = match (compaction.degraded(), compaction.heartbeat_age) {
+            (Some(reason), _) => reason.to_string(),
+            (None, Some(age)) => format!("healthy, heartbeat {}ms ago", age.as_millis()),
+            (None, None) => "not running (async compaction disabled)".to_string(),
+        };
+        row(&mut html, "Health", &health);
+        row(
+            &mut html,
+            "Pending triggers",
+            &compaction.pending_triggers.to_string(),
+        );
+        end_section(&mut html);
+
+        section(&mut html, "Recent events");
+        let events = self.engine.recent_events();
+        let skip = events.len().saturating_sub(EVENTS_SHOWN);
+        if skip == events.len() {
+            row(&mut html, "None", "");
+        }
+        // Newest first, as an operator reads the table during an incident.
+        for event in events[skip..].iter().rev() {
+            row(
+                &mut html,
+                &unix_secs(Some(event.at)),
+                &event.kind.to_string(),
+            );
+        }
+        end_section(&mut html);
+
+        section(&mut html, "Recent background errors");
+        match &compaction.last_outcome {
+            Some(CompactionOutcome::Failed(err)) => row(&mut html, "Compaction", err),
+            _ => row(&mut html, "None", ""),
+        }
+        end_section(&mut html);
+
+        html.push_str("</body></html>\n");
+        html
+    }
+}
+
+fn section(html: &mut String, title: &str) {
+    let _ = write!(html, "<h2>{}</h2>\n<table>\n", escape(title));
+}
+
+fn end_section(html: &mut String) {
+    html.push_str("</table>\n");
+}
+
+fn row(html: &mut String, name: &str, value: &str) {
+    let _ = writeln!(
+        html,
+        "<tr><th align=\"left\">{}</th><td>{}</td></tr>",
+        escape(name),
+        escape(value)
+    );
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+fn optional_duration(value: Option<Duration>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| format!("{value:?}"))
+}
+
+/// Formats a time as seconds since the Unix epoch, or `-` when absent.
+fn unix_secs(time: Option<SystemTime>) -> String {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(|| "-".to_string(), |since| since.as_secs().to_string())
+}
+
+/// Escapes text for use inside HTML elements and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
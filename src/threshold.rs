@@ -0,0 +1,152 @@
+//! Soft limits that notify a callback when engine gauges cross them.
+//!
+//! Each limit fires once when its gauge rises above it and re-arms only after
+//! the gauge falls back below the limit less [`HYSTERESIS`], so a gauge
+//! hovering around the limit does not fire on every write.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Share of a limit a gauge must fall below it before the limit fires again.
+pub const HYSTERESIS: f64 = 0.1;
+
+/// Limit crossed upward, carrying the gauge's value at the time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThresholdEvent {
+    /// Bytes in the log, live and stale.
+    WalBytesAbove(u64),
+    /// Stale share of the log, from 0.0 to 1.0.
+    StaleRatioAbove(f64),
+    /// Keys in the index, including expired ones not yet removed.
+    KeyCountAbove(u64),
+    /// Unflushed writes in the write-back buffer.
+    WriteBufferAbove(usize),
+}
+
+/// Callback registered with [`CrabKvBuilder::on_threshold`].
+///
+/// [`CrabKvBuilder::on_threshold`]: crate::CrabKvBuilder::on_threshold
+#[derive(Clone)]
+pub struct ThresholdListener(Arc<dyn Fn(ThresholdEvent) + Send + Sync>);
+
+impl ThresholdListener {
+    /// Wraps the provided callback.
+    pub fn new(callback: impl Fn(ThresholdEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ThresholdListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ThresholdListener")
+    }
+}
+
+/// One limit on a gauge; the comparison is shared by soft and hard limits.
+#[derive(Debug)]
+pub(crate) struct Threshold {
+    limit: f64,
+    armed: AtomicBool,
+}
+
+impl Threshold {
+    pub fn new(limit: f64) -> Self {
+        Self {
+            limit,
+            armed: AtomicBool::new(true),
+        }
+    }
+
+    /// Returns whether `value` is above the limit.
+    pub fn exceeded(&self, value: f64) -> bool {
+        value > self.limit
+    }
+
+    /// Returns true the first time `value` rises above the limit, then false
+    /// until it has fallen below the re-arm level in between.
+    pub fn crossed(&self, value: f64) -> bool {
+        if self.exceeded(value) {
+            self.armed.swap(false, Ordering::Relaxed)
+        } else {
+            if value < self.limit * (1.0 - HYSTERESIS) {
+                self.armed.store(true, Ordering::Relaxed);
+            }
+            false
+        }
+    }
+}
+
+/// Gauges the soft limits are evaluated against.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Gauges {
+    pub wal_bytes: u64,
+    pub stale_bytes: u64,
+    pub keys: u64,
+    pub write_buffer: usize,
+}
+
+/// Soft limit levels set on the builder.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SoftLimitLevels {
+    pub wal_bytes: Option<u64>,
+    pub stale_ratio: Option<f64>,
+    pub keys: Option<u64>,
+    pub write_buffer: Option<usize>,
+}
+
+/// Configured soft limits and the listener they notify.
+#[derive(Debug, Default)]
+pub(crate) struct SoftLimits {
+    wal_bytes: Option<Threshold>,
+    stale_ratio: Option<Threshold>,
+    keys: Option<Threshold>,
+    write_buffer: Option<Threshold>,
+    listener: Option<ThresholdListener>,
+}
+
+impl SoftLimits {
+    pub fn new(levels: SoftLimitLevels, listener: Option<ThresholdListener>) -> Self {
+        Self {
+            wal_bytes: levels.wal_bytes.map(|level| Threshold::new(level as f64)),
+            stale_ratio: levels.stale_ratio.map(Threshold::new),
+            keys: levels.keys.map(|level| Threshold::new(level as f64)),
+            write_buffer: levels
+                .write_buffer
+                .map(|level| Threshold::new(level as f64)),
+            listener,
+        }
+    }
+
+    /// Passes every limit `gauges` newly crossed to `record`, then to the listener.
+    pub fn check(&self, gauges: Gauges, mut record: impl FnMut(ThresholdEvent)) {
+        let mut notify = |event| {
+            record(event);
+            if let Some(listener) = &self.listener {
+                (listener.0)(event);
+            }
+        };
+        let stale_ratio = if gauges.wal_bytes == 0 {
+            0.0
+        } else {
+            gauges.stale_bytes as f64 / gauges.wal_bytes as f64
+        };
+        let crossed = |threshold: &Option<Threshold>, value: f64| {
+            threshold
+                .as_ref()
+                .is_some_and(|threshold| threshold.crossed(value))
+        };
+        if crossed(&self.wal_bytes, gauges.wal_bytes as f64) {
+            notify(ThresholdEvent::WalBytesAbove(gauges.wal_bytes));
+        }
+        if crossed(&self.stale_ratio, stale_ratio) {
+            notify(ThresholdEvent::StaleRatioAbove(stale_ratio));
+        }
+        if crossed(&self.keys, gauges.keys as f64) {
+            notify(ThresholdEvent::KeyCountAbove(gauges.keys));
+        }
+        if crossed(&self.write_buffer, gauges.write_buffer as f64) {
+            notify(ThresholdEvent::WriteBufferAbove(gauges.write_buffer));
+        }
+    }
+}
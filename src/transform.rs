@@ -0,0 +1,112 @@
+//! Read-side transformations that return part of a stored value.
+
+use std::fmt;
+use std::io;
+
+/// Error payload returned when a transformation cannot be applied to a value.
+///
+/// Carried inside an [`io::Error`] of kind [`io::ErrorKind::InvalidInput`], or
+/// [`io::ErrorKind::InvalidData`] when the stored value is not valid JSON;
+/// recover it with `err.get_ref().and_then(|e| e.downcast_ref::<TransformError>())`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TransformError {
+    /// The range starts past the end of the value; lengths are in characters.
+    OutOfRange { start: usize, value_len: usize },
+    /// The stored value does not parse as JSON.
+    InvalidJson(String),
+    /// The JSON pointer is not valid RFC 6901 syntax.
+    BadPointer(String),
+    /// The JSON pointer does not reference any part of the document.
+    NoMatch(String),
+}
+
+impl TransformError {
+    /// Short machine-readable code, used as the first word of server `ERR` replies.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransformError::OutOfRange { .. } => "OUT_OF_RANGE",
+            TransformError::InvalidJson(_) => "INVALID_JSON",
+            TransformError::BadPointer(_) => "BAD_POINTER",
+            TransformError::NoMatch(_) => "NO_MATCH",
+        }
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.code())?;
+        match self {
+            TransformError::OutOfRange { start, value_len } => {
+                write!(
+                    f,
+                    "start {start} is past the end of a {value_len}-character value"
+                )
+            }
+            TransformError::InvalidJson(reason) => write!(f, "value is not JSON: {reason}"),
+            TransformError::BadPointer(pointer) => write!(f, "malformed JSON pointer `{pointer}`"),
+            TransformError::NoMatch(pointer) => write!(f, "nothing at JSON pointer `{pointer}`"),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+impl From<TransformError> for io::Error {
+    fn from(err: TransformError) -> Self {
+        let kind = match err {
+            TransformError::InvalidJson(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Returns up to `len` characters of `value` starting at character `start`.
+///
+/// Counting characters rather than bytes keeps multibyte UTF-8 sequences whole.
+/// A range running past the end is cut short; only a `start` beyond the end fails.
+pub fn char_range(value: &str, start: usize, len: usize) -> Result<String, TransformError> {
+    let value_len = value.chars().count();
+    if start > value_len {
+        return Err(TransformError::OutOfRange { start, value_len });
+    }
+    let from = value
+        .char_indices()
+        .nth(start)
+        .map_or(value.len(), |(offset, _)| offset);
+    let to = value[from..]
+        .char_indices()
+        .nth(len)
+        .map_or(value.len(), |(offset, _)| from + offset);
+    Ok(value[from..to].to_string())
+}
+
+/// Applies an RFC 6901 JSON pointer to `value` and returns the matched fragment
+/// serialized as JSON.
+#[cfg(feature = "json")]
+pub fn json_pointer(value: &str, pointer: &str) -> Result<String, TransformError> {
+    if !is_valid_pointer(pointer) {
+        return Err(TransformError::BadPointer(pointer.to_string()));
+    }
+    let document: serde_json::Value =
+        serde_json::from_str(value).map_err(|err| TransformError::InvalidJson(err.to_string()))?;
+    let fragment = document
+        .pointer(pointer)
+        .ok_or_else(|| TransformError::NoMatch(pointer.to_string()))?;
+    Ok(fragment.to_string())
+}
+
+/// Checks that the pointer is empty or `/`-prefixed and only uses `~0` and `~1` escapes.
+#[cfg(feature = "json")]
+fn is_valid_pointer(pointer: &str) -> bool {
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return false;
+    }
+    let mut chars = pointer.chars();
+    while let Some(c) = chars.next() {
+        if c == '~' && !matches!(chars.next(), Some('0' | '1')) {
+            return false;
+        }
+    }
+    true
+}
@@ -0,0 +1,125 @@
+//! What reads and compaction do with a stored value that is not valid UTF-8,
+//! such as one a binary client wrote or one damaged on disk.
+
+use std::fmt;
+
+/// Chosen with [`CrabKvBuilder::utf8_policy`](crate::CrabKvBuilder::utf8_policy).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Utf8Policy {
+    /// Reads fail with an [`InvalidUtf8`] error, and compaction quarantines
+    /// the key; opening a log holding such a value fails.
+    #[default]
+    Error,
+    /// Invalid sequences are replaced with U+FFFD, counted in
+    /// [`EngineStats::lossy_utf8_reads`](crate::EngineStats::lossy_utf8_reads).
+    /// Compaction writes the repaired value back.
+    Lossy,
+    /// The key reads as missing and is quarantined, on open, on its first
+    /// read, or by compaction, whichever comes first.
+    Skip,
+}
+
+impl fmt::Display for Utf8Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Utf8Policy::Error => "error",
+            Utf8Policy::Lossy => "lossy",
+            Utf8Policy::Skip => "skip",
+        })
+    }
+}
+
+/// Error payload returned when a stored value is not valid UTF-8 under
+/// [`Utf8Policy::Error`].
+///
+/// Carried inside an [`io::Error`](std::io::Error) of kind
+/// [`InvalidData`](std::io::ErrorKind::InvalidData); match it with
+/// `err.get_ref().and_then(|e| e.downcast_ref::<InvalidUtf8>())`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidUtf8 {
+    /// The key that was read. With value dedup the record at `offset` may
+    /// have been written under another key that shares the value.
+    pub key: String,
+    /// Offset of the record holding the value in the log.
+    pub offset: u64,
+}
+
+impl fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the value of `{}` at log offset {} is not valid utf-8",
+            self.key, self.offset
+        )
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+impl InvalidUtf8 {
+    /// Returns the payload `err` carries, if any.
+    pub fn of(err: &std::io::Error) -> Option<&InvalidUtf8> {
+        err.get_ref()?.downcast_ref::<InvalidUtf8>()
+    }
+
+    /// `err` with its payload naming `key`, the key the caller read, rather
+    /// than the one the record was written under.
+    pub(crate) fn naming(err: std::io::Error, key: &str) -> std::io::Error {
+        match Self::of(&err) {
+            Some(invalid) if invalid.key != key => std::io::Error::new(
+                err.kind(),
+                InvalidUtf8 {
+                    key: key.to_owned(),
+                    offset: invalid.offset,
+                },
+            ),
+            _ => err,
+        }
+    }
+}
+
+/// Error payload returned when a read that yields a string meets a value
+/// stored with [`CrabKv::put_bytes`](crate::CrabKv::put_bytes) that is not
+/// valid UTF-8; [`CrabKv::get_bytes`](crate::CrabKv::get_bytes) reads it.
+///
+/// Carried inside an [`io::Error`](std::io::Error) of kind
+/// [`InvalidData`](std::io::ErrorKind::InvalidData). Such a value was written
+/// on purpose, so unlike [`InvalidUtf8`] no [`Utf8Policy`] applies to it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BinaryValue {
+    /// The key whose value is binary.
+    pub key: String,
+}
+
+impl fmt::Display for BinaryValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the value of `{}` is binary; read it with get_bytes",
+            self.key
+        )
+    }
+}
+
+impl std::error::Error for BinaryValue {}
+
+impl BinaryValue {
+    /// Returns the payload `err` carries, if any.
+    pub fn of(err: &std::io::Error) -> Option<&BinaryValue> {
+        err.get_ref()?.downcast_ref::<BinaryValue>()
+    }
+
+    pub(crate) fn error(key: &str) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            BinaryValue {
+                key: key.to_owned(),
+            },
+        )
+    }
+}
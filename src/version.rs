@@ -0,0 +1,63 @@
+//! Version and build details for support bundles and startup logs.
+
+use std::fmt;
+
+/// Cargo features compiled into this build.
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "client")]
+    "client",
+    #[cfg(feature = "config-file")]
+    "config-file",
+    #[cfg(feature = "json")]
+    "json",
+    #[cfg(feature = "serde")]
+    "serde",
+];
+
+/// What was built, from which commit, and when.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BuildInfo {
+    /// Crate version, e.g. `0.1.0`.
+    pub version: &'static str,
+    /// Abbreviated git commit, or `unknown` when built outside a checkout.
+    pub git_hash: &'static str,
+    /// UTC build date as `YYYY-MM-DD`.
+    pub build_date: &'static str,
+    /// Enabled optional cargo features.
+    pub features: &'static [&'static str],
+}
+
+/// Returns the details of the running build.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("CRABKV_GIT_HASH"),
+        build_date: env!("CRABKV_BUILD_DATE"),
+        features: FEATURES,
+    }
+}
+
+impl BuildInfo {
+    /// Enabled features joined with commas, or `none`.
+    pub fn feature_list(&self) -> String {
+        if self.features.is_empty() {
+            "none".to_string()
+        } else {
+            self.features.join(",")
+        }
+    }
+}
+
+/// Formats as `crabkv 0.1.0 (git 1a2b3c4d5e6f, built 2024-05-01, features: json)`.
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "crabkv {} (git {}, built {}, features: {})",
+            self.version,
+            self.git_hash,
+            self.build_date,
+            self.feature_list()
+        )
+    }
+}
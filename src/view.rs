@@ -0,0 +1,53 @@
+//! Consistent multi-key reads without blocking writers.
+
+use crate::engine::CrabKv;
+use std::fmt;
+use std::io;
+
+/// Read-only view of the store as of the moment it was taken.
+///
+/// A view holds no lock and pins nothing. Each read checks that the key has not
+/// been written since the view was taken; if it has, the read fails with
+/// [`ViewInvalidated`] rather than return a value the view must not see. Keys
+/// written together by one batch share a sequence number, so a view sees all
+/// of a batch or none of it. Deleting any key after the view was taken also
+/// invalidates reads of keys the view finds missing.
+#[derive(Clone)]
+pub struct ReadView {
+    engine: CrabKv,
+    lsn: u64,
+}
+
+impl ReadView {
+    pub(crate) fn new(engine: CrabKv, lsn: u64) -> Self {
+        Self { engine, lsn }
+    }
+
+    /// Returns the log sequence number the view reads at.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    /// Returns the key's value as the view sees it.
+    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+        self.engine.get_at(key, self.lsn)
+    }
+}
+
+/// Error payload returned when a key changed after a [`ReadView`] was taken.
+///
+/// Carried inside an [`io::Error`] of kind [`io::ErrorKind::Other`]; recover it
+/// with `err.get_ref().and_then(|e| e.downcast_ref::<ViewInvalidated>())` and
+/// retry with a fresh view.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ViewInvalidated {
+    pub key: String,
+}
+
+impl fmt::Display for ViewInvalidated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` changed after the read view was taken", self.key)
+    }
+}
+
+impl std::error::Error for ViewInvalidated {}
@@ -0,0 +1,185 @@
+//! Read-only access to the log of a store that is not open, for tooling that
+//! should not depend on the record format.
+
+use crate::internals::blob::BlobStore;
+use crate::internals::wal::{LoadedLog, Wal, WalEntry};
+use crate::utf8::BinaryValue;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// A data directory's `wal.log`, replayed once on open without modifying it.
+#[derive(Debug)]
+pub struct WalFile {
+    wal: Wal,
+    loaded: LoadedLog,
+    compressed: bool,
+    blobs: BlobStore,
+}
+
+/// What [`WalFile::verify`] found.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WalVerification {
+    /// Size of `wal.log` on disk.
+    pub file_len: u64,
+    /// Length up to the last complete record or batch.
+    pub valid_len: u64,
+    /// Keys live when the log was opened.
+    pub live_keys: usize,
+    /// Bytes held by superseded, deleted or expired records.
+    pub stale_bytes: u64,
+    /// Records skipped because their opcode was not recognised.
+    pub unknown_skipped: u64,
+    /// Whether values are Snappy-compressed.
+    pub compressed: bool,
+}
+
+impl WalVerification {
+    /// Bytes after the last complete record, left by an interrupted write.
+    pub fn torn_bytes(&self) -> u64 {
+        self.file_len.saturating_sub(self.valid_len)
+    }
+
+    /// Whether every byte belongs to a complete, recognised record.
+    pub fn is_clean(&self) -> bool {
+        self.torn_bytes() == 0 && self.unknown_skipped == 0
+    }
+}
+
+impl WalFile {
+    /// Replays `wal.log` in the data directory `dir` as of now.
+    ///
+    /// Fails with [`ErrorKind::NotFound`] when the directory has no log.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_at(dir.as_ref(), SystemTime::now())
+    }
+
+    /// Like [`WalFile::open`], deciding expiry as of `now`.
+    pub(crate) fn open_at(dir: &Path, now: SystemTime) -> io::Result<Self> {
+        let path = dir.join("wal.log");
+        if !path.exists() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+        let (wal, loaded, compressed) = replay(&path, now)?;
+        Ok(Self {
+            wal,
+            loaded,
+            compressed,
+            blobs: BlobStore::new(dir.join("blobs")),
+        })
+    }
+
+    /// Number of live keys.
+    pub fn len(&self) -> usize {
+        self.loaded.index.len()
+    }
+
+    /// Whether no key is live.
+    pub fn is_empty(&self) -> bool {
+        self.loaded.index.is_empty()
+    }
+
+    /// Live keys and their values in key order, reading each value as the
+    /// iterator reaches it. A binary value yields a [`BinaryValue`] error.
+    pub fn iter(&self) -> impl Iterator<Item = io::Result<(String, String)>> + '_ {
+        let mut keys: Vec<&String> = self.loaded.index.keys().collect();
+        keys.sort_unstable();
+        keys.into_iter().map(|key| {
+            let value = self.read_value(key)?;
+            Ok((key.clone(), value))
+        })
+    }
+
+    /// Checks the log for a torn tail and unknown records, and reads every
+    /// live value so a damaged record or missing blob surfaces as an error.
+    pub fn verify(&self) -> io::Result<WalVerification> {
+        for entry in self.iter() {
+            match entry {
+                // Decoded in full, just not as a string.
+                Err(err) if BinaryValue::of(&err).is_some() => {}
+                entry => {
+                    entry?;
+                }
+            }
+        }
+        Ok(WalVerification {
+            file_len: self.wal.size()?,
+            valid_len: self.loaded.valid_len,
+            live_keys: self.loaded.index.len(),
+            stale_bytes: self.loaded.stale_bytes,
+            unknown_skipped: self.loaded.unknown_skipped,
+            compressed: self.compressed,
+        })
+    }
+
+    /// Every live key as a put of its value, with the expiry and write time
+    /// the index holds, in key order. Values are read through the pointer
+    /// only, since a deduplicated key points at another key's record.
+    pub(crate) fn live_entries(&self) -> io::Result<Vec<WalEntry>> {
+        let mut keys: Vec<&String> = self.loaded.index.keys().collect();
+        keys.sort_unstable();
+        keys.into_iter()
+            .map(|key| {
+                let entry = self.loaded.index[key];
+                let (key, expires_at, written_at) =
+                    (key.clone(), entry.expires_at, entry.written_at);
+                match self.wal.read_record(entry.pointer)?.entry {
+                    WalEntry::Put { value, .. } => Ok(WalEntry::Put {
+                        key,
+                        value,
+                        expires_at,
+                        written_at,
+                    }),
+                    WalEntry::Bytes { value, .. } => Ok(WalEntry::Bytes {
+                        key,
+                        value,
+                        expires_at,
+                        written_at,
+                    }),
+                    WalEntry::Blob { blob, .. } => Ok(WalEntry::Put {
+                        value: self.blobs.read(&blob)?,
+                        key,
+                        expires_at,
+                        written_at,
+                    }),
+                    WalEntry::Delete { .. } | WalEntry::Token { .. } => Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("live key {key} points at a delete or token record"),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    fn read_value(&self, key: &str) -> io::Result<String> {
+        let entry = self.loaded.index[key];
+        match self.wal.read_record(entry.pointer)?.entry {
+            WalEntry::Put { value, .. } => Ok(value),
+            WalEntry::Bytes { .. } => Err(BinaryValue::error(key)),
+            WalEntry::Blob { blob, .. } => self.blobs.read(&blob),
+            WalEntry::Delete { .. } | WalEntry::Token { .. } => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("live key {key} points at a delete or token record"),
+            )),
+        }
+    }
+}
+
+/// Replays the log without modifying it, trying plain values first and Snappy
+/// second since the log does not record which was used.
+pub(crate) fn replay(path: &Path, now: SystemTime) -> io::Result<(Wal, LoadedLog, bool)> {
+    let mut first_err = None;
+    for compressed in [false, true] {
+        let wal = Wal::open(path, None, compressed)?.skip_unknown_ops(true);
+        match wal.load_index(now) {
+            Ok(loaded) => return Ok((wal, loaded, compressed)),
+            Err(err) => {
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    Err(first_err.expect("both replays failed"))
+}
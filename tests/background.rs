@@ -0,0 +1,127 @@
+use crabkv::{BackgroundError, BackgroundWorker, CrabKv, ManualClock};
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+mod common;
+
+use common::wait_for;
+
+fn crash_of(engine: &CrabKv, worker: BackgroundWorker) -> Option<BackgroundError> {
+    engine
+        .background_errors()
+        .into_iter()
+        .find(|error| error.worker == worker)
+}
+
+fn assert_crash_reported(engine: &CrabKv, worker: BackgroundWorker) -> io::Result<()> {
+    wait_for(
+        "the crash report",
+        || Ok(crash_of(engine, worker).is_some()),
+    )?;
+    let error = crash_of(engine, worker).unwrap();
+    let message = error.error.to_string();
+    assert!(message.contains("injected crash"), "{message}");
+    assert_eq!(engine.recent_background_error().unwrap().worker, worker);
+    Ok(())
+}
+
+#[test]
+fn compaction_worker_restarts_after_a_crash() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).async_compaction(true).build()?;
+    for i in 0..10 {
+        engine.put("key".into(), format!("v{i}"))?;
+    }
+    assert!(engine.background_errors().is_empty());
+
+    engine.crash_background_worker(BackgroundWorker::Compaction);
+    assert_crash_reported(&engine, BackgroundWorker::Compaction)?;
+    wait_for("the worker restart", || {
+        Ok(engine.compaction_status().worker_running)
+    })?;
+    engine.compact_and_wait()?;
+    assert_eq!(engine.compaction_count()?, 1);
+    assert_eq!(engine.get("key")?.as_deref(), Some("v9"));
+    Ok(())
+}
+
+#[test]
+fn wal_flusher_restarts_after_a_crash() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_millis(5))
+        .build()?;
+
+    engine.crash_background_worker(BackgroundWorker::WalFlusher);
+    assert_crash_reported(&engine, BackgroundWorker::WalFlusher)?;
+    let syncs = engine.stats()?.log_syncs;
+    engine.put("k".into(), "v".into())?;
+    wait_for("a sync after the restart", || {
+        Ok(engine.stats()?.log_syncs > syncs)
+    })
+}
+
+#[test]
+fn a_migrated_log_reports_to_the_same_engine() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_millis(5))
+        .build()?;
+    let moved = tempfile::tempdir()?;
+    engine.migrate_to(moved.path())?;
+
+    engine.crash_background_worker(BackgroundWorker::WalFlusher);
+    assert_crash_reported(&engine, BackgroundWorker::WalFlusher)
+}
+
+#[test]
+fn checkpoint_writer_retries_after_a_crash() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .checkpoint_mutations(10)
+        .checkpoint_min_interval(Duration::ZERO)
+        .build()?;
+
+    engine.crash_background_worker(BackgroundWorker::Checkpoint);
+    for i in 0..10 {
+        engine.put(format!("key{i}"), "v".into())?;
+    }
+    assert_crash_reported(&engine, BackgroundWorker::Checkpoint)?;
+    assert_eq!(engine.checkpoint_status()?.last_taken, None);
+
+    // The next write after the backoff starts a checkpoint again.
+    let mut i = 10;
+    wait_for("a checkpoint after the crash", || {
+        engine.put(format!("key{i}"), "v".into())?;
+        i += 1;
+        let status = engine.checkpoint_status()?;
+        Ok(status.last_taken.is_some() && !status.in_progress)
+    })?;
+    assert_eq!(engine.background_errors().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn errors_stop_counting_as_recent_after_the_window() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .sync_interval(Duration::from_millis(5))
+        .background_error_window(Duration::from_secs(60))
+        .build()?;
+    assert!(engine.recent_background_error().is_none());
+
+    engine.crash_background_worker(BackgroundWorker::WalFlusher);
+    assert_crash_reported(&engine, BackgroundWorker::WalFlusher)?;
+    clock.advance(Duration::from_secs(59));
+    assert!(engine.recent_background_error().is_some());
+    clock.advance(Duration::from_secs(1));
+    assert!(engine.recent_background_error().is_none());
+    // Still listed until cleared.
+    assert_eq!(engine.background_errors().len(), 1);
+
+    engine.clear_background_errors();
+    assert!(engine.background_errors().is_empty());
+    Ok(())
+}
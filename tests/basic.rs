@@ -1,8 +1,9 @@
-use crabkv::CrabKv;
+use crabkv::{CrabKv, ManualClock, WriteBatch};
 use std::fs;
 use std::io;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::thread::sleep;
+use std::thread::{self, sleep};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[test]
@@ -56,6 +57,778 @@ fn ttl_expiration() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn len_and_keys_skip_expired_entries() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+    assert!(engine.is_empty()?);
+    assert!(engine.keys()?.is_empty());
+
+    engine.put("b".into(), "2".into())?;
+    engine.put("a".into(), "1".into())?;
+    engine.put("a".into(), "one".into())?;
+    engine.put_with_ttl("c".into(), "3".into(), Some(Duration::from_secs(5)))?;
+    engine.put("gone".into(), "x".into())?;
+    engine.delete("gone")?;
+    assert_eq!(engine.len()?, 3);
+    assert_eq!(engine.keys()?, ["a", "b", "c"]);
+
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(engine.len()?, 2);
+    assert_eq!(engine.keys()?, ["a", "b"]);
+    assert!(!engine.is_empty()?);
+    Ok(())
+}
+
+#[test]
+fn contains_key_reads_only_the_index() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+    engine.put("kept".into(), "1".into())?;
+    engine.put_with_ttl("short".into(), "2".into(), Some(Duration::from_secs(5)))?;
+    engine.put("gone".into(), "3".into())?;
+    engine.delete("gone")?;
+
+    // With the log emptied behind its back, only the index can answer.
+    fs::OpenOptions::new()
+        .write(true)
+        .open(temp.path().join("wal.log"))?
+        .set_len(0)?;
+    assert!(engine.contains_key("kept")?);
+    assert!(engine.contains_key("short")?);
+    assert!(!engine.contains_key("gone")?);
+    assert!(!engine.contains_key("missing")?);
+
+    // An expired key is reported like a read would report it.
+    let expired = engine.subscribe_expired()?;
+    clock.advance(Duration::from_secs(6));
+    assert!(!engine.contains_key("short")?);
+    assert!(!engine.contains_key("short")?);
+    assert_eq!(expired.try_iter().collect::<Vec<_>>(), ["short"]);
+    assert_eq!(engine.stats()?.expiry.queued, 1);
+    Ok(())
+}
+
+#[test]
+fn last_write_time_tracks_mutations() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    assert_eq!(engine.last_write_time()?, None);
+
+    engine.put("alpha".into(), "1".into())?;
+    let now = SystemTime::now();
+    let written = engine.last_write_time()?.expect("write recorded");
+    let drift = now
+        .duration_since(written)
+        .unwrap_or_else(|err| err.duration());
+    assert!(drift < Duration::from_secs(1));
+
+    drop(engine);
+    let engine = CrabKv::open(temp.path())?;
+    assert!(engine.last_write_time()?.is_some());
+    Ok(())
+}
+
+#[test]
+fn expired_keys_are_dropped_on_load() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    let batch = (0..10_000)
+        .map(|i| {
+            (
+                format!("k{i}"),
+                "v".to_string(),
+                Some(Duration::from_millis(50)),
+            )
+        })
+        .collect();
+    engine.put_batch(batch)?;
+    drop(engine);
+    sleep(Duration::from_millis(100));
+
+    let wal = temp.path().join("wal.log");
+    let size_before = fs::metadata(&wal)?.len();
+    let engine = CrabKv::open(temp.path())?;
+    let report = engine.open_report();
+    assert_eq!(report.live_keys, 0);
+    assert_eq!(report.expired_skipped, 10_000);
+    assert_eq!(report.stale_bytes, size_before);
+    assert_eq!(fs::metadata(&wal)?.len(), size_before);
+
+    engine.compact()?;
+    assert!(fs::metadata(&wal)?.len() < size_before);
+    Ok(())
+}
+
+#[test]
+fn change_counters_track_user_mutations() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    assert_eq!(engine.mutations_since_open(), 0);
+
+    engine.put("a".into(), "1".into())?;
+    let marker = engine.change_marker();
+    engine.put_batch(vec![
+        ("b".into(), "2".into(), None),
+        ("c".into(), "3".into(), None),
+    ])?;
+    engine.delete("a")?;
+    assert_eq!(engine.mutations_since_open(), 4);
+    assert_eq!(engine.mutations_since(marker), 3);
+
+    let written = engine.bytes_written_since_open();
+    assert_eq!(written, fs::metadata(temp.path().join("wal.log"))?.len());
+
+    engine.compact()?;
+    assert_eq!(engine.mutations_since_open(), 4);
+    assert_eq!(engine.bytes_written_since_open(), written);
+    Ok(())
+}
+
+#[test]
+fn write_batch_applies_puts_and_deletes_in_order() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    {
+        let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+        engine.put("gone".into(), "old".into())?;
+        engine.put("revived".into(), "old".into())?;
+        let batch = WriteBatch::new()
+            .put("a".into(), "1".into(), None)
+            .put("b".into(), "2".into(), Some(Duration::from_secs(5)))
+            .delete("gone".into())
+            .put("c".into(), "3".into(), None)
+            .delete("c".into())
+            .delete("revived".into())
+            .put("revived".into(), "new".into(), None)
+            .put("a".into(), "10".into(), None);
+        assert_eq!(batch.len(), 8);
+        let before = engine.mutations_since_open();
+
+        // A failed append applies nothing.
+        engine.fail_next_appends(1)?;
+        assert!(engine.apply(batch.clone()).is_err());
+        assert_eq!(engine.mutations_since_open(), before);
+        assert_eq!(engine.get("gone")?.as_deref(), Some("old"));
+        assert_eq!(engine.get("a")?, None);
+
+        engine.apply(batch)?;
+        assert_eq!(engine.mutations_since_open(), before + 8);
+        engine.apply(WriteBatch::new())?;
+        assert_eq!(engine.mutations_since_open(), before + 8);
+    }
+
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.get("a")?.as_deref(), Some("10"));
+    assert_eq!(engine.get("b")?.as_deref(), Some("2"));
+    assert_eq!(engine.get("c")?, None);
+    assert_eq!(engine.get("gone")?, None);
+    assert_eq!(engine.get("revived")?.as_deref(), Some("new"));
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(engine.keys()?, ["a", "revived"]);
+    Ok(())
+}
+
+#[test]
+fn pop_takes_a_value_exactly_once() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(temp.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .clock(clock.clock())
+        .build()?;
+    let wal_len = || fs::metadata(temp.path().join("wal.log")).map(|meta| meta.len());
+
+    engine.put("job".into(), "payload".into())?;
+    assert_eq!(engine.pop("job")?.as_deref(), Some("payload"));
+    assert_eq!(engine.get("job")?, None);
+    assert_eq!(engine.pop("job")?, None);
+
+    // A missing key writes nothing.
+    let len = wal_len()?;
+    assert_eq!(engine.pop("never")?, None);
+    assert_eq!(wal_len()?, len);
+
+    // An expired key is not returned but is still removed.
+    engine.put_with_ttl("late".into(), "v".into(), Some(Duration::from_secs(1)))?;
+    clock.advance(Duration::from_secs(2));
+    let len = wal_len()?;
+    assert_eq!(engine.pop("late")?, None);
+    assert!(wal_len()? > len);
+    assert_eq!(engine.stats()?.keys, 0);
+
+    // Of many workers taking the same items, each item goes to exactly one.
+    for i in 0..50 {
+        engine.put(format!("item:{i}"), format!("{i}"))?;
+    }
+    let taken: Vec<Vec<String>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| {
+                    (0..50)
+                        .filter_map(|i| engine.pop(&format!("item:{i}")).unwrap())
+                        .collect()
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+    let mut all: Vec<String> = taken.into_iter().flatten().collect();
+    all.sort_by_key(|value| value.parse::<u32>().unwrap());
+    assert_eq!(all, (0..50).map(|i| i.to_string()).collect::<Vec<_>>());
+    drop(engine);
+
+    let engine = CrabKv::open(temp.path())?;
+    assert_eq!(engine.len()?, 0);
+    Ok(())
+}
+
+#[test]
+fn get_set_returns_the_value_it_replaces() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(temp.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .default_ttl(Duration::from_secs(10))
+        .clock(clock.clock())
+        .build()?;
+
+    assert_eq!(engine.get_set("k".into(), "one".into())?, None);
+    assert_eq!(
+        engine.get_set("k".into(), "two".into())?.as_deref(),
+        Some("one")
+    );
+    assert_eq!(engine.get("k")?.as_deref(), Some("two"));
+
+    // The new value takes the default TTL, and an expired one reads as absent.
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(engine.get("k")?, None);
+    assert_eq!(engine.get_set("k".into(), "three".into())?, None);
+    assert_eq!(engine.get("k")?.as_deref(), Some("three"));
+
+    // Concurrent swaps form one chain: every value is handed back exactly once.
+    engine.put("slot".into(), "start".into())?;
+    let returned: Vec<String> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let engine = &engine;
+                scope.spawn(move || {
+                    (0..25)
+                        .map(|i| {
+                            engine
+                                .get_set("slot".into(), format!("{worker}:{i}"))
+                                .unwrap()
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    });
+    let mut seen: Vec<String> = returned;
+    seen.extend(engine.get("slot")?);
+    seen.sort();
+    let mut written: Vec<String> = (0..4)
+        .flat_map(|worker| (0..25).map(move |i| format!("{worker}:{i}")))
+        .chain(["start".to_string()])
+        .collect();
+    written.sort();
+    assert_eq!(seen, written);
+    drop(engine);
+
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.get("k")?.as_deref(), Some("three"));
+    Ok(())
+}
+
+#[test]
+fn delete_batch_removes_keys_and_skips_missing_ones() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    {
+        let engine = CrabKv::open(temp.path())?;
+        let batch: Vec<(String, String, Option<Duration>)> = (0..100)
+            .map(|i| (format!("session:{i}"), "data".to_string(), None))
+            .collect();
+        engine.put_batch(batch)?;
+
+        let syncs = engine.stats()?.log_syncs;
+        let doomed: Vec<String> = (0..50)
+            .map(|i| format!("session:{i}"))
+            .chain(["session:0".into(), "missing".into()])
+            .collect();
+        engine.delete_batch(doomed)?;
+        assert_eq!(engine.stats()?.log_syncs, syncs + 1);
+        assert_eq!(engine.mutations_since_open(), 150);
+
+        let written = engine.bytes_written_since_open();
+        engine.delete_batch(vec!["missing".into(), "session:0".into()])?;
+        engine.delete_batch(Vec::new())?;
+        assert_eq!(engine.bytes_written_since_open(), written);
+    }
+
+    let engine = CrabKv::open(temp.path())?;
+    assert_eq!(engine.get("session:49")?, None);
+    assert_eq!(engine.get("session:50")?, Some("data".into()));
+    assert_eq!(engine.stats()?.keys, 50);
+    Ok(())
+}
+
+#[test]
+fn swap_exchanges_values_and_ttls() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    engine.put_with_ttl(
+        "config:active".into(),
+        "blue".into(),
+        Some(Duration::from_millis(100)),
+    )?;
+    engine.put("config:staging".into(), "green".into())?;
+
+    engine.swap("config:active", "config:staging")?;
+    assert_eq!(engine.get("config:active")?, Some("green".into()));
+    assert_eq!(engine.get("config:staging")?, Some("blue".into()));
+
+    sleep(Duration::from_millis(150));
+    assert_eq!(engine.get("config:active")?, Some("green".into()));
+    assert_eq!(engine.get("config:staging")?, None);
+
+    engine.swap("config:active", "config:missing")?;
+    assert_eq!(engine.get("config:active")?, None);
+    assert_eq!(engine.get("config:missing")?, Some("green".into()));
+    Ok(())
+}
+
+#[test]
+fn put_if_absent_inserts_once_and_replaces_expired_keys() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+
+    assert!(engine.put_if_absent("lock".into(), "a".into(), Some(Duration::from_secs(10)))?);
+    assert!(!engine.put_if_absent("lock".into(), "b".into(), None)?);
+    assert_eq!(engine.get("lock")?, Some("a".into()));
+
+    clock.advance(Duration::from_secs(11));
+    let stale_before = engine.pressure_gauges().stale_bytes;
+    assert!(engine.put_if_absent("lock".into(), "c".into(), None)?);
+    assert_eq!(engine.get("lock")?, Some("c".into()));
+    assert!(engine.pressure_gauges().stale_bytes > stale_before);
+
+    drop(engine);
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.get("lock")?, Some("c".into()));
+    Ok(())
+}
+
+#[test]
+fn put_if_absent_has_one_winner_across_handles() -> io::Result<()> {
+    const THREADS: usize = 8;
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    let workers: Vec<_> = (0..THREADS)
+        .map(|worker| {
+            let engine = engine.clone();
+            thread::spawn(move || engine.put_if_absent("leader".into(), worker.to_string(), None))
+        })
+        .collect();
+    let mut winners = Vec::new();
+    for (worker, handle) in workers.into_iter().enumerate() {
+        if handle.join().unwrap()? {
+            winners.push(worker.to_string());
+        }
+    }
+    assert_eq!(winners.len(), 1);
+    assert_eq!(engine.get("leader")?, winners.pop());
+    Ok(())
+}
+
+#[test]
+fn incr_counts_from_zero_and_keeps_the_expiry() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+
+    assert_eq!(engine.incr("hits", 1)?, 1);
+    assert_eq!(engine.incr("hits", 5)?, 6);
+    assert_eq!(engine.incr("hits", -10)?, -4);
+    assert_eq!(engine.get("hits")?, Some("-4".into()));
+
+    engine.put_with_ttl("window".into(), "7".into(), Some(Duration::from_secs(10)))?;
+    assert_eq!(engine.incr("window", 1)?, 8);
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(engine.get("window")?, None);
+    assert_eq!(engine.incr("window", 1)?, 1);
+
+    drop(engine);
+    let engine = CrabKv::builder(temp.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.incr("hits", 4)?, 0);
+    Ok(())
+}
+
+#[test]
+fn expire_extends_and_clears_ttls_in_place() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let open = || CrabKv::builder(temp.path()).clock(clock.clock()).build();
+    let engine = open()?;
+    let session = "s".repeat(4096);
+    engine.put_with_ttl(
+        "session".into(),
+        session.clone(),
+        Some(Duration::from_secs(10)),
+    )?;
+    engine.put("forever".into(), "v".into())?;
+
+    clock.advance(Duration::from_secs(8));
+    let stale_before = engine.pressure_gauges().stale_bytes;
+    assert!(engine.expire("session", Some(Duration::from_secs(10)))?);
+    assert!(engine.pressure_gauges().stale_bytes >= stale_before + session.len() as u64);
+    clock.advance(Duration::from_secs(8));
+    assert_eq!(engine.get("session")?, Some(session.clone()));
+
+    // `None` persists the key; a TTL on a key without one sets it.
+    assert!(engine.expire("session", None)?);
+    assert!(engine.expire("forever", Some(Duration::from_secs(5)))?);
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(engine.get("forever")?, None);
+    assert!(!engine.expire("forever", None)?);
+    assert!(!engine.expire("missing", None)?);
+
+    drop(engine);
+    let engine = open()?;
+    clock.advance(Duration::from_secs(3600));
+    assert_eq!(engine.get("session")?, Some(session));
+    assert_eq!(engine.get("forever")?, None);
+    Ok(())
+}
+
+#[test]
+fn incr_refuses_non_integers_and_overflow() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    engine.put("name".into(), "crab".into())?;
+    let err = engine.incr("name", 1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(engine.get("name")?, Some("crab".into()));
+
+    engine.put("big".into(), i64::MAX.to_string())?;
+    let err = engine.incr("big", 1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(engine.get("big")?, Some(i64::MAX.to_string()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_incr_loses_no_updates() -> io::Result<()> {
+    const THREADS: usize = 4;
+    const INCREMENTS: i64 = 250;
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    let workers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> io::Result<()> {
+                for _ in 0..INCREMENTS {
+                    engine.incr("counter", 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    assert_eq!(
+        engine.get("counter")?,
+        Some((THREADS as i64 * INCREMENTS).to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn update_many_transfers_never_create_or_destroy_value() -> io::Result<()> {
+    const THREADS: usize = 4;
+    const TRANSFERS: usize = 200;
+    let temp = TempDir::new()?;
+    let engine = CrabKv::builder(temp.path())
+        .cache_capacity(NonZeroUsize::new(2).unwrap())
+        .build()?;
+    engine.put("account:a".into(), "1000".into())?;
+    engine.put("account:b".into(), "1000".into())?;
+
+    let workers: Vec<_> = (0..THREADS)
+        .map(|worker| {
+            let engine = engine.clone();
+            thread::spawn(move || -> io::Result<()> {
+                let (from, to) = if worker % 2 == 0 {
+                    ("account:a", "account:b")
+                } else {
+                    ("account:b", "account:a")
+                };
+                for amount in (0..TRANSFERS).map(|i| (i % 7 + 1) as u64) {
+                    engine.update_many(&[from, to], |current| {
+                        let balance = |key: &str| -> u64 {
+                            current[key].as_deref().unwrap().parse().unwrap()
+                        };
+                        let moved = amount.min(balance(from));
+                        vec![
+                            (from.to_string(), Some((balance(from) - moved).to_string())),
+                            (to.to_string(), Some((balance(to) + moved).to_string())),
+                        ]
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    let total = |engine: &CrabKv| -> io::Result<u64> {
+        let mut total = 0;
+        for key in ["account:a", "account:b"] {
+            total += engine.get(key)?.unwrap().parse::<u64>().unwrap();
+        }
+        Ok(total)
+    };
+    assert_eq!(total(&engine)?, 2000);
+    drop(engine);
+    let reopened = CrabKv::open(temp.path())?;
+    assert_eq!(total(&reopened)?, 2000);
+    Ok(())
+}
+
+#[test]
+fn update_many_deletes_and_rejects_reentry() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    engine.put("pending".into(), "job".into())?;
+
+    let inner = engine.clone();
+    engine.update_many(&["pending", "done"], |current| {
+        assert_eq!(current["done"], None);
+        let err = inner.get("pending").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Deadlock);
+        assert!(inner.put("other".into(), "x".into()).is_err());
+        let err = inner.force_compaction_check().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Deadlock);
+        vec![
+            ("pending".into(), None),
+            ("done".into(), current["pending"].clone()),
+        ]
+    })?;
+    assert_eq!(engine.get("pending")?, None);
+    assert_eq!(engine.get("done")?, Some("job".into()));
+    assert_eq!(engine.get("other")?, None);
+    Ok(())
+}
+
+#[test]
+fn contains_many_matches_input_order_and_skips_expired() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    let batch: Vec<(String, String, Option<Duration>)> = (0..10_000)
+        .map(|i| {
+            let ttl = (i % 3 == 0).then_some(Duration::from_millis(20));
+            (format!("k{i}"), "value".to_string(), ttl)
+        })
+        .collect();
+    engine.put_batch(batch)?;
+    engine.delete("k1")?;
+    sleep(Duration::from_millis(40));
+
+    let probes: Vec<String> = (0..12_000).map(|i| format!("k{i}")).collect();
+    let found = engine.contains_many(probes.iter().map(String::as_str))?;
+    assert_eq!(found.len(), probes.len());
+    for (i, present) in found.into_iter().enumerate() {
+        let expected = i < 10_000 && i % 3 != 0 && i != 1;
+        assert_eq!(present, expected, "k{i}");
+    }
+    assert!(engine.contains_many(std::iter::empty())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn get_many_matches_get_and_queues_expired_keys() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::builder(temp.path())
+        .cache_capacity(NonZeroUsize::new(8).unwrap())
+        .build()?;
+    let expired = engine.subscribe_expired()?;
+    for i in (0..50_u32).rev() {
+        let ttl = i.is_multiple_of(5).then_some(Duration::from_millis(20));
+        engine.put_with_ttl(format!("k{i}"), format!("value{i}"), ttl)?;
+    }
+    sleep(Duration::from_millis(40));
+
+    let probes: Vec<String> = (0..60).chain([7, 7]).map(|i| format!("k{i}")).collect();
+    let probes: Vec<&str> = probes.iter().map(String::as_str).collect();
+    let values = engine.get_many(&probes)?;
+    assert_eq!(values.len(), probes.len());
+    for (key, value) in probes.iter().zip(&values) {
+        let i: usize = key[1..].parse().unwrap();
+        let expected = (i < 50 && !i.is_multiple_of(5)).then(|| format!("value{i}"));
+        assert_eq!(value, &expected, "{key}");
+        assert_eq!(engine.get(key)?, expected, "{key}");
+    }
+    let mut reported: Vec<String> = expired.try_iter().collect();
+    reported.sort();
+    assert_eq!(reported.len(), 10, "{reported:?}");
+    assert!(engine.get_many(&[])?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn multi_get_into_appends_values_to_the_arena() -> io::Result<()> {
+    for compression in [false, true] {
+        let temp = TempDir::new()?;
+        let engine = CrabKv::builder(temp.path())
+            .cache_capacity(NonZeroUsize::new(4).unwrap())
+            .compression(compression)
+            .build()?;
+        for i in 0..20_usize {
+            engine.put(format!("k{i}"), "v".repeat(i * 1_000))?;
+        }
+        engine.put_with_ttl(
+            "gone".into(),
+            "soon".into(),
+            Some(Duration::from_millis(20)),
+        )?;
+        engine.get("k3")?;
+        sleep(Duration::from_millis(40));
+
+        let keys = ["k19", "missing", "k3", "gone", "k0", "k7", "k19"];
+        let mut arena = Vec::with_capacity(16);
+        arena.extend_from_slice(b"prefix");
+        let ranges = engine.multi_get_into(&keys, &mut arena)?;
+        assert_eq!(ranges.len(), keys.len());
+        assert_eq!(&arena[..6], b"prefix");
+        let values: Vec<Option<Vec<u8>>> = ranges
+            .iter()
+            .map(|range| range.clone().map(|range| arena[range].to_vec()))
+            .collect();
+        for (key, value) in keys.iter().zip(&values) {
+            let expected = engine.get(key)?.map(String::into_bytes);
+            assert_eq!(value, &expected, "{key}");
+        }
+        assert_eq!(values[1], None);
+        assert_eq!(values[3], None);
+        assert_eq!(values[4], Some(Vec::new()));
+
+        // A reused arena stops growing once it fits the request.
+        arena.truncate(6);
+        let capacity = arena.capacity();
+        let ranges = engine.multi_get_into(&keys, &mut arena)?;
+        assert_eq!(arena.capacity(), capacity);
+        for (range, value) in ranges.into_iter().zip(&values) {
+            assert_eq!(range.map(|range| arena[range].to_vec()), *value);
+        }
+        assert!(engine.multi_get_into(&[], &mut arena)?.is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn sample_keys_is_deterministic_and_skips_expired() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    let mut batch: Vec<(String, String, Option<Duration>)> = (0..500)
+        .map(|i| (format!("live{i}"), "value".to_string(), None))
+        .collect();
+    batch.extend((0..500).map(|i| {
+        (
+            format!("gone{i}"),
+            "value".to_string(),
+            Some(Duration::from_millis(20)),
+        )
+    }));
+    engine.put_batch(batch)?;
+    sleep(Duration::from_millis(50));
+
+    let first = engine.sample_keys(50, 7)?;
+    assert_eq!(first.len(), 50);
+    assert!(first.iter().all(|sample| sample.key.starts_with("live")));
+    assert!(first.iter().all(|sample| sample.value_len == 5));
+    assert_eq!(engine.sample_keys(50, 7)?, first);
+    assert_ne!(engine.sample_keys(50, 8)?, first);
+
+    drop(engine);
+    let engine = CrabKv::open(temp.path())?;
+    assert_eq!(engine.sample_keys(50, 7)?, first);
+    assert_eq!(engine.sample_keys(5_000, 7)?.len(), 500);
+    Ok(())
+}
+
+#[test]
+fn try_close_requires_the_last_handle() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::builder(temp.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .async_compaction(true)
+        .build()?;
+    assert_eq!(engine.handle_count(), 1);
+
+    let workers: Vec<_> = (0..3)
+        .map(|i| {
+            let handle = engine.clone();
+            thread::spawn(move || {
+                handle.put(format!("k{i}"), format!("v{i}")).unwrap();
+                handle
+            })
+        })
+        .collect();
+    let extras: Vec<CrabKv> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+    assert_eq!(engine.handle_count(), 4);
+
+    let engine = engine
+        .try_close()
+        .expect_err("other handles are still open");
+    drop(extras);
+    assert_eq!(engine.handle_count(), 1);
+    engine
+        .try_close()
+        .unwrap_or_else(|_| panic!("last handle should close"))?;
+
+    let engine = CrabKv::open(temp.path())?;
+    for i in 0..3 {
+        assert_eq!(engine.get(&format!("k{i}"))?, Some(format!("v{i}")));
+    }
+    Ok(())
+}
+
+#[test]
+fn changed_since_returns_only_later_writes() -> io::Result<()> {
+    let temp = TempDir::new()?;
+    let engine = CrabKv::open(temp.path())?;
+    engine.put("early".into(), "1".into())?;
+    engine.put("rewritten".into(), "old".into())?;
+    sleep(Duration::from_millis(10));
+    let since = SystemTime::now();
+    sleep(Duration::from_millis(10));
+    engine.put("late".into(), "2".into())?;
+    engine.put("rewritten".into(), "new".into())?;
+
+    let expected = vec![
+        ("late".to_string(), "2".to_string()),
+        ("rewritten".to_string(), "new".to_string()),
+    ];
+    assert_eq!(engine.changed_since(since)?, expected);
+
+    engine.compact()?;
+    drop(engine);
+    let engine = CrabKv::open(temp.path())?;
+    assert_eq!(engine.changed_since(since)?, expected);
+    Ok(())
+}
+
 struct TempDir {
     path: PathBuf,
 }
@@ -0,0 +1,121 @@
+use crabkv::CrabKv;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const THRESHOLD: usize = 64;
+
+fn blob_files(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    match fs::read_dir(dir.join("blobs")) {
+        Ok(entries) => {
+            for entry in entries {
+                names.push(entry?.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[test]
+fn values_over_the_threshold_live_in_blob_files() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let open = || {
+        CrabKv::builder(dir.path())
+            .blob_threshold(THRESHOLD)
+            .build()
+    };
+    let large = "x".repeat(4096);
+
+    let engine = open()?;
+    engine.put("small".into(), "tiny".into())?;
+    engine.put("large".into(), large.clone())?;
+    engine.put_batch(vec![("batched".into(), large.clone(), None)])?;
+    assert_eq!(blob_files(dir.path())?.len(), 2);
+    assert!(fs::metadata(dir.path().join("wal.log"))?.len() < large.len() as u64);
+    assert_eq!(engine.get("large")?, Some(large.clone()));
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.get("small")?, Some("tiny".into()));
+    assert_eq!(engine.get("large")?, Some(large.clone()));
+    assert_eq!(engine.get("batched")?, Some(large));
+    assert_eq!(engine.open_report().orphaned_blobs, 0);
+    Ok(())
+}
+
+#[test]
+fn blob_written_before_a_failed_append_is_removed_on_open() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let open = || {
+        CrabKv::builder(dir.path())
+            .blob_threshold(THRESHOLD)
+            .build()
+    };
+    let large = "y".repeat(THRESHOLD * 2);
+
+    let engine = open()?;
+    engine.put("kept".into(), large.clone())?;
+    engine.fail_next_appends(1)?;
+    assert!(
+        engine
+            .put_batch(vec![("lost".into(), large.clone(), None)])
+            .is_err()
+    );
+    assert_eq!(blob_files(dir.path())?.len(), 2);
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.open_report().orphaned_blobs, 1);
+    assert_eq!(blob_files(dir.path())?.len(), 1);
+    assert_eq!(engine.get("lost")?, None);
+    assert_eq!(engine.get("kept")?, Some(large));
+    Ok(())
+}
+
+#[test]
+fn deleted_and_overwritten_blobs_are_removed_by_compaction() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .blob_threshold(THRESHOLD)
+        .build()?;
+    let large = "z".repeat(THRESHOLD + 1);
+
+    engine.put("a".into(), large.clone())?;
+    engine.put("b".into(), large.clone())?;
+    engine.put("b".into(), "small now".into())?;
+    engine.delete("a")?;
+    assert_eq!(blob_files(dir.path())?.len(), 2);
+
+    engine.compact()?;
+    assert!(blob_files(dir.path())?.is_empty());
+    assert_eq!(engine.get("b")?, Some("small now".into()));
+    Ok(())
+}
+
+#[test]
+fn compaction_copies_references_not_blob_bytes() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .blob_threshold(THRESHOLD)
+        .build()?;
+    let large = "m".repeat(1 << 20);
+
+    for i in 0..4 {
+        engine.put(format!("blob{i}"), large.clone())?;
+        engine.put(format!("blob{i}"), large.clone())?;
+    }
+    let before = blob_files(dir.path())?;
+    engine.compact()?;
+
+    // Four live references remain in the log while megabytes stay in blob files.
+    assert!(fs::metadata(dir.path().join("wal.log"))?.len() < 1024);
+    let after = blob_files(dir.path())?;
+    assert_eq!(after.len(), 4);
+    assert!(after.iter().all(|name| before.contains(name)));
+    assert_eq!(engine.get("blob3")?, Some(large));
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use crabkv::{BinaryValue, ChangeKind, CrabKv, Utf8Policy, WalFile};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+/// A msgpack-style payload that is not valid UTF-8.
+const PAYLOAD: &[u8] = &[0x93, 0x01, 0xff, 0x00, 0xc0, 0x80, b'x'];
+
+fn open(dir: &Path, compression: bool) -> io::Result<CrabKv> {
+    CrabKv::builder(dir)
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .compression(compression)
+        .build()
+}
+
+#[test]
+fn byte_values_round_trip_through_reopen_and_compaction() -> io::Result<()> {
+    for compression in [false, true] {
+        let dir = tempfile::tempdir()?;
+        let engine = open(dir.path(), compression)?;
+        let large: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        engine.put_bytes("small".into(), PAYLOAD.to_vec())?;
+        engine.put_bytes("large".into(), large.clone())?;
+        engine.put_bytes("empty".into(), Vec::new())?;
+        assert_eq!(engine.get_bytes("small")?.as_deref(), Some(PAYLOAD));
+        assert_eq!(engine.get_bytes("large")?, Some(large.clone()));
+        assert_eq!(engine.get_bytes("missing")?, None);
+
+        engine.put_bytes("small".into(), PAYLOAD[..3].to_vec())?;
+        engine.compact()?;
+        assert_eq!(engine.get_bytes("small")?.as_deref(), Some(&PAYLOAD[..3]));
+        drop(engine);
+
+        let engine = open(dir.path(), compression)?;
+        assert_eq!(engine.get_bytes("small")?.as_deref(), Some(&PAYLOAD[..3]));
+        assert_eq!(engine.get_bytes("large")?, Some(large));
+        assert_eq!(engine.get("empty")?.as_deref(), Some(""));
+        assert!(WalFile::open(dir.path())?.verify()?.is_clean());
+    }
+    Ok(())
+}
+
+#[test]
+fn string_reads_refuse_binary_values() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .utf8_policy(Utf8Policy::Skip)
+        .build()?;
+    engine.put_bytes("bin".into(), PAYLOAD.to_vec())?;
+    engine.put_bytes("text".into(), b"plain".to_vec())?;
+    engine.put("string".into(), "stored as text".into())?;
+
+    let err = engine.get("bin").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(BinaryValue::of(&err).map(|e| e.key.as_str()), Some("bin"));
+    assert!(engine.get_many(&["text", "bin"]).is_err());
+    // Written on purpose, so never quarantined as damage.
+    assert!(engine.quarantined()?.is_empty());
+
+    assert_eq!(engine.get("text")?.as_deref(), Some("plain"));
+    assert_eq!(
+        engine.get_bytes("string")?.as_deref(),
+        Some(&b"stored as text"[..])
+    );
+    let mut arena = Vec::new();
+    let ranges = engine.multi_get_into(&["bin", "string"], &mut arena)?;
+    assert_eq!(&arena[ranges[0].clone().unwrap()], PAYLOAD);
+    assert_eq!(&arena[ranges[1].clone().unwrap()], b"stored as text");
+    let mut sink = Vec::new();
+    assert_eq!(
+        engine.get_into("bin", &mut sink)?,
+        Some(PAYLOAD.len() as u64)
+    );
+    assert_eq!(sink, PAYLOAD);
+
+    engine.delete("bin")?;
+    assert_eq!(engine.get("bin")?, None);
+    Ok(())
+}
+
+#[test]
+fn binary_writes_replace_cached_strings_and_reach_the_change_feed() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .build()?;
+    engine.put("k".into(), "buffered".into())?;
+    assert_eq!(engine.get("k")?.as_deref(), Some("buffered"));
+    engine.put_bytes("k".into(), PAYLOAD.to_vec())?;
+    assert_eq!(engine.get_bytes("k")?.as_deref(), Some(PAYLOAD));
+    engine.flush()?;
+    assert_eq!(engine.get_bytes("k")?.as_deref(), Some(PAYLOAD));
+
+    let batch = engine.export_changes(0, u64::MAX)?;
+    let kinds: Vec<&ChangeKind> = batch.changes.iter().map(|change| &change.kind).collect();
+    assert_eq!(
+        kinds,
+        [&ChangeKind::PutBytes {
+            value: PAYLOAD.to_vec(),
+            expires_at: None,
+        }]
+    );
+    Ok(())
+}
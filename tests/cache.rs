@@ -0,0 +1,49 @@
+use crabkv::CrabKv;
+use crabkv::internals::cache::{Cache, CacheEntry};
+use std::io;
+use std::num::NonZeroUsize;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[test]
+fn cache_entry_ttl_forces_reload_from_wal() -> io::Result<()> {
+    let cache =
+        Cache::new(NonZeroUsize::new(8).unwrap()).with_entry_ttl(Some(Duration::from_millis(50)));
+    cache.put("hot".into(), CacheEntry::new("v".into(), None));
+    assert!(cache.get("hot").is_some());
+    sleep(Duration::from_millis(80));
+    assert!(cache.get("hot").is_none());
+
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(8).unwrap())
+        .cache_entry_ttl(Duration::from_millis(50))
+        .build()?;
+    engine.put("hot".into(), "value".into())?;
+    assert_eq!(engine.get("hot")?, Some("value".into()));
+    sleep(Duration::from_millis(80));
+    assert_eq!(engine.get("hot")?, Some("value".into()));
+    Ok(())
+}
+
+#[test]
+fn panicking_eviction_listener_disables_cache() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(1).unwrap())
+        .cache_eviction_listener(|key| panic!("listener failed for {key}"))
+        .build()?;
+
+    engine.put("first".into(), "1".into())?;
+    engine.put("second".into(), "2".into())?;
+    engine.put("third".into(), "3".into())?;
+    assert_eq!(engine.get("first")?, Some("1".into()));
+    assert_eq!(engine.get("second")?, Some("2".into()));
+    assert_eq!(engine.get("third")?, Some("3".into()));
+
+    drop(engine);
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.open_report().live_keys, 3);
+    assert_eq!(engine.open_report().stale_bytes, 0);
+    Ok(())
+}
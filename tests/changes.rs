@@ -0,0 +1,213 @@
+use crabkv::{Change, ChangeKind, CrabKv, HistoryTruncated};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+fn put(change: &Change) -> Option<(&str, &str)> {
+    match &change.kind {
+        ChangeKind::Put { value, .. } => Some((&change.key, value)),
+        ChangeKind::PutBytes { .. } | ChangeKind::Delete => None,
+    }
+}
+
+#[test]
+fn changes_are_consumed_incrementally_until_compaction() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let first = engine.export_changes(0, u64::MAX)?;
+    assert!(first.changes.is_empty());
+    assert!(first.at_end);
+    assert_eq!(first.next_offset, 0);
+
+    engine.put("a".into(), "1".into())?;
+    engine.put_with_ttl("b".into(), "2".into(), Some(Duration::from_secs(600)))?;
+    let batch = engine.export_changes(first.next_offset, u64::MAX)?;
+    assert_eq!(batch.generation, first.generation);
+    let puts: Vec<_> = batch.changes.iter().filter_map(put).collect();
+    assert_eq!(puts, [("a", "1"), ("b", "2")]);
+    assert!(matches!(
+        batch.changes[1].kind,
+        ChangeKind::Put {
+            expires_at: Some(_),
+            ..
+        }
+    ));
+    assert!(batch.changes[0].offset < batch.changes[1].offset);
+
+    // A consumer reading one record at a time sees the same writes, and never
+    // half of a batch.
+    engine.delete("a")?;
+    engine.put_batch(vec![
+        ("c".into(), "3".into(), None),
+        ("d".into(), "4".into(), None),
+    ])?;
+    let mut offset = batch.next_offset;
+    let mut seen = Vec::new();
+    loop {
+        let step = engine.export_changes(offset, 1)?;
+        assert_eq!(step.generation, first.generation);
+        seen.push(step.changes.len());
+        offset = step.next_offset;
+        if step.at_end {
+            break;
+        }
+    }
+    assert_eq!(seen, [1, 2]);
+    let rest = engine.export_changes(batch.next_offset, u64::MAX)?;
+    assert_eq!(rest.changes[0].key, "a");
+    assert_eq!(rest.changes[0].kind, ChangeKind::Delete);
+    assert_eq!(rest.next_offset, offset);
+
+    // Nothing new means an empty batch at the same offset.
+    let idle = engine.export_changes(offset, u64::MAX)?;
+    assert!(idle.changes.is_empty() && idle.at_end);
+    assert_eq!(idle.next_offset, offset);
+
+    engine.compact()?;
+    let after = engine.export_changes(0, u64::MAX)?;
+    assert_ne!(after.generation, first.generation);
+    assert_eq!(after.generation, engine.log_generation()?);
+    let mut live: Vec<_> = after.changes.iter().filter_map(put).collect();
+    live.sort();
+    assert_eq!(live, [("b", "2"), ("c", "3"), ("d", "4")]);
+    Ok(())
+}
+
+#[test]
+fn the_generation_survives_a_restart() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let generation = {
+        let engine = CrabKv::open(dir.path())?;
+        engine.put("k".into(), "v".into())?;
+        engine.compact()?;
+        engine.compact()?;
+        engine.log_generation()?
+    };
+    assert_eq!(generation, 2);
+
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.log_generation()?, generation);
+    engine.compact()?;
+    assert_eq!(engine.export_changes(0, 0)?.generation, generation + 1);
+    Ok(())
+}
+
+#[test]
+fn offsets_past_the_end_are_refused() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+    let end = engine.export_changes(0, u64::MAX)?.next_offset;
+    let err = engine.export_changes(end + 1, u64::MAX).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn watch_replays_then_follows_without_gaps_or_repeats() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_millis(5))
+        .build()?;
+    engine.put("watched:early".into(), "missed".into())?;
+    let marker = engine.change_marker();
+    for i in 0..300 {
+        engine.put(format!("watched:{i:04}"), format!("v{i}"))?;
+        engine.put(format!("other:{i:04}"), "ignored".into())?;
+    }
+    engine.delete("watched:0000")?;
+
+    let mut watch = engine.watch_prefix("watched:", Some(marker))?;
+    // Keeps writing while the watch is still replaying, then past its end.
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || -> io::Result<()> {
+            for i in 300..2_000 {
+                engine.put(format!("watched:{i:04}"), format!("v{i}"))?;
+                engine.put(format!("other:{i:04}"), "ignored".into())?;
+                if i % 500 == 0 {
+                    engine.put_batch(vec![
+                        (format!("watched:batch{i}"), "a".into(), None),
+                        (format!("other:batch{i}"), "b".into(), None),
+                    ])?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let mut expected: Vec<(String, Option<String>)> = (0..300)
+        .map(|i| (format!("watched:{i:04}"), Some(format!("v{i}"))))
+        .collect();
+    expected.push(("watched:0000".into(), None));
+    for i in 300..2_000 {
+        expected.push((format!("watched:{i:04}"), Some(format!("v{i}"))));
+        if i % 500 == 0 {
+            expected.push((format!("watched:batch{i}"), Some("a".into())));
+        }
+    }
+    let mut seen = Vec::new();
+    let mut last_offset = None;
+    while seen.len() < expected.len() {
+        let change = watch
+            .next_timeout(Duration::from_secs(10))?
+            .expect("a change within the timeout");
+        assert!(last_offset < Some(change.offset));
+        last_offset = Some(change.offset);
+        let value = put(&change).map(|(_, value)| value.to_string());
+        seen.push((change.key, value));
+    }
+    writer.join().unwrap()?;
+    assert_eq!(seen, expected);
+    assert!(watch.next_timeout(Duration::from_millis(50))?.is_none());
+
+    // Nothing is delivered twice once the watch has caught up, either.
+    engine.put("watched:last".into(), "1".into())?;
+    let change = watch.next().expect("live change")?;
+    assert_eq!(put(&change), Some(("watched:last", "1")));
+    assert!(watch.next_timeout(Duration::from_millis(50))?.is_none());
+    Ok(())
+}
+
+#[test]
+fn watch_from_before_a_compaction_reports_truncated_history() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "1".into())?;
+    let marker = engine.change_marker();
+    engine.put("k".into(), "2".into())?;
+    engine.compact()?;
+
+    let err = engine.watch_prefix("k", Some(marker)).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    let truncated = HistoryTruncated::of(&err).expect("typed error");
+    assert_eq!(truncated.current, engine.log_generation()?);
+
+    // A stream open across a compaction fails the same way, then ends.
+    let mut watch = engine.watch_prefix("k", None)?;
+    engine.put("k".into(), "3".into())?;
+    assert_eq!(put(&watch.next().unwrap()?), Some(("k", "3")));
+    engine.compact()?;
+    engine.put("k".into(), "4".into())?;
+    let err = watch.next().unwrap().unwrap_err();
+    assert!(HistoryTruncated::of(&err).is_some());
+    assert!(watch.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn a_compaction_wakes_a_blocked_watch() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "1".into())?;
+    engine.put("k".into(), "2".into())?;
+    let mut watch = engine.watch_prefix("k", None)?;
+    let waiting = thread::spawn(move || watch.next_timeout(Duration::from_secs(30)));
+
+    // Nothing is written after the compaction, so only it can wake the watch.
+    thread::sleep(Duration::from_millis(50));
+    engine.compact()?;
+    let err = waiting.join().unwrap().unwrap_err();
+    assert!(HistoryTruncated::of(&err).is_some(), "{err}");
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use crabkv::internals::checkpoint::CHECKPOINT_FILE;
+use crabkv::{CrabKv, EventKind, ManualClock};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+fn log_len(dir: &Path) -> io::Result<u64> {
+    Ok(fs::metadata(dir.join("wal.log"))?.len())
+}
+
+/// Every live key with its value, in key order.
+fn contents(engine: &CrabKv) -> io::Result<Vec<(String, String)>> {
+    let mut keys = engine.keys_matching("*")?;
+    keys.sort();
+    keys.into_iter()
+        .map(|key| {
+            let value = engine.get(&key)?.expect("listed key");
+            Ok((key, value))
+        })
+        .collect()
+}
+
+#[test]
+fn reopening_after_a_crash_replays_only_the_tail() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let expected = {
+        let engine = CrabKv::builder(dir.path()).value_dedup(true).build()?;
+        for i in 0..500 {
+            engine.put(format!("key{i:03}"), format!("first value of key {i:03}"))?;
+        }
+        for i in (0..500).step_by(7) {
+            engine.delete(&format!("key{i:03}"))?;
+        }
+        engine.put_with_ttl("short".into(), "v".into(), Some(Duration::from_secs(3600)))?;
+        engine.checkpoint()?;
+        let covered = log_len(dir.path())?;
+
+        for i in (0..500).step_by(5) {
+            engine.put(format!("key{i:03}"), format!("second value of key {i:03}"))?;
+        }
+        engine.delete("key001")?;
+        engine.put("new".into(), "written after the checkpoint".into())?;
+        let expected = contents(&engine)?;
+        // Leaked without closing, as a crash would leave it.
+        std::mem::forget(engine);
+        (covered, expected)
+    };
+    let (covered, expected) = expected;
+    let tail = log_len(dir.path())? - covered;
+
+    let engine = CrabKv::open(dir.path())?;
+    let report = engine.open_report();
+    assert_eq!(report.checkpoint_offset, Some(covered));
+    assert_eq!(report.replayed_bytes, tail);
+    assert_eq!(report.live_keys, expected.len());
+    assert_eq!(contents(&engine)?, expected);
+    assert!(engine.stats()?.checkpoint_age.is_some());
+
+    // A full replay of the same log finds the same keys and stale bytes.
+    drop(engine);
+    let resumed = report;
+    fs::remove_file(dir.path().join(CHECKPOINT_FILE))?;
+    let engine = CrabKv::open(dir.path())?;
+    let report = engine.open_report();
+    assert_eq!(report.checkpoint_offset, None);
+    assert_eq!(report.replayed_bytes, log_len(dir.path())?);
+    assert_eq!(report.stale_bytes, resumed.stale_bytes);
+    assert_eq!(contents(&engine)?, expected);
+    Ok(())
+}
+
+#[test]
+fn checkpoint_keys_are_front_coded() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let prefix = "tenant/0000123/user/";
+    let engine = CrabKv::open(dir.path())?;
+    // Written out of order; the checkpoint sorts them.
+    for i in (0..200).rev() {
+        engine.put(format!("{prefix}{i:04}"), "v".into())?;
+    }
+    engine.checkpoint()?;
+    drop(engine);
+
+    let bytes = fs::read(dir.path().join(CHECKPOINT_FILE))?;
+    assert!(bytes.starts_with(b"CKVCKPT2"));
+    // Pointer, expiry, write time, flags and reference length per entry,
+    // against which whole keys would add 24 bytes each.
+    let fields = 200 * (16 + 8 + 8 + 1 + 4);
+    let whole_keys = 200 * (prefix.len() + 4);
+    assert!(
+        bytes.len() < fields + whole_keys / 3,
+        "{} bytes",
+        bytes.len()
+    );
+    let engine = CrabKv::open(dir.path())?;
+    assert!(engine.open_report().checkpoint_offset.is_some());
+    assert_eq!(engine.open_report().live_keys, 200);
+    assert_eq!(engine.get(&format!("{prefix}0007"))?.as_deref(), Some("v"));
+    drop(engine);
+
+    // A checkpoint in the previous format is ignored and the log replayed.
+    let mut old = bytes;
+    old[..8].copy_from_slice(b"CKVCKPT1");
+    fs::write(dir.path().join(CHECKPOINT_FILE), old)?;
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.open_report().checkpoint_offset, None);
+    assert_eq!(engine.open_report().live_keys, 200);
+    Ok(())
+}
+
+#[test]
+fn writes_checkpoint_in_the_background_every_n_mutations() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .checkpoint_mutations(50)
+        .checkpoint_min_interval(Duration::ZERO)
+        .build()?;
+    assert_eq!(engine.checkpoint_status()?.last_taken, None);
+    assert_eq!(engine.stats()?.checkpoint_age, None);
+    for i in 0..120 {
+        engine.put(format!("key{i:03}"), "v".repeat(32))?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while engine.checkpoint_status()?.last_taken.is_none()
+        || engine.checkpoint_status()?.in_progress
+    {
+        assert!(Instant::now() < deadline, "no checkpoint was written");
+        thread::sleep(Duration::from_millis(5));
+    }
+    let status = engine.checkpoint_status()?;
+    assert!(status.uncovered_bytes < log_len(dir.path())?, "{status:?}");
+    assert!(engine.stats()?.checkpoint_age.is_some());
+    drop(engine);
+
+    let engine = CrabKv::open(dir.path())?;
+    let report = engine.open_report();
+    assert!(report.checkpoint_offset.is_some());
+    assert!(report.replayed_bytes < log_len(dir.path())?);
+    assert_eq!(report.live_keys, 120);
+    Ok(())
+}
+
+#[test]
+fn compaction_discards_the_checkpoint() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for i in 0..50 {
+        engine.put(format!("key{i:02}"), "old".into())?;
+        engine.put(format!("key{i:02}"), "new".into())?;
+    }
+    engine.checkpoint()?;
+    assert!(dir.path().join(CHECKPOINT_FILE).exists());
+    engine.compact()?;
+    assert!(!dir.path().join(CHECKPOINT_FILE).exists());
+    assert_eq!(engine.checkpoint_status()?.uncovered_bytes, 0);
+    drop(engine);
+
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.open_report().checkpoint_offset, None);
+    assert_eq!(engine.get("key07")?.as_deref(), Some("new"));
+    Ok(())
+}
+
+#[test]
+fn a_checkpoint_that_no_longer_matches_the_log_is_ignored() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    {
+        let engine = CrabKv::open(dir.path())?;
+        for i in 0..20 {
+            engine.put(format!("key{i:02}"), "before".into())?;
+        }
+        engine.checkpoint()?;
+        engine.put("key00".into(), "after".into())?;
+    }
+
+    // A damaged checkpoint fails its hash.
+    let checkpoint = dir.path().join(CHECKPOINT_FILE);
+    let saved = fs::read(&checkpoint)?;
+    let mut file = OpenOptions::new().write(true).open(&checkpoint)?;
+    file.seek(SeekFrom::Start(40))?;
+    file.write_all(&[0xff; 4])?;
+    drop(file);
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.open_report().checkpoint_offset, None);
+    assert_eq!(engine.open_report().replayed_bytes, log_len(dir.path())?);
+    assert_eq!(engine.get("key00")?.as_deref(), Some("after"));
+    assert!(!checkpoint.exists());
+    let events = engine.recent_events();
+    assert!(
+        matches!(&events[0].kind, EventKind::CheckpointIgnored { error } if error.contains("hash")),
+        "{events:?}"
+    );
+    drop(engine);
+
+    // So does one for a log cut short behind its back.
+    fs::write(&checkpoint, &saved)?;
+    let wal = OpenOptions::new()
+        .write(true)
+        .open(dir.path().join("wal.log"))?;
+    wal.set_len(10)?;
+    drop(wal);
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.open_report().checkpoint_offset, None);
+    assert_eq!(engine.open_report().live_keys, 0);
+    Ok(())
+}
+
+#[test]
+fn health_degrades_once_the_checkpoint_is_too_old() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .checkpoint_max_age(Duration::from_secs(60))
+        .build()?;
+    clock.advance(Duration::from_secs(120));
+    // Nothing to replay, so no checkpoint is needed.
+    assert_eq!(engine.checkpoint_status()?.degraded(), None);
+
+    engine.put("k".into(), "v".into())?;
+    assert_eq!(
+        engine.checkpoint_status()?.degraded(),
+        Some("index checkpoint too old")
+    );
+    engine.checkpoint()?;
+    let status = engine.checkpoint_status()?;
+    assert_eq!(status.degraded(), None);
+    assert_eq!(status.uncovered_bytes, 0);
+
+    engine.put("k".into(), "w".into())?;
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(engine.checkpoint_status()?.degraded(), None);
+    assert_eq!(
+        engine.stats()?.checkpoint_age,
+        Some(Duration::from_secs(30))
+    );
+    clock.advance(Duration::from_secs(31));
+    assert!(engine.checkpoint_status()?.degraded().is_some());
+    Ok(())
+}
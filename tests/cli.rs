@@ -0,0 +1,207 @@
+use crabkv::lock_file::{self, LOCK_FILE, ServerLock};
+use crabkv::{CrabKv, ServerConfig, server};
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn crabkv(data_dir: &Path, args: &[&str]) -> io::Result<Output> {
+    Command::new(env!("CARGO_BIN_EXE_CrabKv"))
+        .args(args)
+        .env("CRABKV_DATA_DIR", data_dir)
+        .env_remove("CRABKV_CACHE_CAPACITY")
+        .env_remove("CRABKV_DEFAULT_TTL_SECS")
+        .output()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn compact_and_stats_are_forwarded_to_a_running_server() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+    let handle = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let addr = handle.local_addrs()[0];
+    let lock = ServerLock::acquire(dir.path(), addr)?;
+    let holder = lock_file::holder(dir.path())?.unwrap();
+    assert_eq!(holder.addr, addr);
+    assert_eq!(holder.pid, std::process::id());
+    assert_eq!(holder.store_id.as_deref(), Some(engine.store_id()));
+
+    let compacted = stdout(&crabkv(dir.path(), &["compact"])?);
+    assert!(
+        compacted.starts_with(&format!("forwarded to running server at {addr}\n")),
+        "{compacted}"
+    );
+    assert_eq!(engine.compaction_count()?, 1);
+
+    let stats = stdout(&crabkv(dir.path(), &["stats"])?);
+    assert!(stats.contains("\nkeys 1\n"), "{stats}");
+    assert!(stats.contains("\ncompactions 1\n"), "{stats}");
+
+    drop(lock);
+    assert!(!dir.path().join(LOCK_FILE).exists());
+    handle.shutdown()
+}
+
+#[test]
+fn reads_and_writes_go_through_a_running_server() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let handle = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let addr = handle.local_addrs()[0];
+    let lock = ServerLock::acquire(dir.path(), addr)?;
+    let forwarded = format!("forwarded to running server at {addr}\n");
+
+    let stored = stdout(&crabkv(dir.path(), &["put", "k", "v", "--ttl", "1h"])?);
+    assert_eq!(stored, format!("{forwarded}stored\n"));
+    assert_eq!(engine.get("k")?.as_deref(), Some("v"));
+    let read = stdout(&crabkv(dir.path(), &["get", "k"])?);
+    assert_eq!(read, format!("{forwarded}v\n"));
+    let flushed = stdout(&crabkv(dir.path(), &["flush"])?);
+    assert_eq!(flushed, format!("{forwarded}flushed\n"));
+    assert!(engine.sample_keys(1, 0)?[0].expires_at.is_some());
+    let deleted = stdout(&crabkv(dir.path(), &["delete", "k"])?);
+    assert_eq!(deleted, format!("{forwarded}deleted\n"));
+    let missing = stdout(&crabkv(dir.path(), &["get", "k"])?);
+    assert_eq!(missing, format!("{forwarded}key not found\n"));
+
+    let spaced = crabkv(dir.path(), &["put", "k", "two words"])?;
+    assert!(!spaced.status.success());
+    for args in [&["sample"][..], &["recent", "--since", "1h"]] {
+        let refused = crabkv(dir.path(), args)?;
+        assert!(!refused.status.success(), "{args:?}");
+        let stderr = String::from_utf8_lossy(&refused.stderr);
+        assert!(
+            stderr.contains(&format!("process {}", std::process::id())),
+            "{stderr}"
+        );
+        assert!(stderr.contains(&addr.to_string()), "{stderr}");
+    }
+    assert_eq!(engine.get("k")?, None);
+    drop(lock);
+    handle.shutdown()
+}
+
+#[test]
+fn commands_open_the_store_when_no_server_answers() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    CrabKv::open(dir.path())?.put("k".into(), "v".into())?;
+    let compacted = stdout(&crabkv(dir.path(), &["compact"])?);
+    assert_eq!(compacted, "compacted\n");
+
+    // A lock file left by a crashed server names an address nothing listens on.
+    let closed: SocketAddr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    fs::write(
+        dir.path().join(LOCK_FILE),
+        format!("pid=1\naddr={closed}\n"),
+    )?;
+    assert_eq!(lock_file::live_holder(dir.path())?, None);
+    let stats = stdout(&crabkv(dir.path(), &["stats"])?);
+    assert!(stats.starts_with("keys 1\n"), "{stats}");
+    Ok(())
+}
+
+#[test]
+fn scrub_reports_damage_through_a_running_server_or_the_files() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("good".into(), "intact".into())?;
+    engine.put("bad".into(), "damaged".into())?;
+    let clean = stdout(&crabkv(dir.path(), &["scrub"])?);
+    assert!(clean.starts_with("SCRUB records=2 "), "{clean}");
+
+    let wal = dir.path().join("wal.log");
+    let mut bytes = fs::read(&wal)?;
+    let at = bytes.windows(7).position(|w| w == b"damaged").unwrap();
+    bytes[at..at + 7].fill(0xff);
+    fs::write(&wal, bytes)?;
+
+    let handle = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let lock = ServerLock::acquire(dir.path(), handle.local_addrs()[0])?;
+    let forwarded = crabkv(dir.path(), &["scrub"])?;
+    assert!(!forwarded.status.success());
+    let out = String::from_utf8_lossy(&forwarded.stdout);
+    assert!(out.contains("SCRUB records=2 "), "{out}");
+    assert!(out.contains(" damaged=1 quarantined=1"), "{out}");
+    assert!(String::from_utf8_lossy(&forwarded.stderr).contains("1 damaged log records"));
+    assert_eq!(engine.quarantined()?[0].key, "bad");
+    drop(lock);
+    handle.shutdown()
+}
+
+#[test]
+fn changes_resume_from_an_offset_and_report_a_new_generation() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "one\n".into())?;
+    drop(engine);
+
+    let first = stdout(&crabkv(dir.path(), &["changes", "--from", "0", "--json"])?);
+    assert!(
+        first.starts_with("{\"generation\":0,\"reset\":false,"),
+        "{first}"
+    );
+    assert!(first.contains("\"op\":\"put\",\"key\":\"k\",\"value\":\"one\\n\""));
+    let next = first
+        .split("\"next_offset\":")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .unwrap()
+        .to_string();
+
+    let engine = CrabKv::open(dir.path())?;
+    engine.delete("k")?;
+    drop(engine);
+    let second = stdout(&crabkv(
+        dir.path(),
+        &["changes", "--from", &next, "--generation", "0", "--json"],
+    )?);
+    assert!(second.contains("\"changes\":[{\"offset\":"), "{second}");
+    assert!(
+        second.contains("\"op\":\"delete\",\"key\":\"k\"}]"),
+        "{second}"
+    );
+
+    let engine = CrabKv::open(dir.path())?;
+    engine.compact()?;
+    drop(engine);
+    let reset = stdout(&crabkv(
+        dir.path(),
+        &["changes", "--from", &next, "--generation", "0", "--json"],
+    )?);
+    assert!(
+        reset.starts_with("{\"generation\":1,\"reset\":true,\"next_offset\":0,"),
+        "{reset}"
+    );
+    Ok(())
+}
+
+#[test]
+fn put_needs_force_to_create_a_store_beside_other_files() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("notes.txt"), "not a store")?;
+
+    let refused = crabkv(dir.path(), &["put", "k", "v"])?;
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(stderr.contains("--force"), "{stderr}");
+    assert!(!dir.path().join("wal.log").exists());
+
+    assert_eq!(
+        stdout(&crabkv(dir.path(), &["put", "k", "v", "--force"])?),
+        "stored\n"
+    );
+    // Once the store exists it opens without the flag.
+    assert_eq!(stdout(&crabkv(dir.path(), &["get", "k"])?), "v\n");
+    Ok(())
+}
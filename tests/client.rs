@@ -0,0 +1,193 @@
+#![cfg(feature = "client")]
+
+use crabkv::client::{Client, Pool, ServerError};
+use crabkv::{CrabKv, EpochMismatch, QuotaExceeded, ServerConfig, server};
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+#[test]
+fn mirrors_the_engine_api() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let mut client = Client::connect(server.local_addr())?;
+
+    client.put("a", "1")?;
+    client.put("b", "2")?;
+    client.put("b", "two")?;
+    client.put_with_ttl("c", "3", Some(Duration::from_secs(3600)))?;
+    assert_eq!(client.get("a")?.as_deref(), Some("1"));
+    assert_eq!(client.get("missing")?, None);
+    assert!(client.contains_key("a")?);
+    assert!(!client.contains_key("missing")?);
+    // Values come back framed, so a newline written in-process survives.
+    engine.put("lines".into(), "first\nsecond\n".into())?;
+    assert_eq!(
+        client.mget(&["a", "missing", "lines", "b"])?,
+        [
+            Some("1".to_string()),
+            None,
+            Some("first\nsecond\n".to_string()),
+            Some("two".to_string())
+        ]
+    );
+
+    client.delete("a")?;
+    client.delete("a")?;
+    assert_eq!(client.get("a")?, None);
+    client.compact()?;
+    let stats = client.stats()?;
+    assert_eq!(stats["keys"], "3");
+    assert_eq!(stats["keys_with_ttl"], "1");
+    assert_eq!(stats["stale_bytes"], "0");
+    assert!(client.command("VERSION")?.starts_with("VERSION version="));
+    assert!(!client.is_broken());
+    drop(client);
+    server.shutdown()
+}
+
+#[test]
+fn err_replies_map_to_typed_errors() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .prefix_quota("tenant:", 64)
+        .build()?;
+    let mut config = ServerConfig::default();
+    config.pressure.critical.stale_bytes = 0;
+    engine.put("stale".into(), "old".into())?;
+    engine.put("stale".into(), "new".into())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), config)?;
+    let mut client = Client::connect(server.local_addr())?;
+
+    // Writes are refused while stale bytes are above the critical limit.
+    let err = client.put("tenant:a", "v").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    let busy = ServerError::of(&err).expect("server error");
+    assert_eq!(busy.retry_after, Some(Duration::from_millis(100)));
+    assert!(busy.request_id.is_some());
+    engine.compact()?;
+
+    let err = client.put("tenant:a", &"x".repeat(100)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    let quota = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<QuotaExceeded>())
+        .expect("quota payload");
+    assert_eq!(quota.prefix, "tenant:");
+    assert_eq!(quota.limit, 64);
+
+    let err = client.command("NOSUCH thing").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(ServerError::of(&err).unwrap().message, "bad command");
+
+    // Rejected before reaching the server, which would split it.
+    let err = client.put("two words", "v").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(ServerError::of(&err).is_none());
+
+    // None of that left the connection out of step.
+    client.put("tenant:a", "ok")?;
+    assert_eq!(client.get("tenant:a")?.as_deref(), Some("ok"));
+    drop(client);
+    server.shutdown()
+}
+
+#[test]
+fn pool_caps_its_connections() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine, ServerConfig::default())?;
+    let pool = Pool::new(server.local_addr(), 2)?.checkout_timeout(Duration::from_millis(100));
+    assert_eq!(pool.open_connections(), 0);
+
+    let mut first = pool.get()?;
+    let second = pool.get()?;
+    first.put("k", "v")?;
+    let err = pool.get().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    drop(first);
+    let mut again = pool.get()?;
+    assert_eq!(again.get("k")?.as_deref(), Some("v"));
+    assert_eq!(pool.open_connections(), 2);
+    drop((again, second));
+    drop(pool);
+    server.shutdown()
+}
+
+#[test]
+fn reconnects_after_the_server_restarts() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let addr = server.local_addr();
+    let pool = Pool::new(addr, 1)?;
+    let mut client = Client::connect(addr)?;
+    pool.get()?.put("k", "before")?;
+
+    server.shutdown()?;
+    let server = server::spawn(&addr.to_string(), engine, ServerConfig::default())?;
+
+    // A lone client notices the restart on its next call and stays broken.
+    assert!(client.get("k").is_err());
+    assert!(client.is_broken());
+    let err = client.get("k").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+    // The pool checks its idle connection and opens a fresh one.
+    let mut pooled = pool.get()?;
+    assert_eq!(pooled.get("k")?.as_deref(), Some("before"));
+    pooled.put("k", "after")?;
+    drop(pooled);
+    assert_eq!(pool.open_connections(), 1);
+    assert_eq!(Client::connect(addr)?.get("k")?.as_deref(), Some("after"));
+    drop(pool);
+    server.shutdown()
+}
+
+#[test]
+fn pinned_clients_are_fenced_off_by_a_restore() -> io::Result<()> {
+    let backup = tempfile::tempdir()?;
+    {
+        let engine = CrabKv::open(backup.path())?;
+        engine.put("k".into(), "restored".into())?;
+        engine.close()?;
+    }
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let pool = Pool::new(server.local_addr(), 1)?;
+    let mut client = Client::connect(server.local_addr())?;
+    assert_eq!(client.epoch(), Some(0));
+    client.pin_epoch(0)?;
+    client.put("k", "live")?;
+    let mut pooled = pool.get()?;
+    pooled.pin_epoch(0)?;
+
+    // Restored underneath both connections.
+    assert_eq!(engine.restore_from(backup.path())?, 1);
+    let err = client.put("k", "stale").unwrap_err();
+    let mismatch = EpochMismatch::of(&err).expect("typed");
+    assert_eq!((mismatch.expected, mismatch.current), (0, 1));
+    assert!(client.is_fenced());
+    assert!(!client.is_broken());
+    assert!(EpochMismatch::of(&client.get("k").unwrap_err()).is_some());
+    assert_eq!(client.current_epoch()?, 1);
+    assert!(EpochMismatch::of(&pooled.delete("k").unwrap_err()).is_some());
+    drop(pooled);
+    assert_eq!(pool.open_connections(), 0);
+    assert_eq!(engine.get("k")?.as_deref(), Some("restored"));
+
+    let mut fresh = Client::connect(server.local_addr())?;
+    assert_eq!(fresh.epoch(), Some(1));
+    fresh.pin_epoch(1)?;
+    assert_eq!(fresh.get("k")?.as_deref(), Some("restored"));
+    fresh.put("k", "fresh")?;
+    let mut pooled = pool.get()?;
+    let epoch = pooled.epoch().expect("reported");
+    pooled.pin_epoch(epoch)?;
+    assert_eq!(pooled.get("k")?.as_deref(), Some("fresh"));
+    drop(pooled);
+    drop((client, fresh, pool));
+    server.shutdown()
+}
@@ -0,0 +1,65 @@
+use crabkv::CrabKv;
+use crabkv::ManualClock;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn start() -> ManualClock {
+    ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+}
+
+#[test]
+fn expired_keys_stay_expired_when_the_clock_steps_back() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+
+    engine.put_with_ttl("short".into(), "v".into(), Some(Duration::from_secs(10)))?;
+    engine.put("kept".into(), "v".into())?;
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(engine.keys_matching("*")?, vec!["kept"]);
+
+    clock.rewind(Duration::from_secs(5));
+    assert_eq!(engine.keys_matching("*")?, vec!["kept"]);
+    assert_eq!(engine.get("short")?, None);
+    assert_eq!(engine.clock_steps_back(), 1);
+
+    // Writes made while the clock is behind are not stamped in the past.
+    let before_step: SystemTime = clock.now() + Duration::from_secs(5);
+    engine.put("during".into(), "v".into())?;
+    let changed: Vec<String> = engine
+        .changed_since(before_step)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(changed, vec!["during"]);
+    Ok(())
+}
+
+#[test]
+fn small_backward_jitter_is_not_counted() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+
+    engine.put("a".into(), "v".into())?;
+    clock.rewind(Duration::from_millis(200));
+    engine.put("b".into(), "v".into())?;
+    assert_eq!(engine.clock_steps_back(), 0);
+    Ok(())
+}
+
+#[test]
+fn expiry_before_the_epoch_is_kept_through_a_reopen() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH - Duration::from_secs(100));
+    let open = || CrabKv::builder(dir.path()).clock(clock.clock()).build();
+
+    let engine = open()?;
+    engine.put_with_ttl("old".into(), "v".into(), Some(Duration::from_secs(10)))?;
+    clock.advance(Duration::from_secs(101));
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.get("old")?, None);
+    Ok(())
+}
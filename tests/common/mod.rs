@@ -0,0 +1,15 @@
+//! Helpers shared by the integration tests.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Polls `done` until it holds, failing the test after ten seconds.
+pub fn wait_for(what: &str, mut done: impl FnMut() -> io::Result<bool>) -> io::Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done()? {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(5));
+    }
+    Ok(())
+}
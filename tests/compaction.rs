@@ -0,0 +1,512 @@
+use crabkv::CompactionOutcome;
+use crabkv::ManualClock;
+use crabkv::{CompactionPolicy, CrabKv};
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+#[test]
+fn writes_after_compaction_persist() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("before".into(), "1".into())?;
+    engine.compact()?;
+    engine.put("after".into(), "2".into())?;
+    assert_eq!(engine.get("after")?, Some("2".into()));
+
+    drop(engine);
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.get("before")?, Some("1".into()));
+    assert_eq!(engine.get("after")?, Some("2".into()));
+    Ok(())
+}
+
+#[test]
+fn min_compaction_interval_limits_rewrites() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .compaction_policy(CompactionPolicy::every_n_mutations(10))
+        .min_compaction_interval(Duration::from_secs(3600))
+        .build()?;
+    for i in 0..100 {
+        engine.put("hot".into(), i.to_string())?;
+    }
+    assert_eq!(engine.compaction_count()?, 1);
+    assert_eq!(engine.get("hot")?.as_deref(), Some("99"));
+    assert!(!engine.force_compaction_check()?);
+
+    let unbounded_dir = tempfile::tempdir()?;
+    let unbounded = CrabKv::builder(unbounded_dir.path())
+        .compaction_policy(CompactionPolicy::every_n_mutations(10))
+        .build()?;
+    for i in 0..100 {
+        unbounded.put("hot".into(), i.to_string())?;
+    }
+    assert_eq!(unbounded.compaction_count()?, 10);
+    Ok(())
+}
+
+#[test]
+fn every_n_mutations_compacts_on_schedule() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .compaction_policy(CompactionPolicy::every_n_mutations(5))
+        .build()?;
+    for i in 1..=20u64 {
+        if i % 4 == 0 {
+            engine.delete("key")?;
+        } else {
+            engine.put("key".into(), i.to_string())?;
+        }
+        assert_eq!(engine.compaction_count()?, i / 5, "after mutation {i}");
+    }
+    // A batch counts each of its writes.
+    let batch = (0..7)
+        .map(|i| (format!("b{i}"), "v".into(), None))
+        .collect();
+    engine.put_batch(batch)?;
+    assert_eq!(engine.compaction_count()?, 5);
+    Ok(())
+}
+
+#[test]
+fn every_n_mutations_triggers_the_async_worker_on_schedule() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .compaction_policy(CompactionPolicy::every_n_mutations(5))
+        .async_compaction(true)
+        .build()?;
+    let wait_for = |count: u64| -> io::Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while engine.compaction_count()? < count {
+            assert!(Instant::now() < deadline, "compaction {count} never ran");
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    };
+    for i in 1..=20u64 {
+        engine.put(format!("key{}", i % 3), i.to_string())?;
+        if i % 5 == 0 {
+            wait_for(i / 5)?;
+        }
+        assert_eq!(engine.compaction_count()?, i / 5, "after mutation {i}");
+    }
+    assert_eq!(engine.get("key2")?.as_deref(), Some("20"));
+    Ok(())
+}
+
+#[test]
+fn force_compaction_check_applies_the_policy_without_a_write() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    {
+        let engine = CrabKv::open(dir.path())?;
+        for i in 0..3 {
+            engine.put("key".into(), i.to_string())?;
+        }
+    }
+    // The default policy ignores a log this small.
+    let engine = CrabKv::open(dir.path())?;
+    assert!(!engine.force_compaction_check()?);
+    drop(engine);
+
+    // Buffered puts wait for a flush before writes check the policy.
+    let engine = CrabKv::builder(dir.path())
+        .compaction_policy(CompactionPolicy::every_n_mutations(3))
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .build()?;
+    for i in 0..3 {
+        engine.put(format!("buffered{i}"), i.to_string())?;
+    }
+    assert_eq!(engine.compaction_count()?, 0);
+    assert!(engine.force_compaction_check()?);
+    assert_eq!(engine.compaction_count()?, 1);
+    assert!(!engine.force_compaction_check()?);
+    assert_eq!(engine.get("buffered2")?.as_deref(), Some("2"));
+    Ok(())
+}
+
+#[test]
+fn is_compacting_is_visible_during_compaction() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).async_compaction(true).build()?;
+    let batch = (0..20_000)
+        .map(|i| (format!("key{i}"), "v".repeat(512), None))
+        .collect();
+    engine.put_batch(batch)?;
+    assert!(!engine.is_compacting());
+
+    let worker = engine.clone();
+    let handle = thread::spawn(move || worker.compact());
+    let mut observed = false;
+    while !handle.is_finished() {
+        observed |= engine.is_compacting();
+    }
+    handle.join().unwrap()?;
+    assert!(observed);
+    assert!(!engine.is_compacting());
+    Ok(())
+}
+
+#[test]
+fn compaction_while_reader_thread_is_active() -> io::Result<()> {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for i in 0..50 {
+        engine.put(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let engine = engine.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> io::Result<()> {
+            while !done.load(Ordering::Relaxed) {
+                for i in 0..50 {
+                    assert_eq!(engine.get(&format!("key{i}"))?, Some(format!("value{i}")));
+                }
+            }
+            Ok(())
+        })
+    };
+
+    for round in 0..20 {
+        engine.put("churn".into(), round.to_string())?;
+        engine.compact()?;
+    }
+    done.store(true, Ordering::Relaxed);
+    reader.join().expect("reader thread panicked")?;
+
+    drop(engine);
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.get("key49")?, Some("value49".into()));
+    assert_eq!(engine.get("churn")?, Some("19".into()));
+    Ok(())
+}
+
+#[test]
+fn gets_see_no_errors_across_repeated_compaction_swaps() -> io::Result<()> {
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).async_compaction(true).build()?;
+    for i in 0..200 {
+        engine.put(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let (mut reads, mut errors) = (0u64, Vec::new());
+                while !done.load(Ordering::Relaxed) {
+                    for i in 0..200 {
+                        match engine.get(&format!("key{i}")) {
+                            Ok(Some(value)) if value == format!("value{i}") => {}
+                            other => errors.push(format!("key{i}: {other:?}")),
+                        }
+                        reads += 1;
+                    }
+                }
+                (reads, errors)
+            })
+        })
+        .collect();
+
+    for round in 0..50 {
+        engine.put("churn".into(), round.to_string())?;
+        engine.compact_and_wait()?;
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        let (reads, errors) = reader.join().expect("reader thread panicked");
+        assert!(reads > 0);
+        assert!(errors.is_empty(), "{errors:?}");
+    }
+    assert!(engine.compaction_count()? >= 50);
+    Ok(())
+}
+
+#[test]
+fn compact_and_wait_blocks_until_the_worker_run_finishes() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).async_compaction(true).build()?;
+    let status = engine.compaction_status();
+    assert!(status.worker_running);
+    assert_eq!(status.last_outcome, None);
+
+    for i in 0..10 {
+        engine.put("key".into(), format!("v{i}"))?;
+    }
+    engine.compact_and_wait()?;
+    assert_eq!(engine.compaction_count()?, 1);
+    let status = engine.compaction_status();
+    assert_eq!(status.last_outcome, Some(CompactionOutcome::Succeeded));
+    assert!(status.last_started <= status.last_finished);
+    assert_eq!(status.pending_triggers, 0);
+    assert_eq!(status.degraded(), None);
+
+    engine.stop_compaction_worker();
+    let status = engine.compaction_status();
+    assert!(!status.worker_running);
+    assert_eq!(status.degraded(), Some("compaction worker stopped"));
+    assert!(engine.compact_and_wait().is_err());
+    Ok(())
+}
+
+/// Minimal xorshift generator so each writer's operation sequence is reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+#[test]
+fn writes_racing_concurrent_compactions_match_a_sequential_model() -> io::Result<()> {
+    const WRITERS: u64 = 4;
+    const KEYS: u64 = 32;
+    const RUN_FOR: Duration = Duration::from_secs(3);
+
+    let dir = tempfile::tempdir()?;
+    // Without a sync per write, writers take the lock often enough to land
+    // between a compaction's snapshot and its swap.
+    let open = || {
+        CrabKv::builder(dir.path())
+            .async_compaction(true)
+            .sync_interval(Duration::from_millis(50))
+            .build()
+    };
+    let engine = open()?;
+    let stop = AtomicBool::new(false);
+
+    let models = thread::scope(|scope| -> io::Result<Vec<HashMap<String, String>>> {
+        let compactor = scope.spawn(|| -> io::Result<u64> {
+            let mut runs = 0;
+            while !stop.load(Ordering::Relaxed) {
+                if runs % 2 == 0 {
+                    engine.compact()?;
+                } else {
+                    engine.compact_and_wait()?;
+                }
+                runs += 1;
+            }
+            Ok(runs)
+        });
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let engine = &engine;
+                let stop = &stop;
+                scope.spawn(move || -> io::Result<HashMap<String, String>> {
+                    // Each writer owns its keys, so its own order decides their final state.
+                    let mut model = HashMap::new();
+                    let mut rng = Rng(writer + 1);
+                    let mut step = 0u64;
+                    while !stop.load(Ordering::Relaxed) {
+                        step += 1;
+                        let key = format!("w{writer}-{}", rng.below(KEYS));
+                        let value = format!("v{step}");
+                        match rng.below(3) {
+                            0 => {
+                                engine.delete(&key)?;
+                                model.remove(&key);
+                                let other = format!("w{writer}-{}", rng.below(KEYS));
+                                engine.put_batch(vec![
+                                    (other.clone(), format!("o{step}"), None),
+                                    (key.clone(), value.clone(), None),
+                                ])?;
+                                model.insert(other, format!("o{step}"));
+                                model.insert(key, value);
+                            }
+                            1 => {
+                                engine.put(key.clone(), value.clone())?;
+                                model.insert(key, value);
+                            }
+                            _ => {
+                                engine.delete(&key)?;
+                                model.remove(&key);
+                            }
+                        }
+                    }
+                    Ok(model)
+                })
+            })
+            .collect();
+        thread::sleep(RUN_FOR);
+        stop.store(true, Ordering::Relaxed);
+        assert!(compactor.join().unwrap()? > 1);
+        writers.into_iter().map(|w| w.join().unwrap()).collect()
+    })?;
+
+    let check = |engine: &CrabKv| -> io::Result<()> {
+        for (writer, model) in models.iter().enumerate() {
+            for key in 0..KEYS {
+                let key = format!("w{writer}-{key}");
+                assert_eq!(engine.get(&key)?, model.get(&key).cloned(), "{key}");
+            }
+        }
+        Ok(())
+    };
+    check(&engine)?;
+    engine.compact()?;
+    check(&engine)?;
+    drop(engine);
+    check(&open()?)
+}
+
+#[test]
+fn parallel_compaction_writes_the_same_log_as_one_thread() -> io::Result<()> {
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let mut logs = Vec::new();
+    let mut dirs = Vec::new();
+    for threads in [1, 4] {
+        let dir = tempfile::tempdir()?;
+        let engine = CrabKv::builder(dir.path())
+            .clock(clock.clock())
+            .compression(true)
+            .value_dedup(true)
+            .compaction_threads(threads)
+            .build()?;
+        // Enough keys to span several worker windows, with shared values,
+        // expiries and deletes mixed in.
+        for i in 0..3_000 {
+            let key = format!("k{i:05}");
+            let value = format!("value-{}-{}", i % 700, "x".repeat(i % 97));
+            if i % 5 == 0 {
+                engine.put_with_ttl(key.clone(), value, Some(Duration::from_secs(600)))?;
+            } else {
+                engine.put(key.clone(), value)?;
+            }
+            if i % 7 == 0 {
+                engine.delete(&key)?;
+            }
+        }
+        engine.compact()?;
+        drop(engine);
+        logs.push(std::fs::read(dir.path().join("wal.log"))?);
+        dirs.push(dir);
+    }
+    assert!(logs[0] == logs[1], "compacted logs differ");
+
+    let engine = CrabKv::builder(dirs[1].path())
+        .clock(clock.clock())
+        .compression(true)
+        .build()?;
+    assert_eq!(engine.get("k00001")?, Some(format!("value-1-{}", "x")));
+    assert_eq!(engine.get("k00007")?, None);
+    assert_eq!(
+        engine.keys_matching("*")?.len(),
+        3_000 - 3_000usize.div_ceil(7)
+    );
+    Ok(())
+}
+
+#[test]
+fn compaction_holds_no_more_values_than_its_buffer() -> io::Result<()> {
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let mut logs = Vec::new();
+    for buffer in [4 * 1024, 8 * 1024 * 1024] {
+        let dir = tempfile::tempdir()?;
+        let engine = CrabKv::builder(dir.path())
+            .clock(clock.clock())
+            .compaction_threads(2)
+            .compaction_buffer_bytes(buffer)
+            .build()?;
+        for round in 0..2 {
+            for i in 0..500 {
+                engine.put(format!("k{i:03}"), format!("{round}-{}", "v".repeat(200)))?;
+            }
+        }
+        engine.compact()?;
+        let peak = engine.compaction_status().peak_buffer_bytes;
+        assert!(peak > 0);
+        assert!(
+            peak <= buffer as u64,
+            "held {peak} bytes with a {buffer} byte buffer"
+        );
+        assert_eq!(engine.get("k042")?, Some(format!("1-{}", "v".repeat(200))));
+        drop(engine);
+        logs.push(std::fs::read(dir.path().join("wal.log"))?);
+    }
+    // Window size only changes how the copy is paced, not what it writes.
+    assert!(logs[0] == logs[1], "compacted logs differ");
+    Ok(())
+}
+
+#[test]
+fn a_failed_swap_keeps_writing_to_the_log_in_place() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("before".into(), "1".into())?;
+    let marker = engine.change_marker();
+    // A directory where the old log would be set aside cannot be removed
+    // like a leftover backup file.
+    let blocker = dir.path().join("wal.backup");
+    std::fs::create_dir(&blocker)?;
+    std::fs::write(blocker.join("keep"), b"x")?;
+    assert!(engine.compact().is_err());
+
+    // The index still matches the log in place, so reads need no retry.
+    assert_eq!(engine.get("before")?.as_deref(), Some("1"));
+    engine.watch_prefix("", Some(marker))?;
+    engine.put("after".into(), "2".into())?;
+    std::fs::remove_dir_all(&blocker)?;
+    engine.compact()?;
+    engine.put("later".into(), "3".into())?;
+    drop(engine);
+
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.keys()?, ["after", "before", "later"]);
+    Ok(())
+}
+
+/// Windows refuses to rename or delete a file while a handle without
+/// `FILE_SHARE_DELETE` holds it open, so this compacts under a reader.
+#[cfg(windows)]
+#[test]
+fn windows_compaction_replaces_the_log_under_an_active_reader() -> io::Result<()> {
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for i in 0..50 {
+        engine.put(format!("key{i}"), format!("value{i}"))?;
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let engine = engine.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> io::Result<()> {
+            while !done.load(Ordering::Relaxed) {
+                for i in 0..50 {
+                    assert_eq!(engine.get(&format!("key{i}"))?, Some(format!("value{i}")));
+                }
+            }
+            Ok(())
+        })
+    };
+
+    for round in 0..20 {
+        engine.put("churn".into(), round.to_string())?;
+        engine.compact()?;
+        assert!(!dir.path().join("wal.backup").exists());
+        assert!(!dir.path().join("wal.compact").exists());
+    }
+    done.store(true, Ordering::Relaxed);
+    reader.join().expect("reader thread panicked")?;
+    drop(engine);
+    // No handle is left open on a file the renames moved.
+    dir.close()
+}
@@ -0,0 +1,309 @@
+use crabkv::config::{format_duration, format_size, parse_duration, parse_size};
+use crabkv::{CompactionPolicy, CrabKv, EngineConfig, Utf8Policy};
+use std::io;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+#[test]
+fn accepted_duration_forms() -> io::Result<()> {
+    let cases = [
+        ("90", Duration::from_secs(90)),
+        ("90s", Duration::from_secs(90)),
+        ("0", Duration::ZERO),
+        ("500ms", Duration::from_millis(500)),
+        ("10m", Duration::from_secs(600)),
+        ("2h", Duration::from_secs(2 * 3600)),
+        ("7d", Duration::from_secs(7 * 86_400)),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(parse_duration(input)?, expected, "{input}");
+    }
+    Ok(())
+}
+
+#[test]
+fn rejected_duration_forms_explain_why() {
+    let cases = [
+        ("", "expected a number"),
+        ("m", "expected a number"),
+        ("-5", "expected a number"),
+        ("1.5h", "unknown unit `.5h`"),
+        ("10 m", "unknown unit ` m`"),
+        ("10min", "unknown unit `min`"),
+        ("5M", "unknown unit `M`"),
+        ("99999999999999999999", "too large"),
+        ("300000000000000d", "too large"),
+    ];
+    for (input, reason) in cases {
+        let err = parse_duration(input).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{input}");
+        assert!(err.to_string().contains(reason), "{input}: {err}");
+    }
+}
+
+#[test]
+fn durations_and_sizes_format_in_the_largest_exact_unit() -> io::Result<()> {
+    let durations = [
+        (Duration::ZERO, "0s"),
+        (Duration::from_millis(100), "100ms"),
+        (Duration::from_millis(1_500), "1500ms"),
+        (Duration::from_secs(90), "90s"),
+        (Duration::from_secs(600), "10m"),
+        (Duration::from_secs(2 * 3600), "2h"),
+        (Duration::from_secs(7 * 86_400), "7d"),
+    ];
+    for (duration, text) in durations {
+        assert_eq!(format_duration(duration), text);
+        assert_eq!(parse_duration(text)?, duration);
+    }
+    let sizes = [
+        (0, "0B"),
+        (1_000, "1000B"),
+        (4 << 10, "4KiB"),
+        (64 << 20, "64MiB"),
+        (3 << 30, "3GiB"),
+        (1 << 40, "1TiB"),
+    ];
+    for (bytes, text) in sizes {
+        assert_eq!(format_size(bytes), text);
+        assert_eq!(parse_size(text)?, bytes);
+    }
+    assert_eq!(parse_size("512")?, 512);
+    for bad in ["", "MiB", "64MB", "1.5GiB", "99999999999TiB"] {
+        assert!(parse_size(bad).is_err(), "{bad}");
+    }
+    Ok(())
+}
+
+#[test]
+fn diff_names_each_changed_setting() {
+    type Change = fn(&mut EngineConfig);
+    let changes: [(&str, Change, &str); 35] = [
+        (
+            "cache_capacity",
+            |c| c.cache_capacity = NonZeroUsize::new(64),
+            "64",
+        ),
+        (
+            "default_ttl",
+            |c| c.default_ttl = Some(Duration::from_secs(3600)),
+            "1h",
+        ),
+        (
+            "sync_interval",
+            |c| c.sync_interval = Some(Duration::from_millis(100)),
+            "100ms",
+        ),
+        ("compression", |c| c.compression = true, "true"),
+        ("write_back_cache", |c| c.write_back_cache = true, "true"),
+        (
+            "cache_entry_ttl",
+            |c| c.cache_entry_ttl = Some(Duration::from_secs(5)),
+            "5s",
+        ),
+        (
+            "min_compaction_interval",
+            |c| c.min_compaction_interval = Some(Duration::from_secs(60)),
+            "1m",
+        ),
+        (
+            "compaction_policy",
+            |c| c.compaction_policy = CompactionPolicy::every_n_mutations(100),
+            "every_n_mutations=100",
+        ),
+        (
+            "checkpoint_mutations",
+            |c| c.checkpoint_mutations = Some(10_000),
+            "10000",
+        ),
+        (
+            "checkpoint_bytes",
+            |c| c.checkpoint_bytes = Some(64 << 20),
+            "64MiB",
+        ),
+        (
+            "checkpoint_min_interval",
+            |c| c.checkpoint_min_interval = Duration::from_secs(5),
+            "5s",
+        ),
+        (
+            "checkpoint_max_age",
+            |c| c.checkpoint_max_age = Some(Duration::from_secs(600)),
+            "10m",
+        ),
+        ("skip_unknown_ops", |c| c.skip_unknown_ops = true, "true"),
+        (
+            "utf8_policy",
+            |c| c.utf8_policy = Utf8Policy::Lossy,
+            "lossy",
+        ),
+        ("flush_retries", |c| c.flush_retries = 3, "3"),
+        (
+            "flush_retry_backoff",
+            |c| c.flush_retry_backoff = Duration::from_millis(10),
+            "10ms",
+        ),
+        (
+            "prefix_quotas",
+            |c| c.prefix_quotas.push(("user:".into(), 1 << 20)),
+            "[\"user:\"=1MiB]",
+        ),
+        ("value_dedup", |c| c.value_dedup = true, "true"),
+        (
+            "min_free_bytes",
+            |c| c.min_free_bytes = Some(256 << 20),
+            "256MiB",
+        ),
+        (
+            "retention",
+            |c| c.retention = Some(Duration::from_secs(86_400)),
+            "1d",
+        ),
+        ("blob_threshold", |c| c.blob_threshold = Some(4096), "4KiB"),
+        ("histogram_limit", |c| c.histogram_limit = 10, "10"),
+        (
+            "track_modifications",
+            |c| c.track_modifications = true,
+            "true",
+        ),
+        ("ordered_index", |c| c.ordered_index = true, "true"),
+        ("compaction_threads", |c| c.compaction_threads = 4, "4"),
+        (
+            "compaction_buffer_bytes",
+            |c| c.compaction_buffer_bytes = 1 << 20,
+            "1MiB",
+        ),
+        ("event_capacity", |c| c.event_capacity = 16, "16"),
+        (
+            "idempotency_window",
+            |c| c.idempotency_window = Duration::from_secs(3600),
+            "1h",
+        ),
+        (
+            "idempotency_capacity",
+            |c| c.idempotency_capacity = 10,
+            "10",
+        ),
+        (
+            "background_error_window",
+            |c| c.background_error_window = Duration::from_secs(60),
+            "1m",
+        ),
+        (
+            "scrub_interval",
+            |c| c.scrub_interval = Some(Duration::from_secs(86_400)),
+            "1d",
+        ),
+        (
+            "scrub_bytes_per_sec",
+            |c| c.scrub_bytes_per_sec = 1 << 20,
+            "1MiB",
+        ),
+        (
+            "ttl_sweep_interval",
+            |c| c.ttl_sweep_interval = Some(Duration::from_secs(60)),
+            "1m",
+        ),
+        (
+            "allow_existing_dir",
+            |c| c.allow_existing_dir = true,
+            "true",
+        ),
+        (
+            "expected_store_id",
+            |c| c.expected_store_id = Some("store-a".into()),
+            "store-a",
+        ),
+    ];
+    let base = EngineConfig::default();
+    assert!(base.diff(&base).is_empty());
+    for (field, change, new) in changes {
+        let mut changed = base.clone();
+        change(&mut changed);
+        let diff = base.diff(&changed);
+        assert_eq!(diff.len(), 1, "{field}: {diff:?}");
+        assert_eq!(diff[0].field, field);
+        assert_eq!(diff[0].new, new, "{field}");
+        assert_eq!(
+            diff[0].to_string(),
+            format!("{field}: {} -> {new}", diff[0].old)
+        );
+    }
+}
+
+#[test]
+fn builder_applies_a_whole_config() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = EngineConfig {
+        compression: true,
+        default_ttl: Some(Duration::from_secs(60)),
+        event_capacity: 8,
+        ..EngineConfig::default()
+    };
+    let engine = CrabKv::builder(dir.path())
+        .config(config.clone())
+        .value_dedup(true)
+        .build()?;
+    let diff = config.diff(engine.config());
+    assert_eq!(diff.len(), 1, "{diff:?}");
+    assert_eq!(diff[0].field, "value_dedup");
+    let shown = engine.config().to_string();
+    assert!(
+        shown.starts_with("cache_capacity=- default_ttl=1m "),
+        "{shown}"
+    );
+    assert!(shown.contains(" event_capacity=8 "), "{shown}");
+    Ok(())
+}
+
+#[cfg(feature = "config-file")]
+#[test]
+fn configs_round_trip_through_toml() -> io::Result<()> {
+    let config = EngineConfig {
+        cache_capacity: NonZeroUsize::new(1024),
+        sync_interval: Some(Duration::from_millis(100)),
+        flush_retries: 2,
+        flush_retry_backoff: Duration::from_millis(25),
+        prefix_quotas: vec![("user:".into(), 64 << 20)],
+        min_free_bytes: Some(1 << 30),
+        blob_threshold: Some(1 << 20),
+        retention: Some(Duration::from_secs(7 * 86_400)),
+        compaction_policy: CompactionPolicy::every_n_mutations(1000),
+        ..EngineConfig::default()
+    };
+
+    let text = config.to_toml()?;
+    for line in [
+        "sync_interval = \"100ms\"",
+        "min_free_bytes = \"1GiB\"",
+        "blob_threshold = \"1MiB\"",
+        "retention = \"7d\"",
+        "\"user:\" = \"64MiB\"",
+        "every_n_mutations = 1000",
+    ] {
+        assert!(text.contains(line), "{line} missing from:\n{text}");
+    }
+    assert!(!text.contains("default_ttl"), "{text}");
+    let parsed = EngineConfig::from_toml(&text)?;
+    assert!(
+        config.diff(&parsed).is_empty(),
+        "{:?}",
+        config.diff(&parsed)
+    );
+
+    // Missing keys keep their defaults.
+    let sparse = EngineConfig::from_toml("compression = true\ndefault_ttl = \"2h\"\n")?;
+    let diff = EngineConfig::default().diff(&sparse);
+    let fields: Vec<&str> = diff.iter().map(|change| change.field).collect();
+    assert_eq!(fields, vec!["default_ttl", "compression"]);
+
+    for bad in [
+        "compresion = true",
+        "sync_interval = \"fast\"",
+        "min_free_bytes = \"1GB\"",
+    ] {
+        let err = EngineConfig::from_toml(bad).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{bad}");
+    }
+    Ok(())
+}
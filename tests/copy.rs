@@ -0,0 +1,147 @@
+use crabkv::CrabKv;
+use crabkv::ManualClock;
+use crabkv::{CopyReport, ValidationProblem};
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn copy_moves_only_the_prefix() -> io::Result<()> {
+    let source_dir = tempfile::tempdir()?;
+    let dest_dir = tempfile::tempdir()?;
+    let source = CrabKv::open(source_dir.path())?;
+    let dest = CrabKv::open(dest_dir.path())?;
+
+    source.put("tenant1:a".into(), "1".into())?;
+    source.put("tenant1:b".into(), "22".into())?;
+    source.put("tenant2:a".into(), "3".into())?;
+    source.delete("tenant1:b")?;
+
+    let report = source.copy_prefix_to("tenant1:", &dest, false)?;
+    assert_eq!(
+        report,
+        CopyReport {
+            keys_copied: 1,
+            bytes_copied: 10,
+            conflicts_skipped: 0,
+            keys_overwritten: 0,
+        }
+    );
+    assert_eq!(dest.keys_matching("*")?, vec!["tenant1:a"]);
+    assert_eq!(source.get("tenant1:a")?, Some("1".into()));
+    Ok(())
+}
+
+#[test]
+fn copy_skips_or_overwrites_conflicts() -> io::Result<()> {
+    let source_dir = tempfile::tempdir()?;
+    let dest_dir = tempfile::tempdir()?;
+    let source = CrabKv::open(source_dir.path())?;
+    let dest = CrabKv::open(dest_dir.path())?;
+
+    source.put("t:a".into(), "new".into())?;
+    source.put("t:b".into(), "new".into())?;
+    dest.put("t:a".into(), "old".into())?;
+
+    let report = source.copy_prefix_to("t:", &dest, false)?;
+    assert_eq!((report.keys_copied, report.conflicts_skipped), (1, 1));
+    assert_eq!(dest.get("t:a")?, Some("old".into()));
+    assert_eq!(dest.get("t:b")?, Some("new".into()));
+
+    let report = source.copy_prefix_to("t:", &dest, true)?;
+    assert_eq!((report.keys_copied, report.conflicts_skipped), (2, 0));
+    assert_eq!(dest.get("t:a")?, Some("new".into()));
+    Ok(())
+}
+
+#[test]
+fn copied_keys_keep_their_absolute_expiry() -> io::Result<()> {
+    let source_dir = tempfile::tempdir()?;
+    let dest_dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let open = |path| CrabKv::builder(path).clock(clock.clock()).build();
+    let source = open(source_dir.path())?;
+    let dest = open(dest_dir.path())?;
+
+    source.put_with_ttl("t:short".into(), "v".into(), Some(Duration::from_secs(60)))?;
+    source.put("t:forever".into(), "v".into())?;
+    clock.advance(Duration::from_secs(40));
+    source.copy_prefix_to("t:", &dest, false)?;
+    drop(dest);
+
+    let dest = open(dest_dir.path())?;
+    assert_eq!(dest.get("t:short")?, Some("v".into()));
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(dest.get("t:short")?, None);
+    assert_eq!(dest.get("t:forever")?, Some("v".into()));
+    Ok(())
+}
+
+#[test]
+fn dry_run_predicts_the_copy_without_writing() -> io::Result<()> {
+    let source_dir = tempfile::tempdir()?;
+    let dest_dir = tempfile::tempdir()?;
+    let source = CrabKv::open(source_dir.path())?;
+    let dest = CrabKv::open(dest_dir.path())?;
+
+    for i in 0..600 {
+        source.put(format!("t:{i:04}"), format!("value {i}"))?;
+    }
+    dest.put("t:0007".into(), "old".into())?;
+    dest.put("t:0599".into(), "old".into())?;
+
+    for overwrite in [false, true] {
+        let copy_dir = tempfile::tempdir()?;
+        let copy = CrabKv::open(copy_dir.path())?;
+        dest.copy_prefix_to("", &copy, false)?;
+
+        let plan = source.plan_copy_prefix_to("t:", &copy, overwrite)?;
+        assert!(plan.issues.is_empty(), "{:?}", plan.issues);
+        assert_eq!(copy.get("t:0007")?, Some("old".into()));
+        assert_eq!(copy.keys_matching("*")?.len(), 2);
+
+        let report = source.copy_prefix_to("t:", &copy, overwrite)?;
+        assert_eq!(plan.report, report);
+        let (overwritten, skipped) = if overwrite { (2, 0) } else { (0, 2) };
+        assert_eq!(
+            (report.keys_overwritten, report.conflicts_skipped),
+            (overwritten, skipped)
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn dry_run_reports_the_keys_a_quota_would_reject() -> io::Result<()> {
+    let source_dir = tempfile::tempdir()?;
+    let dest_dir = tempfile::tempdir()?;
+    let source = CrabKv::open(source_dir.path())?;
+    let dest = CrabKv::builder(dest_dir.path())
+        .prefix_quota("t:", 4_000)
+        .build()?;
+
+    // Enough keys to span several chunks, so quota usage must carry across them.
+    for i in 0..600 {
+        source.put(format!("t:{i:04}"), "x".repeat(40))?;
+    }
+
+    let plan = source.plan_copy_prefix_to("t:", &dest, false)?;
+    assert!(!plan.issues.is_empty());
+    assert!(matches!(
+        plan.issues[0].problem,
+        ValidationProblem::QuotaExceeded(_)
+    ));
+    assert!(dest.keys_matching("*")?.is_empty());
+
+    // The real copy fails on the chunk holding the first predicted rejection.
+    let err = source.copy_prefix_to("t:", &dest, false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+    let first = &plan.issues[0].key;
+    let written = dest.keys_matching("*")?;
+    assert!(written.iter().all(|key| key < first), "{first} {written:?}");
+
+    let batch: Vec<_> = (0..3)
+        .map(|i| (format!("u:{i}"), "v".to_string(), None))
+        .collect();
+    assert!(dest.validate_batch(&batch)?.is_empty());
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use crabkv::CrabKv;
+use std::fs;
+use std::io;
+
+#[test]
+fn repeated_values_are_stored_once() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let wal = dir.path().join("wal.log");
+    let value = "v".repeat(1024);
+    let open = || CrabKv::builder(dir.path()).value_dedup(true).build();
+
+    let engine = open()?;
+    for i in 0..100 {
+        engine.put(format!("key{i:03}"), value.clone())?;
+    }
+    let grown = fs::metadata(&wal)?.len();
+    assert!(grown < 2 * 1024 + 100 * 64, "log grew by {grown} bytes");
+    assert_eq!(engine.get("key042")?, Some(value.clone()));
+
+    // The record owner going away must not take the shared value with it.
+    engine.delete("key000")?;
+    drop(engine);
+    let engine = open()?;
+    assert_eq!(engine.open_report().live_keys, 99);
+    assert_eq!(engine.get("key000")?, None);
+    assert_eq!(engine.get("key099")?, Some(value.clone()));
+
+    engine.compact()?;
+    assert!(fs::metadata(&wal)?.len() < 2 * 1024 + 100 * 64);
+    engine.put("key001".into(), "changed".into())?;
+    drop(engine);
+    let engine = open()?;
+    assert_eq!(engine.get("key001")?, Some("changed".into()));
+    for i in 2..100 {
+        assert_eq!(engine.get(&format!("key{i:03}"))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn many_keys_sharing_few_values_store_each_value_once() -> io::Result<()> {
+    const KEYS: usize = 20_000;
+    const VALUE_LEN: usize = 200;
+    let dir = tempfile::tempdir()?;
+    let wal = dir.path().join("wal.log");
+    let open = || CrabKv::builder(dir.path()).value_dedup(true).build();
+    let value = |flag: usize| format!("flag{flag}-{}", "x".repeat(VALUE_LEN));
+
+    let engine = open()?;
+    for batch in 0..KEYS / 1_000 {
+        let entries = (0..1_000)
+            .map(|i| {
+                let n = batch * 1_000 + i;
+                (format!("user{n:05}"), value(n % 10), None)
+            })
+            .collect();
+        engine.put_batch(entries)?;
+    }
+    // Batches bypass dedup, so compaction is what shares the values.
+    engine.compact()?;
+    let compacted = fs::metadata(&wal)?.len();
+    // Ten full values plus one reference record per key.
+    let references = (KEYS * 64) as u64;
+    assert!(
+        compacted < 10 * 2 * VALUE_LEN as u64 + references,
+        "log is {compacted} bytes"
+    );
+    // Each reference saves the value less its own payload.
+    let saved = engine.stats()?.dedup_saved_bytes;
+    assert!(
+        saved > ((KEYS - 10) * (VALUE_LEN - 32)) as u64,
+        "saved {saved} bytes"
+    );
+
+    // The value map is rebuilt on open, so new puts share the old records.
+    drop(engine);
+    let engine = open()?;
+    assert_eq!(engine.get("user12345")?, Some(value(5)));
+    for i in 0..100 {
+        engine.put(format!("new{i}"), value(i % 10))?;
+    }
+    let grown = fs::metadata(&wal)?.len() - compacted;
+    assert!(grown < 100 * 64, "log grew by {grown} bytes");
+    Ok(())
+}
+
+#[test]
+fn compaction_reclaims_a_value_once_no_key_references_it() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let wal = dir.path().join("wal.log");
+    let engine = CrabKv::builder(dir.path()).value_dedup(true).build()?;
+    let doomed = format!("doomed-{}", "d".repeat(500));
+    for i in 0..50 {
+        engine.put(format!("old{i}"), doomed.clone())?;
+        engine.put(format!("keep{i}"), format!("kept-{}", "k".repeat(100)))?;
+    }
+    for i in 0..50 {
+        engine.delete(&format!("old{i}"))?;
+    }
+    engine.compact()?;
+
+    let log = fs::read(&wal)?;
+    assert!(
+        !log.windows(7).any(|window| window == b"doomed-"),
+        "compaction kept an unreferenced value"
+    );
+    assert!(log.len() < 1_000 + 50 * 64, "log is {} bytes", log.len());
+    assert_eq!(engine.get("old0")?, None);
+    assert_eq!(
+        engine.get("keep49")?,
+        Some(format!("kept-{}", "k".repeat(100)))
+    );
+    Ok(())
+}
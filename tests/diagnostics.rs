@@ -0,0 +1,98 @@
+use crabkv::CrabKv;
+use crabkv::diagnostics::{self, Check, Status};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+fn check<'a>(checks: &'a [Check], name: &str) -> &'a Check {
+    checks
+        .iter()
+        .find(|check| check.name == name)
+        .unwrap_or_else(|| panic!("no `{name}` check in {checks:?}"))
+}
+
+fn populate(dir: &Path) -> io::Result<()> {
+    let engine = CrabKv::builder(dir).build()?;
+    engine.put("a".into(), "1".into())?;
+    engine.put("b".into(), "2".into())?;
+    Ok(())
+}
+
+#[test]
+fn healthy_store_passes_every_check() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    populate(dir.path())?;
+
+    let checks = diagnostics::full_checks(dir.path(), SystemTime::now());
+    for check in &checks {
+        assert_ne!(check.status, Status::Fail, "{check:?}");
+        assert!(
+            check.name == "disk" || check.status == Status::Ok,
+            "{check:?}"
+        );
+    }
+    assert_eq!(
+        check(&checks, "index").detail,
+        "2 live keys, 0 expired, 0% stale"
+    );
+    Ok(())
+}
+
+#[test]
+fn torn_tail_is_a_warning_and_left_in_place() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    populate(dir.path())?;
+    let wal_path = dir.path().join("wal.log");
+    OpenOptions::new()
+        .append(true)
+        .open(&wal_path)?
+        .write_all(&[1, 0, 0])?;
+    let len = fs::metadata(&wal_path)?.len();
+
+    let checks = diagnostics::full_checks(dir.path(), SystemTime::now());
+    let verify = check(&checks, "verify");
+    assert_eq!(verify.status, Status::Warn);
+    assert!(verify.detail.starts_with("3 torn bytes"), "{verify:?}");
+    assert!(verify.remedy.is_some());
+    assert_eq!(
+        check(&checks, "index").detail,
+        "2 live keys, 0 expired, 0% stale"
+    );
+    assert_eq!(fs::metadata(&wal_path)?.len(), len);
+    Ok(())
+}
+
+#[test]
+fn store_open_in_another_engine_is_checked_read_only() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).build()?;
+    engine.put("a".into(), "1".into())?;
+    let len = fs::metadata(dir.path().join("wal.log"))?.len();
+
+    let checks = diagnostics::full_checks(dir.path(), SystemTime::now());
+    assert_eq!(check(&checks, "verify").status, Status::Ok);
+    assert_eq!(fs::metadata(dir.path().join("wal.log"))?.len(), len);
+    engine.put("b".into(), "2".into())?;
+    assert_eq!(engine.get("a")?, Some("1".into()));
+    Ok(())
+}
+
+#[test]
+fn backup_without_a_log_fails_the_quick_checks() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    populate(dir.path())?;
+    fs::rename(dir.path().join("wal.log"), dir.path().join("wal.backup"))?;
+
+    let checks = diagnostics::quick_checks(dir.path());
+    let leftovers = check(&checks, "leftovers");
+    assert_eq!(leftovers.status, Status::Fail);
+    assert!(
+        leftovers
+            .remedy
+            .as_deref()
+            .unwrap()
+            .contains("rename wal.backup")
+    );
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use crabkv::CrabKv;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[test]
+fn writes_are_refused_when_free_space_is_low() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let free = Arc::new(AtomicU64::new(10 << 20));
+    let queries = Arc::new(AtomicUsize::new(0));
+    let open = || {
+        let free = Arc::clone(&free);
+        let queries = Arc::clone(&queries);
+        CrabKv::builder(dir.path())
+            .min_free_bytes(1 << 20)
+            .free_space_probe(move |_| {
+                queries.fetch_add(1, Ordering::Relaxed);
+                Ok(free.load(Ordering::Relaxed))
+            })
+            .build()
+    };
+
+    let engine = open()?;
+    for i in 0..100 {
+        engine.put(format!("k{i}"), "v".into())?;
+    }
+    assert_eq!(queries.load(Ordering::Relaxed), 1);
+    drop(engine);
+
+    free.store(4096, Ordering::Relaxed);
+    let engine = open()?;
+    let err = engine.put("new".into(), "v".into()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    let err = engine
+        .put_batch(vec![("batched".into(), "v".into(), None)])
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    assert_eq!(engine.get("new")?, None);
+
+    // Deleting is how space gets reclaimed, so it is never refused.
+    engine.delete("k0")?;
+    assert_eq!(engine.get("k0")?, None);
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use crabkv::manifest::{self, MANIFEST_FILE};
+use crabkv::{CrabKv, EpochMismatch, EventKind, ManualClock, ServerConfig, WriteOptions, server};
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn mismatch(err: &io::Error) -> Option<(u64, u64)> {
+    EpochMismatch::of(err).map(|mismatch| (mismatch.expected, mismatch.current))
+}
+
+/// Writes a closed store in `dir` to restore from.
+fn write_backup(dir: &Path, clock: &ManualClock) -> io::Result<()> {
+    let backup = CrabKv::builder(dir).clock(clock.clock()).build()?;
+    backup.put("shared".into(), "from-backup".into())?;
+    backup.put("only-in-backup".into(), "kept".into())?;
+    backup.put_with_ttl(
+        "session".into(),
+        "short".into(),
+        Some(Duration::from_secs(60)),
+    )?;
+    backup.put_bytes("binary".into(), vec![0xff, 0x00, 0xfe])?;
+    backup.put("gone".into(), "deleted later".into())?;
+    backup.delete("gone")?;
+    backup.close()
+}
+
+#[test]
+fn restore_replaces_the_data_and_fences_pinned_writes() -> io::Result<()> {
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let backup_dir = tempfile::tempdir()?;
+    write_backup(backup_dir.path(), &clock)?;
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .write_back_cache(true)
+        .build()?;
+    engine.put("shared".into(), "live".into())?;
+    engine.put("only-live".into(), "dropped".into())?;
+    engine.flush()?;
+    engine.put("buffered".into(), "dropped too".into())?;
+    assert_eq!(engine.epoch(), 0);
+
+    let pinned = WriteOptions::new().expected_epoch(engine.epoch());
+    engine.put_with("before".into(), "fine".into(), &pinned)?;
+    assert_eq!(engine.restore_from(backup_dir.path())?, 1);
+    assert_eq!(engine.epoch(), 1);
+    assert_eq!(
+        engine.keys()?,
+        ["binary", "only-in-backup", "session", "shared"]
+    );
+    assert_eq!(engine.get("shared")?.as_deref(), Some("from-backup"));
+    assert_eq!(engine.get_bytes("binary")?, Some(vec![0xff, 0x00, 0xfe]));
+    assert_eq!(engine.get("buffered")?, None);
+    assert!(engine.recent_events().iter().any(|event| event.kind
+        == EventKind::Restored {
+            from: backup_dir.path().to_path_buf(),
+            epoch: 1,
+        }));
+
+    let err = engine
+        .put_with("after".into(), "stale".into(), &pinned)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::StaleNetworkFileHandle);
+    assert_eq!(mismatch(&err), Some((0, 1)));
+    let err = engine.delete_with("shared", &pinned).unwrap_err();
+    assert_eq!(mismatch(&err), Some((0, 1)));
+    assert_eq!(engine.get("after")?, None);
+    assert_eq!(engine.get("shared")?.as_deref(), Some("from-backup"));
+    let current = WriteOptions::new().expected_epoch(1);
+    engine.put_with("after".into(), "fresh".into(), &current)?;
+
+    // Restored keys keep their expiry.
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(engine.get("session")?, None);
+    engine.close()?;
+
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.epoch(), 1);
+    assert_eq!(
+        engine.keys()?,
+        ["after", "binary", "only-in-backup", "shared"]
+    );
+    Ok(())
+}
+
+#[test]
+fn restore_keeps_keys_sharing_a_deduplicated_value() -> io::Result<()> {
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let backup_dir = tempfile::tempdir()?;
+    let shared = "same-value-".repeat(8);
+    {
+        let backup = CrabKv::builder(backup_dir.path())
+            .clock(clock.clock())
+            .value_dedup(true)
+            .build()?;
+        backup.put("owner".into(), shared.clone())?;
+        // One copy sorts before the owner and one after it.
+        backup.put("copy".into(), shared.clone())?;
+        backup.put_with_ttl(
+            "later".into(),
+            shared.clone(),
+            Some(Duration::from_secs(60)),
+        )?;
+        backup.put("owner".into(), "owner-changed".into())?;
+        backup.close()?;
+    }
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    engine.restore_from(backup_dir.path())?;
+    assert_eq!(engine.keys()?, ["copy", "later", "owner"]);
+    assert_eq!(engine.get("copy")?.as_deref(), Some(shared.as_str()));
+    assert_eq!(engine.get("later")?.as_deref(), Some(shared.as_str()));
+    assert_eq!(engine.get("owner")?.as_deref(), Some("owner-changed"));
+
+    // Each key keeps its own expiry, not the owner's.
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(engine.keys()?, ["copy", "owner"]);
+    Ok(())
+}
+
+#[test]
+fn a_pin_fences_every_write_the_thread_makes() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).write_back_cache(true).build()?;
+    let other_dir = tempfile::tempdir()?;
+    let other = CrabKv::open(other_dir.path())?;
+    engine.put("queue".into(), "item".into())?;
+    engine.put("count".into(), "1".into())?;
+
+    let pin = engine.pin_epoch(0);
+    assert_eq!(engine.incr("count", 1)?, 2);
+    engine.clear()?;
+    assert!(EpochMismatch::of(&engine.put("queue".into(), "late".into()).unwrap_err()).is_some());
+    drop(pin);
+    engine.put("queue".into(), "restored".into())?;
+
+    let pin = engine.pin_epoch(0);
+    let refused: [io::Result<()>; 5] = [
+        engine.put("k".into(), "buffered".into()),
+        engine.pop("queue").map(drop),
+        engine.incr("count", 1).map(drop),
+        engine.get_set("queue".into(), "stale".into()).map(drop),
+        engine.delete("queue"),
+    ];
+    for result in refused {
+        assert_eq!(mismatch(&result.unwrap_err()), Some((0, 1)));
+    }
+    // Reads and other engines are not pinned.
+    assert_eq!(engine.get("queue")?.as_deref(), Some("restored"));
+    other.put("k".into(), "v".into())?;
+    drop(pin);
+
+    assert_eq!(engine.pop("queue")?.as_deref(), Some("restored"));
+    let _pin = engine.pin_epoch(1);
+    assert_eq!(engine.incr("count", 5)?, 5);
+    Ok(())
+}
+
+#[test]
+fn clear_and_migrate_move_to_the_next_epoch() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).write_back_cache(true).build()?;
+    engine.put("a".into(), "1".into())?;
+    engine.flush()?;
+    engine.put("b".into(), "2".into())?;
+    assert_eq!(engine.clear()?, 1);
+    assert!(engine.keys()?.is_empty());
+    assert_eq!(engine.get("b")?, None);
+    assert!(
+        engine
+            .recent_events()
+            .iter()
+            .any(|event| event.kind == EventKind::Cleared { epoch: 1 })
+    );
+
+    engine.put("c".into(), "3".into())?;
+    let moved = tempfile::tempdir()?;
+    engine.migrate_to(moved.path())?;
+    assert_eq!(engine.epoch(), 2);
+    let copied = manifest::read(moved.path())?.expect("manifest");
+    assert_eq!(copied.epoch, 2);
+    assert_eq!(copied.store_id, engine.store_id());
+    // The directory left behind still says what it held.
+    assert_eq!(manifest::read(dir.path())?.expect("manifest").epoch, 1);
+    engine.close()?;
+
+    let engine = CrabKv::open(moved.path())?;
+    assert_eq!(engine.epoch(), 2);
+    assert_eq!(engine.keys()?, ["c"]);
+    Ok(())
+}
+
+#[test]
+fn manifests_without_an_epoch_read_as_epoch_zero() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let id = CrabKv::open(dir.path())?.store_id().to_string();
+    fs::write(dir.path().join(MANIFEST_FILE), format!("store_id={id}\n"))?;
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.epoch(), 0);
+    assert_eq!(engine.clear()?, 1);
+    let text = fs::read_to_string(dir.path().join(MANIFEST_FILE))?;
+    assert_eq!(text, format!("store_id={id}\nepoch=1\n"));
+
+    fs::write(
+        dir.path().join(MANIFEST_FILE),
+        format!("store_id={id}\nepoch=soon\n"),
+    )?;
+    let err = manifest::read(dir.path()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    Ok(())
+}
+
+fn connect(addr: SocketAddr) -> io::Result<(TcpStream, BufReader<TcpStream>, String)> {
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut welcome = String::new();
+    reader.read_line(&mut welcome)?;
+    Ok((stream, reader, welcome))
+}
+
+fn send(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    line: &str,
+) -> io::Result<String> {
+    writeln!(stream, "{line}")?;
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+#[test]
+fn pinned_connections_get_err_epoch_after_a_clear() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let (mut stream, mut reader, welcome) = connect(server.local_addr())?;
+    assert!(welcome.contains(". epoch=0. "), "{welcome}");
+    assert_eq!(send(&mut stream, &mut reader, "EPOCH")?, "EPOCH 0");
+    assert!(send(&mut stream, &mut reader, "EPOCH 7")?.starts_with("ERR EPOCH expected 7"));
+    assert!(
+        send(&mut stream, &mut reader, "EPOCH soon")?.starts_with("ERR `soon` is not an epoch")
+    );
+    assert_eq!(send(&mut stream, &mut reader, "EPOCH 0")?, "EPOCH 0");
+    assert!(send(&mut stream, &mut reader, "PUT k v")?.starts_with("OK "));
+
+    engine.clear()?;
+    for command in ["PUT k v", "DELETE k", "GET k"] {
+        let reply = send(&mut stream, &mut reader, command)?;
+        assert!(
+            reply.starts_with("ERR EPOCH expected 0, the store is at epoch 1"),
+            "{command}: {reply}"
+        );
+    }
+    // The epoch itself can still be asked for, to learn what moved.
+    assert_eq!(send(&mut stream, &mut reader, "EPOCH")?, "EPOCH 1");
+
+    let (mut fresh, mut fresh_reader, welcome) = connect(server.local_addr())?;
+    assert!(welcome.contains(". epoch=1. "), "{welcome}");
+    assert_eq!(send(&mut fresh, &mut fresh_reader, "EPOCH 1")?, "EPOCH 1");
+    assert!(send(&mut fresh, &mut fresh_reader, "PUT k fresh")?.starts_with("OK "));
+    assert_eq!(engine.get("k")?.as_deref(), Some("fresh"));
+    drop((stream, fresh));
+    server.shutdown()
+}
+
+#[test]
+fn a_clear_that_fails_to_write_keeps_the_epoch() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+    let before = engine.epoch();
+    let pinned = engine.pin_epoch(before);
+
+    engine.fail_next_appends(1)?;
+    assert!(engine.clear().is_err());
+    assert_eq!(engine.epoch(), before);
+    assert_eq!(manifest::read(dir.path())?.map(|m| m.epoch), Some(before));
+    assert_eq!(engine.get("k")?.as_deref(), Some("v"));
+    // Writers pinned to the epoch are not fenced out by a clear that did nothing.
+    engine.put("still".into(), "writable".into())?;
+    drop(pinned);
+
+    assert_eq!(engine.clear()?, before + 1);
+    assert_eq!(
+        manifest::read(dir.path())?.map(|m| m.epoch),
+        Some(before + 1)
+    );
+    assert_eq!(engine.get("k")?, None);
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crabkv::events::{self, EVENTS_FILE};
+use crabkv::{CrabKv, EventKind, ManualClock, ThresholdEvent};
+use std::io;
+use std::num::NonZeroUsize;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn names(engine: &CrabKv) -> Vec<&'static str> {
+    engine
+        .recent_events()
+        .iter()
+        .map(|event| event.kind.as_str())
+        .collect()
+}
+
+#[test]
+fn events_are_recorded_in_order_and_saved_on_close() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .soft_key_count(1)
+        .build()?;
+
+    engine.put("a".into(), "1".into())?;
+    engine.put("b".into(), "2".into())?;
+    clock.advance(Duration::from_secs(1));
+    engine.flush()?;
+    clock.advance(Duration::from_secs(1));
+    engine.compact()?;
+
+    assert_eq!(
+        names(&engine),
+        vec![
+            "opened",
+            "flushed",
+            "threshold_crossed",
+            "compaction_started",
+            "compaction_finished"
+        ]
+    );
+    let events = engine.recent_events();
+    assert_eq!(events[1].kind, EventKind::Flushed { writes: 2 });
+    assert_eq!(
+        events[2].kind,
+        EventKind::ThresholdCrossed(ThresholdEvent::KeyCountAbove(2))
+    );
+    assert!(events.windows(2).all(|pair| pair[0].seq + 1 == pair[1].seq));
+    assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    assert_eq!(events[4].at, clock.now());
+
+    // Compaction already wrote the file; closing adds its own event.
+    assert!(dir.path().join(EVENTS_FILE).exists());
+    assert!(engine.try_close().is_ok_and(|closed| closed.is_ok()));
+    let lines = events::read_recent(dir.path(), 2)?;
+    assert_eq!(lines.len(), 2);
+    assert!(
+        lines[0].contains(" compaction_finished background=false log_bytes="),
+        "{lines:?}"
+    );
+    assert!(lines[1].ends_with(" closed"), "{lines:?}");
+    Ok(())
+}
+
+#[test]
+fn the_ring_keeps_only_the_latest_events() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).event_capacity(3).build()?;
+    for i in 0..4 {
+        engine.put(format!("k{i}"), "v".into())?;
+        engine.compact()?;
+    }
+    // One open plus a start and a finish per compaction.
+    let events = engine.recent_events();
+    assert_eq!(events.len(), 3);
+    assert_eq!(
+        events.iter().map(|event| event.seq).collect::<Vec<_>>(),
+        vec![6, 7, 8]
+    );
+    assert_eq!(
+        names(&engine),
+        vec![
+            "compaction_finished",
+            "compaction_started",
+            "compaction_finished"
+        ]
+    );
+
+    let silent_dir = tempfile::tempdir()?;
+    let silent = CrabKv::builder(silent_dir.path())
+        .event_capacity(0)
+        .build()?;
+    silent.compact()?;
+    assert!(silent.recent_events().is_empty());
+    Ok(())
+}
+
+#[test]
+fn a_failed_close_on_drop_is_saved_as_an_event() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .build()?;
+    engine.put("a".into(), "1".into())?;
+    engine.fail_next_appends(1)?;
+    drop(engine);
+
+    let lines = events::read_recent(dir.path(), 1)?;
+    assert!(lines[0].contains(" close_failed error="), "{lines:?}");
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use crabkv::expiry::{ExpiryCounters, QUEUE_CAPACITY, RECLAIM_BATCH, SWEEP_BATCH};
+use crabkv::{BackgroundWorker, CrabKv, EngineMode, ManualClock};
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+mod common;
+
+use common::wait_for;
+
+fn put_expiring(engine: &CrabKv, prefix: &str, count: usize) -> io::Result<()> {
+    let batch = (0..count)
+        .map(|i| {
+            (
+                format!("{prefix}{i}"),
+                "v".to_string(),
+                Some(Duration::from_secs(5)),
+            )
+        })
+        .collect();
+    engine.put_batch(batch)
+}
+
+#[test]
+fn one_write_reclaims_a_bounded_batch_of_expired_reads() -> io::Result<()> {
+    const KEYS: usize = 500;
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    let batch = (0..KEYS)
+        .map(|i| {
+            (
+                format!("k{i}"),
+                "v".to_string(),
+                Some(Duration::from_secs(5)),
+            )
+        })
+        .collect();
+    engine.put_batch(batch)?;
+    clock.advance(Duration::from_secs(10));
+
+    let events = engine.subscribe_expired()?;
+    let wal = dir.path().join("wal.log");
+    let before = fs::metadata(&wal)?.len();
+    for _ in 0..3 {
+        for i in 0..KEYS {
+            assert_eq!(engine.get(&format!("k{i}"))?, None);
+        }
+    }
+    // Reads queue keys and report them once, but write nothing.
+    assert_eq!(fs::metadata(&wal)?.len(), before);
+    assert_eq!(events.try_iter().count(), KEYS);
+    let queued = ExpiryCounters {
+        queued: KEYS as u64,
+        ..ExpiryCounters::default()
+    };
+    assert_eq!(engine.stats()?.expiry, queued);
+
+    let written = engine.bytes_written_since_open();
+    engine.put("fresh".into(), "v".into())?;
+    let counters = engine.stats()?.expiry;
+    assert_eq!(counters.reclaimed, RECLAIM_BATCH as u64);
+    let put_and_tombstones = fs::metadata(&wal)?.len() - before;
+    let put = engine.bytes_written_since_open() - written;
+    assert!(put_and_tombstones > put, "no tombstones appended");
+    assert!(put_and_tombstones < put + 64 * RECLAIM_BATCH as u64);
+
+    while engine.stats()?.expiry.reclaimed < KEYS as u64 {
+        engine.put("fresh".into(), "v".into())?;
+    }
+    drop(engine);
+
+    // The tombstones keep the keys gone after a reopen and a compaction.
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    let events = engine.subscribe_expired()?;
+    engine.compact_and_wait()?;
+    assert_eq!(events.try_iter().count(), 0);
+    assert_eq!(engine.keys_matching("*")?, vec!["fresh"]);
+    Ok(())
+}
+
+#[test]
+fn full_queue_drops_keys_and_compaction_reports_them() -> io::Result<()> {
+    let extra = 10;
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    let batch = (0..QUEUE_CAPACITY + extra)
+        .map(|i| {
+            (
+                format!("k{i}"),
+                "v".to_string(),
+                Some(Duration::from_secs(5)),
+            )
+        })
+        .collect();
+    engine.put_batch(batch)?;
+    clock.advance(Duration::from_secs(10));
+
+    let events = engine.subscribe_expired()?;
+    for i in 0..QUEUE_CAPACITY + extra {
+        engine.get(&format!("k{i}"))?;
+    }
+    let counters = engine.stats()?.expiry;
+    assert_eq!(counters.queued, QUEUE_CAPACITY as u64);
+    assert_eq!(counters.dropped, extra as u64);
+    assert_eq!(events.try_iter().count(), QUEUE_CAPACITY);
+
+    // Compaction removes every expired key and only reports the dropped ones.
+    engine.compact_and_wait()?;
+    assert_eq!(events.try_iter().count(), extra);
+    engine.put("fresh".into(), "v".into())?;
+    assert_eq!(engine.stats()?.expiry.reclaimed, 0);
+    Ok(())
+}
+
+#[test]
+fn sweeper_removes_expired_keys_nobody_reads() -> io::Result<()> {
+    let keys = 2 * SWEEP_BATCH + 500;
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .ttl_sweep_interval(Duration::from_millis(10))
+        .build()?;
+    put_expiring(&engine, "k", keys)?;
+    engine.put_with_ttl("rewritten".into(), "v".into(), Some(Duration::from_secs(5)))?;
+    engine.put("kept".into(), "v".into())?;
+    // One expired key is read first, so the next write reclaims it.
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(engine.get("k0")?, None);
+    let events = engine.subscribe_expired()?;
+    engine.put("rewritten".into(), "again".into())?;
+    let stale_before = engine.stats()?.stale_bytes;
+
+    wait_for("the sweep", || Ok(engine.stats()?.expiry.swept > 0))?;
+    wait_for("every batch", || {
+        Ok(engine.stats()?.expired_unreclaimed == 0)
+    })?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.expiry.reclaimed, 1);
+    assert_eq!(stats.expiry.swept, keys as u64 - 1);
+    assert_eq!(stats.expiry.expired, keys as u64);
+    assert_eq!(stats.keys, 2);
+    assert!(stats.stale_bytes > stale_before);
+    assert_eq!(events.try_iter().count(), keys - 1);
+    let mut live = engine.keys_matching("*")?;
+    live.sort();
+    assert_eq!(live, ["kept", "rewritten"]);
+    assert!(engine.background_errors().is_empty());
+
+    // The tombstones keep the keys gone after a restart.
+    drop(engine);
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.stats()?.keys, 2);
+    assert_eq!(engine.get("rewritten")?.as_deref(), Some("again"));
+    Ok(())
+}
+
+#[test]
+fn a_sweep_leaving_the_log_stale_starts_a_compaction() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .ttl_sweep_interval(Duration::from_millis(5))
+        .build()?;
+    let batch = (0..300)
+        .map(|i| {
+            (
+                format!("k{i}"),
+                "v".repeat(8 * 1024),
+                Some(Duration::from_secs(5)),
+            )
+        })
+        .collect();
+    engine.put_batch(batch)?;
+    engine.put("kept".into(), "v".into())?;
+    assert_eq!(engine.compaction_count()?, 0);
+
+    // Nothing is written after the keys expire; only the sweep can trigger it.
+    clock.advance(Duration::from_secs(10));
+    wait_for("the compaction", || Ok(engine.compaction_count()? > 0))?;
+    assert_eq!(engine.keys()?, ["kept"]);
+    Ok(())
+}
+
+#[test]
+fn sweeper_leaves_a_read_only_store_alone() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .ttl_sweep_interval(Duration::from_millis(5))
+        .build()?;
+    put_expiring(&engine, "k", 100)?;
+    engine.set_mode(EngineMode::ReadOnly)?;
+    clock.advance(Duration::from_secs(10));
+    let wal = dir.path().join("wal.log");
+    let before = fs::read(&wal)?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(fs::read(&wal)?, before);
+    assert_eq!(engine.stats()?.expiry.swept, 0);
+
+    engine.set_mode(EngineMode::Normal)?;
+    wait_for("the sweep", || Ok(engine.stats()?.expiry.swept == 100))?;
+    assert!(fs::metadata(&wal)?.len() > before.len() as u64);
+    Ok(())
+}
+
+#[test]
+fn sweeper_stops_with_the_last_handle_and_restarts_after_a_crash() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .ttl_sweep_interval(Duration::from_millis(5))
+        .build()?;
+    engine.crash_background_worker(BackgroundWorker::TtlSweeper);
+    wait_for("the crash report", || {
+        Ok(!engine.background_errors().is_empty())
+    })?;
+    assert_eq!(
+        engine.background_errors()[0].worker,
+        BackgroundWorker::TtlSweeper
+    );
+    put_expiring(&engine, "first", 10)?;
+    clock.advance(Duration::from_secs(10));
+    wait_for("the restarted sweeper", || {
+        Ok(engine.stats()?.expiry.swept == 10)
+    })?;
+
+    let clone = engine.clone();
+    put_expiring(&engine, "second", 10)?;
+    drop(engine);
+    drop(clone);
+    clock.advance(Duration::from_secs(10));
+    let wal = dir.path().join("wal.log");
+    let before = fs::read(&wal)?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(fs::read(&wal)?, before, "a sweep ran after the last drop");
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use crabkv::config::ServerConfig;
+use crabkv::handoff::{self, HANDOFF_FILE, HandoffState};
+use crabkv::{CrabKv, server};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Opens a connection, reads `k` and hangs up, failing if the connection is
+/// refused or closed before the reply.
+fn read_once(addr: SocketAddr) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut lines = BufReader::new(stream.try_clone()?).lines();
+    let welcome = lines.next().transpose()?.unwrap_or_default();
+    if !welcome.starts_with("Welcome") {
+        return Err(io::Error::other(format!("no welcome, got {welcome:?}")));
+    }
+    writeln!(stream, "GET k")?;
+    for line in lines {
+        let line = line?;
+        if line != "GOAWAY" {
+            return Ok(line);
+        }
+    }
+    Err(io::Error::other("closed before the reply"))
+}
+
+#[test]
+fn no_client_is_refused_while_a_server_hands_off() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = ServerConfig::default();
+    let listener = server::bind("127.0.0.1:0", &config)?.remove(0);
+    let addr = listener.local_addr()?;
+    // What the successor process would inherit.
+    let inherited = listener.try_clone()?;
+    let state_file = dir.path().join(HANDOFF_FILE);
+
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "old".into())?;
+    let old = server::spawn_on(vec![listener], engine.clone(), config.clone())?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || -> io::Result<Vec<String>> {
+                let mut replies = Vec::new();
+                while !stop.load(Ordering::SeqCst) {
+                    replies.push(read_once(addr)?);
+                }
+                Ok(replies)
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(100));
+
+    let successor = {
+        let dir = dir.path().to_owned();
+        let state_file = state_file.clone();
+        thread::spawn(move || -> io::Result<server::ServerHandle> {
+            handoff::wait_released(&state_file, Duration::from_secs(10))?;
+            let engine = CrabKv::open(&dir)?;
+            engine.put("k".into(), "new".into())?;
+            let server = server::spawn_on(vec![inherited], engine, config)?;
+            handoff::mark_ready(&state_file)?;
+            Ok(server)
+        })
+    };
+    // Give the old server a backlog of clients to hand over.
+    thread::sleep(Duration::from_millis(100));
+    old.hand_off(Duration::from_secs(5))?;
+    match engine.try_close() {
+        Ok(closed) => closed?,
+        Err(_) => panic!("the old server kept a handle on the store"),
+    }
+    handoff::mark_released(&state_file)?;
+
+    let new = successor.join().unwrap()?;
+    assert_eq!(
+        handoff::state(&state_file)?,
+        Some(HandoffState::Ready { pid: process::id() })
+    );
+    thread::sleep(Duration::from_millis(200));
+    stop.store(true, Ordering::SeqCst);
+    let mut replies = Vec::new();
+    for client in clients {
+        replies.extend(client.join().unwrap()?);
+    }
+    assert!(replies.contains(&"VALUE old".to_string()), "{replies:?}");
+    assert!(replies.contains(&"VALUE new".to_string()), "{replies:?}");
+    assert!(
+        replies
+            .iter()
+            .all(|reply| reply == "VALUE old" || reply == "VALUE new"),
+        "{replies:?}"
+    );
+    new.shutdown()
+}
+
+#[test]
+fn the_successor_waits_for_the_store_to_be_released() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let state_file = dir.path().join(HANDOFF_FILE);
+    assert_eq!(handoff::state(&state_file)?, None);
+
+    let err = handoff::wait_released(&state_file, Duration::from_millis(50)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    handoff::mark_released(&state_file)?;
+    handoff::wait_released(&state_file, Duration::from_millis(50))?;
+    handoff::reset(&state_file)?;
+    assert_eq!(handoff::state(&state_file)?, None);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn only_listening_sockets_are_inherited() -> io::Result<()> {
+    let stream_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let client = TcpStream::connect(stream_listener.local_addr()?)?;
+    let fd = std::os::fd::AsRawFd::as_raw_fd(&client);
+    let err = handoff::inherited_listener(fd).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crabkv::server;
+use crabkv::{CrabKv, ManualClock, WriteOptions};
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn start() -> ManualClock {
+    ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+}
+
+fn open(dir: &Path, clock: &ManualClock) -> io::Result<CrabKv> {
+    CrabKv::builder(dir)
+        .clock(clock.clock())
+        .idempotency_window(Duration::from_secs(60))
+        .build()
+}
+
+#[test]
+fn repeats_within_the_window_are_replayed_across_a_restart() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let options = WriteOptions::new().idempotency_key("req-1");
+    {
+        let engine = open(dir.path(), &clock)?;
+        let first = engine.put_with("k".into(), "first".into(), &options)?;
+        assert!(!first.replayed);
+        let repeat = engine.put_with("k".into(), "second".into(), &options)?;
+        assert!(repeat.replayed);
+        assert_eq!(engine.get("k")?.as_deref(), Some("first"));
+        // Only the first application counts as a write.
+        assert_eq!(engine.mutations_since_open(), 1);
+    }
+
+    clock.advance(Duration::from_secs(30));
+    let engine = open(dir.path(), &clock)?;
+    let retried = engine.put_with("k".into(), "retried".into(), &options)?;
+    assert!(retried.replayed);
+    assert_eq!(engine.get("k")?.as_deref(), Some("first"));
+    Ok(())
+}
+
+#[test]
+fn repeats_after_the_window_are_applied() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let options = WriteOptions::new().idempotency_key("req-1");
+    {
+        let engine = open(dir.path(), &clock)?;
+        engine.put_with("k".into(), "first".into(), &options)?;
+    }
+
+    clock.advance(Duration::from_secs(61));
+    let engine = open(dir.path(), &clock)?;
+    let retried = engine.put_with("k".into(), "retried".into(), &options)?;
+    assert!(!retried.replayed);
+    assert_eq!(engine.get("k")?.as_deref(), Some("retried"));
+    Ok(())
+}
+
+#[test]
+fn tokens_outlive_compaction_and_deletes_are_deduplicated() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let remove = WriteOptions::new().idempotency_key("del-1");
+    {
+        let engine = open(dir.path(), &clock)?;
+        engine.put("k".into(), "v".into())?;
+        assert!(!engine.delete_with("k", &remove)?.replayed);
+        engine.put("k".into(), "again".into())?;
+        engine.compact()?;
+        assert!(engine.delete_with("k", &remove)?.replayed);
+    }
+
+    let engine = open(dir.path(), &clock)?;
+    assert!(engine.delete_with("k", &remove)?.replayed);
+    assert_eq!(engine.get("k")?.as_deref(), Some("again"));
+
+    // Compaction drops tokens whose window has ended.
+    clock.advance(Duration::from_secs(61));
+    engine.compact()?;
+    drop(engine);
+    clock.rewind(Duration::from_secs(61));
+    let engine = open(dir.path(), &clock)?;
+    assert!(!engine.delete_with("k", &remove)?.replayed);
+    assert_eq!(engine.get("k")?, None);
+    Ok(())
+}
+
+#[test]
+fn the_oldest_tokens_are_forgotten_past_capacity() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .idempotency_capacity(2)
+        .build()?;
+    for token in ["a", "b", "c"] {
+        let options = WriteOptions::new().idempotency_key(token);
+        engine.put_with(token.into(), "v".into(), &options)?;
+        clock.advance(Duration::from_secs(1));
+    }
+    let repeat = |token: &str| {
+        let options = WriteOptions::new().idempotency_key(token);
+        engine.put_with(token.into(), "v".into(), &options)
+    };
+    assert!(repeat("c")?.replayed);
+    assert!(repeat("b")?.replayed);
+    assert!(!repeat("a")?.replayed);
+    Ok(())
+}
+
+#[test]
+fn idem_tokens_over_the_protocol() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let input = "PUT k 1 idem=t1\nPUT k 2 idem=t1\nPUT k 3 ttl=1h idem=t2\n\
+                 DELETE k idem=t3\nDELETE k idem=t3\nPUT k 4 idem=t1 idem=t2\n";
+    let mut output = Vec::new();
+    server::serve_connection(Cursor::new(input), &mut output, &engine)?;
+    let text = String::from_utf8(output).unwrap();
+    let replies: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(replies.len(), 6, "{replies:?}");
+    assert!(replies[..5].iter().all(|reply| reply.starts_with("OK ")));
+    assert!(replies[5].starts_with("ERR bad command"), "{}", replies[5]);
+    assert_eq!(engine.get("k")?, None);
+    Ok(())
+}
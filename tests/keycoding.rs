@@ -0,0 +1,51 @@
+use crabkv::CrabKv;
+use crabkv::keycoding::{self, FORMAT_VERSION};
+use std::io;
+
+#[test]
+fn hierarchical_keys_round_trip_through_the_index() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for tenant in 0..20 {
+        for user in 0..50 {
+            let key = format!("tenant/{tenant:07}/user/{user:05}");
+            engine.put(key, "v".into())?;
+        }
+    }
+    engine.put("tenant/ü/é".into(), "v".into())?;
+    engine.put("tenant/ü/ê".into(), "v".into())?;
+
+    let keys = engine.keys_matching("*")?;
+    let encoded = keycoding::encode(keys.iter().map(String::as_str));
+    let raw: usize = keys.iter().map(String::len).sum();
+    assert!(encoded.len() * 3 < raw, "{} of {raw} bytes", encoded.len());
+    assert_eq!(keycoding::decode(&encoded)?, keys);
+
+    drop(engine);
+    let reopened = CrabKv::open(dir.path())?;
+    assert_eq!(keycoding::decode(&encoded)?, reopened.keys_matching("*")?);
+    Ok(())
+}
+
+#[test]
+fn keys_without_shared_prefixes_round_trip() -> io::Result<()> {
+    let keys: Vec<String> = (0..500u32)
+        .map(|i| format!("{}{i}", char::from_u32(0x4e00 + i).unwrap()))
+        .collect();
+    let encoded = keycoding::encode(keys.iter().map(String::as_str));
+    let raw: usize = keys.iter().map(String::len).sum();
+    assert_eq!(encoded.len(), 9 + raw + 2 * keys.len());
+    assert_eq!(keycoding::decode(&encoded)?, keys);
+
+    assert!(keycoding::decode(&keycoding::encode([]))?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn decoding_rejects_unknown_versions_and_truncation() {
+    let mut encoded = keycoding::encode(["a/b", "a/c"]);
+    assert!(keycoding::decode(&encoded[..encoded.len() - 1]).is_err());
+    encoded[0] = FORMAT_VERSION + 1;
+    let err = keycoding::decode(&encoded).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "unicode-keys")]
+
+use crabkv::manifest::MANIFEST_FILE;
+use crabkv::{CrabKv, KeyPolicy, KeyPolicyMismatch};
+use std::fs;
+use std::io;
+
+/// `café` with a precomposed `é`.
+const NFC: &str = "caf\u{e9}";
+/// `café` as `e` followed by a combining acute accent.
+const NFD: &str = "cafe\u{301}";
+
+fn mismatch(err: &io::Error) -> Option<(KeyPolicy, KeyPolicy)> {
+    KeyPolicyMismatch::of(err).map(|mismatch| (mismatch.store, mismatch.requested))
+}
+
+#[test]
+fn nfc_keys_reach_the_same_entry_from_either_spelling() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let open = || {
+        CrabKv::builder(dir.path())
+            .key_policy(KeyPolicy::normalize_nfc(true))
+            .build()
+    };
+    let engine = open()?;
+    engine.put(NFD.into(), "one".into())?;
+    assert_eq!(engine.get(NFC)?.as_deref(), Some("one"));
+    assert_eq!(engine.keys()?, [NFC]);
+    assert!(engine.contains_key(NFD)?);
+    assert_eq!(
+        engine.get_many(&[NFC, NFD])?,
+        [Some("one".into()), Some("one".into())]
+    );
+    assert_eq!(engine.incr("n\u{303}", 2)?, 2);
+    assert_eq!(engine.get("\u{f1}")?.as_deref(), Some("2"));
+    assert!(!engine.put_if_absent(NFC.into(), "two".into(), None)?);
+    assert_eq!(engine.keys_matching("caf\u{e9}*")?, [NFC]);
+    engine.delete(NFC)?;
+    assert_eq!(engine.get(NFD)?, None);
+    engine.put(NFC.into(), "kept".into())?;
+    engine.close()?;
+
+    let text = fs::read_to_string(dir.path().join(MANIFEST_FILE))?;
+    assert!(text.ends_with("keys=nfc\n"), "{text}");
+    let engine = open()?;
+    assert_eq!(engine.get(NFD)?.as_deref(), Some("kept"));
+    drop(engine);
+    let err = CrabKv::open(dir.path()).err().expect("refused");
+    assert_eq!(mismatch(&err), Some((KeyPolicy::Nfc, KeyPolicy::Raw)));
+    Ok(())
+}
+
+#[test]
+fn restoring_a_raw_backup_normalizes_its_keys() -> io::Result<()> {
+    let backup = tempfile::tempdir()?;
+    {
+        let engine = CrabKv::open(backup.path())?;
+        engine.put(NFD.into(), "from-backup".into())?;
+        engine.close()?;
+    }
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .key_policy(KeyPolicy::normalize_nfc(true))
+        .build()?;
+    engine.restore_from(backup.path())?;
+    assert_eq!(engine.keys()?, [NFC]);
+    assert_eq!(engine.get(NFD)?.as_deref(), Some("from-backup"));
+    Ok(())
+}
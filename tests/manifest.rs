@@ -0,0 +1,193 @@
+use crabkv::diagnostics::{self, Status};
+use crabkv::manifest::{self, MANIFEST_FILE};
+use crabkv::{CrabKv, KeyPolicy, KeyPolicyMismatch, StoreIdMismatch};
+use std::fs;
+use std::io::{self, ErrorKind};
+
+#[test]
+fn refuses_a_non_empty_directory_without_a_store() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("photo.jpg"), b"\xff\xd8")?;
+
+    let err = CrabKv::open(dir.path()).err().expect("refused");
+    assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+    assert!(err.to_string().contains("allow_existing_dir"), "{err}");
+    let mut names: Vec<_> = fs::read_dir(dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    names.sort();
+    assert_eq!(names, ["photo.jpg"]);
+
+    let checks = diagnostics::quick_checks(dir.path());
+    let store = checks.iter().find(|check| check.name == "store").unwrap();
+    assert_eq!(store.status, Status::Warn, "{store:?}");
+    Ok(())
+}
+
+#[test]
+fn allow_existing_dir_creates_the_store_and_records_an_id() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("photo.jpg"), b"\xff\xd8")?;
+
+    let engine = CrabKv::builder(dir.path())
+        .allow_existing_dir(true)
+        .build()?;
+    engine.put("k".into(), "v".into())?;
+    let id = engine.store_id().to_string();
+    assert_eq!(id.len(), 36, "{id}");
+    assert_eq!(id.as_bytes()[14], b'4', "{id}");
+    assert_eq!(
+        manifest::read(dir.path())?.map(|manifest| manifest.store_id),
+        Some(id.clone())
+    );
+    drop(engine);
+
+    // The store is recognised from now on, and keeps its id.
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.store_id(), id);
+    assert_eq!(engine.get("k")?.as_deref(), Some("v"));
+    assert_ne!(CrabKv::open(tempfile::tempdir()?.path())?.store_id(), id);
+
+    let checks = diagnostics::quick_checks(dir.path());
+    let store = checks.iter().find(|check| check.name == "store").unwrap();
+    assert_eq!(store.detail, format!("store id {id}"));
+    Ok(())
+}
+
+#[test]
+fn stores_from_before_manifests_open_and_get_one() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    CrabKv::open(dir.path())?.put("k".into(), "v".into())?;
+    fs::remove_file(dir.path().join(MANIFEST_FILE))?;
+    fs::write(dir.path().join("notes.txt"), "kept beside the store")?;
+
+    let engine = CrabKv::builder(dir.path())
+        .expected_store_id("orders-eu")
+        .build()?;
+    assert_eq!(engine.store_id(), "orders-eu");
+    assert_eq!(engine.get("k")?.as_deref(), Some("v"));
+    Ok(())
+}
+
+#[test]
+fn a_different_expected_store_id_fails_fast() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let id = CrabKv::open(dir.path())?.store_id().to_string();
+    let len = fs::metadata(dir.path().join("wal.log"))?.len();
+
+    let err = CrabKv::builder(dir.path())
+        .expected_store_id("some-other-store")
+        .build()
+        .err()
+        .expect("mismatch");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let mismatch = StoreIdMismatch::of(&err).expect("mismatch payload");
+    assert_eq!(mismatch.expected, "some-other-store");
+    assert_eq!(mismatch.found, id);
+    assert_eq!(fs::metadata(dir.path().join("wal.log"))?.len(), len);
+
+    let err = CrabKv::builder(dir.path())
+        .expected_store_id("two words")
+        .build()
+        .err()
+        .expect("invalid id");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    CrabKv::builder(dir.path()).expected_store_id(id).build()?;
+    Ok(())
+}
+
+#[test]
+fn migration_carries_the_store_id() -> io::Result<()> {
+    let old = tempfile::tempdir()?;
+    let new = tempfile::tempdir()?;
+    let engine = CrabKv::open(old.path())?;
+    engine.put("k".into(), "v".into())?;
+    let id = engine.store_id().to_string();
+
+    engine.migrate_to(new.path())?;
+    drop(engine);
+    let engine = CrabKv::builder(new.path())
+        .expected_store_id(id.as_str())
+        .build()?;
+    assert_eq!(engine.store_id(), id);
+    Ok(())
+}
+
+#[test]
+fn a_failed_first_open_leaves_no_manifest() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    // An opcode no version of the log uses.
+    fs::write(dir.path().join("wal.log"), [0xee; 32])?;
+
+    assert!(CrabKv::open(dir.path()).is_err());
+    assert_eq!(manifest::read(dir.path())?, None);
+    assert!(!dir.path().join(MANIFEST_FILE).exists());
+
+    fs::remove_file(dir.path().join("wal.log"))?;
+    let id = CrabKv::open(dir.path())?.store_id().to_string();
+    assert_eq!(manifest::read(dir.path())?.expect("manifest").store_id, id);
+    assert_eq!(CrabKv::open(dir.path())?.store_id(), id);
+    Ok(())
+}
+
+#[test]
+fn raw_keys_keep_both_spellings_of_a_key_apart() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("cafe\u{301}".into(), "decomposed".into())?;
+    assert_eq!(engine.get("caf\u{e9}")?, None);
+    engine.put("caf\u{e9}".into(), "composed".into())?;
+    assert_eq!(engine.keys()?.len(), 2);
+    assert_eq!(engine.get("cafe\u{301}")?.as_deref(), Some("decomposed"));
+    assert_eq!(
+        manifest::read(dir.path())?.expect("manifest").keys,
+        KeyPolicy::Raw
+    );
+    Ok(())
+}
+
+fn policy_mismatch(err: &io::Error) -> Option<(KeyPolicy, KeyPolicy)> {
+    KeyPolicyMismatch::of(err).map(|mismatch| (mismatch.store, mismatch.requested))
+}
+
+#[test]
+fn a_store_is_never_reopened_with_another_key_policy() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let id = CrabKv::open(dir.path())?.store_id().to_string();
+    let err = CrabKv::builder(dir.path())
+        .key_policy(KeyPolicy::Nfc)
+        .build()
+        .err()
+        .expect("refused");
+    if cfg!(feature = "unicode-keys") {
+        assert_eq!(
+            policy_mismatch(&err),
+            Some((KeyPolicy::Raw, KeyPolicy::Nfc))
+        );
+    } else {
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    // A store created with NFC keys refuses an open that compares raw bytes.
+    fs::write(
+        dir.path().join(MANIFEST_FILE),
+        format!("store_id={id}\nepoch=0\nkeys=nfc\n"),
+    )?;
+    let err = CrabKv::open(dir.path()).err().expect("refused");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(
+        policy_mismatch(&err),
+        Some((KeyPolicy::Nfc, KeyPolicy::Raw))
+    );
+
+    fs::write(
+        dir.path().join(MANIFEST_FILE),
+        format!("store_id={id}\nkeys=nfkc\n"),
+    )?;
+    assert_eq!(
+        manifest::read(dir.path()).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use crabkv::CrabKv;
+use crabkv::lock_file::{self, LOCK_FILE, ServerLock};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn migration_under_load_moves_every_write_to_the_new_directory() -> io::Result<()> {
+    let old = tempfile::tempdir()?;
+    let target = tempfile::tempdir()?;
+    let new_dir = target.path().join("data");
+    let engine = CrabKv::builder(old.path())
+        .sync_interval(Duration::from_millis(5))
+        .blob_threshold(64)
+        .build()?;
+    for i in 0..500 {
+        engine.put(format!("seed{i}"), format!("v{i}"))?;
+    }
+    engine.put("big".into(), "x".repeat(200))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let started = Arc::new(Barrier::new(3));
+    let writer = {
+        let (engine, stop, started) = (engine.clone(), stop.clone(), started.clone());
+        thread::spawn(move || -> io::Result<HashMap<String, String>> {
+            let mut written = HashMap::new();
+            started.wait();
+            let mut i = 0u64;
+            while !stop.load(Ordering::Relaxed) {
+                let key = format!("live{}", i % 300);
+                // Every tenth value is large enough to land in a blob file.
+                let value = if i.is_multiple_of(10) {
+                    format!("{i}-{}", "b".repeat(100))
+                } else {
+                    i.to_string()
+                };
+                engine.put(key.clone(), value.clone())?;
+                written.insert(key, value);
+                i += 1;
+            }
+            Ok(written)
+        })
+    };
+    let reader = {
+        let (engine, stop, started) = (engine.clone(), stop.clone(), started.clone());
+        thread::spawn(move || -> io::Result<()> {
+            started.wait();
+            while !stop.load(Ordering::Relaxed) {
+                for i in (0..500).step_by(7) {
+                    assert_eq!(engine.get(&format!("seed{i}"))?, Some(format!("v{i}")));
+                }
+            }
+            Ok(())
+        })
+    };
+
+    started.wait();
+    thread::sleep(Duration::from_millis(20));
+    engine.migrate_to(&new_dir)?;
+    thread::sleep(Duration::from_millis(20));
+    stop.store(true, Ordering::Relaxed);
+    let written = writer.join().unwrap()?;
+    reader.join().unwrap()?;
+    assert_eq!(engine.directory(), new_dir);
+
+    // The old directory stopped changing at the switch.
+    let old_wal = old.path().join("wal.log");
+    let old_len = fs::metadata(&old_wal)?.len();
+    engine.put("after".into(), "moved".into())?;
+    engine.flush()?;
+    assert_eq!(fs::metadata(&old_wal)?.len(), old_len);
+
+    drop(engine);
+    let reopened = CrabKv::open(&new_dir)?;
+    for i in 0..500 {
+        assert_eq!(reopened.get(&format!("seed{i}"))?, Some(format!("v{i}")));
+    }
+    assert_eq!(reopened.get("big")?, Some("x".repeat(200)));
+    for (key, value) in &written {
+        assert_eq!(reopened.get(key)?.as_ref(), Some(value), "{key}");
+    }
+    assert_eq!(reopened.get("after")?, Some("moved".into()));
+    Ok(())
+}
+
+#[test]
+fn migration_refuses_a_directory_that_already_holds_a_log() -> io::Result<()> {
+    let old = tempfile::tempdir()?;
+    let occupied = tempfile::tempdir()?;
+    fs::write(occupied.path().join("wal.log"), b"")?;
+    let engine = CrabKv::open(old.path())?;
+    engine.put("k".into(), "v".into())?;
+
+    let err = engine.migrate_to(occupied.path()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(engine.directory(), old.path());
+    engine.put("still".into(), "here".into())?;
+    drop(engine);
+    assert_eq!(CrabKv::open(old.path())?.get("still")?, Some("here".into()));
+    Ok(())
+}
+
+#[test]
+fn a_served_store_takes_its_lock_file_along() -> io::Result<()> {
+    let old = tempfile::tempdir()?;
+    let new = tempfile::tempdir()?;
+    let new_dir = new.path().join("data");
+    let engine = CrabKv::open(old.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let lock = ServerLock::acquire_for(&engine, listener.local_addr()?)?;
+    let holder = lock_file::live_holder(old.path())?.expect("served");
+
+    engine.migrate_to(&new_dir)?;
+    assert_eq!(lock_file::holder(old.path())?, None);
+    assert_eq!(lock_file::live_holder(&new_dir)?, Some(holder));
+    assert_eq!(lock.path(), new_dir.join(LOCK_FILE));
+    drop(lock);
+    assert_eq!(lock_file::holder(&new_dir)?, None);
+    Ok(())
+}
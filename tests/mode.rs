@@ -0,0 +1,242 @@
+use crabkv::{Activity, CompactionPolicy, CrabKv, EngineMode, EventKind, ModeRefused};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+use common::wait_for;
+
+/// Every file under `dir` with its contents, to show nothing changed.
+fn snapshot(dir: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
+            for (inner, contents) in snapshot(&entry.path())? {
+                files.insert(format!("{name}/{inner}"), contents);
+            }
+        } else {
+            files.insert(name, fs::read(entry.path())?);
+        }
+    }
+    Ok(files)
+}
+
+fn refusal(err: &io::Error) -> (EngineMode, Activity, bool) {
+    let refused = ModeRefused::of(err).unwrap_or_else(|| panic!("not a mode refusal: {err}"));
+    (refused.mode, refused.activity, refused.deferred)
+}
+
+#[test]
+fn frozen_writes_wait_for_unfreeze() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .build()?;
+    engine.put("buffered".into(), "before".into())?;
+    assert_eq!(engine.set_mode(EngineMode::Frozen)?, EngineMode::Normal);
+    // Freezing flushed the buffer into the log.
+    let frozen = snapshot(dir.path())?;
+    assert!(String::from_utf8_lossy(&frozen["wal.log"]).contains("before"));
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            let put = engine.put("k".into(), "written while frozen".into());
+            let deleted = engine.delete("buffered");
+            done_tx.send(()).unwrap();
+            put.and(deleted)
+        })
+    };
+    assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    assert_eq!(engine.get("k")?, None);
+    assert_eq!(engine.get("buffered")?.as_deref(), Some("before"));
+    engine.flush()?;
+    assert_eq!(snapshot(dir.path())?, frozen);
+
+    assert_eq!(engine.set_mode(EngineMode::Normal)?, EngineMode::Frozen);
+    writer.join().unwrap()?;
+    assert_eq!(engine.get("k")?.as_deref(), Some("written while frozen"));
+    assert_eq!(engine.get("buffered")?, None);
+    assert!(engine.recent_events().iter().any(|event| event.kind
+        == EventKind::ModeChanged {
+            from: EngineMode::Frozen,
+            to: EngineMode::Normal,
+        }));
+    Ok(())
+}
+
+#[test]
+fn compaction_asked_for_while_frozen_runs_after_unfreeze() -> io::Result<()> {
+    for async_compaction in [false, true] {
+        let dir = tempfile::tempdir()?;
+        let engine = CrabKv::builder(dir.path())
+            .async_compaction(async_compaction)
+            .build()?;
+        for i in 0..20 {
+            engine.put("k".into(), format!("version {i}"))?;
+        }
+        engine.set_mode(EngineMode::Frozen)?;
+        let frozen = snapshot(dir.path())?;
+
+        let err = engine.compact().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            refusal(&err),
+            (EngineMode::Frozen, Activity::Compaction, true)
+        );
+        assert!(engine.compact_and_wait().is_err());
+        assert_eq!(engine.compaction_count()?, 0);
+        assert_eq!(snapshot(dir.path())?, frozen);
+
+        engine.set_mode(EngineMode::Normal)?;
+        wait_for("the deferred compaction", || {
+            Ok(engine.compaction_count()? == 1)
+        })?;
+        assert_eq!(engine.stats()?.stale_bytes, 0);
+        assert_eq!(engine.get("k")?.as_deref(), Some("version 19"));
+        // Deferred once, run once.
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(engine.compaction_count()?, 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn policy_compactions_due_while_frozen_run_after_unfreeze() -> io::Result<()> {
+    for async_compaction in [false, true] {
+        let dir = tempfile::tempdir()?;
+        let engine = CrabKv::builder(dir.path())
+            .async_compaction(async_compaction)
+            .compaction_policy(CompactionPolicy::every_n_mutations(1))
+            .min_compaction_interval(Duration::from_millis(50))
+            .build()?;
+        engine.put("k".into(), "first".into())?;
+        wait_for("the first compaction", || {
+            Ok(engine.compaction_count()? == 1)
+        })?;
+        // Due, but held back by the minimum interval.
+        engine.put("k".into(), "second".into())?;
+        engine.set_mode(EngineMode::Frozen)?;
+        let frozen = snapshot(dir.path())?;
+        thread::sleep(Duration::from_millis(60));
+
+        assert!(!engine.force_compaction_check()?);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(engine.compaction_count()?, 1);
+        assert_eq!(snapshot(dir.path())?, frozen);
+
+        engine.set_mode(EngineMode::Normal)?;
+        wait_for("the deferred compaction", || {
+            Ok(engine.compaction_count()? == 2)
+        })?;
+        assert_eq!(engine.get("k")?.as_deref(), Some("second"));
+    }
+    Ok(())
+}
+
+#[test]
+fn checkpoints_asked_for_while_frozen_are_written_after_unfreeze() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+    engine.set_mode(EngineMode::Frozen)?;
+    let err = engine.checkpoint().unwrap_err();
+    assert_eq!(
+        refusal(&err),
+        (EngineMode::Frozen, Activity::Checkpoint, true)
+    );
+    assert_eq!(engine.checkpoint_status()?.last_taken, None);
+
+    engine.set_mode(EngineMode::Normal)?;
+    let status = engine.checkpoint_status()?;
+    assert!(status.last_taken.is_some());
+    assert_eq!(status.uncovered_bytes, 0);
+    Ok(())
+}
+
+#[test]
+fn read_only_refuses_every_change_to_the_directory() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .async_compaction(true)
+        .checkpoint_mutations(1)
+        .build()?;
+    for i in 0..10 {
+        engine.put("k".into(), format!("version {i}"))?;
+    }
+    engine.set_mode(EngineMode::ReadOnly)?;
+    let before = snapshot(dir.path())?;
+
+    let write = engine.put("k".into(), "refused".into()).unwrap_err();
+    assert_eq!(write.kind(), io::ErrorKind::ReadOnlyFilesystem);
+    assert_eq!(
+        refusal(&write),
+        (EngineMode::ReadOnly, Activity::Write, false)
+    );
+    assert!(engine.delete("k").is_err());
+    assert!(engine.incr("n", 1).is_err());
+    assert!(engine.pop("k").is_err());
+    assert_eq!(
+        refusal(&engine.compact().unwrap_err()),
+        (EngineMode::ReadOnly, Activity::Compaction, false)
+    );
+    assert!(engine.compact_and_wait().is_err());
+    assert!(!engine.force_compaction_check()?);
+    assert_eq!(
+        refusal(&engine.checkpoint().unwrap_err()),
+        (EngineMode::ReadOnly, Activity::Checkpoint, false)
+    );
+    assert_eq!(
+        refusal(&engine.migrate_to(target.path().join("moved")).unwrap_err()),
+        (EngineMode::ReadOnly, Activity::Migration, false)
+    );
+    engine.flush()?;
+    assert_eq!(engine.get("k")?.as_deref(), Some("version 9"));
+    assert_eq!(engine.stats()?.mode, EngineMode::ReadOnly);
+    assert_eq!(snapshot(dir.path())?, before);
+
+    // Nothing was deferred; closing writes nothing either.
+    let compactions = engine.compaction_count()?;
+    engine.set_mode(EngineMode::Shadow)?;
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(engine.compaction_count()?, compactions);
+    engine.set_mode(EngineMode::ReadOnly)?;
+    let before = snapshot(dir.path())?;
+    engine.try_close().ok().expect("last handle")?;
+    assert_eq!(snapshot(dir.path())?, before);
+
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(engine.mode(), EngineMode::Normal);
+    assert_eq!(engine.get("k")?.as_deref(), Some("version 9"));
+    Ok(())
+}
+
+#[test]
+fn shadow_mode_allows_everything() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).async_compaction(true).build()?;
+    engine.set_mode(EngineMode::Shadow)?;
+    engine.put("k".into(), "first".into())?;
+    engine.put("k".into(), "second".into())?;
+    engine.compact_and_wait()?;
+    engine.compact()?;
+    engine.checkpoint()?;
+    assert_eq!(engine.compaction_count()?, 2);
+    assert_eq!(engine.get("k")?.as_deref(), Some("second"));
+    assert_eq!(engine.stats()?.mode, EngineMode::Shadow);
+    assert!(engine.background_errors().is_empty());
+    Ok(())
+}
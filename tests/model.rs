@@ -0,0 +1,220 @@
+//! Drives the engine and an in-memory oracle with the same random operations
+//! and checks every read against the oracle.
+//!
+//! `CRABKV_MODEL_SEEDS` and `CRABKV_MODEL_OPS` lengthen the run; a failure names
+//! its seed, which `CRABKV_MODEL_SEED` replays on its own.
+
+use crabkv::ManualClock;
+use crabkv::{CrabKv, CrabKvBuilder};
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_SEEDS: u64 = 8;
+const DEFAULT_OPS: usize = 2_000;
+
+/// Few enough keys that operations keep hitting the same ones.
+const KEYS: u64 = 24;
+
+/// Above the blob threshold of the configurations that set one.
+const LARGE_VALUE: usize = 96;
+
+/// Minimal xorshift generator; the seed alone decides the whole run.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+
+    fn key(&mut self) -> String {
+        format!("k{}", self.below(KEYS))
+    }
+
+    fn value(&mut self, step: usize) -> String {
+        match self.below(8) {
+            0 => format!("{step}-{}", "x".repeat(LARGE_VALUE)),
+            // Repeated values give value dedup something to share.
+            1 => "shared".to_string(),
+            _ => format!("v{step}"),
+        }
+    }
+
+    /// Whole seconds, since expiry times are stored at second precision.
+    fn ttl(&mut self) -> Option<Duration> {
+        match self.below(3) {
+            0 => None,
+            _ => Some(Duration::from_secs(1 + self.below(5))),
+        }
+    }
+}
+
+/// Expected contents: each key's value and when it stops being readable.
+#[derive(Default)]
+struct Oracle {
+    entries: HashMap<String, (String, Option<SystemTime>)>,
+}
+
+impl Oracle {
+    fn put(&mut self, key: String, value: String, ttl: Option<Duration>, now: SystemTime) {
+        self.entries.insert(key, (value, ttl.map(|ttl| now + ttl)));
+    }
+
+    fn get(&self, key: &str, now: SystemTime) -> Option<String> {
+        match self.entries.get(key) {
+            Some((value, expires_at)) if expires_at.is_none_or(|deadline| now < deadline) => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Builder options varied by seed so each run covers a different mix.
+fn configure(builder: CrabKvBuilder, seed: u64) -> CrabKvBuilder {
+    let capacity = NonZeroUsize::new(4).unwrap();
+    match seed % 5 {
+        0 => builder,
+        1 => builder.cache_capacity(capacity),
+        2 => builder.cache_capacity(capacity).write_back_cache(true),
+        3 => builder
+            .compression(true)
+            .value_dedup(true)
+            .ordered_index(true),
+        _ => builder.blob_threshold(LARGE_VALUE / 2),
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn run(seed: u64, ops: usize, dir: &Path) -> io::Result<()> {
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let open = || {
+        configure(CrabKv::builder(dir), seed)
+            .clock(clock.clock())
+            .build()
+    };
+    let mut engine = open()?;
+    let mut rng = Rng::new(seed);
+    let mut oracle = Oracle::default();
+
+    for step in 0..ops {
+        let now = clock.now();
+        match rng.below(100) {
+            0..=24 => {
+                let (key, value) = (rng.key(), rng.value(step));
+                engine.put(key.clone(), value.clone())?;
+                oracle.put(key, value, None, now);
+            }
+            25..=39 => {
+                let (key, value, ttl) = (rng.key(), rng.value(step), rng.ttl());
+                engine.put_with_ttl(key.clone(), value.clone(), ttl)?;
+                oracle.put(key, value, ttl, now);
+            }
+            40..=47 => {
+                let key = rng.key();
+                engine.delete(&key)?;
+                oracle.entries.remove(&key);
+            }
+            48..=51 => {
+                let len = 1 + rng.below(4);
+                let keys: Vec<String> = (0..len).map(|_| rng.key()).collect();
+                engine.delete_batch(keys.clone())?;
+                for key in keys {
+                    oracle.entries.remove(&key);
+                }
+            }
+            52..=59 => {
+                let len = 1 + rng.below(4);
+                let batch: Vec<_> = (0..len)
+                    .map(|_| (rng.key(), rng.value(step), rng.ttl()))
+                    .collect();
+                engine.put_batch(batch.clone())?;
+                for (key, value, ttl) in batch {
+                    oracle.put(key, value, ttl, now);
+                }
+            }
+            60..=87 => {
+                let key = rng.key();
+                let expected = oracle.get(&key, now);
+                let actual = engine.get(&key)?;
+                if actual != expected {
+                    return Err(io::Error::other(format!(
+                        "step {step}: get {key} returned {actual:?}, oracle has {expected:?}"
+                    )));
+                }
+            }
+            88..=91 => clock.advance(Duration::from_secs(rng.below(3))),
+            92..=94 => engine.flush()?,
+            95..=97 => engine.compact()?,
+            _ => {
+                // A clean shutdown: write-back entries are flushed before the drop.
+                engine.flush()?;
+                drop(engine);
+                engine = open()?;
+            }
+        }
+    }
+
+    let now = clock.now();
+    for key in 0..KEYS {
+        let key = format!("k{key}");
+        let expected = oracle.get(&key, now);
+        let actual = engine.get(&key)?;
+        if actual != expected {
+            return Err(io::Error::other(format!(
+                "final get {key} returned {actual:?}, oracle has {expected:?}"
+            )));
+        }
+    }
+    let mut expected: Vec<(String, String)> = (0..KEYS)
+        .map(|key| format!("k{key}"))
+        .filter_map(|key| oracle.get(&key, now).map(|value| (key, value)))
+        .collect();
+    expected.sort();
+    let scanned: Vec<(String, String)> = engine.range(..).collect::<io::Result<_>>()?;
+    if scanned != expected {
+        return Err(io::Error::other(format!(
+            "final range scan returned {} pairs, oracle has {}",
+            scanned.len(),
+            expected.len()
+        )));
+    }
+    Ok(())
+}
+
+#[test]
+fn random_operations_match_the_oracle() -> io::Result<()> {
+    let ops = env_or("CRABKV_MODEL_OPS", DEFAULT_OPS);
+    let seeds = match env::var("CRABKV_MODEL_SEED") {
+        Ok(seed) => {
+            let seed = seed.parse().expect("CRABKV_MODEL_SEED must be a number");
+            seed..seed + 1
+        }
+        Err(_) => 0..env_or("CRABKV_MODEL_SEEDS", DEFAULT_SEEDS),
+    };
+    for seed in seeds {
+        let dir = tempfile::tempdir()?;
+        if let Err(err) = run(seed, ops, dir.path()) {
+            panic!(
+                "seed {seed} diverged from the oracle (rerun with CRABKV_MODEL_SEED={seed}): {err}"
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use crabkv::CrabKv;
+use crabkv::ManualClock;
+use crabkv::Modification;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn at(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn changed(key: &str, secs: u64, deleted: bool) -> Modification {
+    Modification {
+        key: key.into(),
+        at: at(secs),
+        deleted,
+    }
+}
+
+#[test]
+fn modified_since_lists_latest_changes_newest_first() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(at(1_000));
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .track_modifications(true)
+        .build()?;
+
+    engine.put("a".into(), "1".into())?;
+    clock.advance(Duration::from_secs(10));
+    engine.put("b".into(), "2".into())?;
+    clock.advance(Duration::from_secs(10));
+    engine.put("c".into(), "3".into())?;
+    clock.advance(Duration::from_secs(10));
+    engine.put("a".into(), "4".into())?;
+    engine.delete("b")?;
+    engine.delete("never-written")?;
+
+    assert_eq!(
+        engine.modified_since(at(1_010), 10)?,
+        vec![
+            changed("b", 1_030, true),
+            changed("a", 1_030, false),
+            changed("c", 1_020, false),
+        ]
+    );
+    assert_eq!(
+        engine.modified_since(at(1_000), 2)?,
+        vec![changed("b", 1_030, true), changed("a", 1_030, false)]
+    );
+    assert!(engine.modified_since(at(1_031), 10)?.is_empty());
+    assert!(engine.modified_since(at(0), 0)?.is_empty());
+
+    // Reopening rebuilds the order from the write times in the log.
+    drop(engine);
+    let engine = CrabKv::builder(dir.path())
+        .clock(clock.clock())
+        .track_modifications(true)
+        .build()?;
+    assert_eq!(
+        engine.modified_since(at(0), 10)?,
+        vec![changed("a", 1_030, false), changed("c", 1_020, false)]
+    );
+    Ok(())
+}
+
+#[test]
+fn modified_since_requires_tracking() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let err = engine.modified_since(UNIX_EPOCH, 10).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    Ok(())
+}
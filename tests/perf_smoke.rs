@@ -0,0 +1,241 @@
+//! Fixed workloads with generous bounds on wall time and on the counters that
+//! stand in for efficiency: fsyncs, log file opens, and bytes and allocations
+//! per operation. They are slow, so they only run when asked for:
+//!
+//! ```text
+//! cargo test --release --test perf_smoke -- --ignored
+//! ```
+//!
+//! The bounds are loose enough for a busy CI machine; a failure means a change
+//! made a workload several times costlier, not a few percent.
+
+use crabkv::CrabKv;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+/// Counts the bytes and allocations each thread makes, so a test sees only
+/// its own.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<u64> = const { Cell::new(0) };
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ =
+            ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size() as u64));
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let grown = new_size.saturating_sub(layout.size()) as u64;
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + grown));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocated() -> u64 {
+    ALLOCATED.with(Cell::get)
+}
+
+fn allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[test]
+#[ignore = "perf smoke test; run with --ignored"]
+fn puts_under_a_sync_interval_share_fsyncs() -> io::Result<()> {
+    const PUTS: u64 = 10_000;
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_millis(10))
+        .min_compaction_interval(Duration::from_secs(3600))
+        .build()?;
+    let syncs_before = engine.stats()?.log_syncs;
+
+    let started = Instant::now();
+    let allocated_before = allocated();
+    for i in 0..PUTS {
+        engine.put(format!("key:{i:05}"), "v".repeat(100))?;
+    }
+    let per_put = (allocated() - allocated_before) / PUTS;
+    let elapsed = started.elapsed();
+
+    let syncs = engine.stats()?.log_syncs - syncs_before;
+    assert!(
+        elapsed < Duration::from_secs(10),
+        "{PUTS} puts took {elapsed:?}"
+    );
+    // One fsync per interval, not per put.
+    assert!(syncs < PUTS / 20, "{PUTS} puts issued {syncs} fsyncs");
+    assert!(per_put < 4096, "each put allocated {per_put} bytes");
+    Ok(())
+}
+
+#[test]
+#[ignore = "perf smoke test; run with --ignored"]
+fn multi_get_into_allocates_per_call_not_per_value() -> io::Result<()> {
+    const KEYS: usize = 500;
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("k{i}")).collect();
+    for key in &keys {
+        engine.put(key.clone(), "v".repeat(1024))?;
+    }
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+
+    let before = allocations();
+    engine.get_many(&keys)?;
+    let get_many = allocations() - before;
+
+    let mut arena = Vec::new();
+    engine.multi_get_into(&keys, &mut arena)?;
+    arena.clear();
+    let before = allocations();
+    engine.multi_get_into(&keys, &mut arena)?;
+    let into = allocations() - before;
+
+    assert!(
+        get_many >= KEYS as u64,
+        "get_many made {get_many} allocations"
+    );
+    // The returned ranges, the pointers to read and a read buffer.
+    assert!(into < 16, "multi_get_into made {into} allocations");
+    Ok(())
+}
+
+#[test]
+#[ignore = "perf smoke test; run with --ignored"]
+fn cached_gets_stay_off_the_disk() -> io::Result<()> {
+    const GETS: u64 = 100_000;
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(1024).unwrap())
+        .build()?;
+    for i in 0..100 {
+        engine.put(format!("key:{i:03}"), "v".repeat(100))?;
+    }
+    let before = engine.stats()?;
+
+    let started = Instant::now();
+    for i in 0..GETS {
+        let value = engine.get(&format!("key:{:03}", i % 100))?;
+        assert!(value.is_some());
+    }
+    let elapsed = started.elapsed();
+
+    let after = engine.stats()?;
+    assert!(
+        elapsed < Duration::from_secs(10),
+        "{GETS} gets took {elapsed:?}"
+    );
+    assert!(
+        after.cache_hits - before.cache_hits >= GETS - 100,
+        "only {} of {GETS} gets hit the cache",
+        after.cache_hits - before.cache_hits
+    );
+    assert_eq!(after.log_opens, before.log_opens, "gets opened log files");
+    assert_eq!(after.log_syncs, before.log_syncs, "gets synced the log");
+    Ok(())
+}
+
+#[test]
+#[ignore = "perf smoke test; run with --ignored"]
+fn compacting_fifty_megabytes_opens_and_syncs_a_handful_of_times() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_millis(10))
+        .min_compaction_interval(Duration::from_secs(3600))
+        .build()?;
+    // 50 MB of live values, each written twice so half the log is stale.
+    let value = "v".repeat(64 * 1024);
+    for _ in 0..2 {
+        for i in 0..800 {
+            engine.put(format!("key:{i:03}"), value.clone())?;
+        }
+    }
+    let before = engine.stats()?;
+
+    let started = Instant::now();
+    engine.compact()?;
+    let elapsed = started.elapsed();
+
+    let after = engine.stats()?;
+    assert!(
+        elapsed < Duration::from_secs(30),
+        "compaction took {elapsed:?}"
+    );
+    let opens = after.log_opens - before.log_opens;
+    let syncs = after.log_syncs - before.log_syncs;
+    assert!(opens <= 2, "compaction opened {opens} log files");
+    assert!(syncs <= 4, "compaction issued {syncs} fsyncs");
+    assert_eq!(after.keys, 800);
+    Ok(())
+}
+
+#[test]
+#[ignore = "perf smoke test; run with --ignored"]
+fn a_million_small_records_open_and_compact_in_bounded_memory() -> io::Result<()> {
+    const KEYS: u64 = 1_000_000;
+    const BUFFER: usize = 1024 * 1024;
+    let dir = tempfile::tempdir()?;
+    {
+        let engine = CrabKv::builder(dir.path())
+            .min_compaction_interval(Duration::from_secs(3600))
+            .build()?;
+        let mut batch = Vec::new();
+        for i in 0..KEYS {
+            batch.push((format!("key:{i:07}"), format!("v{i}"), None));
+            if batch.len() == 10_000 {
+                engine.put_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        // A fifth of the keys are overwritten, so part of the log is stale.
+        for i in (0..KEYS).step_by(5) {
+            batch.push((format!("key:{i:07}"), format!("w{i}"), None));
+            if batch.len() == 10_000 {
+                engine.put_batch(std::mem::take(&mut batch))?;
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let engine = CrabKv::builder(dir.path())
+        .min_compaction_interval(Duration::from_secs(3600))
+        .compaction_buffer_bytes(BUFFER)
+        .build()?;
+    let elapsed = started.elapsed();
+    let report = engine.open_report();
+    assert_eq!(report.live_keys, KEYS as usize);
+    assert!(elapsed < Duration::from_secs(30), "open took {elapsed:?}");
+    // The replayed map and the index it becomes, each with a power-of-two
+    // table, plus the keys themselves.
+    let budget = KEYS * 384;
+    assert!(
+        report.peak_memory_bytes < budget,
+        "open held an estimated {} bytes, over {budget}",
+        report.peak_memory_bytes
+    );
+
+    engine.compact()?;
+    let peak = engine.compaction_status().peak_buffer_bytes;
+    assert!(
+        peak > 0 && peak <= BUFFER as u64,
+        "compaction held {peak} value bytes with a {BUFFER} byte buffer"
+    );
+    assert_eq!(engine.get("key:0000005")?.as_deref(), Some("w5"));
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use crabkv::diagnostics::{self, Status};
+use crabkv::{Corrupted, CrabKv};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Overwrites the first occurrence of `value` in the log with bytes that are
+/// not UTF-8, so the record holding it no longer decodes.
+fn corrupt_value(dir: &Path, value: &str) -> io::Result<()> {
+    let wal = dir.join("wal.log");
+    let bytes = fs::read(&wal)?;
+    let at = bytes
+        .windows(value.len())
+        .position(|window| window == value.as_bytes())
+        .expect("value in the log");
+    let mut file = OpenOptions::new().write(true).open(&wal)?;
+    file.seek(SeekFrom::Start(at as u64))?;
+    file.write_all(&vec![0xff; value.len()])?;
+    file.sync_all()
+}
+
+fn corrupted_key(err: &io::Error) -> Option<&str> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<Corrupted>())
+        .map(|corrupted| corrupted.key.as_str())
+}
+
+#[test]
+fn compaction_quarantines_an_unreadable_record_and_keeps_the_rest() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for i in 0..1000 {
+        engine.put(format!("key{i:04}"), format!("value-{i:04}"))?;
+    }
+    engine.delete("key0001")?;
+    corrupt_value(dir.path(), "value-0500")?;
+
+    engine.compact()?;
+    let quarantined = engine.quarantined()?;
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].key, "key0500");
+    assert!(quarantined[0].error.contains("utf-8"), "{quarantined:?}");
+    assert_eq!(engine.stats()?.quarantined, 1);
+
+    let err = engine.get("key0500").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(corrupted_key(&err), Some("key0500"));
+    for i in (0..1000).filter(|i| *i != 500 && *i != 1) {
+        assert_eq!(
+            engine.get(&format!("key{i:04}"))?,
+            Some(format!("value-{i:04}"))
+        );
+    }
+
+    // The damaged record is gone, so later compactions run normally.
+    for i in 0..1000 {
+        engine.put(format!("key{i:04}"), format!("again-{i:04}"))?;
+    }
+    engine.compact()?;
+    assert_eq!(engine.compaction_count()?, 2);
+    Ok(())
+}
+
+#[test]
+fn quarantine_survives_a_reopen_until_the_key_is_written_or_dropped() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for i in 0..100 {
+        engine.put(format!("key{i:02}"), format!("value-{i:02}"))?;
+    }
+    corrupt_value(dir.path(), "value-07")?;
+    corrupt_value(dir.path(), "value-42")?;
+    engine.compact()?;
+    drop(engine);
+
+    let doctor = diagnostics::full_checks(dir.path(), SystemTime::now());
+    let check = doctor
+        .iter()
+        .find(|check| check.name == "quarantine")
+        .expect("quarantine check");
+    assert_eq!(check.status, Status::Warn);
+    assert!(check.detail.contains("key07") && check.detail.contains("key42"));
+
+    let engine = CrabKv::open(dir.path())?;
+    assert_eq!(
+        corrupted_key(&engine.get("key07").unwrap_err()),
+        Some("key07")
+    );
+    engine.put("key42".into(), "restored".into())?;
+    assert_eq!(engine.get("key42")?, Some("restored".into()));
+    assert_eq!(engine.quarantined()?.len(), 1);
+
+    assert_eq!(engine.drop_quarantined()?, vec!["key07".to_string()]);
+    assert_eq!(engine.get("key07")?, None);
+    assert!(!dir.path().join("quarantine.log").exists());
+    drop(engine);
+
+    let engine = CrabKv::open(dir.path())?;
+    assert!(engine.quarantined()?.is_empty());
+    assert_eq!(engine.get("key42")?, Some("restored".into()));
+    Ok(())
+}
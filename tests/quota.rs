@@ -0,0 +1,139 @@
+use crabkv::CrabKv;
+use crabkv::QuotaExceeded;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+
+#[test]
+fn prefix_quota_rejects_and_recovers() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let value = "x".repeat(40);
+    let open = || {
+        CrabKv::builder(dir.path())
+            .prefix_quota("tenant:", 300)
+            .build()
+    };
+
+    let engine = open()?;
+    let mut stored = 0;
+    let err = loop {
+        match engine.put(format!("tenant:{stored}"), value.clone()) {
+            Ok(()) => stored += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(stored > 0);
+    assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+    let exceeded = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<QuotaExceeded>())
+        .expect("quota error payload");
+    assert_eq!(exceeded.prefix, "tenant:");
+    assert_eq!(exceeded.limit, 300);
+    assert!(exceeded.used <= 300);
+
+    // Other prefixes are not limited.
+    engine.put("other:key".into(), value.repeat(20))?;
+
+    let full = engine.stats_by_prefix()?[0].used;
+    assert_eq!(full, exceeded.used);
+    engine.delete("tenant:0")?;
+    let after_delete = engine.stats_by_prefix()?[0].used;
+    assert!(after_delete < full);
+    engine.put(format!("tenant:{stored}"), value.clone())?;
+    assert!(engine.put("tenant:extra".into(), value.clone()).is_err());
+
+    // Usage is rebuilt on open; the billing counters start again from zero.
+    let used = |engine: &CrabKv| -> io::Result<Vec<u64>> {
+        Ok(engine.stats_by_prefix()?.iter().map(|u| u.used).collect())
+    };
+    let usage = used(&engine)?;
+    drop(engine);
+    let engine = open()?;
+    assert_eq!(used(&engine)?, usage);
+    assert_eq!(engine.stats_by_prefix()?[0].writes, 0);
+    engine.compact()?;
+    assert_eq!(used(&engine)?, usage);
+    Ok(())
+}
+
+/// `(bytes_written, bytes_read, writes, reads)` of each registered prefix.
+fn billed(usage: &[crabkv::PrefixUsage]) -> Vec<(u64, u64, u64, u64)> {
+    usage
+        .iter()
+        .map(|u| (u.bytes_written, u.bytes_read, u.writes, u.reads))
+        .collect()
+}
+
+#[test]
+fn prefixes_are_billed_uncompressed_bytes_and_operations() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .prefix_quota("a:", u64::MAX)
+        .prefix_quota("b:", u64::MAX)
+        .compression(true)
+        .cache_capacity(NonZeroUsize::new(1).unwrap())
+        .build()?;
+    let value = "x".repeat(1000);
+    let log_before = fs::metadata(dir.path().join("wal.log"))?.len();
+    engine.put("a:1".into(), value.clone())?;
+    // Billed the uncompressed 1003 bytes, though far fewer reached the log.
+    assert!(fs::metadata(dir.path().join("wal.log"))?.len() - log_before < 200);
+
+    engine.put_batch(vec![
+        ("a:2".into(), "hello".into(), None),
+        ("b:1".into(), "world!".into(), None),
+        ("other".into(), "unbilled".into(), None),
+    ])?;
+    engine.delete("b:1")?;
+    engine.delete_batch(vec!["a:2".into(), "b:gone".into()])?;
+    assert_eq!(
+        billed(&engine.stats_by_prefix()?),
+        vec![(1003 + 8 + 3, 0, 3, 0), (9 + 3, 0, 2, 0)]
+    );
+
+    // The cache holds one entry: `a:1` is read from the log, then the cache.
+    assert_eq!(engine.get("a:1")?.as_deref(), Some(value.as_str()));
+    assert_eq!(engine.get("a:1")?.as_deref(), Some(value.as_str()));
+    engine.put("b:2".into(), "abc".into())?;
+    assert_eq!(
+        engine.get_many(&["b:2", "b:missing", "a:1"])?,
+        vec![Some("abc".into()), None, Some(value.clone())]
+    );
+    engine.get("other")?;
+
+    let snapshot = engine.reset_prefix_counters()?;
+    assert_eq!(
+        billed(&snapshot),
+        vec![(1014, 3000, 3, 3), (12 + 6, 3, 3, 2)]
+    );
+    assert_eq!(snapshot[0].prefix, "a:");
+    assert_eq!(snapshot[1].used, engine.stats_by_prefix()?[1].used);
+    assert_eq!(
+        billed(&engine.stats_by_prefix()?),
+        vec![(0, 0, 0, 0), (0, 0, 0, 0)]
+    );
+    Ok(())
+}
+
+#[test]
+fn write_back_puts_are_billed_when_flushed() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .prefix_quota("t:", u64::MAX)
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .build()?;
+    engine.put("t:1".into(), "first".into())?;
+    engine.put("t:1".into(), "second".into())?;
+    engine.put("t:2".into(), "v".into())?;
+    assert_eq!(billed(&engine.stats_by_prefix()?), vec![(0, 0, 0, 0)]);
+
+    // Only the values that reach the log are billed.
+    engine.flush()?;
+    assert_eq!(
+        billed(&engine.stats_by_prefix()?),
+        vec![(3 + 6 + 3 + 1, 0, 2, 0)]
+    );
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use crabkv::{CrabKv, ManualClock};
+use std::io;
+use std::ops::Bound;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn keys(engine: &CrabKv, range: (Bound<String>, Bound<String>)) -> io::Result<Vec<String>> {
+    engine
+        .range(range)
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect()
+}
+
+#[test]
+fn range_returns_pairs_in_key_order_with_either_index() -> io::Result<()> {
+    for ordered in [false, true] {
+        let dir = tempfile::tempdir()?;
+        let engine = CrabKv::builder(dir.path()).ordered_index(ordered).build()?;
+        // More keys than one chunk, written out of order.
+        for i in (0..1_000).rev() {
+            engine.put(format!("k{i:04}"), format!("v{i}"))?;
+        }
+        engine.delete("k0500")?;
+
+        let pairs: Vec<(String, String)> = engine
+            .range("k0100".to_string().."k0700".to_string())
+            .collect::<io::Result<_>>()?;
+        assert_eq!(pairs.len(), 599, "ordered={ordered}");
+        assert_eq!(pairs[0], ("k0100".to_string(), "v100".to_string()));
+        assert_eq!(pairs.last().unwrap().0, "k0699");
+        assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(pairs.iter().all(|(key, _)| key != "k0500"));
+
+        let tail = keys(&engine, (Bound::Excluded("k0997".into()), Bound::Unbounded))?;
+        assert_eq!(tail, ["k0998", "k0999"]);
+        let head = keys(&engine, (Bound::Unbounded, Bound::Included("k0001".into())))?;
+        assert_eq!(head, ["k0000", "k0001"]);
+        assert_eq!(engine.range(..).count(), 999);
+        assert_eq!(engine.range("x".to_string()..).count(), 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn ordered_index_survives_compaction_and_reopen() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let open = || {
+        CrabKv::builder(dir.path())
+            .ordered_index(true)
+            .clock(clock.clock())
+            .build()
+    };
+    let engine = open()?;
+    engine.put("b".into(), "2".into())?;
+    engine.put("a".into(), "1".into())?;
+    engine.put_with_ttl("c".into(), "3".into(), Some(Duration::from_secs(1)))?;
+    engine.put("d".into(), "4".into())?;
+    clock.advance(Duration::from_secs(2));
+
+    let expected = ["a", "b", "d"];
+    assert_eq!(
+        keys(&engine, (Bound::Unbounded, Bound::Unbounded))?,
+        expected
+    );
+    engine.compact()?;
+    assert_eq!(
+        keys(&engine, (Bound::Unbounded, Bound::Unbounded))?,
+        expected
+    );
+    drop(engine);
+
+    let engine = open()?;
+    assert!(engine.config().ordered_index);
+    assert_eq!(
+        keys(&engine, (Bound::Unbounded, Bound::Unbounded))?,
+        expected
+    );
+    assert_eq!(engine.get("b")?.as_deref(), Some("2"));
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use crabkv::CrabKv;
+use crabkv::ManualClock;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn start() -> ManualClock {
+    ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+}
+
+#[test]
+fn records_older_than_the_retention_window_expire() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let open = || {
+        CrabKv::builder(dir.path())
+            .retention(7 * DAY)
+            .clock(clock.clock())
+            .build()
+    };
+
+    let engine = open()?;
+    engine.put("old".into(), "1".into())?;
+    engine.put_with_ttl("short".into(), "2".into(), Some(DAY))?;
+    clock.advance(5 * DAY);
+    engine.put("recent".into(), "3".into())?;
+    engine.put_with_ttl("long".into(), "4".into(), Some(30 * DAY))?;
+
+    // The TTL wins when it runs out first, the retention window otherwise.
+    assert_eq!(engine.get("short")?, None);
+    assert_eq!(engine.get("old")?, Some("1".into()));
+    clock.advance(3 * DAY);
+    assert_eq!(engine.keys_matching("*")?, vec!["long", "recent"]);
+    assert_eq!(engine.get("old")?, None);
+
+    engine.compact()?;
+    drop(engine);
+    let engine = open()?;
+    assert_eq!(engine.open_report().live_keys, 2);
+    assert_eq!(engine.get("recent")?, Some("3".into()));
+
+    clock.advance(5 * DAY);
+    drop(engine);
+    let engine = open()?;
+    assert_eq!(engine.open_report().live_keys, 0);
+    assert_eq!(engine.open_report().expired_skipped, 2);
+    assert_eq!(engine.get("long")?, None);
+    Ok(())
+}
+
+#[test]
+fn purge_older_than_deletes_stale_keys_durably() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = start();
+    let open = || CrabKv::builder(dir.path()).clock(clock.clock()).build();
+
+    let engine = open()?;
+    for i in 0..3 {
+        engine.put(format!("old{i}"), "v".into())?;
+    }
+    clock.advance(DAY);
+    let cutoff: SystemTime = clock.now();
+    engine.put("old0".into(), "rewritten".into())?;
+    engine.put("new".into(), "v".into())?;
+
+    assert_eq!(engine.purge_older_than(cutoff)?, 2);
+    assert_eq!(engine.purge_older_than(cutoff)?, 0);
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.keys_matching("*")?, vec!["new", "old0"]);
+    assert_eq!(engine.get("old0")?, Some("rewritten".into()));
+    Ok(())
+}
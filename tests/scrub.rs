@@ -0,0 +1,166 @@
+use crabkv::{BackgroundWorker, Corrupted, CrabKv, EngineMode, EventKind, Utf8Policy};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+mod common;
+
+use common::wait_for;
+
+/// Overwrites the first occurrence of `value` in the log with bytes that are
+/// not UTF-8, so the record holding it no longer decodes.
+fn corrupt_value(dir: &Path, value: &str) -> io::Result<()> {
+    let wal = dir.join("wal.log");
+    let bytes = fs::read(&wal)?;
+    let at = bytes
+        .windows(value.len())
+        .position(|window| window == value.as_bytes())
+        .expect("value in the log");
+    let mut file = OpenOptions::new().write(true).open(&wal)?;
+    file.seek(SeekFrom::Start(at as u64))?;
+    file.write_all(&vec![0xff; value.len()])?;
+    file.sync_all()
+}
+
+#[test]
+fn manual_scrub_quarantines_a_damaged_record_nothing_read() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    for i in 0..100 {
+        engine.put(format!("key{i:02}"), format!("value-{i:02}"))?;
+    }
+    engine.delete("key07")?;
+    let clean = engine.scrub_now()?;
+    assert!(clean.is_clean());
+    assert_eq!(clean.records_checked, 101);
+    assert_eq!(clean.bytes_checked, engine.stats()?.wal_bytes);
+
+    corrupt_value(dir.path(), "value-42")?;
+    let report = engine.scrub_now()?;
+    assert_eq!(report.records_checked, 101);
+    assert_eq!(report.damaged_records, 1);
+    assert_eq!(report.quarantined_keys, 1);
+    assert_eq!(report.findings.len(), 1);
+    assert_eq!(report.findings[0].keys, ["key42"]);
+    assert!(report.findings[0].error.contains("utf-8"), "{report:?}");
+
+    let quarantined = engine.quarantined()?;
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].key, "key42");
+    assert_eq!(quarantined[0].offset, report.findings[0].offset);
+    let errors = engine.background_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].worker, BackgroundWorker::Scrub);
+    assert_eq!(engine.stats()?.last_scrub, Some(report));
+    assert!(engine.recent_events().iter().any(|event| event.kind
+        == EventKind::ScrubFinished {
+            records: 101,
+            damaged: 1,
+        }));
+
+    let err = engine.get("key42").unwrap_err();
+    assert_eq!(
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<Corrupted>())
+            .map(|corrupted| corrupted.key.as_str()),
+        Some("key42")
+    );
+    assert_eq!(engine.get("key41")?.as_deref(), Some("value-41"));
+
+    // Once compaction drops the damaged record, the log is clean again.
+    engine.compact()?;
+    assert!(engine.scrub_now()?.is_clean());
+    Ok(())
+}
+
+#[test]
+fn background_scrub_runs_once_per_interval() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .scrub_interval(Duration::from_millis(20))
+        .scrub_bytes_per_sec(0)
+        .build()?;
+    for i in 0..100 {
+        engine.put(format!("key{i:02}"), format!("value-{i:02}"))?;
+    }
+    corrupt_value(dir.path(), "value-13")?;
+    wait_for("the background scrub", || {
+        Ok(!engine.quarantined()?.is_empty())
+    })?;
+    assert_eq!(engine.quarantined()?[0].key, "key13");
+    wait_for("a scrub covering the whole log", || {
+        Ok(engine
+            .stats()?
+            .last_scrub
+            .is_some_and(|scrub| scrub.damaged_records == 1))
+    })?;
+    let error = &engine.background_errors()[0];
+    assert_eq!(error.worker, BackgroundWorker::Scrub);
+    assert!(error.error.to_string().contains("damaged"), "{error:?}");
+
+    // Stopped and the progress saved on close.
+    engine.try_close().ok().expect("last handle")?;
+    assert!(dir.path().join("scrub.progress").exists());
+    Ok(())
+}
+
+#[test]
+fn a_pass_cut_short_resumes_after_a_restart() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let padding = "x".repeat(200);
+    for i in 0..2000 {
+        engine.put(format!("key{i:04}"), format!("value-{i:04}-{padding}"))?;
+    }
+    // One in the first step of a pass, one well past it.
+    corrupt_value(dir.path(), "value-0010")?;
+    corrupt_value(dir.path(), "value-1900")?;
+    drop(engine);
+
+    // A byte a second leaves the pass after its first step.
+    let engine = CrabKv::builder(dir.path())
+        .utf8_policy(Utf8Policy::Skip)
+        .scrub_interval(Duration::from_secs(3600))
+        .scrub_bytes_per_sec(1)
+        .build()?;
+    wait_for("the first step", || {
+        Ok(!engine.background_errors().is_empty())
+    })?;
+    assert_eq!(engine.stats()?.last_scrub, None);
+    engine.try_close().ok().expect("last handle")?;
+
+    let engine = CrabKv::builder(dir.path())
+        .utf8_policy(Utf8Policy::Skip)
+        .build()?;
+    let resumed = engine.scrub_now()?;
+    assert_eq!(resumed.damaged_records, 2);
+    assert_eq!(resumed.findings.len(), 1, "{:?}", resumed.findings);
+    assert_eq!(resumed.records_checked, 2000);
+    assert_eq!(resumed.bytes_checked, engine.stats()?.wal_bytes);
+
+    let again = engine.scrub_now()?;
+    assert_eq!(again.findings.len(), 2);
+    assert!(again.findings[0].offset < resumed.findings[0].offset);
+    Ok(())
+}
+
+#[test]
+fn scrubs_in_a_read_only_store_leave_the_directory_alone() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "value".into())?;
+    engine.put("other".into(), "fine".into())?;
+    engine.set_mode(EngineMode::ReadOnly)?;
+    corrupt_value(dir.path(), "value")?;
+    let report = engine.scrub_now()?;
+    assert_eq!(report.quarantined_keys, 1);
+    assert_eq!(engine.quarantined()?.len(), 1);
+    assert!(!dir.path().join("scrub.progress").exists());
+    assert!(!dir.path().join("quarantine.log").exists());
+
+    engine.set_mode(EngineMode::Normal)?;
+    engine.put("unrelated".into(), "write".into())?;
+    assert!(dir.path().join("quarantine.log").exists());
+    Ok(())
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "serde")]
+
+use crabkv::{CrabKv, ManualClock};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Address {
+    city: String,
+    zip: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Account {
+    id: u64,
+    name: String,
+    addresses: Vec<Address>,
+    limits: BTreeMap<String, f64>,
+}
+
+fn account() -> Account {
+    Account {
+        id: 7,
+        name: "ann \"the\" admin".into(),
+        addresses: vec![
+            Address {
+                city: "Lyon".into(),
+                zip: Some("69001".into()),
+            },
+            Address {
+                city: "Oslo".into(),
+                zip: None,
+            },
+        ],
+        limits: BTreeMap::from([("daily".into(), 250.5), ("monthly".into(), 4000.0)]),
+    }
+}
+
+#[test]
+fn nested_structs_round_trip_and_expire() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+
+    engine.put_json("account:7".into(), &account())?;
+    engine.put_json_with_ttl(
+        "session:7".into(),
+        &account(),
+        Some(Duration::from_secs(60)),
+    )?;
+    assert_eq!(engine.get_json::<Account>("account:7")?, Some(account()));
+    assert_eq!(engine.get_json::<Account>("session:7")?, Some(account()));
+    assert_eq!(engine.get_json::<Account>("missing")?, None);
+    // Stored as plain JSON text, readable without the typed layer.
+    let raw = engine.get("account:7")?.expect("stored");
+    assert!(raw.starts_with(r#"{"id":7,"name":"#), "{raw}");
+
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(engine.get_json::<Account>("session:7")?, None);
+    drop(engine);
+
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    assert_eq!(engine.get_json::<Account>("account:7")?, Some(account()));
+    assert_eq!(engine.get_json::<Account>("session:7")?, None);
+    Ok(())
+}
+
+#[test]
+fn values_of_another_shape_are_invalid_data() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("text".into(), "not json".into())?;
+    engine.put_json("address".into(), &account().addresses[0])?;
+
+    let err = engine.get_json::<Account>("text").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("expected ident"), "{err}");
+    let err = engine.get_json::<Account>("address").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("missing field `id`"), "{err}");
+    assert!(
+        err.get_ref()
+            .is_some_and(|inner| inner.is::<serde_json::Error>())
+    );
+
+    // Maps with non-string keys do not serialize to JSON; nothing is stored.
+    let unkeyed = BTreeMap::from([((1, 2), "pair")]);
+    let err = engine.put_json("pairs".into(), &unkeyed).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("key must be a string"), "{err}");
+    assert_eq!(engine.get("pairs")?, None);
+    Ok(())
+}
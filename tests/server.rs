@@ -0,0 +1,1065 @@
+use crabkv::ServerConfig;
+use crabkv::server;
+use crabkv::{BackgroundWorker, CrabKv, EngineMode, OpContext};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::wait_for;
+
+#[derive(Default)]
+struct CountingWriter {
+    bytes: Vec<u8>,
+    flushes: usize,
+}
+
+impl Write for &mut CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+/// Refuses every write with `WouldBlock` until `ready_at`, like a full
+/// non-blocking socket, counting the tries.
+struct FullWriter {
+    ready_at: Instant,
+    bytes: Vec<u8>,
+    tries: usize,
+}
+
+impl Write for &mut FullWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tries += 1;
+        if Instant::now() < self.ready_at {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn a_full_socket_is_waited_on_not_spun_on() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let big = "x".repeat(1 << 17);
+    engine.put("big".into(), big.clone())?;
+
+    let mut output = FullWriter {
+        ready_at: Instant::now() + Duration::from_millis(100),
+        bytes: Vec::new(),
+        tries: 0,
+    };
+    server::serve_connection(Cursor::new("GETRAW big\nGET big\n"), &mut output, &engine)?;
+    assert!(output.tries < 100, "tried {} times", output.tries);
+    let text = String::from_utf8(output.bytes).unwrap();
+    let mut lines = text.lines().skip(1);
+    assert_eq!(lines.next(), Some(format!("VALUE {}", big.len()).as_str()));
+    assert_eq!(lines.next(), Some(big.as_str()));
+    assert_eq!(lines.next(), Some(format!("VALUE {big}").as_str()));
+    Ok(())
+}
+
+#[test]
+fn pipelined_puts_batch_flushes() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_secs(60))
+        .build()?;
+
+    let mut input = String::new();
+    for i in 0..10_000 {
+        input.push_str(&format!("PUT key{i} value{i}\n"));
+    }
+    let mut output = CountingWriter::default();
+    server::serve_connection(Cursor::new(input), &mut output, &engine)?;
+
+    let text = String::from_utf8(output.bytes).unwrap();
+    assert_eq!(
+        text.lines().filter(|line| line.starts_with("OK ")).count(),
+        10_000
+    );
+    assert!(output.flushes < 1_000, "flushed {} times", output.flushes);
+    assert_eq!(engine.get("key9999")?, Some("value9999".into()));
+    Ok(())
+}
+
+#[test]
+fn scankv_returns_matching_pairs() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("cfg:a".into(), "1".into())?;
+    engine.put("cfg:b".into(), "2".into())?;
+    engine.put("user:a".into(), "3".into())?;
+
+    let mut output = CountingWriter::default();
+    server::serve_connection(Cursor::new("SCANKV cfg:*\n"), &mut output, &engine)?;
+
+    let text = String::from_utf8(output.bytes).unwrap();
+    let lines: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(lines, vec!["cfg:a 1", "cfg:b 2", "END"]);
+    Ok(())
+}
+
+fn run_session(engine: &CrabKv, input: &str) -> io::Result<Vec<String>> {
+    let mut output = CountingWriter::default();
+    server::serve_connection(Cursor::new(input.to_string()), &mut output, engine)?;
+    let text = String::from_utf8(output.bytes).unwrap();
+    Ok(text.lines().skip(1).map(str::to_string).collect())
+}
+
+#[test]
+fn exists_answers_one_or_zero() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("present".into(), "v".into())?;
+    let replies = run_session(&engine, "EXISTS present\nEXISTS absent\nEXISTS\n")?;
+    assert_eq!(replies[..2], ["1", "0"]);
+    assert!(replies[2].starts_with("ERR bad command"), "{replies:?}");
+    Ok(())
+}
+
+#[test]
+fn pop_returns_the_value_once() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("job".into(), "payload".into())?;
+    let replies = run_session(&engine, "POP job\nPOP job\nGET job\nPOP\n")?;
+    assert_eq!(replies[..3], ["VALUE payload", "NOT_FOUND", "NOT_FOUND"]);
+    assert!(replies[3].starts_with("ERR bad command"), "{replies:?}");
+    Ok(())
+}
+
+#[test]
+fn a_failed_get_answers_err_and_the_session_goes_on() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put_bytes("bin".into(), vec![0xff, 0xfe])?;
+    engine.put("ok".into(), "v".into())?;
+    let replies = run_session(&engine, "GET bin\nGET ok\n")?;
+    assert_eq!(replies.len(), 2, "{replies:?}");
+    assert!(replies[0].starts_with("ERR "), "{replies:?}");
+    assert!(
+        replies[0].ends_with(']') && replies[0].contains("[id="),
+        "{replies:?}"
+    );
+    assert_eq!(replies[1], "VALUE v");
+    Ok(())
+}
+
+/// Hands out its chunks one per read, timing out between them like a client
+/// whose line arrives in pieces.
+struct Stuttering {
+    chunks: Vec<&'static [u8]>,
+    timed_out: bool,
+}
+
+impl Read for Stuttering {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunks.is_empty() {
+            return Ok(0);
+        }
+        self.timed_out = !self.timed_out;
+        if self.timed_out {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let chunk = self.chunks.remove(0);
+        buf[..chunk.len()].copy_from_slice(chunk);
+        Ok(chunk.len())
+    }
+}
+
+#[test]
+fn a_read_timing_out_inside_a_character_keeps_the_line() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    // `é` is 0xc3 0xa9; the timeouts fall inside it.
+    let input = Stuttering {
+        chunks: vec![b"PUT caf\xc3", b"\xa9 v\nGET caf\xc3", b"\xa9\n"],
+        timed_out: false,
+    };
+    let mut output = CountingWriter::default();
+    server::serve_connection(input, &mut output, &engine)?;
+    let text = String::from_utf8(output.bytes).unwrap();
+    let replies: Vec<&str> = text.lines().skip(1).collect();
+    assert_eq!(replies.len(), 2, "{replies:?}");
+    assert!(replies[0].starts_with("OK "), "{replies:?}");
+    assert_eq!(replies[1], "VALUE v");
+    assert_eq!(engine.get("caf\u{e9}")?.as_deref(), Some("v"));
+    Ok(())
+}
+
+#[test]
+fn every_registered_command_parses_and_dispatches() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+
+    let listing = run_session(&engine, "COMMANDS\n")?;
+    assert_eq!(listing.last().map(String::as_str), Some("END"));
+    let commands: Vec<(String, usize)> = listing[..listing.len() - 1]
+        .iter()
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next().unwrap().to_string();
+            let arity = fields.next().unwrap();
+            let min = arity.split('-').next().unwrap().parse().unwrap();
+            (name, min)
+        })
+        .collect();
+    assert!(commands.iter().any(|(name, _)| name == "PUT"));
+
+    // MIGRATE moves the engine, so give it a directory of its own.
+    let migrated = tempfile::tempdir()?;
+    for (name, min_args) in &commands {
+        let mut line = name.clone();
+        if name == "MIGRATE" {
+            line = format!("MIGRATE {}", migrated.path().join("data").display());
+        } else {
+            for _ in 0..*min_args {
+                line.push_str(" k");
+            }
+        }
+        let response = run_session(&engine, &format!("{line}\n"))?;
+        assert!(!response[0].starts_with("ERR bad command"), "{line}");
+
+        let help = run_session(&engine, &format!("HELP {name}\n"))?;
+        assert!(help[0].starts_with(&format!("Usage: {name}")), "{help:?}");
+    }
+
+    let invalid = run_session(&engine, "GET\nFROB x\n")?;
+    assert_eq!(invalid.len(), 2);
+    assert!(
+        invalid
+            .iter()
+            .all(|reply| reply.starts_with("ERR bad command [id=")),
+        "{invalid:?}"
+    );
+    Ok(())
+}
+
+#[test]
+fn worker_pool_serves_concurrent_clients() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let config = ServerConfig {
+        workers: NonZeroUsize::new(2).unwrap(),
+        ..ServerConfig::default()
+    };
+    let server = server::spawn("127.0.0.1:0", engine, config)?;
+    let addr = server.local_addr();
+
+    let clients: Vec<_> = (0..6)
+        .map(|i| {
+            thread::spawn(move || -> io::Result<Vec<String>> {
+                let mut stream = TcpStream::connect(addr)?;
+                write!(stream, "PUT client{i} v{i}\nGET client{i}\n")?;
+                stream.shutdown(Shutdown::Write)?;
+                BufReader::new(stream).lines().skip(1).collect()
+            })
+        })
+        .collect();
+
+    for (i, client) in clients.into_iter().enumerate() {
+        let lines = client.join().unwrap()?;
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        assert!(lines[0].starts_with("OK "), "{lines:?}");
+        assert_eq!(lines[1], format!("VALUE v{i}"));
+    }
+    server.shutdown()
+}
+
+#[test]
+fn subscribed_connection_receives_expiry_events() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put_with_ttl("short".into(), "v".into(), Some(Duration::from_millis(50)))?;
+    let server = server::spawn("127.0.0.1:0", engine, ServerConfig::default())?;
+    let addr = server.local_addr();
+
+    let mut subscriber = TcpStream::connect(addr)?;
+    subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut events = BufReader::new(subscriber.try_clone()?).lines();
+    events.next().transpose()?;
+    writeln!(subscriber, "SUBSCRIBE expired")?;
+    assert_eq!(events.next().transpose()?.as_deref(), Some("OK"));
+
+    thread::sleep(Duration::from_millis(80));
+    let mut reader = TcpStream::connect(addr)?;
+    writeln!(reader, "GET short")?;
+    reader.shutdown(Shutdown::Write)?;
+    let replies: Vec<String> = BufReader::new(reader)
+        .lines()
+        .skip(1)
+        .collect::<io::Result<_>>()?;
+    assert_eq!(replies, vec!["NOT_FOUND"]);
+
+    assert_eq!(events.next().transpose()?.as_deref(), Some("EXPIRED short"));
+    server.shutdown()
+}
+
+#[test]
+fn subscribers_leave_the_workers_to_other_clients() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let config = ServerConfig {
+        workers: NonZeroUsize::new(1).unwrap(),
+        max_subscribers: 1,
+        ..ServerConfig::default()
+    };
+    let server = server::spawn("127.0.0.1:0", engine, config)?;
+    let addr = server.local_addr();
+
+    let mut subscriber = TcpStream::connect(addr)?;
+    subscriber.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut events = BufReader::new(subscriber.try_clone()?).lines();
+    events.next().transpose()?;
+    writeln!(subscriber, "SUBSCRIBE expired")?;
+    assert_eq!(events.next().transpose()?.as_deref(), Some("OK"));
+
+    // The only worker is free again, and the only place is taken.
+    let mut client = TcpStream::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    writeln!(client, "PUT k v\nSUBSCRIBE expired")?;
+    client.shutdown(Shutdown::Write)?;
+    let replies: Vec<String> = BufReader::new(client)
+        .lines()
+        .skip(1)
+        .collect::<io::Result<_>>()?;
+    assert!(replies[0].starts_with("OK "), "{replies:?}");
+    assert!(
+        replies[1].starts_with("ERR too many subscribers, at most 1"),
+        "{replies:?}"
+    );
+
+    // A subscriber that leaves gives its place back.
+    drop((events, subscriber));
+    wait_for("the subscriber's place", || {
+        let mut client = TcpStream::connect(addr)?;
+        writeln!(client, "SUBSCRIBE expired")?;
+        client.shutdown(Shutdown::Write)?;
+        let reply = BufReader::new(client).lines().nth(1).transpose()?;
+        Ok(reply.as_deref() == Some("OK"))
+    })?;
+    server.shutdown()
+}
+
+#[test]
+fn spawned_server_round_trips_and_shuts_down() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let addr = server.local_addr();
+    assert_ne!(addr.port(), 0);
+
+    let mut client = TcpStream::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut replies = BufReader::new(client.try_clone()?).lines();
+    replies.next().transpose()?;
+    writeln!(client, "PUT greeting hello")?;
+    let reply = replies.next().transpose()?.unwrap_or_default();
+    assert!(reply.starts_with("OK "), "{reply}");
+    writeln!(client, "GET greeting")?;
+    assert_eq!(replies.next().transpose()?.as_deref(), Some("VALUE hello"));
+
+    // Shut down while the client is still connected; every server thread must exit.
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || done_tx.send(server.shutdown()));
+    done_rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server threads did not exit")?;
+
+    assert!(replies.next().transpose()?.is_none());
+    assert!(TcpStream::connect(addr).is_err());
+    assert_eq!(engine.get("greeting")?, Some("hello".into()));
+    Ok(())
+}
+
+/// Connects to `addr`, skips the welcome line and returns the reply to `GET k`.
+fn get_over_tcp(addr: SocketAddr) -> io::Result<String> {
+    let mut client = TcpStream::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut replies = BufReader::new(client.try_clone()?).lines();
+    replies.next().transpose()?;
+    writeln!(client, "GET k")?;
+    Ok(replies.next().transpose()?.unwrap_or_default())
+}
+
+#[test]
+fn restarted_server_rebinds_its_port_at_once() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let addr = server.local_addr();
+    assert_eq!(get_over_tcp(addr)?, "VALUE v");
+    // Shutting down with a client connected closes the server's end first,
+    // which leaves it in TIME_WAIT.
+    let client = TcpStream::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut welcome = String::new();
+    BufReader::new(&client).read_line(&mut welcome)?;
+    server.shutdown()?;
+    drop(client);
+
+    let server = server::spawn(&addr.to_string(), engine, ServerConfig::default())?;
+    assert_eq!(server.local_addr(), addr);
+    assert_eq!(get_over_tcp(addr)?, "VALUE v");
+    server.shutdown()
+}
+
+#[test]
+fn bind_errors_name_the_address_and_a_fix() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let taken = TcpListener::bind("127.0.0.1:0")?;
+    let addr = taken.local_addr()?;
+    let err = match server::spawn(&addr.to_string(), engine.clone(), ServerConfig::default()) {
+        Ok(_) => panic!("bound a port another listener holds"),
+        Err(err) => err,
+    };
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    let message = err.to_string();
+    assert!(message.contains(&addr.to_string()), "{message}");
+    assert!(message.contains("--addr"), "{message}");
+
+    let err = server::bind("127.0.0.1:0,", &ServerConfig::default()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn one_server_accepts_on_every_listed_address() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+
+    let server = server::spawn(
+        "127.0.0.1:0,127.0.0.1:0",
+        engine.clone(),
+        ServerConfig::default(),
+    )?;
+    let addrs = server.local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+    for addr in addrs {
+        assert_eq!(get_over_tcp(addr)?, "VALUE v");
+    }
+    server.shutdown()?;
+
+    // Both families on one port, where the host has IPv6.
+    if TcpListener::bind("[::1]:0").is_err() {
+        return Ok(());
+    }
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let server = server::spawn(
+        &format!("0.0.0.0:{port},[::]:{port}"),
+        engine,
+        ServerConfig::default(),
+    )?;
+    assert_eq!(
+        get_over_tcp(SocketAddr::from(([127, 0, 0, 1], port)))?,
+        "VALUE v"
+    );
+    assert_eq!(
+        get_over_tcp(SocketAddr::from((Ipv6Addr::LOCALHOST, port)))?,
+        "VALUE v"
+    );
+    server.shutdown()
+}
+
+#[test]
+fn writes_slow_down_then_are_refused_under_pressure_while_reads_continue() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(16).unwrap())
+        .write_back_cache(true)
+        .build()?;
+    let mut config = ServerConfig::default();
+    config.pressure.elevated.write_buffer = 0;
+    config.pressure.critical.write_buffer = 2;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), config)?;
+
+    let mut client = TcpStream::connect(server.local_addr())?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut replies = BufReader::new(client.try_clone()?).lines();
+    replies.next().transpose()?;
+    let mut send = |line: &str, expected: usize| -> io::Result<Vec<String>> {
+        writeln!(client, "{line}")?;
+        (0..expected)
+            .map(|_| replies.next().transpose().map(Option::unwrap_or_default))
+            .collect()
+    };
+
+    // Nothing buffered yet: no pressure.
+    let ok = send("PUT a 1", 1)?;
+    assert!(ok[0].starts_with("OK "), "{ok:?}");
+    // One or two buffered writes: elevated, so a note precedes the reply.
+    for key in ["b", "c"] {
+        let elevated = send(&format!("PUT {key} 1"), 2)?;
+        assert_eq!(elevated[0], "WARN pressure=elevated");
+        assert!(elevated[1].starts_with("OK "), "{elevated:?}");
+    }
+    assert_eq!(engine.pressure_gauges().write_buffer, 3);
+
+    // Three buffered writes: critical, so writes are refused and reads are not.
+    for write in ["PUT d 1", "DELETE a"] {
+        let busy = send(write, 1)?;
+        assert!(
+            busy[0].starts_with("ERR BUSY retry_after=100 [id="),
+            "{busy:?}"
+        );
+    }
+    assert_eq!(send("GET a", 1)?, vec!["VALUE 1"]);
+    assert_eq!(engine.get("d")?, None);
+
+    // Flushing drains the buffer and writes go through again.
+    engine.flush()?;
+    let ok = send("PUT d 1", 1)?;
+    assert!(ok[0].starts_with("OK "), "{ok:?}");
+    assert_eq!(engine.pressure(), crabkv::PressureLevel::Ok);
+    server.shutdown()
+}
+
+#[test]
+fn getrange_counts_characters_not_bytes() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("greeting".into(), "héllo wörld ✓".into())?;
+
+    assert_eq!(engine.get_range("greeting", 1, 4)?.as_deref(), Some("éllo"));
+    assert_eq!(engine.get_range("greeting", 12, 5)?.as_deref(), Some("✓"));
+    assert_eq!(engine.get_range("greeting", 13, 1)?.as_deref(), Some(""));
+    assert_eq!(engine.get_range("missing", 0, 1)?, None);
+
+    let err = engine.get_range("greeting", 14, 1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let replies = run_session(
+        &engine,
+        "GETRANGE greeting 7 4\nGETRANGE greeting 20 1\nGETRANGE greeting -1 1\nGETRANGE missing 0 1\n",
+    )?;
+    assert_eq!(replies[0], "VALUE örld");
+    assert!(replies[1].starts_with("ERR OUT_OF_RANGE"), "{replies:?}");
+    assert!(replies[2].starts_with("ERR start"), "{replies:?}");
+    assert_eq!(replies[3], "NOT_FOUND");
+    Ok(())
+}
+
+#[test]
+fn incr_adds_and_answers_with_the_new_value() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("name".into(), "crab".into())?;
+
+    let replies = run_session(
+        &engine,
+        "INCR visits\nINCR visits 10\nINCR visits -3\nINCR visits x\nINCR name\nLASTWRITE\n",
+    )?;
+    assert_eq!(replies[..3], ["VALUE 1", "VALUE 11", "VALUE 8"]);
+    assert!(replies[3].starts_with("ERR delta"), "{replies:?}");
+    assert!(
+        replies[4].starts_with("ERR the value of `name`"),
+        "{replies:?}"
+    );
+    assert!(replies[5].starts_with("TOKEN "), "{replies:?}");
+    assert_eq!(engine.get("visits")?.as_deref(), Some("8"));
+    Ok(())
+}
+
+#[test]
+fn getraw_frames_values_by_length() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let big = "x".repeat(1 << 20);
+    engine.put("empty".into(), String::new())?;
+    engine.put("small".into(), "two\nlines".into())?;
+    engine.put("big".into(), big.clone())?;
+
+    let mut sink = Vec::new();
+    assert_eq!(engine.get_into("small", &mut sink)?, Some(9));
+    assert_eq!(engine.get_into("missing", &mut sink)?, None);
+    assert_eq!(sink, b"two\nlines");
+
+    let mut output = CountingWriter::default();
+    let input = "GETRAW empty\nGETRAW small\nGETRAW big\nGETRAW missing\nGET small\n";
+    server::serve_connection(Cursor::new(input), &mut output, &engine)?;
+    let mut reader = BufReader::new(Cursor::new(output.bytes));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    assert!(line.starts_with("Welcome"), "{line}");
+
+    let mut read_framed = || -> io::Result<Option<Vec<u8>>> {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let Some(len) = header.trim_end().strip_prefix("VALUE ") else {
+            assert_eq!(header, "NOT_FOUND\n");
+            return Ok(None);
+        };
+        let mut value = vec![0; len.parse().unwrap()];
+        reader.read_exact(&mut value)?;
+        let mut newline = [0];
+        reader.read_exact(&mut newline)?;
+        assert_eq!(&newline, b"\n");
+        Ok(Some(value))
+    };
+    assert_eq!(read_framed()?, Some(Vec::new()));
+    assert_eq!(read_framed()?, Some(b"two\nlines".to_vec()));
+    assert_eq!(read_framed()?, Some(big.into_bytes()));
+    assert_eq!(read_framed()?, None);
+
+    // Plain GET keeps its line-based reply.
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest)?;
+    assert_eq!(rest, "VALUE two\nlines\n");
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn getjson_follows_pointers_into_nested_arrays() -> io::Result<()> {
+    use crabkv::TransformError;
+
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let doc =
+        r#"{"a/b":{"~k":1},"users":[{"name":"ann","tags":["a","b"]},{"name":"bo","tags":[]}]}"#;
+    engine.put("doc".into(), doc.into())?;
+    engine.put("text".into(), "not json".into())?;
+
+    let path = |pointer: &str| engine.get_json_path("doc", pointer);
+    assert_eq!(path("/users/0/tags/1")?.as_deref(), Some(r#""b""#));
+    assert_eq!(
+        path("/users/1")?.as_deref(),
+        Some(r#"{"name":"bo","tags":[]}"#)
+    );
+    assert_eq!(path("/a~1b/~0k")?.as_deref(), Some("1"));
+    assert_eq!(path("")?.as_deref(), Some(doc));
+
+    let transform_error = |err: io::Error| {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<TransformError>())
+            .cloned()
+    };
+    assert!(matches!(
+        transform_error(path("/users/2").unwrap_err()),
+        Some(TransformError::NoMatch(_))
+    ));
+    assert!(matches!(
+        transform_error(path("users").unwrap_err()),
+        Some(TransformError::BadPointer(_))
+    ));
+    assert!(matches!(
+        transform_error(engine.get_json_path("text", "/x").unwrap_err()),
+        Some(TransformError::InvalidJson(_))
+    ));
+
+    let replies = run_session(
+        &engine,
+        "GETJSON doc /users/0/tags\nGETJSON doc /users/9\nGETJSON text /x\nGETJSON missing /x\n",
+    )?;
+    assert_eq!(replies[0], r#"VALUE ["a","b"]"#);
+    assert!(replies[1].starts_with("ERR NO_MATCH"), "{replies:?}");
+    assert!(replies[2].starts_with("ERR INVALID_JSON"), "{replies:?}");
+    assert_eq!(replies[3], "NOT_FOUND");
+    Ok(())
+}
+
+#[test]
+fn waitdurable_returns_once_the_write_survives_a_reopen() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(64).unwrap())
+        .write_back_cache(true)
+        .sync_interval(Duration::from_secs(60))
+        .build()?;
+    // Opening a copy of the log simulates a crash that loses everything not yet written out.
+    let recovered = || -> io::Result<Option<String>> {
+        let copy = tempfile::tempdir()?;
+        std::fs::copy(dir.path().join("wal.log"), copy.path().join("wal.log"))?;
+        CrabKv::open(copy.path())?.get("buffered")
+    };
+
+    let replies = run_session(&engine, "LASTWRITE\nPUT buffered v1\nLASTWRITE\n")?;
+    assert_eq!(replies[0], "NOT_FOUND");
+    let token = replies[1].strip_prefix("OK ").expect("PUT returns a token");
+    assert_eq!(replies[2], format!("TOKEN {token}"));
+    assert_eq!(recovered()?, None);
+
+    let replies = run_session(&engine, &format!("WAITDURABLE {token} 1000\n"))?;
+    assert_eq!(replies, vec!["OK"]);
+    assert_eq!(recovered()?, Some("v1".into()));
+
+    // Deletes bypass the write-back buffer but still wait for the next sync.
+    let replies = run_session(&engine, "DELETE buffered\n")?;
+    let token = replies[0]
+        .strip_prefix("OK ")
+        .expect("DELETE returns a token");
+    assert_eq!(recovered()?, Some("v1".into()));
+    let replies = run_session(
+        &engine,
+        &format!("WAITDURABLE {token}\nWAITDURABLE bogus\n"),
+    )?;
+    assert_eq!(replies[0], "OK");
+    assert!(replies[1].starts_with("ERR malformed"), "{replies:?}");
+    assert_eq!(recovered()?, None);
+    Ok(())
+}
+
+#[test]
+fn health_reports_a_stopped_compaction_worker() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).async_compaction(true).build()?;
+    assert_eq!(run_session(&engine, "HEALTH\n")?, vec!["HEALTH OK"]);
+    let stats = run_session(&engine, "STATS\n")?;
+    assert!(stats[0].contains("compaction_worker=true"), "{stats:?}");
+
+    engine.stop_compaction_worker();
+    assert_eq!(
+        run_session(&engine, "HEALTH\n")?,
+        vec!["HEALTH DEGRADED compaction worker stopped"]
+    );
+    let stats = run_session(&engine, "STATS\n")?;
+    assert!(stats[0].contains("compaction_worker=false"), "{stats:?}");
+    Ok(())
+}
+
+#[test]
+fn health_reports_a_recent_background_error() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .sync_interval(Duration::from_millis(5))
+        .build()?;
+    engine.crash_background_worker(BackgroundWorker::WalFlusher);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while engine.background_errors().is_empty() {
+        assert!(Instant::now() < deadline, "the crash was not reported");
+        thread::sleep(Duration::from_millis(5));
+    }
+    let health = run_session(&engine, "HEALTH\n")?;
+    assert!(
+        health[0].starts_with("HEALTH DEGRADED wal-flusher worker failed: panicked:"),
+        "{health:?}"
+    );
+
+    engine.clear_background_errors();
+    assert_eq!(run_session(&engine, "HEALTH\n")?, vec!["HEALTH OK"]);
+    Ok(())
+}
+
+#[test]
+fn health_and_stats_report_the_mode() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let stats = run_session(&engine, "STATS\n")?;
+    assert!(stats[0].starts_with("STATS mode=normal "), "{stats:?}");
+
+    engine.set_mode(EngineMode::ReadOnly)?;
+    let replies = run_session(&engine, "HEALTH\nSTATS\nPUT k v\nGET k\n")?;
+    assert_eq!(replies[0], "HEALTH OK mode=read-only");
+    assert!(
+        replies[1].starts_with("STATS mode=read-only "),
+        "{replies:?}"
+    );
+    assert!(
+        replies[2].starts_with("ERR write refused, the store is read-only"),
+        "{replies:?}"
+    );
+    assert_eq!(replies[3], "NOT_FOUND");
+
+    engine.set_mode(EngineMode::Normal)?;
+    assert_eq!(run_session(&engine, "HEALTH\n")?, vec!["HEALTH OK"]);
+    Ok(())
+}
+
+#[test]
+fn stats_reports_size_histograms() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).histogram_limit(1).build()?;
+    engine.put("k".into(), "v".into())?;
+    let replies = run_session(&engine, "STATS\n")?;
+    assert!(
+        replies[0].contains("keys=1 key_sizes=[0,1,0,"),
+        "{replies:?}"
+    );
+
+    engine.put("k2".into(), "v".into())?;
+    let replies = run_session(&engine, "STATS\nSTATS force=true\nSTATS force=maybe\n")?;
+    assert!(
+        replies[0].contains("keys=2 key_sizes=skipped value_sizes=skipped wal_bytes="),
+        "{replies:?}"
+    );
+    assert!(
+        replies[0].contains(
+            " stale_bytes=0 cache_capacity=- compaction_pending=false checkpoint_age_ms=- dedup_saved_bytes=0 quarantined=0 "
+        ),
+        "{replies:?}"
+    );
+    assert!(replies[1].contains("value_sizes=[0,2,0,"), "{replies:?}");
+    assert!(replies[2].starts_with("ERR"), "{replies:?}");
+    Ok(())
+}
+
+#[test]
+fn version_reply_matches_the_build_info() -> io::Result<()> {
+    let info = crabkv::build_info();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(!info.git_hash.is_empty());
+    assert_eq!(info.build_date.len(), "2024-05-01".len());
+    assert!(!info.feature_list().is_empty());
+
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let mut output = CountingWriter::default();
+    server::serve_connection(Cursor::new("VERSION\n"), &mut output, &engine)?;
+    let text = String::from_utf8(output.bytes).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines[0].contains(&info.to_string()), "{lines:?}");
+
+    let fields: Vec<(&str, &str)> = lines[1]
+        .strip_prefix("VERSION ")
+        .expect("VERSION reply")
+        .split(' ')
+        .map(|field| field.split_once('=').expect("key=value field"))
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            ("version", info.version),
+            ("git", info.git_hash),
+            ("build_date", info.build_date),
+            ("features", info.feature_list().as_str()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn migrate_moves_the_store_between_commands() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = tempfile::tempdir()?;
+    let new_dir = target.path().join("moved");
+    let engine = CrabKv::open(dir.path())?;
+    let commands = format!(
+        "PUT before 1\nMIGRATE {0}\nPUT after 2\nGET before\nMIGRATE {0}\n",
+        new_dir.display()
+    );
+    let replies = run_session(&engine, &commands)?;
+    assert_eq!(replies[1], "OK");
+    assert_eq!(replies[3], "VALUE 1");
+    // The target now holds a log, so a second move there is refused.
+    assert!(replies[4].starts_with("ERR "), "{replies:?}");
+    assert_eq!(engine.directory(), new_dir);
+
+    drop(engine);
+    let reopened = CrabKv::open(&new_dir)?;
+    assert_eq!(reopened.get("after")?, Some("2".into()));
+    Ok(())
+}
+
+/// Returns the id in a reply ending in `[id=<id>]`.
+fn reply_id(reply: &str) -> Option<&str> {
+    reply
+        .strip_suffix(']')?
+        .rsplit_once(" [id=")
+        .map(|(_, id)| id)
+}
+
+#[test]
+fn each_command_runs_under_its_own_request_id() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = CrabKv::builder(dir.path())
+        .prefix_quota("small:", 4)
+        .soft_key_count(1)
+        .on_threshold(move |_| sink.lock().unwrap().push(OpContext::current_id()))
+        .build()?;
+
+    let replies = run_session(&engine, "PUT a 1\nPUT b 2\nPUT small:k too-large\nFROB\n")?;
+    assert!(replies[0].starts_with("OK ") && replies[1].starts_with("OK "));
+    let quota_id = reply_id(&replies[2]).expect("quota error carries an id");
+    let invalid_id = reply_id(&replies[3]).expect("bad command carries an id");
+    for id in [quota_id, invalid_id] {
+        assert_eq!(id.len(), 6, "{replies:?}");
+        assert!(id.bytes().all(|byte| byte.is_ascii_hexdigit()));
+    }
+    assert_ne!(quota_id, invalid_id);
+
+    // The threshold event fired inside the second PUT, under that command's id.
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    let event_id = seen[0].as_deref().expect("event ran inside a command");
+    assert_eq!(event_id.len(), 6);
+    assert!(event_id != quota_id && event_id != invalid_id);
+    Ok(())
+}
+
+#[test]
+fn health_reports_a_failing_directory_check() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).build()?;
+    engine.put("k".into(), "v".into())?;
+    assert_eq!(run_session(&engine, "HEALTH\n")?, vec!["HEALTH OK"]);
+
+    fs::rename(dir.path().join("wal.log"), dir.path().join("wal.backup"))?;
+    let replies = run_session(&engine, "HEALTH\n")?;
+    assert!(
+        replies[0].starts_with("HEALTH DEGRADED leftovers: wal.log is missing"),
+        "{replies:?}"
+    );
+    Ok(())
+}
+
+#[test]
+fn put_ttl_accepts_unit_suffixes() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+
+    let replies = run_session(&engine, "PUT a 1 ttl=10m\nPUT b 2 ttl=10min\nPUT c 3 10\n")?;
+    assert!(replies[0].starts_with("OK "), "{replies:?}");
+    assert!(replies[1].contains("unknown unit `min`"), "{replies:?}");
+    assert!(
+        replies[2].contains("expected ttl=<duration>"),
+        "{replies:?}"
+    );
+    assert_eq!(engine.get("a")?, Some("1".into()));
+    assert_eq!(engine.get("b")?, None);
+    Ok(())
+}
+
+#[test]
+fn draining_server_finishes_open_sessions_then_closes_them() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let server = server::spawn("127.0.0.1:0", engine.clone(), ServerConfig::default())?;
+    let addr = server.local_addr();
+
+    let mut client = TcpStream::connect(addr)?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut replies = BufReader::new(client.try_clone()?).lines();
+    replies.next().transpose()?;
+
+    let drain_timeout = Duration::from_millis(500);
+    let started = Instant::now();
+    let drained = thread::spawn(move || server.drain(drain_timeout));
+    assert_eq!(replies.next().transpose()?.as_deref(), Some("GOAWAY"));
+    while TcpStream::connect(addr).is_ok() {
+        assert!(started.elapsed() < drain_timeout, "listener still open");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // The open connection still gets one more command answered.
+    writeln!(client, "PUT late value")?;
+    let reply = replies.next().transpose()?.unwrap_or_default();
+    assert!(reply.starts_with("OK "), "{reply}");
+
+    // Then it is closed once the drain timeout runs out.
+    assert!(replies.next().transpose()?.is_none());
+    drained.join().unwrap()?;
+    assert!(started.elapsed() < drain_timeout + Duration::from_secs(2));
+    assert_eq!(engine.get("late")?, Some("value".into()));
+    Ok(())
+}
+
+#[test]
+fn a_reply_larger_than_the_output_limit_goes_out_alone() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let big = "x".repeat(4096);
+    engine.put("big".into(), big.clone())?;
+    let config = ServerConfig {
+        max_output_bytes: 1024,
+        ..ServerConfig::default()
+    };
+    let server = server::spawn("127.0.0.1:0", engine, config)?;
+
+    let mut client = TcpStream::connect(server.local_addr())?;
+    client.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut replies = BufReader::new(client.try_clone()?).lines();
+    replies.next().transpose()?;
+    for _ in 0..2 {
+        writeln!(client, "GET big")?;
+        assert_eq!(replies.next().transpose()?, Some(format!("VALUE {big}")));
+    }
+    writeln!(client, "GET missing")?;
+    assert_eq!(replies.next().transpose()?.as_deref(), Some("NOT_FOUND"));
+    server.shutdown()
+}
+
+#[test]
+fn unread_subscriber_is_dropped_at_the_output_limit() -> io::Result<()> {
+    const KEYS: usize = 100_000;
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    let batch = (0..KEYS)
+        .map(|i| {
+            let key = format!("{i:0>100}");
+            (key, "v".to_string(), Some(Duration::from_millis(1)))
+        })
+        .collect();
+    engine.put_batch(batch)?;
+    let config = ServerConfig {
+        max_output_bytes: 64 * 1024,
+        ..ServerConfig::default()
+    };
+    let server = server::spawn("127.0.0.1:0", engine.clone(), config)?;
+    let addr = server.local_addr();
+
+    let mut subscriber = TcpStream::connect(addr)?;
+    subscriber.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut events = BufReader::new(subscriber.try_clone()?);
+    let mut line = String::new();
+    events.read_line(&mut line)?;
+    writeln!(subscriber, "SUBSCRIBE expired")?;
+    line.clear();
+    events.read_line(&mut line)?;
+    assert_eq!(line, "OK\n");
+
+    // Compaction reports every key as expired while the subscriber reads nothing.
+    thread::sleep(Duration::from_millis(10));
+    engine.compact_and_wait()?;
+    thread::sleep(Duration::from_millis(200));
+
+    let mut other = TcpStream::connect(addr)?;
+    write!(other, "PUT k v\nGET k\nSTATS\n")?;
+    other.shutdown(Shutdown::Write)?;
+    let replies: Vec<String> = BufReader::new(other)
+        .lines()
+        .skip(1)
+        .collect::<io::Result<_>>()?;
+    assert!(replies[0].starts_with("OK "), "{replies:?}");
+    assert_eq!(replies[1], "VALUE v");
+    // Only this connection's own pipelined replies are still queued.
+    let queued: usize = replies[2]
+        .split_once(" output_bytes=")
+        .and_then(|(_, rest)| rest.split(' ').next())
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap();
+    assert!(queued < 1024, "{}", replies[2]);
+
+    // The subscriber gets what the socket already held, then end of stream.
+    let mut rest = String::new();
+    events.read_to_string(&mut rest)?;
+    let received = rest
+        .lines()
+        .filter(|line| line.starts_with("EXPIRED "))
+        .count();
+    assert!(received < KEYS, "received all {received} events");
+    server.shutdown()
+}
@@ -0,0 +1,202 @@
+use crabkv::ManualClock;
+use crabkv::{CompactionPolicy, CrabKv};
+use crabkv::{SizeHistogram, TtlHistogram};
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn histograms_bucket_live_key_and_value_lengths() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+
+    engine.put("a".into(), String::new())?;
+    engine.put("bb".into(), "x".repeat(3))?;
+    engine.put("cccc".into(), "x".repeat(1000))?;
+    engine.put("dddd".into(), "x".repeat(1024))?;
+    engine.put_with_ttl("gone".into(), "x".into(), Some(Duration::from_secs(1)))?;
+    clock.advance(Duration::from_secs(2));
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 4);
+    let keys = stats.key_sizes.expect("key histogram");
+    assert_eq!(keys.counts[SizeHistogram::bucket_of(1)], 1);
+    assert_eq!(keys.counts[SizeHistogram::bucket_of(2)], 1);
+    assert_eq!(keys.counts[SizeHistogram::bucket_of(4)], 2);
+    assert_eq!(keys.total(), 4);
+
+    let values = stats.value_sizes.expect("value histogram");
+    assert_eq!(values.counts[0], 1);
+    assert_eq!(values.counts[2], 1);
+    assert_eq!(values.counts[10], 1);
+    assert_eq!(values.counts[11], 1);
+    assert_eq!(SizeHistogram::lower_bound(11), 1024);
+    assert_eq!(values.total(), 4);
+    Ok(())
+}
+
+#[test]
+fn histograms_are_skipped_above_the_limit_unless_forced() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).histogram_limit(2).build()?;
+    for key in ["a", "b", "c"] {
+        engine.put(key.into(), "v".into())?;
+    }
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 3);
+    assert_eq!(stats.key_sizes, None);
+    assert_eq!(stats.value_sizes, None);
+
+    let forced = engine.stats_with_histograms()?;
+    assert_eq!(forced.keys, 3);
+    assert_eq!(forced.key_sizes.map(|sizes| sizes.counts[1]), Some(3));
+    assert_eq!(forced.value_sizes.map(|sizes| sizes.total()), Some(3));
+    Ok(())
+}
+
+#[test]
+fn ttl_report_buckets_remaining_time_and_counts_expiry() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let engine = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    let minute = Duration::from_secs(60);
+    let hour = 60 * minute;
+    let day = 24 * hour;
+    let ttls = [
+        (3, Some(Duration::from_secs(30))),
+        (2, Some(30 * minute)),
+        (1, Some(12 * hour)),
+        (4, Some(3 * day)),
+        (1, Some(30 * day)),
+        (5, None),
+    ];
+    for (group, (count, ttl)) in ttls.into_iter().enumerate() {
+        for i in 0..count {
+            engine.put_with_ttl(format!("g{group}-{i}"), "v".into(), ttl)?;
+        }
+    }
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys_with_ttl, 11);
+    assert_eq!(stats.keys_without_ttl, 5);
+    let ttl = stats.ttl_remaining.expect("ttl histogram");
+    assert_eq!(ttl.counts, [3, 2, 1, 4, 1]);
+    assert_eq!(ttl.total(), 11);
+    assert_eq!(TtlHistogram::bucket_of(Duration::from_secs(59)), 0);
+    assert_eq!(TtlHistogram::bucket_of(minute), 1);
+
+    // Half an hour later the 30-second keys are expired but still indexed,
+    // and the 30-minute keys are down to their last minute.
+    clock.advance(29 * minute + Duration::from_secs(30));
+    let stats = engine.stats()?;
+    assert_eq!(stats.expired_unreclaimed, 3);
+    assert_eq!(
+        stats.ttl_remaining.expect("ttl histogram").counts,
+        [2, 0, 1, 4, 1]
+    );
+
+    // Extending a live key's TTL counts; shortening one or rewriting an
+    // expired one does not.
+    engine.put_with_ttl("g1-0".into(), "v".into(), Some(2 * hour))?;
+    engine.put_with_ttl("g2-0".into(), "v".into(), Some(minute))?;
+    engine.put("g3-0".into(), "v".into())?;
+    engine.put_with_ttl("g0-0".into(), "v".into(), Some(hour))?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.ttl_extensions, 2);
+    assert_eq!(stats.expired_unreclaimed, 2);
+
+    // A read queues an expired key and the next write removes it; compaction
+    // removes the rest.
+    assert_eq!(engine.get("g0-1")?, None);
+    engine.put("other".into(), "v".into())?;
+    assert_eq!(engine.stats()?.expiry.expired, 1);
+    engine.compact()?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.expired_unreclaimed, 0);
+    assert_eq!(stats.expiry.expired, 2);
+    Ok(())
+}
+
+#[test]
+fn ttl_histogram_follows_the_size_histogram_limit() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).histogram_limit(1).build()?;
+    engine.put_with_ttl("a".into(), "v".into(), Some(Duration::from_secs(10)))?;
+    engine.put("b".into(), "v".into())?;
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.ttl_remaining, None);
+    assert_eq!((stats.keys_with_ttl, stats.keys_without_ttl), (1, 1));
+    let forced = engine.stats_with_histograms()?;
+    assert_eq!(
+        forced.ttl_remaining.map(|ttl| ttl.counts),
+        Some([1, 0, 0, 0, 0])
+    );
+    Ok(())
+}
+
+#[test]
+fn log_counters_track_fsyncs_and_file_opens() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).build()?;
+    let opened = engine.stats()?;
+    assert!(opened.log_opens >= 1);
+
+    for i in 0..5 {
+        engine.put(format!("k{i}"), "v".into())?;
+    }
+    engine.get("k0")?;
+    let written = engine.stats()?;
+    // Without a sync interval every put syncs on its own.
+    assert_eq!(written.log_syncs - opened.log_syncs, 5);
+    assert_eq!(written.log_opens, opened.log_opens);
+
+    engine.compact()?;
+    let compacted = engine.stats()?;
+    assert_eq!(compacted.log_opens - written.log_opens, 1);
+    assert!(compacted.log_syncs > written.log_syncs);
+    Ok(())
+}
+
+#[test]
+fn stats_report_log_bytes_cache_capacity_and_pending_compaction() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(64).unwrap())
+        .compaction_policy(CompactionPolicy::every_n_mutations(4))
+        .min_compaction_interval(Duration::from_secs(3600))
+        .build()?;
+    engine.put("key".into(), "first".into())?;
+    engine.put("key".into(), "second".into())?;
+    engine.put("other".into(), "v".into())?;
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.cache_capacity, Some(64));
+    assert_eq!(
+        stats.wal_bytes,
+        fs::metadata(dir.path().join("wal.log"))?.len()
+    );
+    assert!(stats.stale_bytes > 0);
+    assert_eq!(stats.live_bytes + stats.stale_bytes, stats.wal_bytes);
+    assert!(!stats.compaction_pending);
+
+    // The fourth write compacts; the next one due waits out the interval.
+    engine.delete("other")?;
+    assert_eq!(engine.compaction_count()?, 1);
+    let compacted = engine.stats()?;
+    assert_eq!(compacted.stale_bytes, 0);
+    assert_eq!(compacted.live_bytes, compacted.wal_bytes);
+    assert!(!compacted.compaction_pending);
+    for i in 0..4 {
+        engine.put("key".into(), i.to_string())?;
+    }
+    assert_eq!(engine.compaction_count()?, 1);
+    assert!(engine.stats()?.compaction_pending);
+
+    let uncached = CrabKv::open(tempfile::tempdir()?.path())?;
+    assert_eq!(uncached.stats()?.cache_capacity, None);
+    Ok(())
+}
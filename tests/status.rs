@@ -0,0 +1,64 @@
+use crabkv::CrabKv;
+use crabkv::status;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+fn fetch(addr: SocketAddr, path: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn status_page_shows_engine_state() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .cache_capacity(NonZeroUsize::new(1).unwrap())
+        .async_compaction(true)
+        .build()?;
+    engine.put("a".into(), "1".into())?;
+    engine.put("b".into(), "2".into())?;
+    // The cache holds one entry, so `b` hits and `a` misses.
+    engine.get("b")?;
+    engine.get("a")?;
+    let handle = status::spawn("127.0.0.1:0", engine.clone())?;
+
+    let page = fetch(handle.local_addr(), "/status")?;
+    assert!(page.starts_with("HTTP/1.1 200 OK"), "{page}");
+    assert!(page.contains("<meta http-equiv=\"refresh\""));
+    assert!(page.contains("Uptime"));
+    assert!(page.contains(&dir.path().display().to_string()));
+    assert!(page.contains("<th align=\"left\">Cache capacity</th><td>1</td>"));
+    assert!(page.contains("<th align=\"left\">Live keys</th><td>2</td>"));
+    assert!(
+        page.contains("<th align=\"left\">Cache hit rate</th><td>50.0%</td>"),
+        "{page}"
+    );
+    assert!(page.contains("healthy, heartbeat"));
+    assert!(page.contains("Recent background errors"));
+
+    let missing = fetch(handle.local_addr(), "/nope")?;
+    assert!(missing.starts_with("HTTP/1.1 404"), "{missing}");
+    handle.shutdown()
+}
+
+#[test]
+fn status_page_renders_while_writes_are_stalled() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("k".into(), "v".into())?;
+    let handle = status::spawn("127.0.0.1:0", engine.clone())?;
+    fetch(handle.local_addr(), "/status")?;
+
+    let stall = engine.stall_writes_for(Duration::from_millis(500));
+    let page = fetch(handle.local_addr(), "/status")?;
+    assert!(page.starts_with("HTTP/1.1 200 OK"), "{page}");
+    assert!(page.contains("engine busy; stale as of"), "{page}");
+    assert!(page.contains("<th align=\"left\">Live keys</th><td>1</td>"));
+    stall.join().unwrap();
+    handle.shutdown()
+}
@@ -0,0 +1,128 @@
+use crabkv::{CrabKv, CrabKvBuilder};
+use crabkv::{OpContext, ThresholdEvent};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Opens an engine recording every threshold event it fires.
+fn open_recording(
+    dir: &Path,
+    configure: impl FnOnce(CrabKvBuilder) -> CrabKvBuilder,
+) -> io::Result<(CrabKv, Arc<Mutex<Vec<ThresholdEvent>>>)> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    let engine = configure(CrabKv::builder(dir))
+        .on_threshold(move |event| sink.lock().unwrap().push(event))
+        .build()?;
+    Ok((engine, events))
+}
+
+fn take(events: &Mutex<Vec<ThresholdEvent>>) -> Vec<ThresholdEvent> {
+    std::mem::take(&mut *events.lock().unwrap())
+}
+
+#[test]
+fn key_count_fires_once_and_rearms_below_the_hysteresis_band() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let (engine, events) = open_recording(dir.path(), |builder| builder.soft_key_count(10))?;
+
+    for i in 0..10 {
+        engine.put(format!("k{i}"), "v".into())?;
+    }
+    assert!(take(&events).is_empty());
+    engine.put("k10".into(), "v".into())?;
+    engine.put("k11".into(), "v".into())?;
+    assert_eq!(take(&events), vec![ThresholdEvent::KeyCountAbove(11)]);
+
+    // Back to 9 keys is within 10% of the limit, so it stays disarmed.
+    for i in 9..12 {
+        engine.delete(&format!("k{i}"))?;
+    }
+    engine.put("k9".into(), "v".into())?;
+    engine.put("k10".into(), "v".into())?;
+    assert!(take(&events).is_empty());
+
+    // Dropping to 8 re-arms it.
+    for i in 8..11 {
+        engine.delete(&format!("k{i}"))?;
+    }
+    for i in 8..11 {
+        engine.put(format!("k{i}"), "v".into())?;
+    }
+    assert_eq!(take(&events), vec![ThresholdEvent::KeyCountAbove(11)]);
+    Ok(())
+}
+
+#[test]
+fn write_buffer_rearms_after_a_flush() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let (engine, events) = open_recording(dir.path(), |builder| {
+        builder
+            .cache_capacity(NonZeroUsize::new(64).unwrap())
+            .write_back_cache(true)
+            .soft_write_buffer(5)
+    })?;
+
+    for round in 0..2 {
+        for i in 0..8 {
+            engine.put(format!("k{round}-{i}"), "v".into())?;
+        }
+        assert_eq!(take(&events), vec![ThresholdEvent::WriteBufferAbove(6)]);
+        engine.flush()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn log_size_and_stale_ratio_fire_once_each() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let (engine, events) = open_recording(dir.path(), |builder| {
+        builder.soft_wal_bytes(4_096).soft_stale_ratio(0.5)
+    })?;
+
+    let value = "v".repeat(100);
+    engine.put("a".into(), value.clone())?;
+    engine.put("b".into(), value.clone())?;
+    assert!(take(&events).is_empty());
+    for _ in 0..60 {
+        engine.put("a".into(), value.clone())?;
+    }
+    let fired = take(&events);
+    assert_eq!(fired.len(), 2, "{fired:?}");
+    assert!(matches!(fired[0], ThresholdEvent::StaleRatioAbove(ratio) if ratio > 0.5));
+    assert!(matches!(fired[1], ThresholdEvent::WalBytesAbove(bytes) if bytes > 4_096));
+
+    // Compaction shrinks both gauges, so the next writes re-arm and refire.
+    engine.compact()?;
+    engine.put("c".into(), value.clone())?;
+    assert!(take(&events).is_empty());
+    for _ in 0..60 {
+        engine.put("a".into(), value.clone())?;
+    }
+    assert_eq!(take(&events).len(), 2);
+    Ok(())
+}
+
+#[test]
+fn events_carry_the_callers_operation_id() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&ids);
+    let engine = CrabKv::builder(dir.path())
+        .soft_key_count(1)
+        .on_threshold(move |_| sink.lock().unwrap().push(OpContext::current_id()))
+        .build()?;
+
+    engine.put("a".into(), "v".into())?;
+    OpContext::new("req-42").scope(|| engine.put("b".into(), "v".into()))?;
+    assert_eq!(OpContext::current_id(), None);
+    // Emptying the store re-arms the limit for a put outside any context.
+    engine.delete("a")?;
+    engine.delete("b")?;
+    engine.put("a".into(), "v".into())?;
+    engine.put("b".into(), "v".into())?;
+
+    assert_eq!(*ids.lock().unwrap(), vec![Some("req-42".to_string()), None]);
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use crabkv::{CrabKv, InvalidUtf8, Utf8Policy};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Overwrites the first byte of `value` in the log with one that is never
+/// valid UTF-8, as a binary client or a damaged disk might leave it.
+fn break_value(dir: &Path, value: &str) -> io::Result<()> {
+    let wal = dir.join("wal.log");
+    let bytes = fs::read(&wal)?;
+    let at = bytes
+        .windows(value.len())
+        .position(|window| window == value.as_bytes())
+        .expect("value in the log");
+    let mut file = OpenOptions::new().write(true).open(&wal)?;
+    file.seek(SeekFrom::Start(at as u64))?;
+    file.write_all(&[0xff])?;
+    file.sync_all()
+}
+
+fn open(dir: &Path, policy: Utf8Policy) -> io::Result<CrabKv> {
+    CrabKv::builder(dir).utf8_policy(policy).build()
+}
+
+/// Writes `good` and `bad`, then breaks the value of `bad` behind the
+/// engine's back.
+fn seed(dir: &Path) -> io::Result<()> {
+    let engine = CrabKv::open(dir)?;
+    engine.put("good".into(), "fine".into())?;
+    engine.put("bad".into(), "broken".into())?;
+    drop(engine);
+    break_value(dir, "broken")
+}
+
+#[test]
+fn the_error_policy_names_the_key_and_offset() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    {
+        let engine = CrabKv::open(dir.path())?;
+        engine.put("good".into(), "fine".into())?;
+        engine.put("bad".into(), "broken".into())?;
+        break_value(dir.path(), "broken")?;
+
+        let err = engine.get("bad").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let invalid = InvalidUtf8::of(&err).expect("typed error");
+        assert_eq!(invalid.key, "bad");
+        assert!(invalid.offset > 0);
+        assert!(err.to_string().contains("`bad`"), "{err}");
+        assert_eq!(engine.get("good")?.as_deref(), Some("fine"));
+        assert!(engine.get_many(&["good", "bad"]).is_err());
+    }
+
+    let err = open(dir.path(), Utf8Policy::Error)
+        .err()
+        .expect("open fails");
+    assert_eq!(InvalidUtf8::of(&err).map(|e| e.key.as_str()), Some("bad"));
+    Ok(())
+}
+
+#[test]
+fn the_lossy_policy_replaces_and_counts() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    seed(dir.path())?;
+
+    let engine = open(dir.path(), Utf8Policy::Lossy)?;
+    assert_eq!(engine.get("bad")?.as_deref(), Some("\u{fffd}roken"));
+    assert_eq!(
+        engine.get_many(&["good", "bad"])?,
+        vec![Some("fine".into()), Some("\u{fffd}roken".into())]
+    );
+    assert_eq!(engine.stats()?.lossy_utf8_reads, 2);
+    let mut arena = Vec::new();
+    let ranges = engine.multi_get_into(&["bad", "good"], &mut arena)?;
+    let values: Vec<&[u8]> = ranges
+        .into_iter()
+        .map(|range| &arena[range.unwrap()])
+        .collect();
+    assert_eq!(values, ["\u{fffd}roken".as_bytes(), b"fine"]);
+
+    // Compaction writes the repaired value back, so it reads cleanly after.
+    engine.compact()?;
+    assert!(engine.quarantined()?.is_empty());
+    drop(engine);
+    let engine = open(dir.path(), Utf8Policy::Error)?;
+    assert_eq!(engine.get("bad")?.as_deref(), Some("\u{fffd}roken"));
+    assert_eq!(engine.stats()?.lossy_utf8_reads, 0);
+    Ok(())
+}
+
+#[test]
+fn the_skip_policy_quarantines_on_open() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    seed(dir.path())?;
+
+    let engine = open(dir.path(), Utf8Policy::Skip)?;
+    assert_eq!(engine.get("bad")?, None);
+    assert_eq!(engine.get("good")?.as_deref(), Some("fine"));
+    assert_eq!(engine.stats()?.keys, 1);
+    let quarantined = engine.quarantined()?;
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].key, "bad");
+    assert!(quarantined[0].error.contains("utf-8"), "{quarantined:?}");
+
+    // A new write supersedes the bad value.
+    engine.put("bad".into(), "mended".into())?;
+    assert_eq!(engine.get("bad")?.as_deref(), Some("mended"));
+    engine.compact()?;
+    drop(engine);
+    let engine = open(dir.path(), Utf8Policy::Error)?;
+    assert_eq!(engine.get("bad")?.as_deref(), Some("mended"));
+    Ok(())
+}
+
+#[test]
+fn the_skip_policy_quarantines_on_first_read() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = open(dir.path(), Utf8Policy::Skip)?;
+    engine.put("good".into(), "fine".into())?;
+    engine.put("bad".into(), "broken".into())?;
+    engine.put("other".into(), "broken too".into())?;
+    break_value(dir.path(), "broken")?;
+    break_value(dir.path(), "broken too")?;
+
+    assert_eq!(engine.get("bad")?, None);
+    assert_eq!(
+        engine.get_many(&["other", "good", "bad"])?,
+        vec![None, Some("fine".into()), None]
+    );
+    let mut keys: Vec<String> = engine.quarantined()?.into_iter().map(|q| q.key).collect();
+    keys.sort();
+    assert_eq!(keys, ["bad", "other"]);
+    assert_eq!(engine.stats()?.keys, 1);
+    Ok(())
+}
+
+#[test]
+fn compaction_under_skip_reclaims_around_the_bad_value() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = open(dir.path(), Utf8Policy::Skip)?;
+    for i in 0..100 {
+        engine.put(format!("key{i:03}"), format!("value-{i:03}"))?;
+        engine.put(format!("key{i:03}"), format!("latest-{i:03}"))?;
+    }
+    break_value(dir.path(), "latest-042")?;
+    let log_len = || fs::metadata(dir.path().join("wal.log")).map(|meta| meta.len());
+    let before = log_len()?;
+
+    engine.compact()?;
+    assert!(log_len()? < before / 2 + 64);
+    assert_eq!(engine.get("key042")?, None);
+    assert_eq!(engine.quarantined()?.len(), 1);
+    assert_eq!(engine.get("key041")?.as_deref(), Some("latest-041"));
+    Ok(())
+}
+
+#[test]
+fn a_deduplicated_value_names_and_skips_the_key_that_was_read() -> io::Result<()> {
+    let shared = "shared-value-".repeat(4);
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path()).value_dedup(true).build()?;
+    engine.put("owner".into(), shared.clone())?;
+    engine.put("copy".into(), shared.clone())?;
+    break_value(dir.path(), &shared)?;
+    for err in [
+        engine.get("copy").unwrap_err(),
+        engine.get_many(&["copy"]).unwrap_err(),
+    ] {
+        assert_eq!(InvalidUtf8::of(&err).map(|e| e.key.as_str()), Some("copy"));
+    }
+
+    // Under skip the key read is quarantined, not the owner over and over.
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::builder(dir.path())
+        .value_dedup(true)
+        .utf8_policy(Utf8Policy::Skip)
+        .build()?;
+    engine.put("owner".into(), shared.clone())?;
+    engine.put("copy".into(), shared.clone())?;
+    engine.put("good".into(), "fine".into())?;
+    break_value(dir.path(), &shared)?;
+    assert_eq!(engine.get("copy")?, None);
+    assert_eq!(
+        engine.get_many(&["good", "owner"])?,
+        vec![Some("fine".into()), None]
+    );
+    let mut keys: Vec<String> = engine.quarantined()?.into_iter().map(|q| q.key).collect();
+    keys.sort();
+    assert_eq!(keys, ["copy", "owner"]);
+    Ok(())
+}
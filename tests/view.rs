@@ -0,0 +1,75 @@
+use crabkv::CrabKv;
+use crabkv::ViewInvalidated;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+fn is_invalidated(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<ViewInvalidated>())
+        .is_some()
+}
+
+#[test]
+fn view_fails_reads_of_keys_written_after_it() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("a".into(), "1".into())?;
+    engine.put("b".into(), "1".into())?;
+
+    let view = engine.read_view()?;
+    engine.put("a".into(), "2".into())?;
+    let err = view.get("a").unwrap_err();
+    assert!(is_invalidated(&err), "{err}");
+    assert_eq!(view.get("b")?, Some("1".into()));
+
+    engine.delete("b")?;
+    assert!(is_invalidated(&view.get("b").unwrap_err()));
+    assert_eq!(engine.read_view()?.get("a")?, Some("2".into()));
+    Ok(())
+}
+
+#[test]
+fn readers_never_see_half_of_a_batch() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put_batch(vec![
+        ("left".into(), "0".into(), None),
+        ("right".into(), "0".into(), None),
+    ])?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let engine = engine.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || -> io::Result<()> {
+            for i in 1..=300 {
+                engine.put_batch(vec![
+                    ("left".into(), i.to_string(), None),
+                    ("right".into(), i.to_string(), None),
+                ])?;
+            }
+            done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+
+    let mut consistent_reads = 0;
+    while !done.load(Ordering::SeqCst) || consistent_reads == 0 {
+        let view = engine.read_view()?;
+        let pair = view
+            .get("left")
+            .and_then(|left| Ok((left, view.get("right")?)));
+        match pair {
+            Ok((left, right)) => {
+                assert_eq!(left, right);
+                consistent_reads += 1;
+            }
+            Err(err) if is_invalidated(&err) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use crabkv::internals::wal::{Wal, WalEntry};
+use crabkv::{CrabKv, RetryableCompaction, WalFile};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn unknown_record() -> Vec<u8> {
+    let mut record = vec![99u8];
+    record.extend_from_slice(&3u32.to_le_bytes());
+    record.extend_from_slice(&2u32.to_le_bytes());
+    record.push(0);
+    record.extend_from_slice(&0u64.to_le_bytes());
+    record.extend_from_slice(b"abcxy");
+    record
+}
+
+#[test]
+fn unknown_ops_are_skipped_when_enabled() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("before".into(), "1".into())?;
+    drop(engine);
+
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("wal.log"))?;
+    wal.write_all(&unknown_record())?;
+    drop(wal);
+
+    let engine = CrabKv::builder(dir.path()).skip_unknown_ops(true).build()?;
+    engine.put("after".into(), "2".into())?;
+    drop(engine);
+
+    assert!(CrabKv::open(dir.path()).is_err());
+
+    let engine = CrabKv::builder(dir.path()).skip_unknown_ops(true).build()?;
+    assert_eq!(engine.open_report().unknown_skipped, 1);
+    assert_eq!(engine.get("before")?, Some("1".into()));
+    assert_eq!(engine.get("after")?, Some("2".into()));
+    Ok(())
+}
+
+fn put(key: &str) -> WalEntry {
+    WalEntry::Put {
+        key: key.into(),
+        value: "value".into(),
+        expires_at: None,
+        written_at: None,
+    }
+}
+
+#[test]
+fn interval_appends_are_synced_by_the_flusher() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let wal = Arc::new(Wal::open(
+        dir.path().join("wal.log"),
+        Some(Duration::from_millis(20)),
+        false,
+    )?);
+    wal.start_flusher()?;
+
+    wal.append(&put("a"))?;
+    let lsn = wal.lsn();
+    assert!(wal.wait_synced(lsn, Duration::from_secs(5)));
+    assert!(wal.durable_lsn() >= lsn);
+    Ok(())
+}
+
+#[test]
+fn durable_watermark_only_covers_synced_appends() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal.log");
+    // Long enough that the flusher never ticks during the test.
+    let wal = Arc::new(Wal::open(&path, Some(Duration::from_secs(3600)), false)?);
+    wal.start_flusher()?;
+
+    wal.append(&put("a"))?;
+    let first = wal.lsn();
+    assert_eq!(wal.durable_lsn(), 0);
+    assert!(!wal.wait_synced(first, Duration::from_millis(20)));
+
+    wal.sync()?;
+    assert_eq!(wal.durable_lsn(), first);
+    wal.append(&put("b"))?;
+    assert!(wal.wait_synced(first, Duration::ZERO));
+    assert!(!wal.wait_synced(wal.lsn(), Duration::ZERO));
+
+    // Dropping the last handle stops the flusher and syncs the pending append.
+    drop(wal);
+    let reopened = Wal::open(&path, None, false)?;
+    let loaded = reopened.load_index(UNIX_EPOCH)?;
+    assert!(loaded.index.contains_key("a"));
+    assert!(loaded.index.contains_key("b"));
+    Ok(())
+}
+
+#[test]
+fn appends_without_an_interval_sync_inline() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let wal = Arc::new(Wal::open(dir.path().join("wal.log"), None, false)?);
+    wal.start_flusher()?;
+    wal.append(&put("a"))?;
+    assert_eq!(wal.durable_lsn(), wal.lsn());
+    Ok(())
+}
+
+#[test]
+fn reads_of_a_replaced_generation_are_retryable() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let wal = Wal::open(dir.path().join("wal.log"), None, false)?;
+    wal.append(&put("a"))?;
+    let pointer = wal.append(&put("b"))?;
+    assert_eq!(wal.generation(), 0);
+    assert_eq!(wal.read_record_in(0, pointer)?.entry, put("b"));
+
+    let loaded = wal.rewrite(&[put("b")])?;
+    assert_eq!(wal.generation(), 1);
+    let err = wal.read_record_in(0, pointer).unwrap_err();
+    assert!(RetryableCompaction::is(&err), "{err}");
+
+    let fresh = loaded.index["b"].pointer;
+    assert_eq!(wal.read_record_in(1, fresh)?.entry, put("b"));
+    Ok(())
+}
+
+#[test]
+fn wal_file_reads_and_verifies_a_closed_store() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    assert_eq!(
+        WalFile::open(dir.path()).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+
+    let engine = CrabKv::builder(dir.path()).blob_threshold(64).build()?;
+    engine.put("b".into(), "small".into())?;
+    engine.put("a".into(), "x".repeat(100))?;
+    engine.put("c".into(), "gone".into())?;
+    engine.delete("c")?;
+    drop(engine);
+
+    let file = WalFile::open(dir.path())?;
+    assert_eq!(file.len(), 2);
+    let entries = file.iter().collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(
+        entries,
+        vec![("a".into(), "x".repeat(100)), ("b".into(), "small".into())]
+    );
+    let verification = file.verify()?;
+    assert!(verification.is_clean());
+    assert_eq!(verification.live_keys, 2);
+    assert!(verification.stale_bytes > 0);
+
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("wal.log"))?;
+    wal.write_all(&[1, 0, 0])?;
+    drop(wal);
+    let verification = WalFile::open(dir.path())?.verify()?;
+    assert_eq!(verification.torn_bytes(), 3);
+    assert!(!verification.is_clean());
+    Ok(())
+}
+
+#[test]
+fn a_damaged_length_mid_log_fails_the_open_instead_of_truncating() -> io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal.log");
+    CrabKv::open(dir.path())?.put("a".into(), "1".into())?;
+    let first_len = std::fs::metadata(&path)?.len();
+    let engine = CrabKv::open(dir.path())?;
+    engine.put("b".into(), "2".into())?;
+    engine.put("c".into(), "3".into())?;
+    drop(engine);
+    let intact = std::fs::read(&path)?;
+
+    // The key length of `b` now reaches far past the end of the file.
+    let mut damaged = intact.clone();
+    let at = first_len as usize + 1;
+    damaged[at..at + 4].copy_from_slice(&1_000_000u32.to_le_bytes());
+    std::fs::write(&path, &damaged)?;
+    let err = CrabKv::open(dir.path()).err().expect("refused");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::read(&path)?, damaged);
+
+    // Cut off inside the last record, it is a torn tail like before.
+    std::fs::write(&path, &intact[..intact.len() - 2])?;
+    let engine = CrabKv::open(dir.path())?;
+    assert!(engine.open_report().torn_bytes > 0);
+    assert_eq!(engine.keys()?, ["a", "b"]);
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use crabkv::CrabKv;
+use crabkv::ManualClock;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 struct TempDir {
     path: PathBuf,
@@ -51,17 +53,17 @@ fn write_back_cache_with_flush() -> io::Result<()> {
     assert_eq!(db.get("key2")?, Some("value2".into()));
     assert_eq!(db.get("key3")?, Some("value3".into()));
 
-    // Drop et réouverture SANS flush - les données ne doivent PAS persister
+    // Drop et réouverture SANS flush - le dernier handle vide le buffer en partant
     drop(db);
     let db = CrabKv::builder(dir.path())
         .cache_capacity(100.try_into().unwrap())
         .write_back_cache(false) // Sans write-back pour lire depuis WAL
         .build()?;
-    
-    // Les données ne doivent pas exister (elles n'ont jamais été écrites dans le WAL)
-    assert_eq!(db.get("key1")?, None);
-    assert_eq!(db.get("key2")?, None);
-    assert_eq!(db.get("key3")?, None);
+
+    // Les données ont été écrites dans le WAL par le drop
+    assert_eq!(db.get("key1")?, Some("value1".into()));
+    assert_eq!(db.get("key2")?, Some("value2".into()));
+    assert_eq!(db.get("key3")?, Some("value3".into()));
 
     drop(db);
 
@@ -101,7 +103,11 @@ fn write_back_cache_with_ttl() -> io::Result<()> {
         .build()?;
 
     // Écrit avec TTL court
-    db.put_with_ttl("temp_key".into(), "temp_value".into(), Some(Duration::from_millis(100)))?;
+    db.put_with_ttl(
+        "temp_key".into(),
+        "temp_value".into(),
+        Some(Duration::from_millis(100)),
+    )?;
 
     // Lisible immédiatement
     assert_eq!(db.get("temp_key")?, Some("temp_value".into()));
@@ -138,3 +144,269 @@ fn write_back_cache_update_overwrites_buffer() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn write_back_flush_is_ordered_and_atomic() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let wal = dir.path().join("wal.log");
+
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .build()?;
+    db.put("base".into(), "0".into())?;
+    db.flush()?;
+    let committed = fs::metadata(&wal)?.len();
+
+    db.put("third".into(), "3".into())?;
+    db.put("first".into(), "1".into())?;
+    db.put("second".into(), "2".into())?;
+    db.flush()?;
+    drop(db);
+
+    let bytes = fs::read(&wal)?;
+    let position = |needle: &[u8]| bytes.windows(needle.len()).position(|w| w == needle);
+    assert!(position(b"third") < position(b"first"));
+    assert!(position(b"first") < position(b"second"));
+
+    // Simulate a crash part-way through writing the batch.
+    let file = fs::OpenOptions::new().write(true).open(&wal)?;
+    file.set_len(bytes.len() as u64 - 4)?;
+    drop(file);
+
+    let db = CrabKv::open(dir.path())?;
+    assert!(db.open_report().torn_bytes > 0);
+    assert_eq!(fs::metadata(&wal)?.len(), committed);
+    assert_eq!(db.get("base")?, Some("0".into()));
+    assert_eq!(db.get("first")?, None);
+    assert_eq!(db.get("second")?, None);
+    assert_eq!(db.get("third")?, None);
+
+    db.put("after".into(), "ok".into())?;
+    drop(db);
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("after")?, Some("ok".into()));
+    Ok(())
+}
+
+#[test]
+fn failed_flush_keeps_buffered_writes() -> io::Result<()> {
+    let dir = TempDir::new()?;
+
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .flush_retries(2, Duration::from_millis(1))
+        .build()?;
+    db.put("retried".into(), "1".into())?;
+    db.fail_next_appends(1)?;
+    db.flush()?;
+    drop(db);
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("retried")?, Some("1".into()));
+    drop(db);
+
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .build()?;
+    db.put("a".into(), "1".into())?;
+    db.put("b".into(), "2".into())?;
+    db.fail_next_appends(1)?;
+    let err = db.flush().unwrap_err();
+    assert!(err.to_string().contains("2 writes still pending"));
+    assert_eq!(db.get("a")?, Some("1".into()));
+    assert_eq!(db.get("b")?, Some("2".into()));
+
+    db.flush()?;
+    drop(db);
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("retried")?, Some("1".into()));
+    assert_eq!(db.get("a")?, Some("1".into()));
+    assert_eq!(db.get("b")?, Some("2".into()));
+    Ok(())
+}
+
+#[test]
+fn flush_backs_off_without_holding_the_store() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .flush_retries(1, Duration::from_secs(1))
+        .build()?;
+    db.put("buffered".into(), "1".into())?;
+    db.fail_next_appends(1)?;
+    let flusher = {
+        let db = db.clone();
+        thread::spawn(move || db.flush())
+    };
+    thread::sleep(Duration::from_millis(100));
+
+    // Deletes take the write lock the flush let go of while backing off.
+    let started = Instant::now();
+    db.delete("other")?;
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert!(!flusher.is_finished());
+    flusher.join().unwrap()?;
+    drop(db);
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("buffered")?, Some("1".into()));
+    Ok(())
+}
+
+#[test]
+fn compaction_expiring_the_durable_value_keeps_a_buffered_write() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .clock(clock.clock())
+        .build()?;
+
+    db.put_with_ttl("k".into(), "old".into(), Some(Duration::from_secs(1)))?;
+    db.flush()?;
+    clock.advance(Duration::from_secs(2));
+    db.put("k".into(), "new".into())?;
+    db.compact()?;
+    assert_eq!(db.get("k")?, Some("new".into()));
+
+    db.flush()?;
+    drop(db);
+    let db = CrabKv::builder(dir.path()).clock(clock.clock()).build()?;
+    assert_eq!(db.get("k")?, Some("new".into()));
+    Ok(())
+}
+
+#[test]
+fn len_and_keys_include_buffered_writes() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .clock(clock.clock())
+        .build()?;
+
+    db.put("durable".into(), "1".into())?;
+    db.put_with_ttl("short".into(), "1".into(), Some(Duration::from_secs(1)))?;
+    db.flush()?;
+    db.put("durable".into(), "2".into())?;
+    db.put("buffered".into(), "1".into())?;
+    assert_eq!(db.len()?, 3);
+    assert_eq!(db.keys()?, ["buffered", "durable", "short"]);
+    assert!(db.contains_key("buffered")?);
+
+    // A buffered write with a TTL decides, not the durable value it replaces.
+    db.put_with_ttl("durable".into(), "3".into(), Some(Duration::from_secs(1)))?;
+    db.put("short".into(), "kept".into())?;
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(db.len()?, 2);
+    assert_eq!(db.keys()?, ["buffered", "short"]);
+    assert!(!db.contains_key("durable")?);
+    assert!(db.contains_key("short")?);
+
+    db.flush()?;
+    assert_eq!(db.len()?, 2);
+    assert_eq!(db.keys()?, ["buffered", "short"]);
+    Ok(())
+}
+
+#[test]
+fn pop_takes_buffered_writes() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .build()?;
+
+    db.put("job".into(), "durable".into())?;
+    db.flush()?;
+    db.put("job".into(), "buffered".into())?;
+    assert_eq!(db.pop("job")?.as_deref(), Some("buffered"));
+    assert_eq!(db.get("job")?, None);
+    db.flush()?;
+    assert_eq!(db.get("job")?, None);
+    drop(db);
+
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("job")?, None);
+    Ok(())
+}
+
+#[test]
+fn get_set_replaces_buffered_writes() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .build()?;
+
+    db.put("job".into(), "durable".into())?;
+    db.flush()?;
+    db.put("job".into(), "buffered".into())?;
+    assert_eq!(
+        db.get_set("job".into(), "swapped".into())?.as_deref(),
+        Some("buffered")
+    );
+    assert_eq!(db.get("job")?.as_deref(), Some("swapped"));
+    db.flush()?;
+    drop(db);
+
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("job")?.as_deref(), Some("swapped"));
+    Ok(())
+}
+
+#[test]
+fn buffered_writes_survive_the_last_handle_dropped_on_any_thread() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .async_compaction(true)
+        .build()?;
+    let writers: Vec<_> = (0..8)
+        .map(|i| {
+            let db = db.clone();
+            std::thread::spawn(move || db.put(format!("k{i}"), format!("v{i}")))
+        })
+        .collect();
+    drop(db);
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let db = CrabKv::open(dir.path())?;
+    for i in 0..8 {
+        assert_eq!(db.get(&format!("k{i}"))?, Some(format!("v{i}")));
+    }
+    Ok(())
+}
+
+#[test]
+fn close_flushes_its_handle_and_stops_workers_with_the_last() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let wal = dir.path().join("wal.log");
+    let db = CrabKv::builder(dir.path())
+        .cache_capacity(100.try_into().unwrap())
+        .write_back_cache(true)
+        .async_compaction(true)
+        .build()?;
+    db.put("k".into(), "first".into())?;
+    db.put("k".into(), "second".into())?;
+
+    // Closing a clone makes the buffer durable but leaves the store open.
+    db.clone().close()?;
+    assert!(String::from_utf8_lossy(&fs::read(&wal)?).contains("second"));
+    assert_eq!(db.handle_count(), 1);
+    db.put("k".into(), "third".into())?;
+    db.compact_and_wait()?;
+    assert!(db.compaction_status().worker_running);
+
+    db.close()?;
+    let db = CrabKv::open(dir.path())?;
+    assert_eq!(db.get("k")?, Some("third".into()));
+    Ok(())
+}